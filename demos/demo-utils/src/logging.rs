@@ -4,17 +4,17 @@ use bbqueue::{ArrayLength, GrantW, Producer};
 use core::{cell::RefCell, fmt};
 use cortex_m::interrupt::{self, Mutex};
 use log::{Log, Metadata, Record};
-use rubble::time::Timer;
+use rubble::time::StampSource;
 
 const DATA_LOST_MSG: &str = "…\n";
 
 /// A `fmt::Write` adapter that prints a timestamp before each line.
-pub struct StampedLogger<T: Timer, L: fmt::Write> {
+pub struct StampedLogger<T: StampSource, L: fmt::Write> {
     timer: T,
     inner: L,
 }
 
-impl<T: Timer, L: fmt::Write> StampedLogger<T, L> {
+impl<T: StampSource, L: fmt::Write> StampedLogger<T, L> {
     /// Creates a new `StampedLogger` that will print to `inner` and obtains timestamps using
     /// `timer`.
     pub fn new(inner: L, timer: T) -> Self {
@@ -22,11 +22,14 @@ impl<T: Timer, L: fmt::Write> StampedLogger<T, L> {
     }
 }
 
-impl<T: Timer, L: fmt::Write> fmt::Write for StampedLogger<T, L> {
+impl<T: StampSource, L: fmt::Write> fmt::Write for StampedLogger<T, L> {
     fn write_str(&mut self, s: &str) -> fmt::Result {
         for (i, line) in s.split('\n').enumerate() {
             if i != 0 {
-                write!(self.inner, "\n{} - ", self.timer.now())?;
+                match self.timer.now_extended() {
+                    Some(now) => write!(self.inner, "\n{:?} - ", now)?,
+                    None => write!(self.inner, "\n{} - ", self.timer.now())?,
+                }
             }
 
             self.inner.write_str(line)?;
@@ -21,13 +21,14 @@ use nrf52833_hal as hal;
 #[cfg(feature = "52840")]
 use nrf52840_hal as hal;
 
-use rtic::cyccnt::U32Ext;
-use rubble::beacon::Beacon;
+use rubble::beacon::BeaconAdvertiser;
 use rubble::link::{ad_structure::AdStructure, MIN_PDU_BUF};
+use rubble::time::{Duration, Timer};
 use rubble_nrf5x::radio::{BleRadio, PacketBuffer};
+use rubble_nrf5x::timer::BleTimer;
 use rubble_nrf5x::utils::get_device_address;
 
-#[rtic::app(device = crate::hal::pac, peripherals = true, monotonic = rtic::cyccnt::CYCCNT)]
+#[rtic::app(device = crate::hal::pac, peripherals = true)]
 const APP: () = {
     struct Resources {
         #[init([0; MIN_PDU_BUF])]
@@ -35,57 +36,67 @@ const APP: () = {
         #[init([0; MIN_PDU_BUF])]
         ble_rx_buf: PacketBuffer,
         radio: BleRadio,
-        beacon: Beacon,
+        ble_timer: BleTimer<hal::pac::TIMER0>,
+        beacon: BeaconAdvertiser,
     }
 
-    #[init(resources = [ble_tx_buf, ble_rx_buf], spawn = [update])]
+    #[init(resources = [ble_tx_buf, ble_rx_buf])]
     fn init(ctx: init::Context) -> init::LateResources {
         // On reset, the internal high frequency clock is already used, but we
         // also need to switch to the external HF oscillator. This is needed
         // for Bluetooth to work.
         let _clocks = hal::clocks::Clocks::new(ctx.device.CLOCK).enable_ext_hfosc();
 
-        // Initialize (enable) the monotonic timer (CYCCNT)
-        let mut core = ctx.core;
-        core.DCB.enable_trace();
-        core.DWT.enable_cycle_counter();
+        let mut ble_timer = BleTimer::init(ctx.device.TIMER0);
 
         // Determine device address
         let device_address = get_device_address();
 
         // Rubble currently requires an RX buffer even though the radio is only used as a TX-only beacon.
-        let radio = BleRadio::new(
+        let mut radio = BleRadio::new(
             ctx.device.RADIO,
             &ctx.device.FICR,
             ctx.resources.ble_tx_buf,
             ctx.resources.ble_rx_buf,
         );
 
-        let beacon = Beacon::new(
+        // Report the actual transmit power, so receivers can estimate the distance to the beacon
+        let tx_power = radio.set_tx_power(0);
+
+        // Advertise about 3 times per second
+        let mut beacon = BeaconAdvertiser::new(
             device_address,
-            &[AdStructure::CompleteLocalName("Rusty Beacon (nRF52)")],
+            &[
+                AdStructure::CompleteLocalName("Rusty Beacon (nRF52)"),
+                AdStructure::TxPowerLevel(tx_power),
+            ],
+            Duration::from_millis(325),
         )
         .unwrap();
 
-        ctx.spawn.update().ok();
-
-        init::LateResources { radio, beacon }
-    }
-
-    /// Fire the beacon.
-    #[task(schedule = [update], resources = [beacon, radio])]
-    fn update(ctx: update::Context) {
-        ctx.resources.beacon.broadcast(ctx.resources.radio);
+        let cmd = beacon.start(ble_timer.now());
+        ble_timer.configure_interrupt(cmd.next_update);
 
-        ctx.schedule
-            .update(ctx.scheduled + 21_333_333.cycles())
-            .ok(); // about 3 times per second as nrf52 runs at 64 MHz
+        init::LateResources {
+            radio,
+            ble_timer,
+            beacon,
+        }
     }
 
-    // Here we list unused interrupt vectors that can be used to dispatch software tasks
-    //
-    // One needs one free interrupt per priority level used in software tasks.
-    extern "C" {
-        fn TIMER1();
+    /// Fire the beacon on the next advertising channel.
+    #[task(binds = TIMER0, resources = [beacon, radio, ble_timer])]
+    fn timer0(ctx: timer0::Context) {
+        let timer = ctx.resources.ble_timer;
+        if !timer.is_interrupt_pending() {
+            return;
+        }
+        timer.clear_interrupt();
+
+        let cmd = ctx
+            .resources
+            .beacon
+            .timer_update(timer.now(), ctx.resources.radio);
+        timer.configure_interrupt(cmd.next_update);
     }
 };
@@ -3,6 +3,7 @@
 use bbqueue::{BBBuffer, ConstBBBuffer, Consumer};
 use cortex_m::interrupt;
 use demo_utils::logging::{BbqLogger, StampedLogger, WriteLogger};
+use rubble::time::ExtendedStampSource;
 use rubble_nrf5x::timer::StampSource;
 
 #[cfg(feature = "log")]
@@ -14,7 +15,8 @@ pub(crate) use bbqueue::consts::U1 as BufferSize;
 #[cfg(feature = "log")]
 use log::LevelFilter;
 
-type Logger = StampedLogger<StampSource<LogTimer>, BbqLogger<'static, BufferSize>>;
+type Logger =
+    StampedLogger<ExtendedStampSource<StampSource<LogTimer>>, BbqLogger<'static, BufferSize>>;
 
 type LogTimer = crate::hal::pac::TIMER0;
 
@@ -27,7 +29,7 @@ static BUFFER: BBBuffer<BufferSize> = BBBuffer(ConstBBBuffer::new());
 #[cfg(feature = "log")]
 pub fn init(timer: StampSource<LogTimer>) -> Consumer<'static, BufferSize> {
     let (tx, log_sink) = BUFFER.try_split().unwrap();
-    let logger = StampedLogger::new(BbqLogger::new(tx), timer);
+    let logger = StampedLogger::new(BbqLogger::new(tx), ExtendedStampSource::new(timer));
 
     let log = WriteLogger::new(logger);
     interrupt::free(|_| unsafe {
@@ -29,47 +29,66 @@ use rubble::{
     l2cap::{BleChannelMap, L2CAPState},
     link::{
         ad_structure::AdStructure,
-        queue::{PacketQueue, SimpleQueue},
+        queue::{PacketQueue, RingQueue},
         LinkLayer, Responder, MIN_PDU_BUF,
     },
+    log::NoLogger,
     security::NoSecurity,
     time::{Duration, Timer},
+    Error,
 };
 use rubble_nrf5x::{
+    monotonic::Timer0Mono,
     radio::{BleRadio, PacketBuffer},
+    rng::HwRng,
     timer::BleTimer,
     utils::get_device_address,
 };
 
+/// Number of packets that can be buffered in each direction.
+const QUEUE_PACKETS: usize = 4;
+
 pub enum AppConfig {}
 
 impl Config for AppConfig {
     type Timer = BleTimer<hal::pac::TIMER0>;
     type Transmitter = BleRadio;
     type ChannelMapper = BleChannelMap<attrs::DemoAttrs, NoSecurity>;
-    type PacketQueue = &'static mut SimpleQueue;
+    type PacketQueue = &'static mut RingQueue<QUEUE_PACKETS>;
+    type Rng = HwRng;
+    type Logger = NoLogger;
 }
 
-#[rtic::app(device = crate::hal::pac, peripherals = true)]
-const APP: () = {
-    struct Resources {
-        #[init([0; MIN_PDU_BUF])]
-        ble_tx_buf: PacketBuffer,
-        #[init([0; MIN_PDU_BUF])]
-        ble_rx_buf: PacketBuffer,
-        #[init(SimpleQueue::new())]
-        tx_queue: SimpleQueue,
-        #[init(SimpleQueue::new())]
-        rx_queue: SimpleQueue,
+#[rtic::app(device = crate::hal::pac, peripherals = true, dispatchers = [WDT])]
+mod app {
+    use super::*;
+
+    /// Resources used by the real-time Link-Layer code.
+    ///
+    /// They are only accessed by the `RADIO` and `TIMER0` handlers, which run at the same priority,
+    /// so no locking is needed.
+    #[shared]
+    struct Shared {
+        #[lock_free]
+        radio: BleRadio,
+        #[lock_free]
         ble_ll: LinkLayer<AppConfig>,
+    }
+
+    #[local]
+    struct Local {
         ble_r: Responder<AppConfig>,
-        radio: BleRadio,
         log_channel: UpChannel,
         log_sink: Consumer<'static, logger::BufferSize>,
     }
 
-    #[init(resources = [ble_tx_buf, ble_rx_buf, tx_queue, rx_queue])]
-    fn init(ctx: init::Context) -> init::LateResources {
+    #[init(local = [
+        ble_tx_buf: PacketBuffer = [0; MIN_PDU_BUF],
+        ble_rx_buf: PacketBuffer = [0; MIN_PDU_BUF],
+        tx_queue: RingQueue<QUEUE_PACKETS> = RingQueue::new(),
+        rx_queue: RingQueue<QUEUE_PACKETS> = RingQueue::new(),
+    ])]
+    fn init(ctx: init::Context) -> (Shared, Local) {
         let rtt = rtt_init! {
             up: {
                 0: {
@@ -86,7 +105,11 @@ const APP: () = {
         // for Bluetooth to work.
         let _clocks = hal::clocks::Clocks::new(ctx.device.CLOCK).enable_ext_hfosc();
 
-        let ble_timer = BleTimer::init(ctx.device.TIMER0);
+        let mut ble_timer = BleTimer::init(ctx.device.TIMER0);
+        // Timestamp received packets in hardware
+        ble_timer.capture_radio_end(&ctx.device.PPI, 0);
+        // Lets tasks use `Timer0Mono::delay` and friends
+        Timer0Mono::start(&ble_timer);
 
         let p0 = hal::gpio::p0::Parts::new(ctx.device.P0);
 
@@ -96,15 +119,15 @@ const APP: () = {
         let mut radio = BleRadio::new(
             ctx.device.RADIO,
             &ctx.device.FICR,
-            ctx.resources.ble_tx_buf,
-            ctx.resources.ble_rx_buf,
+            ctx.local.ble_tx_buf,
+            ctx.local.ble_rx_buf,
         );
 
         let log_sink = logger::init(ble_timer.create_stamp_source());
 
         // Create TX/RX queues
-        let (tx, tx_cons) = ctx.resources.tx_queue.split();
-        let (rx_prod, rx) = ctx.resources.rx_queue.split();
+        let (tx, tx_cons) = ctx.local.tx_queue.split();
+        let (rx_prod, rx) = ctx.local.rx_queue.split();
 
         // Create the actual BLE stack objects
         let mut ble_ll = LinkLayer::<AppConfig>::new(device_address, ble_timer);
@@ -119,8 +142,8 @@ const APP: () = {
             ))),
         );
 
-        // Send advertisement and set up regular interrupt
-        let next_update = ble_ll
+        // Send advertisement. This also schedules the first timer interrupt.
+        ble_ll
             .start_advertise(
                 Duration::from_millis(200),
                 &[AdStructure::CompleteLocalName("CONCVRRENS CERTA CELERIS")],
@@ -130,66 +153,59 @@ const APP: () = {
             )
             .unwrap();
 
-        ble_ll.timer().configure_interrupt(next_update);
-
-        init::LateResources {
-            radio,
-            ble_ll,
-            ble_r,
-            log_channel,
-            log_sink,
-        }
+        (
+            Shared { radio, ble_ll },
+            Local {
+                ble_r,
+                log_channel,
+                log_sink,
+            },
+        )
     }
 
-    #[task(binds = RADIO, resources = [radio, ble_ll], spawn = [ble_worker], priority = 3)]
+    #[task(binds = RADIO, shared = [radio, ble_ll], priority = 3)]
     fn radio(ctx: radio::Context) {
-        let ble_ll: &mut LinkLayer<AppConfig> = ctx.resources.ble_ll;
-        if let Some(cmd) = ctx
-            .resources
-            .radio
-            .recv_interrupt(ble_ll.timer().now(), ble_ll)
-        {
-            ctx.resources.radio.configure_receiver(cmd.radio);
-            ble_ll.timer().configure_interrupt(cmd.next_update);
-
-            if cmd.queued_work {
+        let radio = ctx.shared.radio;
+        let ble_ll = ctx.shared.ble_ll;
+        if let Some(cmd) = radio.recv_interrupt(ble_ll.timer().last_radio_end(), ble_ll) {
+            radio.configure_receiver(cmd.radio);
+
+            if cmd.queued_work || cmd.tx_space_freed {
                 // If there's any lower-priority work to be done, ensure that happens.
-                // If we fail to spawn the task, it's already scheduled.
-                ctx.spawn.ble_worker().ok();
+                // If we fail to spawn the task, it's already running.
+                ble_worker::spawn().ok();
             }
         }
     }
 
-    #[task(binds = TIMER0, resources = [radio, ble_ll], spawn = [ble_worker], priority = 3)]
+    #[task(binds = TIMER0, shared = [radio, ble_ll], priority = 3)]
     fn timer0(ctx: timer0::Context) {
-        let timer = ctx.resources.ble_ll.timer();
+        // The interrupt is shared with the monotonic
+        Timer0Mono::on_interrupt();
+
+        let timer = ctx.shared.ble_ll.timer();
         if !timer.is_interrupt_pending() {
             return;
         }
         timer.clear_interrupt();
 
-        let cmd = ctx.resources.ble_ll.update_timer(ctx.resources.radio);
-        ctx.resources.radio.configure_receiver(cmd.radio);
-
-        ctx.resources
-            .ble_ll
-            .timer()
-            .configure_interrupt(cmd.next_update);
+        let cmd = ctx.shared.ble_ll.update_timer(ctx.shared.radio);
+        ctx.shared.radio.configure_receiver(cmd.radio);
 
-        if cmd.queued_work {
+        if cmd.queued_work || cmd.tx_space_freed {
             // If there's any lower-priority work to be done, ensure that happens.
-            // If we fail to spawn the task, it's already scheduled.
-            ctx.spawn.ble_worker().ok();
+            // If we fail to spawn the task, it's already running.
+            ble_worker::spawn().ok();
         }
     }
 
-    #[idle(resources = [log_sink, log_channel])]
+    #[idle(local = [log_sink, log_channel])]
     fn idle(ctx: idle::Context) -> ! {
         // Drain the logging buffer through the serial connection
         loop {
             if cfg!(feature = "log") {
-                while let Ok(grant) = ctx.resources.log_sink.read() {
-                    ctx.resources.log_channel.write(grant.buf());
+                while let Ok(grant) = ctx.local.log_sink.read() {
+                    ctx.local.log_channel.write(grant.buf());
 
                     let len = grant.buf().len();
                     grant.release(len);
@@ -201,15 +217,16 @@ const APP: () = {
         }
     }
 
-    #[task(resources = [ble_r], priority = 2)]
-    fn ble_worker(ctx: ble_worker::Context) {
+    #[task(local = [ble_r], priority = 2)]
+    async fn ble_worker(ctx: ble_worker::Context) {
         // Fully drain the packet queue
-        while ctx.resources.ble_r.has_work() {
-            ctx.resources.ble_r.process_one().unwrap();
+        while ctx.local.ble_r.has_work() {
+            // Malformed packets are dropped by the responder. Fatal errors are logged and the
+            // connection is terminated, so processing can simply continue.
+            if let Err(Error::TxBusy) = ctx.local.ble_r.process_one() {
+                // Retried once the Link-Layer frees up space in the TX queue
+                break;
+            }
         }
     }
-
-    extern "C" {
-        fn WDT();
-    }
-};
+}
@@ -0,0 +1,337 @@
+//! Runs the Rubble BLE stack on the nRF52-series radios under the [Embassy] framework.
+//!
+//! Rubble's Link-Layer has hard real-time requirements, since received packets have to be answered
+//! within 150 µs. Received packets are therefore still processed in the `RADIO` interrupt handler,
+//! which the application has to forward to [`Stack::on_radio_interrupt`]. Everything else happens
+//! in async tasks:
+//!
+//! * [`Stack::run`] performs the Link-Layer updates (advertising and connection events) scheduled
+//!   via [`EmbassyTimer`], which uses Embassy's time driver.
+//! * [`Stack::next_event`] reports when a connection is established or lost.
+//! * Incoming L2CAP traffic (ATT/GATT, Security Manager, ...) is handled by Rubble's `Responder`
+//!   in a separate task, using `Responder::process_one_async` with [`Stack::queue_waker`].
+//!
+//! Since Link-Layer updates have to be on time, the task calling [`Stack::run`] should be spawned
+//! on a high-priority `InterruptExecutor`.
+//!
+//! There is no stream of GATT events: Reads and writes of attributes are handled by the
+//! application's `AttributeProvider` while the `Responder` processes incoming packets. If other
+//! tasks need to react to them, the `AttributeProvider` can pass them on through an Embassy
+//! `Channel`.
+//!
+//! # Supported chips
+//!
+//! The target chip is selected by enabling exactly one of the Cargo features `52805`, `52810`,
+//! `52811`, `52832`, `52833` or `52840`, which are passed on to `rubble-nrf5x`.
+//!
+//! # Example
+//!
+//! ```ignore
+//! static STACK: Stack<AppConfig> = Stack::new();
+//! static RESPONDER: StaticCell<Responder<AppConfig>> = StaticCell::new();
+//!
+//! #[interrupt]
+//! fn RADIO() {
+//!     STACK.on_radio_interrupt();
+//! }
+//!
+//! #[embassy_executor::task]
+//! async fn link_layer() {
+//!     STACK.run().await
+//! }
+//!
+//! #[embassy_executor::task]
+//! async fn host(responder: &'static mut Responder<AppConfig>) {
+//!     loop {
+//!         responder.process_one_async(STACK.queue_waker()).await.ok();
+//!     }
+//! }
+//!
+//! // In `main`, after creating the radio, Link-Layer and responder:
+//! STACK.init(radio, ll);
+//! STACK.with(|radio, ll| ll.start_advertise(interval, &ad, radio, tx_cons, rx_prod))?;
+//! ```
+//!
+//! [Embassy]: https://embassy.dev
+//! [`Stack::on_radio_interrupt`]: struct.Stack.html#method.on_radio_interrupt
+//! [`Stack::run`]: struct.Stack.html#method.run
+//! [`Stack::next_event`]: struct.Stack.html#method.next_event
+//! [`Stack::queue_waker`]: struct.Stack.html#method.queue_waker
+//! [`EmbassyTimer`]: timer/struct.EmbassyTimer.html
+
+#![cfg_attr(not(test), no_std)]
+#![warn(rust_2018_idioms)]
+
+pub mod timer;
+
+use self::timer::EmbassyTimer;
+use core::cell::RefCell;
+use embassy_futures::select::{select, Either};
+use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, CriticalSectionMutex};
+use embassy_sync::{channel::Channel, signal::Signal};
+use rubble::config::Config;
+use rubble::link::{queue::QueueWaker, Cmd, LinkLayer, NextUpdate, MIN_PDU_BUF};
+use rubble::time::Timer;
+use rubble_nrf5x::radio::BleRadio;
+
+/// Number of `LinkEvent`s buffered until they are retrieved with `Stack::next_event`.
+const EVENT_CAPACITY: usize = 4;
+
+/// Change in the Link-Layer state, reported by [`Stack::next_event`].
+///
+/// [`Stack::next_event`]: struct.Stack.html#method.next_event
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum LinkEvent {
+    /// A connection was established.
+    Connected,
+
+    /// The connection was closed or lost.
+    Disconnected,
+}
+
+/// Buffers `LinkEvent`s until they are retrieved by `Stack::next_event`.
+struct LinkEvents {
+    channel: Channel<CriticalSectionRawMutex, LinkEvent, EVENT_CAPACITY>,
+}
+
+impl LinkEvents {
+    const fn new() -> Self {
+        Self {
+            channel: Channel::new(),
+        }
+    }
+
+    /// Queues an event if `connected` differs from the state last `reported`.
+    fn update(&self, reported: &mut bool, connected: bool) {
+        if connected != *reported {
+            *reported = connected;
+            let event = if connected {
+                LinkEvent::Connected
+            } else {
+                LinkEvent::Disconnected
+            };
+
+            // If the application doesn't keep up, newer events are dropped. It can still query
+            // the current state via `with`.
+            self.channel.try_send(event).ok();
+        }
+    }
+
+    async fn next(&self) -> LinkEvent {
+        self.channel.receive().await
+    }
+}
+
+/// Radio and Link-Layer, shared between the `RADIO` interrupt and the `run` task.
+struct Inner<C: Config, const N: usize> {
+    radio: BleRadio<N>,
+    ll: LinkLayer<C>,
+    connected: bool,
+}
+
+/// Owns the radio and the Link-Layer and drives them from the `RADIO` interrupt and an async task.
+///
+/// A `Stack` is meant to be stored in a `static`, so that it can be accessed from the interrupt
+/// handler and from tasks. It has to be initialized by calling [`init`] before the `RADIO`
+/// interrupt is enabled and before [`run`] is called.
+///
+/// [`init`]: #method.init
+/// [`run`]: #method.run
+pub struct Stack<C: Config, const N: usize = MIN_PDU_BUF> {
+    inner: CriticalSectionMutex<RefCell<Option<Inner<C, N>>>>,
+    /// Signaled when the Link-Layer's scheduled update might have changed outside of `run`.
+    reschedule: Signal<CriticalSectionRawMutex, ()>,
+    events: LinkEvents,
+    queue: QueueWaker,
+}
+
+impl<C, const N: usize> Stack<C, N>
+where
+    C: Config<Timer = EmbassyTimer, Transmitter = BleRadio<N>>,
+{
+    /// Creates an uninitialized stack.
+    pub const fn new() -> Self {
+        Self {
+            inner: CriticalSectionMutex::new(RefCell::new(None)),
+            reschedule: Signal::new(),
+            events: LinkEvents::new(),
+            queue: QueueWaker::new(),
+        }
+    }
+
+    /// Hands the radio and the Link-Layer over to the stack.
+    pub fn init(&self, radio: BleRadio<N>, ll: LinkLayer<C>) {
+        let connected = ll.is_connected();
+        self.inner.lock(|inner| {
+            *inner.borrow_mut() = Some(Inner {
+                radio,
+                ll,
+                connected,
+            })
+        });
+        self.reschedule.signal(());
+    }
+
+    /// Calls `f` with exclusive access to the radio and the Link-Layer.
+    ///
+    /// This can be used to start advertising or to change settings like the transmit power. `f`
+    /// runs in a critical section, so it should be short.
+    ///
+    /// # Panics
+    ///
+    /// This will panic if the stack wasn't initialized with [`init`].
+    ///
+    /// [`init`]: #method.init
+    pub fn with<R>(&self, f: impl FnOnce(&mut BleRadio<N>, &mut LinkLayer<C>) -> R) -> R {
+        let result = self.lock(|inner| {
+            let result = f(&mut inner.radio, &mut inner.ll);
+            self.update_connection_state(inner);
+            result
+        });
+        self.reschedule.signal(());
+        result
+    }
+
+    /// Processes a radio event. Call this from the `RADIO` interrupt handler.
+    pub fn on_radio_interrupt(&self) {
+        let rescheduled = self.lock(|inner| {
+            let now = inner.ll.timer().now();
+            match inner.radio.recv_interrupt(now, &mut inner.ll) {
+                Some(cmd) => {
+                    let rescheduled = !matches!(cmd.next_update, NextUpdate::Keep);
+                    self.handle(inner, cmd);
+                    rescheduled
+                }
+                None => false,
+            }
+        });
+
+        if rescheduled {
+            self.reschedule.signal(());
+        }
+    }
+
+    /// Performs the Link-Layer updates scheduled via the [`EmbassyTimer`].
+    ///
+    /// This never returns, and should be spawned as a task on a high-priority executor.
+    ///
+    /// # Panics
+    ///
+    /// This will panic if the stack wasn't initialized with [`init`].
+    ///
+    /// [`EmbassyTimer`]: timer/struct.EmbassyTimer.html
+    /// [`init`]: #method.init
+    pub async fn run(&self) -> ! {
+        loop {
+            let deadline = match self.lock(|inner| inner.ll.timer().deadline()) {
+                Some(deadline) => deadline,
+                None => {
+                    self.reschedule.wait().await;
+                    continue;
+                }
+            };
+
+            let timer = embassy_time::Timer::at(deadline);
+            if let Either::Second(()) = select(timer, self.reschedule.wait()).await {
+                continue;
+            }
+
+            self.lock(|inner| {
+                // The update might have been rescheduled by the `RADIO` interrupt in the meantime
+                let due = inner
+                    .ll
+                    .timer()
+                    .deadline()
+                    .map_or(false, |deadline| deadline <= embassy_time::Instant::now());
+                if due {
+                    inner.ll.timer().cancel();
+                    let cmd = inner.ll.update_timer(&mut inner.radio);
+                    self.handle(inner, cmd);
+                }
+            });
+        }
+    }
+
+    /// Waits for the next change in the Link-Layer state.
+    ///
+    /// Only the latest few events are buffered, so this should be called regularly.
+    pub async fn next_event(&self) -> LinkEvent {
+        self.events.next().await
+    }
+
+    /// Returns the `QueueWaker` notified whenever the Link-Layer modifies the packet queues.
+    ///
+    /// This has to be passed to the async methods of the `Responder`.
+    pub fn queue_waker(&self) -> &QueueWaker {
+        &self.queue
+    }
+
+    fn lock<R>(&self, f: impl FnOnce(&mut Inner<C, N>) -> R) -> R {
+        self.inner.lock(|inner| {
+            let mut inner = inner.borrow_mut();
+            f(inner.as_mut().expect("`Stack::init` was not called"))
+        })
+    }
+
+    /// Applies the `Cmd` returned by the Link-Layer.
+    fn handle(&self, inner: &mut Inner<C, N>, cmd: Cmd) {
+        inner.radio.configure_receiver(cmd.radio);
+
+        if cmd.queued_work || cmd.tx_space_freed {
+            self.queue.wake();
+        }
+
+        self.update_connection_state(inner);
+    }
+
+    fn update_connection_state(&self, inner: &mut Inner<C, N>) {
+        self.events
+            .update(&mut inner.connected, inner.ll.is_connected());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use embassy_futures::block_on;
+
+    #[test]
+    fn link_events() {
+        let events = LinkEvents::new();
+        let mut reported = false;
+        events.update(&mut reported, false);
+        assert!(events.channel.try_receive().is_err());
+
+        // Only changes are reported
+        events.update(&mut reported, true);
+        events.update(&mut reported, true);
+        assert!(reported);
+        assert_eq!(block_on(events.next()), LinkEvent::Connected);
+        assert!(events.channel.try_receive().is_err());
+
+        events.update(&mut reported, false);
+        assert!(!reported);
+        assert_eq!(block_on(events.next()), LinkEvent::Disconnected);
+    }
+
+    #[test]
+    fn link_events_overflow() {
+        let events = LinkEvents::new();
+        let mut reported = false;
+        for i in 0..EVENT_CAPACITY + 3 {
+            events.update(&mut reported, i % 2 == 0);
+        }
+        assert!(reported);
+
+        // The newest events are dropped
+        for i in 0..EVENT_CAPACITY {
+            let expected = if i % 2 == 0 {
+                LinkEvent::Connected
+            } else {
+                LinkEvent::Disconnected
+            };
+            assert_eq!(block_on(events.next()), expected);
+        }
+        assert!(events.channel.try_receive().is_err());
+    }
+}
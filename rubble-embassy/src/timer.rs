@@ -0,0 +1,67 @@
+//! Rubble `Timer` implementation using Embassy's time driver.
+
+use embassy_time::{Duration as EmbassyDuration, Instant as EmbassyInstant};
+use rubble::time::{ExtendedInstant, Instant, Timer};
+
+/// Implements Rubble's `Timer` trait on top of Embassy's global time driver.
+///
+/// The timer doesn't deliver the events scheduled by the Link-Layer by itself. Instead, they are
+/// awaited by [`Stack::run`].
+///
+/// Rubble works with µs timestamps, so the time driver should be configured with a tick rate of
+/// 1 MHz (the `tick-hz-1_000_000` feature of `embassy-time`). Slower tick rates work, but the
+/// rounding makes the Link-Layer open its receive windows late.
+///
+/// [`Stack::run`]: ../struct.Stack.html#method.run
+pub struct EmbassyTimer {
+    deadline: Option<EmbassyInstant>,
+}
+
+impl EmbassyTimer {
+    /// Creates a timer with no scheduled event.
+    pub const fn new() -> Self {
+        Self { deadline: None }
+    }
+
+    /// Returns the time at which the Link-Layer wants to be updated next.
+    ///
+    /// Returns `None` if no update is scheduled.
+    pub fn deadline(&self) -> Option<EmbassyInstant> {
+        self.deadline
+    }
+}
+
+impl Default for EmbassyTimer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Timer for EmbassyTimer {
+    fn now(&self) -> Instant {
+        Instant::from_raw_micros(EmbassyInstant::now().as_micros() as u32)
+    }
+
+    fn schedule_at(&mut self, at: Instant) {
+        // `at` is the Embassy time truncated to 32 bits. Interpret it relative to the current time,
+        // treating the lower half of the range as the future and the upper half as the past.
+        let now = EmbassyInstant::now();
+        let delta = at.raw_micros().wrapping_sub(now.as_micros() as u32);
+        let deadline = if delta > i32::max_value() as u32 {
+            now
+        } else {
+            now + EmbassyDuration::from_micros(delta.into())
+        };
+        self.deadline = Some(deadline);
+    }
+
+    fn cancel(&mut self) {
+        self.deadline = None;
+    }
+
+    fn now_extended(&self) -> Option<ExtendedInstant> {
+        Some(ExtendedInstant::from_micros(
+            EmbassyInstant::now().as_micros(),
+        ))
+    }
+}
@@ -0,0 +1,435 @@
+//! Procedural macros for Rubble.
+//!
+//! # Declaring GATT services
+//!
+//! The [`gatt_service`] attribute turns a struct into an `AttributeProvider` hosting a single
+//! *Primary Service*. Every field annotated with `#[characteristic(...)]` becomes a characteristic
+//! of that service, and the macro generates the service and characteristic declarations (including
+//! their handles and encoded UUIDs) at compile time.
+//!
+//! ```
+//! use rubble_macros::gatt_service;
+//! # use rubble::Error;
+//!
+//! #[gatt_service(uuid = "180f")]
+//! pub struct BatteryService {
+//!     /// Battery Level, in percent.
+//!     #[characteristic(uuid = "2a19", read, notify)]
+//!     level: [u8; 1],
+//!
+//!     /// A custom characteristic whose value is computed on every read, and which forwards writes
+//!     /// to a method.
+//!     #[characteristic(
+//!         uuid = "a86a62f1-5d26-4538-b364-5654961515c9",
+//!         read,
+//!         write,
+//!         on_read = "read_led",
+//!         on_write = "write_led",
+//!     )]
+//!     led: bool,
+//! }
+//!
+//! impl BatteryService {
+//!     fn read_led(&self) -> [u8; 1] {
+//!         [self.led as u8]
+//!     }
+//!
+//!     fn write_led(&mut self, data: &[u8]) -> Result<(), Error> {
+//!         match data {
+//!             [value] => {
+//!                 self.led = *value != 0;
+//!                 Ok(())
+//!             }
+//!             _ => Err(Error::InvalidLength),
+//!         }
+//!     }
+//! }
+//! ```
+//!
+//! The service declaration is assigned handle `0x0001`, and every characteristic uses two
+//! consecutive handles afterwards (for the characteristic declaration and value attribute), in
+//! field order.
+//!
+//! UUIDs can be given in their 16-bit (`"2a19"`) or 128-bit form
+//! (`"a86a62f1-5d26-4538-b364-5654961515c9"`).
+//!
+//! Supported characteristic properties are `broadcast`, `read`, `write_without_response`,
+//! `write`, `notify` and `indicate`. They are put into the characteristic declaration and
+//! determine the access permissions of the value attribute.
+//!
+//! By default, the characteristic value is read from the field, which must implement `Clone` and
+//! `AsRef<[u8]>` (fixed-size byte arrays are a good fit). Writes are stored into the field, which
+//! must implement `AsMut<[u8]>`, and must have the same length as the field. Both can be
+//! overridden by naming a method:
+//!
+//! * `on_read = "method"`: `fn method(&self) -> impl AsRef<[u8]> + 'static` returns the current
+//!   value.
+//! * `on_write = "method"`: `fn method(&mut self, data: &[u8]) -> Result<(), rubble::Error>` is
+//!   invoked when the client writes the value.
+//!
+//! Descriptors (like the *Client Characteristic Configuration*) are not yet supported.
+//!
+//! [`gatt_service`]: attr.gatt_service.html
+
+extern crate proc_macro;
+
+use proc_macro::TokenStream;
+use proc_macro2::{Span, TokenStream as TokenStream2};
+use quote::quote;
+use syn::{
+    parse_macro_input, spanned::Spanned, AttributeArgs, Data, DeriveInput, Fields, Ident, Lit,
+    LitStr, Meta, NestedMeta,
+};
+
+/// Implements `AttributeProvider` for a struct describing a GATT service.
+///
+/// Refer to the [crate-level documentation](index.html) for details.
+#[proc_macro_attribute]
+pub fn gatt_service(args: TokenStream, item: TokenStream) -> TokenStream {
+    let args = parse_macro_input!(args as AttributeArgs);
+    let mut input = parse_macro_input!(item as DeriveInput);
+
+    // The helper attributes are removed even if expansion fails, to avoid follow-up errors
+    let tokens = expand(args, &mut input).unwrap_or_else(|e| e.to_compile_error());
+    quote!(#input #tokens).into()
+}
+
+/// Properties of a characteristic, as encoded in the characteristic declaration.
+const PROPERTIES: &[(&str, u8)] = &[
+    ("broadcast", 0x01),
+    ("read", 0x02),
+    ("write_without_response", 0x04),
+    ("write", 0x08),
+    ("notify", 0x10),
+    ("indicate", 0x20),
+];
+
+const PROP_READ: u8 = 0x02;
+const PROP_WRITE: u8 = 0x04 | 0x08;
+
+enum Uuid {
+    Uuid16(u16),
+    Uuid128(u128),
+}
+
+impl Uuid {
+    fn parse(lit: &LitStr) -> syn::Result<Self> {
+        let s = lit.value();
+        let error = || syn::Error::new(lit.span(), "invalid UUID");
+
+        let hex = match s.len() {
+            4 => s.clone(),
+            36 => {
+                let dashes = [8, 13, 18, 23];
+                if s.char_indices()
+                    .any(|(i, c)| (c == '-') != dashes.contains(&i))
+                {
+                    return Err(error());
+                }
+                s.replace('-', "")
+            }
+            _ => return Err(error()),
+        };
+        if !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Err(error());
+        }
+
+        if hex.len() == 4 {
+            Ok(Uuid::Uuid16(u16::from_str_radix(&hex, 16).unwrap()))
+        } else {
+            Ok(Uuid::Uuid128(u128::from_str_radix(&hex, 16).unwrap()))
+        }
+    }
+
+    /// Returns the UUID's bytes in the little-endian order used in attribute values.
+    fn le_bytes(&self) -> Vec<u8> {
+        match self {
+            Uuid::Uuid16(uuid) => uuid.to_le_bytes().to_vec(),
+            Uuid::Uuid128(uuid) => uuid.to_le_bytes().to_vec(),
+        }
+    }
+
+    /// Returns an expression creating the corresponding `AttUuid`.
+    fn att_uuid(&self) -> TokenStream2 {
+        match self {
+            Uuid::Uuid16(uuid) => {
+                quote!(::rubble::att::AttUuid::Uuid16(::rubble::uuid::Uuid16(#uuid)))
+            }
+            Uuid::Uuid128(uuid) => {
+                quote!(::rubble::att::AttUuid::Uuid128(::rubble::uuid::Uuid128::from_u128(#uuid)))
+            }
+        }
+    }
+}
+
+struct Characteristic {
+    field: Ident,
+    uuid: Uuid,
+    properties: u8,
+    on_read: Option<Ident>,
+    on_write: Option<Ident>,
+}
+
+fn expand(args: AttributeArgs, input: &mut DeriveInput) -> syn::Result<TokenStream2> {
+    let mut service_uuid = None;
+    for arg in &args {
+        match arg {
+            NestedMeta::Meta(Meta::NameValue(nv)) if nv.path.is_ident("uuid") => {
+                service_uuid = Some(Uuid::parse(&lit_str(&nv.lit)?)?);
+            }
+            _ => return Err(syn::Error::new(arg.span(), "expected `uuid = \"...\"`")),
+        }
+    }
+    let service_uuid = service_uuid.ok_or_else(|| {
+        syn::Error::new(
+            Span::call_site(),
+            "missing service UUID (`#[gatt_service(uuid = \"...\")]`)",
+        )
+    })?;
+
+    let fields = match &mut input.data {
+        Data::Struct(data) => match &mut data.fields {
+            Fields::Named(fields) => &mut fields.named,
+            _ => return Err(syn::Error::new(input.ident.span(), "expected named fields")),
+        },
+        _ => return Err(syn::Error::new(input.ident.span(), "expected a struct")),
+    };
+
+    let mut characteristics = Vec::new();
+    let mut errors = Vec::new();
+    for field in fields.iter_mut() {
+        let attrs = field.attrs.clone();
+        field
+            .attrs
+            .retain(|attr| !attr.path.is_ident("characteristic"));
+        for attr in attrs
+            .iter()
+            .filter(|attr| attr.path.is_ident("characteristic"))
+        {
+            let field = field.ident.clone().unwrap();
+            match attr
+                .parse_meta()
+                .and_then(|meta| parse_characteristic(field, meta))
+            {
+                Ok(c) => characteristics.push(c),
+                Err(e) => errors.push(e),
+            }
+        }
+    }
+    if let Some(mut error) = errors.pop() {
+        for e in errors {
+            error.combine(e);
+        }
+        return Err(error);
+    }
+    if characteristics.len() > 0x7FFF {
+        return Err(syn::Error::new(
+            input.ident.span(),
+            "too many characteristics",
+        ));
+    }
+
+    let declarations = quote!(::rubble::uuid::consts::declarations);
+    let service_decl = service_uuid.le_bytes();
+    let mut attrs = Vec::new();
+    let mut permissions = Vec::new();
+    let mut writes = Vec::new();
+    let mut last = (
+        1u16,
+        quote!(::rubble::att::AttUuid::Uuid16(#declarations::PRIMARY_SERVICE)),
+    );
+    for (i, c) in characteristics.iter().enumerate() {
+        let decl_handle = 2 + 2 * i as u16;
+        let value_handle = decl_handle + 1;
+        let field = &c.field;
+        let uuid = c.uuid.att_uuid();
+
+        let mut decl = vec![c.properties];
+        decl.extend_from_slice(&value_handle.to_le_bytes());
+        decl.extend(c.uuid.le_bytes());
+        let value = match &c.on_read {
+            Some(method) => quote!(self.#method()),
+            None => quote!(::core::clone::Clone::clone(&self.#field)),
+        };
+        attrs.push(quote! {
+            if range.contains(Handle::from_raw(#decl_handle)) {
+                f(self, &Attribute::new(
+                    #declarations::CHARACTERISTIC.into(),
+                    Handle::from_raw(#decl_handle),
+                    &[#(#decl),*][..],
+                ))?;
+            }
+            if range.contains(Handle::from_raw(#value_handle)) {
+                let value = #value;
+                f(self, &Attribute::new(#uuid, Handle::from_raw(#value_handle), value))?;
+            }
+        });
+
+        let readable = c.properties & PROP_READ != 0;
+        let writeable = c.properties & PROP_WRITE != 0;
+        let permission = match (readable, writeable) {
+            (true, true) => quote!(ReadableAndWriteable),
+            (false, true) => quote!(Writeable),
+            _ => quote!(Readable),
+        };
+        permissions.push(quote!(#value_handle => AttributeAccessPermissions::#permission,));
+
+        if writeable {
+            let write = match &c.on_write {
+                Some(method) => quote!(self.#method(data)),
+                None => quote! {{
+                    let value = ::core::convert::AsMut::<[u8]>::as_mut(&mut self.#field);
+                    if data.len() != value.len() {
+                        return Err(Error::InvalidLength);
+                    }
+                    value.copy_from_slice(data);
+                    Ok(())
+                }},
+            };
+            writes.push(quote!(#value_handle => #write,));
+        }
+
+        last = (value_handle, uuid);
+    }
+
+    let name = &input.ident;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+    let (end_handle, end_uuid) = last;
+    Ok(quote! {
+        impl #impl_generics ::rubble::att::AttributeProvider for #name #ty_generics #where_clause {
+            fn for_attrs_in_range(
+                &mut self,
+                range: ::rubble::att::HandleRange,
+                mut f: impl FnMut(
+                    &Self,
+                    &::rubble::att::Attribute<dyn AsRef<[u8]>>,
+                ) -> Result<(), ::rubble::Error>,
+            ) -> Result<(), ::rubble::Error> {
+                use ::rubble::att::{Attribute, Handle};
+
+                if range.contains(Handle::from_raw(1)) {
+                    f(self, &Attribute::new(
+                        #declarations::PRIMARY_SERVICE.into(),
+                        Handle::from_raw(1),
+                        &[#(#service_decl),*][..],
+                    ))?;
+                }
+                #(#attrs)*
+                Ok(())
+            }
+
+            fn is_grouping_attr(&self, uuid: ::rubble::att::AttUuid) -> bool {
+                uuid == #declarations::PRIMARY_SERVICE
+            }
+
+            fn group_end(
+                &self,
+                handle: ::rubble::att::Handle,
+            ) -> Option<&::rubble::att::Attribute<dyn AsRef<[u8]>>> {
+                // Only the handle of the returned attribute is used, so its value is left empty
+                static END: ::rubble::att::Attribute<&'static [u8]> = ::rubble::att::Attribute {
+                    att_type: #end_uuid,
+                    handle: ::rubble::att::Handle::from_raw(#end_handle),
+                    value: &[],
+                };
+
+                if handle.as_u16() == 1 {
+                    Some(&END)
+                } else {
+                    None
+                }
+            }
+
+            fn attr_access_permissions(
+                &self,
+                handle: ::rubble::att::Handle,
+            ) -> ::rubble::att::AttributeAccessPermissions {
+                use ::rubble::att::AttributeAccessPermissions;
+
+                match handle.as_u16() {
+                    #(#permissions)*
+                    _ => AttributeAccessPermissions::Readable,
+                }
+            }
+
+            fn write_attr(
+                &mut self,
+                handle: ::rubble::att::Handle,
+                data: &[u8],
+                _peer: Option<&::rubble::link::Peer>,
+            ) -> Result<(), ::rubble::Error> {
+                use ::rubble::Error;
+
+                match handle.as_u16() {
+                    #(#writes)*
+                    _ => Err(Error::InvalidValue),
+                }
+            }
+        }
+    })
+}
+
+fn parse_characteristic(field: Ident, meta: Meta) -> syn::Result<Characteristic> {
+    let list = match meta {
+        Meta::List(list) => list,
+        _ => {
+            return Err(syn::Error::new(
+                meta.span(),
+                "expected `#[characteristic(uuid = \"...\", ...)]`",
+            ))
+        }
+    };
+
+    let mut uuid = None;
+    let mut properties = 0;
+    let mut on_read = None;
+    let mut on_write = None;
+    for nested in &list.nested {
+        match nested {
+            NestedMeta::Meta(Meta::NameValue(nv)) => {
+                let value = lit_str(&nv.lit)?;
+                if nv.path.is_ident("uuid") {
+                    uuid = Some(Uuid::parse(&value)?);
+                } else if nv.path.is_ident("on_read") {
+                    on_read = Some(value.parse::<Ident>()?);
+                } else if nv.path.is_ident("on_write") {
+                    on_write = Some(value.parse::<Ident>()?);
+                } else {
+                    return Err(syn::Error::new(nv.path.span(), "unknown argument"));
+                }
+            }
+            NestedMeta::Meta(Meta::Path(path)) => {
+                let bit = PROPERTIES
+                    .iter()
+                    .find(|(name, _)| path.is_ident(name))
+                    .map(|(_, bit)| *bit)
+                    .ok_or_else(|| syn::Error::new(path.span(), "unknown property"))?;
+                properties |= bit;
+            }
+            _ => return Err(syn::Error::new(nested.span(), "unexpected argument")),
+        }
+    }
+
+    let uuid = uuid.ok_or_else(|| syn::Error::new(list.span(), "missing `uuid = \"...\"`"))?;
+    if on_write.is_some() && properties & PROP_WRITE == 0 {
+        return Err(syn::Error::new(
+            list.span(),
+            "`on_write` requires the `write` or `write_without_response` property",
+        ));
+    }
+
+    Ok(Characteristic {
+        field,
+        uuid,
+        properties,
+        on_read,
+        on_write,
+    })
+}
+
+fn lit_str(lit: &Lit) -> syn::Result<LitStr> {
+    match lit {
+        Lit::Str(s) => Ok(s.clone()),
+        _ => Err(syn::Error::new(lit.span(), "expected a string literal")),
+    }
+}
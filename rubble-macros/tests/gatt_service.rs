@@ -0,0 +1,153 @@
+use rubble::att::{AttUuid, AttributeAccessPermissions, AttributeProvider, Handle, HandleRange};
+use rubble::gatt::{characteristic::Properties, StaticAttributes};
+use rubble::uuid::consts::{characteristics, declarations, services};
+use rubble::uuid::Uuid128;
+use rubble::Error;
+use rubble_macros::gatt_service;
+
+const LED_UUID: Uuid128 = Uuid128::parse_static("a86a62f1-5d26-4538-b364-5654961515c9");
+
+#[gatt_service(uuid = "180f")]
+struct Service {
+    #[characteristic(uuid = "2a19", read, notify)]
+    level: [u8; 1],
+
+    #[characteristic(
+        uuid = "a86a62f1-5d26-4538-b364-5654961515c9",
+        read,
+        write,
+        on_read = "read_led",
+        on_write = "write_led"
+    )]
+    led: bool,
+
+    #[characteristic(uuid = "2a00", write_without_response)]
+    name: [u8; 4],
+
+    // Not part of the service
+    writes: u32,
+}
+
+impl Service {
+    fn read_led(&self) -> [u8; 1] {
+        [self.led as u8]
+    }
+
+    fn write_led(&mut self, data: &[u8]) -> Result<(), Error> {
+        self.writes += 1;
+        match data {
+            [value] => {
+                self.led = *value != 0;
+                Ok(())
+            }
+            _ => Err(Error::InvalidLength),
+        }
+    }
+}
+
+fn collect<A: AttributeProvider>(attrs: &mut A) -> Vec<(u16, AttUuid, Vec<u8>)> {
+    let mut list = Vec::new();
+    attrs
+        .for_attrs_in_range(
+            HandleRange::new(Handle::from_raw(0x0001), Handle::from_raw(0xFFFF)),
+            |_, attr| {
+                list.push((
+                    attr.handle.as_u16(),
+                    attr.att_type,
+                    attr.value.as_ref().to_vec(),
+                ));
+                Ok(())
+            },
+        )
+        .unwrap();
+    list
+}
+
+fn service() -> Service {
+    Service {
+        level: [48],
+        led: true,
+        name: *b"abcd",
+        writes: 0,
+    }
+}
+
+#[test]
+fn matches_static_attributes() {
+    let mut expected = StaticAttributes::<7>::new();
+    expected
+        .push_primary_service(services::BATTERY.into())
+        .unwrap();
+    expected
+        .push_characteristic(
+            characteristics::BATTERY_LEVEL.into(),
+            Properties::READ | Properties::NOTIFY,
+            &[48],
+            AttributeAccessPermissions::Readable,
+        )
+        .unwrap();
+    expected
+        .push_characteristic(
+            LED_UUID.into(),
+            Properties::READ | Properties::WRITE,
+            &[1],
+            AttributeAccessPermissions::ReadableAndWriteable,
+        )
+        .unwrap();
+    expected
+        .push_characteristic(
+            characteristics::DEVICE_NAME.into(),
+            Properties::WRITE_NO_RSP,
+            b"abcd",
+            AttributeAccessPermissions::Writeable,
+        )
+        .unwrap();
+
+    let mut service = service();
+    assert_eq!(collect(&mut service), collect(&mut expected));
+    for raw in 1..=8 {
+        let handle = Handle::from_raw(raw);
+        assert_eq!(
+            service.attr_access_permissions(handle),
+            expected.attr_access_permissions(handle),
+            "{:?}",
+            handle
+        );
+    }
+
+    assert!(service.is_grouping_attr(declarations::PRIMARY_SERVICE.into()));
+    assert!(!service.is_grouping_attr(declarations::CHARACTERISTIC.into()));
+    let end = service.group_end(Handle::from_raw(0x0001)).unwrap();
+    assert_eq!(end.handle, Handle::from_raw(0x0007));
+    assert!(service.group_end(Handle::from_raw(0x0002)).is_none());
+}
+
+#[test]
+fn writes() {
+    let mut service = service();
+
+    service
+        .write_attr(Handle::from_raw(0x0005), &[0], None)
+        .unwrap();
+    assert!(!service.led);
+    assert_eq!(service.writes, 1);
+    assert_eq!(
+        service.write_attr(Handle::from_raw(0x0005), &[0, 0], None),
+        Err(Error::InvalidLength)
+    );
+
+    service
+        .write_attr(Handle::from_raw(0x0007), b"wxyz", None)
+        .unwrap();
+    assert_eq!(&service.name, b"wxyz");
+    assert_eq!(
+        service.write_attr(Handle::from_raw(0x0007), b"xyz", None),
+        Err(Error::InvalidLength)
+    );
+
+    assert_eq!(
+        service.write_attr(Handle::from_raw(0x0003), &[0], None),
+        Err(Error::InvalidValue)
+    );
+    assert_eq!(service.level, [48]);
+}
@@ -0,0 +1,141 @@
+//! Hardware-accelerated AES-128 using the ECB peripheral.
+//!
+//! The ECB peripheral encrypts a single 16-Byte block in a few microseconds, which is much faster
+//! than the software implementation in Rubble and doesn't block the CPU while running.
+//!
+//! Note that the ECB peripheral shares the AES core with the CCM and AAR peripherals, which have
+//! higher priority. Any ongoing ECB operation is aborted when one of them is started.
+//!
+//! Since there is only one ECB peripheral, an `EcbAes` can either be the Security Manager's
+//! `AesProvider`, or encrypt Data Channel PDUs when installed in the radio with
+//! [`BleRadio::set_ecb`]. The latter is time-critical, so the Security Manager should use
+//! `SoftAes` then.
+//!
+//! [`BleRadio::set_ecb`]: ../radio/struct.BleRadio.html#method.set_ecb
+
+use crate::pac::ECB;
+use core::sync::atomic::{compiler_fence, Ordering};
+use rubble::security::AesProvider;
+
+/// Memory block accessed by the ECB peripheral via `ECBDATAPTR`.
+#[repr(C)]
+struct EcbData {
+    key: [u8; 16],
+    cleartext: [u8; 16],
+    ciphertext: [u8; 16],
+}
+
+/// Error returned when an ECB operation was aborted.
+///
+/// This happens when the CCM or AAR peripheral is started while the operation is in progress.
+#[derive(Debug)]
+pub struct Aborted;
+
+/// An `AesProvider` using the ECB peripheral.
+///
+/// All ECB interrupts are disabled. Completion of operations is detected by polling the event
+/// registers.
+pub struct EcbAes {
+    ecb: ECB,
+    data: EcbData,
+}
+
+impl EcbAes {
+    /// Creates a new `EcbAes` provider from the ECB peripheral.
+    pub fn new(ecb: ECB) -> Self {
+        ecb.intenclr
+            .write(|w| w.endecb().clear().errorecb().clear());
+        Self {
+            ecb,
+            data: EcbData {
+                key: [0; 16],
+                cleartext: [0; 16],
+                ciphertext: [0; 16],
+            },
+        }
+    }
+
+    /// Destroys `self`, returning the ECB peripheral.
+    pub fn free(self) -> ECB {
+        self.ecb
+    }
+
+    /// Encrypts `block` in-place using `key`, blocking until the operation is complete.
+    ///
+    /// If the operation is aborted by a higher-priority user of the AES core, it is restarted.
+    pub fn encrypt(&mut self, key: &[u8; 16], block: &mut [u8; 16]) {
+        loop {
+            if let Ok(ciphertext) = self.start(key, block).wait() {
+                *block = ciphertext;
+                return;
+            }
+        }
+    }
+
+    /// Starts encrypting `block` using `key` without waiting for the operation to complete.
+    ///
+    /// The returned `EcbOperation` can be polled for completion. Dropping it stops the operation.
+    pub fn start(&mut self, key: &[u8; 16], block: &[u8; 16]) -> EcbOperation<'_> {
+        self.data.key = *key;
+        self.data.cleartext = *block;
+
+        self.ecb.events_endecb.reset();
+        self.ecb.events_errorecb.reset();
+        self.ecb
+            .ecbdataptr
+            .write(|w| unsafe { w.bits(&self.data as *const _ as u32) });
+
+        // "Preceding reads and writes cannot be moved past subsequent writes."
+        compiler_fence(Ordering::Release);
+
+        self.ecb.tasks_startecb.write(|w| unsafe { w.bits(1) });
+        EcbOperation { aes: self }
+    }
+}
+
+impl AesProvider for EcbAes {
+    fn encrypt_block(&mut self, key: &[u8; 16], block: &mut [u8; 16]) {
+        self.encrypt(key, block);
+    }
+}
+
+/// An ongoing ECB encryption.
+///
+/// This borrows the `EcbAes` provider, which ensures that the memory accessed by the peripheral
+/// stays valid until the operation is complete.
+pub struct EcbOperation<'a> {
+    aes: &'a mut EcbAes,
+}
+
+impl EcbOperation<'_> {
+    /// Returns whether the operation has finished (successfully or not).
+    pub fn is_done(&self) -> bool {
+        self.aes.ecb.events_endecb.read().bits() != 0
+            || self.aes.ecb.events_errorecb.read().bits() != 0
+    }
+
+    /// Waits until the operation is done and returns the encrypted block.
+    pub fn wait(self) -> Result<[u8; 16], Aborted> {
+        while !self.is_done() {}
+
+        // "Subsequent reads and writes cannot be moved ahead of preceding reads."
+        compiler_fence(Ordering::Acquire);
+
+        if self.aes.ecb.events_errorecb.read().bits() != 0 {
+            Err(Aborted)
+        } else {
+            Ok(self.aes.data.ciphertext)
+        }
+    }
+}
+
+impl Drop for EcbOperation<'_> {
+    fn drop(&mut self) {
+        if !self.is_done() {
+            self.aes.ecb.tasks_stopecb.write(|w| unsafe { w.bits(1) });
+            while !self.is_done() {}
+        }
+        self.aes.ecb.events_endecb.reset();
+        self.aes.ecb.events_errorecb.reset();
+    }
+}
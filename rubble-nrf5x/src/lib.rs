@@ -1,6 +1,13 @@
 //! A Rubble BLE driver for the nRF51/nRF52-series radios.
-
-#![no_std]
+//!
+//! # RTIC
+//!
+//! With the `rtic` Cargo feature, the [`monotonic`] module provides RTIC 2 monotonics that share
+//! the hardware timer of a `BleTimer`.
+//!
+//! [`monotonic`]: monotonic/index.html
+
+#![cfg_attr(not(test), no_std)]
 #![warn(rust_2018_idioms)]
 
 #[cfg(feature = "51")]
@@ -24,6 +31,10 @@ use nrf52833_pac as pac;
 #[cfg(feature = "52840")]
 use nrf52840_pac as pac;
 
+pub mod ecb;
+#[cfg(feature = "rtic")]
+pub mod monotonic;
 pub mod radio;
+pub mod rng;
 pub mod timer;
 pub mod utils;
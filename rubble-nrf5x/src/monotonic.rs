@@ -0,0 +1,114 @@
+//! RTIC 2 monotonics running off the timer of a `BleTimer`.
+//!
+//! The Link-Layer needs exclusive control over the `BleTimer`'s interrupt, so it can't be used as a
+//! monotonic itself. Instead, the monotonics in this module read the same 1 MHz counter and
+//! schedule their wakeups via `CC[3]`, which `BleTimer` doesn't use. Since both share the timer's
+//! interrupt, its handler must call the monotonic's `on_interrupt` method before handling the
+//! `BleTimer`:
+//!
+//! ```ignore
+//! #[task(binds = TIMER0, shared = [radio, ble_ll], priority = 3)]
+//! fn timer0(ctx: timer0::Context) {
+//!     Timer0Mono::on_interrupt();
+//!
+//!     let timer = ctx.shared.ble_ll.timer();
+//!     if !timer.is_interrupt_pending() {
+//!         return;
+//!     }
+//!     // ...
+//! }
+//! ```
+//!
+//! Instants are 32-bit microsecond counts that wrap around after about 71 minutes, so delays and
+//! timeouts must be shorter than half of that.
+//!
+//! This module is only available with the `rtic` Cargo feature.
+
+use crate::{pac, timer::BleTimer};
+use rtic_time::{monotonic::TimerQueueBasedMonotonic, TimerQueue, TimerQueueBackend};
+
+macro_rules! impl_monotonic {
+    ( $($timer:ident: $mono:ident, $backend:ident, $queue:ident;)+ ) => {
+        $(
+            /// Timer queue backend of the monotonic with the same prefix.
+            pub struct $backend {
+                _p: (),
+            }
+
+            static $queue: TimerQueue<$backend> = TimerQueue::new();
+
+            impl TimerQueueBackend for $backend {
+                type Ticks = u32;
+
+                fn now() -> u32 {
+                    let timer = unsafe { &*pac::$timer::ptr() };
+                    // `BleTimer` reads the counter the same way. If it interrupts us, we just read
+                    // a slightly later value.
+                    timer.tasks_capture[0].write(|w| unsafe { w.bits(1) });
+                    timer.cc[0].read().bits()
+                }
+
+                fn set_compare(instant: u32) {
+                    let timer = unsafe { &*pac::$timer::ptr() };
+                    timer.cc[3].write(|w| unsafe { w.bits(instant) });
+                }
+
+                fn clear_compare_flag() {
+                    let timer = unsafe { &*pac::$timer::ptr() };
+                    timer.events_compare[3].reset();
+                }
+
+                fn pend_interrupt() {
+                    pac::NVIC::pend(pac::Interrupt::$timer);
+                }
+
+                fn timer_queue() -> &'static TimerQueue<Self> {
+                    &$queue
+                }
+            }
+
+            /// RTIC monotonic with microsecond resolution, sharing the timer of a `BleTimer`.
+            pub struct $mono;
+
+            impl $mono {
+                /// Starts the monotonic.
+                ///
+                /// Taking the `BleTimer` ensures that the counter is already running.
+                pub fn start(_timer: &BleTimer<pac::$timer>) {
+                    let timer = unsafe { &*pac::$timer::ptr() };
+                    timer.events_compare[3].reset();
+                    timer.intenset.write(|w| w.compare3().set());
+
+                    $queue.initialize($backend { _p: () });
+                }
+
+                /// Wakes up the tasks whose delays have expired.
+                ///
+                /// This must be called from the timer's interrupt handler.
+                pub fn on_interrupt() {
+                    unsafe {
+                        $queue.on_monotonic_interrupt();
+                    }
+                }
+            }
+
+            impl TimerQueueBasedMonotonic for $mono {
+                type Backend = $backend;
+                type Instant = fugit::TimerInstantU32<1_000_000>;
+                type Duration = fugit::TimerDurationU32<1_000_000>;
+            }
+        )+
+    };
+}
+
+#[cfg(not(feature = "51"))]
+impl_monotonic!(
+    TIMER0: Timer0Mono, Timer0Backend, TIMER0_QUEUE;
+    TIMER1: Timer1Mono, Timer1Backend, TIMER1_QUEUE;
+    TIMER2: Timer2Mono, Timer2Backend, TIMER2_QUEUE;
+);
+
+#[cfg(feature = "51")]
+impl_monotonic!(
+    TIMER0: Timer0Mono, Timer0Backend, TIMER0_QUEUE;
+);
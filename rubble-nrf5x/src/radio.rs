@@ -40,148 +40,476 @@
 //! In our case, this involves "splitting" the header into the `S0` field (everything preceding the
 //! length), the `Length` field, and the `S1` field (which just contains 2 unused bits, but they
 //! must still be sent, of course).
+//!
+//! # Turnaround
+//!
+//! By default, the Inter Frame Space `T_IFS` between a received PDU and our response is enforced by
+//! the radio's `DISABLED_TXEN` shortcut. Alternatively, a [`Turnaround`] engine using a spare
+//! `TIMER` and 2 PPI channels can be installed with [`BleRadio::set_turnaround`]. It starts the
+//! next radio operation a configurable time after the last one ended, in either direction.
+//!
+//! This applies to data channel PDUs as well as to *Scan Responses*. In both cases, the radio
+//! starts ramping up for transmission while the `RADIO` interrupt handler runs, and the response
+//! only has to be in place by the time ramp-up completes. TX ramp-up takes 140 µs on all supported
+//! chips (the nRF52's fast ramp-up mode is not used, since hardware `T_IFS` is only qualified for
+//! the default mode), so the interrupt handler has roughly 150 µs after the end of the received
+//! packet to pass the response to the radio. RX ramp-up takes 140 µs on the nRF52 and 138 µs on
+//! the nRF51, which the `Turnaround` engine compensates for when built with the `51` feature.
+//!
+//! The 150 µs budget is tight on the nRF51, which runs at 16 MHz and has a slower interrupt entry.
+//! A response that misses the deadline is sent as soon as possible instead, and counted (see
+//! [`BleRadio::late_responses`]). The timing has not been verified on nRF51 hardware yet, and there
+//! is no hardware-in-the-loop test for it. To validate it, run a peripheral against a scanner
+//! performing active scans (or a central), and check that `late_responses` stays at 0. The actual
+//! gap can be measured with a sniffer or by routing the radio's `END` and `ADDRESS` events to GPIOs
+//! via PPI/GPIOTE.
+//!
+//! [`Turnaround`]: struct.Turnaround.html
+//! [`BleRadio::set_turnaround`]: struct.BleRadio.html#method.set_turnaround
+//! [`BleRadio::late_responses`]: struct.BleRadio.html#method.late_responses
+//!
+//! # Reconfiguration latency
+//!
+//! After a packet was received, the radio has to be reconfigured for the next operation within a
+//! few dozen µs. Peripheral register accesses are slow compared to RAM accesses (each one stalls
+//! the CPU for several cycles, which hurts most on the 16 MHz nRF51), so `BleRadio` avoids them
+//! where possible:
+//!
+//! * The packet layout (`PCNF0`) is the same for advertising and data channel PDUs and is only
+//!   written when the radio is initialized.
+//! * `PCNF1` is written as a single precomputed value instead of a read-modify-write, and only
+//!   when the maximum payload length changes.
+//! * The data channel access address is only programmed when a new connection uses a different
+//!   one.
+//!
+//! Switching between data channels within a connection thus only updates the registers that
+//! depend on the channel (frequency, whitening IV) and the packet pointer.
+//!
+//! The resulting ISR latency has not been measured on hardware yet, so there are no numbers for
+//! how much time this saves. It can be measured by toggling a GPIO at the start and end of the
+//! `RADIO` interrupt handler and observing the pin with a logic analyzer.
+//!
+//! # Encryption
+//!
+//! On an encrypted connection, the Link-Layer decrypts each received PDU and encrypts the response
+//! from within the `RADIO` interrupt handler, so both count against the `T_IFS` budget described
+//! above. With a 27-Byte payload, AES-CCM needs 7 AES-128 block operations per PDU, so up to 14
+//! blocks have to be encrypted before the response is due.
+//!
+//! By default, the blocks are encrypted in software by `SoftAes`. An [`EcbAes`] provider installed
+//! with [`BleRadio::set_ecb`] uses the ECB peripheral instead, which takes a few µs per block and
+//! should be used whenever encryption is enabled. Neither variant has been timed on hardware yet.
+//! A PDU that isn't processed in time shows up in [`BleRadio::late_responses`] like any other late
+//! response, which can serve as a check when testing a new configuration.
+//!
+//! [`EcbAes`]: ../ecb/struct.EcbAes.html
+//! [`BleRadio::set_ecb`]: struct.BleRadio.html#method.set_ecb
+//!
+//! # Test modes
+//!
+//! For regulatory (FCC/ETSI) measurements, [`BleRadio::start_test_mode`] makes the radio emit an
+//! unmodulated or modulated carrier on a fixed channel until [`BleRadio::stop_test_mode`] is
+//! called. Test modes can only be entered while the Link-Layer is in Standby. Any radio operation
+//! requested by the Link-Layer ends the test mode.
+//!
+//! [`BleRadio::start_test_mode`]: struct.BleRadio.html#method.start_test_mode
+//! [`BleRadio::stop_test_mode`]: struct.BleRadio.html#method.stop_test_mode
 
+use crate::ecb::EcbAes;
 use crate::pac;
-use crate::pac::{radio::state::STATE_R, RADIO};
-use core::cmp;
+use crate::pac::{ppi, radio::state::STATE_R, timer0, PPI, RADIO};
+use crate::timer::{BleTimer, NrfTimerExt};
 use core::sync::atomic::{compiler_fence, Ordering};
+use core::{cmp, mem, slice};
 use rubble::config::Config;
 use rubble::link::{
-    advertising, data, Cmd, LinkLayer, RadioCmd, Transmitter, CRC_POLY, MIN_PDU_BUF,
+    advertising, data, filter::PeerFilter, queue::RawSlot, Cmd, LinkLayer, RadioCmd, Role,
+    Transmitter, CRC_POLY, MAX_PDU_BUF, MIN_PAYLOAD_BUF, MIN_PDU_BUF,
 };
 use rubble::phy::{AdvertisingChannel, DataChannel};
-use rubble::time::{Duration, Instant};
+use rubble::security::{AesProvider, SoftAes};
+use rubble::time::{Duration, Instant, Timer};
 
 /// A packet buffer that can hold header and payload of any advertising or data channel packet.
-pub type PacketBuffer = [u8; MIN_PDU_BUF];
+///
+/// By default, the buffer is large enough for advertising channel PDUs and data channel PDUs
+/// without the Packet Length Extension. Use [`ExtendedPacketBuffer`] (or a custom `N` between
+/// `MIN_PDU_BUF` and `MAX_PDU_BUF`) to receive longer data channel PDUs.
+///
+/// [`ExtendedPacketBuffer`]: type.ExtendedPacketBuffer.html
+pub type PacketBuffer<const N: usize = MIN_PDU_BUF> = [u8; N];
+
+/// A packet buffer that can hold any data channel PDU allowed by the Packet Length Extension.
+pub type ExtendedPacketBuffer = PacketBuffer<MAX_PDU_BUF>;
+
+/// Supported transmit power levels in dBm and their `TXPOWER` register values, strongest first.
+#[cfg(feature = "51")]
+const TX_POWER_LEVELS: &[(i8, u8)] = &[
+    (4, 4),
+    (0, 0),
+    (-4, 252),
+    (-8, 248),
+    (-12, 244),
+    (-16, 240),
+    (-20, 236),
+    (-30, 216),
+];
+
+/// Supported transmit power levels in dBm and their `TXPOWER` register values, strongest first.
+#[cfg(any(
+    feature = "52805",
+    feature = "52810",
+    feature = "52811",
+    feature = "52832"
+))]
+const TX_POWER_LEVELS: &[(i8, u8)] = &[
+    (4, 4),
+    (3, 3),
+    (0, 0),
+    (-4, 252),
+    (-8, 248),
+    (-12, 244),
+    (-16, 240),
+    (-20, 236),
+    (-40, 216),
+];
+
+/// Supported transmit power levels in dBm and their `TXPOWER` register values, strongest first.
+#[cfg(any(feature = "52833", feature = "52840"))]
+const TX_POWER_LEVELS: &[(i8, u8)] = &[
+    (8, 8),
+    (7, 7),
+    (6, 6),
+    (5, 5),
+    (4, 4),
+    (3, 3),
+    (2, 2),
+    (0, 0),
+    (-4, 252),
+    (-8, 248),
+    (-12, 244),
+    (-16, 240),
+    (-20, 236),
+    (-40, 216),
+];
+
+/// Highest RF channel index (2480 MHz).
+const MAX_RF_CHANNEL: u8 = 39;
+
+/// A radio test mode used for regulatory measurements.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum TestMode {
+    /// Transmit an unmodulated carrier.
+    ///
+    /// The radio ramps up for transmission, but never starts sending a packet.
+    UnmodulatedCarrier,
+
+    /// Transmit a modulated carrier.
+    ///
+    /// The radio sends packets with a PRBS9 payload back to back, like the *Direct Test Mode*
+    /// defined by the Bluetooth specification.
+    ModulatedCarrier,
+}
+
+/// Error returned when a test mode can not be started.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum TestModeError {
+    /// The Link-Layer is advertising or connected.
+    LinkActive,
+
+    /// The RF channel is out of range `0..=39`.
+    InvalidChannel,
+}
 
 /// An interface to the nRF radio in BLE mode.
-pub struct BleRadio {
+///
+/// `N` is the size of the packet buffers passed to `new`, which limits the length of data channel
+/// PDUs that can be sent and received (see [`PacketBuffer`]).
+///
+/// [`PacketBuffer`]: type.PacketBuffer.html
+pub struct BleRadio<const N: usize = MIN_PDU_BUF> {
     /// `true` if the radio is operating on an advertising channel, `false` if it's a data channel.
     advertising: bool,
-    radio: RADIO,
-    tx_buf: &'static mut PacketBuffer,
+
+    /// The `RADIO` register block.
+    ///
+    /// This is usually the real peripheral, but tests substitute a register block in RAM.
+    radio: &'static pac::radio::RegisterBlock,
+
+    /// Ownership of the `RADIO` peripheral.
+    _peripheral: RADIO,
+
+    tx_buf: &'static mut PacketBuffer<N>,
 
     /// Receive buffer.
     ///
     /// This is an `Option` because we need to pass a `&mut BleRadio` to the BLE stack while still
     /// having access to this buffer.
-    rx_buf: Option<&'static mut PacketBuffer>,
+    rx_buf: Option<&'static mut PacketBuffer<N>>,
+
+    /// Packet queue storage the radio is currently receiving a data channel PDU into.
+    ///
+    /// If this is `None`, `rx_buf` is used instead.
+    rx_slot: Option<RawSlot>,
+
+    /// Configured transmit power in dBm.
+    tx_power: i8,
+
+    /// Hardware turnaround engine used instead of the `DISABLED_TXEN` shortcut, if installed.
+    turnaround: Option<Turnaround>,
+
+    /// ECB peripheral used to encrypt Data Channel PDUs instead of `SoftAes`, if installed.
+    ecb: Option<EcbAes>,
+
+    /// Whether the radio peripheral is powered.
+    powered: bool,
+
+    /// Whether the HFXO is stopped while the radio is powered off.
+    hfxo_control: bool,
+
+    /// Whether device address matching is enabled for the current advertising channel listen.
+    address_match: bool,
+
+    /// Last values written to rarely changing registers.
+    cache: RegisterCache,
+
+    /// Whether the radio is turning around to send a response to the advertising channel PDU that
+    /// was just received.
+    response_pending: bool,
+
+    /// Number of responses that could not be sent within `T_IFS`.
+    late_responses: u32,
+
+    /// The test mode the radio is in, if any.
+    test_mode: Option<TestMode>,
 }
 
-impl BleRadio {
+/// Values of radio registers that are only rewritten when they change.
+///
+/// Must be reset whenever the registers are reset (ie. when the radio is powered off).
+#[derive(Default)]
+struct RegisterCache {
+    /// Value of the `MAXLEN` field in `PCNF1`.
+    maxlen: Option<u8>,
+
+    /// Access address assigned to logical address 1.
+    data_address: Option<u32>,
+}
+
+// The register block reference is only used by the owner of the `RADIO` peripheral, so the radio
+// may be moved between execution contexts like the peripheral itself.
+unsafe impl<const N: usize> Send for BleRadio<N> {}
+
+impl<const N: usize> BleRadio<N> {
+    /// The largest PDU payload that fits in the packet buffers.
+    const MAX_PAYLOAD: usize = N - 2;
+
+    /// The largest advertising channel PDU payload.
+    ///
+    /// Legacy advertising PDUs are limited to 37 Bytes, so there's no need to receive more.
+    const MAX_ADV_PAYLOAD: usize = if MIN_PAYLOAD_BUF < Self::MAX_PAYLOAD {
+        MIN_PAYLOAD_BUF
+    } else {
+        Self::MAX_PAYLOAD
+    };
+
+    /// Fails compilation if the buffer size `N` is not in range `MIN_PDU_BUF..=MAX_PDU_BUF`.
+    const BUF_SIZE_OK: () = assert!(
+        N >= MIN_PDU_BUF && N <= MAX_PDU_BUF,
+        "packet buffer size out of range"
+    );
+
     /// Initializes the radio in BLE mode and takes ownership of the RX and TX buffers.
+    ///
+    /// The buffer size `N` must be in range `MIN_PDU_BUF..=MAX_PDU_BUF`, which is checked at
+    /// compile time.
     // TODO: Use type-safe clock configuration to ensure that chip uses ext. crystal
     pub fn new(
         radio: RADIO,
         ficr: &pac::FICR,
-        tx_buf: &'static mut PacketBuffer,
-        rx_buf: &'static mut PacketBuffer,
+        tx_buf: &'static mut PacketBuffer<N>,
+        rx_buf: &'static mut PacketBuffer<N>,
     ) -> Self {
-        assert!(radio.state.read().state().is_disabled());
-
-        // The nRF51 requires manually setting the trim values.
-        #[cfg(feature = "51")]
-        {
-            if ficr.overrideen.read().ble_1mbit().is_override() {
-                unsafe {
-                    radio
-                        .override0
-                        .write(|w| w.override0().bits(ficr.ble_1mbit[0].read().bits()));
-                    radio
-                        .override1
-                        .write(|w| w.override1().bits(ficr.ble_1mbit[1].read().bits()));
-                    radio
-                        .override2
-                        .write(|w| w.override2().bits(ficr.ble_1mbit[2].read().bits()));
-                    radio
-                        .override3
-                        .write(|w| w.override3().bits(ficr.ble_1mbit[3].read().bits()));
-                    radio.override4.write(|w| {
-                        w.override4()
-                            .bits(ficr.ble_1mbit[4].read().bits())
-                            .enable()
-                            .set_bit()
-                    });
-                }
-            }
-        }
-
-        // The nRF52/53 do not require setting trim values, but we take the `ficr` reference anyways
-        // to have a consistent interface. Silence the unused variable warning:
-        let _ = ficr;
-
-        radio.mode.write(|w| w.mode().ble_1mbit());
-        radio.txpower.write(|w| w.txpower().pos4d_bm());
+        let registers = unsafe { &*RADIO::ptr() };
+        Self::with_registers(radio, registers, ficr, tx_buf, rx_buf)
+    }
 
-        let max_payload = rx_buf.len() - 2;
-        assert!(max_payload <= usize::from(u8::max_value()));
+    /// Initializes the radio using `registers` instead of the peripheral's register block.
+    fn with_registers(
+        peripheral: RADIO,
+        radio: &'static pac::radio::RegisterBlock,
+        ficr: &pac::ficr::RegisterBlock,
+        tx_buf: &'static mut PacketBuffer<N>,
+        rx_buf: &'static mut PacketBuffer<N>,
+    ) -> Self {
+        #[allow(clippy::let_unit_value)]
+        let () = Self::BUF_SIZE_OK;
+        assert!(radio.state.read().state().is_disabled());
 
-        unsafe {
-            radio.pcnf1.write(|w| {
-                // no packet length limit
-                w.maxlen()
-                    .bits(max_payload as u8)
-                    // 3-Byte Base Address + 1-Byte Address Prefix
-                    .balen()
-                    .bits(3)
-                    // Enable Data Whitening over PDU+CRC
-                    .whiteen()
-                    .set_bit()
-            });
-
-            radio.crccnf.write(|w| {
-                // skip address since only the S0, Length, S1 and Payload need CRC
-                // 3 Bytes = CRC24
-                w.skipaddr().skip().len().three()
-            });
-
-            radio
-                .crcpoly
-                .write(|w| w.crcpoly().bits(CRC_POLY & 0x00FFFFFF));
-
-            // Configure logical address 0 as the canonical advertising address.
-            // Base addresses are up to 32 bits in size. However, an 8 bit Address Prefix is
-            // *always* appended, so we must use a 24 bit Base Address and the 8 bit Prefix.
-            // BASE0 has, apparently, undocumented semantics: It is a proper 32-bit register, but
-            // it ignores the *lowest* 8 bit and instead transmits the upper 24 as the low 24 bits
-            // of the Access Address. Shift address up to fix this.
-            radio
-                .base0
-                .write(|w| w.bits(advertising::ACCESS_ADDRESS << 8));
-            radio
-                .prefix0
-                .write(|w| w.ap0().bits((advertising::ACCESS_ADDRESS >> 24) as u8));
-        }
-
-        // FIXME: No TIFS hardware support for now. Revisit when precise semantics are clear.
-        // Activate END_DISABLE and DISABLED_TXEN shortcuts so TIFS is enforced. We might enable
-        // more shortcuts later.
-        /*radio.shorts.write(|w| w
-            .end_disable().enabled()
-            .disabled_txen().enabled()
-        );*/
-
-        /*unsafe {
-            radio.tifs.write(|w| w.tifs().bits(BLE_TIFS));
-        }*/
-
-        // Configure shortcuts to simplify and speed up sending and receiving packets.
-        radio.shorts.write(|w| {
-            // start transmission/recv immediately after ramp-up
-            // disable radio when transmission/recv is done
-            w.ready_start().enabled().end_disable().enabled()
-        });
+        init_registers(radio, ficr, Self::MAX_PAYLOAD as u8);
 
         // We can now start the TXEN/RXEN tasks and the radio will do the rest and return to the
         // disabled state.
 
-        Self {
+        let mut this = Self {
             advertising: false,
             radio,
+            _peripheral: peripheral,
             tx_buf,
             rx_buf: Some(rx_buf),
+            rx_slot: None,
+            tx_power: 0,
+            turnaround: None,
+            ecb: None,
+            powered: true,
+            hfxo_control: false,
+            address_match: false,
+            cache: RegisterCache::default(),
+            response_pending: false,
+            late_responses: 0,
+            test_mode: None,
+        };
+        this.set_tx_power(4);
+        this
+    }
+
+    /// Sets the transmit power to `dbm`.
+    ///
+    /// The radio only supports a few discrete power levels, which depend on the chip. The highest
+    /// level not exceeding `dbm` is used (or the lowest level, if `dbm` is below all of them). The
+    /// chosen level is returned.
+    ///
+    /// The default is +4 dBm.
+    pub fn set_tx_power(&mut self, dbm: i8) -> i8 {
+        let level = self.write_tx_power(dbm);
+        self.tx_power = level;
+        level
+    }
+
+    /// Programs the supported power level for `dbm` without changing the configured one.
+    fn write_tx_power(&mut self, dbm: i8) -> i8 {
+        let (level, raw) = TX_POWER_LEVELS
+            .iter()
+            .copied()
+            .find(|&(level, _)| level <= dbm)
+            .unwrap_or(TX_POWER_LEVELS[TX_POWER_LEVELS.len() - 1]);
+
+        self.radio
+            .txpower
+            .write(|w| unsafe { w.txpower().bits(raw) });
+        level
+    }
+
+    /// Returns the configured transmit power in dBm.
+    pub fn tx_power(&self) -> i8 {
+        self.tx_power
+    }
+
+    /// Installs a PPI-based turnaround engine, or removes it when passing `None`.
+    ///
+    /// When installed, the engine starts transmitting the response to a received PDU exactly
+    /// `T_IFS` after the PDU was received, instead of relying on the radio's `DISABLED_TXEN`
+    /// shortcut. The previously installed engine is returned.
+    pub fn set_turnaround(&mut self, turnaround: Option<Turnaround>) -> Option<Turnaround> {
+        let mut previous = mem::replace(&mut self.turnaround, turnaround);
+        if let Some(previous) = &mut previous {
+            previous.cancel();
+        }
+        previous
+    }
+
+    /// Installs the ECB peripheral for encrypting Data Channel PDUs, or removes it when passing
+    /// `None`.
+    ///
+    /// Without it, encrypted connections use `SoftAes`, which may not finish processing a PDU
+    /// within `T_IFS` (see the [module documentation][enc]). The previously installed provider is
+    /// returned.
+    ///
+    /// [enc]: index.html#encryption
+    pub fn set_ecb(&mut self, ecb: Option<EcbAes>) -> Option<EcbAes> {
+        mem::replace(&mut self.ecb, ecb)
+    }
+
+    /// Powers the radio peripheral off.
+    ///
+    /// This is done automatically when the Link-Layer requests `RadioCmd::Off` (eg. when entering
+    /// Standby), and reduces the idle current. The radio is powered on again when it is needed.
+    ///
+    /// If enabled via [`set_hfxo_control`], this also stops the external high-frequency crystal.
+    ///
+    /// [`set_hfxo_control`]: #method.set_hfxo_control
+    pub fn power_off(&mut self) {
+        if !self.powered {
+            return;
+        }
+
+        if let Some(turnaround) = &mut self.turnaround {
+            turnaround.cancel();
+        }
+        self.radio.intenclr.write(|w| w.disabled().clear());
+        self.radio.power.write(|w| w.power().disabled());
+        self.powered = false;
+        self.test_mode = None;
+
+        if self.hfxo_control {
+            let clock = unsafe { &*pac::CLOCK::ptr() };
+            clock.tasks_hfclkstop.write(|w| unsafe { w.bits(1) });
+        }
+    }
+
+    /// Powers the radio peripheral on and restores its configuration.
+    ///
+    /// If enabled via [`set_hfxo_control`], this also starts the external high-frequency crystal
+    /// and waits until it is running, which can take more than a millisecond.
+    ///
+    /// [`set_hfxo_control`]: #method.set_hfxo_control
+    pub fn power_on(&mut self) {
+        if self.powered {
+            return;
         }
+
+        if self.hfxo_control {
+            let clock = unsafe { &*pac::CLOCK::ptr() };
+            clock.events_hfclkstarted.reset();
+            clock.tasks_hfclkstart.write(|w| unsafe { w.bits(1) });
+            while clock.events_hfclkstarted.read().bits() == 0 {}
+        }
+
+        self.radio.power.write(|w| w.power().enabled());
+        self.powered = true;
+
+        // Powering the radio off resets all of its registers
+        let ficr = unsafe { &*pac::FICR::ptr() };
+        init_registers(self.radio, ficr, Self::MAX_PAYLOAD as u8);
+        self.cache = RegisterCache::default();
+        self.set_tx_power(self.tx_power);
+    }
+
+    /// Returns whether the radio peripheral is currently powered.
+    pub fn is_powered(&self) -> bool {
+        self.powered
+    }
+
+    /// Sets whether the external high-frequency crystal (HFXO) is stopped while the radio is
+    /// powered off.
+    ///
+    /// This saves power between radio events, but must only be enabled if nothing else needs an
+    /// accurate high-frequency clock. In particular, the `BleTimer` runs off the high-frequency
+    /// clock and loses accuracy without the crystal, so this is only useful for applications that
+    /// are in Standby for extended periods, or that keep time using a low-frequency clock.
+    ///
+    /// Disabled by default.
+    pub fn set_hfxo_control(&mut self, enabled: bool) {
+        self.hfxo_control = enabled;
+    }
+
+    /// Returns the number of responses that missed `T_IFS`.
+    ///
+    /// This counts responses that were not ready when the radio finished ramping up for them. They
+    /// are still sent, but late, which some peers won't accept. The counter wraps around on
+    /// overflow.
+    pub fn late_responses(&self) -> u32 {
+        self.late_responses
     }
 
     /// Returns the current radio state.
@@ -189,8 +517,103 @@ impl BleRadio {
         self.radio.state.read().state()
     }
 
+    /// Starts transmitting a carrier for regulatory testing.
+    ///
+    /// The carrier is sent on RF channel `rf_channel` (`0..=39`, 2402 + 2 * `rf_channel` MHz) at
+    /// the supported power level for `dbm` (see [`set_tx_power`]), which is returned. It is sent
+    /// until [`stop_test_mode`] is called, or until the Link-Layer uses the radio.
+    ///
+    /// As an interlock, this fails with `TestModeError::LinkActive` unless `ll` is in Standby. The
+    /// `RADIO` interrupt is disabled while the test mode runs, and the configured transmit power
+    /// is restored when it ends.
+    ///
+    /// [`set_tx_power`]: #method.set_tx_power
+    /// [`stop_test_mode`]: #method.stop_test_mode
+    pub fn start_test_mode<C: Config, R: Role>(
+        &mut self,
+        mode: TestMode,
+        rf_channel: u8,
+        dbm: i8,
+        ll: &LinkLayer<C, R>,
+    ) -> Result<i8, TestModeError> {
+        if ll.is_advertising() || ll.is_connected() {
+            return Err(TestModeError::LinkActive);
+        }
+        if rf_channel > MAX_RF_CHANNEL {
+            return Err(TestModeError::InvalidChannel);
+        }
+
+        Ok(self.enter_test_mode(mode, rf_channel, dbm))
+    }
+
+    /// Stops the running test mode, if any, and disables the radio.
+    pub fn stop_test_mode(&mut self) {
+        if self.test_mode.is_some() {
+            self.stop();
+        }
+    }
+
+    /// Returns the test mode the radio is in, or `None` if it isn't in a test mode.
+    pub fn test_mode(&self) -> Option<TestMode> {
+        self.test_mode
+    }
+
+    /// Configures the radio for `mode` and starts it, after the interlocks have been checked.
+    fn enter_test_mode(&mut self, mode: TestMode, rf_channel: u8, dbm: i8) -> i8 {
+        self.power_on();
+        self.radio.intenclr.write(|w| w.disabled().clear());
+        if !self.state().is_disabled() {
+            self.stop();
+        }
+
+        let level = self.write_tx_power(dbm);
+        self.test_mode = Some(mode);
+        self.advertising = false;
+        self.radio
+            .frequency
+            .write(|w| unsafe { w.frequency().bits(2 + 2 * rf_channel) });
+
+        match mode {
+            // Without a `START` task, the radio stays in `TXIDLE` after ramp-up and transmits the
+            // carrier only
+            TestMode::UnmodulatedCarrier => self.radio.shorts.reset(),
+            TestMode::ModulatedCarrier => {
+                let len = Self::MAX_ADV_PAYLOAD;
+                self.tx_buf[0] = 0;
+                self.tx_buf[1] = len as u8;
+                prbs9(&mut self.tx_buf[2..2 + len]);
+                self.set_max_payload(len);
+
+                self.radio
+                    .txaddress
+                    .write(|w| unsafe { w.txaddress().bits(0) });
+                self.radio
+                    .packetptr
+                    .write(|w| unsafe { w.bits(self.tx_buf as *const _ as u32) });
+                // Send the packet over and over again
+                self.radio
+                    .shorts
+                    .write(|w| w.ready_start().enabled().end_start().enabled());
+            }
+        }
+
+        // "Preceding reads and writes cannot be moved past subsequent writes."
+        compiler_fence(Ordering::Release);
+
+        self.radio.tasks_txen.write(|w| unsafe { w.bits(1) });
+        level
+    }
+
     /// Configures the Radio for (not) receiving data according to `cmd`.
     pub fn configure_receiver(&mut self, cmd: RadioCmd) {
+        if let RadioCmd::Off = cmd {
+            if !self.powered {
+                return;
+            }
+        } else {
+            self.power_on();
+        }
+
         // Waits for the end of any ongoing transmissions. Don't wait if we lost the last connection
         // event, since we shouldn't be transmitting anyway
         if let RadioCmd::ListenData { timeout, .. } = cmd {
@@ -204,21 +627,20 @@ impl BleRadio {
         // Disable `DISABLED` interrupt, effectively stopping reception
         self.radio.intenclr.write(|w| w.disabled().clear());
 
-        // Acknowledge left-over disable event
-        self.radio.events_disabled.reset();
-        // Disable radio
-        self.radio.tasks_disable.write(|w| unsafe { w.bits(1) });
-        // Then wait until disable event is triggered
-        while self.radio.events_disabled.read().bits() == 0 {}
-        // And acknowledge it
-        self.radio.events_disabled.reset();
+        self.stop();
 
         match cmd {
-            RadioCmd::Off => {}
-            RadioCmd::ListenAdvertising { channel } => {
+            RadioCmd::Off => self.power_off(),
+            RadioCmd::ListenAdvertising {
+                channel,
+                peer_filter,
+            } => {
                 self.prepare_txrx_advertising(channel);
+                self.configure_address_match(peer_filter.as_ref());
 
+                self.rx_slot = None;
                 let rx_buf = (*self.rx_buf.as_mut().unwrap()) as *mut _ as u32;
+                self.set_max_payload(Self::MAX_ADV_PAYLOAD);
                 self.radio.packetptr.write(|w| unsafe { w.bits(rx_buf) });
 
                 // Enable `DISABLED` interrupt (packet fully received)
@@ -227,31 +649,31 @@ impl BleRadio {
                 // Match on logical address 0 only
                 self.radio.rxaddresses.write(|w| w.addr0().enabled());
 
-                // Enable the correct shortcuts in case it was changed in a previous connection.
-                self.radio
-                    .shorts
-                    .write(|w| w.ready_start().enabled().end_disable().enabled());
-
-                // "Preceding reads and writes cannot be moved past subsequent writes."
-                compiler_fence(Ordering::Release);
-
-                // ...and enter RX mode
-                self.radio.tasks_rxen.write(|w| unsafe { w.bits(1) });
+                self.listen_advertising();
             }
             RadioCmd::ListenData {
                 channel,
                 access_address,
                 crc_init,
+                rx_buf: rx_slot,
                 ..
             } => {
                 self.prepare_txrx_data(channel, access_address, crc_init);
-
-                // Enforce T_IFS in hardware.
-                self.radio
-                    .tifs
-                    .write(|w| unsafe { w.bits(Duration::T_IFS.as_micros()) });
-
-                let rx_buf = (*self.rx_buf.as_mut().unwrap()) as *mut _ as u32;
+                let turnaround = self.arm_rx_to_tx();
+
+                // If the Link-Layer provided storage in the RX queue, receive the packet directly
+                // into it. This saves copying the packet in the interrupt handler.
+                self.rx_slot = rx_slot.filter(|slot| slot.size() > 2);
+                let rx_buf = match self.rx_slot {
+                    Some(slot) => {
+                        self.set_max_payload(slot.size() - 2);
+                        slot.as_ptr() as u32
+                    }
+                    None => {
+                        self.set_max_payload(Self::MAX_PAYLOAD);
+                        (*self.rx_buf.as_mut().unwrap()) as *mut _ as u32
+                    }
+                };
                 self.radio.packetptr.write(|w| unsafe { w.bits(rx_buf) });
 
                 // Enable `DISABLED` interrupt (packet fully received)
@@ -272,7 +694,7 @@ impl BleRadio {
                     w.end_disable()
                         .enabled()
                         .disabled_txen()
-                        .enabled()
+                        .bit(!turnaround)
                         .ready_start()
                         .enabled()
                 });
@@ -280,15 +702,57 @@ impl BleRadio {
         }
     }
 
+    /// Handles pending radio and timer events without relying on interrupts.
+    ///
+    /// This does the work of both the `RADIO` and the timer interrupt handler, so that the stack
+    /// can be driven from a main loop: If a packet was received, it is passed to the Link-Layer
+    /// (like [`recv_interrupt`] does), otherwise the Link-Layer is updated if its timer expired.
+    /// The radio is then reconfigured according to the resulting `Cmd`, which is returned so that
+    /// the caller can process queued work. If nothing happened, `None` is returned.
+    ///
+    /// `now` is used as the time at which a packet was received, unless the timer captures it in
+    /// hardware (see `BleTimer::capture_radio_end`).
+    ///
+    /// Events are only handled when this is called, so it should be called continuously. Responses
+    /// have to be passed to the radio within about 150 µs after a packet was received (see the
+    /// module documentation), so any other work done between calls must be short.
+    ///
+    /// [`recv_interrupt`]: #method.recv_interrupt
+    pub fn poll<C, T, R>(&mut self, now: Instant, ll: &mut LinkLayer<C, R>) -> Option<Cmd>
+    where
+        C: Config<Transmitter = Self, Timer = BleTimer<T>>,
+        T: NrfTimerExt,
+        R: Role,
+    {
+        let timestamp = ll.timer().last_capture().unwrap_or(now);
+        if let Some(cmd) = self.recv_interrupt(timestamp, ll) {
+            self.configure_receiver(cmd.radio.clone());
+            return Some(cmd);
+        }
+
+        let timer = ll.timer();
+        if !timer.is_interrupt_pending() {
+            return None;
+        }
+        timer.clear_interrupt();
+
+        let cmd = ll.update_timer(self);
+        self.configure_receiver(cmd.radio.clone());
+        Some(cmd)
+    }
+
     /// Call this when the `RADIO` interrupt fires.
     ///
     /// Automatically reconfigures the radio according to the `RadioCmd` returned by the BLE stack.
     ///
+    /// `timestamp` is the time at which the packet was fully received. For best accuracy, use the
+    /// hardware timestamp provided by `BleTimer::last_radio_end`.
+    ///
     /// Returns when the `update` method should be called the next time.
-    pub fn recv_interrupt<C: Config<Transmitter = Self>>(
+    pub fn recv_interrupt<C: Config<Transmitter = Self>, R: Role>(
         &mut self,
         timestamp: Instant,
-        ll: &mut LinkLayer<C>,
+        ll: &mut LinkLayer<C, R>,
     ) -> Option<Cmd> {
         if self.radio.events_disabled.read().bits() == 0 {
             return None;
@@ -303,8 +767,24 @@ impl BleRadio {
         let crc_ok = self.radio.crcstatus.read().crcstatus().is_crcok();
 
         let cmd = if self.advertising {
-            // When we get here, the radio must have transitioned to DISABLED state.
-            assert!(self.state().is_disabled());
+            // The radio is turning around to TX. Don't let it start transmitting before a response
+            // is in place (`transmit_advertising` re-enables `READY_START`).
+            self.radio.shorts.write(|w| w.end_disable().enabled());
+            if let Some(turnaround) = &mut self.turnaround {
+                turnaround.disarm();
+            }
+
+            if self.address_match && self.radio.events_devmiss.read().bits() != 0 {
+                // The sender isn't in the peer filter. Drop the packet without bothering the
+                // Link-Layer and keep listening on the same channel.
+                self.radio.events_devmatch.reset();
+                self.radio.events_devmiss.reset();
+                self.stop();
+                self.listen_advertising();
+                return None;
+            }
+
+            self.response_pending = true;
 
             let header = advertising::Header::parse(*self.rx_buf.as_ref().unwrap());
 
@@ -314,26 +794,176 @@ impl BleRadio {
             let payload = &rx_buf[2..pl_lim];
             let cmd = ll.process_adv_packet(timestamp, self, header, payload, crc_ok);
             self.rx_buf = Some(rx_buf);
+
+            if mem::replace(&mut self.response_pending, false) {
+                // The Link-Layer didn't respond. Abort the turnaround.
+                self.stop();
+            }
             cmd
         } else {
             // Important! Turn ready->start off before TXREADY is reached (in ~150µs)
             self.radio.shorts.modify(|_, w| w.ready_start().disabled());
             assert!(!self.state().is_tx());
 
-            let header = data::Header::parse(*self.rx_buf.as_ref().unwrap());
+            // The turnaround to TX is already running. Make sure the end of our response doesn't
+            // start another one.
+            if let Some(turnaround) = &mut self.turnaround {
+                turnaround.disarm();
+            }
 
-            // check that `payload_length` is in bounds
-            let rx_buf = self.rx_buf.take().unwrap();
-            let pl_lim = cmp::min(2 + usize::from(header.payload_length()), rx_buf.len());
-            let payload = &rx_buf[2..pl_lim];
-            let cmd = ll.process_data_packet(timestamp, self, header, payload, crc_ok);
-            self.rx_buf = Some(rx_buf);
-            cmd
+            if let Some(slot) = self.rx_slot.take() {
+                // The packet was received into the RX queue. The slot stays valid until the
+                // Link-Layer commits it, and the queue's consumer doesn't access it before that.
+                let raw = unsafe { slice::from_raw_parts(slot.as_ptr() as *const u8, slot.size()) };
+                let header = data::Header::parse(raw);
+
+                // check that `payload_length` is in bounds
+                let pl_lim = cmp::min(2 + usize::from(header.payload_length()), raw.len());
+                let payload = &raw[2..pl_lim];
+                ll.process_data_packet(timestamp, self, header, payload, crc_ok)
+            } else {
+                let header = data::Header::parse(*self.rx_buf.as_ref().unwrap());
+
+                // check that `payload_length` is in bounds
+                let rx_buf = self.rx_buf.take().unwrap();
+                let pl_lim = cmp::min(2 + usize::from(header.payload_length()), rx_buf.len());
+                let payload = &rx_buf[2..pl_lim];
+                let cmd = ll.process_data_packet(timestamp, self, header, payload, crc_ok);
+                self.rx_buf = Some(rx_buf);
+                cmd
+            }
         };
 
         Some(cmd)
     }
 
+    /// Enforces `T_IFS` between the next received packet and the response to it.
+    ///
+    /// Returns whether the turnaround engine is used. If not, the `DISABLED_TXEN` shortcut must be
+    /// enabled.
+    fn arm_rx_to_tx(&mut self) -> bool {
+        if let Some(turnaround) = &mut self.turnaround {
+            turnaround.arm(TurnaroundDir::RxToTx, Duration::T_IFS);
+            true
+        } else {
+            self.radio
+                .tifs
+                .write(|w| unsafe { w.bits(Duration::T_IFS.as_micros()) });
+            false
+        }
+    }
+
+    /// Starts receiving on the configured advertising channel, turning around for a response after
+    /// a packet was received.
+    fn listen_advertising(&mut self) {
+        let turnaround = self.arm_rx_to_tx();
+        self.radio.shorts.write(|w| {
+            w.ready_start()
+                .enabled()
+                .end_disable()
+                .enabled()
+                .disabled_txen()
+                .bit(!turnaround)
+        });
+
+        // "Preceding reads and writes cannot be moved past subsequent writes."
+        compiler_fence(Ordering::Release);
+
+        // ...and enter RX mode
+        self.radio.tasks_rxen.write(|w| unsafe { w.bits(1) });
+    }
+
+    /// Lets the radio transmit the response in `tx_buf` once it finished ramping up.
+    ///
+    /// If ramp-up has already completed, `T_IFS` was missed. The response is then started
+    /// immediately and counted as late.
+    fn start_response(&mut self, end_disable: bool) {
+        // "Preceding reads and writes cannot be moved past subsequent writes."
+        compiler_fence(Ordering::Release);
+
+        self.radio
+            .shorts
+            .write(|w| w.ready_start().enabled().end_disable().bit(end_disable));
+
+        if self.state().is_tx_idle() {
+            self.radio.tasks_start.write(|w| unsafe { w.bits(1) });
+            self.late_responses = self.late_responses.wrapping_add(1);
+        }
+    }
+
+    /// Stops all radio activity, including pending turnarounds, and waits until the radio is
+    /// disabled.
+    fn stop(&mut self) {
+        // Make sure neither the shortcuts nor a pending turnaround restart the radio
+        self.radio.shorts.reset();
+        if let Some(turnaround) = &mut self.turnaround {
+            turnaround.cancel();
+        }
+        self.response_pending = false;
+        if self.test_mode.take().is_some() {
+            // Test modes transmit at their own power level
+            self.write_tx_power(self.tx_power);
+        }
+
+        // Acknowledge left-over disable event
+        self.radio.events_disabled.reset();
+        // Disable radio
+        self.radio.tasks_disable.write(|w| unsafe { w.bits(1) });
+        // Then wait until disable event is triggered
+        while self.radio.events_disabled.read().bits() == 0 {}
+        // And acknowledge it
+        self.radio.events_disabled.reset();
+    }
+
+    /// Programs the device address match registers with the addresses in `filter`.
+    ///
+    /// The radio compares the first 6 Bytes of the payload of every received advertising channel
+    /// PDU against the enabled addresses. For *Scan Requests* and *Connect Requests*, this is the
+    /// address of the sending device. Passing `None` disables address matching.
+    fn configure_address_match(&mut self, filter: Option<&PeerFilter>) {
+        self.radio.events_devmatch.reset();
+        self.radio.events_devmiss.reset();
+
+        let filter = match filter {
+            Some(filter) => filter,
+            None => {
+                self.address_match = false;
+                self.radio.dacnf.reset();
+                return;
+            }
+        };
+
+        let mut dacnf = 0;
+        for (i, address) in filter.addresses().iter().enumerate() {
+            let raw = address.raw();
+            self.radio.dab[i]
+                .write(|w| unsafe { w.bits(u32::from_le_bytes([raw[0], raw[1], raw[2], raw[3]])) });
+            self.radio.dap[i]
+                .write(|w| unsafe { w.bits(u32::from(u16::from_le_bytes([raw[4], raw[5]]))) });
+
+            // ENAn in bits 0-7, TXADDn in bits 8-15
+            dacnf |= 1 << i;
+            if address.is_random() {
+                dacnf |= 1 << (i + 8);
+            }
+        }
+        self.radio.dacnf.write(|w| unsafe { w.bits(dacnf) });
+        self.address_match = true;
+    }
+
+    /// Sets the maximum payload length the radio will receive.
+    ///
+    /// Longer packets are truncated to fit into the receive buffer.
+    fn set_max_payload(&mut self, max_payload: usize) {
+        let max_payload = cmp::min(max_payload, usize::from(u8::max_value())) as u8;
+        if self.cache.maxlen != Some(max_payload) {
+            self.radio
+                .pcnf1
+                .write(|w| unsafe { w.bits(pcnf1(max_payload)) });
+            self.cache.maxlen = Some(max_payload);
+        }
+    }
+
     /// Perform preparations to receive or send on an advertising channel.
     ///
     /// This will disable the radio, configure the packet layout, set initial values for CRC and
@@ -349,27 +979,15 @@ impl BleRadio {
     fn prepare_txrx_advertising(&mut self, channel: AdvertisingChannel) {
         self.advertising = true;
 
-        unsafe {
-            // Acknowledge left-over disable event
-            self.radio.events_disabled.reset();
-
-            if !self.state().is_disabled() {
-                // In case we're currently receiving, stop that
-                self.radio.tasks_disable.write(|w| w.bits(1));
-
-                // Then wait until disable event is triggered
-                while self.radio.events_disabled.read().bits() == 0 {}
-            }
+        if !self.state().is_disabled() {
+            // In case we're currently receiving, stop that
+            self.stop();
         }
 
         assert!(self.state().is_disabled());
 
         // Now we can freely configure all registers we need
         unsafe {
-            self.radio
-                .pcnf0
-                .write(|w| w.s0len().bit(true).lflen().bits(8).s1len().bits(0));
-
             self.radio
                 .datawhiteiv
                 .write(|w| w.datawhiteiv().bits(channel.whitening_iv()));
@@ -386,10 +1004,6 @@ impl BleRadio {
         self.advertising = false;
 
         unsafe {
-            self.radio
-                .pcnf0
-                .write(|w| w.s0len().bit(true).lflen().bits(8).s1len().bits(0));
-
             self.radio
                 .datawhiteiv
                 .write(|w| w.datawhiteiv().bits(channel.whitening_iv()));
@@ -400,11 +1014,14 @@ impl BleRadio {
                 .frequency
                 .write(|w| w.frequency().bits((channel.freq() - 2400) as u8));
 
-            // Address #1 is our data channel access address
-            self.radio.base1.write(|w| w.bits(access_address << 8));
-            self.radio
-                .prefix0
-                .write(|w| w.ap1().bits((access_address >> 24) as u8));
+            // Address #1 is our data channel access address. It only changes between connections.
+            if self.cache.data_address != Some(access_address) {
+                self.radio.base1.write(|w| w.bits(access_address << 8));
+                self.radio
+                    .prefix0
+                    .write(|w| w.bits(prefix0(access_address)));
+                self.cache.data_address = Some(access_address);
+            }
         }
     }
 
@@ -426,6 +1043,10 @@ impl BleRadio {
             // Acknowledge left-over disable event
             self.radio.events_disabled.reset(); // FIXME unnecessary, right?
 
+            self.radio
+                .shorts
+                .write(|w| w.ready_start().enabled().end_disable().enabled());
+
             // "Preceding reads and writes cannot be moved past subsequent writes."
             compiler_fence(Ordering::Release);
 
@@ -443,7 +1064,7 @@ impl BleRadio {
     }
 }
 
-impl Transmitter for BleRadio {
+impl<const N: usize> Transmitter for BleRadio<N> {
     fn tx_payload_buf(&mut self) -> &mut [u8] {
         // Wait for any ongoing transmissions
         while self.state().is_tx() {}
@@ -455,12 +1076,32 @@ impl Transmitter for BleRadio {
     }
 
     fn transmit_advertising(&mut self, header: advertising::Header, channel: AdvertisingChannel) {
+        self.power_on();
+
         let raw_header = header.to_u16();
         // S0 = 8 bits (LSB)
         self.tx_buf[0] = raw_header as u8;
         // Length = 6 bits, followed by 2 RFU bits (0)
         self.tx_buf[1] = header.payload_length();
 
+        if mem::replace(&mut self.response_pending, false) {
+            // Response to the PDU that was just received. The radio is already turning around on
+            // the right channel.
+            self.radio
+                .txaddress
+                .write(|w| unsafe { w.txaddress().bits(0) });
+            self.radio
+                .packetptr
+                .write(|w| unsafe { w.bits(self.tx_buf as *const _ as u32) });
+            self.start_response(true);
+
+            // Block until the response was sent, like `transmit` does.
+            while self.radio.events_disabled.read().bits() == 0 {}
+            // "Subsequent reads and writes cannot be moved ahead of preceding reads."
+            compiler_fence(Ordering::Acquire);
+            return;
+        }
+
         self.prepare_txrx_advertising(channel);
 
         // Set transmission address:
@@ -497,12 +1138,570 @@ impl Transmitter for BleRadio {
             .packetptr
             .write(|w| unsafe { w.bits(self.tx_buf as *const _ as u32) });
 
+        // ...and kick off the transmission
+        self.start_response(false);
+    }
+
+    fn set_tx_power(&mut self, dbm: i8) -> i8 {
+        BleRadio::<N>::set_tx_power(self, dbm)
+    }
+
+    fn tx_power_range(&self) -> Option<(i8, i8)> {
+        let highest = TX_POWER_LEVELS[0].0;
+        let lowest = TX_POWER_LEVELS[TX_POWER_LEVELS.len() - 1].0;
+        Some((lowest, highest))
+    }
+
+    fn encrypt_block(&mut self, key: &[u8; 16], block: &mut [u8; 16]) {
+        match &mut self.ecb {
+            Some(ecb) => ecb.encrypt_block(key, block),
+            None => SoftAes.encrypt_block(key, block),
+        }
+    }
+}
+
+/// Configures the radio registers for BLE operation.
+///
+/// This is needed after reset and after the radio was powered off.
+fn init_registers(
+    radio: &pac::radio::RegisterBlock,
+    ficr: &pac::ficr::RegisterBlock,
+    max_payload: u8,
+) {
+    // The nRF51 requires manually setting the trim values.
+    #[cfg(feature = "51")]
+    {
+        if ficr.overrideen.read().ble_1mbit().is_override() {
+            unsafe {
+                radio
+                    .override0
+                    .write(|w| w.override0().bits(ficr.ble_1mbit[0].read().bits()));
+                radio
+                    .override1
+                    .write(|w| w.override1().bits(ficr.ble_1mbit[1].read().bits()));
+                radio
+                    .override2
+                    .write(|w| w.override2().bits(ficr.ble_1mbit[2].read().bits()));
+                radio
+                    .override3
+                    .write(|w| w.override3().bits(ficr.ble_1mbit[3].read().bits()));
+                radio.override4.write(|w| {
+                    w.override4()
+                        .bits(ficr.ble_1mbit[4].read().bits())
+                        .enable()
+                        .set_bit()
+                });
+            }
+        }
+    }
+
+    // The nRF52/53 do not require setting trim values, but we take the `ficr` reference anyways
+    // to have a consistent interface. Silence the unused variable warning:
+    let _ = ficr;
+
+    radio.mode.write(|w| w.mode().ble_1mbit());
+
+    unsafe {
+        // The layout is the same for advertising and data channel PDUs. Bluetooth 4.0 and 4.1 data
+        // channel PDUs have a 5-bit length field followed by 3 RFU bits. Since the Packet Length
+        // Extension uses all 8 bits, treat them as one field.
+        radio
+            .pcnf0
+            .write(|w| w.s0len().bit(true).lflen().bits(8).s1len().bits(0));
+
+        radio.pcnf1.write(|w| w.bits(pcnf1(max_payload)));
+
+        radio.crccnf.write(|w| {
+            // skip address since only the S0, Length, S1 and Payload need CRC
+            // 3 Bytes = CRC24
+            w.skipaddr().skip().len().three()
+        });
+
+        radio
+            .crcpoly
+            .write(|w| w.crcpoly().bits(CRC_POLY & 0x00FFFFFF));
+
+        // Configure logical address 0 as the canonical advertising address.
+        // Base addresses are up to 32 bits in size. However, an 8 bit Address Prefix is
+        // *always* appended, so we must use a 24 bit Base Address and the 8 bit Prefix.
+        // BASE0 has, apparently, undocumented semantics: It is a proper 32-bit register, but
+        // it ignores the *lowest* 8 bit and instead transmits the upper 24 as the low 24 bits
+        // of the Access Address. Shift address up to fix this.
+        radio
+            .base0
+            .write(|w| w.bits(advertising::ACCESS_ADDRESS << 8));
+        radio.prefix0.write(|w| w.bits(prefix0(0)));
+    }
+
+    // FIXME: No TIFS hardware support for now. Revisit when precise semantics are clear.
+    // Activate END_DISABLE and DISABLED_TXEN shortcuts so TIFS is enforced. We might enable
+    // more shortcuts later.
+    /*radio.shorts.write(|w| w
+        .end_disable().enabled()
+        .disabled_txen().enabled()
+    );*/
+
+    /*unsafe {
+        radio.tifs.write(|w| w.tifs().bits(BLE_TIFS));
+    }*/
+
+    // Configure shortcuts to simplify and speed up sending and receiving packets.
+    radio.shorts.write(|w| {
+        // start transmission/recv immediately after ramp-up
+        // disable radio when transmission/recv is done
+        w.ready_start().enabled().end_disable().enabled()
+    });
+}
+
+/// Fills `buf` with the PRBS9 sequence (`x^9 + x^5 + 1`, seeded with all ones).
+///
+/// Bits are sent LSB first, like the *Direct Test Mode* payloads of the Bluetooth specification.
+fn prbs9(buf: &mut [u8]) {
+    let mut state: u16 = 0x1FF;
+    for byte in buf {
+        let mut value = 0;
+        for bit in 0..8 {
+            let out = state & 1;
+            value |= (out as u8) << bit;
+            let feedback = (state ^ (state >> 4)) & 1;
+            state = (state >> 1) | feedback << 8;
+        }
+        *byte = value;
+    }
+}
+
+/// Computes the value of the `PCNF1` register for receiving payloads of up to `max_payload` Bytes.
+const fn pcnf1(max_payload: u8) -> u32 {
+    // MAXLEN = `max_payload`, STATLEN = 0
+    // BALEN = 3 (3-Byte Base Address + 1-Byte Address Prefix)
+    // ENDIAN = little, WHITEEN = 1 (Data Whitening over PDU+CRC)
+    max_payload as u32 | 3 << 16 | 1 << 25
+}
+
+/// Computes the value of the `PREFIX0` register.
+///
+/// Logical address 0 is the advertising channel access address, and logical address 1 is
+/// `data_address`. Both prefixes live in the same register, so both are written at once.
+const fn prefix0(data_address: u32) -> u32 {
+    (advertising::ACCESS_ADDRESS >> 24) | (data_address >> 24) << 8
+}
+
+/// Time it takes the radio to ramp up after `TXEN`, in µs.
+///
+/// Taken from the product specifications. This is the default ramp-up mode on the nRF52, and the
+/// only one available on the nRF51.
+const TX_RAMP_UP_MICROS: u32 = 140;
+
+/// Time it takes the radio to ramp up after `RXEN`, in µs.
+#[cfg(feature = "51")]
+const RX_RAMP_UP_MICROS: u32 = 138;
+
+/// Time it takes the radio to ramp up after `RXEN`, in µs.
+#[cfg(not(feature = "51"))]
+const RX_RAMP_UP_MICROS: u32 = 140;
+
+/// Direction of a radio turnaround.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum TurnaroundDir {
+    /// Start transmitting after a packet was received.
+    RxToTx,
+
+    /// Start receiving after a packet was transmitted.
+    TxToRx,
+}
+
+/// PPI and `TIMER` based engine that starts a radio operation with µs accuracy after the end of
+/// the previous one.
+///
+/// When armed, the radio's `END` event starts the timer, and the timer's `COMPARE[0]` event
+/// triggers `TXEN` or `RXEN`, compensating for the radio's ramp-up time. The timer stops itself
+/// afterwards, so every arming results in at most one turnaround.
+///
+/// The engine takes ownership of a `TIMER` peripheral and uses 2 PPI channels, which must not be
+/// used by anything else.
+pub struct Turnaround {
+    timer: &'static timer0::RegisterBlock,
+    ppi: &'static ppi::RegisterBlock,
+
+    /// PPI channel connecting `RADIO.EVENTS_END` to `TIMER.TASKS_START`.
+    start_ch: usize,
+
+    /// PPI channel connecting `TIMER.EVENTS_COMPARE[0]` to `RADIO.TASKS_TXEN`/`TASKS_RXEN`.
+    trigger_ch: usize,
+}
+
+// Like the PAC's peripheral types, the engine owns its registers and may be moved between
+// execution contexts.
+unsafe impl Send for Turnaround {}
+
+impl Turnaround {
+    /// Creates a turnaround engine using `timer` and the PPI channels `channels`.
+    ///
+    /// The engine is initially disarmed.
+    pub fn new<T: TurnaroundTimer>(timer: T, ppi: &PPI, channels: [usize; 2]) -> Self {
+        let [start_ch, trigger_ch] = channels;
+        assert!(start_ch != trigger_ch);
+        assert!(start_ch < ppi.ch.len() && trigger_ch < ppi.ch.len());
+
+        // We own the timer now. The PPI channels are exclusively ours by contract, and the enable
+        // registers are write-1-to-set/clear, so other channels aren't affected by our writes.
+        let _ = timer;
+        let timer = unsafe { &*T::ptr() };
+        let ppi = unsafe { &*PPI::ptr() };
+        let radio = unsafe { &*RADIO::ptr() };
+
+        timer.tasks_stop.write(|w| unsafe { w.bits(1) });
+        timer.mode.write(|w| w.mode().timer());
+        timer.bitmode.write(|w| w.bitmode()._16bit());
+        // 16 MHz / 2^4 = 1 MHz = µs resolution
+        timer.prescaler.write(|w| unsafe { w.prescaler().bits(4) });
+        timer
+            .shorts
+            .write(|w| w.compare0_clear().enabled().compare0_stop().enabled());
+        timer.tasks_clear.write(|w| unsafe { w.bits(1) });
+
+        let this = Self {
+            timer,
+            ppi,
+            start_ch,
+            trigger_ch,
+        };
+        this.ppi
+            .chenclr
+            .write(|w| unsafe { w.bits(this.channel_mask()) });
+
+        unsafe {
+            let start = &this.ppi.ch[start_ch];
+            start
+                .eep
+                .write(|w| w.bits(&radio.events_end as *const _ as u32));
+            start
+                .tep
+                .write(|w| w.bits(&timer.tasks_start as *const _ as u32));
+
+            let trigger = &this.ppi.ch[trigger_ch];
+            trigger
+                .eep
+                .write(|w| w.bits(&timer.events_compare[0] as *const _ as u32));
+        }
+
+        this
+    }
+
+    /// Arms the engine so that the next `END` event of the radio starts a turnaround.
+    ///
+    /// The radio will be enabled in the direction given by `dir`, so that it is ready to transmit
+    /// or receive `gap` after the end of the current packet. The radio must be configured to
+    /// disable itself at the end of the packet (`END_DISABLE` shortcut) and to start after ramp-up
+    /// (`READY_START` shortcut).
+    ///
+    /// For `TxToRx`, `gap` should leave some margin before the peer's packet starts.
+    pub fn arm(&mut self, dir: TurnaroundDir, gap: Duration) {
+        self.cancel();
+
+        let radio = unsafe { &*RADIO::ptr() };
+        let (task, ramp_up) = match dir {
+            TurnaroundDir::RxToTx => (&radio.tasks_txen as *const _ as u32, TX_RAMP_UP_MICROS),
+            TurnaroundDir::TxToRx => (&radio.tasks_rxen as *const _ as u32, RX_RAMP_UP_MICROS),
+        };
+
+        // A compare value of 0 would only match after the counter wraps around
+        let delay = cmp::max(gap.as_micros().saturating_sub(ramp_up), 1);
+        self.timer.cc[0].write(|w| unsafe { w.bits(delay) });
+        self.timer.events_compare[0].reset();
+        self.ppi.ch[self.trigger_ch]
+            .tep
+            .write(|w| unsafe { w.bits(task) });
+
         // "Preceding reads and writes cannot be moved past subsequent writes."
         compiler_fence(Ordering::Release);
 
-        // ...and kick off the transmission
-        self.radio
-            .shorts
-            .write(|w| w.ready_start().enabled().end_disable().disabled());
+        self.ppi
+            .chenset
+            .write(|w| unsafe { w.bits(self.channel_mask()) });
+    }
+
+    /// Prevents further `END` events from starting a turnaround.
+    ///
+    /// A turnaround that is already in progress still completes.
+    pub fn disarm(&mut self) {
+        self.ppi
+            .chenclr
+            .write(|w| unsafe { w.bits(1 << self.start_ch) });
+    }
+
+    /// Disarms the engine and aborts any turnaround in progress.
+    pub fn cancel(&mut self) {
+        self.ppi
+            .chenclr
+            .write(|w| unsafe { w.bits(self.channel_mask()) });
+        self.timer.tasks_stop.write(|w| unsafe { w.bits(1) });
+        self.timer.tasks_clear.write(|w| unsafe { w.bits(1) });
+    }
+
+    fn channel_mask(&self) -> u32 {
+        1 << self.start_ch | 1 << self.trigger_ch
+    }
+}
+
+mod sealed {
+    pub trait Sealed {}
+}
+
+/// Trait implemented by the `TIMER` peripherals usable by a [`Turnaround`] engine.
+///
+/// The timer must not be the one used by the `BleTimer`.
+///
+/// [`Turnaround`]: struct.Turnaround.html
+pub trait TurnaroundTimer: sealed::Sealed {
+    /// Returns a pointer to the timer's register block.
+    fn ptr() -> *const timer0::RegisterBlock;
+}
+
+macro_rules! impl_turnaround_timer {
+    ( $($ty:ty),+ ) => {
+        $(
+            impl TurnaroundTimer for $ty {
+                fn ptr() -> *const timer0::RegisterBlock {
+                    <$ty>::ptr()
+                }
+            }
+
+            impl sealed::Sealed for $ty {}
+        )+
+    };
+}
+
+impl_turnaround_timer!(pac::TIMER0, pac::TIMER1, pac::TIMER2);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rubble::link::{AddressKind, DeviceAddress};
+
+    /// Value of the `STATE` register in `TXRU` state.
+    const STATE_TXRU: u32 = 9;
+    /// Value of the `STATE` register in `TXIDLE` state.
+    const STATE_TXIDLE: u32 = 10;
+
+    /// Allocates a zero-initialized register block in RAM.
+    ///
+    /// The register blocks consist solely of registers, for which all-zeroes is a valid value.
+    fn mock_registers<T>() -> &'static T {
+        Box::leak(Box::new(unsafe { mem::zeroed::<T>() }))
+    }
+
+    /// Reads a register, including write-only ones.
+    fn peek<T>(reg: &T) -> u32 {
+        unsafe { (reg as *const T as *const u32).read_volatile() }
+    }
+
+    /// Writes a register, including read-only ones.
+    fn poke<T>(reg: &T, value: u32) {
+        unsafe { (reg as *const T as *mut u32).write_volatile(value) }
+    }
+
+    /// Creates a `BleRadio` operating on mock registers.
+    fn mock_radio() -> BleRadio {
+        let peripheral = unsafe { pac::Peripherals::steal() }.RADIO;
+        BleRadio::with_registers(
+            peripheral,
+            mock_registers(),
+            mock_registers(),
+            Box::leak(Box::new([0; MIN_PDU_BUF])),
+            Box::leak(Box::new([0; MIN_PDU_BUF])),
+        )
+    }
+
+    #[test]
+    fn packet_config() {
+        let radio = mock_radio();
+        let regs = radio.radio;
+
+        let pcnf0 = regs.pcnf0.read();
+        assert!(pcnf0.s0len().bit());
+        assert_eq!(pcnf0.lflen().bits(), 8);
+        assert_eq!(pcnf0.s1len().bits(), 0);
+
+        let pcnf1 = regs.pcnf1.read();
+        assert_eq!(
+            usize::from(pcnf1.maxlen().bits()),
+            BleRadio::<MIN_PDU_BUF>::MAX_PAYLOAD
+        );
+        assert_eq!(pcnf1.statlen().bits(), 0);
+        assert_eq!(pcnf1.balen().bits(), 3);
+        assert!(pcnf1.endian().is_little());
+        assert!(pcnf1.whiteen().bit());
+
+        assert!(regs.crccnf.read().len().is_three());
+        assert!(regs.crccnf.read().skipaddr().is_skip());
+        assert_eq!(regs.crcpoly.read().bits(), CRC_POLY & 0x00FFFFFF);
+        assert_eq!(regs.base0.read().bits(), advertising::ACCESS_ADDRESS << 8);
+        assert_eq!(
+            u32::from(regs.prefix0.read().ap0().bits()),
+            advertising::ACCESS_ADDRESS >> 24
+        );
+        assert!(regs.mode.read().mode().is_ble_1mbit());
+        assert_eq!(regs.txpower.read().bits(), 4);
+    }
+
+    #[test]
+    fn max_payload_cached() {
+        let mut radio = mock_radio();
+
+        radio.set_max_payload(37);
+        assert_eq!(radio.radio.pcnf1.read().maxlen().bits(), 37);
+        assert_eq!(radio.radio.pcnf1.read().balen().bits(), 3);
+
+        // Unchanged values are not written again
+        poke(&radio.radio.pcnf1, 0);
+        radio.set_max_payload(37);
+        assert_eq!(radio.radio.pcnf1.read().bits(), 0);
+
+        radio.set_max_payload(300);
+        assert_eq!(radio.radio.pcnf1.read().maxlen().bits(), 255);
+    }
+
+    #[test]
+    fn data_channel_config() {
+        let mut radio = mock_radio();
+        let channel = DataChannel::new(17);
+        let access_address = 0x50654A8D;
+
+        radio.prepare_txrx_data(channel, access_address, 0xAB123456);
+        let regs = radio.radio;
+        assert!(!radio.advertising);
+        assert_eq!(regs.base1.read().bits(), access_address << 8);
+        assert_eq!(regs.prefix0.read().ap1().bits(), 0x50);
+        // The advertising prefix must be retained
+        assert_eq!(
+            u32::from(regs.prefix0.read().ap0().bits()),
+            advertising::ACCESS_ADDRESS >> 24
+        );
+        assert_eq!(regs.crcinit.read().bits(), 0x00123456);
+        assert_eq!(
+            u16::from(regs.frequency.read().frequency().bits()),
+            channel.freq() - 2400
+        );
+        assert_eq!(
+            regs.datawhiteiv.read().datawhiteiv().bits(),
+            channel.whitening_iv()
+        );
+
+        // The access address is only written when it changes
+        poke(&regs.base1, 0);
+        radio.prepare_txrx_data(DataChannel::new(3), access_address, 0x123456);
+        assert_eq!(regs.base1.read().bits(), 0);
+        radio.prepare_txrx_data(DataChannel::new(3), 0x71764129, 0x123456);
+        assert_eq!(regs.base1.read().bits(), 0x71764129 << 8);
+        assert_eq!(regs.prefix0.read().ap1().bits(), 0x71);
+    }
+
+    #[test]
+    fn advertising_listen_shortcuts() {
+        let mut radio = mock_radio();
+        radio.listen_advertising();
+
+        let regs = radio.radio;
+        let shorts = regs.shorts.read();
+        assert!(shorts.ready_start().is_enabled());
+        assert!(shorts.end_disable().is_enabled());
+        assert!(shorts.disabled_txen().is_enabled());
+        assert_eq!(regs.tifs.read().bits(), Duration::T_IFS.as_micros());
+        assert_eq!(peek(&regs.tasks_rxen), 1);
+    }
+
+    #[test]
+    fn response_timing() {
+        let mut radio = mock_radio();
+        let regs = radio.radio;
+
+        // Still ramping up: the `READY_START` shortcut starts the transmission in time
+        poke(&regs.state, STATE_TXRU);
+        radio.start_response(true);
+        assert!(regs.shorts.read().ready_start().is_enabled());
+        assert!(regs.shorts.read().end_disable().is_enabled());
+        assert_eq!(peek(&regs.tasks_start), 0);
+        assert_eq!(radio.late_responses(), 0);
+
+        // Ramp-up already completed: start manually and count the miss
+        poke(&regs.state, STATE_TXIDLE);
+        radio.start_response(false);
+        assert!(regs.shorts.read().end_disable().is_disabled());
+        assert_eq!(peek(&regs.tasks_start), 1);
+        assert_eq!(radio.late_responses(), 1);
+    }
+
+    #[test]
+    fn address_match() {
+        let mut radio = mock_radio();
+        let filter = PeerFilter::from_slice(&[
+            DeviceAddress::new([0x01, 0x02, 0x03, 0x04, 0x05, 0x06], AddressKind::Public),
+            DeviceAddress::new([0x11, 0x12, 0x13, 0x14, 0x15, 0xC6], AddressKind::Random),
+        ])
+        .unwrap();
+
+        radio.configure_address_match(Some(&filter));
+        let regs = radio.radio;
+        assert!(radio.address_match);
+        assert_eq!(regs.dab[0].read().bits(), 0x04030201);
+        assert_eq!(regs.dap[0].read().bits(), 0x0605);
+        assert_eq!(regs.dab[1].read().bits(), 0x14131211);
+        assert_eq!(regs.dap[1].read().bits(), 0xC615);
+        // ENA0, ENA1 and TXADD1
+        assert_eq!(regs.dacnf.read().bits(), 0b10_0000_0011);
+
+        radio.configure_address_match(None);
+        assert!(!radio.address_match);
+        assert_eq!(regs.dacnf.read().bits(), 0);
+    }
+
+    #[test]
+    fn tx_power() {
+        let mut radio = mock_radio();
+        assert_eq!(radio.set_tx_power(-5), -8);
+        assert_eq!(radio.radio.txpower.read().bits(), 248);
+        assert_eq!(radio.tx_power(), -8);
+        assert_eq!(radio.set_tx_power(100), TX_POWER_LEVELS[0].0);
+    }
+
+    #[test]
+    fn prbs9_sequence() {
+        let mut buf = [0; 8];
+        prbs9(&mut buf);
+        assert_eq!(buf, [0xFF, 0xC1, 0xFB, 0xE8, 0x4C, 0x90, 0x72, 0x8B]);
+    }
+
+    #[test]
+    fn test_modes() {
+        let mut radio = mock_radio();
+        let regs = radio.radio;
+        radio.set_tx_power(0);
+
+        assert_eq!(
+            radio.enter_test_mode(TestMode::UnmodulatedCarrier, 39, -5),
+            -8
+        );
+        assert_eq!(radio.test_mode(), Some(TestMode::UnmodulatedCarrier));
+        assert_eq!(regs.frequency.read().frequency().bits(), 80);
+        assert_eq!(regs.txpower.read().bits(), 248);
+        assert_eq!(regs.shorts.read().bits(), 0);
+        assert_eq!(peek(&regs.tasks_txen), 1);
+        // The configured power is kept for the Link-Layer
+        assert_eq!(radio.tx_power(), 0);
+
+        poke(&regs.tasks_txen, 0);
+        radio.enter_test_mode(TestMode::ModulatedCarrier, 0, 4);
+        assert_eq!(regs.frequency.read().frequency().bits(), 2);
+        assert!(regs.shorts.read().ready_start().is_enabled());
+        assert!(regs.shorts.read().end_start().is_enabled());
+        assert!(regs.shorts.read().end_disable().is_disabled());
+        assert_eq!(
+            regs.packetptr.read().bits(),
+            radio.tx_buf as *const _ as u32
+        );
+        assert_eq!(radio.tx_buf[1], 37);
+        assert_eq!(radio.tx_buf[2..4], [0xFF, 0xC1]);
+        assert_eq!(peek(&regs.tasks_txen), 1);
     }
 }
@@ -0,0 +1,63 @@
+//! Cryptographically secure random number generation using the RNG peripheral.
+//!
+//! The RNG peripheral generates random numbers from thermal noise. Bias correction is always
+//! enabled, which makes the generated numbers uniformly distributed at the cost of lower
+//! throughput, as required by the Bluetooth specification.
+
+use crate::pac::RNG;
+use rand_core::{impls, CryptoRng, Error, RngCore};
+
+/// A `RngCore` implementation backed by the RNG peripheral.
+///
+/// This is suitable for use as `Config::Rng`.
+pub struct HwRng {
+    rng: RNG,
+}
+
+impl HwRng {
+    /// Creates a new `HwRng` from the RNG peripheral and starts generating random numbers.
+    pub fn new(rng: RNG) -> Self {
+        rng.intenclr.write(|w| w.valrdy().clear());
+        rng.shorts.reset();
+        rng.config.write(|w| w.dercen().enabled());
+        rng.events_valrdy.reset();
+        rng.tasks_start.write(|w| unsafe { w.bits(1) });
+        Self { rng }
+    }
+
+    /// Stops random number generation and returns the RNG peripheral.
+    pub fn free(self) -> RNG {
+        self.rng.tasks_stop.write(|w| unsafe { w.bits(1) });
+        self.rng
+    }
+
+    /// Waits for the next random Byte from the peripheral.
+    fn next_byte(&mut self) -> u8 {
+        while self.rng.events_valrdy.read().bits() == 0 {}
+        self.rng.events_valrdy.reset();
+        self.rng.value.read().value().bits()
+    }
+}
+
+impl RngCore for HwRng {
+    fn next_u32(&mut self) -> u32 {
+        impls::next_u32_via_fill(self)
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        impls::next_u64_via_fill(self)
+    }
+
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        for b in dest {
+            *b = self.next_byte();
+        }
+    }
+
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), Error> {
+        self.fill_bytes(dest);
+        Ok(())
+    }
+}
+
+impl CryptoRng for HwRng {}
@@ -4,7 +4,7 @@ use crate::pac;
 use core::mem;
 use rubble::{
     link::NextUpdate,
-    time::{Instant, Timer},
+    time::{ExtendedInstant, Instant, Rollover, Timer},
 };
 
 /// Implements Rubble's `Timer` trait for the timers on the nRF chip.
@@ -14,6 +14,8 @@ pub struct BleTimer<T: NrfTimerExt> {
     inner: T,
     next: Instant,
     interrupt_enabled: bool,
+    capturing: bool,
+    rollover: Rollover,
 }
 
 impl<T: NrfTimerExt> BleTimer<T> {
@@ -24,28 +26,26 @@ impl<T: NrfTimerExt> BleTimer<T> {
             inner: peripheral,
             next: Instant::from_raw_micros(0),
             interrupt_enabled: false,
+            capturing: false,
+            rollover: Rollover::new(),
         }
     }
 
     /// Configures the timer interrupt to fire according to `next`.
+    ///
+    /// The `LinkLayer` already schedules its timer events via `Timer::schedule_at`, so this is only
+    /// needed for other users of `Cmd`s, like `rubble::beacon::Beacon`.
     pub fn configure_interrupt(&mut self, next: NextUpdate) {
         match next {
             NextUpdate::Keep => {
                 // Don't call `set_interrupt` when the interrupt is already configured, since that
                 // might result in races (it resets the event)
                 if !self.interrupt_enabled {
-                    self.inner.set_interrupt(self.next);
-                    self.interrupt_enabled = true;
+                    self.schedule_at(self.next);
                 }
             }
-            NextUpdate::Disable => {
-                self.inner.clear_interrupt();
-                self.interrupt_enabled = false;
-            }
-            NextUpdate::At(instant) => {
-                self.inner.set_interrupt(instant);
-                self.interrupt_enabled = true;
-            }
+            NextUpdate::Disable => self.cancel(),
+            NextUpdate::At(instant) => self.schedule_at(instant),
         }
     }
 
@@ -64,6 +64,7 @@ impl<T: NrfTimerExt> BleTimer<T> {
     /// Clears a pending interrupt and disables generation of further interrupts.
     pub fn clear_interrupt(&mut self) {
         self.inner.clear_interrupt();
+        self.interrupt_enabled = false;
     }
 
     /// Provides access to the raw peripheral. Use with caution.
@@ -71,6 +72,36 @@ impl<T: NrfTimerExt> BleTimer<T> {
         &mut self.inner
     }
 
+    /// Makes the timer capture the time at which the radio finishes sending or receiving a packet.
+    ///
+    /// This connects `RADIO.EVENTS_END` to the timer's `TASKS_CAPTURE[2]` using PPI channel
+    /// `channel`, which must not be used by anything else. Afterwards, [`last_radio_end`] returns
+    /// the exact end of the last packet, which should be passed to `BleRadio::recv_interrupt`
+    /// instead of the current time. That avoids the jitter introduced by interrupt latency and
+    /// improves the accuracy of the connection's anchor points.
+    ///
+    /// [`last_radio_end`]: #method.last_radio_end
+    pub fn capture_radio_end(&mut self, ppi: &pac::PPI, channel: usize) {
+        let radio = unsafe { &*pac::RADIO::ptr() };
+        let ch = &ppi.ch[channel];
+        unsafe {
+            ch.eep
+                .write(|w| w.bits(&radio.events_end as *const _ as u32));
+            ch.tep.write(|w| w.bits(self.inner.capture_task()));
+            ppi.chenset.write(|w| w.bits(1 << channel));
+        }
+        self.capturing = true;
+    }
+
+    /// Returns the time at which the radio last finished sending or receiving a packet.
+    ///
+    /// Only meaningful after [`capture_radio_end`] was called.
+    ///
+    /// [`capture_radio_end`]: #method.capture_radio_end
+    pub fn last_radio_end(&self) -> Instant {
+        self.inner.captured()
+    }
+
     /// Creates a new `StampSource` using this timer.
     ///
     /// The `StampSource` can be used to obtain the current time, but can not do anything else. This
@@ -84,16 +115,44 @@ impl<T: NrfTimerExt> BleTimer<T> {
 
 impl<T: NrfTimerExt> Timer for BleTimer<T> {
     fn now(&self) -> Instant {
-        self.inner.now()
+        // Feed every reading to the rollover counter, so that wraparounds are detected as long as
+        // the stack is active
+        let now = self.inner.now();
+        self.rollover.extend(now);
+        now
+    }
+
+    fn schedule_at(&mut self, at: Instant) {
+        self.inner.set_interrupt(at);
+        self.next = at;
+        self.interrupt_enabled = true;
+    }
+
+    fn cancel(&mut self) {
+        self.clear_interrupt();
+    }
+
+    fn last_capture(&self) -> Option<Instant> {
+        if self.capturing {
+            Some(self.last_radio_end())
+        } else {
+            None
+        }
+    }
+
+    fn now_extended(&self) -> Option<ExtendedInstant> {
+        Some(self.rollover.extend(self.inner.now()))
     }
 }
 
 /// A timer interface that only allows reading the current time stamp.
+///
+/// Wrap it in an `ExtendedStampSource` to obtain timestamps that don't wrap around.
 pub struct StampSource<T: NrfTimerExt> {
     inner: T,
 }
 
-impl<T: NrfTimerExt> Timer for StampSource<T> {
+impl<T: NrfTimerExt> rubble::time::StampSource for StampSource<T> {
     fn now(&self) -> Instant {
         self.inner.now()
     }
@@ -105,7 +164,8 @@ mod sealed {
 
 /// Extension trait implemented for the nRF timer peripherals.
 ///
-/// We use `CC[0]` to read the counter value, and `CC[1]` to set timer interrupts.
+/// We use `CC[0]` to read the counter value, `CC[1]` to set timer interrupts, and `CC[2]` to
+/// capture the end of radio packets.
 pub trait NrfTimerExt: sealed::Sealed {
     unsafe fn duplicate(&self) -> Self;
 
@@ -125,6 +185,12 @@ pub trait NrfTimerExt: sealed::Sealed {
 
     /// Obtains the current time as an `Instant`.
     fn now(&self) -> Instant;
+
+    /// Returns the address of the task capturing the counter value into `CC[2]`.
+    fn capture_task(&self) -> u32;
+
+    /// Returns the counter value last captured into `CC[2]`.
+    fn captured(&self) -> Instant;
 }
 
 macro_rules! impl_timer {
@@ -168,6 +234,14 @@ macro_rules! impl_timer {
                     let micros = self.cc[0].read().bits();
                     Instant::from_raw_micros(micros)
                 }
+
+                fn capture_task(&self) -> u32 {
+                    &self.tasks_capture[2] as *const _ as u32
+                }
+
+                fn captured(&self) -> Instant {
+                    Instant::from_raw_micros(self.cc[2].read().bits())
+                }
             }
 
             impl sealed::Sealed for $ty {}
@@ -6,6 +6,10 @@ use rubble::link::{AddressKind, DeviceAddress};
 
 /// Return the `DeviceAddress`, which is pre-programmed in the device FICR
 /// (Factory information configuration registers).
+///
+/// If the FICR contains a random address, its 2 most significant bits are set to `0b11`, as
+/// required for random static addresses (the factory-programmed value is fully random). Use
+/// `rubble::link::StaticAddress` and `NonResolvableAddress` to generate other random addresses.
 pub fn get_device_address() -> DeviceAddress {
     // FICR is read-only, so accessing it directly should be safe
     let ficr = unsafe { &*pac::FICR::ptr() };
@@ -20,7 +24,10 @@ pub fn get_device_address() -> DeviceAddress {
     // Address type
     let devaddr_type = match ficr.deviceaddrtype.read().deviceaddrtype().variant() {
         DEVICEADDRTYPE_A::PUBLIC => AddressKind::Public,
-        DEVICEADDRTYPE_A::RANDOM => AddressKind::Random,
+        DEVICEADDRTYPE_A::RANDOM => {
+            devaddr[5] |= 0xC0;
+            AddressKind::Random
+        }
     };
 
     DeviceAddress::new(devaddr, devaddr_type)
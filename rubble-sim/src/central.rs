@@ -0,0 +1,651 @@
+//! A scripted BLE central.
+
+use crate::AirPacket;
+use rubble::bytes::{ByteReader, ByteWriter, FromBytes, ToBytes};
+use rubble::l2cap::Channel;
+use rubble::link::{
+    advertising::{self, Pdu, PduType},
+    ccm::{Ccm, MIC_LEN},
+    data::{self, Llid},
+    llcp::ControlPdu,
+    AddressKind, DeviceAddress, SeqNum, MIN_DATA_PAYLOAD_BUF,
+};
+use rubble::phy::DataChannel;
+use rubble::security::{AesProvider, SoftAes};
+use rubble::time::{Duration, Instant};
+use std::collections::VecDeque;
+use std::fmt;
+
+/// Number of connection events after which a connection that never received a packet is
+/// considered to have failed to be established.
+const ESTABLISHMENT_EVENTS: u32 = 6;
+
+/// Airtime of a data channel packet with a 27-Byte payload on the LE 1M PHY.
+const MAX_DATA_AIRTIME: Duration = Duration::from_micros((1 + 4 + 2 + 27 + 3) * 8);
+
+/// The master's part of the session key diversifier (`SKDm`) sent in `LL_ENC_REQ`.
+const SKD_M: u64 = 0xACBD_CEDF_E0F1_0213;
+
+/// The master's part of the initialization vector (`IVm`) sent in `LL_ENC_REQ`.
+const IV_M: u32 = 0xBADC_AB24;
+
+/// Connection parameters sent in the `CONNECT_REQ` by a [`Central`].
+///
+/// [`Central`]: struct.Central.html
+#[derive(Debug, Copy, Clone)]
+pub struct ConnectParams {
+    /// Access Address of the connection.
+    pub access_address: u32,
+
+    /// Initial value of the CRC shift register (24 bits).
+    pub crc_init: u32,
+
+    /// Connection interval, must be a multiple of 1.25 ms.
+    pub interval: Duration,
+
+    /// Transmit window offset in units of 1.25 ms.
+    pub window_offset: u16,
+
+    /// Transmit window size in units of 1.25 ms.
+    pub window_size: u8,
+
+    /// Slave latency in connection events.
+    pub slave_latency: u16,
+
+    /// Supervision timeout, must be a multiple of 10 ms.
+    pub supervision_timeout: Duration,
+
+    /// Channel hop increment (5-16).
+    pub hop: u8,
+}
+
+impl Default for ConnectParams {
+    fn default() -> Self {
+        Self {
+            access_address: 0x50C6_5A17,
+            crc_init: 0x55_5555,
+            interval: Duration::from_micros(7_500),
+            window_offset: 0,
+            window_size: 1,
+            slave_latency: 0,
+            supervision_timeout: Duration::from_millis(1000),
+            hop: 7,
+        }
+    }
+}
+
+#[derive(Debug)]
+enum State {
+    Idle,
+    Initiating(ConnectParams),
+    Connected(Conn),
+}
+
+#[derive(Debug)]
+struct Conn {
+    params: ConnectParams,
+
+    /// Anchor point of the next connection event.
+    anchor: Instant,
+
+    /// Unmapped channel index of the last connection event.
+    unmapped_channel: u8,
+
+    /// Channel used in the current connection event.
+    channel: DataChannel,
+
+    /// Number of connection events that have taken place.
+    events: u32,
+
+    /// Whether a packet was received from the peripheral at all.
+    established: bool,
+
+    /// Time at which the last valid packet from the peripheral was received.
+    last_rx: Instant,
+
+    /// Whether the current connection event expects a response from the peripheral.
+    awaiting_response: bool,
+
+    sn: SeqNum,
+    nesn: SeqNum,
+}
+
+impl Conn {
+    fn hop_channel(&mut self) {
+        // All data channels are used, so no remapping is necessary
+        self.unmapped_channel = (self.unmapped_channel + self.params.hop) % 37;
+        self.channel = DataChannel::new(self.unmapped_channel);
+    }
+}
+
+/// State of the *Encryption Start* and *Encryption Pause* procedures.
+#[derive(Default)]
+struct Encryption {
+    /// LTK requested with the last `LL_ENC_REQ`.
+    ltk: Option<u128>,
+
+    /// LTK, EDIV and Rand to request once encryption has been paused.
+    refresh: Option<(u128, u16, u64)>,
+
+    /// Session key, once the peripheral sent `LL_ENC_RSP`.
+    ccm: Option<Ccm>,
+
+    /// Whether PDUs are encrypted and decrypted.
+    enabled: bool,
+
+    /// Whether the peripheral has answered with `LL_START_ENC_RSP`.
+    established: bool,
+
+    tx_counter: u64,
+    rx_counter: u64,
+}
+
+impl Encryption {
+    /// Returns the LL Control PDU that starts encrypting with the given LTK.
+    fn start(&mut self, ltk: u128, ediv: u16, rand: u64) -> ControlPdu<'static> {
+        if self.enabled {
+            self.refresh = Some((ltk, ediv, rand));
+            ControlPdu::PauseEncReq
+        } else {
+            self.ltk = Some(ltk);
+            ControlPdu::EncReq {
+                rand,
+                ediv,
+                skd_m: SKD_M,
+                iv_m: IV_M,
+            }
+        }
+    }
+
+    /// Advances the encryption procedures when the peripheral sends an LL Control PDU.
+    ///
+    /// Responses are put at the front of `tx_queue`.
+    fn process(&mut self, payload: &[u8], tx_queue: &mut VecDeque<(Llid, Vec<u8>)>) {
+        let pdu = match ControlPdu::from_bytes(&mut ByteReader::new(payload)) {
+            Ok(pdu) => pdu,
+            Err(_) => return,
+        };
+        match pdu {
+            ControlPdu::EncRsp { skd_s, iv_s } => {
+                if let Some(ltk) = self.ltk {
+                    // SK = e(LTK, SKDs || SKDm)
+                    let mut sk = (u128::from(skd_s) << 64 | u128::from(SKD_M)).to_be_bytes();
+                    SoftAes.encrypt_block(&ltk.to_be_bytes(), &mut sk);
+                    let iv = u64::from(iv_s) << 32 | u64::from(IV_M);
+                    self.ccm = Some(Ccm::new(u128::from_be_bytes(sk), iv));
+                }
+            }
+            ControlPdu::StartEncReq if self.ccm.is_some() => {
+                // Our `LL_START_ENC_RSP` is the first encrypted PDU
+                self.enabled = true;
+                self.tx_counter = 0;
+                self.rx_counter = 0;
+                let rsp = encode_control(&ControlPdu::StartEncRsp);
+                tx_queue.push_front((Llid::Control, rsp));
+            }
+            ControlPdu::StartEncRsp => self.established = true,
+            ControlPdu::PauseEncRsp if self.enabled => {
+                // Our `LL_PAUSE_ENC_RSP` is sent unencrypted, followed by the new `LL_ENC_REQ`
+                let (ltk, ediv, rand) = self.refresh.take().expect("no key to refresh to");
+                *self = Encryption::default();
+                let req = encode_control(&self.start(ltk, ediv, rand));
+                tx_queue.push_front((Llid::Control, req));
+                let rsp = encode_control(&ControlPdu::PauseEncRsp);
+                tx_queue.push_front((Llid::Control, rsp));
+            }
+            ControlPdu::RejectInd { .. } | ControlPdu::RejectIndExt { .. } if !self.enabled => {
+                *self = Encryption::default();
+            }
+            _ => {}
+        }
+    }
+
+    /// Encrypts a payload about to be sent for the first time, appending the MIC.
+    fn encrypt(&mut self, llid: Llid, payload: &mut Vec<u8>) {
+        match self.ccm {
+            Some(ccm) if self.enabled && !payload.is_empty() => {
+                let header = data::Header::new(llid).to_u16() as u8;
+                let mic = ccm.encrypt(&mut SoftAes, self.tx_counter, true, header, payload);
+                payload.extend_from_slice(&mic);
+                self.tx_counter += 1;
+            }
+            _ => {}
+        }
+    }
+
+    /// Decrypts the payload of a new, non-empty PDU received from the peripheral.
+    ///
+    /// # Panics
+    ///
+    /// This panics if the MIC is invalid.
+    fn decrypt(&mut self, header: data::Header, payload: &[u8]) -> Vec<u8> {
+        match self.ccm {
+            Some(ccm) if self.enabled => {
+                let len = payload
+                    .len()
+                    .checked_sub(MIC_LEN)
+                    .expect("encrypted PDU without MIC");
+                let (data, mic) = payload.split_at(len);
+                let mut plain = data.to_vec();
+                let header = header.to_u16() as u8;
+                ccm.decrypt(
+                    &mut SoftAes,
+                    self.rx_counter,
+                    false,
+                    header,
+                    &mut plain,
+                    mic,
+                )
+                .expect("MIC check failed");
+                self.rx_counter += 1;
+                plain
+            }
+            _ => payload.to_vec(),
+        }
+    }
+}
+
+impl fmt::Debug for Encryption {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // Keys are left out
+        f.debug_struct("Encryption")
+            .field("enabled", &self.enabled)
+            .field("established", &self.established)
+            .field("tx_counter", &self.tx_counter)
+            .field("rx_counter", &self.rx_counter)
+            .finish()
+    }
+}
+
+/// A simulated BLE central that connects to the peripheral under test.
+///
+/// The central answers the first connectable advertisement it sees with a `CONNECT_REQ` once
+/// [`connect`] was called, and then sends packets in every connection event, taking care of
+/// acknowledgements and retransmissions. Payloads to send are queued via [`send`] and friends,
+/// and packets received from the peripheral are recorded and can be inspected with [`received`].
+///
+/// Connection events are extended for as long as the peripheral sets the `MD` bit and there is
+/// time left, but the central never sets the `MD` bit itself. All 37 data channels are used.
+///
+/// The connection can be encrypted with [`start_encryption`]. PDUs are then encrypted and
+/// decrypted transparently, so [`received`] always returns plain text.
+///
+/// [`connect`]: #method.connect
+/// [`start_encryption`]: #method.start_encryption
+/// [`send`]: #method.send
+/// [`received`]: #method.received
+#[derive(Debug)]
+pub struct Central {
+    address: DeviceAddress,
+    state: State,
+
+    /// Queued payloads that haven't been sent yet.
+    tx_queue: VecDeque<(Llid, Vec<u8>)>,
+
+    /// Packet sent in the last connection event that wasn't acknowledged yet.
+    in_flight: Option<(Llid, Vec<u8>)>,
+
+    /// Whether `in_flight` is an `LL_TERMINATE_IND`.
+    terminating: bool,
+
+    /// Non-empty data channel PDUs received from the peripheral.
+    received: Vec<(Llid, Vec<u8>)>,
+
+    /// Number of `CONNECT_REQ`s sent.
+    connect_attempts: u32,
+
+    encryption: Encryption,
+}
+
+impl Central {
+    /// Creates a new central using the given device address.
+    pub fn new(address: DeviceAddress) -> Self {
+        Self {
+            address,
+            state: State::Idle,
+            tx_queue: VecDeque::new(),
+            in_flight: None,
+            terminating: false,
+            received: Vec::new(),
+            connect_attempts: 0,
+            encryption: Encryption::default(),
+        }
+    }
+
+    /// Returns the device address of this central.
+    pub fn address(&self) -> DeviceAddress {
+        self.address
+    }
+
+    /// Starts initiating a connection to the next connectable advertiser.
+    pub fn connect(&mut self, params: ConnectParams) {
+        self.state = State::Initiating(params);
+    }
+
+    /// Returns whether the central considers itself connected to the peripheral.
+    ///
+    /// This is the case after sending the `CONNECT_REQ` and until the connection is terminated or
+    /// times out.
+    pub fn is_connected(&self) -> bool {
+        matches!(self.state, State::Connected(_))
+    }
+
+    /// Returns the number of `CONNECT_REQ`s sent so far.
+    pub fn connect_attempts(&self) -> u32 {
+        self.connect_attempts
+    }
+
+    /// Queues a data channel PDU with the given LLID and payload for transmission.
+    pub fn send(&mut self, llid: Llid, payload: &[u8]) {
+        assert!(
+            payload.len() <= MIN_DATA_PAYLOAD_BUF,
+            "payload too large for a single PDU"
+        );
+        self.tx_queue.push_back((llid, payload.to_vec()));
+    }
+
+    /// Queues an LL Control PDU for transmission.
+    pub fn send_control(&mut self, pdu: &ControlPdu<'_>) {
+        self.send(Llid::Control, &encode_control(pdu));
+    }
+
+    /// Queues an L2CAP message for `channel`, which must fit into a single PDU.
+    pub fn send_l2cap(&mut self, channel: Channel, payload: &[u8]) {
+        let mut pdu = Vec::with_capacity(4 + payload.len());
+        pdu.extend_from_slice(&(payload.len() as u16).to_le_bytes());
+        pdu.extend_from_slice(&channel.as_raw().to_le_bytes());
+        pdu.extend_from_slice(payload);
+        self.send(Llid::DataStart, &pdu);
+    }
+
+    /// Terminates the connection by sending an `LL_TERMINATE_IND`.
+    ///
+    /// Queued PDUs are sent first.
+    pub fn disconnect(&mut self) {
+        // 0x13 = Remote User Terminated Connection
+        self.tx_queue.push_back((Llid::Control, vec![0x02, 0x13]));
+    }
+
+    /// Starts encrypting the connection with the LTK identified by `ediv` and `rand`.
+    ///
+    /// This sends an `LL_ENC_REQ`, or an `LL_PAUSE_ENC_REQ` first if the connection is encrypted
+    /// already, to change the key. Encryption is started once the peripheral answers with its part
+    /// of the session key and `LL_START_ENC_REQ`. If the peripheral doesn't know the key, it
+    /// answers with an `LL_REJECT_IND`.
+    pub fn start_encryption(&mut self, ltk: u128, ediv: u16, rand: u64) {
+        let pdu = self.encryption.start(ltk, ediv, rand);
+        self.send_control(&pdu);
+    }
+
+    /// Returns whether the *Encryption Start* procedure has completed, so that PDUs are encrypted
+    /// in both directions.
+    pub fn is_encrypted(&self) -> bool {
+        self.encryption.established
+    }
+
+    /// Returns the non-empty PDUs received from the peripheral so far.
+    pub fn received(&self) -> &[(Llid, Vec<u8>)] {
+        &self.received
+    }
+
+    /// Removes and returns all PDUs received from the peripheral so far.
+    pub fn take_received(&mut self) -> Vec<(Llid, Vec<u8>)> {
+        std::mem::take(&mut self.received)
+    }
+
+    /// Returns the LL Control PDUs received from the peripheral so far.
+    pub fn received_control(&self) -> Vec<ControlPdu<'_>> {
+        self.received
+            .iter()
+            .filter(|(llid, _)| *llid == Llid::Control)
+            .map(|(_, payload)| ControlPdu::from_bytes(&mut ByteReader::new(payload)).unwrap())
+            .collect()
+    }
+
+    /// Returns the payloads of all L2CAP messages received on `channel` so far.
+    ///
+    /// Messages are expected to fit into a single PDU.
+    pub fn received_l2cap(&self, channel: Channel) -> Vec<&[u8]> {
+        self.received
+            .iter()
+            .filter(|(llid, pdu)| {
+                *llid == Llid::DataStart
+                    && pdu.len() >= 4
+                    && u16::from_le_bytes([pdu[2], pdu[3]]) == channel.as_raw()
+            })
+            .map(|(_, pdu)| {
+                let len = usize::from(u16::from_le_bytes([pdu[0], pdu[1]]));
+                &pdu[4..][..len]
+            })
+            .collect()
+    }
+
+    /// Returns the time of the next connection event, if connected.
+    pub(crate) fn next_event(&self) -> Option<Instant> {
+        match &self.state {
+            State::Connected(conn) => Some(conn.anchor),
+            _ => None,
+        }
+    }
+
+    /// Runs the connection event scheduled at `now`, returning the packet to transmit.
+    pub(crate) fn connection_event(&mut self, now: Instant) -> Option<AirPacket> {
+        let conn = match &mut self.state {
+            State::Connected(conn) => conn,
+            _ => return None,
+        };
+
+        if now.duration_since(conn.last_rx) >= conn.params.supervision_timeout
+            || (!conn.established && conn.events >= ESTABLISHMENT_EVENTS)
+        {
+            // Connection lost or never established
+            self.state = State::Idle;
+            self.in_flight = None;
+            self.terminating = false;
+            self.encryption = Encryption::default();
+            return None;
+        }
+
+        if conn.events > 0 {
+            conn.hop_channel();
+        }
+        conn.events += 1;
+        conn.anchor += conn.params.interval;
+        self.data_packet(now)
+    }
+
+    /// Builds the next data channel packet to send at `start` in the current connection event.
+    fn data_packet(&mut self, start: Instant) -> Option<AirPacket> {
+        let conn = match &mut self.state {
+            State::Connected(conn) => conn,
+            _ => return None,
+        };
+
+        if self.in_flight.is_none() {
+            self.in_flight = self.tx_queue.pop_front();
+            self.terminating = matches!(
+                &self.in_flight,
+                Some((Llid::Control, payload)) if payload.first() == Some(&0x02)
+            );
+            // Retransmissions have to be identical, so encrypt only once
+            if let Some((llid, payload)) = &mut self.in_flight {
+                self.encryption.encrypt(*llid, payload);
+            }
+        }
+
+        let (llid, payload) = match &self.in_flight {
+            Some((llid, payload)) => (*llid, payload.clone()),
+            None => (Llid::DataCont, Vec::new()),
+        };
+
+        let mut header = data::Header::new(llid);
+        header.set_payload_length(payload.len() as u8);
+        header.set_sn(conn.sn);
+        header.set_nesn(conn.nesn);
+
+        let packet = AirPacket {
+            start,
+            freq: conn.channel.freq(),
+            access_address: conn.params.access_address,
+            crc_init: conn.params.crc_init,
+            header: header.to_u16(),
+            payload,
+        };
+
+        conn.awaiting_response = true;
+        Some(packet)
+    }
+
+    /// Processes a packet sent by the peripheral, returning a packet to send in response.
+    ///
+    /// `rx_end` is the time at which the packet was received completely.
+    pub(crate) fn receive(&mut self, packet: &AirPacket, rx_end: Instant) -> Option<AirPacket> {
+        match &mut self.state {
+            State::Idle => None,
+            State::Initiating(params) => {
+                let params = *params;
+                self.receive_adv(packet, rx_end, params)
+            }
+            State::Connected(conn) => {
+                if !conn.awaiting_response
+                    || packet.freq != conn.channel.freq()
+                    || packet.access_address != conn.params.access_address
+                {
+                    return None;
+                }
+                conn.awaiting_response = false;
+                conn.established = true;
+                conn.last_rx = rx_end;
+
+                let header = data::Header::parse(&packet.header.to_le_bytes());
+                if header.nesn() != conn.sn {
+                    // Our packet was acknowledged
+                    conn.sn += SeqNum::ONE;
+                    self.in_flight = None;
+                }
+                if header.sn() == conn.nesn {
+                    // New packet from the peripheral
+                    conn.nesn += SeqNum::ONE;
+                    if !packet.payload.is_empty() {
+                        let payload = self.encryption.decrypt(header, &packet.payload);
+                        if header.llid() == Llid::Control {
+                            self.encryption.process(&payload, &mut self.tx_queue);
+                        }
+                        self.received.push((header.llid(), payload));
+                    }
+                }
+
+                if self.terminating && self.in_flight.is_none() {
+                    // `LL_TERMINATE_IND` was acknowledged
+                    self.terminating = false;
+                    self.state = State::Idle;
+                    self.encryption = Encryption::default();
+                    return None;
+                }
+
+                // Continue the connection event if the peripheral has more data and there's
+                // enough time left before the next one (`anchor` is already the next anchor).
+                let next = rx_end + Duration::T_IFS;
+                let exchange = (Duration::T_IFS + MAX_DATA_AIRTIME).saturating_mul(2);
+                if header.md() && (next + exchange).raw_micros() < conn.anchor.raw_micros() {
+                    self.data_packet(next)
+                } else {
+                    None
+                }
+            }
+        }
+    }
+
+    fn receive_adv(
+        &mut self,
+        packet: &AirPacket,
+        rx_end: Instant,
+        params: ConnectParams,
+    ) -> Option<AirPacket> {
+        if packet.access_address != advertising::ACCESS_ADDRESS {
+            return None;
+        }
+
+        let header = advertising::Header::parse(&packet.header.to_le_bytes());
+        let pdu = Pdu::from_header_and_payload(header, &mut ByteReader::new(&packet.payload));
+        let advertiser = match pdu {
+            Ok(pdu @ Pdu::ConnectableUndirected { .. }) => *pdu.sender(),
+            Ok(pdu @ Pdu::ConnectableDirected { .. }) if pdu.receiver() == Some(&self.address) => {
+                *pdu.sender()
+            }
+            _ => return None,
+        };
+
+        let mut payload = Vec::with_capacity(34);
+        payload.extend_from_slice(self.address.raw());
+        payload.extend_from_slice(advertiser.raw());
+        payload.extend_from_slice(&params.access_address.to_le_bytes());
+        payload.extend_from_slice(&params.crc_init.to_le_bytes()[..3]);
+        payload.push(params.window_size);
+        payload.extend_from_slice(&params.window_offset.to_le_bytes());
+        payload.extend_from_slice(&((params.interval.as_micros() / 1250) as u16).to_le_bytes());
+        payload.extend_from_slice(&params.slave_latency.to_le_bytes());
+        payload.extend_from_slice(
+            &((params.supervision_timeout.as_micros() / 10_000) as u16).to_le_bytes(),
+        );
+        payload.extend_from_slice(&[0xFF, 0xFF, 0xFF, 0xFF, 0x1F]);
+        // Hop increment and a sleep clock accuracy of 0-20 ppm
+        payload.push(params.hop | (7 << 5));
+
+        let mut header = advertising::Header::new(PduType::ConnectReq);
+        header.set_tx_add(self.address.is_random());
+        header.set_rx_add(advertiser.is_random());
+        header.set_payload_length(payload.len() as u8);
+
+        let connect_req = AirPacket {
+            start: rx_end + Duration::T_IFS,
+            freq: packet.freq,
+            access_address: advertising::ACCESS_ADDRESS,
+            crc_init: advertising::CRC_PRESET,
+            header: header.to_u16(),
+            payload,
+        };
+
+        // The first connection event takes place at the start of the transmit window
+        let anchor =
+            connect_req.end() + Duration::from_micros(1250 * (1 + u32::from(params.window_offset)));
+        let mut conn = Conn {
+            params,
+            anchor,
+            unmapped_channel: 0,
+            channel: DataChannel::new(0),
+            events: 0,
+            established: false,
+            last_rx: connect_req.end(),
+            awaiting_response: false,
+            sn: SeqNum::ZERO,
+            nesn: SeqNum::ZERO,
+        };
+        conn.hop_channel();
+
+        self.state = State::Connected(conn);
+        self.in_flight = None;
+        self.terminating = false;
+        self.encryption = Encryption::default();
+        self.connect_attempts += 1;
+        Some(connect_req)
+    }
+}
+
+impl Default for Central {
+    fn default() -> Self {
+        Self::new(DeviceAddress::new(
+            [0x0C, 0x0E, 0x01, 0x0C, 0x0E, 0xC2],
+            AddressKind::Random,
+        ))
+    }
+}
+
+/// Encodes an LL Control PDU.
+fn encode_control(pdu: &ControlPdu<'_>) -> Vec<u8> {
+    let mut buf = [0; MIN_DATA_PAYLOAD_BUF];
+    let mut writer = ByteWriter::new(&mut buf);
+    pdu.to_bytes(&mut writer).unwrap();
+    let len = MIN_DATA_PAYLOAD_BUF - writer.space_left();
+    buf[..len].to_vec()
+}
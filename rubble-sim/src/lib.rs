@@ -0,0 +1,1243 @@
+//! A host-side simulator for testing Rubble's Link-Layer without hardware.
+//!
+//! A [`Simulation`] connects a Rubble peripheral (a `LinkLayer` and its `Responder`) to a scripted
+//! [`Central`] over a virtual air interface. Time is simulated, so tests run instantly and
+//! deterministically. The air interface can drop packets and delay their reception (see
+//! [`AirConfig`]) to exercise retransmissions, missed connection events and timeouts.
+//!
+//! ```
+//! use rubble::link::llcp::ControlPdu;
+//! use rubble::link::FeatureSet;
+//! use rubble::time::Duration;
+//! use rubble_sim::{AirConfig, ConnectParams, Simulation};
+//!
+//! let mut sim = Simulation::new(rubble::att::NoAttributes, AirConfig::default());
+//! sim.start_advertising(Duration::from_millis(20), &[]).unwrap();
+//! sim.central().connect(ConnectParams::default());
+//! sim.run_for(Duration::from_millis(100));
+//! assert!(sim.link_layer().is_connected());
+//!
+//! sim.central().send_control(&ControlPdu::FeatureReq {
+//!     features_master: FeatureSet::supported(),
+//! });
+//! sim.run_for(Duration::from_millis(50));
+//! assert_eq!(sim.central().received_control().len(), 1);
+//! ```
+//!
+//! Connections recorded in the simulation or captured from real devices can be replayed
+//! deterministically with the [`replay`] module.
+//!
+//! [`Simulation`]: struct.Simulation.html
+//! [`Central`]: struct.Central.html
+//! [`AirConfig`]: struct.AirConfig.html
+//! [`replay`]: replay/index.html
+
+mod central;
+pub mod replay;
+
+pub use self::central::{Central, ConnectParams};
+
+use self::replay::TraceRecord;
+use rand_core::{impls, CryptoRng, RngCore};
+use rubble::att::AttributeProvider;
+use rubble::config::Config;
+use rubble::l2cap::{BleChannelMap, L2CAPState};
+use rubble::link::{
+    ad_structure::AdStructure,
+    advertising, data,
+    queue::{PacketQueue, RingQueue},
+    AddressKind, Cmd, DeviceAddress, LinkLayer, RadioCmd, Responder, Transmitter, MIN_PDU_BUF,
+};
+use rubble::log::NoLogger;
+use rubble::phy::{AdvertisingChannel, DataChannel};
+use rubble::security::{BondManager, LegacyPairing, NoDelegate, PairingConfig, SoftAes};
+use rubble::time::{Duration, Instant, Timer};
+use rubble::Error;
+use std::cell::Cell;
+use std::marker::PhantomData;
+use std::rc::Rc;
+
+/// Number of packets buffered in each direction between `LinkLayer` and `Responder`.
+pub const QUEUE_PACKETS: usize = 4;
+
+/// Number of bonds the simulated peripheral can store.
+pub const MAX_BONDS: usize = 4;
+
+/// Security level of the simulated peripheral: *LE Legacy Pairing*, keeping bonds in RAM.
+pub type SimSecurity = LegacyPairing<SoftAes, SimRng, NoDelegate, BondManager<MAX_BONDS>>;
+
+/// Device address used by the simulated peripheral.
+pub const PERIPHERAL_ADDRESS: DeviceAddress =
+    DeviceAddress::new([0x5A, 0x5A, 0x5A, 0x5A, 0x5A, 0xC5], AddressKind::Random);
+
+/// The simulated time, shared between all parts of a simulation.
+#[derive(Debug, Clone, Default)]
+pub struct SimClock(Rc<Cell<u32>>);
+
+impl SimClock {
+    /// Returns the current simulated time.
+    pub fn now(&self) -> Instant {
+        Instant::from_raw_micros(self.0.get())
+    }
+
+    /// Advances the clock to `time`, which must not lie in the past.
+    fn advance_to(&self, time: Instant) {
+        assert!(time.raw_micros() >= self.0.get(), "time moved backwards");
+        self.0.set(time.raw_micros());
+    }
+}
+
+/// A `Timer` reading the simulated time.
+///
+/// Timer events scheduled by the `LinkLayer` are run by the `Simulation`.
+pub struct SimTimer {
+    clock: SimClock,
+    scheduled: Rc<Cell<Option<Instant>>>,
+}
+
+impl Timer for SimTimer {
+    fn now(&self) -> Instant {
+        self.clock.now()
+    }
+
+    fn schedule_at(&mut self, at: Instant) {
+        self.scheduled.set(Some(at));
+    }
+
+    fn cancel(&mut self) {
+        self.scheduled.set(None);
+    }
+}
+
+/// A deterministic xorshift random number generator.
+///
+/// **This is not a cryptographically secure RNG.** It only implements `CryptoRng` so that it can
+/// be used in simulated stack configurations.
+#[derive(Debug, Clone)]
+pub struct SimRng(u64);
+
+impl SimRng {
+    /// Creates an RNG from a seed.
+    pub fn new(seed: u64) -> Self {
+        // xorshift gets stuck on 0
+        SimRng(seed | 1)
+    }
+
+    /// Returns `true` with a probability of `percent`%.
+    fn chance(&mut self, percent: u8) -> bool {
+        self.next_u32() % 100 < u32::from(percent)
+    }
+}
+
+impl RngCore for SimRng {
+    fn next_u32(&mut self) -> u32 {
+        (self.next_u64() >> 32) as u32
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        let mut x = self.0;
+        x ^= x << 13;
+        x ^= x >> 7;
+        x ^= x << 17;
+        self.0 = x;
+        x
+    }
+
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        impls::fill_bytes_via_next(self, dest)
+    }
+
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), rand_core::Error> {
+        self.fill_bytes(dest);
+        Ok(())
+    }
+}
+
+impl CryptoRng for SimRng {}
+
+/// A packet transmitted over the simulated air interface.
+#[derive(Debug, Clone)]
+pub struct AirPacket {
+    /// Time at which the transmission starts.
+    pub start: Instant,
+
+    /// Frequency in MHz.
+    pub freq: u16,
+
+    /// Access Address of the packet.
+    pub access_address: u32,
+
+    /// CRC initialization value used by the sender.
+    pub crc_init: u32,
+
+    /// Raw 16-bit PDU header.
+    pub header: u16,
+
+    /// PDU payload.
+    pub payload: Vec<u8>,
+}
+
+impl AirPacket {
+    /// Returns the time needed to transmit this packet on the LE 1M PHY.
+    pub fn airtime(&self) -> Duration {
+        // Preamble, Access Address, header, payload and CRC at 1 µs per bit
+        let bytes = 1 + 4 + 2 + self.payload.len() as u32 + 3;
+        Duration::from_micros(bytes * 8)
+    }
+
+    /// Returns the time at which the transmission ends.
+    pub fn end(&self) -> Instant {
+        self.start + self.airtime()
+    }
+}
+
+/// Properties of the simulated air interface.
+#[derive(Debug, Copy, Clone)]
+pub struct AirConfig {
+    /// Probability that any given packet is lost, in percent.
+    pub loss_percent: u8,
+
+    /// Delay between the end of a transmission and the end of its reception.
+    pub latency: Duration,
+
+    /// Seed for deciding which packets are lost.
+    pub seed: u64,
+}
+
+impl Default for AirConfig {
+    /// Returns a perfect air interface that never loses packets.
+    fn default() -> Self {
+        Self {
+            loss_percent: 0,
+            latency: Duration::from_micros(0),
+            seed: 0x5EED,
+        }
+    }
+}
+
+/// The `Transmitter` of the simulated peripheral.
+pub struct SimRadio {
+    clock: SimClock,
+    buf: [u8; MIN_PDU_BUF],
+
+    /// Start time of packets sent in response to a received one.
+    respond_at: Option<Instant>,
+
+    /// Packets transmitted but not yet put on the air.
+    sent: Vec<AirPacket>,
+
+    tx_power: i8,
+}
+
+impl SimRadio {
+    fn new(clock: SimClock) -> Self {
+        Self {
+            clock,
+            buf: [0; MIN_PDU_BUF],
+            respond_at: None,
+            sent: Vec::new(),
+            tx_power: 0,
+        }
+    }
+
+    fn push(&mut self, access_address: u32, crc_init: u32, header: u16, freq: u16) {
+        let len = usize::from(header.to_le_bytes()[1]);
+        self.sent.push(AirPacket {
+            start: self.respond_at.unwrap_or_else(|| self.clock.now()),
+            freq,
+            access_address,
+            crc_init,
+            header,
+            payload: self.buf[..len].to_vec(),
+        });
+    }
+}
+
+impl Transmitter for SimRadio {
+    fn tx_payload_buf(&mut self) -> &mut [u8] {
+        &mut self.buf
+    }
+
+    fn transmit_advertising(&mut self, header: advertising::Header, channel: AdvertisingChannel) {
+        self.push(
+            advertising::ACCESS_ADDRESS,
+            advertising::CRC_PRESET,
+            header.to_u16(),
+            channel.freq(),
+        );
+    }
+
+    fn transmit_data(
+        &mut self,
+        access_address: u32,
+        crc_iv: u32,
+        header: data::Header,
+        channel: DataChannel,
+    ) {
+        self.push(access_address, crc_iv, header.to_u16(), channel.freq());
+    }
+
+    fn set_tx_power(&mut self, dbm: i8) -> i8 {
+        self.tx_power = dbm;
+        dbm
+    }
+}
+
+/// Stack configuration of the simulated peripheral.
+pub struct SimConfig<A>(PhantomData<A>);
+
+impl<A: AttributeProvider> Config for SimConfig<A> {
+    type Timer = SimTimer;
+    type Transmitter = SimRadio;
+    type ChannelMapper = BleChannelMap<A, SimSecurity>;
+    type PacketQueue = &'static mut RingQueue<QUEUE_PACKETS>;
+    type Rng = SimRng;
+    type Logger = NoLogger;
+}
+
+/// Creates the channel mapper of the simulated peripheral, using the keys from `bonds`.
+fn channel_mapper<A: AttributeProvider>(
+    attributes: A,
+    bonds: BondManager<MAX_BONDS>,
+) -> BleChannelMap<A, SimSecurity> {
+    let security = LegacyPairing::new(PairingConfig::new(), SoftAes, SimRng::new(0x5EC))
+        .with_bond_store(bonds);
+    BleChannelMap::with_attributes(attributes).with_security(security)
+}
+
+type Queues<A> = (
+    <<SimConfig<A> as Config>::PacketQueue as PacketQueue>::Consumer,
+    <<SimConfig<A> as Config>::PacketQueue as PacketQueue>::Producer,
+);
+
+/// A Rubble peripheral and a scripted central connected by a simulated air interface.
+///
+/// The packet queues of the peripheral are handed to the `LinkLayer` when advertising is started.
+/// To connect again after a connection has ended, they have to be taken back with
+/// `LinkLayer::take_released_queues`.
+pub struct Simulation<A: AttributeProvider> {
+    clock: SimClock,
+    air: AirConfig,
+    air_rng: SimRng,
+    radio: SimRadio,
+    radio_cmd: RadioCmd,
+    /// Timer event scheduled by the `LinkLayer`.
+    scheduled: Rc<Cell<Option<Instant>>>,
+    ll: LinkLayer<SimConfig<A>>,
+    responder: Responder<SimConfig<A>>,
+    queues: Option<Queues<A>>,
+    central: Central,
+    lost_packets: u32,
+    /// Packets sent by the central that weren't lost.
+    trace: Vec<TraceRecord>,
+}
+
+impl<A: AttributeProvider> Simulation<A> {
+    /// Creates a simulation of a peripheral hosting `attributes`.
+    pub fn new(attributes: A, air: AirConfig) -> Self {
+        Self::with_bonds(attributes, BondManager::new(), air)
+    }
+
+    /// Creates a simulation of a peripheral hosting `attributes`, which knows the keys in `bonds`.
+    ///
+    /// The peripheral supports *LE Legacy Pairing*, and the central can encrypt the connection
+    /// with the local LTK of a bond using `Central::start_encryption`.
+    pub fn with_bonds(attributes: A, bonds: BondManager<MAX_BONDS>, air: AirConfig) -> Self {
+        let clock = SimClock::default();
+        let tx: &'static mut RingQueue<QUEUE_PACKETS> = Box::leak(Box::new(RingQueue::new()));
+        let rx: &'static mut RingQueue<QUEUE_PACKETS> = Box::leak(Box::new(RingQueue::new()));
+        let (tx_prod, tx_cons) = tx.split();
+        let (rx_prod, rx_cons) = rx.split();
+        let scheduled = Rc::new(Cell::new(None));
+        let timer = SimTimer {
+            clock: clock.clone(),
+            scheduled: scheduled.clone(),
+        };
+
+        Self {
+            ll: LinkLayer::new(PERIPHERAL_ADDRESS, timer),
+            responder: Responder::new(
+                tx_prod,
+                rx_cons,
+                L2CAPState::new(channel_mapper(attributes, bonds)),
+            ),
+            radio: SimRadio::new(clock.clone()),
+            clock,
+            air,
+            air_rng: SimRng::new(air.seed),
+            radio_cmd: RadioCmd::Off,
+            scheduled,
+            queues: Some((tx_cons, rx_prod)),
+            central: Central::default(),
+            lost_packets: 0,
+            trace: Vec::new(),
+        }
+    }
+
+    /// Returns the current simulated time.
+    pub fn now(&self) -> Instant {
+        self.clock.now()
+    }
+
+    /// Returns the Link-Layer of the simulated peripheral.
+    pub fn link_layer(&mut self) -> &mut LinkLayer<SimConfig<A>> {
+        &mut self.ll
+    }
+
+    /// Returns the `Responder` of the simulated peripheral.
+    pub fn responder(&mut self) -> &mut Responder<SimConfig<A>> {
+        &mut self.responder
+    }
+
+    /// Returns the scripted central.
+    pub fn central(&mut self) -> &mut Central {
+        &mut self.central
+    }
+
+    /// Returns the number of packets dropped by the air interface so far.
+    pub fn lost_packets(&self) -> u32 {
+        self.lost_packets
+    }
+
+    /// Returns all packets sent by the central that weren't lost, in the order they were sent.
+    ///
+    /// This includes packets the peripheral wasn't listening for. The trace can be replayed with a
+    /// `replay::Replay`.
+    pub fn trace(&self) -> &[TraceRecord] {
+        &self.trace
+    }
+
+    /// Changes the properties of the air interface for all packets sent from now on.
+    pub fn set_air(&mut self, air: AirConfig) {
+        self.air = air;
+    }
+
+    /// Makes the peripheral start advertising.
+    ///
+    /// # Panics
+    ///
+    /// This panics when called more than once, since the packet queues are moved into the
+    /// `LinkLayer`.
+    pub fn start_advertising(
+        &mut self,
+        interval: Duration,
+        data: &[AdStructure<'_>],
+    ) -> Result<(), Error> {
+        let (tx, rx) = self.queues.take().expect("advertising already started");
+        self.ll
+            .start_advertise(interval, data, &mut self.radio, tx, rx)?;
+        self.flush_air();
+        Ok(())
+    }
+
+    /// Runs the simulation for `duration`.
+    pub fn run_for(&mut self, duration: Duration) {
+        let end = self.now() + duration;
+        loop {
+            let ll = self.scheduled.get();
+            let central = self.central.next_event();
+            let (time, is_ll) = match (ll, central) {
+                (Some(ll), Some(c)) if c.raw_micros() < ll.raw_micros() => (c, false),
+                (Some(ll), _) => (ll, true),
+                (None, Some(c)) => (c, false),
+                (None, None) => break,
+            };
+            if time.raw_micros() > end.raw_micros() {
+                break;
+            }
+
+            // Packets are delivered in the past if latency or airtime delays their processing
+            // beyond the next event, so never move the clock backwards.
+            if time.raw_micros() > self.now().raw_micros() {
+                self.clock.advance_to(time);
+            }
+
+            if is_ll {
+                self.scheduled.set(None);
+                let cmd = self.ll.update_timer(&mut self.radio);
+                self.apply_cmd(cmd);
+            } else if let Some(packet) = self.central.connection_event(time) {
+                self.deliver_to_peripheral(packet);
+            }
+            self.flush_air();
+        }
+
+        if end.raw_micros() > self.now().raw_micros() {
+            self.clock.advance_to(end);
+        }
+    }
+
+    fn apply_cmd(&mut self, cmd: Cmd) {
+        // The timer event was already scheduled by the `LinkLayer`
+        self.radio_cmd = cmd.radio;
+        if cmd.queued_work || cmd.tx_space_freed {
+            run_responder(&mut self.responder);
+        }
+    }
+
+    /// Decides whether the air interface drops a packet.
+    fn lose_packet(&mut self) -> bool {
+        let lost = self.air_rng.chance(self.air.loss_percent);
+        if lost {
+            self.lost_packets += 1;
+        }
+        lost
+    }
+
+    /// Puts all packets sent by the peripheral on the air, delivering them to the central.
+    fn flush_air(&mut self) {
+        while !self.radio.sent.is_empty() {
+            let sent = std::mem::take(&mut self.radio.sent);
+            for packet in sent {
+                if self.lose_packet() {
+                    continue;
+                }
+                let rx_end = packet.end() + self.air.latency;
+                if let Some(response) = self.central.receive(&packet, rx_end) {
+                    self.deliver_to_peripheral(response);
+                }
+            }
+        }
+    }
+
+    /// Delivers a packet sent by the central to the peripheral, if it is listening for it.
+    fn deliver_to_peripheral(&mut self, packet: AirPacket) {
+        if self.lose_packet() {
+            return;
+        }
+
+        let rx_end = packet.end() + self.air.latency;
+        self.trace.push(TraceRecord::from_air(&packet, rx_end));
+        let listening = match self.radio_cmd {
+            RadioCmd::Off => false,
+            RadioCmd::ListenAdvertising { channel, .. } => {
+                packet.freq == channel.freq()
+                    && packet.access_address == advertising::ACCESS_ADDRESS
+            }
+            RadioCmd::ListenData {
+                channel,
+                access_address,
+                ..
+            } => packet.freq == channel.freq() && packet.access_address == access_address,
+        };
+        if !listening {
+            return;
+        }
+
+        // The radio raises its interrupt once the packet was received completely
+        if rx_end.raw_micros() > self.now().raw_micros() {
+            self.clock.advance_to(rx_end);
+        }
+
+        let header = packet.header.to_le_bytes();
+        self.radio.respond_at = Some(rx_end + Duration::T_IFS);
+        let cmd = match self.radio_cmd {
+            RadioCmd::ListenData { crc_init, .. } => self.ll.process_data_packet(
+                rx_end,
+                &mut self.radio,
+                data::Header::parse(&header),
+                &packet.payload,
+                packet.crc_init == crc_init,
+            ),
+            _ => {
+                let cmd = self.ll.process_adv_packet(
+                    rx_end,
+                    &mut self.radio,
+                    advertising::Header::parse(&header),
+                    &packet.payload,
+                    packet.crc_init == advertising::CRC_PRESET,
+                );
+                if let Some(conn) = self.ll.connection() {
+                    // Like an application would, inform the host about the new connection
+                    let peer = conn.peer();
+                    self.responder.connected(PERIPHERAL_ADDRESS, peer);
+                }
+                cmd
+            }
+        };
+        self.radio.respond_at = None;
+        self.apply_cmd(cmd);
+    }
+}
+
+/// Processes all packets the `Responder` can handle without blocking.
+fn run_responder<A: AttributeProvider>(responder: &mut Responder<SimConfig<A>>) {
+    loop {
+        match responder.process_one() {
+            Ok(()) => {}
+            Err(Error::Eof) | Err(Error::TxBusy) | Err(Error::RxBusy) => break,
+            Err(e) => panic!("fatal responder error: {:?}", e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rubble::att::{AttUuid, Attribute, Handle, HandleRange, NoAttributes};
+    use rubble::gatt::{characteristic::Properties, StaticAttributes};
+    use rubble::l2cap::Channel;
+    use rubble::link::llcp::{ControlPdu, VersionInfo, VersionNumber};
+    use rubble::link::{data::Llid, filter::PeerFilter, CompanyId, FeatureSet};
+    use rubble::security::{AesProvider, Bond, LinkSecurity, LongTermKey};
+    use rubble::uuid::consts::{characteristics, services};
+
+    fn connect(air: AirConfig) -> Simulation<NoAttributes> {
+        let mut sim = Simulation::new(NoAttributes, air);
+        sim.start_advertising(Duration::from_millis(20), &[])
+            .unwrap();
+        sim.central().connect(ConnectParams::default());
+        sim.run_for(Duration::from_millis(500));
+        sim
+    }
+
+    #[test]
+    fn connection_established() {
+        let mut sim = connect(AirConfig::default());
+        assert!(sim.central().is_connected());
+        assert!(sim.link_layer().is_connected());
+        assert_eq!(sim.central().connect_attempts(), 1);
+
+        // Connection stays alive by exchanging empty PDUs
+        sim.run_for(Duration::from_secs(2));
+        assert!(sim.link_layer().is_connected());
+        assert!(sim.central().received().is_empty());
+    }
+
+    #[test]
+    fn peer_filter() {
+        let mut sim = Simulation::new(NoAttributes, AirConfig::default());
+        let central = sim.central().address();
+        let other = DeviceAddress::new([1, 2, 3, 4, 5, 6], AddressKind::Random);
+        sim.link_layer()
+            .set_peer_filter(Some(PeerFilter::from_slice(&[other]).unwrap()));
+        sim.start_advertising(Duration::from_millis(20), &[])
+            .unwrap();
+        sim.central().connect(ConnectParams::default());
+        sim.run_for(Duration::from_millis(500));
+        assert!(!sim.link_layer().is_connected());
+
+        sim.link_layer()
+            .set_peer_filter(Some(PeerFilter::from_slice(&[other, central]).unwrap()));
+        sim.central().connect(ConnectParams::default());
+        sim.run_for(Duration::from_millis(500));
+        assert!(sim.link_layer().is_connected());
+        assert_eq!(sim.central().connect_attempts(), 2);
+    }
+
+    #[test]
+    fn connection_establishment_timeout() {
+        let mut sim = Simulation::new(NoAttributes, AirConfig::default());
+        sim.start_advertising(Duration::from_millis(20), &[])
+            .unwrap();
+        sim.central().connect(ConnectParams::default());
+        while !sim.link_layer().is_connected() {
+            sim.run_for(Duration::from_millis(1));
+        }
+
+        // The central's packets never arrive, so the connection is never established
+        sim.set_air(AirConfig {
+            loss_percent: 100,
+            ..AirConfig::default()
+        });
+        let interval = ConnectParams::default().interval;
+        sim.run_for(interval.saturating_mul(7));
+        assert!(sim.link_layer().is_advertising());
+
+        // Advertising resumes with the original parameters, so the central can connect again
+        sim.set_air(AirConfig::default());
+        sim.central().connect(ConnectParams::default());
+        sim.run_for(Duration::from_millis(500));
+        assert!(sim.link_layer().is_connected());
+        assert_eq!(sim.central().connect_attempts(), 2);
+    }
+
+    #[test]
+    fn update_adv_data() {
+        use crate::replay::Replay;
+
+        let mut replay = Replay::new(NoAttributes, PERIPHERAL_ADDRESS);
+        replay
+            .start_advertising(
+                Duration::from_millis(20),
+                &[AdStructure::CompleteLocalName("old")],
+            )
+            .unwrap();
+        replay.run_until(Instant::from_raw_micros(50_000));
+        let sent = replay.transmitted();
+        assert!(!sent.is_empty());
+        assert!(sent.iter().all(|p| p.payload.ends_with(b"old")));
+
+        // The next advertising event is not rescheduled
+        let next = replay.link_layer().next_event().unwrap();
+        replay
+            .link_layer()
+            .update_adv_data(&[AdStructure::CompleteLocalName("new")])
+            .unwrap();
+        assert_eq!(
+            replay.link_layer().next_event().unwrap().raw_micros(),
+            next.raw_micros()
+        );
+
+        // Data that doesn't fit is rejected without changing the PDU
+        let long = "x".repeat(40);
+        assert_eq!(
+            replay
+                .link_layer()
+                .update_adv_data(&[AdStructure::CompleteLocalName(&long)]),
+            Err(Error::Eof)
+        );
+
+        replay.take_events();
+        replay.run_until(next);
+        let sent = replay.transmitted();
+        assert!(!sent.is_empty());
+        assert!(sent.iter().all(|p| p.payload.ends_with(b"new")));
+
+        let mut sim = connect(AirConfig::default());
+        assert_eq!(
+            sim.link_layer().update_adv_data(&[]),
+            Err(Error::InvalidValue)
+        );
+    }
+
+    #[test]
+    fn connection_info() {
+        let mut sim = connect(AirConfig::default());
+        let now = sim.now();
+        let info = sim.link_layer().connection().unwrap().info();
+        let interval = ConnectParams::default().interval;
+
+        let anchor = info.anchor.unwrap();
+        assert!(now - anchor < interval);
+        assert_eq!(info.next_event.unwrap() - anchor, interval);
+        assert_eq!(info.window_widening, Duration::from_micros(0));
+    }
+
+    #[test]
+    fn llcp_feature_exchange() {
+        let mut sim = connect(AirConfig::default());
+        sim.central().send_control(&ControlPdu::FeatureReq {
+            features_master: FeatureSet::all(),
+        });
+        sim.run_for(Duration::from_millis(50));
+
+        let received = sim.central().received_control();
+        assert_eq!(received.len(), 1);
+        match &received[0] {
+            ControlPdu::FeatureRsp { features_used } => {
+                assert!(features_used.contains(FeatureSet::supported()));
+            }
+            pdu => panic!("unexpected response {:?}", pdu),
+        }
+    }
+
+    #[test]
+    fn llcp_feature_gating() {
+        let mut sim = connect(AirConfig::default());
+        assert_eq!(sim.link_layer().connection().unwrap().features(), None);
+        sim.central().send_control(&ControlPdu::FeatureReq {
+            features_master: FeatureSet::all(),
+        });
+        sim.run_for(Duration::from_millis(50));
+        sim.central().take_received();
+
+        // The simulated radio can't change its transmit power, so LE Power Control isn't used
+        let features = sim.link_layer().connection().unwrap().features();
+        assert_eq!(features, Some(FeatureSet::supported()));
+        sim.responder().send_power_control_request(0, 0).unwrap();
+        sim.run_for(Duration::from_millis(50));
+        assert!(sim.central().received().is_empty());
+        assert!(sim.link_layer().is_connected());
+    }
+
+    #[test]
+    fn llcp_version_exchange() {
+        let mut sim = connect(AirConfig::default());
+        assert_eq!(sim.link_layer().connection().unwrap().peer_version(), None);
+        // `LL_VERSION_IND`: Bluetooth 5.0, company 0x0059, subversion 0x1234
+        let version = [0x0C, 0x09, 0x59, 0x00, 0x34, 0x12];
+        sim.central().send(Llid::Control, &version);
+        sim.run_for(Duration::from_millis(50));
+
+        let received = sim.central().received_control();
+        assert_eq!(received.len(), 1);
+        match &received[0] {
+            ControlPdu::VersionInd {
+                vers_nr,
+                comp_id,
+                sub_vers_nr,
+            } => {
+                assert_eq!(*vers_nr, rubble::BLUETOOTH_VERSION);
+                assert_eq!(*comp_id, CompanyId::UNASSIGNED);
+                assert_eq!(sub_vers_nr.0, 0);
+            }
+            pdu => panic!("unexpected response {:?}", pdu),
+        }
+        sim.central().take_received();
+        assert_eq!(
+            sim.link_layer().connection().unwrap().peer_version(),
+            Some(VersionInfo {
+                version: VersionNumber::V5_0,
+                company: CompanyId::from_raw(0x0059),
+                subversion: 0x1234,
+            })
+        );
+
+        // Our `LL_VERSION_IND` is only sent once per connection
+        sim.central().send(Llid::Control, &version);
+        sim.run_for(Duration::from_millis(50));
+        assert!(sim.central().received_control().is_empty());
+        assert!(sim.link_layer().is_connected());
+    }
+
+    #[test]
+    fn llcp_reject_unsupported() {
+        let mut sim = connect(AirConfig::default());
+        // Unknown opcode, and `LL_LENGTH_REQ` before the features are known
+        sim.central().send(Llid::Control, &[0xF0]);
+        sim.central()
+            .send(Llid::Control, &[0x14, 27, 0, 0x48, 0x01, 27, 0, 0x48, 0x01]);
+        sim.run_for(Duration::from_millis(100));
+        assert_eq!(
+            sim.central().take_received(),
+            [
+                (Llid::Control, vec![0x07, 0xF0]),
+                (Llid::Control, vec![0x07, 0x14]),
+            ]
+        );
+
+        // Once the central is known to support it, `LL_REJECT_EXT_IND` is used
+        sim.central().send_control(&ControlPdu::FeatureReq {
+            features_master: FeatureSet::EXTENDED_REJECT_INDICATION,
+        });
+        sim.central()
+            .send(Llid::Control, &[0x14, 27, 0, 0x48, 0x01, 27, 0, 0x48, 0x01]);
+        sim.run_for(Duration::from_millis(100));
+        let received = sim.central().take_received();
+        assert_eq!(received.len(), 2);
+        assert_eq!(received[1], (Llid::Control, vec![0x11, 0x14, 0x1A]));
+
+        // Responses are never answered
+        sim.central().send(Llid::Control, &[0x07, 0x12]);
+        sim.run_for(Duration::from_millis(100));
+        assert!(sim.central().received().is_empty());
+        assert!(sim.link_layer().is_connected());
+    }
+
+    #[test]
+    fn llcp_keep_alive() {
+        let mut sim = connect(AirConfig::default());
+        sim.central().send_control(&ControlPdu::PingReq);
+        sim.run_for(Duration::from_millis(50));
+        assert_eq!(sim.central().take_received(), [(Llid::Control, vec![0x13])]);
+
+        // Idle for more than half of the 1 s supervision timeout since the `LL_PING_RSP`
+        sim.run_for(Duration::from_millis(500));
+        assert!(sim.central().received().is_empty());
+        sim.link_layer()
+            .connection_mut()
+            .unwrap()
+            .set_keep_alive(50);
+        sim.run_for(Duration::from_millis(50));
+        assert_eq!(sim.central().take_received(), [(Llid::Control, vec![0x12])]);
+
+        // The response restarts the idle time
+        sim.central().send_control(&ControlPdu::PingRsp);
+        sim.run_for(Duration::from_millis(400));
+        assert!(sim.central().received().is_empty());
+        sim.run_for(Duration::from_millis(200));
+        assert_eq!(sim.central().take_received(), [(Llid::Control, vec![0x12])]);
+        assert!(sim.link_layer().is_connected());
+    }
+
+    #[test]
+    fn llcp_procedure_timeout() {
+        let mut sim = connect(AirConfig::default());
+        sim.responder().send_power_control_request(0, 0).unwrap();
+        sim.responder().send_power_control_request(0, 0).unwrap();
+        sim.run_for(Duration::from_secs(39));
+
+        // The central never responds, so the second request stays queued
+        let received = sim.central().received_control();
+        assert_eq!(received.len(), 1);
+        assert!(matches!(received[0], ControlPdu::PowerControlReq { .. }));
+        assert!(sim.link_layer().is_connected());
+
+        sim.run_for(Duration::from_secs(2));
+        assert!(!sim.link_layer().is_connected());
+    }
+
+    #[test]
+    fn att_mtu_exchange() {
+        let mut sim = connect(AirConfig::default());
+        // ATT_EXCHANGE_MTU_REQ with an MTU of 100
+        sim.central().send_l2cap(Channel::ATT, &[0x02, 100, 0]);
+        sim.run_for(Duration::from_millis(50));
+
+        let received = sim.central().received_l2cap(Channel::ATT);
+        assert_eq!(received.len(), 1);
+        assert_eq!(received[0][0], 0x03, "expected ATT_EXCHANGE_MTU_RSP");
+    }
+
+    #[test]
+    fn att_response_waits_for_tx_space() {
+        let mut sim = connect(AirConfig::default());
+        let handle = Handle::from_raw(0x0003);
+        let mut notifications = 0;
+        while let Some(att) = sim.responder().att() {
+            att.notify_raw(handle, &[0xAB; 8]);
+            notifications += 1;
+        }
+        assert!(notifications > 0);
+
+        // The response doesn't fit in the TX queue until the notifications are sent
+        sim.central().send_l2cap(Channel::ATT, &[0x02, 100, 0]);
+        sim.run_for(Duration::from_millis(500));
+
+        let received = sim.central().received_l2cap(Channel::ATT);
+        assert_eq!(received.len(), notifications + 1);
+        assert_eq!(
+            received[notifications][0], 0x03,
+            "expected ATT_EXCHANGE_MTU_RSP"
+        );
+    }
+
+    #[test]
+    fn throughput_mode() {
+        fn fill_tx_queue(sim: &mut Simulation<NoAttributes>) -> usize {
+            let mut notifications = 0;
+            while let Some(att) = sim.responder().att() {
+                att.notify_raw(Handle::from_raw(0x0003), &[0xAB; 20]);
+                notifications += 1;
+            }
+            notifications
+        }
+
+        let mut sim = connect(AirConfig::default());
+        let interval = ConnectParams::default().interval;
+        assert!(fill_tx_queue(&mut sim) > 2);
+        sim.run_for(interval);
+        assert_eq!(sim.central().take_received().len(), 1);
+        sim.run_for(Duration::from_millis(100));
+        sim.central().take_received();
+
+        sim.link_layer()
+            .connection_mut()
+            .unwrap()
+            .set_throughput_mode(true);
+        let notifications = fill_tx_queue(&mut sim);
+        sim.run_for(interval);
+        assert_eq!(sim.central().take_received().len(), notifications);
+
+        let stats = *sim.link_layer().connection().unwrap().stats();
+        sim.run_for(Duration::from_millis(100));
+        assert!(sim.link_layer().is_connected());
+        assert_eq!(
+            sim.link_layer().connection().unwrap().stats().missed_events,
+            stats.missed_events
+        );
+    }
+
+    #[test]
+    fn malformed_att_request() {
+        let mut sim = connect(AirConfig::default());
+        // ATT_READ_REQ without a handle
+        sim.central().send_l2cap(Channel::ATT, &[0x0A]);
+        sim.run_for(Duration::from_millis(50));
+
+        // ATT_ERROR_RSP with "Invalid PDU"
+        let received = sim.central().received_l2cap(Channel::ATT);
+        assert_eq!(received, [&[0x01, 0x0A, 0x00, 0x00, 0x04][..]]);
+        assert!(sim.link_layer().is_connected());
+    }
+
+    #[test]
+    fn malformed_l2cap_message() {
+        let mut sim = connect(AirConfig::default());
+        // Truncated L2CAP header, and a length shorter than the payload
+        sim.central().send(Llid::DataStart, &[0x03]);
+        sim.central()
+            .send(Llid::DataStart, &[0x01, 0x00, 0x04, 0x00, 0x02, 100, 0]);
+        // ATT_EXCHANGE_MTU_REQ is still answered
+        sim.central().send_l2cap(Channel::ATT, &[0x02, 100, 0]);
+        sim.run_for(Duration::from_millis(100));
+
+        let received = sim.central().received_l2cap(Channel::ATT);
+        assert_eq!(received.len(), 1);
+        assert_eq!(received[0][0], 0x03, "expected ATT_EXCHANGE_MTU_RSP");
+        assert!(sim.link_layer().is_connected());
+    }
+
+    #[test]
+    fn invalid_channel_map() {
+        let mut sim = connect(AirConfig::default());
+        // LL_CHANNEL_MAP_IND without any used channels
+        sim.central()
+            .send(Llid::Control, &[0x01, 0, 0, 0, 0, 0, 0x10, 0x00]);
+        sim.run_for(Duration::from_millis(50));
+        assert!(!sim.link_layer().is_connected());
+    }
+
+    #[test]
+    fn disconnect() {
+        let mut sim = connect(AirConfig::default());
+        sim.central().disconnect();
+        sim.run_for(Duration::from_millis(50));
+        assert!(!sim.link_layer().is_connected());
+
+        // The peripheral doesn't acknowledge the `LL_TERMINATE_IND`, so the central only gives up
+        // after the supervision timeout.
+        sim.run_for(Duration::from_secs(2));
+        assert!(!sim.central().is_connected());
+    }
+
+    #[test]
+    fn reconnect_directed() {
+        let mut sim = connect(AirConfig::default());
+        sim.central().disconnect();
+        sim.run_for(Duration::from_secs(2));
+        assert!(!sim.central().is_connected());
+
+        let (peer, tx, rx) = sim.link_layer().take_released_queues().unwrap();
+        assert_eq!(peer.address, sim.central().address());
+        let Simulation { ll, radio, .. } = &mut sim;
+        ll.start_directed_advertise(Duration::from_millis(20), peer.address, radio, tx, rx)
+            .unwrap();
+        sim.flush_air();
+        sim.central().connect(ConnectParams::default());
+        sim.run_for(Duration::from_millis(500));
+        assert!(sim.link_layer().is_connected());
+        assert_eq!(sim.central().connect_attempts(), 2);
+    }
+
+    /// A Battery Level characteristic, readable only over an encrypted link.
+    struct EncryptedBattery(StaticAttributes<3>);
+
+    impl EncryptedBattery {
+        fn new() -> Self {
+            let mut attributes = StaticAttributes::new();
+            attributes
+                .push_primary_service(services::BATTERY.into())
+                .unwrap();
+            attributes
+                .push_characteristic(
+                    characteristics::BATTERY_LEVEL.into(),
+                    Properties::READ,
+                    &[48u8],
+                    Default::default(),
+                )
+                .unwrap();
+            EncryptedBattery(attributes)
+        }
+    }
+
+    impl AttributeProvider for EncryptedBattery {
+        fn for_attrs_in_range(
+            &mut self,
+            range: HandleRange,
+            mut f: impl FnMut(&Self, &Attribute<dyn AsRef<[u8]>>) -> Result<(), Error>,
+        ) -> Result<(), Error> {
+            let this = &*self;
+            this.0.for_each_in_range(range, |attr| f(this, attr))
+        }
+
+        fn is_grouping_attr(&self, uuid: AttUuid) -> bool {
+            self.0.is_grouping_attr(uuid)
+        }
+
+        fn group_end(&self, handle: Handle) -> Option<&Attribute<dyn AsRef<[u8]>>> {
+            self.0.group_end(handle)
+        }
+
+        fn attr_security(&self, _handle: Handle) -> LinkSecurity {
+            LinkSecurity::Encrypted
+        }
+    }
+
+    #[test]
+    fn encryption() {
+        const LTK: u128 = 0x4C68_3841_39F5_74D8_36BC_F34E_9DFB_01BF;
+        const EDIV: u16 = 0x2474;
+        const RAND: u64 = 0xABCD_EF12_3456_7890;
+
+        let mut bonds = BondManager::new();
+        bonds
+            .restore(Bond {
+                peer: Central::default().address(),
+                authenticated: false,
+                key_size: 16,
+                local_ltk: Some(LongTermKey {
+                    ltk: LTK,
+                    ediv: EDIV,
+                    rand: RAND,
+                }),
+                local_csrk: None,
+                peer_ltk: None,
+                peer_irk: None,
+                peer_identity: None,
+                peer_csrk: None,
+            })
+            .unwrap();
+        let mut sim = Simulation::with_bonds(EncryptedBattery::new(), bonds, AirConfig::default());
+        sim.start_advertising(Duration::from_millis(20), &[])
+            .unwrap();
+        sim.central().connect(ConnectParams::default());
+        sim.run_for(Duration::from_millis(500));
+
+        // ATT_READ_REQ of the Battery Level is rejected with "Insufficient Encryption"
+        sim.central().send_l2cap(Channel::ATT, &[0x0A, 3, 0]);
+        sim.run_for(Duration::from_millis(50));
+        assert_eq!(
+            sim.central().received_l2cap(Channel::ATT),
+            [&[0x01, 0x0A, 3, 0, 0x0F]]
+        );
+        sim.central().take_received();
+
+        // Unknown keys are rejected with "PIN or Key Missing"
+        sim.central().start_encryption(LTK, EDIV, RAND + 1);
+        sim.run_for(Duration::from_millis(100));
+        let received = sim.central().received_control();
+        assert_eq!(received.len(), 1);
+        assert!(
+            matches!(received[0], ControlPdu::RejectInd { error_code } if error_code.0 == 0x06)
+        );
+        assert!(!sim.central().is_encrypted());
+        assert!(!sim.link_layer().connection().unwrap().is_encrypted());
+        sim.central().take_received();
+
+        sim.central().start_encryption(LTK, EDIV, RAND);
+        sim.run_for(Duration::from_millis(100));
+        let received = sim.central().received_control();
+        assert_eq!(received.len(), 3);
+        assert!(matches!(received[0], ControlPdu::EncRsp { .. }));
+        assert!(matches!(received[1], ControlPdu::StartEncReq));
+        assert!(matches!(received[2], ControlPdu::StartEncRsp));
+        assert!(sim.central().is_encrypted());
+        assert!(sim.link_layer().connection().unwrap().is_encrypted());
+        sim.central().take_received();
+
+        // The ATT server was told about the encrypted link
+        sim.central().send_l2cap(Channel::ATT, &[0x0A, 3, 0]);
+        sim.run_for(Duration::from_millis(50));
+        assert_eq!(sim.central().received_l2cap(Channel::ATT), [&[0x0B, 48]]);
+        sim.central().take_received();
+
+        // Changing the key pauses encryption first
+        sim.central().start_encryption(LTK, EDIV, RAND);
+        sim.run_for(Duration::from_millis(100));
+        let received = sim.central().received_control();
+        assert_eq!(received.len(), 4);
+        assert!(matches!(received[0], ControlPdu::PauseEncRsp));
+        assert!(matches!(received[1], ControlPdu::EncRsp { .. }));
+        assert!(matches!(received[2], ControlPdu::StartEncReq));
+        assert!(matches!(received[3], ControlPdu::StartEncRsp));
+        assert!(sim.link_layer().connection().unwrap().is_encrypted());
+        sim.central().take_received();
+
+        sim.central().send_l2cap(Channel::ATT, &[0x0A, 3, 0]);
+        sim.run_for(Duration::from_millis(50));
+        assert_eq!(sim.central().received_l2cap(Channel::ATT), [&[0x0B, 48]]);
+        assert!(sim.link_layer().is_connected());
+    }
+
+    /// The security function `e` (AES-128).
+    fn e(key: u128, plaintext: u128) -> u128 {
+        let mut block = plaintext.to_be_bytes();
+        SoftAes.encrypt_block(&key.to_be_bytes(), &mut block);
+        u128::from_be_bytes(block)
+    }
+
+    /// Reads a little-endian integer of up to 16 Bytes.
+    fn le_int(bytes: &[u8]) -> u128 {
+        let mut buf = [0; 16];
+        buf[..bytes.len()].copy_from_slice(bytes);
+        u128::from_le_bytes(buf)
+    }
+
+    #[test]
+    fn pairing_key_distribution() {
+        let mut sim = connect(AirConfig::default());
+        let ia = sim.central().address();
+        let ra = PERIPHERAL_ADDRESS;
+
+        // Just Works (TK = 0), bonding, the peripheral distributes its LTK
+        let preq = [0x01, 0x03, 0x00, 0x01, 16, 0x00, 0x01];
+        sim.central()
+            .send_l2cap(Channel::LE_SECURITY_MANAGER, &preq);
+        sim.run_for(Duration::from_millis(50));
+        let pres = sim.central().received_l2cap(Channel::LE_SECURITY_MANAGER)[0].to_vec();
+        assert_eq!(pres.len(), 7);
+        assert_eq!(pres[0], 0x02, "expected Pairing Response");
+
+        // Mconfirm = c1(TK, Mrand, ...)
+        let mrand = 0x1234_5678_9ABC_DEF0_0FED_CBA9_8765_4321;
+        let p1 = le_int(&pres) << 72
+            | le_int(&preq) << 16
+            | u128::from(ra.is_random()) << 8
+            | u128::from(ia.is_random());
+        let p2 = le_int(ia.raw()) << 48 | le_int(ra.raw());
+        let mconfirm = e(0, e(0, mrand ^ p1) ^ p2);
+        let mut cmd = vec![0x03];
+        cmd.extend_from_slice(&mconfirm.to_le_bytes());
+        sim.central().send_l2cap(Channel::LE_SECURITY_MANAGER, &cmd);
+        cmd[0] = 0x04;
+        cmd[1..].copy_from_slice(&mrand.to_le_bytes());
+        sim.central().send_l2cap(Channel::LE_SECURITY_MANAGER, &cmd);
+        sim.run_for(Duration::from_millis(100));
+
+        let received = sim.central().received_l2cap(Channel::LE_SECURITY_MANAGER);
+        assert_eq!(received.len(), 3, "keys must not be sent before encryption");
+        assert_eq!(received[2][0], 0x04, "expected Pairing Random");
+        let srand = le_int(&received[2][1..]);
+        let stk = e(
+            0,
+            (srand & 0xFFFF_FFFF_FFFF_FFFF) << 64 | mrand & 0xFFFF_FFFF_FFFF_FFFF,
+        );
+        sim.central().take_received();
+
+        // The STK is requested with EDIV and Rand set to 0, then the LTK is distributed
+        sim.central().start_encryption(stk, 0, 0);
+        sim.run_for(Duration::from_millis(100));
+        assert!(sim.central().is_encrypted());
+        let received = sim.central().received_l2cap(Channel::LE_SECURITY_MANAGER);
+        assert_eq!(received.len(), 2);
+        assert_eq!(received[0][0], 0x06, "expected Encryption Information");
+        assert_eq!(received[1][0], 0x07, "expected Master Identification");
+        let ltk = le_int(&received[0][1..]);
+        let ediv = u16::from_le_bytes([received[1][1], received[1][2]]);
+        let rand = le_int(&received[1][3..]) as u64;
+        sim.central().take_received();
+
+        // The bond was stored, so the distributed LTK can encrypt the link
+        sim.central().start_encryption(ltk, ediv, rand);
+        sim.run_for(Duration::from_millis(100));
+        let received = sim.central().received_control();
+        assert_eq!(received.len(), 4);
+        assert!(matches!(received[3], ControlPdu::StartEncRsp));
+        assert!(sim.link_layer().connection().unwrap().is_encrypted());
+    }
+
+    #[test]
+    fn lossy_air() {
+        let mut sim = connect(AirConfig {
+            loss_percent: 20,
+            latency: Duration::from_micros(5),
+            seed: 1234,
+        });
+        assert!(sim.link_layer().is_connected());
+
+        for _ in 0..5 {
+            sim.central().send_control(&ControlPdu::FeatureReq {
+                features_master: FeatureSet::all(),
+            });
+        }
+        sim.run_for(Duration::from_millis(500));
+
+        // Every request is answered exactly once despite retransmissions
+        assert!(sim.lost_packets() > 0);
+        assert_eq!(sim.central().received_control().len(), 5);
+        let stats = sim.link_layer().connection().unwrap().stats();
+        assert!(stats.missed_events > 0);
+    }
+}
@@ -0,0 +1,709 @@
+//! Deterministic replay of recorded packet traces.
+//!
+//! A [`Replay`] feeds the packets sent by a central, recorded as [`TraceRecord`]s, into a fresh
+//! Rubble peripheral at their original timestamps. Timer events scheduled by the `LinkLayer` in
+//! between are run in simulated time, and everything the peripheral does in response is recorded
+//! as a [`ReplayEvent`]. This allows turning a misbehaving connection seen in the wild into a
+//! regression test.
+//!
+//! Traces can be obtained from `pcap` files using the `LINKTYPE_BLUETOOTH_LE_LL_WITH_PHDR` link
+//! type, which is what the nRF Sniffer for Wireshark and Rubble's own capture support produce (see
+//! [`parse_pcap`]). A sniffer also records the packets sent by the peripheral, which have to be
+//! removed with [`central_records`] before replaying the trace. Traces of simulated connections
+//! are available via [`Simulation::trace`].
+//!
+//! ```
+//! use rubble::time::Duration;
+//! use rubble_sim::replay::{Replay, ReplayEvent};
+//! use rubble_sim::{AirConfig, ConnectParams, Simulation, PERIPHERAL_ADDRESS};
+//!
+//! let mut sim = Simulation::new(rubble::att::NoAttributes, AirConfig::default());
+//! sim.start_advertising(Duration::from_millis(20), &[]).unwrap();
+//! sim.central().connect(ConnectParams::default());
+//! sim.run_for(Duration::from_millis(200));
+//!
+//! let mut replay = Replay::new(rubble::att::NoAttributes, PERIPHERAL_ADDRESS);
+//! replay.start_advertising(Duration::from_millis(20), &[]).unwrap();
+//! replay.run(sim.trace());
+//! assert!(replay.link_layer().is_connected());
+//! assert!(!replay
+//!     .events()
+//!     .iter()
+//!     .any(|event| matches!(event, ReplayEvent::Missed { .. })));
+//! ```
+//!
+//! [`Replay`]: struct.Replay.html
+//! [`TraceRecord`]: struct.TraceRecord.html
+//! [`ReplayEvent`]: enum.ReplayEvent.html
+//! [`parse_pcap`]: fn.parse_pcap.html
+//! [`central_records`]: fn.central_records.html
+//! [`Simulation::trace`]: ../struct.Simulation.html#method.trace
+
+use crate::{
+    channel_mapper, AirPacket, Queues, SimClock, SimConfig, SimRadio, SimTimer, QUEUE_PACKETS,
+};
+use rubble::att::AttributeProvider;
+use rubble::bytes::ByteReader;
+use rubble::l2cap::L2CAPState;
+use rubble::link::{
+    ad_structure::AdStructure,
+    advertising, data,
+    queue::{PacketQueue, RingQueue},
+    Cmd, DeviceAddress, LinkLayer, RadioCmd, Responder,
+};
+use rubble::security::BondManager;
+use rubble::time::{Duration, Instant};
+use rubble::Error;
+use std::cell::Cell;
+use std::rc::Rc;
+
+/// `pcap` link type of traces accepted by `parse_pcap` (`LINKTYPE_BLUETOOTH_LE_LL_WITH_PHDR`).
+const LINKTYPE_BLUETOOTH_LE_LL_WITH_PHDR: u32 = 256;
+
+/// `pcap` magic numbers for microsecond and nanosecond timestamps.
+const MAGIC_MICROS: u32 = 0xA1B2_C3D4;
+const MAGIC_NANOS: u32 = 0xA1B2_3C4D;
+
+/// Pseudo-header flags.
+const FLAG_CRC_CHECKED: u16 = 0x0400;
+const FLAG_CRC_VALID: u16 = 0x0800;
+
+/// Min. time between the end of the last packet of a connection event and the end of the first
+/// packet of the next one.
+///
+/// Within a connection event, packets are separated by T_IFS, so the ends of 2 consecutive packets
+/// are at most T_IFS plus the airtime of a maximum-length packet apart. Connection intervals are
+/// at least 7.5 ms long.
+const EVENT_GAP: Duration = Duration::from_micros(3_000);
+
+/// A packet received by the peripheral, as recorded in a trace.
+#[derive(Debug, Clone)]
+pub struct TraceRecord {
+    /// Time at which the reception of the packet ended.
+    pub timestamp: Instant,
+
+    /// The RF channel (0-39) the packet was sent on.
+    pub rf_channel: u8,
+
+    /// Access Address of the packet.
+    pub access_address: u32,
+
+    /// Whether the packet was received with a correct CRC.
+    pub crc_ok: bool,
+
+    /// Raw 16-bit PDU header.
+    pub header: u16,
+
+    /// PDU payload.
+    pub payload: Vec<u8>,
+}
+
+impl TraceRecord {
+    /// Creates a record of an advertising channel PDU, given as its header followed by the
+    /// payload.
+    ///
+    /// # Panics
+    ///
+    /// This will panic if `pdu` is shorter than 2 Bytes.
+    pub fn advertising(timestamp: Instant, rf_channel: u8, pdu: &[u8]) -> Self {
+        Self::data(timestamp, rf_channel, advertising::ACCESS_ADDRESS, pdu)
+    }
+
+    /// Creates a record of a data channel PDU, given as its header followed by the payload.
+    ///
+    /// # Panics
+    ///
+    /// This will panic if `pdu` is shorter than 2 Bytes.
+    pub fn data(timestamp: Instant, rf_channel: u8, access_address: u32, pdu: &[u8]) -> Self {
+        Self {
+            timestamp,
+            rf_channel,
+            access_address,
+            crc_ok: true,
+            header: u16::from_le_bytes([pdu[0], pdu[1]]),
+            payload: pdu[2..].to_vec(),
+        }
+    }
+
+    /// Creates a record of a packet sent over the simulated air interface, received at `rx_end`.
+    pub(crate) fn from_air(packet: &AirPacket, rx_end: Instant) -> Self {
+        Self {
+            timestamp: rx_end,
+            rf_channel: ((packet.freq - 2402) / 2) as u8,
+            access_address: packet.access_address,
+            crc_ok: true,
+            header: packet.header,
+            payload: packet.payload.clone(),
+        }
+    }
+
+    /// Returns the frequency of the record's RF channel in MHz.
+    pub fn freq(&self) -> u16 {
+        2402 + 2 * u16::from(self.rf_channel)
+    }
+
+    /// Returns whether the packet was sent on an advertising channel.
+    pub fn is_advertising(&self) -> bool {
+        self.access_address == advertising::ACCESS_ADDRESS
+    }
+}
+
+/// Parses a `pcap` file using the `LINKTYPE_BLUETOOTH_LE_LL_WITH_PHDR` link type.
+///
+/// The timestamps of the returned records are relative to the first record, which is placed at
+/// `Instant::from_raw_micros(0)`. Only little-endian files are supported.
+///
+/// Returns `Error::InvalidValue` if the file isn't a little-endian `pcap` file of the right link
+/// type, and `Error::Eof` if it is truncated.
+pub fn parse_pcap(file: &[u8]) -> Result<Vec<TraceRecord>, Error> {
+    let mut reader = ByteReader::new(file);
+    let nanos = match reader.read_u32_le()? {
+        MAGIC_MICROS => false,
+        MAGIC_NANOS => true,
+        _ => return Err(Error::InvalidValue),
+    };
+    reader.skip(16)?; // version, timezone, accuracy, snapshot length
+    if reader.read_u32_le()? != LINKTYPE_BLUETOOTH_LE_LL_WITH_PHDR {
+        return Err(Error::InvalidValue);
+    }
+
+    let mut records = Vec::new();
+    let mut first = None;
+    while reader.bytes_left() > 0 {
+        let secs = u64::from(reader.read_u32_le()?);
+        let mut micros = u64::from(reader.read_u32_le()?);
+        if nanos {
+            micros /= 1_000;
+        }
+        let included = reader.read_u32_le()? as usize;
+        reader.skip(4)?; // original length
+        let mut packet = ByteReader::new(reader.read_slice(included)?);
+
+        let time = secs * 1_000_000 + micros;
+        let start = *first.get_or_insert(time);
+        let timestamp = Instant::from_raw_micros(time.wrapping_sub(start) as u32);
+
+        // Pseudo-header
+        let rf_channel = packet.read_u8()?;
+        packet.skip(7)?; // signal and noise power, AA offenses, reference AA
+        let flags = packet.read_u16_le()?;
+
+        // Packet, followed by its CRC
+        let access_address = packet.read_u32_le()?;
+        let header = packet.read_u16_le()?;
+        let len = usize::from(header.to_le_bytes()[1]);
+        let payload = packet.read_slice(len)?.to_vec();
+
+        records.push(TraceRecord {
+            timestamp,
+            rf_channel,
+            access_address,
+            crc_ok: flags & FLAG_CRC_CHECKED == 0 || flags & FLAG_CRC_VALID != 0,
+            header,
+            payload,
+        });
+    }
+
+    Ok(records)
+}
+
+/// Returns the records of a sniffer trace that were sent by the central.
+///
+/// Advertising channel PDUs whose first address field is `peripheral` are removed. On data
+/// channels, the central sends the first packet of every connection event and the devices take
+/// turns afterwards, so every second packet of an event is removed. A connection event is assumed
+/// to end when the channel changes or when no packet is received for a few milliseconds.
+///
+/// This only works for complete traces: If the sniffer missed a packet, the directions of the
+/// remaining packets of that connection event are mixed up.
+pub fn central_records(records: &[TraceRecord], peripheral: DeviceAddress) -> Vec<TraceRecord> {
+    let mut central = Vec::new();
+    let mut last: Option<&TraceRecord> = None;
+    let mut from_central = true;
+    for record in records {
+        if record.is_advertising() {
+            if record.payload.get(..6) != Some(&peripheral.raw()[..]) {
+                central.push(record.clone());
+            }
+            continue;
+        }
+
+        let new_event = match last {
+            Some(last) => {
+                last.is_advertising()
+                    || last.rf_channel != record.rf_channel
+                    || record.timestamp - last.timestamp > EVENT_GAP
+            }
+            None => true,
+        };
+        from_central = new_event || !from_central;
+        if from_central {
+            central.push(record.clone());
+        }
+        last = Some(record);
+    }
+    central
+}
+
+/// Something done by the peripheral during a replay.
+#[derive(Debug, Clone)]
+pub enum ReplayEvent {
+    /// The `LinkLayer` ran a scheduled timer event.
+    Timer {
+        /// Time at which the event ran.
+        time: Instant,
+
+        /// The `Cmd` returned by `LinkLayer::update_timer`.
+        cmd: Cmd,
+    },
+
+    /// A record of the trace was passed to the `LinkLayer`.
+    Received {
+        /// Index of the record in the trace.
+        index: usize,
+
+        /// The `Cmd` returned by `LinkLayer::process_*_packet`.
+        cmd: Cmd,
+    },
+
+    /// A record of the trace was dropped, since the radio wasn't listening for it.
+    Missed {
+        /// Index of the record in the trace.
+        index: usize,
+    },
+
+    /// The peripheral transmitted a packet.
+    Transmitted(AirPacket),
+}
+
+/// A Rubble peripheral fed with the packets of a recorded trace.
+///
+/// Packets are only passed to the `LinkLayer` if its radio is listening for them. Data channel
+/// packets have to match the channel and Access Address the radio listens on. Since advertising
+/// events are delayed by a random amount, the peripheral can't be expected to advertise on the
+/// same channels as the recorded device, so advertising channel packets are delivered whenever the
+/// radio listens on any advertising channel.
+///
+/// The `LinkLayer` is created with a default configuration. It can be adjusted via
+/// [`link_layer`] before calling [`start_advertising`].
+///
+/// [`link_layer`]: #method.link_layer
+/// [`start_advertising`]: #method.start_advertising
+pub struct Replay<A: AttributeProvider> {
+    clock: SimClock,
+    radio: SimRadio,
+    radio_cmd: RadioCmd,
+    /// Timer event scheduled by the `LinkLayer`.
+    scheduled: Rc<Cell<Option<Instant>>>,
+    ll: LinkLayer<SimConfig<A>>,
+    responder: Responder<SimConfig<A>>,
+    queues: Option<Queues<A>>,
+    events: Vec<ReplayEvent>,
+}
+
+impl<A: AttributeProvider> Replay<A> {
+    /// Creates a peripheral with device address `address`, hosting `attributes`.
+    ///
+    /// Connection requests are only accepted if they are addressed to `address`, so it has to match
+    /// the address of the device the trace was recorded with.
+    pub fn new(attributes: A, address: DeviceAddress) -> Self {
+        let clock = SimClock::default();
+        let tx: &'static mut RingQueue<QUEUE_PACKETS> = Box::leak(Box::new(RingQueue::new()));
+        let rx: &'static mut RingQueue<QUEUE_PACKETS> = Box::leak(Box::new(RingQueue::new()));
+        let (tx_prod, tx_cons) = tx.split();
+        let (rx_prod, rx_cons) = rx.split();
+        let scheduled = Rc::new(Cell::new(None));
+        let timer = SimTimer {
+            clock: clock.clone(),
+            scheduled: scheduled.clone(),
+        };
+
+        Self {
+            ll: LinkLayer::new(address, timer),
+            responder: Responder::new(
+                tx_prod,
+                rx_cons,
+                L2CAPState::new(channel_mapper(attributes, BondManager::new())),
+            ),
+            radio: SimRadio::new(clock.clone()),
+            clock,
+            radio_cmd: RadioCmd::Off,
+            scheduled,
+            queues: Some((tx_cons, rx_prod)),
+            events: Vec::new(),
+        }
+    }
+
+    /// Returns the current simulated time.
+    pub fn now(&self) -> Instant {
+        self.clock.now()
+    }
+
+    /// Returns the Link-Layer of the peripheral.
+    pub fn link_layer(&mut self) -> &mut LinkLayer<SimConfig<A>> {
+        &mut self.ll
+    }
+
+    /// Returns the `Responder` of the peripheral.
+    pub fn responder(&mut self) -> &mut Responder<SimConfig<A>> {
+        &mut self.responder
+    }
+
+    /// Returns the events recorded so far.
+    pub fn events(&self) -> &[ReplayEvent] {
+        &self.events
+    }
+
+    /// Removes and returns the events recorded so far.
+    pub fn take_events(&mut self) -> Vec<ReplayEvent> {
+        std::mem::take(&mut self.events)
+    }
+
+    /// Returns the packets transmitted by the peripheral so far.
+    pub fn transmitted(&self) -> Vec<&AirPacket> {
+        self.events
+            .iter()
+            .filter_map(|event| match event {
+                ReplayEvent::Transmitted(packet) => Some(packet),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Makes the peripheral start advertising.
+    ///
+    /// # Panics
+    ///
+    /// This panics if the peripheral is already advertising or connected, since the packet queues
+    /// are moved into the `LinkLayer`.
+    pub fn start_advertising(
+        &mut self,
+        interval: Duration,
+        data: &[AdStructure<'_>],
+    ) -> Result<(), Error> {
+        let (tx, rx) = self.queues.take().expect("advertising already started");
+        self.ll
+            .start_advertise(interval, data, &mut self.radio, tx, rx)?;
+        self.record_transmitted();
+        Ok(())
+    }
+
+    /// Makes the peripheral stop advertising, so that it can be restarted with
+    /// [`start_advertising`].
+    ///
+    /// Returns `false` if the peripheral wasn't advertising.
+    ///
+    /// [`start_advertising`]: #method.start_advertising
+    pub fn stop_advertising(&mut self) -> bool {
+        match self.ll.stop_advertising() {
+            Some(queues) => {
+                self.queues = Some(queues);
+                self.radio_cmd = RadioCmd::Off;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Replays `trace`, whose records must be ordered by their timestamps.
+    ///
+    /// Timer events scheduled up to and including the timestamp of a record run before the
+    /// record is delivered. Timer events after the last record are not run, use [`run_until`]
+    /// for that.
+    ///
+    /// The indices of the records are reported in `ReplayEvent`s, so a trace should be replayed
+    /// with a single call.
+    ///
+    /// [`run_until`]: #method.run_until
+    pub fn run(&mut self, trace: &[TraceRecord]) {
+        for (index, record) in trace.iter().enumerate() {
+            self.run_until(record.timestamp);
+            self.deliver(index, record);
+        }
+    }
+
+    /// Runs the timer events scheduled up to and including `end`, and advances the clock to `end`.
+    pub fn run_until(&mut self, end: Instant) {
+        while let Some(time) = self.scheduled.get() {
+            if time.raw_micros() > end.raw_micros() {
+                break;
+            }
+            if time.raw_micros() > self.now().raw_micros() {
+                self.clock.advance_to(time);
+            }
+
+            self.scheduled.set(None);
+            let cmd = self.ll.update_timer(&mut self.radio);
+            self.events.push(ReplayEvent::Timer {
+                time,
+                cmd: cmd.clone(),
+            });
+            self.apply_cmd(cmd);
+        }
+
+        if end.raw_micros() > self.now().raw_micros() {
+            self.clock.advance_to(end);
+        }
+    }
+
+    fn deliver(&mut self, index: usize, record: &TraceRecord) {
+        let listening = match &self.radio_cmd {
+            RadioCmd::Off => false,
+            RadioCmd::ListenAdvertising { .. } => record.is_advertising(),
+            RadioCmd::ListenData {
+                channel,
+                access_address,
+                ..
+            } => record.freq() == channel.freq() && record.access_address == *access_address,
+        };
+        if !listening {
+            self.events.push(ReplayEvent::Missed { index });
+            return;
+        }
+
+        let header = record.header.to_le_bytes();
+        self.radio.respond_at = Some(record.timestamp + Duration::T_IFS);
+        let cmd = match self.radio_cmd {
+            RadioCmd::ListenData { .. } => self.ll.process_data_packet(
+                record.timestamp,
+                &mut self.radio,
+                data::Header::parse(&header),
+                &record.payload,
+                record.crc_ok,
+            ),
+            _ => self.ll.process_adv_packet(
+                record.timestamp,
+                &mut self.radio,
+                advertising::Header::parse(&header),
+                &record.payload,
+                record.crc_ok,
+            ),
+        };
+        self.radio.respond_at = None;
+        self.events.push(ReplayEvent::Received {
+            index,
+            cmd: cmd.clone(),
+        });
+        self.apply_cmd(cmd);
+    }
+
+    fn apply_cmd(&mut self, cmd: Cmd) {
+        self.record_transmitted();
+        self.radio_cmd = cmd.radio;
+        if cmd.queued_work || cmd.tx_space_freed {
+            crate::run_responder(&mut self.responder);
+            self.record_transmitted();
+        }
+    }
+
+    fn record_transmitted(&mut self) {
+        let sent = std::mem::take(&mut self.radio.sent);
+        self.events
+            .extend(sent.into_iter().map(ReplayEvent::Transmitted));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{AirConfig, ConnectParams, Simulation, PERIPHERAL_ADDRESS};
+    use rubble::att::NoAttributes;
+    use rubble::link::capture::{write_pcap_header, CapturedPdu, Direction, MAX_PCAP_RECORD};
+    use rubble::link::data::Llid;
+    use rubble::link::llcp::ControlPdu;
+    use rubble::link::FeatureSet;
+
+    fn simulated_trace() -> Vec<TraceRecord> {
+        let mut sim = Simulation::new(NoAttributes, AirConfig::default());
+        sim.start_advertising(Duration::from_millis(20), &[])
+            .unwrap();
+        sim.central().connect(ConnectParams::default());
+        sim.run_for(Duration::from_millis(100));
+        sim.central().send_control(&ControlPdu::FeatureReq {
+            features_master: FeatureSet::all(),
+        });
+        sim.run_for(Duration::from_millis(100));
+        sim.trace().to_vec()
+    }
+
+    fn replay(trace: &[TraceRecord]) -> Replay<NoAttributes> {
+        let mut replay = Replay::new(NoAttributes, PERIPHERAL_ADDRESS);
+        replay
+            .start_advertising(Duration::from_millis(20), &[])
+            .unwrap();
+        replay.run(trace);
+        replay
+    }
+
+    fn missed(replay: &Replay<NoAttributes>) -> Vec<usize> {
+        replay
+            .events()
+            .iter()
+            .filter_map(|event| match event {
+                ReplayEvent::Missed { index } => Some(*index),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn replay_simulated_connection() {
+        let trace = simulated_trace();
+        assert!(trace[0].is_advertising());
+        assert!(!trace.last().unwrap().is_advertising());
+
+        let mut replay = replay(&trace);
+        assert_eq!(missed(&replay), []);
+        assert!(replay.link_layer().is_connected());
+
+        // Every data channel packet is answered, and the `LL_FEATURE_REQ` with an `LL_FEATURE_RSP`
+        let data = trace.iter().filter(|r| !r.is_advertising()).count();
+        let responses = replay
+            .transmitted()
+            .into_iter()
+            .filter(|p| p.access_address != advertising::ACCESS_ADDRESS)
+            .collect::<Vec<_>>();
+        assert_eq!(responses.len(), data);
+        assert!(responses.iter().any(|p| {
+            data::Header::parse(&p.header.to_le_bytes()).llid() == Llid::Control
+                && p.payload[0] == 0x09
+        }));
+    }
+
+    #[test]
+    fn replay_is_deterministic() {
+        let trace = simulated_trace();
+        let first = replay(&trace).transmitted().len();
+        let events = replay(&trace).take_events();
+        let second = events
+            .iter()
+            .filter(|e| matches!(e, ReplayEvent::Transmitted(_)))
+            .count();
+        assert_eq!(first, second);
+    }
+
+    #[test]
+    fn missed_transmit_window() {
+        let mut trace = simulated_trace();
+
+        // Move the first data channel packet past the end of the transmit window
+        let first = trace.iter().position(|r| !r.is_advertising()).unwrap();
+        let connect_ind = trace[first - 1].timestamp;
+        trace[first].timestamp = connect_ind + Duration::from_millis(4);
+        trace.truncate(first + 1);
+
+        let mut replay = replay(&trace);
+        assert_eq!(missed(&replay), [first]);
+
+        // The peripheral keeps listening during the next connection events
+        assert!(replay.link_layer().is_connected());
+    }
+
+    #[test]
+    fn stop_and_restart_advertising() {
+        let mut replay = replay(&[]);
+        replay.run_until(Instant::from_raw_micros(50_000));
+        assert!(!replay.transmitted().is_empty());
+
+        assert!(replay.stop_advertising());
+        assert!(!replay.stop_advertising());
+        assert!(!replay.link_layer().is_advertising());
+        assert!(replay.link_layer().next_event().is_none());
+        replay.take_events();
+        replay.run_until(Instant::from_raw_micros(200_000));
+        assert!(replay.transmitted().is_empty());
+
+        replay
+            .start_advertising(Duration::from_millis(20), &[])
+            .unwrap();
+        replay.run_until(Instant::from_raw_micros(250_000));
+        assert!(!replay.transmitted().is_empty());
+    }
+
+    #[test]
+    fn stop_advertising_while_connecting() {
+        let mut trace = simulated_trace();
+        let first = trace.iter().position(|r| !r.is_advertising()).unwrap();
+        trace.truncate(first);
+
+        let mut replay = replay(&trace);
+        assert!(replay.link_layer().is_connected());
+        assert!(replay.stop_advertising());
+        assert!(!replay.link_layer().is_connected());
+        assert!(replay.link_layer().next_event().is_none());
+    }
+
+    #[test]
+    fn pcap_round_trip() {
+        let trace = simulated_trace();
+
+        let mut file = vec![0; 24];
+        write_pcap_header(&mut file).unwrap();
+        for record in &trace {
+            let pdu = CapturedPdu {
+                direction: Direction::Rx,
+                timestamp: record.timestamp + Duration::from_secs(3),
+                rf_channel: record.rf_channel,
+                access_address: record.access_address,
+                crc_init: 0x555555,
+                crc_ok: record.crc_ok,
+                header: record.header,
+                payload: &record.payload,
+            };
+            let mut buf = [0; MAX_PCAP_RECORD];
+            let len = pdu.write_pcap_record(&mut buf).unwrap();
+            file.extend_from_slice(&buf[..len]);
+        }
+
+        // Timestamps are relative to the first record
+        let offset = trace[0].timestamp - Instant::from_raw_micros(0);
+        let parsed = parse_pcap(&file).unwrap();
+        assert_eq!(parsed.len(), trace.len());
+        for (parsed, record) in parsed.iter().zip(&trace) {
+            assert_eq!(
+                (parsed.timestamp + offset).raw_micros(),
+                record.timestamp.raw_micros()
+            );
+            assert_eq!(parsed.rf_channel, record.rf_channel);
+            assert_eq!(parsed.access_address, record.access_address);
+            assert_eq!(parsed.crc_ok, record.crc_ok);
+            assert_eq!(parsed.header, record.header);
+            assert_eq!(parsed.payload, record.payload);
+        }
+
+        assert_eq!(parse_pcap(&file[..30]).unwrap_err(), Error::Eof);
+        file[20] = 1;
+        assert_eq!(parse_pcap(&file).unwrap_err(), Error::InvalidValue);
+    }
+
+    #[test]
+    fn sniffer_directions() {
+        let central = [1, 2, 3, 4, 5, 6];
+        let mut adv_ind = vec![0x40, 6];
+        adv_ind.extend_from_slice(PERIPHERAL_ADDRESS.raw());
+        let mut scan_req = vec![0x43, 12];
+        scan_req.extend_from_slice(&central);
+        scan_req.extend_from_slice(PERIPHERAL_ADDRESS.raw());
+
+        let at = Instant::from_raw_micros;
+        let aa = 0x1234_5678;
+        let trace = [
+            TraceRecord::advertising(at(0), 0, &adv_ind),
+            TraceRecord::advertising(at(300), 0, &scan_req),
+            // Connection event with 3 packets
+            TraceRecord::data(at(10_000), 5, aa, &[0x01, 0]),
+            TraceRecord::data(at(10_230), 5, aa, &[0x01, 0]),
+            TraceRecord::data(at(10_460), 5, aa, &[0x01, 0]),
+            // Central's packet was lost
+            TraceRecord::data(at(20_000), 9, aa, &[0x01, 0]),
+            TraceRecord::data(at(30_000), 13, aa, &[0x09, 0]),
+        ];
+
+        let central = central_records(&trace, PERIPHERAL_ADDRESS);
+        let timestamps = central
+            .iter()
+            .map(|r| r.timestamp.raw_micros())
+            .collect::<Vec<_>>();
+        assert_eq!(timestamps, [300, 10_000, 10_460, 20_000, 30_000]);
+    }
+}
@@ -0,0 +1,6 @@
+/* Large enough for every probe configuration. The images are never flashed. */
+MEMORY
+{
+  FLASH : ORIGIN = 0x00000000, LENGTH = 1024K
+  RAM : ORIGIN = 0x20000000, LENGTH = 256K
+}
@@ -0,0 +1,266 @@
+//! Firmware image measured by `rubble-size`.
+//!
+//! The Cargo features select which parts of the stack are used (see `Cargo.toml`). The hardware
+//! interface is implemented by stubs that access a fake peripheral via volatile reads and writes,
+//! so that the image doesn't depend on any particular chip, and the optimizer can't remove any
+//! code the stack would run on real hardware. The image is never executed.
+
+#![no_std]
+#![no_main]
+#![warn(rust_2018_idioms)]
+
+use core::panic::PanicInfo;
+use core::ptr;
+use cortex_m_rt::entry;
+use rand_core::{impls, CryptoRng, RngCore};
+use rubble::config::Config;
+use rubble::l2cap::BleChannelMap;
+use rubble::link::queue::RingQueue;
+use rubble::link::{
+    advertising, data, AddressKind, Cmd, DeviceAddress, LinkLayerBuilder, NextUpdate, RadioCmd,
+    Transmitter, MIN_PDU_BUF,
+};
+use rubble::log::NoLogger;
+use rubble::phy::{AdvertisingChannel, DataChannel};
+use rubble::time::{Duration, Instant, Timer};
+
+#[cfg(feature = "peripheral")]
+use rubble::{gatt::BatteryServiceAttrs, l2cap::L2CAPState, link::responder::Responder};
+
+#[cfg(not(feature = "peripheral"))]
+type Attributes = rubble::att::NoAttributes;
+#[cfg(feature = "peripheral")]
+type Attributes = BatteryServiceAttrs;
+
+#[cfg(not(feature = "security"))]
+type Security = rubble::security::NoSecurity;
+#[cfg(feature = "security")]
+type Security = rubble::security::ConfLegacyPairing<ProbeConfig, rubble::security::SoftAes>;
+
+/// Base address of the fake peripheral.
+const REGS: usize = 0x4000_0000;
+
+fn read_reg(offset: usize) -> u32 {
+    unsafe { ptr::read_volatile((REGS + offset) as *const u32) }
+}
+
+fn write_reg(offset: usize, value: u32) {
+    unsafe { ptr::write_volatile((REGS + offset) as *mut u32, value) }
+}
+
+fn schedule(next: NextUpdate) {
+    if let NextUpdate::At(time) = next {
+        write_reg(0x04, time.raw_micros());
+    }
+}
+
+struct StubTimer;
+
+impl Timer for StubTimer {
+    fn now(&self) -> Instant {
+        Instant::from_raw_micros(read_reg(0x00))
+    }
+
+    fn schedule_at(&mut self, at: Instant) {
+        write_reg(0x04, at.raw_micros());
+    }
+
+    fn cancel(&mut self) {
+        write_reg(0x08, 1);
+    }
+}
+
+struct StubRadio {
+    buf: [u8; MIN_PDU_BUF],
+}
+
+impl StubRadio {
+    /// Reconfigures the radio as requested by the Link-Layer.
+    fn apply(&mut self, cmd: Cmd) {
+        schedule(cmd.next_update);
+        if let RadioCmd::Off = cmd.radio {
+            write_reg(0x10, 0);
+        }
+        write_reg(0x14, cmd.queued_work as u32);
+    }
+
+    /// Returns a received packet, if there is one.
+    fn receive(&mut self, rx: &mut [u8; MIN_PDU_BUF]) -> Option<(Instant, bool)> {
+        if read_reg(0x20) == 0 {
+            return None;
+        }
+        for (i, b) in rx.iter_mut().enumerate() {
+            *b = read_reg(0x40 + i * 4) as u8;
+        }
+        Some((
+            Instant::from_raw_micros(read_reg(0x24)),
+            read_reg(0x28) != 0,
+        ))
+    }
+}
+
+impl Transmitter for StubRadio {
+    fn tx_payload_buf(&mut self) -> &mut [u8] {
+        &mut self.buf
+    }
+
+    fn transmit_advertising(&mut self, header: advertising::Header, channel: AdvertisingChannel) {
+        write_reg(0x30, u32::from(header.to_u16()));
+        write_reg(0x34, u32::from(channel.freq()));
+        write_reg(0x38, self.buf.as_ptr() as u32);
+    }
+
+    fn transmit_data(
+        &mut self,
+        access_address: u32,
+        crc_iv: u32,
+        header: data::Header,
+        channel: DataChannel,
+    ) {
+        write_reg(0x2C, access_address);
+        write_reg(0x3C, crc_iv);
+        write_reg(0x30, u32::from(header.to_u16()));
+        write_reg(0x34, u32::from(channel.freq()));
+        write_reg(0x38, self.buf.as_ptr() as u32);
+    }
+
+    fn set_tx_power(&mut self, dbm: i8) -> i8 {
+        write_reg(0x0C, dbm as u32);
+        dbm
+    }
+}
+
+pub struct StubRng;
+
+impl RngCore for StubRng {
+    fn next_u32(&mut self) -> u32 {
+        read_reg(0x18)
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        impls::next_u64_via_u32(self)
+    }
+
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        impls::fill_bytes_via_next(self, dest)
+    }
+
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), rand_core::Error> {
+        self.fill_bytes(dest);
+        Ok(())
+    }
+}
+
+impl CryptoRng for StubRng {}
+
+const QUEUE_PACKETS: usize = 4;
+
+enum ProbeConfig {}
+
+impl Config for ProbeConfig {
+    type Timer = StubTimer;
+    type Transmitter = StubRadio;
+    type ChannelMapper = BleChannelMap<Attributes, Security>;
+    type PacketQueue = &'static mut RingQueue<QUEUE_PACKETS>;
+    type Rng = StubRng;
+    type Logger = NoLogger;
+}
+
+#[cfg(not(feature = "peripheral"))]
+#[entry]
+fn main() -> ! {
+    use rubble::link::ad_structure::AdStructure;
+
+    let mut radio = StubRadio {
+        buf: [0; MIN_PDU_BUF],
+    };
+    let address = DeviceAddress::new([0x01, 0x02, 0x03, 0x04, 0x05, 0xC6], AddressKind::Random);
+    let mut ll = LinkLayerBuilder::<ProbeConfig>::new(address, StubTimer)
+        .broadcaster()
+        .build();
+    let next = ll
+        .start_broadcast(
+            Duration::from_millis(100),
+            &[AdStructure::CompleteLocalName("Rubble")],
+            &mut radio,
+        )
+        .unwrap();
+    schedule(next);
+
+    loop {
+        if read_reg(0x1C) != 0 {
+            let cmd = ll.update_timer(&mut radio);
+            radio.apply(cmd);
+        }
+    }
+}
+
+#[cfg(feature = "peripheral")]
+#[entry]
+fn main() -> ! {
+    use rubble::link::ad_structure::AdStructure;
+    use rubble::link::queue::PacketQueue;
+
+    static mut TX_QUEUE: RingQueue<QUEUE_PACKETS> = RingQueue::new();
+    static mut RX_QUEUE: RingQueue<QUEUE_PACKETS> = RingQueue::new();
+
+    let mut radio = StubRadio {
+        buf: [0; MIN_PDU_BUF],
+    };
+    let mut rx_buf = [0; MIN_PDU_BUF];
+    let address = DeviceAddress::new([0x01, 0x02, 0x03, 0x04, 0x05, 0xC6], AddressKind::Random);
+    let mut ll = LinkLayerBuilder::<ProbeConfig>::new(address, StubTimer).build();
+
+    let (tx, tx_cons) = TX_QUEUE.split();
+    let (rx_prod, rx) = RX_QUEUE.split();
+    let channels = BleChannelMap::with_attributes(BatteryServiceAttrs::new());
+    #[cfg(feature = "security")]
+    let channels = channels.with_security(rubble::security::LegacyPairing::new(
+        Default::default(),
+        rubble::security::SoftAes,
+        StubRng,
+    ));
+    let mut responder = Responder::<ProbeConfig>::new(tx, rx, L2CAPState::new(channels));
+
+    let next = ll
+        .start_advertise(
+            Duration::from_millis(100),
+            &[AdStructure::CompleteLocalName("Rubble")],
+            &mut radio,
+            tx_cons,
+            rx_prod,
+        )
+        .unwrap();
+    schedule(next);
+
+    loop {
+        if let Some((rx_end, crc_ok)) = radio.receive(&mut rx_buf) {
+            let cmd = if read_reg(0x20) == 1 {
+                let header = advertising::Header::parse(&rx_buf);
+                let payload = &rx_buf[2..2 + usize::from(header.payload_length())];
+                ll.process_adv_packet(rx_end, &mut radio, header, payload, crc_ok)
+            } else {
+                let header = data::Header::parse(&rx_buf);
+                let payload = &rx_buf[2..2 + usize::from(header.payload_length())];
+                ll.process_data_packet(rx_end, &mut radio, header, payload, crc_ok)
+            };
+            radio.apply(cmd);
+        }
+        if read_reg(0x1C) != 0 {
+            let cmd = ll.update_timer(&mut radio);
+            radio.apply(cmd);
+        }
+        while responder.has_work() {
+            if responder.process_one().is_err() {
+                break;
+            }
+        }
+    }
+}
+
+#[panic_handler]
+fn panic(_info: &PanicInfo<'_>) -> ! {
+    loop {
+        write_reg(0xFC, 1);
+    }
+}
@@ -0,0 +1,265 @@
+//! Reports how much code each of Rubble's modules contributes to a firmware image.
+//!
+//! This builds the `probe` firmware in a set of representative configurations for both Cortex-M
+//! targets Rubble supports, and uses [`cargo bloat`] to attribute the size of the `.text` section
+//! to Rubble's modules. It is meant to be run before and after changes that might affect code
+//! size:
+//!
+//! ```notrust
+//! $ cargo install cargo-bloat
+//! $ rustup target add thumbv6m-none-eabi thumbv7em-none-eabi
+//! $ cargo run -p rubble-size -- [--depth <N>] [--target <TRIPLE>]... [<CONFIG>]...
+//! ```
+//!
+//! By default, all configurations are built for all targets, and sizes are reported per top-level
+//! module (`--depth 1`). Larger depths break the sizes down into submodules.
+//!
+//! The probe is built with the release profile of the workspace, which uses LTO. Functions that
+//! get inlined are attributed to their caller, so small helpers don't show up on their own, and
+//! generic code is attributed to the module defining it, not the one instantiating it.
+//!
+//! [`cargo bloat`]: https://github.com/RazrFalcon/cargo-bloat
+
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::env;
+use std::path::Path;
+use std::process::{exit, Command};
+
+/// A stack configuration to measure.
+struct Configuration {
+    name: &'static str,
+    /// Cargo features of the probe to enable.
+    features: &'static str,
+    description: &'static str,
+}
+
+const CONFIGURATIONS: &[Configuration] = &[
+    Configuration {
+        name: "beacon",
+        features: "",
+        description: "non-connectable broadcaster",
+    },
+    Configuration {
+        name: "peripheral",
+        features: "peripheral",
+        description: "connectable peripheral with a GATT server",
+    },
+    Configuration {
+        name: "security",
+        features: "security",
+        description: "peripheral with LE Legacy Pairing",
+    },
+    Configuration {
+        name: "peripheral-log",
+        features: "peripheral log",
+        description: "peripheral logging via the `log` crate",
+    },
+];
+
+const TARGETS: &[&str] = &["thumbv6m-none-eabi", "thumbv7em-none-eabi"];
+
+/// Code size of one configuration built for one target.
+struct Report {
+    text: u64,
+    /// Size of Rubble's functions, by module path (without the leading `rubble::`).
+    modules: BTreeMap<String, u64>,
+}
+
+impl Report {
+    fn rubble_total(&self) -> u64 {
+        self.modules.values().sum()
+    }
+}
+
+fn main() {
+    let mut depth = 1;
+    let mut targets = Vec::new();
+    let mut configs = Vec::new();
+
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match &*arg {
+            "--depth" => {
+                depth = args
+                    .next()
+                    .and_then(|depth| depth.parse().ok())
+                    .filter(|depth| *depth > 0)
+                    .unwrap_or_else(|| usage("`--depth` needs a positive number"));
+            }
+            "--target" => {
+                targets.push(
+                    args.next()
+                        .unwrap_or_else(|| usage("`--target` needs a target triple")),
+                );
+            }
+            "-h" | "--help" => usage(""),
+            name => match CONFIGURATIONS.iter().find(|config| config.name == name) {
+                Some(config) => configs.push(config),
+                None => usage(&format!("unknown configuration `{}`", name)),
+            },
+        }
+    }
+    if targets.is_empty() {
+        targets = TARGETS.iter().map(|target| target.to_string()).collect();
+    }
+    if configs.is_empty() {
+        configs = CONFIGURATIONS.iter().collect();
+    }
+
+    let probe = Path::new(env!("CARGO_MANIFEST_DIR")).join("probe");
+    let mut totals = Vec::new();
+    for target in &targets {
+        for config in &configs {
+            let report = measure(&probe, config, target, depth);
+            println!(
+                "{} ({}), {}: .text {}, rubble {}",
+                config.name,
+                config.description,
+                target,
+                kib(report.text),
+                kib(report.rubble_total())
+            );
+
+            let mut modules = report.modules.iter().collect::<Vec<_>>();
+            modules.sort_by(|a, b| b.1.cmp(a.1).then(a.0.cmp(b.0)));
+            for (module, size) in modules {
+                println!("  {:>10}  {}", kib(*size), module);
+            }
+            println!();
+
+            totals.push((config.name, target, report));
+        }
+    }
+
+    println!(
+        "{:<16} {:<20} {:>10} {:>10}",
+        "config", "target", ".text", "rubble"
+    );
+    for (config, target, report) in &totals {
+        println!(
+            "{:<16} {:<20} {:>10} {:>10}",
+            config,
+            target,
+            kib(report.text),
+            kib(report.rubble_total())
+        );
+    }
+}
+
+/// Builds the probe in `config` for `target` and collects the sizes of Rubble's modules.
+fn measure(probe: &Path, config: &Configuration, target: &str, depth: usize) -> Report {
+    let cargo = env::var("CARGO").unwrap_or_else(|_| "cargo".to_string());
+    let mut command = Command::new(cargo);
+    command
+        .current_dir(probe)
+        .args(["bloat", "--release", "-n", "0", "--message-format", "json"])
+        .args(["--target", target]);
+    if !config.features.is_empty() {
+        command.args(["--features", config.features]);
+    }
+    let output = command
+        .output()
+        .unwrap_or_else(|e| fail(&format!("failed to run cargo: {}", e)));
+    if !output.status.success() {
+        eprintln!("{}", String::from_utf8_lossy(&output.stderr));
+        fail(&format!(
+            "building `{}` for {} failed (is `cargo-bloat` installed?)",
+            config.name, target
+        ));
+    }
+
+    let json: Value = serde_json::from_slice(&output.stdout)
+        .unwrap_or_else(|e| fail(&format!("invalid output from `cargo bloat`: {}", e)));
+    let text = json["text-section-size"].as_u64().unwrap_or(0);
+    let mut modules = BTreeMap::new();
+    for function in json["functions"].as_array().into_iter().flatten() {
+        if function["crate"].as_str() != Some("rubble") {
+            continue;
+        }
+        let name = function["name"].as_str().unwrap_or("");
+        let size = function["size"].as_u64().unwrap_or(0);
+        *modules.entry(module_of(name, depth)).or_insert(0) += size;
+    }
+
+    Report { text, modules }
+}
+
+/// Returns the path of the Rubble module defining the function `name`, up to `depth` modules deep.
+///
+/// `name` is a demangled symbol like `rubble::att::server::AttributeServer<A>::process_request`
+/// or `<rubble::link::LinkLayer<C, R> as core::fmt::Debug>::fmt`.
+fn module_of(name: &str, depth: usize) -> String {
+    let path = match name.find("rubble::") {
+        Some(start) => &name[start + "rubble::".len()..],
+        None => return "(unknown)".to_string(),
+    };
+
+    let mut modules = Vec::new();
+    let mut ended = false;
+    for segment in path.split("::") {
+        let is_module = !segment.is_empty()
+            && segment
+                .chars()
+                .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_');
+        if !is_module {
+            ended = true;
+            break;
+        }
+        modules.push(segment);
+    }
+    if !ended {
+        // Free functions: The last segment is the function name
+        modules.pop();
+    }
+
+    if modules.is_empty() {
+        "(crate root)".to_string()
+    } else {
+        modules.truncate(depth);
+        modules.join("::")
+    }
+}
+
+fn kib(bytes: u64) -> String {
+    format!("{:.1} KiB", bytes as f64 / 1024.0)
+}
+
+fn usage(error: &str) -> ! {
+    if !error.is_empty() {
+        eprintln!("error: {}\n", error);
+    }
+    eprintln!("usage: rubble-size [--depth <N>] [--target <TRIPLE>]... [<CONFIG>]...\n");
+    eprintln!("configurations:");
+    for config in CONFIGURATIONS {
+        eprintln!("  {:<16} {}", config.name, config.description);
+    }
+    exit(if error.is_empty() { 0 } else { 1 })
+}
+
+fn fail(message: &str) -> ! {
+    eprintln!("error: {}", message);
+    exit(1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn module_paths() {
+        let name = "rubble::att::server::AttributeServer<A>::process_request";
+        assert_eq!(module_of(name, 1), "att");
+        assert_eq!(module_of(name, 2), "att::server");
+        assert_eq!(module_of(name, 3), "att::server");
+
+        let name = "<rubble::link::LinkLayer<C, R> as core::fmt::Debug>::fmt";
+        assert_eq!(module_of(name, 2), "link");
+        assert_eq!(
+            module_of("rubble::security::toolbox::f4", 2),
+            "security::toolbox"
+        );
+        assert_eq!(module_of("rubble::ecdh::ecdh", 2), "ecdh");
+        assert_eq!(module_of("core::fmt::write", 2), "(unknown)");
+    }
+}
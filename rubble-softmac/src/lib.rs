@@ -0,0 +1,16 @@
+//! A Rubble Link-Layer backend for radios without BLE support.
+//!
+//! Most BLE-capable chips (like Nordic's nRF series) assemble packets, compute the CRC and apply
+//! data whitening in hardware. Plain 2.4 GHz GFSK transceivers, like SPI-attached radio modules or
+//! the sub-GHz/2.4 GHz radios of many MCUs, can't do that. This crate implements Rubble's
+//! `Transmitter` trait on top of such a radio, doing all of that in software (using the
+//! implementations in `rubble::phy`). The radio only needs to implement `rubble::phy::Radio`.
+//!
+//! The radio still has to meet the Inter Frame Space `T_IFS` when responding to packets, since
+//! that requires µs accuracy (see `Radio::transmit_response`).
+
+#![no_std]
+#![warn(rust_2018_idioms)]
+
+pub mod radio;
+pub mod timer;
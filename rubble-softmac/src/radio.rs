@@ -0,0 +1,263 @@
+//! `Transmitter` implementation on top of a raw `Radio`.
+
+use rubble::config::Config;
+use rubble::link::{
+    advertising::{self, CRC_PRESET},
+    data, Cmd, LinkLayer, RadioCmd, Role, Transmitter, MIN_PDU_BUF,
+};
+use rubble::phy::{crc24, crc_bytes, whiten, AdvertisingChannel, DataChannel, Radio};
+use rubble::time::Instant;
+
+/// Length of the preamble and Access Address preceding the PDU.
+const PDU_OFFSET: usize = 1 + 4;
+
+/// Length of the CRC following the PDU.
+const CRC_LEN: usize = 3;
+
+/// Size of a buffer that can hold any complete packet, from preamble to CRC.
+pub const FRAME_BUF: usize = PDU_OFFSET + MIN_PDU_BUF + CRC_LEN;
+
+/// What the radio is currently listening for.
+#[derive(Debug, Copy, Clone)]
+enum Listen {
+    Off,
+    Advertising(AdvertisingChannel),
+    Data { channel: DataChannel, crc_init: u32 },
+}
+
+/// A BLE `Transmitter` for radios without BLE support.
+///
+/// Packets are assembled, checksummed and whitened in software and handed to the `Radio` as raw
+/// Bytes. Received packets must be passed to [`recv_packet`], which removes the whitening and
+/// checks the CRC before handing them to the `LinkLayer`.
+///
+/// [`recv_packet`]: #method.recv_packet
+pub struct SoftRadio<R: Radio> {
+    radio: R,
+
+    /// PDU header and payload of the last transmitted packet.
+    ///
+    /// This is kept separate from `frame`, since the stack relies on the payload being preserved
+    /// (eg. for retransmissions), while the frame is whitened in-place.
+    pdu_buf: [u8; MIN_PDU_BUF],
+
+    /// Complete packet passed to the radio.
+    frame: [u8; FRAME_BUF],
+
+    listen: Listen,
+
+    /// Whether a received packet is being processed, which makes transmissions responses.
+    responding: bool,
+}
+
+impl<R: Radio> SoftRadio<R> {
+    /// Creates a BLE `Transmitter` using `radio`.
+    pub fn new(radio: R) -> Self {
+        Self {
+            radio,
+            pdu_buf: [0; MIN_PDU_BUF],
+            frame: [0; FRAME_BUF],
+            listen: Listen::Off,
+            responding: false,
+        }
+    }
+
+    /// Provides access to the underlying radio.
+    pub fn radio(&mut self) -> &mut R {
+        &mut self.radio
+    }
+
+    /// Configures the radio for (not) receiving data according to `cmd`.
+    pub fn configure_receiver(&mut self, cmd: RadioCmd) {
+        self.listen = match cmd {
+            RadioCmd::Off => {
+                self.radio.stop();
+                Listen::Off
+            }
+            RadioCmd::ListenAdvertising { channel, .. } => {
+                self.radio
+                    .listen(channel.freq(), advertising::ACCESS_ADDRESS);
+                Listen::Advertising(channel)
+            }
+            RadioCmd::ListenData {
+                channel,
+                access_address,
+                crc_init,
+                ..
+            } => {
+                // Received packets are copied into the RX queue by the Link-Layer, since the raw
+                // packet still needs to be dewhitened and checked before being processed.
+                self.radio.listen(channel.freq(), access_address);
+                Listen::Data { channel, crc_init }
+            }
+        };
+    }
+
+    /// Processes a packet received by the radio.
+    ///
+    /// `raw` must contain the (still whitened) Bytes following the Access Address, and must be
+    /// long enough to hold PDU and CRC. It is dewhitened in-place. `rx_end` is the time at which
+    /// the packet was fully received.
+    ///
+    /// Returns `None` if the radio isn't supposed to be listening, or if `raw` is truncated.
+    /// Otherwise, the radio must be reconfigured according to the returned `Cmd`.
+    pub fn recv_packet<C: Config<Transmitter = Self>, R: Role>(
+        &mut self,
+        rx_end: Instant,
+        raw: &mut [u8],
+        ll: &mut LinkLayer<C, R>,
+    ) -> Option<Cmd> {
+        let (iv, crc_init) = match self.listen {
+            Listen::Off => return None,
+            Listen::Advertising(channel) => (channel.whitening_iv(), CRC_PRESET),
+            Listen::Data { channel, crc_init } => (channel.whitening_iv(), crc_init),
+        };
+
+        if raw.len() < 2 + CRC_LEN {
+            return None;
+        }
+        whiten(iv, raw);
+
+        // The header has the same layout for both PDU types, with the length in the second Byte
+        let pdu_len = 2 + usize::from(raw[1]);
+        if raw.len() < pdu_len + CRC_LEN {
+            return None;
+        }
+        let (pdu, crc) = raw.split_at(pdu_len);
+        let crc_ok = crc_bytes(crc24(crc_init, pdu)) == crc[..CRC_LEN];
+        let payload = &pdu[2..];
+
+        self.responding = true;
+        let cmd = match self.listen {
+            Listen::Off => unreachable!(),
+            Listen::Advertising(_) => {
+                let header = advertising::Header::parse(pdu);
+                ll.process_adv_packet(rx_end, self, header, payload, crc_ok)
+            }
+            Listen::Data { .. } => {
+                let header = data::Header::parse(pdu);
+                ll.process_data_packet(rx_end, self, header, payload, crc_ok)
+            }
+        };
+        self.responding = false;
+
+        Some(cmd)
+    }
+
+    /// Assembles the packet in `frame` and passes it to the radio.
+    fn transmit(&mut self, access_address: u32, crc_init: u32, header: u16, iv: u8, freq: u16) {
+        self.pdu_buf[..2].copy_from_slice(&header.to_le_bytes());
+        let pdu_len = 2 + usize::from(self.pdu_buf[1]);
+        let frame_len = PDU_OFFSET + pdu_len + CRC_LEN;
+
+        // The preamble alternates between 0 and 1, and its last bit differs from the first bit of
+        // the Access Address.
+        self.frame[0] = if access_address & 1 == 0 { 0xAA } else { 0x55 };
+        self.frame[1..PDU_OFFSET].copy_from_slice(&access_address.to_le_bytes());
+
+        let pdu = &self.pdu_buf[..pdu_len];
+        self.frame[PDU_OFFSET..][..pdu_len].copy_from_slice(pdu);
+        self.frame[PDU_OFFSET + pdu_len..frame_len]
+            .copy_from_slice(&crc_bytes(crc24(crc_init, pdu)));
+        whiten(iv, &mut self.frame[PDU_OFFSET..frame_len]);
+
+        let frame = &mut self.frame[..frame_len];
+        if self.responding {
+            self.radio.transmit_response(frame, freq);
+        } else {
+            self.radio.transmit(frame, freq);
+        }
+    }
+}
+
+impl<R: Radio> Transmitter for SoftRadio<R> {
+    fn tx_payload_buf(&mut self) -> &mut [u8] {
+        &mut self.pdu_buf[2..]
+    }
+
+    fn transmit_advertising(&mut self, header: advertising::Header, channel: AdvertisingChannel) {
+        self.transmit(
+            advertising::ACCESS_ADDRESS,
+            CRC_PRESET,
+            header.to_u16(),
+            channel.whitening_iv(),
+            channel.freq(),
+        );
+    }
+
+    fn transmit_data(
+        &mut self,
+        access_address: u32,
+        crc_iv: u32,
+        header: data::Header,
+        channel: DataChannel,
+    ) {
+        self.transmit(
+            access_address,
+            crc_iv,
+            header.to_u16(),
+            channel.whitening_iv(),
+            channel.freq(),
+        );
+    }
+
+    fn set_tx_power(&mut self, dbm: i8) -> i8 {
+        self.radio.set_tx_power(dbm)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rubble::link::advertising::PduType;
+
+    #[derive(Default)]
+    struct MockRadio {
+        sent: Option<([u8; FRAME_BUF], usize, u16)>,
+    }
+
+    impl Radio for MockRadio {
+        fn transmit(&mut self, buf: &mut [u8], freq: u16) {
+            let mut frame = [0; FRAME_BUF];
+            frame[..buf.len()].copy_from_slice(buf);
+            self.sent = Some((frame, buf.len(), freq));
+        }
+
+        fn transmit_response(&mut self, buf: &mut [u8], freq: u16) {
+            self.transmit(buf, freq);
+        }
+
+        fn listen(&mut self, _freq: u16, _access_address: u32) {}
+
+        fn stop(&mut self) {}
+
+        fn set_tx_power(&mut self, _dbm: i8) -> i8 {
+            0
+        }
+    }
+
+    #[test]
+    fn advertising_frame() {
+        let mut radio = SoftRadio::new(MockRadio::default());
+        let payload = [0xC0, 0xFF, 0xEE, 0x00, 0x00, 0xC0, 0x02, 0x01, 0x06];
+        radio.tx_payload_buf()[..payload.len()].copy_from_slice(&payload);
+
+        let mut header = advertising::Header::new(PduType::AdvInd);
+        header.set_payload_length(payload.len() as u8);
+        let channel = AdvertisingChannel::first();
+        radio.transmit_advertising(header, channel);
+
+        let (mut frame, len, freq) = radio.radio().sent.take().unwrap();
+        assert_eq!(freq, 2402);
+        assert_eq!(len, PDU_OFFSET + 2 + payload.len() + CRC_LEN);
+        assert_eq!(frame[..PDU_OFFSET], [0xAA, 0xD6, 0xBE, 0x89, 0x8E]);
+
+        // Removing the whitening restores header, payload and a valid CRC
+        whiten(channel.whitening_iv(), &mut frame[PDU_OFFSET..len]);
+        assert_eq!(frame[PDU_OFFSET + 2..][..payload.len()], payload);
+        assert_eq!(crc24(CRC_PRESET, &frame[PDU_OFFSET..len]), 0);
+
+        // The payload buffer is preserved for retransmissions
+        assert_eq!(radio.tx_payload_buf()[..payload.len()], payload);
+    }
+}
@@ -0,0 +1,37 @@
+//! Generic `Timer` implementation based on a free-running counter.
+
+use rubble::time::{Instant, Timer};
+
+/// A free-running 32-bit counter incrementing once per microsecond.
+///
+/// This is usually a hardware timer peripheral running at 1 MHz.
+pub trait Counter {
+    /// Returns the current counter value.
+    fn micros(&self) -> u32;
+}
+
+/// Implements Rubble's `Timer` trait using a `Counter`.
+///
+/// Scheduling the Link-Layer updates requested via `Cmd::next_update` is left to the application,
+/// since that requires a timer interrupt.
+pub struct CounterTimer<C: Counter> {
+    counter: C,
+}
+
+impl<C: Counter> CounterTimer<C> {
+    /// Creates a timer from a counter.
+    pub fn new(counter: C) -> Self {
+        Self { counter }
+    }
+
+    /// Provides access to the wrapped counter.
+    pub fn inner(&mut self) -> &mut C {
+        &mut self.counter
+    }
+}
+
+impl<C: Counter> Timer for CounterTimer<C> {
+    fn now(&self) -> Instant {
+        Instant::from_raw_micros(self.counter.micros())
+    }
+}
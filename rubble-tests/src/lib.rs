@@ -28,6 +28,15 @@ fn additional_tests() {
     // Enables ring and runs the ECDH test suite on it.
     cargo("test -p rubble --features ring -- ecdh", "rubble");
 
+    // Checks that Rubble builds with the `defmt` logging backend and all log messages enabled.
+    cargo(
+        "check --features defmt-log,defmt-trace --target thumbv7em-none-eabi",
+        "rubble",
+    );
+
+    // Runs the tests of the conversions to and from `fugit` types, and of the `serde` impls.
+    cargo("test --features fugit,serde", "rubble");
+
     // Checks that rubble-nrf5x builds on all supported architectures.
     let targets = [
         (
@@ -45,6 +54,29 @@ fn additional_tests() {
         }
     }
 
+    // Checks that the RTIC monotonics build.
+    cargo(
+        "check --features 52840,rtic --target thumbv7em-none-eabi",
+        "rubble-nrf5x",
+    );
+
+    // Checks that rubble-embassy builds for embedded targets.
+    cargo(
+        "check --features 52840 --target thumbv7em-none-eabi",
+        "rubble-embassy",
+    );
+
+    // Checks that rubble-softmac builds for embedded targets.
+    cargo("check --target thumbv6m-none-eabi", "rubble-softmac");
+
+    // Checks that all configurations measured by rubble-size build.
+    for features in &["", "--features peripheral", "--features security,log"] {
+        cargo(
+            format!("check --target thumbv6m-none-eabi {}", features),
+            "rubble-size/probe",
+        );
+    }
+
     // Checks that the demos build correctly.
     let features = ["52840", "52833", "52832", "52811", "52810"];
     let target = "thumbv7em-none-eabi";
@@ -69,8 +101,12 @@ fn additional_tests() {
     cargo("fmt --all -- --check", ".");
 
     // Generate documentation as part of the test suite. This ensures they always build.
-    doc("rubble", "--all-features");
-    doc("rubble-nrf5x", "--features 52840");
+    // `log` and `defmt-log` are mutually exclusive, so `--all-features` can't be used.
+    doc("rubble", "--features ring,log,async,fugit,serde");
+    doc("rubble-nrf5x", "--features 52840,rtic");
+    doc("rubble-softmac", "");
+    doc("rubble-embassy", "--features 52840");
+    doc("rubble-macros", "");
 
     fs::write(
         "target/doc/index.html",
@@ -8,6 +8,7 @@ use core::{fmt, ops::RangeInclusive};
 ///
 /// The `0x0000` handle (`NULL`) is invalid and must not be used.
 #[derive(Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Handle(u16);
 
 impl Handle {
@@ -21,7 +22,7 @@ impl Handle {
     }
 
     /// Create an attribute handle from a raw u16
-    pub fn from_raw(raw: u16) -> Self {
+    pub const fn from_raw(raw: u16) -> Self {
         Handle(raw)
     }
 }
@@ -52,7 +53,8 @@ impl ToBytes for Handle {
 }
 
 /// A (de)serializable handle range that isn't checked for validity.
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, defmt::Format)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 pub struct RawHandleRange {
     start: Handle,
     end: Handle,
@@ -90,6 +92,7 @@ impl ToBytes for RawHandleRange {
 
 /// A (de)serializable handle range that has been checked for validity.
 #[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 pub struct HandleRange(RangeInclusive<Handle>);
 
 impl HandleRange {
@@ -112,3 +115,9 @@ impl HandleRange {
         *self.0.end()
     }
 }
+
+impl defmt::Format for HandleRange {
+    fn format(&self, fmt: defmt::Formatter<'_>) {
+        defmt::write!(fmt, "HandleRange({}..={})", self.start(), self.end());
+    }
+}
@@ -32,12 +32,45 @@ mod pdus;
 mod server;
 mod uuid;
 
-use self::{handle::*, pdus::*};
+use self::handle::*;
+use crate::link::Peer;
+use crate::security::{LinkSecurity, MIN_KEY_SIZE};
 use crate::{l2cap::Sender, Error};
 
 pub use self::handle::{Handle, HandleRange};
-pub use self::server::{AttributeServer, AttributeServerTx};
-pub use self::uuid::AttUuid;
+pub use self::pdus::{AttError, ErrorCode};
+pub(crate) use self::pdus::{AttPdu, Opcode};
+pub use self::server::{AttributeServer, AttributeServerTx, TRANSACTION_TIMEOUT};
+pub use self::uuid::{AttUuid, UuidWidth};
+
+/// Determines how the `AttributeServer` handles an incoming *Write Command*.
+///
+/// Returned by [`AttributeProvider::write_command_action`].
+///
+/// [`AttributeProvider::write_command_action`]: trait.AttributeProvider.html#method.write_command_action
+#[derive(Debug, Copy, Clone, PartialEq, Eq, defmt::Format)]
+pub enum WriteCommandAction {
+    /// Pass the value to `AttributeProvider::write_attr`.
+    Accept,
+
+    /// Discard the command.
+    ///
+    /// The client isn't informed, since *Write Commands* have no response. This is suitable for
+    /// values where only the latest one matters.
+    Drop,
+
+    /// Keep the command in the RX queue until the provider is ready.
+    ///
+    /// Processing of incoming packets pauses until `write_command_action` returns something else,
+    /// and the Link-Layer eventually stops acknowledging packets from the client once the RX queue
+    /// is full. This is suitable for streams of data that must not lose any values.
+    Stall,
+}
+
+/// The maximum length of an attribute value in Bytes.
+///
+/// Longer values returned by an `AttributeProvider` are truncated to this length.
+pub const MAX_ATTRIBUTE_LEN: usize = 512;
 
 /// An ATT server attribute
 pub struct Attribute<T>
@@ -75,6 +108,7 @@ impl<T: AsRef<[u8]>> Attribute<T> {
     }
 }
 
+#[derive(Debug, Copy, Clone, PartialEq, Eq, defmt::Format)]
 pub enum AttributeAccessPermissions {
     Readable,
     Writeable,
@@ -106,6 +140,14 @@ impl Default for AttributeAccessPermissions {
 
 /// Trait for attribute sets that can be hosted by an `AttributeServer`.
 pub trait AttributeProvider {
+    /// Whether this provider implements queued writes (`prepare_write_attr` and
+    /// `execute_write_attr`).
+    ///
+    /// If `false` (the default), *Prepare Write* and *Execute Write* requests are rejected with
+    /// `RequestNotSupported` without consulting the provider, and the code handling them is left
+    /// out of the binary.
+    const QUEUED_WRITES: bool = false;
+
     /// Calls a closure `f` with every attribute whose handle is inside `range`, ascending.
     ///
     /// If `f` returns an error, this function will stop calling `f` and propagate the error
@@ -153,6 +195,46 @@ pub trait AttributeProvider {
         AttributeAccessPermissions::Readable
     }
 
+    /// Returns the security level the link must have to access the attribute at `handle`.
+    ///
+    /// While the `security` of the peer passed to `AttributeServer::set_peer` is lower, reads and
+    /// writes are rejected with `ErrorCode::InsufficientEncryption`, or with
+    /// `ErrorCode::InsufficientAuthentication` if an authenticated link is required. Clients usually
+    /// react to these errors by encrypting the link, or by pairing if they aren't bonded yet.
+    /// Without a peer, the link is considered unencrypted.
+    ///
+    /// By default, no security is required.
+    fn attr_security(&self, _handle: Handle) -> LinkSecurity {
+        LinkSecurity::Unencrypted
+    }
+
+    /// Returns the minimum encryption key size, in Bytes, required to access the attribute at
+    /// `handle`.
+    ///
+    /// This is only checked for attributes that require an encrypted link (see `attr_security`).
+    /// If the link is encrypted with a shorter key (see `Peer::key_size`), reads and writes are
+    /// rejected with `ErrorCode::InsufficientEncryptionKeySize`.
+    ///
+    /// By default, keys of any size are accepted.
+    fn attr_key_size(&self, _handle: Handle) -> u8 {
+        MIN_KEY_SIZE
+    }
+
+    /// Decides whether `peer` may read the attribute at `handle`.
+    ///
+    /// This is consulted before serving *Read*, *Read Blob* and *Read By Type* requests for
+    /// attributes that are readable according to `attr_access_permissions`, and allows gating
+    /// reads on application-level state (eg. a login). Returning an error code (usually
+    /// `ErrorCode::InsufficientAuthorization`) rejects the request with that code.
+    ///
+    /// `peer` is the connected client, if it was passed to `AttributeServer::set_peer`. Its `id`
+    /// can be used to grant access to a bonded device across reconnections.
+    ///
+    /// By default, all reads are authorized.
+    fn authorize_read(&self, _handle: Handle, _peer: Option<&Peer>) -> Result<(), ErrorCode> {
+        Ok(())
+    }
+
     /// Attempts to write data to the given attribute.
     ///
     /// This will only be called on handles for which
@@ -160,46 +242,106 @@ pub trait AttributeProvider {
     /// [`AttributeAccessPermissions::Writeable`]
     /// or [`AttributeAccessPermissions::ReadableAndWriteable`].
     ///
+    /// `peer` is the connected client, if it was passed to `AttributeServer::set_peer`. Writes to
+    /// *Client Characteristic Configuration* descriptors arrive here as well, so it can be used to
+    /// keep track of subscriptions per peer.
+    ///
     /// By default, panics on all writes. This must be overwritten if
     /// `attribute_access_permissions` is.
-    fn write_attr(&mut self, _handle: Handle, _data: &[u8]) -> Result<(), Error> {
+    fn write_attr(
+        &mut self,
+        _handle: Handle,
+        _data: &[u8],
+        _peer: Option<&Peer>,
+    ) -> Result<(), Error> {
         unimplemented!("by default, no attributes should have write access permissions, and this should never be called");
     }
 
+    /// Decides whether a *Write Command* to `handle` can be processed right now.
+    ///
+    /// Clients don't wait for *Write Commands* to be acknowledged, so they can send them faster
+    /// than the application consumes the written values (eg. the RX characteristic of a UART
+    /// service). Providers with limited buffer space can return [`WriteCommandAction::Drop`] or
+    /// [`WriteCommandAction::Stall`] while their buffer is full.
+    ///
+    /// While a command is stalled, `Responder::process_one` returns `Error::RxBusy` and
+    /// `Responder::has_work` returns `false`. This method is called again to check whether the
+    /// stall has ended, so it must not have side effects. When driving the `Responder`
+    /// asynchronously, the `QueueWaker` has to be woken once the provider has space again.
+    ///
+    /// This is only called for writeable attributes. By default, all commands are accepted.
+    ///
+    /// [`WriteCommandAction::Drop`]: enum.WriteCommandAction.html#variant.Drop
+    /// [`WriteCommandAction::Stall`]: enum.WriteCommandAction.html#variant.Stall
+    fn write_command_action(&self, _handle: Handle) -> WriteCommandAction {
+        WriteCommandAction::Accept
+    }
+
     /// If this read is from dynamic data fill the buffer and return the length of the data.
     /// If not return None.
     ///
-    /// Currently the buffer is 256 bytes.
+    /// The buffer is [`MAX_ATTRIBUTE_LEN`] Bytes long.
+    ///
+    /// [`MAX_ATTRIBUTE_LEN`]: constant.MAX_ATTRIBUTE_LEN.html
     ///
     /// By default returns `None`.
     fn read_attr_dynamic(&mut self, _handle: Handle, _buffer: &mut [u8]) -> Option<usize> {
         None
     }
 
+    /// Returns whether the value of the attribute at `handle` is fetched from slow storage.
+    ///
+    /// *Read* and *Read Blob* requests for such attributes are not answered immediately. Instead,
+    /// `begin_deferred_read` is called and the response is sent once the application has supplied
+    /// the value via `AttributeServer::complete_read`. *Read By Type* responses end before the
+    /// first deferred attribute, and are rejected with `RequestNotSupported` if there is no
+    /// attribute before it.
+    ///
+    /// By default, no attributes are deferred.
+    fn is_deferred(&self, _handle: Handle) -> bool {
+        false
+    }
+
+    /// Starts fetching the value of the deferred attribute at `handle`, starting at `offset`.
+    ///
+    /// This is only called for handles for which `is_deferred` returns `true`, and must not block.
+    /// The application has to pass the value to `AttributeServer::complete_read` (or report an
+    /// error with `AttributeServer::fail_read`) once it is available.
+    fn begin_deferred_read(&mut self, _handle: Handle, _offset: u16) {}
+
     /// In order to write data longer than what would fit one write request the procedure is explained in
     /// BLUETOOTH CORE SPECIFICATION Version 5.2 | Vol 3, Part F section 3.4.6.
+    ///
+    /// This is only called if `QUEUED_WRITES` is `true`. The default implementation returns an
+    /// error.
     fn prepare_write_attr(
         &mut self,
         _handle: Handle,
         _offset: u16,
         _data: &[u8],
     ) -> Result<(), Error> {
-        unimplemented!("you need to implement prepare_write_attr to make queued writes work")
+        Err(Error::InvalidValue)
     }
 
     /// In order to write data longer than what would fit one write request the procedure is explained in
     /// BLUETOOTH CORE SPECIFICATION Version 5.2 | Vol 3, Part F section 3.4.6.
+    ///
+    /// This is only called if `QUEUED_WRITES` is `true`. The default implementation returns an
+    /// error.
     fn execute_write_attr(&mut self, _flags: u8) -> Result<(), Error> {
-        unimplemented!("you need to implement execute_write_attr to make queued writes work")
+        Err(Error::InvalidValue)
     }
 
     /// See BLUETOOTH CORE SPECIFICATION Version 5.2 | Vol 3, Part F section 3.4.3.1 on what to implement here.
+    ///
+    /// This must be implemented to make things like Client Characteristic Configuration work. The
+    /// default implementation makes the server respond with an error.
     fn find_information(
         &mut self,
         _range: HandleRange,
         _responder: &mut Sender<'_>,
     ) -> Result<(), Error> {
-        unimplemented!("you need to implement find_information to make things like Client Characteristic Configuration work")
+        Err(Error::InvalidValue)
     }
 }
 
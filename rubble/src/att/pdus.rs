@@ -2,13 +2,13 @@
 
 use super::{AttUuid, Handle, RawHandleRange};
 use crate::{bytes::*, utils::HexSlice, Error};
-use core::convert::TryInto;
+use core::{convert::TryInto, fmt};
 
 enum_with_unknown! {
     /// Error codes that can be sent from the ATT server to the client in response to a request.
     ///
     /// Used as the payload of `ErrorRsp` PDUs.
-    #[derive(Copy, Clone, Debug, defmt::Format)]
+    #[derive(Copy, Clone, Debug, PartialEq, Eq, defmt::Format)]
     pub enum ErrorCode(u8) {
         /// Attempted to use an `Handle` that isn't valid on this server.
         InvalidHandle = 0x01,
@@ -48,17 +48,19 @@ enum_with_unknown! {
 }
 
 /// An error on the ATT protocol layer. Can be sent as a response.
-#[derive(Debug)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, defmt::Format)]
 pub struct AttError {
     code: ErrorCode,
     handle: Handle,
 }
 
 impl AttError {
+    /// Creates an error with error code `code`, caused by the attribute at `handle`.
     pub fn new(code: ErrorCode, handle: Handle) -> Self {
         Self { code, handle }
     }
 
+    /// Creates an `AttributeNotFound` error with the `NULL` handle.
     pub fn attribute_not_found() -> Self {
         Self::new(ErrorCode::AttributeNotFound, Handle::NULL)
     }
@@ -78,8 +80,18 @@ impl AttError {
     }
 }
 
+impl fmt::Display for AttError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?} (handle {:?})", self.code, self.handle)
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for AttError {}
+
 /// Attribute Data returned in *Read By Type* response.
-#[derive(Debug)]
+#[derive(Debug, defmt::Format)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 pub struct ByTypeAttData<'a> {
     handle: Handle,
     value: HexSlice<&'a [u8]>,
@@ -88,8 +100,12 @@ pub struct ByTypeAttData<'a> {
 impl<'a> ByTypeAttData<'a> {
     /// Creates a *Read By Type Response* attribute data structure from the attribute's handle and
     /// value.
+    ///
+    /// The value is truncated to fit in a response with the given `ATT_MTU`, next to the opcode,
+    /// the length field and the handle. Since the length field also covers the handle, no more
+    /// than 253 Bytes of the value can be returned.
     pub fn new(att_mtu: u8, handle: Handle, mut value: &'a [u8]) -> Self {
-        let max_val_len = usize::from(att_mtu - 2);
+        let max_val_len = usize::from(att_mtu - 4).min(253);
         if value.len() > max_val_len {
             value = &value[..max_val_len];
         }
@@ -128,7 +144,8 @@ impl<'a> ToBytes for ByTypeAttData<'a> {
 }
 
 /// Attribute Data returned in *Read By Group Type* response.
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, defmt::Format)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 pub struct ByGroupAttData<'a> {
     /// The handle of this attribute.
     handle: Handle,
@@ -138,8 +155,9 @@ pub struct ByGroupAttData<'a> {
 
 impl<'a> ByGroupAttData<'a> {
     pub fn new(att_mtu: u8, handle: Handle, group_end_handle: Handle, mut value: &'a [u8]) -> Self {
-        // 2 Bytes for `handle`, 2 Bytes for `group_end_handle`
-        let max_val_len = usize::from(att_mtu - 2 - 2);
+        // Opcode and length field, 2 Bytes for `handle`, 2 Bytes for `group_end_handle`. The
+        // length field covers both handles.
+        let max_val_len = usize::from(att_mtu - 2 - 2 - 2).min(251);
         if value.len() > max_val_len {
             value = &value[..max_val_len];
         }
@@ -201,7 +219,7 @@ enum_with_unknown! {
     ///   can ignore unknown commands. Unlike *Requests*, Commands are not followed by a server
     ///   response.
     /// * **`Method`** defines which operation to perform.
-    #[derive(Debug, Copy, Clone)]
+    #[derive(Debug, Copy, Clone, defmt::Format)]
     pub enum Opcode(u8) {
         ErrorRsp = 0x01,
         ExchangeMtuReq = 0x02,
@@ -260,7 +278,8 @@ impl Opcode {
 /// Structured representation of an ATT message (request or response).
 ///
 /// Note that many responses will need their own type that wraps an iterator.
-#[derive(Debug)]
+#[derive(Debug, defmt::Format)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 pub enum AttPdu<'a> {
     /// Request could not be completed due to an error.
     ErrorRsp {
@@ -466,7 +485,9 @@ impl<'a> FromBytes<'a> for AttPdu<'a> {
             },
             Opcode::SignedWriteCommand => AttPdu::SignedWriteCommand {
                 handle: Handle::from_bytes(bytes)?,
-                value: HexSlice(bytes.read_slice(bytes.bytes_left() - 12)?),
+                value: HexSlice(
+                    bytes.read_slice(bytes.bytes_left().checked_sub(12).ok_or(Error::Eof)?)?,
+                ),
                 signature: HexSlice(bytes.read_slice(12)?.try_into().unwrap()),
             },
             Opcode::PrepareWriteReq => AttPdu::PrepareWriteReq {
@@ -2,23 +2,265 @@
 
 use super::{
     pdus::{AttPdu, ByGroupAttData, ByTypeAttData, ErrorCode, Opcode},
-    AttError, AttributeProvider, Handle, HandleRange,
+    AttError, AttributeProvider, Handle, HandleRange, WriteCommandAction, MAX_ATTRIBUTE_LEN,
 };
 use crate::bytes::{ByteReader, FromBytes, ToBytes};
 use crate::l2cap::{Protocol, ProtocolObj, Sender};
+use crate::link::Peer;
+use crate::security::LinkSecurity;
+use crate::time::{Duration, Instant};
 use crate::{utils::HexSlice, Error};
+use heapless::Vec;
 
-const DYNAMIC_READ_BUFFER_SIZE: usize = 256; // this limits the maximum value size for dynamic reads to 256 bytes
+/// Size of the buffer passed to `AttributeProvider::read_attr_dynamic`.
+const DYNAMIC_READ_BUFFER_SIZE: usize = MAX_ATTRIBUTE_LEN;
+
+/// Maximum length of a deferred value that fits in a *Read Response* (`ATT_MTU - 1`).
+const DEFERRED_VALUE_LEN: usize = 22;
+
+/// Time the client has to confirm an indication before the ATT bearer is closed (Vol 3, Part F,
+/// 3.3.3).
+pub const TRANSACTION_TIMEOUT: Duration = Duration::from_micros(30_000_000);
+
+/// Limits `value` to the maximum attribute length.
+///
+/// Reads always operate on the clamped value, so that *Read Blob* offsets are consistent with what
+/// *Read* returns.
+fn clamp_value(value: &[u8]) -> &[u8] {
+    &value[..value.len().min(MAX_ATTRIBUTE_LEN)]
+}
+
+/// Checks whether the link to `peer` is secure enough to access the attribute at `handle`.
+fn check_security<A: AttributeProvider>(
+    attrs: &A,
+    handle: Handle,
+    peer: Option<&Peer>,
+) -> Result<(), ErrorCode> {
+    let required = attrs.attr_security(handle);
+    let (actual, key_size) = peer.map_or((LinkSecurity::Unencrypted, 0), |peer| {
+        (peer.security, peer.key_size)
+    });
+    if actual >= required {
+        if required > LinkSecurity::Unencrypted && key_size < attrs.attr_key_size(handle) {
+            Err(ErrorCode::InsufficientEncryptionKeySize)
+        } else {
+            Ok(())
+        }
+    } else if required == LinkSecurity::Authenticated {
+        Err(ErrorCode::InsufficientAuthentication)
+    } else {
+        Err(ErrorCode::InsufficientEncryption)
+    }
+}
+
+/// A *Read* or *Read Blob* request waiting for the application to supply the value.
+struct DeferredRead {
+    /// Opcode of the request (`ReadReq` or `ReadBlobReq`).
+    opcode: Opcode,
+    handle: Handle,
+    offset: u16,
+    /// The response, once known.
+    response: Option<Result<Vec<u8, DEFERRED_VALUE_LEN>, ErrorCode>>,
+}
 
 /// An Attribute Protocol server providing read and write access to stored attributes.
 pub struct AttributeServer<A: AttributeProvider> {
     attrs: A,
+    peer: Option<Peer>,
+    deferred: Option<DeferredRead>,
+
+    /// Whether an indication was sent and its confirmation is outstanding.
+    indication_pending: bool,
+
+    /// Time at which the outstanding indication was sent.
+    ///
+    /// `None` if the timeout starts with the next call to `check_timeout`.
+    indication_since: Option<Instant>,
+
+    /// Whether a transaction timed out, which closes the bearer until `reset` is called.
+    timed_out: bool,
+
+    /// Handle of the *Write Command* kept in the RX queue until the provider is ready.
+    stalled: Option<Handle>,
 }
 
 impl<A: AttributeProvider> AttributeServer<A> {
     /// Creates an `AttributeServer` hosting attributes from an `AttributeProvider`.
     pub fn new(attrs: A) -> Self {
-        Self { attrs }
+        Self {
+            attrs,
+            peer: None,
+            deferred: None,
+            indication_pending: false,
+            indication_since: None,
+            timed_out: false,
+            stalled: None,
+        }
+    }
+
+    /// Resets the transaction state for a new connection.
+    ///
+    /// This drops any pending deferred read and outstanding indication, and reopens the bearer
+    /// after a transaction timeout. The peer is not changed.
+    pub fn reset(&mut self) {
+        self.deferred = None;
+        self.indication_pending = false;
+        self.indication_since = None;
+        self.timed_out = false;
+        self.stalled = None;
+    }
+
+    /// Returns whether an indication was sent and not yet confirmed by the client.
+    ///
+    /// No other indication may be sent until this returns `false`.
+    pub fn indication_pending(&self) -> bool {
+        self.indication_pending
+    }
+
+    /// Returns the time at which the outstanding indication times out, if known.
+    ///
+    /// The application should make sure that `check_timeout` is called at that time, for example
+    /// by taking it into account when configuring the Link-Layer's timer.
+    pub fn transaction_deadline(&self) -> Option<Instant> {
+        if self.indication_pending {
+            self.indication_since
+                .map(|since| since + TRANSACTION_TIMEOUT)
+        } else {
+            None
+        }
+    }
+
+    /// Checks whether the outstanding indication has timed out.
+    ///
+    /// If the client doesn't confirm an indication within `TRANSACTION_TIMEOUT`, the ATT bearer is
+    /// closed: The pending deferred read is dropped, no further ATT PDUs are sent or processed,
+    /// and `L2CAPStateTx::att` returns `None`. The specification requires a new bearer, and thus a
+    /// new connection, before the client can be served again, so the application should close the
+    /// connection and call `reset` before serving the next one.
+    ///
+    /// This should be called periodically while connected (eg. whenever the Link-Layer's timer
+    /// fires). The timeout starts with the first call after the indication was sent. Returns
+    /// `true` if the bearer is closed.
+    pub fn check_timeout(&mut self, now: Instant) -> bool {
+        if self.indication_pending {
+            let since = *self.indication_since.get_or_insert(now);
+            if now.duration_since(since) >= TRANSACTION_TIMEOUT {
+                warn!("ATT indication not confirmed in time, closing bearer");
+                self.deferred = None;
+                self.indication_pending = false;
+                self.indication_since = None;
+                self.timed_out = true;
+            }
+        }
+        self.timed_out
+    }
+
+    /// Returns whether processing of incoming packets is paused because the provider isn't ready
+    /// for the next *Write Command*.
+    ///
+    /// See `AttributeProvider::write_command_action` for details.
+    pub fn is_stalled(&self) -> bool {
+        self.stalled.map_or(false, |handle| {
+            self.attrs.write_command_action(handle) == WriteCommandAction::Stall
+        })
+    }
+
+    /// Returns whether a transaction timed out and the bearer is closed.
+    ///
+    /// See [`check_timeout`] for details.
+    ///
+    /// [`check_timeout`]: #method.check_timeout
+    pub fn is_timed_out(&self) -> bool {
+        self.timed_out
+    }
+
+    /// Sets the connected client.
+    ///
+    /// The peer is passed to `AttributeProvider::authorize_read` and `AttributeProvider::write_attr`.
+    /// It should be set when a connection is established (see `Connection::peer`) and cleared when
+    /// it ends. If the client is later identified as a bonded device, it should be set again with
+    /// the resolved `PeerId`.
+    pub fn set_peer(&mut self, peer: Option<Peer>) {
+        self.peer = peer;
+    }
+
+    /// Returns the connected client, if known.
+    pub fn peer(&self) -> Option<&Peer> {
+        self.peer.as_ref()
+    }
+
+    /// Returns the handle and offset of the deferred read waiting for its value, if any.
+    ///
+    /// See `AttributeProvider::is_deferred` for details.
+    pub fn deferred_read(&self) -> Option<(Handle, u16)> {
+        match &self.deferred {
+            Some(read) if read.response.is_none() => Some((read.handle, read.offset)),
+            _ => None,
+        }
+    }
+
+    /// Supplies the value requested by the pending deferred read.
+    ///
+    /// `value` is the attribute value starting at the requested offset. It will be truncated to
+    /// fit in a single `ATT_MTU`. The response is sent by the next call to
+    /// `L2CAPStateTx::send_deferred` (which `Responder::process_one` does automatically).
+    ///
+    /// Returns `Error::InvalidValue` if no read is waiting for its value.
+    pub fn complete_read(&mut self, value: &[u8]) -> Result<(), Error> {
+        let value = &value[..value.len().min(DEFERRED_VALUE_LEN)];
+        self.respond_deferred(Ok(Vec::from_slice(value).unwrap()))
+    }
+
+    /// Rejects the pending deferred read with an ATT error.
+    ///
+    /// This should be used when the storage fails, or with `ErrorCode::InvalidOffset` when the
+    /// requested offset is past the end of the value.
+    ///
+    /// Returns `Error::InvalidValue` if no read is waiting for its value.
+    pub fn fail_read(&mut self, code: ErrorCode) -> Result<(), Error> {
+        self.respond_deferred(Err(code))
+    }
+
+    fn respond_deferred(
+        &mut self,
+        response: Result<Vec<u8, DEFERRED_VALUE_LEN>, ErrorCode>,
+    ) -> Result<(), Error> {
+        match &mut self.deferred {
+            Some(read) if read.response.is_none() => {
+                read.response = Some(response);
+                Ok(())
+            }
+            _ => Err(Error::InvalidValue),
+        }
+    }
+
+    /// Returns whether the response to a deferred read is ready to be sent.
+    pub fn has_deferred_response(&self) -> bool {
+        self.deferred
+            .as_ref()
+            .map_or(false, |read| read.response.is_some())
+    }
+
+    /// Postpones the response to a read until the application supplies the value.
+    fn defer_read(&mut self, opcode: Opcode, handle: Handle, offset: u16) {
+        debug!("deferring read of {:?} at offset {}", handle, offset);
+        if self.deferred.is_some() {
+            warn!("new read request while a deferred read is pending, dropping old one");
+        }
+        self.deferred = Some(DeferredRead {
+            opcode,
+            handle,
+            offset,
+            response: None,
+        });
+        self.attrs.begin_deferred_read(handle, offset);
+    }
+
+    /// Asks the provider whether the client may read the attribute at `handle`.
+    fn authorize_read(&self, handle: Handle) -> Result<(), AttError> {
+        check_security(&self.attrs, handle, self.peer.as_ref())
+            .and_then(|()| self.attrs.authorize_read(handle, self.peer.as_ref()))
+            .map_err(|code| AttError::new(code, handle))
     }
 
     /// Prepares for performing a server-initiated action (eg. sending a notification/indication).
@@ -62,7 +304,8 @@ impl<A: AttributeProvider> AttributeServer<A> {
 
         impl From<Error> for RspError {
             fn from(e: Error) -> Self {
-                panic!("unexpected error: {}", e);
+                error!("unexpected error: {}", e);
+                RspError(AttError::new(ErrorCode::UnlikelyError, Handle::NULL))
             }
         }
 
@@ -73,14 +316,11 @@ impl<A: AttributeProvider> AttributeServer<A> {
         }
 
         match msg {
-            AttPdu::ExchangeMtuReq { mtu: _mtu } => {
-                responder
-                    .send(AttPdu::ExchangeMtuRsp {
-                        mtu: u16::from(Self::RSP_PDU_SIZE),
-                    })
-                    .unwrap();
-                Ok(())
-            }
+            AttPdu::ExchangeMtuReq { mtu: _mtu } => responder
+                .send(AttPdu::ExchangeMtuRsp {
+                    mtu: u16::from(Self::RSP_PDU_SIZE),
+                })
+                .map_err(|_| AttError::new(ErrorCode::UnlikelyError, Handle::NULL)),
 
             AttPdu::ReadByTypeReq {
                 handle_range,
@@ -96,7 +336,9 @@ impl<A: AttributeProvider> AttributeServer<A> {
                     let length = writer.split_next_mut().ok_or(Error::Eof)?;
 
                     let mut size = None;
+                    let mut denied = None;
                     let att_mtu = self.att_mtu();
+                    let peer = self.peer;
                     self.attrs
                         .for_attrs_in_range(range, |provider, attr| {
                             // "Only attributes that can be read shall be returned in a
@@ -104,13 +346,40 @@ impl<A: AttributeProvider> AttributeServer<A> {
                             if attr.att_type == *attribute_type
                                 && provider.attr_access_permissions(attr.handle).is_readable()
                             {
-                                let data =
-                                    ByTypeAttData::new(att_mtu, attr.handle, attr.value.as_ref());
-                                if size == Some(data.encoded_size()) || size.is_none() {
-                                    // Can try to encode `data`. If we run out of space, end the list.
-                                    data.to_bytes(writer)?;
-                                    size = Some(data.encoded_size());
+                                if provider.is_deferred(attr.handle) {
+                                    denied = Some(AttError::new(
+                                        ErrorCode::RequestNotSupported,
+                                        attr.handle,
+                                    ));
+                                    return Err(Error::InvalidValue);
+                                }
+
+                                // An attribute the client may not read ends the list. If it is
+                                // the first one, its error is returned instead.
+                                if let Err(code) =
+                                    check_security(provider, attr.handle, peer.as_ref()).and_then(
+                                        |()| provider.authorize_read(attr.handle, peer.as_ref()),
+                                    )
+                                {
+                                    denied = Some(AttError::new(code, attr.handle));
+                                    return Err(Error::InvalidValue);
+                                }
+
+                                let data = ByTypeAttData::new(
+                                    att_mtu,
+                                    attr.handle,
+                                    clamp_value(attr.value.as_ref()),
+                                );
+                                // All entries must have the same size. The client continues
+                                // after the last returned handle, so an entry of another size
+                                // (eg. a 128-bit UUID after 16-bit ones) ends the list.
+                                if size.map_or(false, |size| size != data.encoded_size()) {
+                                    return Err(Error::InvalidValue);
                                 }
+
+                                // Can try to encode `data`. If we run out of space, end the list.
+                                data.to_bytes(writer)?;
+                                size = Some(data.encoded_size());
                             }
 
                             Ok(())
@@ -121,6 +390,8 @@ impl<A: AttributeProvider> AttributeServer<A> {
                         // At least one attr
                         *length = size;
                         Ok(())
+                    } else if let Some(denied) = denied {
+                        Err(denied.into())
                     } else {
                         Err(AttError::attribute_not_found().into())
                     }
@@ -163,14 +434,20 @@ impl<A: AttributeProvider> AttributeServer<A> {
                                 let data = ByGroupAttData::new(
                                     att_mtu,
                                     attr.handle,
-                                    provider.group_end(attr.handle).unwrap().handle,
+                                    // A group contains at least its grouping attribute
+                                    provider
+                                        .group_end(attr.handle)
+                                        .map_or(attr.handle, |end| end.handle),
                                     attr.value.as_ref(),
                                 );
-                                if size == Some(data.encoded_size()) || size.is_none() {
-                                    // Can try to encode `data`. If we run out of space, end the list.
-                                    data.to_bytes(writer)?;
-                                    size = Some(data.encoded_size());
+                                // Entries of another size end the list, as above
+                                if size.map_or(false, |size| size != data.encoded_size()) {
+                                    return Err(Error::InvalidValue);
                                 }
+
+                                // Can try to encode `data`. If we run out of space, end the list.
+                                data.to_bytes(writer)?;
+                                size = Some(data.encoded_size());
                             }
 
                             Ok(())
@@ -198,15 +475,25 @@ impl<A: AttributeProvider> AttributeServer<A> {
             }
 
             AttPdu::ReadReq { handle } => {
+                if *handle == Handle::NULL {
+                    return Err(AttError::new(ErrorCode::InvalidHandle, *handle));
+                }
                 if !self.attrs.attr_access_permissions(*handle).is_readable() {
                     return Err(AttError::new(ErrorCode::ReadNotPermitted, *handle));
                 }
+                self.authorize_read(*handle)?;
+                if self.attrs.is_deferred(*handle) {
+                    self.defer_read(Opcode::ReadReq, *handle, 0);
+                    return Ok(());
+                }
 
                 responder
                     .send_with(|writer| -> Result<(), Error> {
                         writer.write_u8(Opcode::ReadRsp.into())?;
 
                         let mut buffer = [0u8; DYNAMIC_READ_BUFFER_SIZE];
+                        // Values longer than `ATT_MTU - 1` are truncated, and can be read in full
+                        // using *Read Blob* requests
                         if let Some(data_len) = self.attrs.read_attr_dynamic(*handle, &mut buffer) {
                             let value = &buffer[..data_len];
                             writer.write_slice_truncate(value);
@@ -214,8 +501,7 @@ impl<A: AttributeProvider> AttributeServer<A> {
                             self.attrs.for_attrs_in_range(
                                 HandleRange::new(*handle, *handle),
                                 |_provider, attr| {
-                                    let value = &attr.value.as_ref();
-                                    writer.write_slice_truncate(value);
+                                    writer.write_slice_truncate(clamp_value(attr.value.as_ref()));
                                     Ok(())
                                 },
                             )?;
@@ -223,36 +509,43 @@ impl<A: AttributeProvider> AttributeServer<A> {
 
                         Ok(())
                     })
-                    .unwrap();
+                    .map_err(|_| AttError::new(ErrorCode::UnlikelyError, *handle))?;
 
                 Ok(())
             }
 
             AttPdu::ReadBlobReq { handle, offset } => {
+                if *handle == Handle::NULL {
+                    return Err(AttError::new(ErrorCode::InvalidHandle, *handle));
+                }
                 if !self.attrs.attr_access_permissions(*handle).is_readable() {
                     return Err(AttError::new(ErrorCode::ReadNotPermitted, *handle));
                 }
+                self.authorize_read(*handle)?;
+                if self.attrs.is_deferred(*handle) {
+                    self.defer_read(Opcode::ReadBlobReq, *handle, *offset);
+                    return Ok(());
+                }
 
                 responder
                     .send_with(|writer| -> Result<(), Error> {
                         writer.write_u8(Opcode::ReadBlobRsp.into())?;
 
                         let mut buffer = [0u8; DYNAMIC_READ_BUFFER_SIZE];
+                        // Offsets past the end of the value are rejected with `InvalidValue`
                         if let Some(data_len) = self.attrs.read_attr_dynamic(*handle, &mut buffer) {
                             let offset = *offset as usize;
                             let slice = &buffer[..data_len];
-                            let slice = &slice[offset..];
-
-                            let value = slice.as_ref();
+                            let slice = slice.get(offset..).ok_or(Error::InvalidValue)?;
 
-                            writer.write_slice_truncate(value);
+                            writer.write_slice_truncate(slice);
                         } else {
                             self.attrs.for_attrs_in_range(
                                 HandleRange::new(*handle, *handle),
                                 |_provider, attr| {
-                                    let value = attr.value.as_ref();
+                                    let value = clamp_value(attr.value.as_ref());
                                     let offset = *offset as usize;
-                                    let slice = &value[offset..];
+                                    let slice = value.get(offset..).ok_or(Error::InvalidValue)?;
 
                                     writer.write_slice_truncate(slice);
 
@@ -263,15 +556,25 @@ impl<A: AttributeProvider> AttributeServer<A> {
 
                         Ok(())
                     })
-                    .unwrap();
+                    .map_err(|err| {
+                        AttError::new(
+                            match err {
+                                Error::InvalidValue => ErrorCode::InvalidOffset,
+                                _ => ErrorCode::UnlikelyError,
+                            },
+                            *handle,
+                        )
+                    })?;
 
                 Ok(())
             }
 
             AttPdu::WriteReq { value, handle } => {
                 if self.attrs.attr_access_permissions(*handle).is_writeable() {
+                    check_security(&self.attrs, *handle, self.peer.as_ref())
+                        .map_err(|code| AttError::new(code, *handle))?;
                     self.attrs
-                        .write_attr(*handle, value.as_ref())
+                        .write_attr(*handle, value.as_ref(), self.peer.as_ref())
                         .map_err(|err| {
                             // Convert rubble::Error to AttError
                             AttError::new(
@@ -296,9 +599,11 @@ impl<A: AttributeProvider> AttributeServer<A> {
             }
             AttPdu::WriteCommand { handle, value } => {
                 // WriteCommand shouldn't respond to the client even on failure
-                if self.attrs.attr_access_permissions(*handle).is_writeable() {
+                if self.attrs.attr_access_permissions(*handle).is_writeable()
+                    && check_security(&self.attrs, *handle, self.peer.as_ref()).is_ok()
+                {
                     self.attrs
-                        .write_attr(*handle, value.as_ref())
+                        .write_attr(*handle, value.as_ref(), self.peer.as_ref())
                         .map_err(|err| error!("error while handling write command: {:?}", err))
                         .ok();
                 }
@@ -309,8 +614,19 @@ impl<A: AttributeProvider> AttributeServer<A> {
                 handle,
                 offset,
                 value,
-            } => {
+            } if A::QUEUED_WRITES => {
+                if usize::from(*offset) > MAX_ATTRIBUTE_LEN {
+                    return Err(AttError::new(ErrorCode::InvalidOffset, *handle));
+                }
+                if usize::from(*offset) + value.as_ref().len() > MAX_ATTRIBUTE_LEN {
+                    return Err(AttError::new(
+                        ErrorCode::InvalidAttributeValueLength,
+                        *handle,
+                    ));
+                }
                 if self.attrs.attr_access_permissions(*handle).is_writeable() {
+                    check_security(&self.attrs, *handle, self.peer.as_ref())
+                        .map_err(|code| AttError::new(code, *handle))?;
                     self.attrs
                         .prepare_write_attr(*handle, *offset, value.as_ref())
                         .map_err(|err| {
@@ -339,7 +655,7 @@ impl<A: AttributeProvider> AttributeServer<A> {
                 }
             }
 
-            AttPdu::ExecuteWriteReq { flags } => {
+            AttPdu::ExecuteWriteReq { flags } if A::QUEUED_WRITES => {
                 self.attrs.execute_write_attr(*flags).map_err(|err| {
                     // Convert rubble::Error to AttError
                     AttError::new(
@@ -376,6 +692,16 @@ impl<A: AttributeProvider> AttributeServer<A> {
                     })
             }
 
+            AttPdu::HandleValueConfirmation { .. } => {
+                if self.indication_pending {
+                    self.indication_pending = false;
+                    self.indication_since = None;
+                } else {
+                    warn!("ignoring confirmation without outstanding indication");
+                }
+                Ok(())
+            }
+
             // Responses are always invalid here
             AttPdu::ErrorRsp { .. }
             | AttPdu::ExchangeMtuRsp { .. }
@@ -398,8 +724,9 @@ impl<A: AttributeProvider> AttributeServer<A> {
             AttPdu::Unknown { .. }
             | AttPdu::FindByTypeValueReq { .. }
             | AttPdu::ReadMultipleReq { .. }
-            | AttPdu::SignedWriteCommand { .. }
-            | AttPdu::HandleValueConfirmation { .. } => {
+            | AttPdu::PrepareWriteReq { .. }
+            | AttPdu::ExecuteWriteReq { .. }
+            | AttPdu::SignedWriteCommand { .. } => {
                 if msg.opcode().is_command() {
                     // According to the spec, unknown Command PDUs should be ignored
                     Ok(())
@@ -414,8 +741,53 @@ impl<A: AttributeProvider> AttributeServer<A> {
 
 impl<A: AttributeProvider> ProtocolObj for AttributeServer<A> {
     fn process_message(&mut self, message: &[u8], mut responder: Sender<'_>) -> Result<(), Error> {
-        let pdu = &AttPdu::from_bytes(&mut ByteReader::new(message))?;
+        if self.timed_out {
+            debug!("ATT<- dropping PDU after transaction timeout");
+            return Ok(());
+        }
+
+        let pdu = &match AttPdu::from_bytes(&mut ByteReader::new(message)) {
+            Ok(pdu) => pdu,
+            Err(e) => {
+                // Malformed requests are rejected, malformed commands are dropped
+                let opcode = match message.first() {
+                    Some(&raw) => Opcode::from(raw),
+                    None => return Err(e),
+                };
+                debug!("ATT<- malformed {:?} PDU: {:?}", opcode, e);
+                if opcode.is_command() {
+                    return Ok(());
+                }
+
+                return responder.send(AttPdu::ErrorRsp {
+                    opcode,
+                    handle: Handle::NULL,
+                    error_code: ErrorCode::InvalidPdu,
+                });
+            }
+        };
         let opcode = pdu.opcode();
+
+        if let AttPdu::WriteCommand { handle, .. } = pdu {
+            if self.attrs.attr_access_permissions(*handle).is_writeable() {
+                match self.attrs.write_command_action(*handle) {
+                    WriteCommandAction::Accept => {}
+                    WriteCommandAction::Drop => {
+                        debug!("ATT<- dropping write command to {:?}", handle);
+                        return Ok(());
+                    }
+                    WriteCommandAction::Stall => {
+                        if self.stalled.is_none() {
+                            debug!("ATT<- stalling write command to {:?}", handle);
+                        }
+                        self.stalled = Some(*handle);
+                        return Err(Error::RxBusy);
+                    }
+                }
+            }
+            self.stalled = None;
+        }
+
         debug!("ATT<- {:?}", pdu);
 
         match self.process_request(pdu, &mut responder) {
@@ -436,6 +808,10 @@ impl<A: AttributeProvider> ProtocolObj for AttributeServer<A> {
 impl<A: AttributeProvider> Protocol for AttributeServer<A> {
     // FIXME: Would it be useful to have this as a runtime parameter instead?
     const RSP_PDU_SIZE: u8 = 23;
+
+    fn max_pdu_size(&self) -> u16 {
+        u16::from(self.att_mtu())
+    }
 }
 
 /// An ATT server handle that can send packets and initiate actions.
@@ -443,13 +819,17 @@ impl<A: AttributeProvider> Protocol for AttributeServer<A> {
 /// This type is needed for any server-initiated procedure, where the server sends out a packet on
 /// its own instead of reacting to a client packet.
 pub struct AttributeServerTx<'a, A: AttributeProvider> {
-    #[allow(unused)]
     server: &'a mut AttributeServer<A>,
 
     sender: Sender<'a>,
 }
 
 impl<'a, A: AttributeProvider> AttributeServerTx<'a, A> {
+    /// Provides mutable access to the underlying `AttributeProvider`.
+    pub fn provider(&mut self) -> &mut A {
+        self.server.provider()
+    }
+
     /// Sends an attribute value notification to the connected client.
     ///
     /// Notifications are not acknowledged by the client.
@@ -468,4 +848,639 @@ impl<'a, A: AttributeProvider> AttributeServerTx<'a, A> {
             })
             .unwrap()
     }
+
+    /// Sends the response to a deferred read, if the application has supplied its value.
+    ///
+    /// Returns whether a response was sent. It is usually not necessary to call this directly;
+    /// use `L2CAPStateTx::send_deferred` instead.
+    pub fn send_deferred(mut self) -> bool {
+        let read = match self.server.deferred.take() {
+            Some(DeferredRead {
+                opcode,
+                handle,
+                response: Some(response),
+                ..
+            }) => (opcode, handle, response),
+            other => {
+                self.server.deferred = other;
+                return false;
+            }
+        };
+
+        // This cannot fail for the same reasons as in `notify_raw`.
+        match read {
+            (opcode, _, Ok(value)) => {
+                let rsp = if let Opcode::ReadReq = opcode {
+                    Opcode::ReadRsp
+                } else {
+                    Opcode::ReadBlobRsp
+                };
+                self.sender
+                    .send_with(|writer| -> Result<(), Error> {
+                        writer.write_u8(rsp.into())?;
+                        writer.write_slice_truncate(&value);
+                        Ok(())
+                    })
+                    .unwrap();
+            }
+            (opcode, handle, Err(error_code)) => self
+                .sender
+                .send(AttPdu::ErrorRsp {
+                    opcode,
+                    handle,
+                    error_code,
+                })
+                .unwrap(),
+        }
+        true
+    }
+
+    /// Sends an attribute value indication to the connected client.
+    ///
+    /// Unlike notifications, indications are acknowledged by the client with a *Handle Value
+    /// Confirmation*. Only one indication may be outstanding at any time, which can be checked
+    /// with `AttributeServer::indication_pending`. If the client doesn't confirm the indication
+    /// within `TRANSACTION_TIMEOUT`, the bearer is closed (see `AttributeServer::check_timeout`).
+    ///
+    /// Like with `notify_raw`, `value` will be truncated to fit into a single `ATT_MTU`.
+    pub fn indicate_raw(mut self, handle: Handle, value: &[u8]) {
+        if self.server.indication_pending {
+            warn!("sending indication while another one is outstanding");
+        }
+
+        // This cannot fail for the same reasons as in `notify_raw`.
+        self.sender
+            .send(AttPdu::HandleValueIndication {
+                handle,
+                value: HexSlice(value),
+            })
+            .unwrap();
+        self.server.indication_pending = true;
+        self.server.indication_since = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::att::{AttUuid, Attribute, AttributeAccessPermissions};
+    use crate::gatt::characteristic::Properties;
+    use crate::gatt::StaticAttributes;
+    use crate::l2cap::{BleChannelMap, ChannelMapper, L2CAPState};
+    use crate::link::data::Llid;
+    use crate::link::queue::{Consume, Consumer, PacketQueue, Producer, SimpleQueue};
+    use crate::link::{AddressKind, ConnectionHandle, DeviceAddress, PeerId};
+    use crate::uuid::consts::declarations::{CHARACTERISTIC, PRIMARY_SERVICE};
+    use crate::uuid::{Uuid128, Uuid16};
+    use heapless::Vec;
+
+    const PEER: DeviceAddress = DeviceAddress::new([1, 2, 3, 4, 5, 6], AddressKind::Public);
+
+    /// Two attributes of the same type, the second of which may only be read by `PEER`.
+    struct Locked(StaticAttributes<2>);
+
+    impl AttributeProvider for Locked {
+        fn for_attrs_in_range(
+            &mut self,
+            range: HandleRange,
+            mut f: impl FnMut(&Self, &Attribute<dyn AsRef<[u8]>>) -> Result<(), Error>,
+        ) -> Result<(), Error> {
+            let this = &*self;
+            this.0.for_each_in_range(range, |attr| f(this, attr))
+        }
+
+        fn is_grouping_attr(&self, uuid: AttUuid) -> bool {
+            self.0.is_grouping_attr(uuid)
+        }
+
+        fn group_end(&self, handle: Handle) -> Option<&Attribute<dyn AsRef<[u8]>>> {
+            self.0.group_end(handle)
+        }
+
+        fn authorize_read(&self, handle: Handle, peer: Option<&Peer>) -> Result<(), ErrorCode> {
+            if handle == Handle::from_raw(2) && peer.map(|p| p.id) != Some(PeerId::Identity(PEER)) {
+                Err(ErrorCode::InsufficientAuthorization)
+            } else {
+                Ok(())
+            }
+        }
+    }
+
+    fn server() -> L2CAPState<BleChannelMap<Locked, crate::security::NoSecurity>> {
+        let mut attrs = StaticAttributes::new();
+        for value in &[[0xAA], [0xBB]] {
+            attrs
+                .push(
+                    Uuid16(0x2A19).into(),
+                    value,
+                    AttributeAccessPermissions::Readable,
+                )
+                .unwrap();
+        }
+        L2CAPState::new(BleChannelMap::with_attributes(Locked(attrs)))
+    }
+
+    /// Sends an ATT request to the server and returns the response PDU.
+    ///
+    /// Returns an empty PDU if the server didn't respond.
+    fn request<M: ChannelMapper>(l2cap: &mut L2CAPState<M>, pdu: &[u8]) -> Vec<u8, 23> {
+        let mut queue = SimpleQueue::new();
+        let (mut tx, mut rx) = (&mut queue).split();
+        let mut message = Vec::<u8, 27>::from_slice(&[pdu.len() as u8, 0, 0x04, 0x00]).unwrap();
+        message.extend_from_slice(pdu).unwrap();
+        l2cap.tx(&mut tx).process_start(&message);
+        rx.consume_raw_with(|_, raw| Consume::always(Ok(Vec::from_slice(&raw[4..]).unwrap())))
+            .unwrap_or_default()
+    }
+
+    #[test]
+    fn mixed_uuid_widths() {
+        const CUSTOM: Uuid128 = Uuid128::parse_static("a86a62f0-5d26-4538-b364-5654961515c9");
+
+        let mut attrs = StaticAttributes::<6>::new();
+        // Stored as 128-bit UUID, but sent as 16-bit alias
+        attrs
+            .push_primary_service(Uuid16(0x180F).to_uuid128().into())
+            .unwrap();
+        attrs
+            .push_characteristic(
+                Uuid16(0x2A19).into(),
+                Properties::READ,
+                &[48],
+                Default::default(),
+            )
+            .unwrap();
+        attrs.push_primary_service(CUSTOM.into()).unwrap();
+        attrs
+            .push_characteristic(CUSTOM.into(), Properties::READ, &[0], Default::default())
+            .unwrap();
+        let mut l2cap = L2CAPState::new(
+            BleChannelMap::<_, crate::security::NoSecurity>::with_attributes(attrs),
+        );
+
+        // Responses end at the first entry of another width
+        assert_eq!(
+            &request(&mut l2cap, &[0x10, 1, 0, 0xFF, 0xFF, 0x00, 0x28])[..],
+            &[0x11, 6, 1, 0, 3, 0, 0x0F, 0x18]
+        );
+        assert_eq!(
+            &request(&mut l2cap, &[0x08, 1, 0, 0xFF, 0xFF, 0x03, 0x28])[..],
+            &[0x09, 7, 2, 0, 0x02, 3, 0, 0x19, 0x2A]
+        );
+        assert_eq!(
+            &request(&mut l2cap, &[0x04, 3, 0, 0xFF, 0xFF])[..],
+            &[0x05, 0x01, 3, 0, 0x19, 0x2A, 4, 0, 0x00, 0x28, 5, 0, 0x03, 0x28]
+        );
+
+        let mut expected = Vec::<u8, 23>::from_slice(&[0x05, 0x02, 6, 0]).unwrap();
+        expected.extend_from_slice(&CUSTOM.to_le_bytes()).unwrap();
+        assert_eq!(request(&mut l2cap, &[0x04, 6, 0, 0xFF, 0xFF]), expected);
+    }
+
+    #[test]
+    fn read_authorization() {
+        let mut l2cap = server();
+        assert_eq!(&request(&mut l2cap, &[0x0A, 1, 0])[..], &[0x0B, 0xAA]);
+        assert_eq!(
+            &request(&mut l2cap, &[0x0A, 2, 0])[..],
+            &[0x01, 0x0A, 2, 0, 0x08]
+        );
+        assert_eq!(
+            &request(&mut l2cap, &[0x0C, 2, 0, 0, 0])[..],
+            &[0x01, 0x0C, 2, 0, 0x08]
+        );
+
+        l2cap
+            .channel_mapper()
+            .att()
+            .protocol()
+            .set_peer(Some(Peer::new(ConnectionHandle::from_raw(0), PEER)));
+        assert_eq!(&request(&mut l2cap, &[0x0A, 2, 0])[..], &[0x0B, 0xBB]);
+    }
+
+    #[test]
+    fn read_by_type_authorization() {
+        let mut l2cap = server();
+
+        // The unauthorized attribute ends the list
+        assert_eq!(
+            &request(&mut l2cap, &[0x08, 1, 0, 0xFF, 0xFF, 0x19, 0x2A])[..],
+            &[0x09, 3, 1, 0, 0xAA]
+        );
+        assert_eq!(
+            &request(&mut l2cap, &[0x08, 2, 0, 0xFF, 0xFF, 0x19, 0x2A])[..],
+            &[0x01, 0x08, 2, 0, 0x08]
+        );
+
+        l2cap
+            .channel_mapper()
+            .att()
+            .protocol()
+            .set_peer(Some(Peer::new(ConnectionHandle::from_raw(0), PEER)));
+        assert_eq!(
+            &request(&mut l2cap, &[0x08, 1, 0, 0xFF, 0xFF, 0x19, 0x2A])[..],
+            &[0x09, 3, 1, 0, 0xAA, 2, 0, 0xBB]
+        );
+    }
+
+    #[test]
+    fn indication_timeout() {
+        /// Sends an indication and returns whether the server allowed it.
+        fn indicate<M: ChannelMapper>(l2cap: &mut L2CAPState<M>) -> bool {
+            let mut queue = SimpleQueue::new();
+            let (mut tx, _) = (&mut queue).split();
+            let att = l2cap.tx(&mut tx).into_att();
+            att.map(|att| att.indicate_raw(Handle::from_raw(1), &[0xAA]))
+                .is_some()
+        }
+
+        let at = |secs: u32| Instant::from_raw_micros(secs * 1_000_000);
+        let mut l2cap = server();
+
+        // Confirmed in time
+        assert!(indicate(&mut l2cap));
+        let server = l2cap.channel_mapper().att().into_protocol();
+        assert!(server.indication_pending());
+        assert!(!server.check_timeout(at(0)));
+        assert_eq!(
+            server.transaction_deadline().map(|d| d.raw_micros()),
+            Some(at(30).raw_micros())
+        );
+        assert_eq!(&request(&mut l2cap, &[0x1E])[..], &[]);
+        let server = l2cap.channel_mapper().att().into_protocol();
+        assert!(!server.indication_pending());
+        assert!(server.transaction_deadline().is_none());
+        assert!(!server.check_timeout(at(100)));
+
+        // Not confirmed
+        assert!(indicate(&mut l2cap));
+        let server = l2cap.channel_mapper().att().into_protocol();
+        assert!(!server.check_timeout(at(100)));
+        assert!(!server.check_timeout(at(129)));
+        assert!(server.check_timeout(at(130)));
+        assert!(server.is_timed_out());
+        assert!(!indicate(&mut l2cap));
+        assert_eq!(&request(&mut l2cap, &[0x0A, 1, 0])[..], &[]);
+
+        // New connection
+        l2cap.channel_mapper().att().into_protocol().reset();
+        assert_eq!(&request(&mut l2cap, &[0x0A, 1, 0])[..], &[0x0B, 0xAA]);
+        assert!(indicate(&mut l2cap));
+    }
+
+    /// Two attributes requiring an encrypted and an authenticated link, respectively, and keys of
+    /// at least 10 Bytes.
+    struct Secured(StaticAttributes<2>);
+
+    impl AttributeProvider for Secured {
+        fn for_attrs_in_range(
+            &mut self,
+            range: HandleRange,
+            mut f: impl FnMut(&Self, &Attribute<dyn AsRef<[u8]>>) -> Result<(), Error>,
+        ) -> Result<(), Error> {
+            let this = &*self;
+            this.0.for_each_in_range(range, |attr| f(this, attr))
+        }
+
+        fn is_grouping_attr(&self, uuid: AttUuid) -> bool {
+            self.0.is_grouping_attr(uuid)
+        }
+
+        fn group_end(&self, handle: Handle) -> Option<&Attribute<dyn AsRef<[u8]>>> {
+            self.0.group_end(handle)
+        }
+
+        fn attr_access_permissions(&self, _handle: Handle) -> AttributeAccessPermissions {
+            AttributeAccessPermissions::ReadableAndWriteable
+        }
+
+        fn attr_security(&self, handle: Handle) -> LinkSecurity {
+            if handle == Handle::from_raw(1) {
+                LinkSecurity::Encrypted
+            } else {
+                LinkSecurity::Authenticated
+            }
+        }
+
+        fn attr_key_size(&self, _handle: Handle) -> u8 {
+            10
+        }
+
+        fn write_attr(
+            &mut self,
+            _handle: Handle,
+            _data: &[u8],
+            _peer: Option<&Peer>,
+        ) -> Result<(), Error> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn link_security() {
+        let mut attrs = StaticAttributes::new();
+        for value in &[[0xAA], [0xBB]] {
+            attrs
+                .push(
+                    Uuid16(0x2A19).into(),
+                    value,
+                    AttributeAccessPermissions::ReadableAndWriteable,
+                )
+                .unwrap();
+        }
+        let mut l2cap = L2CAPState::new(
+            BleChannelMap::<_, crate::security::NoSecurity>::with_attributes(Secured(attrs)),
+        );
+        let set_security =
+            |l2cap: &mut L2CAPState<BleChannelMap<Secured, crate::security::NoSecurity>>,
+             security,
+             key_size| {
+                let mut peer = Peer::new(ConnectionHandle::from_raw(0), PEER);
+                peer.security = security;
+                peer.key_size = key_size;
+                l2cap.channel_mapper().att().protocol().set_peer(Some(peer));
+            };
+
+        assert_eq!(
+            &request(&mut l2cap, &[0x0A, 1, 0])[..],
+            &[0x01, 0x0A, 1, 0, 0x0F]
+        );
+        assert_eq!(
+            &request(&mut l2cap, &[0x12, 1, 0, 0xCC])[..],
+            &[0x01, 0x12, 1, 0, 0x0F]
+        );
+        assert_eq!(
+            &request(&mut l2cap, &[0x08, 1, 0, 0xFF, 0xFF, 0x19, 0x2A])[..],
+            &[0x01, 0x08, 1, 0, 0x0F]
+        );
+
+        set_security(&mut l2cap, LinkSecurity::Encrypted, 9);
+        assert_eq!(
+            &request(&mut l2cap, &[0x0A, 1, 0])[..],
+            &[0x01, 0x0A, 1, 0, 0x0C]
+        );
+
+        set_security(&mut l2cap, LinkSecurity::Encrypted, 16);
+        assert_eq!(&request(&mut l2cap, &[0x0A, 1, 0])[..], &[0x0B, 0xAA]);
+        assert_eq!(&request(&mut l2cap, &[0x12, 1, 0, 0xCC])[..], &[0x13]);
+        assert_eq!(
+            &request(&mut l2cap, &[0x0A, 2, 0])[..],
+            &[0x01, 0x0A, 2, 0, 0x05]
+        );
+        assert_eq!(
+            &request(&mut l2cap, &[0x08, 1, 0, 0xFF, 0xFF, 0x19, 0x2A])[..],
+            &[0x09, 3, 1, 0, 0xAA]
+        );
+
+        set_security(&mut l2cap, LinkSecurity::Authenticated, 10);
+        assert_eq!(&request(&mut l2cap, &[0x0A, 2, 0])[..], &[0x0B, 0xBB]);
+    }
+
+    /// A writeable attribute whose written Bytes are buffered until the test drains them.
+    struct Sink {
+        attrs: StaticAttributes<1>,
+        received: Vec<u8, 2>,
+        when_full: WriteCommandAction,
+    }
+
+    impl AttributeProvider for Sink {
+        fn for_attrs_in_range(
+            &mut self,
+            range: HandleRange,
+            mut f: impl FnMut(&Self, &Attribute<dyn AsRef<[u8]>>) -> Result<(), Error>,
+        ) -> Result<(), Error> {
+            let this = &*self;
+            this.attrs.for_each_in_range(range, |attr| f(this, attr))
+        }
+
+        fn is_grouping_attr(&self, uuid: AttUuid) -> bool {
+            self.attrs.is_grouping_attr(uuid)
+        }
+
+        fn group_end(&self, handle: Handle) -> Option<&Attribute<dyn AsRef<[u8]>>> {
+            self.attrs.group_end(handle)
+        }
+
+        fn attr_access_permissions(&self, _handle: Handle) -> AttributeAccessPermissions {
+            AttributeAccessPermissions::Writeable
+        }
+
+        fn write_command_action(&self, _handle: Handle) -> WriteCommandAction {
+            if self.received.is_full() {
+                self.when_full
+            } else {
+                WriteCommandAction::Accept
+            }
+        }
+
+        fn write_attr(
+            &mut self,
+            _handle: Handle,
+            data: &[u8],
+            _peer: Option<&Peer>,
+        ) -> Result<(), Error> {
+            self.received.push(data[0]).map_err(|_| Error::Eof)
+        }
+    }
+
+    fn sink(
+        when_full: WriteCommandAction,
+    ) -> L2CAPState<BleChannelMap<Sink, crate::security::NoSecurity>> {
+        let mut attrs = StaticAttributes::new();
+        attrs
+            .push(
+                Uuid16(0x2A19).into(),
+                &[0],
+                AttributeAccessPermissions::Writeable,
+            )
+            .unwrap();
+        L2CAPState::new(BleChannelMap::with_attributes(Sink {
+            attrs,
+            received: Vec::new(),
+            when_full,
+        }))
+    }
+
+    /// Passes a *Write Command* for attribute 1 to the server, checking that it is only consumed
+    /// if it wasn't stalled.
+    fn write_command<M: ChannelMapper>(l2cap: &mut L2CAPState<M>, value: u8) -> Result<(), Error> {
+        let mut queue = SimpleQueue::new();
+        let (mut tx, _) = (&mut queue).split();
+        let mut rx_queue = SimpleQueue::new();
+        let (mut rx_tx, mut rx) = (&mut rx_queue).split();
+        rx_tx
+            .produce_with(8, |writer| -> Result<_, Error> {
+                writer.write_slice(&[4, 0, 0x04, 0x00, 0x52, 1, 0, value])?;
+                Ok(Llid::DataStart)
+            })
+            .unwrap();
+        let result = rx.consume_raw_with(|_, raw| l2cap.tx(&mut tx).process_start(raw));
+        match result {
+            Err(Error::RxBusy) => assert!(rx.has_data()),
+            _ => assert!(!rx.has_data()),
+        }
+        result
+    }
+
+    #[test]
+    fn write_command_flood() {
+        let mut l2cap = sink(WriteCommandAction::Stall);
+        assert_eq!(write_command(&mut l2cap, 1), Ok(()));
+        assert_eq!(write_command(&mut l2cap, 2), Ok(()));
+        assert_eq!(write_command(&mut l2cap, 3), Err(Error::RxBusy));
+        let mut att = l2cap.channel_mapper().att();
+        assert!(att.protocol().is_stalled());
+        att.protocol().provider().received.clear();
+        assert!(!att.protocol().is_stalled());
+        assert_eq!(write_command(&mut l2cap, 3), Ok(()));
+        let server = l2cap.channel_mapper().att().into_protocol();
+        assert_eq!(&server.provider().received[..], &[3]);
+
+        let mut l2cap = sink(WriteCommandAction::Drop);
+        for value in 1..=3 {
+            assert_eq!(write_command(&mut l2cap, value), Ok(()));
+        }
+        let server = l2cap.channel_mapper().att().into_protocol();
+        assert!(!server.is_stalled());
+        assert_eq!(&server.provider().received[..], &[1, 2]);
+    }
+
+    #[test]
+    fn queued_writes_unsupported() {
+        let mut l2cap = sink(WriteCommandAction::Accept);
+        assert_eq!(
+            &request(&mut l2cap, &[0x16, 1, 0, 0, 0, 0xAA])[..],
+            &[0x01, 0x16, 0, 0, 0x06]
+        );
+        assert_eq!(
+            &request(&mut l2cap, &[0x18, 0x01])[..],
+            &[0x01, 0x18, 0, 0, 0x06]
+        );
+    }
+
+    /// A service with a single characteristic whose value exceeds the maximum attribute length.
+    struct Long {
+        value: Attribute<[u8; 600]>,
+    }
+
+    impl AttributeProvider for Long {
+        const QUEUED_WRITES: bool = true;
+
+        fn for_attrs_in_range(
+            &mut self,
+            range: HandleRange,
+            mut f: impl FnMut(&Self, &Attribute<dyn AsRef<[u8]>>) -> Result<(), Error>,
+        ) -> Result<(), Error> {
+            let this = &*self;
+            let service = Attribute::new(PRIMARY_SERVICE.into(), Handle::from_raw(1), [0x0F, 0x18]);
+            let decl = Attribute::new(
+                CHARACTERISTIC.into(),
+                Handle::from_raw(2),
+                [0x0A, 3, 0, 0x19, 0x2A],
+            );
+            let attrs: [&Attribute<dyn AsRef<[u8]>>; 3] = [&service, &decl, &this.value];
+            for attr in attrs.iter().filter(|attr| range.contains(attr.handle)) {
+                f(this, attr)?;
+            }
+            Ok(())
+        }
+
+        fn is_grouping_attr(&self, uuid: AttUuid) -> bool {
+            uuid == PRIMARY_SERVICE
+        }
+
+        fn group_end(&self, _handle: Handle) -> Option<&Attribute<dyn AsRef<[u8]>>> {
+            None
+        }
+
+        fn attr_access_permissions(&self, _handle: Handle) -> AttributeAccessPermissions {
+            AttributeAccessPermissions::ReadableAndWriteable
+        }
+
+        fn prepare_write_attr(
+            &mut self,
+            _handle: Handle,
+            _offset: u16,
+            _data: &[u8],
+        ) -> Result<(), Error> {
+            Ok(())
+        }
+    }
+
+    fn long() -> L2CAPState<BleChannelMap<Long, crate::security::NoSecurity>> {
+        let mut value = [0; 600];
+        for (i, b) in value.iter_mut().enumerate() {
+            *b = (i % 251) as u8;
+        }
+        let value = Attribute::new(Uuid16(0x2A19).into(), Handle::from_raw(3), value);
+        L2CAPState::new(BleChannelMap::with_attributes(Long { value }))
+    }
+
+    /// Builds the expected response PDU from an opcode and a part of the `Long` value.
+    fn rsp(opcode: u8, value: core::ops::Range<usize>) -> Vec<u8, 23> {
+        let mut rsp = Vec::from_slice(&[opcode]).unwrap();
+        rsp.extend(value.map(|i| (i % 251) as u8));
+        rsp
+    }
+
+    #[test]
+    fn long_discovery() {
+        let mut l2cap = long();
+        assert_eq!(
+            &request(&mut l2cap, &[0x08, 1, 0, 0xFF, 0xFF, 0x03, 0x28])[..],
+            &[0x09, 7, 2, 0, 0x0A, 3, 0, 0x19, 0x2A]
+        );
+
+        // Read By Type truncates to `ATT_MTU - 4`
+        let mut expected = Vec::<u8, 23>::from_slice(&[0x09, 21, 3, 0]).unwrap();
+        expected.extend_from_slice(&rsp(0, 0..19)[1..]).unwrap();
+        assert_eq!(
+            request(&mut l2cap, &[0x08, 1, 0, 0xFF, 0xFF, 0x19, 0x2A]),
+            expected
+        );
+    }
+
+    #[test]
+    fn long_read() {
+        let mut l2cap = long();
+
+        // Read truncates to `ATT_MTU - 1`, the rest is read using Read Blob
+        assert_eq!(request(&mut l2cap, &[0x0A, 3, 0]), rsp(0x0B, 0..22));
+        assert_eq!(request(&mut l2cap, &[0x0C, 3, 0, 22, 0]), rsp(0x0D, 22..44));
+        assert_eq!(
+            request(&mut l2cap, &[0x0C, 3, 0, 0x00, 0x01]),
+            rsp(0x0D, 256..278)
+        );
+
+        // The value ends at 512 Bytes
+        assert_eq!(
+            request(&mut l2cap, &[0x0C, 3, 0, 0xFA, 0x01]),
+            rsp(0x0D, 506..512)
+        );
+        assert_eq!(
+            request(&mut l2cap, &[0x0C, 3, 0, 0x00, 0x02]),
+            rsp(0x0D, 0..0)
+        );
+        assert_eq!(
+            &request(&mut l2cap, &[0x0C, 3, 0, 0x01, 0x02])[..],
+            &[0x01, 0x0C, 3, 0, 0x07]
+        );
+    }
+
+    #[test]
+    fn long_prepare_write() {
+        let mut l2cap = long();
+        assert_eq!(
+            &request(&mut l2cap, &[0x16, 3, 0, 0xFF, 0x01, 0xAA])[..],
+            &[0x17, 3, 0, 0xFF, 0x01, 0xAA]
+        );
+        assert_eq!(
+            &request(&mut l2cap, &[0x16, 3, 0, 0xFF, 0x01, 0xAA, 0xBB])[..],
+            &[0x01, 0x16, 3, 0, 0x0D]
+        );
+        assert_eq!(
+            &request(&mut l2cap, &[0x16, 3, 0, 0x01, 0x02])[..],
+            &[0x01, 0x16, 3, 0, 0x07]
+        );
+    }
 }
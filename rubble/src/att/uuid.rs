@@ -1,15 +1,77 @@
 use crate::{bytes::*, uuid::*, Error};
-use core::{cmp::PartialEq, fmt};
+use core::{
+    cmp::{Ord, Ordering, PartialEq},
+    fmt,
+};
 
 /// ATT protocol UUID (either a 16 or a 128-bit UUID).
 ///
 /// 32-bit UUIDs are not supported by ATT and must be converted to 128-bit UUIDs.
-#[derive(Copy, Clone, Eq)]
+#[derive(Copy, Clone, Eq, defmt::Format)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum AttUuid {
     Uuid16(Uuid16),
     Uuid128(Uuid128),
 }
 
+/// The width of an encoded ATT UUID.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, defmt::Format)]
+pub enum UuidWidth {
+    /// A 16-bit UUID alias (2 Bytes).
+    Bits16,
+    /// A full 128-bit UUID (16 Bytes).
+    Bits128,
+}
+
+impl UuidWidth {
+    /// Returns the number of Bytes a UUID of this width occupies.
+    pub fn encoded_len(&self) -> usize {
+        match self {
+            UuidWidth::Bits16 => 2,
+            UuidWidth::Bits128 => 16,
+        }
+    }
+
+    /// Returns the value of the *Format* field of a *Find Information Response* listing UUIDs of
+    /// this width.
+    pub fn find_information_format(&self) -> u8 {
+        match self {
+            UuidWidth::Bits16 => 0x01,
+            UuidWidth::Bits128 => 0x02,
+        }
+    }
+}
+
+impl AttUuid {
+    /// Returns the shortest representation of this UUID.
+    ///
+    /// 128-bit UUIDs derived from the Bluetooth Base UUID with a 16-bit alias are converted to that
+    /// alias. Responses should send UUIDs in this form, since clients aren't required to recognize
+    /// a 128-bit UUID as equal to its 16-bit alias.
+    pub fn folded(self) -> Self {
+        match self {
+            AttUuid::Uuid128(uuid) => match uuid.to_uuid16() {
+                Some(short) => AttUuid::Uuid16(short),
+                None => self,
+            },
+            AttUuid::Uuid16(_) => self,
+        }
+    }
+
+    /// Returns the width of this UUID, as encoded (without folding).
+    pub fn width(&self) -> UuidWidth {
+        match self {
+            AttUuid::Uuid16(_) => UuidWidth::Bits16,
+            AttUuid::Uuid128(_) => UuidWidth::Bits128,
+        }
+    }
+
+    /// Returns the width of the shortest representation of this UUID.
+    pub fn folded_width(&self) -> UuidWidth {
+        self.folded().width()
+    }
+}
+
 impl FromBytes<'_> for AttUuid {
     fn from_bytes(bytes: &mut ByteReader<'_>) -> Result<Self, Error> {
         Ok(match bytes.bytes_left() {
@@ -44,6 +106,20 @@ impl PartialEq for AttUuid {
     }
 }
 
+/// UUIDs are ordered by their 128-bit value, consistent with `PartialEq`.
+impl Ord for AttUuid {
+    fn cmp(&self, other: &Self) -> Ordering {
+        let (a, b): (Uuid128, Uuid128) = ((*self).into(), (*other).into());
+        a.to_u128().cmp(&b.to_u128())
+    }
+}
+
+impl PartialOrd for AttUuid {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
 impl PartialEq<Uuid16> for AttUuid {
     fn eq(&self, other: &Uuid16) -> bool {
         self == &Self::from(*other)
@@ -91,3 +167,36 @@ impl fmt::Debug for AttUuid {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CUSTOM: Uuid128 = Uuid128::parse_static("a86a62f0-5d26-4538-b364-5654961515c9");
+
+    #[test]
+    fn folding() {
+        let battery = AttUuid::from(Uuid16(0x180F).to_uuid128());
+        assert_eq!(battery.width(), UuidWidth::Bits128);
+        assert_eq!(battery.folded_width(), UuidWidth::Bits16);
+        match battery.folded() {
+            AttUuid::Uuid16(uuid) => assert_eq!(uuid, Uuid16(0x180F)),
+            AttUuid::Uuid128(_) => panic!("not folded"),
+        }
+
+        // 32-bit aliases can't be folded
+        let uuid32 = AttUuid::from(Uuid32(0x0001_0000));
+        assert_eq!(uuid32.folded_width(), UuidWidth::Bits128);
+        assert_eq!(AttUuid::from(CUSTOM).folded_width(), UuidWidth::Bits128);
+    }
+
+    #[test]
+    fn ordering() {
+        let a = AttUuid::from(Uuid16(0x2800));
+        let b = AttUuid::from(Uuid16(0x2803).to_uuid128());
+        assert!(a < b);
+        assert!(AttUuid::from(Uuid16(0x2803)) <= b);
+        assert!(AttUuid::from(Uuid16(0x2803)) >= b);
+        assert!(AttUuid::from(CUSTOM) > b);
+    }
+}
@@ -1,19 +1,27 @@
 //! BLE beacon support, without dealing with Link-Layer stuff.
 
-use crate::link::advertising::{Header, Pdu, PduBuf};
-use crate::link::filter::{self, AddressFilter, ScanFilter};
+use crate::config::Config;
+use crate::link::advertising::{AdvDelay, AdvertisingInterval, Header, Pdu, PduBuf, PduType};
+use crate::link::filter::{self, AdFilter, AddressFilter, ScanFilter};
 use crate::link::{
-    ad_structure::AdStructure, Cmd, DeviceAddress, NextUpdate, RadioCmd, Transmitter,
+    ad_structure::AdStructure, Cmd, DeviceAddress, LinkLayer, NextUpdate, RadioCmd, Transmitter,
 };
 use crate::phy::AdvertisingChannel;
-use crate::time::{Duration, Instant};
+use crate::time::{Duration, Instant, Timer};
 use crate::{bytes::*, Error};
+use heapless::Vec;
 
 /// A BLE beacon.
 ///
-/// FIXME: This has to randomly offset the broadcast interval
+/// The application decides when to call [`Beacon::broadcast`]. To avoid repeated collisions with
+/// other advertisers, the time between two broadcasts should be the advertising interval plus the
+/// pseudo-random delay returned by [`Beacon::adv_delay`].
+///
+/// [`Beacon::broadcast`]: #method.broadcast
+/// [`Beacon::adv_delay`]: #method.adv_delay
 pub struct Beacon {
     pdu: PduBuf,
+    adv_delay: AdvDelay,
 }
 
 impl Beacon {
@@ -30,7 +38,18 @@ impl Beacon {
     /// If `data` doesn't fit in a single PDU, an error will be returned.
     pub fn new(addr: DeviceAddress, data: &[AdStructure<'_>]) -> Result<Self, Error> {
         let pdu = PduBuf::beacon(addr, data)?;
-        Ok(Self { pdu })
+        Ok(Self {
+            pdu,
+            adv_delay: AdvDelay::new(&addr),
+        })
+    }
+
+    /// Returns the pseudo-random delay to add to the advertising interval before the next
+    /// broadcast.
+    ///
+    /// Every call returns a new delay of up to `MAX_ADV_DELAY`.
+    pub fn adv_delay(&mut self) -> Duration {
+        self.adv_delay.next()
     }
 
     /// Broadcasts the beacon data using `tx`.
@@ -53,25 +72,526 @@ impl Beacon {
     }
 }
 
+/// Estimated time needed to ramp up the radio and process a transmission, per advertising channel.
+const TX_OVERHEAD: Duration = Duration::from_micros(250);
+
+/// Safety margin between the end of a beacon broadcast and the next Link-Layer radio event.
+const GUARD_TIME: Duration = Duration::from_micros(500);
+
+/// Time after the start of a Link-Layer radio event at which a postponed beacon is retried.
+///
+/// Connection events without much traffic are over by then.
+const RETRY_DELAY: Duration = Duration::from_micros(2_500);
+
+/// Shares the radio between a [`LinkLayer`] and a [`Beacon`].
+///
+/// This allows a device to broadcast a beacon (eg. with sensor data) while it is advertising or
+/// connected. The beacon is broadcast about every `interval` (plus the delay returned by
+/// [`Beacon::adv_delay`]), in the gaps between the Link-Layer's radio events: A broadcast is only
+/// started if it ends before the next connection or advertising event. Otherwise, it is postponed
+/// until after that event. If the Link-Layer leaves no gaps
+/// that are long enough (eg. with very short connection intervals), the beacon is not sent.
+///
+/// The `BeaconArbiter` shares the Link-Layer's timer and reconfigures the radio for the
+/// Link-Layer after broadcasting, so it has to see all timer events and all `Cmd`s returned by
+/// the Link-Layer:
+///
+/// * Call [`timer_update`] instead of `LinkLayer::update_timer` when the timer fires.
+/// * Pass the `Cmd`s returned by `LinkLayer::process_adv_packet` and
+///   `LinkLayer::process_data_packet` (or by the radio driver's interrupt handler) through
+///   [`track`] before applying them.
+///
+/// [`LinkLayer`]: ../link/struct.LinkLayer.html
+/// [`Beacon::adv_delay`]: struct.Beacon.html#method.adv_delay
+/// [`timer_update`]: #method.timer_update
+/// [`track`]: #method.track
+pub struct BeaconArbiter {
+    beacon: Beacon,
+    interval: Duration,
+    next_beacon: Instant,
+    /// Last radio configuration requested by the Link-Layer.
+    radio: RadioCmd,
+}
+
+impl BeaconArbiter {
+    /// Creates an arbiter broadcasting `beacon` about every `interval`, starting at `now`.
+    pub fn new(beacon: Beacon, interval: Duration, now: Instant) -> Self {
+        Self {
+            beacon,
+            interval,
+            next_beacon: now,
+            radio: RadioCmd::Off,
+        }
+    }
+
+    /// Replaces the broadcast beacon, eg. to update its data.
+    pub fn set_beacon(&mut self, beacon: Beacon) {
+        self.beacon = beacon;
+    }
+
+    /// Records the radio configuration requested by a `Cmd` returned from the Link-Layer and
+    /// schedules the shared timer.
+    ///
+    /// Returns the `Cmd` to apply, with `next_update` covering both the Link-Layer and the beacon.
+    pub fn track<C: Config>(&mut self, ll: &mut LinkLayer<C>, mut cmd: Cmd) -> Cmd {
+        self.radio = cmd.radio.clone();
+        cmd.next_update = self.schedule(ll);
+        cmd
+    }
+
+    /// Updates the Link-Layer or broadcasts the beacon, whichever is due.
+    ///
+    /// This must be called when the timer scheduled by the Link-Layer fires. Returns `None` if the
+    /// radio configuration doesn't need to change (because the beacon had to be postponed).
+    pub fn timer_update<C: Config>(
+        &mut self,
+        ll: &mut LinkLayer<C>,
+        tx: &mut C::Transmitter,
+    ) -> Option<Cmd> {
+        let now = ll.timer().now();
+
+        if ll.next_event().map_or(false, |at| is_due(now, at)) {
+            let cmd = ll.update_timer(tx);
+            return Some(self.track(ll, cmd));
+        }
+
+        if !is_due(now, self.next_beacon) {
+            // Spurious timer event
+            self.schedule(ll);
+            return None;
+        }
+
+        let end = now + self.event_duration() + GUARD_TIME;
+        match Self::next_activity(ll) {
+            Some(busy) if busy.checked_duration_since(end).is_none() => {
+                // Doesn't fit before the Link-Layer's next radio event, retry after it
+                self.next_beacon = busy + RETRY_DELAY;
+                self.schedule(ll);
+                None
+            }
+            _ => {
+                self.beacon.broadcast(tx);
+                let delay = self.interval + self.beacon.adv_delay();
+                self.next_beacon += delay;
+                if is_due(now, self.next_beacon) {
+                    // We fell behind by more than an interval, don't try to catch up
+                    self.next_beacon = now + delay;
+                }
+
+                // Go back to what the Link-Layer was doing
+                let cmd = Cmd {
+                    next_update: NextUpdate::Keep,
+                    radio: self.radio.clone(),
+                    queued_work: false,
+                    tx_space_freed: false,
+                };
+                Some(self.track(ll, cmd))
+            }
+        }
+    }
+
+    /// Schedules the timer for the next Link-Layer update or beacon broadcast.
+    fn schedule<C: Config>(&mut self, ll: &mut LinkLayer<C>) -> NextUpdate {
+        let next = match ll.next_event() {
+            Some(at) if is_due(self.next_beacon, at) => at,
+            _ => self.next_beacon,
+        };
+        ll.timer().schedule_at(next);
+        NextUpdate::At(next)
+    }
+
+    /// Returns the time at which the Link-Layer will next use the radio, if known.
+    fn next_activity<C: Config>(ll: &LinkLayer<C>) -> Option<Instant> {
+        let anchor = ll.connection().and_then(|conn| {
+            let info = conn.info();
+            info.next_event.map(|anchor| anchor - info.window_widening)
+        });
+        anchor.or_else(|| ll.next_event())
+    }
+
+    /// Returns the time needed to broadcast the beacon on all advertising channels.
+    fn event_duration(&self) -> Duration {
+        // Preamble, Access Address, header, payload and CRC at 1 µs per bit
+        let airtime = (1 + 4 + 2 + self.beacon.pdu.payload().len() as u32 + 3) * 8;
+        (TX_OVERHEAD + Duration::from_micros(airtime)).saturating_mul(3)
+    }
+}
+
+/// Returns whether `at` has been reached at `now`.
+fn is_due(now: Instant, at: Instant) -> bool {
+    now.checked_duration_since(at).is_some()
+}
+
+/// Time between the transmissions on the individual advertising channels of an advertising event.
+///
+/// The spec requires all PDUs of an advertising event to be sent within 10 ms.
+const CHANNEL_GAP: Duration = Duration::from_micros(1_000);
+
+/// A BLE beacon that schedules its own advertising events.
+///
+/// Unlike [`Beacon`], which sends out all 3 PDUs of an advertising event at once and leaves
+/// scheduling to the application, `BeaconAdvertiser` works like the [`LinkLayer`] and
+/// [`BeaconScanner`]: It returns [`Cmd`]s whose `next_update` tells the application when to call
+/// [`BeaconAdvertiser::timer_update`] again. Each call transmits the PDU on one advertising channel,
+/// so the beacon can be driven by the same timer interrupt used for the rest of the stack and each
+/// interrupt only performs a single transmission.
+///
+/// Like the Link-Layer's advertising events, the advertising events of the beacon are spaced by
+/// the interval plus a pseudo-random delay of up to `MAX_ADV_DELAY`.
+///
+/// [`LinkLayer`]: ../link/struct.LinkLayer.html
+pub struct BeaconAdvertiser {
+    pdu: PduBuf,
+    interval: Duration,
+    adv_delay: AdvDelay,
+    next_event: Instant,
+    channel: AdvertisingChannel,
+}
+
+impl BeaconAdvertiser {
+    /// Creates a new beacon advertiser that will broadcast `data` every `interval`.
+    ///
+    /// # Parameters
+    ///
+    /// * **`addr`**: Address of the beacon device.
+    /// * **`data`**: Data to broadcast. This must fit within a single PDU.
+    /// * **`interval`**: Time between the start of two advertising events, without the random
+    ///   delay.
+    ///
+    /// # Errors
+    ///
+    /// If `data` doesn't fit in a single PDU, or `interval` is not a valid advertising interval
+    /// (see `AdvertisingInterval::new`), an error will be returned.
+    pub fn new(
+        addr: DeviceAddress,
+        data: &[AdStructure<'_>],
+        interval: Duration,
+    ) -> Result<Self, Error> {
+        let pdu = PduBuf::beacon(addr, data)?;
+        Ok(Self {
+            pdu,
+            interval: AdvertisingInterval::new(interval)?.duration(),
+            adv_delay: AdvDelay::new(&addr),
+            next_event: Instant::from_raw_micros(0),
+            channel: AdvertisingChannel::first(),
+        })
+    }
+
+    /// Starts advertising and returns a `Cmd` to apply to the radio and timer.
+    ///
+    /// The first advertising event will be started at the next timer update, which is scheduled
+    /// immediately.
+    pub fn start(&mut self, now: Instant) -> Cmd {
+        self.next_event = now;
+        self.channel = AdvertisingChannel::first();
+
+        Cmd {
+            next_update: NextUpdate::At(now),
+            radio: RadioCmd::Off,
+            queued_work: false,
+            tx_space_freed: false,
+        }
+    }
+
+    /// Transmits the beacon on the next advertising channel.
+    ///
+    /// This must be called when the time specified by the `next_update` field of the last
+    /// returned `Cmd` is reached.
+    pub fn timer_update<T: Transmitter>(&mut self, now: Instant, tx: &mut T) -> Cmd {
+        if self.channel.channel() == AdvertisingChannel::first().channel() {
+            // Start of a new advertising event
+            self.next_event += self.interval + self.adv_delay.next();
+        }
+
+        let payload = self.pdu.payload();
+        let buf = tx.tx_payload_buf();
+        buf[..payload.len()].copy_from_slice(payload);
+        tx.transmit_advertising(self.pdu.header(), self.channel);
+
+        self.channel = self.channel.cycle();
+        let next_update = if self.channel.channel() == AdvertisingChannel::first().channel() {
+            // Sent on all channels, wait for the next advertising event
+            self.next_event
+        } else {
+            now + CHANNEL_GAP
+        };
+
+        Cmd {
+            next_update: NextUpdate::At(next_update),
+            radio: RadioCmd::Off,
+            queued_work: false,
+            tx_space_freed: false,
+        }
+    }
+}
+
+/// Time the radio listens for a scan request after sending an advertisement on the last channel.
+///
+/// A scan request starts `T_IFS` after the advertisement and takes 176 µs to transmit.
+const SCAN_REQ_WINDOW: Duration = Duration::from_micros(500);
+
+/// A beacon that sends additional data to active scanners.
+///
+/// Like [`BeaconAdvertiser`], a `ScannableBeacon` is driven by [`Cmd`]s and sends its
+/// advertisement on one advertising channel per [`timer_update`]. The advertisement is a scannable
+/// undirected advertisement (`ADV_SCAN_IND`), which can't be connected to. After each
+/// transmission, the returned `Cmd` makes the radio listen on the same channel for a scan request
+/// addressed to the beacon, which is answered with the scan response data. This allows keeping
+/// the advertisement small and only sending more data on demand.
+///
+/// All advertising channel packets received by the radio must be passed to
+/// [`process_adv_packet`].
+///
+/// Like the Link-Layer's advertising events, the advertising events of the beacon are spaced by
+/// the interval plus a pseudo-random delay of up to `MAX_ADV_DELAY`.
+///
+/// [`BeaconAdvertiser`]: struct.BeaconAdvertiser.html
+/// [`Cmd`]: ../link/struct.Cmd.html
+/// [`timer_update`]: #method.timer_update
+/// [`process_adv_packet`]: #method.process_adv_packet
+pub struct ScannableBeacon {
+    addr: DeviceAddress,
+    pdu: PduBuf,
+    scan_rsp: PduBuf,
+    interval: Duration,
+    adv_delay: AdvDelay,
+    next_event: Instant,
+    /// Channel to transmit on next.
+    channel: AdvertisingChannel,
+    /// Channel on which the radio listens for scan requests, if any.
+    listening: Option<AdvertisingChannel>,
+}
+
+impl ScannableBeacon {
+    /// Creates a new scannable beacon that will broadcast `data` every `interval`.
+    ///
+    /// # Parameters
+    ///
+    /// * **`addr`**: Address of the beacon device.
+    /// * **`data`**: Data to broadcast. This must fit within a single PDU.
+    /// * **`scan_data`**: Data to send in response to scan requests. This must fit within a single
+    ///   PDU.
+    /// * **`interval`**: Time between the start of two advertising events, without the random
+    ///   delay.
+    ///
+    /// # Errors
+    ///
+    /// If `data` or `scan_data` doesn't fit in a single PDU, or `interval` is not a valid
+    /// advertising interval (see `AdvertisingInterval::new`), an error will be returned.
+    pub fn new(
+        addr: DeviceAddress,
+        data: &[AdStructure<'_>],
+        scan_data: &[AdStructure<'_>],
+        interval: Duration,
+    ) -> Result<Self, Error> {
+        Ok(Self {
+            addr,
+            pdu: PduBuf::scannable_undirected(addr, data)?,
+            scan_rsp: PduBuf::scan_response(addr, scan_data)?,
+            interval: AdvertisingInterval::new(interval)?.duration(),
+            adv_delay: AdvDelay::new(&addr),
+            next_event: Instant::from_raw_micros(0),
+            channel: AdvertisingChannel::first(),
+            listening: None,
+        })
+    }
+
+    /// Starts advertising and returns a `Cmd` to apply to the radio and timer.
+    ///
+    /// The first advertising event will be started at the next timer update, which is scheduled
+    /// immediately.
+    pub fn start(&mut self, now: Instant) -> Cmd {
+        self.next_event = now;
+        self.channel = AdvertisingChannel::first();
+        self.listening = None;
+        self.cmd(NextUpdate::At(now))
+    }
+
+    /// Transmits the beacon on the next advertising channel and listens for scan requests.
+    ///
+    /// This must be called when the time specified by the `next_update` field of the last
+    /// returned `Cmd` is reached.
+    pub fn timer_update<T: Transmitter>(&mut self, now: Instant, tx: &mut T) -> Cmd {
+        let first = AdvertisingChannel::first().channel();
+        if self.channel.channel() == first {
+            if self.listening.take().is_some() {
+                // Sent on all channels, stop listening until the next advertising event
+                return self.cmd(NextUpdate::At(self.next_event));
+            }
+
+            // Start of a new advertising event
+            self.next_event += self.interval + self.adv_delay.next();
+        }
+
+        let payload = self.pdu.payload();
+        let buf = tx.tx_payload_buf();
+        buf[..payload.len()].copy_from_slice(payload);
+        tx.transmit_advertising(self.pdu.header(), self.channel);
+
+        self.listening = Some(self.channel);
+        self.channel = self.channel.cycle();
+        let next_update = if self.channel.channel() == first {
+            now + SCAN_REQ_WINDOW
+        } else {
+            now + CHANNEL_GAP
+        };
+        self.cmd(NextUpdate::At(next_update))
+    }
+
+    /// Processes a received advertising channel packet.
+    ///
+    /// If the packet is a scan request addressed to this beacon, the scan response is sent and the
+    /// radio is switched off until the next transmission.
+    pub fn process_adv_packet<T: Transmitter>(
+        &mut self,
+        tx: &mut T,
+        header: Header,
+        payload: &[u8],
+        crc_ok: bool,
+    ) -> Cmd {
+        let channel = match self.listening {
+            Some(channel) if crc_ok && header.type_() == PduType::ScanReq => channel,
+            _ => return self.cmd(NextUpdate::Keep),
+        };
+
+        if let Ok(Pdu::ScanRequest {
+            advertiser_addr, ..
+        }) = Pdu::from_header_and_payload(header, &mut ByteReader::new(payload))
+        {
+            if advertiser_addr == self.addr {
+                let payload = self.scan_rsp.payload();
+                tx.tx_payload_buf()[..payload.len()].copy_from_slice(payload);
+                tx.transmit_advertising(self.scan_rsp.header(), channel);
+
+                return Cmd {
+                    next_update: NextUpdate::Keep,
+                    radio: RadioCmd::Off,
+                    queued_work: false,
+                    tx_space_freed: false,
+                };
+            }
+        }
+
+        self.cmd(NextUpdate::Keep)
+    }
+
+    /// Returns a `Cmd` that configures the radio for the current state of the beacon.
+    fn cmd(&self, next_update: NextUpdate) -> Cmd {
+        let radio = match self.listening {
+            Some(channel) => RadioCmd::ListenAdvertising {
+                channel,
+                peer_filter: None,
+            },
+            None => RadioCmd::Off,
+        };
+
+        Cmd {
+            next_update,
+            radio,
+            queued_work: false,
+            tx_space_freed: false,
+        }
+    }
+}
+
 /// Callback for the [`BeaconScanner`].
 pub trait ScanCallback {
-    /// Called when a beacon is received and has passed the configured device address filter.
+    /// Called when a beacon is received and has passed the configured device address and
+    /// advertising data filters.
     ///
     /// # Parameters
     ///
     /// * **`adv_addr`**: Address of the device sending the beacon.
-    /// * **`adv_data`**: Advertising data structures attached to the beacon.
+    /// * **`adv_data`**: Advertising data structures attached to the beacon. If the advertising
+    ///   data is malformed, only the structures preceding the malformed one are included.
     fn beacon<'a, I>(&mut self, adv_addr: DeviceAddress, adv_data: I)
     where
         I: Iterator<Item = AdStructure<'a>>;
 }
 
+/// Number of recently received beacons remembered by the duplicate filter of a [`BeaconScanner`].
+///
+/// [`BeaconScanner`]: struct.BeaconScanner.html
+pub const DUPLICATE_FILTER_LEN: usize = 16;
+
+/// Remembers the most recently reported beacons, to suppress repeated reports of the same data.
+///
+/// Beacons are identified by a hash of their header and payload, which contains both the
+/// advertiser address and the advertising data. When the list is full, the least recently seen
+/// beacon is forgotten.
+struct DuplicateFilter {
+    /// Hashes of recently seen beacons, the most recently seen one last.
+    seen: Vec<u32, DUPLICATE_FILTER_LEN>,
+}
+
+impl DuplicateFilter {
+    fn new() -> Self {
+        Self { seen: Vec::new() }
+    }
+
+    fn clear(&mut self) {
+        self.seen.clear();
+    }
+
+    /// Records a received beacon and returns whether it has been seen recently.
+    fn check(&mut self, header: Header, payload: &[u8]) -> bool {
+        // FNV-1a
+        let hash = header
+            .to_u16()
+            .to_le_bytes()
+            .iter()
+            .chain(payload)
+            .fold(0x811c_9dc5_u32, |hash, b| {
+                (hash ^ u32::from(*b)).wrapping_mul(0x0100_0193)
+            });
+
+        let duplicate = match self.seen.iter().position(|seen| *seen == hash) {
+            Some(i) => {
+                self.seen.remove(i);
+                true
+            }
+            None => {
+                if self.seen.is_full() {
+                    self.seen.remove(0);
+                }
+                false
+            }
+        };
+        self.seen.push(hash).ok();
+        duplicate
+    }
+}
+
 /// A passive scanner for non-connectable beacon advertisements.
-pub struct BeaconScanner<C: ScanCallback, F: AddressFilter> {
+///
+/// Like a BLE controller, the scanner listens on one advertising channel at a time, and switches
+/// to the next channel every *scan interval*. By default, it listens continuously. To save power,
+/// [`configure_window`] can be used to only listen during the first part (the *scan window*) of
+/// each interval, and to switch the radio off for the rest of it.
+///
+/// Beacons usually broadcast the same data over and over. If duplicate filtering is enabled (via
+/// [`set_filter_duplicates`]), a beacon is only reported again if its address or data changed, or
+/// if it hasn't been received among the last [`DUPLICATE_FILTER_LEN`] different beacons.
+///
+/// [`configure_window`]: #method.configure_window
+/// [`set_filter_duplicates`]: #method.set_filter_duplicates
+/// [`DUPLICATE_FILTER_LEN`]: constant.DUPLICATE_FILTER_LEN.html
+pub struct BeaconScanner<C: ScanCallback, F: AddressFilter, A: AdFilter = filter::AllowAll> {
     cb: C,
-    filter: ScanFilter<F>,
+    filter: ScanFilter<F, A>,
     interval: Duration,
+    window: Duration,
     channel: AdvertisingChannel,
+    /// Whether scanning is enabled.
+    scanning: bool,
+    /// Whether the scan window of the current scan interval is still open.
+    listening: bool,
+    /// Start of the current scan interval.
+    interval_start: Instant,
+    filter_duplicates: bool,
+    duplicates: DuplicateFilter,
+    /// Number of received beacons with malformed advertising data.
+    malformed: u32,
 }
 
 impl<C: ScanCallback> BeaconScanner<C, filter::AllowAll> {
@@ -84,51 +604,125 @@ impl<C: ScanCallback> BeaconScanner<C, filter::AllowAll> {
 impl<C: ScanCallback, F: AddressFilter> BeaconScanner<C, F> {
     /// Creates a `BeaconScanner` with a custom device filter.
     pub fn with_filter(callback: C, scan_filter: F) -> Self {
+        Self::with_filters(callback, scan_filter, filter::AllowAll)
+    }
+}
+
+impl<C: ScanCallback, F: AddressFilter, A: AdFilter> BeaconScanner<C, F, A> {
+    /// Creates a `BeaconScanner` with a custom device filter and advertising data filter.
+    ///
+    /// Only beacons sent by a device matched by `scan_filter` *and* carrying advertising data
+    /// matched by `ad_filter` will be passed to the callback.
+    pub fn with_filters(callback: C, scan_filter: F, ad_filter: A) -> Self {
         Self {
             cb: callback,
-            filter: ScanFilter::new(scan_filter),
+            filter: ScanFilter::with_ad_filter(scan_filter, ad_filter),
             interval: Duration::from_micros(0),
+            window: Duration::from_micros(0),
             channel: AdvertisingChannel::first(),
+            scanning: false,
+            listening: false,
+            interval_start: Instant::from_raw_micros(0),
+            filter_duplicates: false,
+            duplicates: DuplicateFilter::new(),
+            malformed: 0,
         }
     }
 
-    /// Configures the `BeaconScanner` and returns a `Cmd` to apply to the radio.
+    /// Enables or disables the suppression of duplicate reports.
+    ///
+    /// Enabling the filter clears the list of beacons that were seen already, so that all beacons
+    /// in range are reported once.
+    pub fn set_filter_duplicates(&mut self, enabled: bool) {
+        self.filter_duplicates = enabled;
+        self.duplicates.clear();
+    }
+
+    /// Returns whether the scanner is enabled.
+    pub fn is_scanning(&self) -> bool {
+        self.scanning
+    }
+
+    /// Returns the number of received beacons whose advertising data contained a malformed AD
+    /// structure.
+    ///
+    /// Such beacons are still filtered and reported, with the AD structures preceding the malformed
+    /// one. The counter wraps around on overflow.
+    pub fn malformed_beacons(&self) -> u32 {
+        self.malformed
+    }
+
+    /// Starts scanning continuously and returns a `Cmd` to apply to the radio.
     ///
     /// The `next_update` field of the returned `Cmd` specifies when to call `timer_update` the next
     /// time. The timer used for this does not have to be very accurate, it is only used to switch
     /// to the next advertising channel after `interval` elapses.
     pub fn configure(&mut self, now: Instant, interval: Duration) -> Cmd {
-        self.interval = interval;
-        self.channel = AdvertisingChannel::first();
+        self.start(now, interval, interval)
+    }
 
-        Cmd {
-            // Switch channels
-            next_update: NextUpdate::At(now + self.interval),
+    /// Starts scanning for `window` out of every `interval` and returns a `Cmd` to apply to the
+    /// radio.
+    ///
+    /// The scanner switches to the next advertising channel at the start of each interval, listens
+    /// on it for `window`, and switches the radio off until the interval ends. `timer_update` has
+    /// to be called at the times requested by the returned `Cmd`s, both to open and close the
+    /// window.
+    ///
+    /// Returns `Error::InvalidValue` if `window` is zero or longer than `interval`.
+    pub fn configure_window(
+        &mut self,
+        now: Instant,
+        interval: Duration,
+        window: Duration,
+    ) -> Result<Cmd, Error> {
+        if window == Duration::from_micros(0) || window > interval {
+            return Err(Error::InvalidValue);
+        }
+        Ok(self.start(now, interval, window))
+    }
 
-            radio: RadioCmd::ListenAdvertising {
-                channel: self.channel,
-            },
+    /// Stops scanning and returns a `Cmd` that switches the radio off.
+    ///
+    /// Scanning can be resumed with `configure` or `configure_window`.
+    pub fn stop(&mut self) -> Cmd {
+        self.scanning = false;
+        self.listening = false;
+        self.cmd(NextUpdate::Disable)
+    }
 
-            queued_work: false,
-        }
+    fn start(&mut self, now: Instant, interval: Duration, window: Duration) -> Cmd {
+        self.interval = interval;
+        self.window = window;
+        self.channel = AdvertisingChannel::first();
+        self.scanning = true;
+        self.listening = true;
+        self.interval_start = now;
+        self.duplicates.clear();
+
+        self.cmd(NextUpdate::At(now + self.window))
     }
 
     /// Updates the `BeaconScanner` after the configured timer has fired.
     ///
-    /// This switches to the next advertising channel and will listen there.
+    /// At the start of a scan interval, this switches to the next advertising channel and will
+    /// listen there. At the end of a scan window, this switches the radio off.
     pub fn timer_update(&mut self, now: Instant) -> Cmd {
-        self.channel = self.channel.cycle();
-
-        Cmd {
-            // Switch channels
-            next_update: NextUpdate::At(now + self.interval),
-
-            radio: RadioCmd::ListenAdvertising {
-                channel: self.channel,
-            },
+        if !self.scanning {
+            return self.cmd(NextUpdate::Disable);
+        }
 
-            queued_work: false,
+        if self.listening && self.window < self.interval {
+            // Close the scan window
+            self.listening = false;
+            return self.cmd(NextUpdate::At(self.interval_start + self.interval));
         }
+
+        // Start of the next scan interval
+        self.interval_start = now;
+        self.listening = true;
+        self.channel = self.channel.cycle();
+        self.cmd(NextUpdate::At(now + self.window))
     }
 
     /// Processes a received advertising channel packet.
@@ -136,22 +730,337 @@ impl<C: ScanCallback, F: AddressFilter> BeaconScanner<C, F> {
     /// This should be called whenever the radio receives a packet on the configured advertising
     /// channel.
     pub fn process_adv_packet(&mut self, header: Header, payload: &[u8], crc_ok: bool) -> Cmd {
-        if crc_ok && header.type_().is_beacon() {
+        if self.listening && crc_ok && header.type_().is_beacon() {
             // Partially decode to get the device ID and run it through the filter
             if let Ok(pdu) = Pdu::from_header_and_payload(header, &mut ByteReader::new(payload)) {
-                if self.filter.should_scan(*pdu.sender()) {
-                    let ad = pdu.advertising_data().unwrap();
-                    self.cb.beacon(*pdu.sender(), ad);
+                if pdu.advertising_data().unwrap().any(|ad| ad.is_err()) {
+                    self.malformed = self.malformed.wrapping_add(1);
+                }
+
+                // Iteration ends at the first malformed structure
+                let valid_ads = || pdu.advertising_data().unwrap().filter_map(Result::ok);
+                if self.filter.should_scan(*pdu.sender())
+                    && self.filter.should_report(valid_ads())
+                    && !(self.filter_duplicates && self.duplicates.check(header, payload))
+                {
+                    self.cb.beacon(*pdu.sender(), valid_ads());
                 }
             }
         }
 
-        Cmd {
-            next_update: NextUpdate::Keep,
-            radio: RadioCmd::ListenAdvertising {
+        self.cmd(NextUpdate::Keep)
+    }
+
+    /// Returns a `Cmd` that configures the radio for the current state of the scanner.
+    fn cmd(&self, next_update: NextUpdate) -> Cmd {
+        let radio = if self.listening {
+            RadioCmd::ListenAdvertising {
                 channel: self.channel,
-            },
+                peer_filter: None,
+            }
+        } else {
+            RadioCmd::Off
+        };
+
+        Cmd {
+            next_update,
+            radio,
             queued_work: false,
+            tx_space_freed: false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::link::advertising::MAX_ADV_DELAY;
+    use crate::link::{data, AddressKind};
+    use crate::phy::DataChannel;
+
+    struct Count(u32);
+
+    impl ScanCallback for Count {
+        fn beacon<'a, I>(&mut self, _adv_addr: DeviceAddress, _adv_data: I)
+        where
+            I: Iterator<Item = AdStructure<'a>>,
+        {
+            self.0 += 1;
         }
     }
+
+    fn at(millis: u32) -> Instant {
+        Instant::from_raw_micros(millis * 1_000)
+    }
+
+    fn next_update(cmd: &Cmd) -> Option<u32> {
+        match cmd.next_update {
+            NextUpdate::At(at) => Some(at.raw_micros()),
+            _ => None,
+        }
+    }
+
+    /// Records the types of transmitted advertising PDUs and their channels.
+    struct Recorder {
+        buf: [u8; 37],
+        sent: std::vec::Vec<(PduType, u8)>,
+    }
+
+    impl Recorder {
+        fn new() -> Self {
+            Self {
+                buf: [0; 37],
+                sent: std::vec::Vec::new(),
+            }
+        }
+    }
+
+    impl Transmitter for Recorder {
+        fn tx_payload_buf(&mut self) -> &mut [u8] {
+            &mut self.buf
+        }
+
+        fn transmit_advertising(&mut self, header: Header, channel: AdvertisingChannel) {
+            self.sent.push((header.type_(), channel.channel()));
+        }
+
+        fn transmit_data(&mut self, _: u32, _: u32, _: data::Header, _: DataChannel) {
+            unreachable!()
+        }
+
+        fn set_tx_power(&mut self, dbm: i8) -> i8 {
+            dbm
+        }
+    }
+
+    #[test]
+    fn beacon_adv_delay() {
+        let addr = DeviceAddress::new([1, 0, 0, 0, 0, 0xC0], AddressKind::Random);
+        let mut beacon = Beacon::new(addr, &[AdStructure::CompleteLocalName("a")]).unwrap();
+        let mut delay = AdvDelay::new(&addr);
+        for _ in 0..10 {
+            let next = beacon.adv_delay();
+            assert_eq!(next, delay.next());
+            assert!(next <= MAX_ADV_DELAY);
+        }
+        assert_ne!(beacon.adv_delay(), beacon.adv_delay());
+
+        let mut tx = Recorder::new();
+        beacon.broadcast(&mut tx);
+        assert_eq!(
+            tx.sent,
+            [
+                (PduType::AdvNonconnInd, 37),
+                (PduType::AdvNonconnInd, 38),
+                (PduType::AdvNonconnInd, 39),
+            ]
+        );
+    }
+
+    #[test]
+    fn beacon_advertiser() {
+        let addr = DeviceAddress::new([1, 0, 0, 0, 0, 0xC0], AddressKind::Random);
+        let name = [AdStructure::CompleteLocalName("a")];
+        assert_eq!(
+            BeaconAdvertiser::new(addr, &name, Duration::from_micros(100_100)).err(),
+            Some(Error::InvalidValue)
+        );
+        let mut beacon = BeaconAdvertiser::new(addr, &name, Duration::from_millis(100)).unwrap();
+        let mut delay = AdvDelay::new(&addr);
+        let mut tx = Recorder::new();
+
+        assert_eq!(next_update(&beacon.start(at(0))), Some(0));
+        let mut event = at(0);
+        for _ in 0..3 {
+            // One PDU per channel, 1 ms apart
+            let first = tx.sent.len();
+            let mut now = event;
+            for channel in 37..=39 {
+                let cmd = beacon.timer_update(now, &mut tx);
+                assert!(matches!(cmd.radio, RadioCmd::Off));
+                assert_eq!(
+                    tx.sent[first..].last(),
+                    Some(&(PduType::AdvNonconnInd, channel))
+                );
+                if channel < 39 {
+                    now += CHANNEL_GAP;
+                    assert_eq!(next_update(&cmd), Some(now.raw_micros()));
+                } else {
+                    // The next event starts after the interval plus a random delay
+                    event = event + Duration::from_millis(100) + delay.next();
+                    assert_eq!(next_update(&cmd), Some(event.raw_micros()));
+                }
+            }
+            assert_eq!(tx.sent.len(), first + 3);
+        }
+    }
+
+    fn receive(scanner: &mut BeaconScanner<Count, filter::AllowAll>, addr: u8, name: &str) {
+        let addr = DeviceAddress::new([addr, 0, 0, 0, 0, 0xC0], AddressKind::Random);
+        let pdu = PduBuf::beacon(addr, &[AdStructure::CompleteLocalName(name)]).unwrap();
+        let _ = scanner.process_adv_packet(pdu.header(), pdu.payload(), true);
+    }
+
+    #[test]
+    fn filter_duplicates() {
+        let mut scanner = BeaconScanner::new(Count(0));
+        let _ = scanner.configure(at(0), Duration::from_millis(100));
+        receive(&mut scanner, 1, "a");
+        receive(&mut scanner, 1, "a");
+        assert_eq!(scanner.cb.0, 2);
+
+        scanner.set_filter_duplicates(true);
+        receive(&mut scanner, 1, "a");
+        receive(&mut scanner, 1, "a");
+        receive(&mut scanner, 1, "b");
+        receive(&mut scanner, 2, "b");
+        assert_eq!(scanner.cb.0, 5);
+
+        // The least recently seen beacon is forgotten first
+        for addr in 3..3 + DUPLICATE_FILTER_LEN as u8 - 3 {
+            receive(&mut scanner, addr, "a");
+        }
+        receive(&mut scanner, 1, "a");
+        receive(&mut scanner, 20, "a");
+        receive(&mut scanner, 1, "b");
+        assert_eq!(scanner.cb.0, 5 + DUPLICATE_FILTER_LEN as u32 - 3 + 2);
+    }
+
+    #[test]
+    fn malformed_beacon() {
+        let mut scanner = BeaconScanner::new(Count(0));
+        let _ = scanner.configure(at(0), Duration::from_millis(100));
+
+        // Flags, followed by a structure whose length exceeds the PDU
+        let payload = [1, 2, 3, 4, 5, 6, 0x02, 0x01, 0x06, 0x1F, 0x09, b'a'];
+        let mut header = Header::new(PduType::AdvNonconnInd);
+        header.set_payload_length(payload.len() as u8);
+        let _ = scanner.process_adv_packet(header, &payload, true);
+        assert_eq!(scanner.cb.0, 1);
+        assert_eq!(scanner.malformed_beacons(), 1);
+
+        receive(&mut scanner, 1, "a");
+        assert_eq!(scanner.cb.0, 2);
+        assert_eq!(scanner.malformed_beacons(), 1);
+    }
+
+    fn scan_request(scanner: DeviceAddress, advertiser: DeviceAddress) -> (Header, [u8; 12]) {
+        let mut payload = [0; 12];
+        payload[..6].copy_from_slice(scanner.raw());
+        payload[6..].copy_from_slice(advertiser.raw());
+        let mut header = Header::new(PduType::ScanReq);
+        header.set_payload_length(12);
+        header.set_rx_add(advertiser.is_random());
+        (header, payload)
+    }
+
+    #[test]
+    fn scannable_beacon() {
+        let addr = DeviceAddress::new([1, 0, 0, 0, 0, 0xC0], AddressKind::Random);
+        let scanner = DeviceAddress::new([2, 0, 0, 0, 0, 0], AddressKind::Public);
+        let name = [AdStructure::CompleteLocalName("a")];
+        assert_eq!(
+            ScannableBeacon::new(addr, &name, &[], Duration::from_millis(10)).err(),
+            Some(Error::InvalidValue)
+        );
+        let mut beacon = ScannableBeacon::new(
+            addr,
+            &name,
+            &[AdStructure::CompleteLocalName("more data")],
+            Duration::from_millis(100),
+        )
+        .unwrap();
+        let mut delay = AdvDelay::new(&addr);
+        let mut tx = Recorder::new();
+
+        assert_eq!(state(&beacon.start(at(0))), (None, Some(0)));
+        assert_eq!(
+            state(&beacon.timer_update(at(0), &mut tx)),
+            (Some(37), Some(1))
+        );
+
+        // Scan requests for other devices are ignored
+        let (header, payload) = scan_request(scanner, scanner);
+        let cmd = beacon.process_adv_packet(&mut tx, header, &payload, true);
+        assert_eq!(state(&cmd), (Some(37), None));
+        assert_eq!(tx.sent, [(PduType::AdvScanInd, 37)]);
+
+        let (header, payload) = scan_request(scanner, addr);
+        let cmd = beacon.process_adv_packet(&mut tx, header, &payload, false);
+        assert_eq!(state(&cmd), (Some(37), None));
+        let cmd = beacon.process_adv_packet(&mut tx, header, &payload, true);
+        assert_eq!(state(&cmd), (None, None));
+        assert_eq!(tx.sent[1], (PduType::ScanRsp, 37));
+        assert_eq!(&tx.buf[6..8], &[10, 0x09]);
+
+        assert_eq!(
+            state(&beacon.timer_update(at(1), &mut tx)),
+            (Some(38), Some(2))
+        );
+        assert_eq!(
+            state(&beacon.timer_update(at(2), &mut tx)),
+            (Some(39), Some(2))
+        );
+        // Stop listening after the last channel until the next event, which is delayed randomly
+        let next = at(100) + delay.next();
+        let cmd = beacon.timer_update(at(2), &mut tx);
+        assert_eq!(
+            (state(&cmd).0, next_update(&cmd)),
+            (None, Some(next.raw_micros()))
+        );
+        assert_eq!(tx.sent.len(), 4);
+        let cmd = beacon.timer_update(next, &mut tx);
+        assert_eq!(state(&cmd).0, Some(37));
+        assert_eq!(tx.sent.len(), 5);
+
+        for _ in 0..2 {
+            let _ = beacon.timer_update(next, &mut tx);
+        }
+        let cmd = beacon.timer_update(next, &mut tx);
+        let after = next + Duration::from_millis(100) + delay.next();
+        assert_eq!(next_update(&cmd), Some(after.raw_micros()));
+    }
+
+    /// Returns the channel the radio listens on and the time of the next update in ms.
+    fn state(cmd: &Cmd) -> (Option<u8>, Option<u32>) {
+        let channel = match &cmd.radio {
+            RadioCmd::ListenAdvertising { channel, .. } => Some(channel.channel()),
+            _ => None,
+        };
+        let next_update = match cmd.next_update {
+            NextUpdate::At(at) => Some(at.raw_micros() / 1_000),
+            _ => None,
+        };
+        (channel, next_update)
+    }
+
+    #[test]
+    fn scan_window() {
+        let interval = Duration::from_millis(100);
+        let mut scanner = BeaconScanner::new(Count(0));
+        assert!(scanner
+            .configure_window(at(0), interval, Duration::from_millis(101))
+            .is_err());
+
+        let cmd = scanner
+            .configure_window(at(0), interval, Duration::from_millis(30))
+            .unwrap();
+        assert_eq!(state(&cmd), (Some(37), Some(30)));
+        receive(&mut scanner, 1, "a");
+
+        // Window closes
+        assert_eq!(state(&scanner.timer_update(at(30))), (None, Some(100)));
+        receive(&mut scanner, 1, "a");
+        assert_eq!(scanner.cb.0, 1);
+
+        // Next interval listens on the next channel
+        assert_eq!(state(&scanner.timer_update(at(100))), (Some(38), Some(130)));
+        receive(&mut scanner, 1, "a");
+        assert_eq!(scanner.cb.0, 2);
+
+        assert_eq!(state(&scanner.stop()), (None, None));
+        assert!(!scanner.is_scanning());
+        receive(&mut scanner, 1, "a");
+        assert_eq!(scanner.cb.0, 2);
+    }
 }
@@ -101,6 +101,38 @@ impl<'a, T: fmt::Debug + FromBytes<'a> + Copy> fmt::Debug for BytesOr<'a, [T]> {
     }
 }
 
+impl<'a, T: defmt::Format + FromBytes<'a> + Copy> defmt::Format for BytesOr<'a, T> {
+    fn format(&self, f: defmt::Formatter<'_>) {
+        self.read().format(f);
+    }
+}
+
+/// Slices that still reside in their encoded form are logged as raw bytes, since `defmt` needs to
+/// know the number of elements up front.
+impl<'a, T: defmt::Format + FromBytes<'a> + Copy> defmt::Format for BytesOr<'a, [T]> {
+    fn format(&self, f: defmt::Formatter<'_>) {
+        match self.0 {
+            Inner::Bytes(b) => defmt::write!(f, "{=[u8]:x}", b),
+            Inner::Or(slice) => slice.format(f),
+        }
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'a, T: serde::Serialize + FromBytes<'a> + Copy> serde::Serialize for BytesOr<'a, T> {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.read().serialize(serializer)
+    }
+}
+
+/// Slices are always serialized as a sequence of their (decoded) elements.
+#[cfg(feature = "serde")]
+impl<'a, T: serde::Serialize + FromBytes<'a> + Copy> serde::Serialize for BytesOr<'a, [T]> {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(self.iter())
+    }
+}
+
 impl<'a, T: ?Sized> BytesOr<'a, T> {
     /// Creates a `BytesOr` that holds on to a `T` via reference.
     ///
@@ -113,6 +145,18 @@ impl<'a, T: ?Sized> BytesOr<'a, T> {
     }
 }
 
+impl<'a, T> BytesOr<'a, [T]> {
+    /// Creates a `BytesOr` that stores `bytes` without checking that they can be decoded.
+    ///
+    /// This is used for data received over the air, where a malformed element should not cause
+    /// the preceding ones to be discarded. Decoding errors are reported by [`try_iter`].
+    ///
+    /// [`try_iter`]: #method.try_iter
+    pub(crate) fn unchecked(bytes: &'a [u8]) -> Self {
+        BytesOr(Inner::Bytes(bytes))
+    }
+}
+
 /// Creates a `BytesOr` that stores bytes that can be decoded to a `T`.
 ///
 /// This will check that `bytes` can indeed be decoded as a `T` using its [`FromBytes`]
@@ -194,10 +238,20 @@ impl<'a, T: Copy + FromBytes<'a>> BytesOr<'a, T> {
 impl<'a, T: Copy + FromBytes<'a>> BytesOr<'a, [T]> {
     /// Returns an iterator over all `T`s stored in `self`.
     ///
-    /// The iterator will copy or decode `T`s out of `self`.
+    /// The iterator will copy or decode `T`s out of `self`. If a `T` can not be decoded, iteration
+    /// ends.
     pub fn iter(&self) -> impl Iterator<Item = T> + 'a {
         IterBytesOr { inner: *self }
     }
+
+    /// Returns an iterator over all `T`s stored in `self` that also reports decoding errors.
+    ///
+    /// If a `T` can not be decoded, the error is yielded and iteration ends, since the following
+    /// `T`s can't be located anymore.
+    pub fn try_iter(&self) -> impl Iterator<Item = Result<T, Error>> + 'a {
+        let mut iter = IterBytesOr { inner: *self };
+        iter::from_fn(move || iter.try_next())
+    }
 }
 
 /// An iterator over values stored in a `BytesOr`.
@@ -205,10 +259,8 @@ struct IterBytesOr<'a, T> {
     inner: BytesOr<'a, [T]>,
 }
 
-impl<'a, T: Copy + FromBytes<'a>> Iterator for IterBytesOr<'a, T> {
-    type Item = T;
-
-    fn next(&mut self) -> Option<Self::Item> {
+impl<'a, T: Copy + FromBytes<'a>> IterBytesOr<'a, T> {
+    fn try_next(&mut self) -> Option<Result<T, Error>> {
         match &mut self.inner.0 {
             Inner::Bytes(b) => {
                 if b.is_empty() {
@@ -216,20 +268,36 @@ impl<'a, T: Copy + FromBytes<'a>> Iterator for IterBytesOr<'a, T> {
                 } else {
                     // Read a `T` and overwrite our `b` with the left-over data
                     let mut reader = ByteReader::new(*b);
-                    let t = T::from_bytes(&mut reader).unwrap();
-                    *b = reader.into_rest();
-                    Some(t)
+                    match T::from_bytes(&mut reader) {
+                        Ok(t) => {
+                            *b = reader.into_rest();
+                            Some(Ok(t))
+                        }
+                        Err(e) => {
+                            // The rest can't be decoded either, so end the iteration
+                            *b = &[];
+                            Some(Err(e))
+                        }
+                    }
                 }
             }
             Inner::Or(slice) => {
                 let (first, rest) = slice.split_first()?;
                 *slice = rest;
-                Some(*first)
+                Some(Ok(*first))
             }
         }
     }
 }
 
+impl<'a, T: Copy + FromBytes<'a>> Iterator for IterBytesOr<'a, T> {
+    type Item = T;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.try_next()?.ok()
+    }
+}
+
 /// Wrapper around a byte slice that can be used to encode data into bytes.
 ///
 /// All `write_*` methods on this type will return `Error::Eof` when the underlying buffer slice is
@@ -632,6 +700,17 @@ where
     }
 }
 
+#[cfg(feature = "serde")]
+impl<PRIM, T> serde::Serialize for Field<PRIM, T>
+where
+    PRIM: zerocopy::FromBytes + Copy,
+    T: RawRepr<PRIM> + serde::Serialize,
+{
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.value().serialize(serializer)
+    }
+}
+
 /// Indicates that a type can be cheaply converted from and to a raw representation of type `T`.
 ///
 /// This is meant to be used with [`Field`], and allows representing types as their raw contents in
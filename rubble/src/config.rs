@@ -1,7 +1,12 @@
 //! Stack configuration trait.
 
-use crate::link::{queue::PacketQueue, Transmitter};
-use crate::{l2cap::ChannelMapper, time::Timer};
+use crate::l2cap::ChannelMapper;
+use crate::link::{capture::CapturedPdu, queue::PacketQueue, CompanyId, FeatureSet, Transmitter};
+use crate::link::{MAX_DATA_PAYLOAD_BUF, MIN_DATA_PAYLOAD_BUF};
+use crate::log::Logger;
+use crate::time::{Instant, Timer};
+use core::marker::PhantomData;
+use rand_core::{CryptoRng, RngCore};
 
 // TODO: Use associated type defaults in the trait once stable
 // https://github.com/rust-lang/rust/issues/29661
@@ -26,7 +31,89 @@ pub trait Config {
 
     /// The packet queue to use for exchanging data between the real-time Link-Layer and
     /// non-realtime parts of the stack.
+    ///
+    /// `&'static mut SimpleQueue` is the smallest option, while `&'static mut RingQueue<N>` can
+    /// buffer several packets and achieves better throughput.
+    ///
+    /// The queue's `PacketQueue::MAX_PAYLOAD` determines the largest data channel PDUs the stack
+    /// can exchange. Configurations using the Data Length Extension need larger slots, eg.
+    /// `RingQueue<N, MAX_DATA_PDU_BUF>`.
     type PacketQueue: PacketQueue;
+
+    /// A cryptographically secure random number generator.
+    ///
+    /// This is used by the security subsystem for pairing and key generation, and for generating
+    /// random device addresses. Since the security of the connection depends on it, this should
+    /// be backed by a hardware entropy source.
+    ///
+    /// `security::ConfLegacyPairing` and `security::privacy::ConfRpaGenerator` name the security
+    /// types using this generator.
+    type Rng: RngCore + CryptoRng;
+
+    /// The sink for log messages emitted by the Link-Layer, the `Responder` and the HCI
+    /// `Controller`.
+    ///
+    /// Use `NoLogger` to discard them. This is independent of the `log` and `defmt-log` Cargo
+    /// features, which route all of Rubble's log messages to a global backend.
+    type Logger: Logger;
+
+    /// The default transmit power in dBm.
+    ///
+    /// This is applied by the `LinkLayer` when advertising starts, and can be changed at runtime via
+    /// `LinkLayer::set_tx_power`.
+    const TX_POWER: i8 = 0;
+
+    /// The number of connection events in which the master has to send its first packet.
+    ///
+    /// If the master doesn't transmit within this many connection events after sending the
+    /// `CONNECT_REQ`, the connection is considered to have failed to be established, and the
+    /// `LinkLayer` resumes advertising. The specification mandates 6 connection events.
+    const CONN_ESTABLISHMENT_EVENTS: u16 = 6;
+
+    /// The interval (in connection events) at which a link quality summary is logged.
+    ///
+    /// The summary contains the CRC error and missed event counts of the connection and the data
+    /// channel with the highest error rate. A value of 0 disables the summary. The full statistics
+    /// are always available via `Connection::stats`.
+    const STATS_LOG_INTERVAL: u16 = 0;
+
+    /// The Link-Layer features to offer to the master during the feature exchange.
+    ///
+    /// Features that Rubble or the `Transmitter` can't support (see `FeatureSet::available`) are
+    /// removed before they are offered, so the default offers everything that is available.
+    /// Removing features makes the peer use fewer procedures, and prevents Rubble from initiating
+    /// the corresponding procedures itself.
+    const FEATURES: FeatureSet = FeatureSet::all();
+
+    /// The company identifier of the Link-Layer implementation, sent in `LL_VERSION_IND`.
+    ///
+    /// Qualified products have to use the identifier assigned to their manufacturer by the
+    /// Bluetooth SIG. The default is `CompanyId::UNASSIGNED`.
+    const COMPANY_ID: CompanyId = CompanyId::UNASSIGNED;
+
+    /// The implementation-defined subversion number sent in `LL_VERSION_IND`.
+    ///
+    /// This identifies the revision of the Link-Layer implementation, and is usually incremented
+    /// with every firmware release.
+    const SUBVERSION: u16 = 0;
+
+    /// Called by the `LinkLayer` whenever it has finished processing a radio or timer event.
+    ///
+    /// `next_event` is the time at which the Link-Layer needs to run next, or `None` if it has no
+    /// event scheduled (eg. in Standby). Until then, the radio is idle, so the application may
+    /// enter System ON sleep as long as the `Timer` keeps running.
+    ///
+    /// This is called from the context driving the Link-Layer (usually an interrupt handler), so it
+    /// must not block. The default implementation does nothing.
+    fn on_idle(_next_event: Option<Instant>) {}
+
+    /// Called by the `LinkLayer` for every PDU it transmits or receives.
+    ///
+    /// This allows capturing the traffic of the device, eg. by streaming the records created by
+    /// `CapturedPdu::write_pcap_record` to a host. Like `on_idle`, this is called from the context
+    /// driving the Link-Layer, and right before or after time-critical radio operations, so it
+    /// must return quickly. The default implementation does nothing.
+    fn capture_pdu(_pdu: &CapturedPdu<'_>) {}
 }
 
 // Helper aliases to make accessing producer/consumer more convenient.
@@ -35,3 +122,15 @@ pub(crate) type ConfConsumer<C> = <<C as Config>::PacketQueue as PacketQueue>::C
 
 // (`C::PacketQueue::Producer` should work, but doesn't)
 // (see: https://github.com/rust-lang/rust/issues/22519)
+
+/// Fails compilation if the buffers of configuration `C` can't hold the PDUs required by the
+/// specification.
+pub(crate) struct BufferSizes<C>(PhantomData<C>);
+
+impl<C: Config> BufferSizes<C> {
+    pub(crate) const CHECK: () = assert!(
+        <C::PacketQueue as PacketQueue>::MAX_PAYLOAD >= MIN_DATA_PAYLOAD_BUF
+            && <C::PacketQueue as PacketQueue>::MAX_PAYLOAD <= MAX_DATA_PAYLOAD_BUF,
+        "`PacketQueue::MAX_PAYLOAD` must be in range `MIN_DATA_PAYLOAD_BUF..=MAX_DATA_PAYLOAD_BUF`"
+    );
+}
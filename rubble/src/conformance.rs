@@ -0,0 +1,174 @@
+//! Known-answer tests of low-level primitives against the *Bluetooth Core Specification*.
+//!
+//! Wherever the specification provides sample data, it is used verbatim, with the section it was
+//! taken from noted on each test. For the CRC and data whitening, the specification only defines
+//! the LFSR circuits (Vol 6, Part B, 3.1.1 and 3.2). Their expected values were computed with a
+//! bit-serial model of those circuits and agree with the implementations used by BLE sniffers.
+//!
+//! The P-256 sample data is checked next to the P-256 provider in `ecdh::p256`, since it needs
+//! access to its internals.
+
+use crate::link::{advertising::CRC_PRESET, AddressKind, DeviceAddress};
+use crate::phy::{crc24, crc_bytes, whiten, AdvertisingChannel, DataChannel};
+use crate::security::privacy::{resolve, RpaGenerator};
+use crate::security::toolbox::{ah, c1, e, s1};
+use crate::security::SoftAes;
+use crate::test_rng::TestRng;
+
+/// Whitening sequences for 16 Bytes, starting with the LFSR initialized for the given channel
+/// index.
+const WHITENING: &[(u8, [u8; 16])] = &[
+    (0, hex16(0x40b2bcc31f374a5f85f69c9ac1d6c544)),
+    (17, hex16(0x196c5d4c0492e51dfeb851fa2ab4e7d4)),
+    (36, hex16(0x442059dee18f1ba5af427b4ecd60eb62)),
+    (37, hex16(0x8dd257a13da766b0753111489677f8e3)),
+    (38, hex16(0xd6c5442059dee18f1ba5af427b4ecd60)),
+    (39, hex16(0x1f374a5f85f69c9ac1d6c5442059dee1)),
+];
+
+/// Converts a 16-Byte value written in transmission order to an array.
+const fn hex16(value: u128) -> [u8; 16] {
+    value.to_be_bytes()
+}
+
+fn whitening_iv(channel: u8) -> u8 {
+    if channel <= 36 {
+        DataChannel::new(channel).whitening_iv()
+    } else {
+        AdvertisingChannel::iter_all()
+            .find(|ch| ch.channel() == channel)
+            .unwrap()
+            .whitening_iv()
+    }
+}
+
+#[test]
+fn whitening_sequence() {
+    for (channel, sequence) in WHITENING {
+        let mut data = [0; 16];
+        whiten(whitening_iv(*channel), &mut data);
+        assert_eq!(data, *sequence, "channel {}", channel);
+    }
+}
+
+#[test]
+fn whitening_period() {
+    // x^7 + x^4 + 1 is primitive, so the sequence repeats after 127 bits
+    let mut data = [0; 127];
+    whiten(whitening_iv(37), &mut data);
+    let bit = |i: usize| (data[i / 8] >> (i % 8)) & 1;
+    for i in 0..(127 * 8 - 127) {
+        assert_eq!(bit(i), bit(i + 127), "bit {}", i);
+    }
+}
+
+#[test]
+fn crc() {
+    let samples: &[(u32, &[u8], [u8; 3])] = &[
+        // ADV_NONCONN_IND with AdvA 66:55:44:33:22:11
+        (
+            CRC_PRESET,
+            &[0x42, 0x06, 0x11, 0x22, 0x33, 0x44, 0x55, 0x66],
+            [0x57, 0x03, 0x1c],
+        ),
+        // ADV_IND
+        (
+            CRC_PRESET,
+            &[0x40, 0x06, 0xD6, 0xBE, 0x89, 0x8E, 0x00, 0x11],
+            [0xab, 0x2f, 0x75],
+        ),
+        // Empty data channel PDU
+        (0x123456, &[0x01, 0x00], [0x48, 0xdc, 0x8a]),
+    ];
+    for (crc_init, pdu, expected) in samples {
+        assert_eq!(crc_bytes(crc24(*crc_init, pdu)), *expected);
+    }
+}
+
+#[test]
+fn on_air_packet() {
+    // PDU and CRC after whitening, as transmitted after the Access Address
+    let mut packet = [0x42, 0x06, 0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0, 0, 0];
+    let crc = crc24(CRC_PRESET, &packet[..8]);
+    packet[8..].copy_from_slice(&crc_bytes(crc));
+    whiten(whitening_iv(37), &mut packet);
+    assert_eq!(
+        packet,
+        [0xcf, 0xd4, 0x46, 0x83, 0x0e, 0xe3, 0x33, 0xd6, 0x22, 0x32, 0x0d]
+    );
+
+    let mut packet = [0x01, 0x00, 0, 0, 0];
+    let crc = crc24(0x123456, &packet[..2]);
+    packet[2..].copy_from_slice(&crc_bytes(crc));
+    whiten(whitening_iv(17), &mut packet);
+    assert_eq!(packet, [0x18, 0x6c, 0x15, 0x90, 0x8e]);
+}
+
+/// Vol 6, Part C, 1: Session key derivation from the LTK and the session key diversifiers.
+#[test]
+fn session_key() {
+    let ltk = 0x4C68384139F574D836BCF34E9DFB01BF;
+    let skd_m = 0xACBDCEDFE0F10213;
+    let skd_s = 0x0213243546576879;
+    let skd = skd_s << 64 | skd_m;
+    assert_eq!(
+        e(&mut SoftAes, ltk, skd),
+        0x99AD1B5226A37E3E058E3B8E27C2C666
+    );
+}
+
+/// Vol 3, Part H, 2.2.3: Confirm value generation function `c1`.
+#[test]
+fn c1_sample() {
+    let mut preq = [0; 7];
+    preq.copy_from_slice(&0x07071000000101u64.to_le_bytes()[..7]);
+    let mut pres = [0; 7];
+    pres.copy_from_slice(&0x05000800000302u64.to_le_bytes()[..7]);
+    let mut ia = [0; 6];
+    ia.copy_from_slice(&0xA1A2A3A4A5A6u64.to_le_bytes()[..6]);
+    let mut ra = [0; 6];
+    ra.copy_from_slice(&0xB1B2B3B4B5B6u64.to_le_bytes()[..6]);
+
+    let confirm = c1(
+        &mut SoftAes,
+        0,
+        0x5783D52156AD6F0E6388274EC6702EE0,
+        &preq,
+        &pres,
+        &DeviceAddress::new(ia, AddressKind::Random),
+        &DeviceAddress::new(ra, AddressKind::Public),
+    );
+    assert_eq!(confirm, 0x1e1e3fef878988ead2a74dc5bef13b86);
+}
+
+/// Vol 3, Part H, 2.2.4: Key generation function `s1`.
+#[test]
+fn s1_sample() {
+    let stk = s1(
+        &mut SoftAes,
+        0,
+        0x000F0E0D0C0B0A091122334455667788,
+        0x010203040506070899AABBCCDDEEFF00,
+    );
+    assert_eq!(stk, 0x9a1fe1f0e8b0f49b5b4216ae796da062);
+}
+
+const IRK: u128 = 0xec0234a357c8ad05341010a60a397d9b;
+
+/// Vol 3, Part H, D.7: Random address hash function `ah`.
+#[test]
+fn ah_sample() {
+    assert_eq!(ah(&mut SoftAes, IRK, 0x708194), 0x0dfbaa);
+}
+
+/// Vol 3, Part H, D.7, and Vol 6, Part B, 1.3.2.2: The Resolvable Private Address built from the
+/// `ah` sample data.
+#[test]
+fn rpa_sample() {
+    // The RNG produces the `prand` value of the `ah` sample data
+    let rpa = RpaGenerator::new(IRK, SoftAes, TestRng::fixed(0x708194)).generate();
+    assert!(rpa.is_random());
+    // hash || prand, with the 2 MSbs of prand set to 0b01
+    assert_eq!(rpa.raw(), &[0xaa, 0xfb, 0x0d, 0x94, 0x81, 0x70]);
+    assert!(resolve(&mut SoftAes, IRK, &rpa));
+}
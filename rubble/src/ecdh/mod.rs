@@ -27,6 +27,7 @@ mod ring;
 #[cfg(feature = "ring")]
 pub use self::ring::*;
 
+use crate::utils::HexSlice;
 use core::fmt;
 use rand_core::{CryptoRng, RngCore};
 
@@ -44,6 +45,18 @@ use rand_core::{CryptoRng, RngCore};
 /// [`PrivateKey`]: trait.PrivateKey.html
 pub struct PublicKey(pub [u8; 64]);
 
+impl fmt::Debug for PublicKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "PublicKey({:?})", HexSlice(&self.0[..]))
+    }
+}
+
+impl defmt::Format for PublicKey {
+    fn format(&self, fmt: defmt::Formatter<'_>) {
+        defmt::write!(fmt, "PublicKey({=[u8]:x})", &self.0[..]);
+    }
+}
+
 /// A shared secret resulting from an ECDH key agreement.
 ///
 /// This is returned by implementations of [`SecretKey::agree`].
@@ -54,7 +67,7 @@ pub struct SharedSecret(pub [u8; 32]);
 /// Error returned by [`SecretKey::agree`] when the public key of the other party is invalid.
 ///
 /// [`SecretKey::agree`]: trait.SecretKey.html#tymethod.agree
-#[derive(Debug)]
+#[derive(Debug, defmt::Format)]
 pub struct InvalidPublicKey {}
 
 impl InvalidPublicKey {
@@ -70,6 +83,9 @@ impl fmt::Display for InvalidPublicKey {
     }
 }
 
+#[cfg(feature = "std")]
+impl std::error::Error for InvalidPublicKey {}
+
 /// Trait for ECDH providers.
 pub trait EcdhProvider {
     /// Provider-defined secret key type.
@@ -1,13 +1,29 @@
+use crate::att::AttError;
+use crate::l2cap::L2capError;
+use crate::link::LinkError;
+use crate::security::SmError;
 use core::fmt;
 
 /// Errors returned by the BLE stack.
+///
+/// This is the aggregate of the errors of all layers. APIs that belong to a specific layer return
+/// that layer's error type instead ([`LinkError`], [`L2capError`], [`AttError`] or [`SmError`]),
+/// which converts into an `Error` via `From`, so `?` can be used to propagate them.
+///
+/// `TxBusy` errors of all layers convert to `Error::TxBusy`, so that code retrying operations once
+/// `Cmd::tx_space_freed` is set only has to check for a single variant.
+///
+/// [`LinkError`]: link/enum.LinkError.html
+/// [`L2capError`]: l2cap/enum.L2capError.html
+/// [`AttError`]: att/struct.AttError.html
+/// [`SmError`]: security/enum.SmError.html
 #[derive(Debug, PartialEq, Eq, defmt::Format)]
 #[non_exhaustive]
 pub enum Error {
     /// Packet specified an invalid length value or was too short.
     ///
-    /// This indicates a protocol violation, so the connection should
-    /// considered lost (if one is currently established).
+    /// This indicates a protocol violation by the peer. The `Responder` drops such packets and
+    /// keeps the connection.
     InvalidLength,
 
     /// Invalid value supplied for field.
@@ -22,6 +38,47 @@ pub enum Error {
 
     /// Parsing didn't consume the entire buffer.
     IncompleteParse,
+
+    /// The TX packet queue doesn't have enough free space for the PDU.
+    ///
+    /// This is a transient condition: The operation can be retried once the Link-Layer has
+    /// transmitted queued packets, which is signaled via `Cmd::tx_space_freed`.
+    TxBusy,
+
+    /// The application isn't ready to process the incoming packet.
+    ///
+    /// The packet is kept in the RX queue, and processing can be retried once the application
+    /// is ready (see `AttributeProvider::write_command_action`).
+    RxBusy,
+
+    /// A Link-Layer operation failed.
+    Link(LinkError),
+
+    /// An L2CAP operation failed.
+    L2cap(L2capError),
+
+    /// An ATT request failed with an error that can be reported to the client.
+    Att(AttError),
+
+    /// A Security Manager operation failed.
+    Sm(SmError),
+}
+
+impl Error {
+    /// Returns whether this error leaves the connection in a state it can't recover from.
+    ///
+    /// This is the case when the stack fails to encode one of its own PDUs, since the peer might
+    /// be waiting for it. Errors caused by malformed packets from the peer, and the transient
+    /// `TxBusy` and `RxBusy` conditions, are not fatal. See `Responder::process_one` for how
+    /// errors are handled while processing packets.
+    pub fn is_fatal(&self) -> bool {
+        matches!(
+            self,
+            Error::Link(LinkError::Encoding)
+                | Error::L2cap(L2capError::Encoding)
+                | Error::Sm(SmError::Encoding)
+        )
+    }
 }
 
 impl fmt::Display for Error {
@@ -31,6 +88,58 @@ impl fmt::Display for Error {
             Error::InvalidValue => "invalid value for field",
             Error::Eof => "end of buffer",
             Error::IncompleteParse => "excess data in buffer",
+            Error::TxBusy => "not enough space in TX queue",
+            Error::RxBusy => "application not ready to process packet",
+            Error::Link(e) => return write!(f, "link layer: {}", e),
+            Error::L2cap(e) => return write!(f, "L2CAP: {}", e),
+            Error::Att(e) => return write!(f, "ATT: {}", e),
+            Error::Sm(e) => return write!(f, "security manager: {}", e),
         })
     }
 }
+
+impl From<LinkError> for Error {
+    fn from(e: LinkError) -> Self {
+        match e {
+            LinkError::TxBusy => Error::TxBusy,
+            e => Error::Link(e),
+        }
+    }
+}
+
+impl From<L2capError> for Error {
+    fn from(e: L2capError) -> Self {
+        match e {
+            L2capError::TxBusy => Error::TxBusy,
+            e => Error::L2cap(e),
+        }
+    }
+}
+
+impl From<AttError> for Error {
+    fn from(e: AttError) -> Self {
+        Error::Att(e)
+    }
+}
+
+impl From<SmError> for Error {
+    fn from(e: SmError) -> Self {
+        match e {
+            SmError::TxBusy => Error::TxBusy,
+            e => Error::Sm(e),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Link(e) => Some(e),
+            Error::L2cap(e) => Some(e),
+            Error::Att(e) => Some(e),
+            Error::Sm(e) => Some(e),
+            _ => None,
+        }
+    }
+}
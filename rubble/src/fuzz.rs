@@ -0,0 +1,168 @@
+//! Entry points for fuzzing the parsers of over-the-air data.
+//!
+//! These functions are not part of the public API. They are used by the `cargo fuzz` targets in
+//! the `fuzz` directory, and feed arbitrary Bytes into the same code paths that process packets
+//! received by the radio. Malformed input must never cause a panic.
+
+use crate::att::NoAttributes;
+use crate::bytes::ByteReader;
+use crate::gatt::BatteryServiceAttrs;
+use crate::l2cap::{BleChannelMap, Channel, L2CAPState};
+use crate::link::{
+    ad_structure::parse_ad_structures,
+    advertising::{Header, Pdu},
+    queue::{Consume, Consumer, PacketQueue, SimpleQueue},
+};
+use crate::security::NoSecurity;
+
+/// Maximum payload length of a data channel PDU, as encoded in its 8-bit length field.
+const MAX_DATA_PAYLOAD: usize = 255;
+
+/// Parses an advertising channel PDU (header and payload).
+pub fn fuzz_adv_pdu(data: &[u8]) {
+    if data.len() < 2 {
+        return;
+    }
+
+    let header = Header::parse(data);
+    let pdu = match Pdu::from_header_and_payload(header, &mut ByteReader::new(&data[2..])) {
+        Ok(pdu) => pdu,
+        Err(_) => return,
+    };
+
+    pdu.sender();
+    pdu.receiver();
+    if let Some(ads) = pdu.advertising_data() {
+        ads.for_each(drop);
+    }
+}
+
+/// Parses a list of AD structures, as received in advertising data.
+pub fn fuzz_ad_structures(data: &[u8]) {
+    parse_ad_structures(data).for_each(drop);
+}
+
+/// Passes an ATT PDU to an ATT server hosting the attributes of a Battery Service.
+pub fn fuzz_att_pdu(data: &[u8]) {
+    // Prepend an L2CAP header addressing the ATT channel
+    let mut message = [0; MAX_DATA_PAYLOAD];
+    if data.len() + 4 > message.len() {
+        return;
+    }
+    message[..2].copy_from_slice(&(data.len() as u16).to_le_bytes());
+    message[2..4].copy_from_slice(&Channel::ATT.as_raw().to_le_bytes());
+    message[4..][..data.len()].copy_from_slice(data);
+    process_l2cap(
+        BleChannelMap::<_, NoSecurity>::with_attributes(BatteryServiceAttrs::new()),
+        &message[..data.len() + 4],
+    );
+}
+
+/// Passes the payload of a data channel PDU starting an L2CAP message to the L2CAP layer.
+pub fn fuzz_l2cap(data: &[u8]) {
+    if data.len() > MAX_DATA_PAYLOAD {
+        return;
+    }
+
+    process_l2cap(
+        BleChannelMap::<_, NoSecurity>::with_attributes(NoAttributes),
+        data,
+    );
+}
+
+fn process_l2cap<M: crate::l2cap::ChannelMapper>(mapper: M, message: &[u8]) {
+    let mut queue = SimpleQueue::new();
+    let (mut tx, mut rx) = queue.split();
+    let mut l2cap = L2CAPState::new(mapper);
+    l2cap.tx(&mut tx).process_start(message);
+
+    // Drain responses
+    while rx.consume_raw_with(|_, _| Consume::always(Ok(()))).is_ok() {}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ITERATIONS: usize = 2000;
+
+    /// Feeds pseudo-random inputs to `f`, starting with each of the given prefixes.
+    fn smoke(prefixes: &[&[u8]], f: fn(&[u8])) {
+        let mut state = 0x1234_5678_9ABC_DEF1_u64;
+        let mut next = move || {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state
+        };
+
+        let mut buf = [0; MAX_DATA_PAYLOAD];
+        for prefix in prefixes {
+            for _ in 0..ITERATIONS {
+                let len = prefix.len() + (next() % 64) as usize;
+                buf[..prefix.len()].copy_from_slice(prefix);
+                for b in &mut buf[prefix.len()..len] {
+                    *b = next() as u8;
+                }
+                f(&buf[..len]);
+            }
+        }
+    }
+
+    #[test]
+    fn adv_pdu() {
+        let headers: Vec<[u8; 2]> = (0..16u8).map(|ty| [ty, 0]).collect();
+        let prefixes: Vec<&[u8]> = headers.iter().map(|h| &h[..]).collect();
+        smoke(&prefixes, |data| {
+            let mut data = data.to_vec();
+            if data.len() >= 2 {
+                data[1] = (data.len() - 2) as u8;
+            }
+            fuzz_adv_pdu(&data)
+        });
+    }
+
+    #[test]
+    fn ad_structures() {
+        // Truncated, zero and oversized length Bytes, with and without a preceding structure
+        smoke(
+            &[
+                &[],
+                &[0x00],
+                &[0x01],
+                &[0xFF, 0x09],
+                &[0x02, 0x01, 0x06, 0x1F],
+                &[0x02, 0x01, 0x06, 0x00],
+            ],
+            fuzz_ad_structures,
+        );
+
+        // The same in beacons, after the header and advertiser address
+        smoke(
+            &[
+                &[0x02, 0, 1, 2, 3, 4, 5, 6, 0x1F],
+                &[0x02, 0, 1, 2, 3, 4, 5, 6, 0x02, 0x01, 0x06, 0xFF],
+            ],
+            |data| {
+                let mut data = data.to_vec();
+                data[1] = (data.len() - 2) as u8;
+                fuzz_adv_pdu(&data)
+            },
+        );
+    }
+
+    #[test]
+    fn att_pdu() {
+        let opcodes: Vec<[u8; 1]> = (0..=255u8).map(|op| [op]).collect();
+        let prefixes: Vec<&[u8]> = opcodes.iter().map(|op| &op[..]).collect();
+        smoke(&prefixes, fuzz_att_pdu);
+    }
+
+    #[test]
+    fn l2cap() {
+        smoke(
+            &[&[], &[0x05, 0x00, 0x06, 0x00], &[0x05, 0x00, 0x05, 0x00]],
+            fuzz_l2cap,
+        );
+    }
+}
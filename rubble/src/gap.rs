@@ -0,0 +1,265 @@
+//! Device identity shared between the advertising data and the GAP service.
+//!
+//! The device name and appearance are exposed twice: In the advertising data, so that scanners
+//! can show them before connecting, and in the *Generic Access* GATT service, which connected
+//! centrals read to get the full name. [`DeviceConfig`] keeps both in one place, so that they
+//! can't go out of sync.
+//!
+//! Legacy advertising data is limited to 31 Bytes. When the complete name doesn't fit into the
+//! space left by the other AD structures, a prefix of it is advertised as the *Shortened Local
+//! Name* instead, and centrals can read the complete name from the GAP service after connecting.
+//!
+//! [`DeviceConfig`]: struct.DeviceConfig.html
+
+use crate::att::{AttUuid, Attribute, AttributeProvider, Handle, HandleRange};
+use crate::gatt::{characteristic::Appearance, characteristic::Properties, StaticAttributes};
+use crate::l2cap::conn_params::ConnectionParameters;
+use crate::link::ad_structure::{AdStructure, AdvertisingData, AdvertisingDataError};
+use crate::uuid::consts::{characteristics, services};
+use crate::Error;
+
+/// Name, appearance and preferred connection parameters of the device.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct DeviceConfig<'a> {
+    /// The complete device name, as shown to users.
+    pub name: &'a str,
+
+    /// External appearance of the device.
+    pub appearance: Appearance,
+
+    /// Connection parameters preferred by this device, exposed via the *Peripheral Preferred
+    /// Connection Parameters* characteristic.
+    ///
+    /// The same parameters should be passed to the `ConnParamUpdater`, if one is used.
+    pub conn_params: Option<ConnectionParameters>,
+}
+
+impl<'a> DeviceConfig<'a> {
+    /// Creates a configuration for a device called `name`, with an unknown appearance and no
+    /// preferred connection parameters.
+    pub const fn new(name: &'a str) -> Self {
+        Self {
+            name,
+            appearance: Appearance::Unknown,
+            conn_params: None,
+        }
+    }
+
+    /// Returns the longest prefix of the name that is at most `max_len` Bytes long.
+    ///
+    /// The name is only cut at character boundaries, so that the result is valid UTF-8.
+    pub fn shortened_name(&self, max_len: usize) -> &'a str {
+        let mut end = max_len.min(self.name.len());
+        while !self.name.is_char_boundary(end) {
+            end -= 1;
+        }
+        &self.name[..end]
+    }
+
+    /// Appends the appearance (unless it is `Appearance::Unknown`) and the name to `data`.
+    ///
+    /// The name gets whatever space is left, so this should be called after adding all other AD
+    /// structures. See [`push_name`] for how the name is shortened.
+    ///
+    /// [`push_name`]: #method.push_name
+    pub fn push_ad_structures(
+        &self,
+        data: &mut AdvertisingData,
+    ) -> Result<(), AdvertisingDataError> {
+        if self.appearance != Appearance::Unknown {
+            data.push(AdStructure::Appearance(self.appearance as u16))?;
+        }
+        self.push_name(data)
+    }
+
+    /// Appends the name to `data`.
+    ///
+    /// If the complete name doesn't fit into the space left in `data`, the longest prefix that
+    /// does is added as a *Shortened Local Name*. If not even a single character fits, the
+    /// `AdvertisingDataError` for the complete name is returned.
+    pub fn push_name(&self, data: &mut AdvertisingData) -> Result<(), AdvertisingDataError> {
+        // Length and type Bytes
+        let max_len = data.space_left().saturating_sub(2);
+        let name = self.shortened_name(max_len);
+        let ad = if name.len() == self.name.len() || name.is_empty() {
+            AdStructure::CompleteLocalName(self.name)
+        } else {
+            AdStructure::ShortenedLocalName(name)
+        };
+        data.push(ad)?;
+        Ok(())
+    }
+
+    /// Appends the *Generic Access* service to `attributes`.
+    ///
+    /// The service contains the read-only *Device Name* and *Appearance* characteristics, and the
+    /// *Peripheral Preferred Connection Parameters* characteristic if `conn_params` is set. It
+    /// takes up 7 attributes (5 without connection parameters).
+    ///
+    /// Returns the handle of the *Device Name* value, which can be used to change the name later.
+    /// Returns `Error::InvalidLength` if the name is longer than `gatt::MAX_VALUE_LEN`, and
+    /// `Error::Eof` if `attributes` is full.
+    pub fn push_gap_service<const N: usize>(
+        &self,
+        attributes: &mut StaticAttributes<N>,
+    ) -> Result<Handle, Error> {
+        attributes.push_primary_service(services::GENERIC_ACCESS.into())?;
+        let name = attributes.push_characteristic(
+            characteristics::DEVICE_NAME.into(),
+            Properties::READ,
+            self.name.as_bytes(),
+            Default::default(),
+        )?;
+        attributes.push_characteristic(
+            characteristics::APPEARANCE.into(),
+            Properties::READ,
+            &(self.appearance as u16).to_le_bytes(),
+            Default::default(),
+        )?;
+        if let Some(params) = &self.conn_params {
+            attributes.push_characteristic(
+                characteristics::PERIPHERAL_PREFERRED_CONNECTION_PARAMETERS.into(),
+                Properties::READ,
+                &params.to_array(),
+                Default::default(),
+            )?;
+        }
+        Ok(name)
+    }
+}
+
+/// An `AttributeProvider` that only hosts the *Generic Access* service of a [`DeviceConfig`].
+///
+/// [`DeviceConfig`]: struct.DeviceConfig.html
+pub struct GapServiceAttrs {
+    attributes: StaticAttributes<7>,
+    name: Handle,
+}
+
+impl GapServiceAttrs {
+    /// Creates the service from `config`.
+    ///
+    /// Returns `Error::InvalidLength` if the name is longer than `gatt::MAX_VALUE_LEN`.
+    pub fn new(config: &DeviceConfig<'_>) -> Result<Self, Error> {
+        let mut attributes = StaticAttributes::new();
+        let name = config.push_gap_service(&mut attributes)?;
+        Ok(Self { attributes, name })
+    }
+
+    /// Changes the device name reported by the service.
+    ///
+    /// Returns `Error::InvalidLength` if the name is longer than `gatt::MAX_VALUE_LEN`.
+    pub fn set_name(&mut self, name: &str) -> Result<(), Error> {
+        self.attributes.set_value(self.name, name.as_bytes())
+    }
+}
+
+impl AttributeProvider for GapServiceAttrs {
+    fn for_attrs_in_range(
+        &mut self,
+        range: HandleRange,
+        mut f: impl FnMut(&Self, &Attribute<dyn AsRef<[u8]>>) -> Result<(), Error>,
+    ) -> Result<(), Error> {
+        let this = &*self;
+        this.attributes
+            .for_each_in_range(range, |attr| f(this, attr))
+    }
+
+    fn is_grouping_attr(&self, uuid: AttUuid) -> bool {
+        self.attributes.is_grouping_attr(uuid)
+    }
+
+    fn group_end(&self, handle: Handle) -> Option<&Attribute<dyn AsRef<[u8]>>> {
+        self.attributes.group_end(handle)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::link::ad_structure::LEGACY_ADV_DATA_LEN;
+
+    const NAME: &str = "Rubble Thermometer \u{dc}tility Device";
+
+    #[test]
+    fn complete_name_fits() {
+        let config = DeviceConfig {
+            appearance: Appearance::GenericThermometer,
+            ..DeviceConfig::new("Rubble")
+        };
+        let mut data = AdvertisingData::new();
+        config.push_ad_structures(&mut data).unwrap();
+        assert_eq!(
+            data.as_bytes(),
+            &[0x03, 0x19, 0x00, 0x03, 0x07, 0x09, b'R', b'u', b'b', b'b', b'l', b'e']
+        );
+    }
+
+    #[test]
+    fn shortened_at_char_boundary() {
+        let config = DeviceConfig::new(NAME);
+        // U+00DC takes 2 Bytes and starts at offset 19
+        assert_eq!(config.shortened_name(20), "Rubble Thermometer ");
+        assert_eq!(config.shortened_name(21), "Rubble Thermometer \u{dc}");
+        assert_eq!(config.shortened_name(100), NAME);
+
+        // 7 Bytes used, 24 left: 22 for the name
+        let mut data = AdvertisingData::new();
+        data.push(AdStructure::TxPowerLevel(0)).unwrap();
+        data.push(AdStructure::Appearance(0)).unwrap();
+        config.push_name(&mut data).unwrap();
+        let mut expected = [0; 2 + 22];
+        expected[..2].copy_from_slice(&[1 + 22, 0x08]);
+        expected[2..].copy_from_slice(&NAME.as_bytes()[..22]);
+        assert_eq!(&data.as_bytes()[7..], &expected[..]);
+        assert_eq!(data.space_left(), 0);
+
+        let mut data = AdvertisingData::with_budget(LEGACY_ADV_DATA_LEN + 10);
+        config.push_name(&mut data).unwrap();
+        assert_eq!(data.as_bytes()[1], 0x09);
+        assert_eq!(&data.as_bytes()[2..], NAME.as_bytes());
+    }
+
+    #[test]
+    fn no_space_for_name() {
+        let mut data = AdvertisingData::with_budget(2);
+        assert!(matches!(
+            DeviceConfig::new(NAME).push_name(&mut data),
+            Err(AdvertisingDataError::DoesNotFit {
+                required: 36,
+                available: 2,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn gap_service() {
+        let params = ConnectionParameters {
+            min_interval: 6,
+            max_interval: 12,
+            slave_latency: 0,
+            supervision_timeout: 100,
+        };
+        let config = DeviceConfig {
+            appearance: Appearance::Keyboard,
+            conn_params: Some(params),
+            ..DeviceConfig::new("Rubble")
+        };
+        let mut gap = GapServiceAttrs::new(&config).unwrap();
+
+        // Service declaration, then declaration and value of each characteristic
+        fn value(gap: &GapServiceAttrs, handle: u16) -> Option<&[u8]> {
+            gap.attributes.value(Handle::from_raw(handle))
+        }
+        assert_eq!(value(&gap, 3), Some(&b"Rubble"[..]));
+        assert_eq!(value(&gap, 5), Some(&[0xC1, 0x03][..]));
+        assert_eq!(value(&gap, 7), Some(&params.to_array()[..]));
+        assert_eq!(value(&gap, 8), None);
+
+        gap.set_name("Rubble 2").unwrap();
+        assert_eq!(value(&gap, 3), Some(&b"Rubble 2"[..]));
+        assert_eq!(gap.set_name(&"x".repeat(65)), Err(Error::InvalidLength));
+        assert_eq!(value(&gap, 3), Some(&b"Rubble 2"[..]));
+    }
+}
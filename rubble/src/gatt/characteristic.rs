@@ -1,4 +1,4 @@
-use crate::{att::AttUuid, uuid::Uuid16};
+use crate::{att::AttUuid, uuid::consts::characteristics};
 use bitflags::bitflags;
 
 bitflags! {
@@ -14,6 +14,12 @@ bitflags! {
     }
 }
 
+impl defmt::Format for Properties {
+    fn format(&self, fmt: defmt::Formatter<'_>) {
+        defmt::write!(fmt, "Properties({=u8:b})", self.bits());
+    }
+}
+
 /// Bitwise or operation on `bitflags!` types that works in a `const` context.
 macro_rules! const_or {
     (
@@ -34,6 +40,7 @@ pub trait Characteristic {
     const UUID: AttUuid;
 }
 
+#[derive(Debug, Copy, Clone, defmt::Format)]
 pub struct BatteryLevel {
     /// Battery level in percent (0-100).
     percentage: u8,
@@ -52,10 +59,10 @@ impl BatteryLevel {
 
 impl Characteristic for BatteryLevel {
     const PROPS: Properties = const_or!(Properties::READ | Properties::WRITE);
-    const UUID: AttUuid = AttUuid::Uuid16(Uuid16(0x2A19));
+    const UUID: AttUuid = AttUuid::Uuid16(characteristics::BATTERY_LEVEL);
 }
 
-#[derive(Copy, Clone, PartialEq, Eq)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, defmt::Format)]
 pub enum Appearance {
     Unknown = 0,
     GenericPhone = 64,
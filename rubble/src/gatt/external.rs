@@ -0,0 +1,258 @@
+//! Attribute values stored in slow external memory.
+//!
+//! Large or rarely read values (eg. logs or calibration data in an external SPI flash or FRAM)
+//! don't need to be kept in RAM. [`ExternalValues`] combines a regular attribute table with a
+//! [`ValueStore`] that owns the values of some of its attributes. Reads of these attributes are
+//! *deferred*: The ATT server asks the store to start fetching the value and returns without
+//! responding. Once the data is available, the application passes it to
+//! `AttributeServer::complete_read`, and the response is sent by the next call to
+//! `Responder::process_one`:
+//!
+//! ```ignore
+//! // In the SPI transfer's completion handler:
+//! let mut l2cap = responder.l2cap();
+//! l2cap.channel_mapper().att().protocol().complete_read(&spi_buffer[..len])?;
+//! ```
+//!
+//! [`ExternalValues`]: struct.ExternalValues.html
+//! [`ValueStore`]: trait.ValueStore.html
+
+use super::StaticAttributes;
+use crate::att::{
+    AttUuid, Attribute, AttributeAccessPermissions, AttributeProvider, Handle, HandleRange,
+};
+use crate::l2cap::Sender;
+use crate::link::Peer;
+use crate::Error;
+
+/// Storage holding the values of some attributes outside of RAM.
+pub trait ValueStore {
+    /// Returns whether the value of the attribute at `handle` is held by this store.
+    fn contains(&self, handle: Handle) -> bool;
+
+    /// Starts reading the value of the attribute at `handle`, beginning at `offset`.
+    ///
+    /// This must not block. The data (or an error) has to be passed to the `AttributeServer` once
+    /// it is available. At most `ATT_MTU - 1` Bytes of it are sent to the client, so there is no
+    /// need to fetch more than that.
+    fn fetch(&mut self, handle: Handle, offset: u16);
+}
+
+/// An `AttributeProvider` serving some attribute values from a [`ValueStore`].
+///
+/// All attributes are declared in the wrapped `StaticAttributes` table. For attributes whose values
+/// live in the store, the table should contain an empty placeholder value, since the stored value
+/// is never sent to the client.
+///
+/// [`ValueStore`]: trait.ValueStore.html
+pub struct ExternalValues<S: ValueStore, const N: usize> {
+    attributes: StaticAttributes<N>,
+    store: S,
+}
+
+impl<S: ValueStore, const N: usize> ExternalValues<S, N> {
+    /// Creates a provider hosting `attributes`, with the values in `store` fetched on demand.
+    pub fn new(attributes: StaticAttributes<N>, store: S) -> Self {
+        Self { attributes, store }
+    }
+
+    /// Provides mutable access to the attribute table.
+    pub fn attributes(&mut self) -> &mut StaticAttributes<N> {
+        &mut self.attributes
+    }
+
+    /// Provides mutable access to the value store.
+    pub fn store(&mut self) -> &mut S {
+        &mut self.store
+    }
+}
+
+impl<S: ValueStore, const N: usize> AttributeProvider for ExternalValues<S, N> {
+    const QUEUED_WRITES: bool = true;
+
+    fn for_attrs_in_range(
+        &mut self,
+        range: HandleRange,
+        mut f: impl FnMut(&Self, &Attribute<dyn AsRef<[u8]>>) -> Result<(), Error>,
+    ) -> Result<(), Error> {
+        let this = &*self;
+        this.attributes
+            .for_each_in_range(range, |attr| f(this, attr))
+    }
+
+    fn is_grouping_attr(&self, uuid: AttUuid) -> bool {
+        self.attributes.is_grouping_attr(uuid)
+    }
+
+    fn group_end(&self, handle: Handle) -> Option<&Attribute<dyn AsRef<[u8]>>> {
+        self.attributes.group_end(handle)
+    }
+
+    fn attr_access_permissions(&self, handle: Handle) -> AttributeAccessPermissions {
+        self.attributes.attr_access_permissions(handle)
+    }
+
+    fn is_deferred(&self, handle: Handle) -> bool {
+        self.store.contains(handle)
+    }
+
+    fn begin_deferred_read(&mut self, handle: Handle, offset: u16) {
+        self.store.fetch(handle, offset);
+    }
+
+    fn write_attr(
+        &mut self,
+        handle: Handle,
+        data: &[u8],
+        peer: Option<&Peer>,
+    ) -> Result<(), Error> {
+        if self.store.contains(handle) {
+            // External values are read-only
+            return Err(Error::InvalidValue);
+        }
+        self.attributes.write_attr(handle, data, peer)
+    }
+
+    fn prepare_write_attr(
+        &mut self,
+        handle: Handle,
+        offset: u16,
+        data: &[u8],
+    ) -> Result<(), Error> {
+        if self.store.contains(handle) {
+            return Err(Error::InvalidValue);
+        }
+        self.attributes.prepare_write_attr(handle, offset, data)
+    }
+
+    fn execute_write_attr(&mut self, flags: u8) -> Result<(), Error> {
+        self.attributes.execute_write_attr(flags)
+    }
+
+    fn find_information(
+        &mut self,
+        range: HandleRange,
+        responder: &mut Sender<'_>,
+    ) -> Result<(), Error> {
+        self.attributes.find_information(range, responder)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::att::ErrorCode;
+    use crate::gatt::characteristic::Properties;
+    use crate::l2cap::{BleChannelMap, ChannelMapper, L2CAPState};
+    use crate::link::queue::{Consume, Consumer, PacketQueue, SimpleQueue};
+    use crate::uuid::Uuid16;
+    use heapless::Vec;
+
+    /// Holds the value of handle 3 and records the last fetch.
+    #[derive(Default)]
+    struct Fram {
+        fetched: Option<(Handle, u16)>,
+    }
+
+    impl ValueStore for Fram {
+        fn contains(&self, handle: Handle) -> bool {
+            handle == Handle::from_raw(3)
+        }
+
+        fn fetch(&mut self, handle: Handle, offset: u16) {
+            self.fetched = Some((handle, offset));
+        }
+    }
+
+    type Map = BleChannelMap<ExternalValues<Fram, 3>, crate::security::NoSecurity>;
+
+    fn server() -> L2CAPState<Map> {
+        let mut attributes = StaticAttributes::new();
+        attributes
+            .push_primary_service(Uuid16(0x180F).into())
+            .unwrap();
+        attributes
+            .push_characteristic(
+                Uuid16(0x2A19).into(),
+                Properties::READ,
+                &[],
+                Default::default(),
+            )
+            .unwrap();
+        L2CAPState::new(BleChannelMap::with_attributes(ExternalValues::new(
+            attributes,
+            Fram::default(),
+        )))
+    }
+
+    /// Passes an ATT request to the server and returns the response, if one was sent.
+    fn request(l2cap: &mut L2CAPState<Map>, pdu: &[u8]) -> Option<Vec<u8, 23>> {
+        let mut queue = SimpleQueue::new();
+        let (mut tx, mut rx) = (&mut queue).split();
+        let mut message = Vec::<u8, 27>::from_slice(&[pdu.len() as u8, 0, 0x04, 0x00]).unwrap();
+        message.extend_from_slice(pdu).unwrap();
+        l2cap.tx(&mut tx).process_start(&message);
+        receive(&mut rx)
+    }
+
+    /// Sends the response to a deferred read, if it is ready.
+    fn flush(l2cap: &mut L2CAPState<Map>) -> Option<Vec<u8, 23>> {
+        let mut queue = SimpleQueue::new();
+        let (mut tx, mut rx) = (&mut queue).split();
+        l2cap.tx(&mut tx).send_deferred().unwrap();
+        receive(&mut rx)
+    }
+
+    fn receive(rx: &mut impl Consumer) -> Option<Vec<u8, 23>> {
+        rx.consume_raw_with(|_, raw| Consume::always(Ok(Vec::from_slice(&raw[4..]).unwrap())))
+            .ok()
+    }
+
+    #[test]
+    fn deferred_read() {
+        let mut l2cap = server();
+        assert!(request(&mut l2cap, &[0x0A, 3, 0]).is_none());
+        assert_eq!(
+            l2cap.channel_mapper().attribute_provider().store().fetched,
+            Some((Handle::from_raw(3), 0))
+        );
+        assert!(flush(&mut l2cap).is_none());
+
+        let mut att = l2cap.channel_mapper().att();
+        let server = att.protocol();
+        assert_eq!(server.deferred_read(), Some((Handle::from_raw(3), 0)));
+        server.complete_read(&[0x12, 0x34]).unwrap();
+        assert_eq!(server.deferred_read(), None);
+        assert_eq!(server.complete_read(&[]), Err(Error::InvalidValue));
+
+        assert_eq!(&flush(&mut l2cap).unwrap()[..], &[0x0B, 0x12, 0x34]);
+        assert!(flush(&mut l2cap).is_none());
+    }
+
+    #[test]
+    fn deferred_read_blob_error() {
+        let mut l2cap = server();
+        assert!(request(&mut l2cap, &[0x0C, 3, 0, 40, 0]).is_none());
+        l2cap
+            .channel_mapper()
+            .att()
+            .protocol()
+            .fail_read(ErrorCode::InvalidOffset)
+            .unwrap();
+        assert_eq!(&flush(&mut l2cap).unwrap()[..], &[0x01, 0x0C, 3, 0, 0x07]);
+    }
+
+    #[test]
+    fn read_by_type_stops_at_deferred() {
+        let mut l2cap = server();
+        assert_eq!(
+            &request(&mut l2cap, &[0x08, 1, 0, 0xFF, 0xFF, 0x19, 0x2A]).unwrap()[..],
+            &[0x01, 0x08, 3, 0, 0x06]
+        );
+        // Regular attributes are served as usual
+        assert_eq!(
+            &request(&mut l2cap, &[0x0A, 1, 0]).unwrap()[..],
+            &[0x0B, 0x0F, 0x18]
+        );
+    }
+}
@@ -4,43 +4,41 @@
 //! interaction
 
 pub mod characteristic;
+pub mod external;
+mod notify;
+pub mod ota;
+mod storage;
 
+pub use self::notify::{NotificationStream, StreamPolicy};
+pub use self::storage::{DynamicAttributes, ServiceBuilder, StaticAttributes, MAX_VALUE_LEN};
+
+use self::characteristic::Properties;
 use crate::att::{AttUuid, Attribute, AttributeProvider, Handle, HandleRange};
-use crate::uuid::{Uuid128, Uuid16};
+use crate::uuid::consts::{characteristics, descriptors, services};
+use crate::uuid::Uuid128;
 use crate::Error;
-use core::cmp;
 
 /// A demo `AttributeProvider` that will enumerate as a *Battery Service*.
 pub struct BatteryServiceAttrs {
-    attributes: [Attribute<&'static [u8]>; 3],
+    attributes: StaticAttributes<3>,
 }
 
 impl BatteryServiceAttrs {
     pub fn new() -> Self {
-        Self {
-            attributes: [
-                Attribute::new(
-                    Uuid16(0x2800).into(), // "Primary Service"
-                    Handle::from_raw(0x0001),
-                    &[0x0F, 0x18], // "Battery Service" = 0x180F
-                ),
-                Attribute::new(
-                    Uuid16(0x2803).into(), // "Characteristic"
-                    Handle::from_raw(0x0002),
-                    &[
-                        0x02, // 1 byte properties: READ = 0x02
-                        0x03, 0x00, // 2 bytes handle = 0x0003
-                        0x19, 0x2A, // 2 bytes UUID = 0x2A19 (Battery Level)
-                    ],
-                ),
-                // Characteristic value (Battery Level)
-                Attribute::new(
-                    AttUuid::Uuid16(Uuid16(0x2A19)), // "Battery Level"
-                    Handle::from_raw(0x0003),
-                    &[48u8],
-                ),
-            ],
-        }
+        let mut attributes = StaticAttributes::new();
+        attributes
+            .push_primary_service(services::BATTERY.into())
+            .unwrap();
+        // Characteristic value (Battery Level)
+        attributes
+            .push_characteristic(
+                characteristics::BATTERY_LEVEL.into(),
+                Properties::READ,
+                &[48u8],
+                Default::default(),
+            )
+            .unwrap();
+        Self { attributes }
     }
 }
 
@@ -50,33 +48,17 @@ impl AttributeProvider for BatteryServiceAttrs {
         range: HandleRange,
         mut f: impl FnMut(&Self, &Attribute<dyn AsRef<[u8]>>) -> Result<(), Error>,
     ) -> Result<(), Error> {
-        let count = self.attributes.len();
-        let start = usize::from(range.start().as_u16() - 1); // handles start at 1, not 0
-        let end = usize::from(range.end().as_u16() - 1);
-
-        let attrs = if start >= count {
-            &[]
-        } else {
-            let end = cmp::min(count - 1, end);
-            &self.attributes[start..=end]
-        };
-
-        for attr in attrs {
-            f(self, attr)?;
-        }
-        Ok(())
+        let this = &*self;
+        this.attributes
+            .for_each_in_range(range, |attr| f(this, attr))
     }
 
     fn is_grouping_attr(&self, uuid: AttUuid) -> bool {
-        uuid == Uuid16(0x2800) // FIXME not characteristics?
+        self.attributes.is_grouping_attr(uuid)
     }
 
     fn group_end(&self, handle: Handle) -> Option<&Attribute<dyn AsRef<[u8]>>> {
-        match handle.as_u16() {
-            0x0001 => Some(&self.attributes[2]),
-            0x0002 => Some(&self.attributes[2]),
-            _ => None,
-        }
+        self.attributes.group_end(handle)
     }
 }
 
@@ -84,73 +66,39 @@ impl AttributeProvider for BatteryServiceAttrs {
 ///
 /// Also refer to <https://www.midi.org/specifications-old/item/bluetooth-le-midi>.
 pub struct MidiServiceAttrs {
-    attributes: [Attribute<&'static [u8]>; 4],
+    attributes: StaticAttributes<4>,
 }
 
-// MIDI Service (UUID: 03B80E5A-EDE8-4B33-A751-6CE34EC4C700)
-// MIDI Data I/O Characteristic (UUID: 7772E5DB-3868-4112-A1A9-F2669D106BF3)
+const MIDI_SERVICE_UUID: Uuid128 = Uuid128::parse_static("03b80e5a-ede8-4b33-a751-6ce34ec4c700");
+const MIDI_DATA_IO_UUID: Uuid128 = Uuid128::parse_static("7772e5db-3868-4112-a1a9-f2669d106bf3");
 
 impl MidiServiceAttrs {
     pub fn new() -> Self {
-        Self {
-            attributes: [
-                Attribute::new(
-                    Uuid16(0x2800).into(), // "Primary Service"
-                    Handle::from_raw(0x0001),
-                    &[
-                        0x00, 0xC7, 0xC4, 0x4E, 0xE3, 0x6C, /* - */
-                        0x51, 0xA7, /* - */
-                        0x33, 0x4B, /* - */
-                        0xE8, 0xED, /* - */
-                        0x5A, 0x0E, 0xB8, 0x03,
-                    ], // "Midi Service"
-                ),
-                Attribute::new(
-                    Uuid16(0x2803).into(), // "Characteristic"
-                    Handle::from_raw(0x0002),
-                    &[
-                        0x02 | 0x08 | 0x04 | 0x10, // 1 byte properties: READ = 0x02, WRITE_REQ = 0x08, WRITE_CMD = 0x04, NOTIFICATION = 0x10
-                        0x03,
-                        0x00, // 2 bytes handle = 0x0003
-                        // the actual UUID
-                        0xF3,
-                        0x6B,
-                        0x10,
-                        0x9D,
-                        0x66,
-                        0xF2, /*-*/
-                        0xA9,
-                        0xA1, /*-*/
-                        0x12,
-                        0x41, /*-*/
-                        0x68,
-                        0x38, /*-*/
-                        0xDB,
-                        0xE5,
-                        0x72,
-                        0x77,
-                    ],
-                ),
-                // Characteristic value (Empty Packet)
-                Attribute::new(
-                    AttUuid::Uuid128(Uuid128::from_bytes([
-                        0xF3, 0x6B, 0x10, 0x9D, 0x66, 0xF2, /*-*/
-                        0xA9, 0xA1, /*-*/
-                        0x12, 0x41, /*-*/
-                        0x68, 0x38, /*-*/
-                        0xDB, 0xE5, 0x72, 0x77,
-                    ])),
-                    Handle::from_raw(0x0003),
-                    &[],
-                ),
-                // CCCD
-                Attribute::new(
-                    AttUuid::Uuid16(Uuid16(0x2902)),
-                    Handle::from_raw(0x0004),
-                    &[0x00, 0x00],
-                ),
-            ],
-        }
+        let mut attributes = StaticAttributes::new();
+        attributes
+            .push_primary_service(MIDI_SERVICE_UUID.into())
+            .unwrap();
+        // Characteristic value (Empty Packet)
+        attributes
+            .push_characteristic(
+                MIDI_DATA_IO_UUID.into(),
+                Properties::READ
+                    | Properties::WRITE
+                    | Properties::WRITE_NO_RSP
+                    | Properties::NOTIFY,
+                &[],
+                Default::default(),
+            )
+            .unwrap();
+        // CCCD
+        attributes
+            .push(
+                descriptors::CLIENT_CHARACTERISTIC_CONFIGURATION.into(),
+                &[0x00, 0x00],
+                Default::default(),
+            )
+            .unwrap();
+        Self { attributes }
     }
 }
 
@@ -160,32 +108,16 @@ impl AttributeProvider for MidiServiceAttrs {
         range: HandleRange,
         mut f: impl FnMut(&Self, &Attribute<dyn AsRef<[u8]>>) -> Result<(), Error>,
     ) -> Result<(), Error> {
-        let count = self.attributes.len();
-        let start = usize::from(range.start().as_u16() - 1); // handles start at 1, not 0
-        let end = usize::from(range.end().as_u16() - 1);
-
-        let attrs = if start >= count {
-            &[]
-        } else {
-            let end = cmp::min(count - 1, end);
-            &self.attributes[start..=end]
-        };
-
-        for attr in attrs {
-            f(self, attr)?;
-        }
-        Ok(())
+        let this = &*self;
+        this.attributes
+            .for_each_in_range(range, |attr| f(this, attr))
     }
 
     fn is_grouping_attr(&self, uuid: AttUuid) -> bool {
-        uuid == Uuid16(0x2800) // FIXME not characteristics?
+        self.attributes.is_grouping_attr(uuid)
     }
 
     fn group_end(&self, handle: Handle) -> Option<&Attribute<dyn AsRef<[u8]>>> {
-        match handle.as_u16() {
-            0x0001 => Some(&self.attributes[3]),
-            0x0002 => Some(&self.attributes[3]),
-            _ => None,
-        }
+        self.attributes.group_end(handle)
     }
 }
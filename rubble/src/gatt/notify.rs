@@ -0,0 +1,182 @@
+use crate::att::Handle;
+use crate::gatt::MAX_VALUE_LEN;
+use crate::l2cap::{ChannelMapper, L2CAPStateTx};
+use crate::link::queue::Producer;
+use crate::Error;
+use heapless::Vec;
+
+type Value = Vec<u8, MAX_VALUE_LEN>;
+
+/// Determines what a [`NotificationStream`] does with values that haven't been sent yet.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, defmt::Format)]
+pub enum StreamPolicy {
+    /// Only the most recent value is kept.
+    ///
+    /// Pushing a value replaces all values that are still pending, so the client always receives
+    /// the latest state of the characteristic, but may miss intermediate values. This is suitable
+    /// for values like sensor readings.
+    LatestOnly,
+
+    /// Every value is sent, in order.
+    ///
+    /// Pushing a value when the stream's buffer is full fails with `Error::TxBusy`. This is
+    /// suitable for values that form a stream of data, like MIDI events.
+    Lossless,
+}
+
+/// A buffer of outgoing notifications for a single characteristic value.
+///
+/// Calling `AttributeServerTx::notify_raw` in a loop either has to wait for the Link-Layer to free
+/// up space in the TX queue, or has to drop the values that don't fit. A `NotificationStream`
+/// instead buffers up to `N` values (each up to [`MAX_VALUE_LEN`] Bytes long) according to its
+/// [`StreamPolicy`], and sends them once [`drain`] is called. `drain` should be called whenever
+/// `Cmd::tx_space_freed` is set.
+///
+/// [`drain`]: #method.drain
+pub struct NotificationStream<const N: usize> {
+    handle: Handle,
+    policy: StreamPolicy,
+    /// Pending values, oldest first.
+    values: Vec<Value, N>,
+}
+
+impl<const N: usize> NotificationStream<N> {
+    /// Creates an empty stream sending notifications for the characteristic value at `handle`.
+    pub fn new(handle: Handle, policy: StreamPolicy) -> Self {
+        assert!(N > 0, "stream needs space for at least one value");
+        Self {
+            handle,
+            policy,
+            values: Vec::new(),
+        }
+    }
+
+    /// Returns the handle of the characteristic value this stream sends notifications for.
+    pub fn handle(&self) -> Handle {
+        self.handle
+    }
+
+    /// Returns the policy for pending values.
+    pub fn policy(&self) -> StreamPolicy {
+        self.policy
+    }
+
+    /// Returns the number of values that have not been sent yet.
+    pub fn pending(&self) -> usize {
+        self.values.len()
+    }
+
+    /// Returns `true` if all values pushed into the stream have been sent.
+    pub fn is_empty(&self) -> bool {
+        self.values.is_empty()
+    }
+
+    /// Discards all pending values.
+    pub fn clear(&mut self) {
+        self.values.clear();
+    }
+
+    /// Enqueues `value` to be sent as a notification.
+    ///
+    /// Returns `Error::InvalidLength` if `value` is longer than [`MAX_VALUE_LEN`], and
+    /// `Error::TxBusy` if the stream uses [`StreamPolicy::Lossless`] and its buffer is full.
+    ///
+    /// [`StreamPolicy::Lossless`]: enum.StreamPolicy.html#variant.Lossless
+    pub fn push(&mut self, value: &[u8]) -> Result<(), Error> {
+        let value = Value::from_slice(value).map_err(|_| Error::InvalidLength)?;
+        match self.policy {
+            StreamPolicy::LatestOnly => self.values.clear(),
+            StreamPolicy::Lossless => {
+                if self.values.is_full() {
+                    return Err(Error::TxBusy);
+                }
+            }
+        }
+        self.values.push(value).map_err(|_| Error::TxBusy)
+    }
+
+    /// Sends as many pending values as fit in the TX queue.
+    ///
+    /// Returns the number of notifications that were sent.
+    pub fn drain<M: ChannelMapper, P: Producer>(
+        &mut self,
+        l2cap: &mut L2CAPStateTx<'_, M, P>,
+    ) -> usize {
+        let mut sent = 0;
+        while let Some(value) = self.values.first() {
+            match l2cap.att() {
+                Some(att) => att.notify_raw(self.handle, value),
+                None => break,
+            }
+            self.values.rotate_left(1);
+            self.values.pop();
+            sent += 1;
+        }
+        if sent != 0 {
+            trace!("sent {} notifications for {:?}", sent, self.handle);
+        }
+        sent
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::att::NoAttributes;
+    use crate::l2cap::{BleChannelMap, L2CAPState};
+    use crate::link::queue::{Consume, Consumer, PacketQueue, SimpleQueue};
+
+    /// Removes the next notification from the queue and returns its value.
+    fn receive(rx: &mut impl Consumer) -> Option<Value> {
+        rx.consume_raw_with(|_, raw| {
+            // L2CAP header, ATT opcode and handle
+            assert_eq!(raw[4], 0x1B);
+            Consume::always(Ok(Value::from_slice(&raw[7..]).unwrap()))
+        })
+        .ok()
+    }
+
+    #[test]
+    fn lossless() {
+        let mut queue = SimpleQueue::new();
+        let (mut tx, mut rx) = (&mut queue).split();
+        let mut l2cap = L2CAPState::new(BleChannelMap::with_attributes(NoAttributes));
+        let mut stream = NotificationStream::<2>::new(Handle::from_raw(3), StreamPolicy::Lossless);
+
+        stream.push(&[1]).unwrap();
+        stream.push(&[2]).unwrap();
+        assert_eq!(stream.push(&[3]), Err(Error::TxBusy));
+        assert_eq!(
+            stream.push(&[0; MAX_VALUE_LEN + 1]),
+            Err(Error::InvalidLength)
+        );
+
+        // The queue only has space for a single packet
+        assert_eq!(stream.drain(&mut l2cap.tx(&mut tx)), 1);
+        assert_eq!(stream.pending(), 1);
+        assert_eq!(stream.drain(&mut l2cap.tx(&mut tx)), 0);
+        assert_eq!(&receive(&mut rx).unwrap()[..], &[1]);
+
+        assert_eq!(stream.drain(&mut l2cap.tx(&mut tx)), 1);
+        assert!(stream.is_empty());
+        assert_eq!(&receive(&mut rx).unwrap()[..], &[2]);
+        assert!(receive(&mut rx).is_none());
+    }
+
+    #[test]
+    fn latest_only() {
+        let mut queue = SimpleQueue::new();
+        let (mut tx, mut rx) = (&mut queue).split();
+        let mut l2cap = L2CAPState::new(BleChannelMap::with_attributes(NoAttributes));
+        let mut stream =
+            NotificationStream::<1>::new(Handle::from_raw(3), StreamPolicy::LatestOnly);
+
+        for i in 0..5 {
+            stream.push(&[i]).unwrap();
+        }
+        assert_eq!(stream.pending(), 1);
+        assert_eq!(stream.drain(&mut l2cap.tx(&mut tx)), 1);
+        assert_eq!(&receive(&mut rx).unwrap()[..], &[4]);
+        assert!(receive(&mut rx).is_none());
+    }
+}
@@ -0,0 +1,532 @@
+//! A firmware update (DFU) service.
+//!
+//! [`OtaService`] is an `AttributeProvider` hosting a simple object-transfer style firmware update
+//! service. The actual storage of the image is delegated to a [`FlashBackend`] implemented by the
+//! application.
+//!
+//! # Protocol
+//!
+//! The service consists of 2 characteristics:
+//!
+//! * The *Control Point* ([`CONTROL_POINT_UUID`]) accepts commands via write requests. Reading it
+//!   returns the [`OtaState`] as a single Byte, followed by the number of image Bytes received so
+//!   far as a little-endian `u32`.
+//! * The *Data* characteristic ([`DATA_UUID`]) receives the image in order, in chunks of arbitrary
+//!   size, via write requests or write commands.
+//!
+//! An update is performed as follows:
+//!
+//! 1. The client writes `START` (`0x01`), followed by the size of the image and its CRC-32 (both
+//!    little-endian `u32`s) to the control point. This calls `FlashBackend::begin`.
+//! 2. The client writes the image to the data characteristic. Data is collected into blocks of
+//!    `CHUNK` Bytes, which are passed to `FlashBackend::write`.
+//! 3. The client writes `FINISH` (`0x02`) to the control point. This writes the last partial block,
+//!    verifies the CRC of the received image and calls `FlashBackend::finish`. Writing `FINISH`
+//!    fails if the image is incomplete or corrupted.
+//!
+//! The transfer can be canceled at any time by writing `ABORT` (`0x03`) to the control point.
+//!
+//! [`OtaService`]: struct.OtaService.html
+//! [`FlashBackend`]: trait.FlashBackend.html
+//! [`OtaState`]: enum.OtaState.html
+//! [`CONTROL_POINT_UUID`]: constant.CONTROL_POINT_UUID.html
+//! [`DATA_UUID`]: constant.DATA_UUID.html
+
+use super::characteristic::Properties;
+use super::StaticAttributes;
+use crate::att::{
+    AttUuid, Attribute, AttributeAccessPermissions, AttributeProvider, Handle, HandleRange,
+};
+use crate::bytes::{ByteReader, ByteWriter};
+use crate::l2cap::Sender;
+use crate::link::Peer;
+use crate::uuid::Uuid128;
+use crate::Error;
+use heapless::Vec;
+
+/// UUID of the firmware update service.
+pub const SERVICE_UUID: Uuid128 = Uuid128::parse_static("9e5d1e47-5c13-43a0-8635-82ad38a1386f");
+
+/// UUID of the control point characteristic.
+pub const CONTROL_POINT_UUID: Uuid128 =
+    Uuid128::parse_static("e3dd50bf-f7a7-4e99-838e-570a086c666b");
+
+/// UUID of the data characteristic.
+pub const DATA_UUID: Uuid128 = Uuid128::parse_static("92e86c7a-d961-4091-b74f-2409e72efe36");
+
+/// Control point command starting a transfer.
+pub const CMD_START: u8 = 0x01;
+
+/// Control point command completing a transfer.
+pub const CMD_FINISH: u8 = 0x02;
+
+/// Control point command canceling a transfer.
+pub const CMD_ABORT: u8 = 0x03;
+
+/// Storage for firmware images received by an [`OtaService`].
+///
+/// This is implemented by the application, typically by writing to an unused flash bank that the
+/// bootloader picks up after a reset.
+///
+/// [`OtaService`]: struct.OtaService.html
+pub trait FlashBackend {
+    /// Returns the maximum size of an image in Bytes.
+    fn capacity(&self) -> u32;
+
+    /// Prepares the storage for receiving an image of `size` Bytes, eg. by erasing it.
+    ///
+    /// `size` is never larger than `capacity()`.
+    fn begin(&mut self, size: u32) -> Result<(), Error>;
+
+    /// Writes `data` at `offset` (relative to the start of the image).
+    ///
+    /// `offset` is always a multiple of the `OtaService`'s `CHUNK` size, and `data` is `CHUNK`
+    /// Bytes long, except for the last block of the image, which may be shorter.
+    fn write(&mut self, offset: u32, data: &[u8]) -> Result<(), Error>;
+
+    /// Called when an image of `size` Bytes has been received completely and its CRC was verified.
+    ///
+    /// This should mark the image as valid, so that the bootloader will apply it.
+    fn finish(&mut self, size: u32) -> Result<(), Error>;
+
+    /// Called when a transfer was aborted or failed.
+    ///
+    /// The default implementation does nothing.
+    fn abort(&mut self) {}
+}
+
+/// State of an [`OtaService`].
+///
+/// [`OtaService`]: struct.OtaService.html
+#[derive(Debug, Copy, Clone, PartialEq, Eq, defmt::Format)]
+pub enum OtaState {
+    /// No transfer is in progress.
+    Idle = 0,
+
+    /// An image is being received.
+    Receiving = 1,
+
+    /// An image was received and verified successfully.
+    Complete = 2,
+
+    /// The last transfer failed due to a CRC mismatch or a `FlashBackend` error.
+    Failed = 3,
+}
+
+/// A firmware update service, storing images in a [`FlashBackend`].
+///
+/// The service receives image data into a buffer of `CHUNK` Bytes, and passes it to the backend
+/// once it is full, so `CHUNK` should be a multiple of the flash's write granularity. See the
+/// [module documentation] for the protocol.
+///
+/// To expose other services alongside this one, the application can wrap it in its own
+/// `AttributeProvider` and forward requests for handles in `handle_range` to it.
+///
+/// [`FlashBackend`]: trait.FlashBackend.html
+/// [module documentation]: index.html
+pub struct OtaService<F: FlashBackend, const CHUNK: usize> {
+    attributes: StaticAttributes<5>,
+    control: Handle,
+    data: Handle,
+    flash: F,
+    state: OtaState,
+    /// Size of the image being received.
+    size: u32,
+    /// CRC-32 of the image, as announced by the client.
+    expected_crc: u32,
+    /// CRC-32 of the Bytes received so far (without the final XOR).
+    crc: u32,
+    /// Number of image Bytes received so far.
+    received: u32,
+    /// Number of image Bytes written to `flash` so far.
+    written: u32,
+    /// Received Bytes that haven't been written to `flash` yet.
+    chunk: Vec<u8, CHUNK>,
+}
+
+impl<F: FlashBackend, const CHUNK: usize> OtaService<F, CHUNK> {
+    /// Creates the service, storing images in `flash`.
+    pub fn new(flash: F) -> Self {
+        assert!(CHUNK > 0, "chunk size must not be 0");
+
+        let mut attributes = StaticAttributes::new();
+        attributes
+            .push_primary_service(SERVICE_UUID.into())
+            .unwrap();
+        let control = attributes
+            .push_characteristic(
+                CONTROL_POINT_UUID.into(),
+                Properties::READ | Properties::WRITE,
+                &[],
+                AttributeAccessPermissions::ReadableAndWriteable,
+            )
+            .unwrap();
+        let data = attributes
+            .push_characteristic(
+                DATA_UUID.into(),
+                Properties::WRITE | Properties::WRITE_NO_RSP,
+                &[],
+                AttributeAccessPermissions::Writeable,
+            )
+            .unwrap();
+
+        Self {
+            attributes,
+            control,
+            data,
+            flash,
+            state: OtaState::Idle,
+            size: 0,
+            expected_crc: 0,
+            crc: CRC_INIT,
+            received: 0,
+            written: 0,
+            chunk: Vec::new(),
+        }
+    }
+
+    /// Returns the range of handles used by the service.
+    pub fn handle_range(&self) -> HandleRange {
+        HandleRange::new(Handle::from_raw(0x0001), self.data)
+    }
+
+    /// Returns the handle of the control point characteristic value.
+    pub fn control_point_handle(&self) -> Handle {
+        self.control
+    }
+
+    /// Returns the handle of the data characteristic value.
+    pub fn data_handle(&self) -> Handle {
+        self.data
+    }
+
+    /// Returns the state of the current or last transfer.
+    pub fn state(&self) -> OtaState {
+        self.state
+    }
+
+    /// Returns the number of image Bytes received in the current or last transfer.
+    pub fn received(&self) -> u32 {
+        self.received
+    }
+
+    /// Returns a reference to the flash backend.
+    pub fn flash(&self) -> &F {
+        &self.flash
+    }
+
+    /// Returns a mutable reference to the flash backend.
+    pub fn flash_mut(&mut self) -> &mut F {
+        &mut self.flash
+    }
+
+    fn command(&mut self, cmd: &[u8]) -> Result<(), Error> {
+        let mut bytes = ByteReader::new(cmd);
+        match bytes.read_u8()? {
+            CMD_START => {
+                let size = bytes.read_u32_le()?;
+                let crc = bytes.read_u32_le()?;
+                if !bytes.is_empty() {
+                    return Err(Error::InvalidLength);
+                }
+                if size == 0 || size > self.flash.capacity() {
+                    return Err(Error::InvalidValue);
+                }
+                if self.state == OtaState::Receiving {
+                    self.flash.abort();
+                }
+
+                self.size = size;
+                self.expected_crc = crc;
+                self.crc = CRC_INIT;
+                self.received = 0;
+                self.written = 0;
+                self.chunk.clear();
+                self.state = OtaState::Receiving;
+                let result = self.flash.begin(size);
+                self.check(result)
+            }
+            CMD_FINISH => {
+                if self.state != OtaState::Receiving || self.received != self.size {
+                    return Err(Error::InvalidValue);
+                }
+
+                self.flush()?;
+                let crc = !self.crc;
+                if crc != self.expected_crc {
+                    warn!("OTA image CRC mismatch");
+                    return self.check(Err(Error::InvalidValue));
+                }
+                let result = self.flash.finish(self.size);
+                self.check(result)?;
+                self.state = OtaState::Complete;
+                info!("OTA image of {} Bytes received", self.size);
+                Ok(())
+            }
+            CMD_ABORT => {
+                if self.state == OtaState::Receiving {
+                    self.flash.abort();
+                }
+                self.state = OtaState::Idle;
+                Ok(())
+            }
+            _ => Err(Error::InvalidValue),
+        }
+    }
+
+    fn write_data(&mut self, mut data: &[u8]) -> Result<(), Error> {
+        if self.state != OtaState::Receiving {
+            return Err(Error::InvalidValue);
+        }
+        if data.len() as u32 > self.size - self.received {
+            return Err(Error::InvalidLength);
+        }
+
+        self.crc = crc32_update(self.crc, data);
+        self.received += data.len() as u32;
+        while !data.is_empty() {
+            let len = data.len().min(CHUNK - self.chunk.len());
+            self.chunk.extend_from_slice(&data[..len]).unwrap();
+            data = &data[len..];
+            if self.chunk.is_full() {
+                self.flush()?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes the buffered data to flash.
+    fn flush(&mut self) -> Result<(), Error> {
+        if self.chunk.is_empty() {
+            return Ok(());
+        }
+
+        let result = self.flash.write(self.written, &self.chunk);
+        self.written += self.chunk.len() as u32;
+        self.chunk.clear();
+        self.check(result)
+    }
+
+    /// Marks the transfer as failed if `result` is an error.
+    fn check(&mut self, result: Result<(), Error>) -> Result<(), Error> {
+        if result.is_err() {
+            self.flash.abort();
+            self.state = OtaState::Failed;
+        }
+        result
+    }
+}
+
+impl<F: FlashBackend, const CHUNK: usize> AttributeProvider for OtaService<F, CHUNK> {
+    fn for_attrs_in_range(
+        &mut self,
+        range: HandleRange,
+        mut f: impl FnMut(&Self, &Attribute<dyn AsRef<[u8]>>) -> Result<(), Error>,
+    ) -> Result<(), Error> {
+        let this = &*self;
+        this.attributes
+            .for_each_in_range(range, |attr| f(this, attr))
+    }
+
+    fn is_grouping_attr(&self, uuid: AttUuid) -> bool {
+        self.attributes.is_grouping_attr(uuid)
+    }
+
+    fn group_end(&self, handle: Handle) -> Option<&Attribute<dyn AsRef<[u8]>>> {
+        self.attributes.group_end(handle)
+    }
+
+    fn attr_access_permissions(&self, handle: Handle) -> AttributeAccessPermissions {
+        self.attributes.attr_access_permissions(handle)
+    }
+
+    fn write_attr(
+        &mut self,
+        handle: Handle,
+        data: &[u8],
+        _peer: Option<&Peer>,
+    ) -> Result<(), Error> {
+        if handle == self.control {
+            self.command(data)
+        } else if handle == self.data {
+            self.write_data(data)
+        } else {
+            Err(Error::InvalidValue)
+        }
+    }
+
+    fn read_attr_dynamic(&mut self, handle: Handle, buffer: &mut [u8]) -> Option<usize> {
+        if handle != self.control {
+            return None;
+        }
+
+        let mut writer = ByteWriter::new(buffer);
+        let space = writer.space_left();
+        writer.write_u8(self.state as u8).ok()?;
+        writer.write_u32_le(self.received).ok()?;
+        Some(space - writer.space_left())
+    }
+
+    fn find_information(
+        &mut self,
+        range: HandleRange,
+        responder: &mut Sender<'_>,
+    ) -> Result<(), Error> {
+        self.attributes.find_information(range, responder)
+    }
+}
+
+/// Initial value of the CRC-32 shift register.
+const CRC_INIT: u32 = 0xFFFF_FFFF;
+
+/// Feeds `data` into the CRC-32 (IEEE 802.3) register `crc`.
+fn crc32_update(mut crc: u32, data: &[u8]) -> u32 {
+    for &byte in data {
+        crc ^= u32::from(byte);
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    crc
+}
+
+/// Computes the CRC-32 (IEEE 802.3, as used by zlib) of `data`.
+///
+/// This is the checksum a client has to send along with the `START` command.
+pub fn crc32(data: &[u8]) -> u32 {
+    !crc32_update(CRC_INIT, data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::vec::Vec as StdVec;
+
+    #[derive(Default)]
+    struct RamFlash {
+        image: StdVec<u8>,
+        writes: StdVec<(u32, usize)>,
+        finished: bool,
+        aborted: bool,
+    }
+
+    impl FlashBackend for RamFlash {
+        fn capacity(&self) -> u32 {
+            1024
+        }
+
+        fn begin(&mut self, size: u32) -> Result<(), Error> {
+            self.image = vec![0xFF; size as usize];
+            Ok(())
+        }
+
+        fn write(&mut self, offset: u32, data: &[u8]) -> Result<(), Error> {
+            let offset = offset as usize;
+            self.image[offset..offset + data.len()].copy_from_slice(data);
+            self.writes.push((offset as u32, data.len()));
+            Ok(())
+        }
+
+        fn finish(&mut self, _size: u32) -> Result<(), Error> {
+            self.finished = true;
+            Ok(())
+        }
+
+        fn abort(&mut self) {
+            self.aborted = true;
+        }
+    }
+
+    fn start(image: &[u8]) -> [u8; 9] {
+        let mut cmd = [CMD_START; 9];
+        cmd[1..5].copy_from_slice(&(image.len() as u32).to_le_bytes());
+        cmd[5..].copy_from_slice(&crc32(image).to_le_bytes());
+        cmd
+    }
+
+    #[test]
+    fn crc() {
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+        assert_eq!(crc32(&[]), 0);
+    }
+
+    #[test]
+    fn transfer() {
+        let image: StdVec<u8> = (0..100).collect();
+        let mut ota = OtaService::<_, 16>::new(RamFlash::default());
+        let (control, data) = (ota.control_point_handle(), ota.data_handle());
+
+        assert_eq!(
+            ota.write_attr(data, &image[..10], None),
+            Err(Error::InvalidValue)
+        );
+        ota.write_attr(control, &start(&image), None).unwrap();
+        assert_eq!(ota.state(), OtaState::Receiving);
+
+        for chunk in image.chunks(20) {
+            ota.write_attr(data, chunk, None).unwrap();
+        }
+        assert_eq!(ota.write_attr(data, &[0], None), Err(Error::InvalidLength));
+        assert_eq!(ota.received(), 100);
+
+        let mut buf = [0; 8];
+        assert_eq!(ota.read_attr_dynamic(control, &mut buf), Some(5));
+        assert_eq!(buf[..5], [1, 100, 0, 0, 0]);
+
+        ota.write_attr(control, &[CMD_FINISH], None).unwrap();
+        assert_eq!(ota.state(), OtaState::Complete);
+        assert!(ota.flash().finished);
+        assert_eq!(ota.flash().image, image);
+        // Blocks are aligned to the chunk size
+        assert!(ota
+            .flash()
+            .writes
+            .iter()
+            .all(|(offset, _)| offset % 16 == 0));
+        assert_eq!(ota.flash().writes.last(), Some(&(96, 4)));
+    }
+
+    #[test]
+    fn crc_mismatch() {
+        let image = [1, 2, 3, 4, 5];
+        let mut ota = OtaService::<_, 4>::new(RamFlash::default());
+        let (control, data) = (ota.control_point_handle(), ota.data_handle());
+
+        ota.write_attr(control, &start(&image), None).unwrap();
+        assert_eq!(
+            ota.write_attr(control, &[CMD_FINISH], None),
+            Err(Error::InvalidValue),
+            "image incomplete"
+        );
+        ota.write_attr(data, &[1, 2, 3, 4, 6], None).unwrap();
+        assert_eq!(
+            ota.write_attr(control, &[CMD_FINISH], None),
+            Err(Error::InvalidValue)
+        );
+        assert_eq!(ota.state(), OtaState::Failed);
+        assert!(ota.flash().aborted);
+        assert!(!ota.flash().finished);
+    }
+
+    #[test]
+    fn invalid_start() {
+        let mut ota = OtaService::<_, 4>::new(RamFlash::default());
+        let control = ota.control_point_handle();
+        let mut cmd = start(&[0; 2048]);
+        assert_eq!(
+            ota.write_attr(control, &cmd, None),
+            Err(Error::InvalidValue)
+        );
+        cmd[1..5].copy_from_slice(&0u32.to_le_bytes());
+        assert_eq!(
+            ota.write_attr(control, &cmd, None),
+            Err(Error::InvalidValue)
+        );
+        assert_eq!(ota.write_attr(control, &cmd[..5], None), Err(Error::Eof));
+        assert_eq!(ota.state(), OtaState::Idle);
+    }
+}
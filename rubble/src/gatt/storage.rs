@@ -0,0 +1,984 @@
+//! Generic, fixed-capacity `AttributeProvider`s.
+
+use super::characteristic::Properties;
+use crate::att::{
+    AttPdu, AttUuid, Attribute, AttributeAccessPermissions, AttributeProvider, ErrorCode, Handle,
+    HandleRange, Opcode,
+};
+use crate::bytes::{ByteWriter, ToBytes};
+use crate::l2cap::Sender;
+use crate::link::Peer;
+use crate::uuid::consts::{characteristics, declarations};
+use crate::Error;
+use heapless::Vec;
+
+/// Maximum length of an attribute value stored in [`StaticAttributes`] and
+/// [`DynamicAttributes`].
+///
+/// [`StaticAttributes`]: struct.StaticAttributes.html
+/// [`DynamicAttributes`]: struct.DynamicAttributes.html
+pub const MAX_VALUE_LEN: usize = 64;
+
+type Value = Vec<u8, MAX_VALUE_LEN>;
+
+struct Entry {
+    attr: Attribute<Value>,
+    permissions: AttributeAccessPermissions,
+}
+
+impl Entry {
+    fn new(
+        att_type: AttUuid,
+        handle: Handle,
+        value: &[u8],
+        permissions: AttributeAccessPermissions,
+    ) -> Result<Self, Error> {
+        let value = Value::from_slice(value).map_err(|()| Error::InvalidLength)?;
+        Ok(Self {
+            attr: Attribute::new(att_type, handle, value),
+            permissions,
+        })
+    }
+
+    fn handle(&self) -> u16 {
+        self.attr.handle.as_u16()
+    }
+
+    fn is_service(&self) -> bool {
+        is_service(self.attr.att_type)
+    }
+}
+
+/// An `AttributeProvider` that owns up to `N` attributes.
+///
+/// Attributes are added in order using the `push` family of methods, and are assigned ascending
+/// handles starting at `0x0001`. Every attribute value is stored inline and may be up to
+/// [`MAX_VALUE_LEN`] Bytes long. Values can be changed at runtime, either by the application via
+/// `set_value` or by the client via write requests, if the attribute's permissions allow that.
+///
+/// Service groups are inferred from the attribute list: A service declaration starts a group,
+/// which extends to the attribute preceding the next service declaration (or to the last
+/// attribute).
+///
+/// [`MAX_VALUE_LEN`]: constant.MAX_VALUE_LEN.html
+pub struct StaticAttributes<const N: usize> {
+    entries: Vec<Entry, N>,
+    /// Handle and value of a pending queued write.
+    prepared: Option<(Handle, Value)>,
+}
+
+impl<const N: usize> StaticAttributes<N> {
+    /// Creates an empty attribute list.
+    pub const fn new() -> Self {
+        Self {
+            entries: Vec::new(),
+            prepared: None,
+        }
+    }
+
+    /// Appends an attribute and returns its handle.
+    ///
+    /// Returns `Error::Eof` if all `N` attributes are already in use, and `Error::InvalidLength`
+    /// if `value` is longer than `MAX_VALUE_LEN`.
+    pub fn push(
+        &mut self,
+        att_type: AttUuid,
+        value: &[u8],
+        permissions: AttributeAccessPermissions,
+    ) -> Result<Handle, Error> {
+        let handle = Handle::from_raw(self.entries.len() as u16 + 1);
+        push(&mut self.entries, att_type, handle, value, permissions)
+    }
+
+    /// Appends a read-only *Primary Service* declaration for the service identified by `uuid`.
+    pub fn push_primary_service(&mut self, uuid: AttUuid) -> Result<Handle, Error> {
+        let handle = Handle::from_raw(self.entries.len() as u16 + 1);
+        push_service(&mut self.entries, handle, uuid)
+    }
+
+    /// Appends a *Characteristic* declaration followed by the characteristic value.
+    ///
+    /// Returns the handle of the characteristic value attribute.
+    pub fn push_characteristic(
+        &mut self,
+        uuid: AttUuid,
+        properties: Properties,
+        value: &[u8],
+        permissions: AttributeAccessPermissions,
+    ) -> Result<Handle, Error> {
+        let handle = self.entries.len() as u16 + 1;
+        push_characteristic(
+            &mut self.entries,
+            handle,
+            uuid,
+            properties,
+            value,
+            permissions,
+        )
+    }
+
+    /// Returns the current value of the attribute at `handle`.
+    pub fn value(&self, handle: Handle) -> Option<&[u8]> {
+        value(&self.entries, handle)
+    }
+
+    /// Changes the value of the attribute at `handle`.
+    ///
+    /// This does not notify the client of the change.
+    pub fn set_value(&mut self, handle: Handle, value: &[u8]) -> Result<(), Error> {
+        set_value(&mut self.entries, handle, value)
+    }
+
+    /// Calls `f` with every attribute whose handle is inside `range`, ascending.
+    ///
+    /// This is the closure-only version of `AttributeProvider::for_attrs_in_range`, which makes it
+    /// easy to forward to from a wrapping provider.
+    pub fn for_each_in_range(
+        &self,
+        range: HandleRange,
+        f: impl FnMut(&Attribute<dyn AsRef<[u8]>>) -> Result<(), Error>,
+    ) -> Result<(), Error> {
+        for_each_in_range(&self.entries, &range, f)
+    }
+}
+
+impl<const N: usize> Default for StaticAttributes<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N: usize> AttributeProvider for StaticAttributes<N> {
+    const QUEUED_WRITES: bool = true;
+
+    fn for_attrs_in_range(
+        &mut self,
+        range: HandleRange,
+        mut f: impl FnMut(&Self, &Attribute<dyn AsRef<[u8]>>) -> Result<(), Error>,
+    ) -> Result<(), Error> {
+        let this = &*self;
+        this.for_each_in_range(range, |attr| f(this, attr))
+    }
+
+    fn is_grouping_attr(&self, uuid: AttUuid) -> bool {
+        is_service(uuid)
+    }
+
+    fn group_end(&self, handle: Handle) -> Option<&Attribute<dyn AsRef<[u8]>>> {
+        group_end(&self.entries, handle)
+    }
+
+    fn attr_access_permissions(&self, handle: Handle) -> AttributeAccessPermissions {
+        permissions(&self.entries, handle)
+    }
+
+    fn write_attr(
+        &mut self,
+        handle: Handle,
+        data: &[u8],
+        _peer: Option<&Peer>,
+    ) -> Result<(), Error> {
+        self.set_value(handle, data)
+    }
+
+    fn prepare_write_attr(
+        &mut self,
+        handle: Handle,
+        offset: u16,
+        data: &[u8],
+    ) -> Result<(), Error> {
+        prepare_write(&self.entries, &mut self.prepared, handle, offset, data)
+    }
+
+    fn execute_write_attr(&mut self, flags: u8) -> Result<(), Error> {
+        execute_write(&mut self.entries, &mut self.prepared, flags)
+    }
+
+    fn find_information(
+        &mut self,
+        range: HandleRange,
+        responder: &mut Sender<'_>,
+    ) -> Result<(), Error> {
+        find_information(&self.entries, range, responder)
+    }
+}
+
+/// An `AttributeProvider` holding up to `N` attributes, which allows adding and removing whole
+/// services at runtime.
+///
+/// This is useful for applications that only expose some services under certain conditions, for
+/// example a firmware update service that only becomes available after authentication.
+///
+/// Unlike [`StaticAttributes`], the attribute handles are not required to be contiguous, so that
+/// removing a service doesn't change the handles of the remaining attributes. Services are always
+/// inserted as a whole, and the table validates that they don't overlap existing services.
+///
+/// Clients may cache the attribute table, so they have to be informed about changes to it. If the
+/// table contains a *Service Changed* characteristic (as part of the *Generic Attribute* service),
+/// [`service_changed`] returns the handle range affected by modifications since the last call,
+/// which the application should then send to the client as an indication:
+///
+/// ```ignore
+/// if let Some(mut att) = l2cap_tx.att() {
+///     if let Some((handle, value)) = att.provider().service_changed() {
+///         att.indicate_raw(handle, &value);
+///     }
+/// }
+/// ```
+///
+/// [`StaticAttributes`]: struct.StaticAttributes.html
+/// [`service_changed`]: #method.service_changed
+pub struct DynamicAttributes<const N: usize> {
+    /// Attributes, sorted by handle.
+    entries: Vec<Entry, N>,
+    /// Handle and value of a pending queued write.
+    prepared: Option<(Handle, Value)>,
+    /// Range of handles modified since the last *Service Changed* indication.
+    changed: Option<(u16, u16)>,
+}
+
+impl<const N: usize> DynamicAttributes<N> {
+    /// Creates an empty attribute table.
+    pub const fn new() -> Self {
+        Self {
+            entries: Vec::new(),
+            prepared: None,
+            changed: None,
+        }
+    }
+
+    /// Returns the handle following the last attribute in the table.
+    ///
+    /// Returns `None` if the last attribute uses the highest possible handle.
+    pub fn next_free_handle(&self) -> Option<Handle> {
+        match self.entries.last() {
+            Some(entry) => entry.handle().checked_add(1).map(Handle::from_raw),
+            None => Some(Handle::from_raw(0x0001)),
+        }
+    }
+
+    /// Inserts a *Primary Service* identified by `uuid`, starting at handle `start`.
+    ///
+    /// The service declaration is placed at `start`, and the closure `f` can add the service's
+    /// characteristics and descriptors using the provided [`ServiceBuilder`]. They are assigned
+    /// ascending handles following `start`.
+    ///
+    /// If `f` returns an error or the service would overlap an existing one, the table is left
+    /// unchanged and the error is returned. `Error::InvalidValue` is returned if the handles are
+    /// already in use, and `Error::Eof` if there is no space left in the table.
+    ///
+    /// On success, returns the handle range of the new service.
+    ///
+    /// [`ServiceBuilder`]: struct.ServiceBuilder.html
+    pub fn insert_service(
+        &mut self,
+        start: Handle,
+        uuid: AttUuid,
+        f: impl FnOnce(&mut ServiceBuilder<'_, N>) -> Result<(), Error>,
+    ) -> Result<HandleRange, Error> {
+        if start == Handle::NULL {
+            return Err(Error::InvalidValue);
+        }
+
+        // The service must go between two existing services
+        let index = self
+            .entries
+            .partition_point(|e| e.handle() < start.as_u16());
+        let next = match self.entries.get(index) {
+            Some(entry) if entry.is_service() && entry.handle() != start.as_u16() => {
+                Some(entry.handle())
+            }
+            Some(_) => return Err(Error::InvalidValue),
+            None => None,
+        };
+
+        // Build the service at the end of the list, then move it into place
+        let old_len = self.entries.len();
+        let result = push_service(&mut self.entries, start, uuid).and_then(|_| {
+            f(&mut ServiceBuilder {
+                entries: &mut self.entries,
+                next: start.as_u16().checked_add(1),
+            })
+        });
+        let end = self.entries.last().map_or(0, |entry| entry.handle());
+        let result = match (result, next) {
+            (Ok(()), Some(next)) if end >= next => Err(Error::InvalidValue),
+            (result, _) => result,
+        };
+        if let Err(e) = result {
+            self.entries.truncate(old_len);
+            return Err(e);
+        }
+
+        let count = self.entries.len() - old_len;
+        self.entries[index..].rotate_right(count);
+        self.mark_changed(start.as_u16(), end);
+        Ok(HandleRange::new(start, Handle::from_raw(end)))
+    }
+
+    /// Appends a *Primary Service* after the last attribute in the table.
+    ///
+    /// This works like [`insert_service`], but picks the start handle automatically.
+    ///
+    /// [`insert_service`]: #method.insert_service
+    pub fn push_service(
+        &mut self,
+        uuid: AttUuid,
+        f: impl FnOnce(&mut ServiceBuilder<'_, N>) -> Result<(), Error>,
+    ) -> Result<HandleRange, Error> {
+        let start = self.next_free_handle().ok_or(Error::Eof)?;
+        self.insert_service(start, uuid, f)
+    }
+
+    /// Removes the service whose declaration is at `handle`, including all of its attributes.
+    ///
+    /// Returns the handle range of the removed service, or `Error::InvalidValue` if `handle` does
+    /// not refer to a service declaration.
+    pub fn remove_service(&mut self, handle: Handle) -> Result<HandleRange, Error> {
+        let start = find(&self.entries, handle).ok_or(Error::InvalidValue)?;
+        if !self.entries[start].is_service() {
+            return Err(Error::InvalidValue);
+        }
+
+        let end = group_end_index(&self.entries, start);
+        let range = HandleRange::new(handle, self.entries[end].attr.handle);
+        if let Some((prepared, _)) = &self.prepared {
+            if range.contains(*prepared) {
+                self.prepared = None;
+            }
+        }
+
+        let count = end - start + 1;
+        self.entries[start..].rotate_left(count);
+        self.entries.truncate(self.entries.len() - count);
+        self.mark_changed(handle.as_u16(), range.end().as_u16());
+        Ok(range)
+    }
+
+    /// Returns the current value of the attribute at `handle`.
+    pub fn value(&self, handle: Handle) -> Option<&[u8]> {
+        value(&self.entries, handle)
+    }
+
+    /// Changes the value of the attribute at `handle`.
+    ///
+    /// This does not notify the client of the change.
+    pub fn set_value(&mut self, handle: Handle, value: &[u8]) -> Result<(), Error> {
+        set_value(&mut self.entries, handle, value)
+    }
+
+    /// Calls `f` with every attribute whose handle is inside `range`, ascending.
+    pub fn for_each_in_range(
+        &self,
+        range: HandleRange,
+        f: impl FnMut(&Attribute<dyn AsRef<[u8]>>) -> Result<(), Error>,
+    ) -> Result<(), Error> {
+        for_each_in_range(&self.entries, &range, f)
+    }
+
+    /// Prepares a *Service Changed* indication covering all services inserted or removed since
+    /// the last call.
+    ///
+    /// Returns the handle of the *Service Changed* characteristic value and the value to indicate
+    /// (the affected start and end handle), or `None` if the table wasn't modified or doesn't
+    /// contain a *Service Changed* characteristic.
+    pub fn service_changed(&mut self) -> Option<(Handle, [u8; 4])> {
+        let entry = self
+            .entries
+            .iter_mut()
+            .find(|entry| entry.attr.att_type == characteristics::SERVICE_CHANGED)?;
+        let (start, end) = self.changed.take()?;
+
+        let mut value = [0; 4];
+        value[..2].copy_from_slice(&start.to_le_bytes());
+        value[2..].copy_from_slice(&end.to_le_bytes());
+        entry.attr.set_value(Value::from_slice(&value).unwrap());
+        Some((entry.attr.handle, value))
+    }
+
+    fn mark_changed(&mut self, start: u16, end: u16) {
+        self.changed = Some(match self.changed {
+            Some((s, e)) => (s.min(start), e.max(end)),
+            None => (start, end),
+        });
+    }
+}
+
+impl<const N: usize> Default for DynamicAttributes<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N: usize> AttributeProvider for DynamicAttributes<N> {
+    const QUEUED_WRITES: bool = true;
+
+    fn for_attrs_in_range(
+        &mut self,
+        range: HandleRange,
+        mut f: impl FnMut(&Self, &Attribute<dyn AsRef<[u8]>>) -> Result<(), Error>,
+    ) -> Result<(), Error> {
+        let this = &*self;
+        this.for_each_in_range(range, |attr| f(this, attr))
+    }
+
+    fn is_grouping_attr(&self, uuid: AttUuid) -> bool {
+        is_service(uuid)
+    }
+
+    fn group_end(&self, handle: Handle) -> Option<&Attribute<dyn AsRef<[u8]>>> {
+        group_end(&self.entries, handle)
+    }
+
+    fn attr_access_permissions(&self, handle: Handle) -> AttributeAccessPermissions {
+        permissions(&self.entries, handle)
+    }
+
+    fn write_attr(
+        &mut self,
+        handle: Handle,
+        data: &[u8],
+        _peer: Option<&Peer>,
+    ) -> Result<(), Error> {
+        self.set_value(handle, data)
+    }
+
+    fn prepare_write_attr(
+        &mut self,
+        handle: Handle,
+        offset: u16,
+        data: &[u8],
+    ) -> Result<(), Error> {
+        prepare_write(&self.entries, &mut self.prepared, handle, offset, data)
+    }
+
+    fn execute_write_attr(&mut self, flags: u8) -> Result<(), Error> {
+        execute_write(&mut self.entries, &mut self.prepared, flags)
+    }
+
+    fn find_information(
+        &mut self,
+        range: HandleRange,
+        responder: &mut Sender<'_>,
+    ) -> Result<(), Error> {
+        find_information(&self.entries, range, responder)
+    }
+}
+
+/// Adds characteristics and descriptors to a service that is being inserted into a
+/// [`DynamicAttributes`] table.
+///
+/// [`DynamicAttributes`]: struct.DynamicAttributes.html
+pub struct ServiceBuilder<'a, const N: usize> {
+    entries: &'a mut Vec<Entry, N>,
+    /// The next free handle, or `None` if the handle space is exhausted.
+    next: Option<u16>,
+}
+
+impl<const N: usize> ServiceBuilder<'_, N> {
+    /// Adds a *Characteristic* declaration followed by the characteristic value.
+    ///
+    /// Returns the handle of the characteristic value attribute.
+    pub fn characteristic(
+        &mut self,
+        uuid: AttUuid,
+        properties: Properties,
+        value: &[u8],
+        permissions: AttributeAccessPermissions,
+    ) -> Result<Handle, Error> {
+        let handle = self.next.filter(|&h| h < 0xFFFF).ok_or(Error::Eof)?;
+        let value_handle =
+            push_characteristic(self.entries, handle, uuid, properties, value, permissions)?;
+        self.next = value_handle.as_u16().checked_add(1);
+        Ok(value_handle)
+    }
+
+    /// Adds an attribute (usually a characteristic descriptor) and returns its handle.
+    pub fn attribute(
+        &mut self,
+        att_type: AttUuid,
+        value: &[u8],
+        permissions: AttributeAccessPermissions,
+    ) -> Result<Handle, Error> {
+        let handle = Handle::from_raw(self.next.ok_or(Error::Eof)?);
+        push(self.entries, att_type, handle, value, permissions)?;
+        self.next = handle.as_u16().checked_add(1);
+        Ok(handle)
+    }
+}
+
+fn push<const N: usize>(
+    entries: &mut Vec<Entry, N>,
+    att_type: AttUuid,
+    handle: Handle,
+    value: &[u8],
+    permissions: AttributeAccessPermissions,
+) -> Result<Handle, Error> {
+    let entry = Entry::new(att_type, handle, value, permissions)?;
+    entries.push(entry).map_err(|_| Error::Eof)?;
+    Ok(handle)
+}
+
+fn push_service<const N: usize>(
+    entries: &mut Vec<Entry, N>,
+    handle: Handle,
+    uuid: AttUuid,
+) -> Result<Handle, Error> {
+    let mut buf = [0; 16];
+    let len = encode_uuid(uuid, &mut buf)?;
+    push(
+        entries,
+        declarations::PRIMARY_SERVICE.into(),
+        handle,
+        &buf[..len],
+        AttributeAccessPermissions::Readable,
+    )
+}
+
+/// Pushes a characteristic declaration at `handle`, followed by its value.
+fn push_characteristic<const N: usize>(
+    entries: &mut Vec<Entry, N>,
+    handle: u16,
+    uuid: AttUuid,
+    properties: Properties,
+    value: &[u8],
+    permissions: AttributeAccessPermissions,
+) -> Result<Handle, Error> {
+    if entries.len() + 2 > N {
+        return Err(Error::Eof);
+    }
+
+    // The value attribute directly follows the declaration
+    let value_handle = handle + 1;
+    let mut decl = [0; 19];
+    decl[0] = properties.bits();
+    decl[1..3].copy_from_slice(&value_handle.to_le_bytes());
+    let len = encode_uuid(uuid, &mut decl[3..])?;
+    push(
+        entries,
+        declarations::CHARACTERISTIC.into(),
+        Handle::from_raw(handle),
+        &decl[..3 + len],
+        AttributeAccessPermissions::Readable,
+    )?;
+    push(
+        entries,
+        uuid,
+        Handle::from_raw(value_handle),
+        value,
+        permissions,
+    )
+}
+
+/// Returns the index of the attribute at `handle` in the sorted `entries`.
+fn find(entries: &[Entry], handle: Handle) -> Option<usize> {
+    entries
+        .binary_search_by_key(&handle.as_u16(), Entry::handle)
+        .ok()
+}
+
+fn value(entries: &[Entry], handle: Handle) -> Option<&[u8]> {
+    find(entries, handle).map(|index| entries[index].attr.value())
+}
+
+fn set_value(entries: &mut [Entry], handle: Handle, value: &[u8]) -> Result<(), Error> {
+    let value = Value::from_slice(value).map_err(|()| Error::InvalidLength)?;
+    let index = find(entries, handle).ok_or(Error::InvalidValue)?;
+    entries[index].attr.set_value(value);
+    Ok(())
+}
+
+fn permissions(entries: &[Entry], handle: Handle) -> AttributeAccessPermissions {
+    find(entries, handle).map_or(AttributeAccessPermissions::Readable, |index| {
+        entries[index].permissions
+    })
+}
+
+fn entries_in_range<'a>(entries: &'a [Entry], range: &HandleRange) -> &'a [Entry] {
+    let start = entries.partition_point(|e| e.handle() < range.start().as_u16());
+    let end = entries.partition_point(|e| e.handle() <= range.end().as_u16());
+    &entries[start..end]
+}
+
+fn for_each_in_range(
+    entries: &[Entry],
+    range: &HandleRange,
+    mut f: impl FnMut(&Attribute<dyn AsRef<[u8]>>) -> Result<(), Error>,
+) -> Result<(), Error> {
+    for entry in entries_in_range(entries, range) {
+        f(&entry.attr)?;
+    }
+    Ok(())
+}
+
+/// Returns the index of the last attribute in the service starting at index `start`.
+fn group_end_index(entries: &[Entry], start: usize) -> usize {
+    entries[start + 1..]
+        .iter()
+        .position(Entry::is_service)
+        .map_or(entries.len() - 1, |next| start + next)
+}
+
+fn group_end(entries: &[Entry], handle: Handle) -> Option<&Attribute<dyn AsRef<[u8]>>> {
+    let start = find(entries, handle)?;
+    if !entries[start].is_service() {
+        return None;
+    }
+
+    Some(&entries[group_end_index(entries, start)].attr)
+}
+
+fn prepare_write(
+    entries: &[Entry],
+    prepared: &mut Option<(Handle, Value)>,
+    handle: Handle,
+    offset: u16,
+    data: &[u8],
+) -> Result<(), Error> {
+    // Only a single attribute can be written with queued writes at a time
+    let value = match prepared {
+        Some((prepared, value)) if *prepared == handle => value,
+        Some(_) => return Err(Error::InvalidValue),
+        None => {
+            let value = Value::from_slice(value(entries, handle).ok_or(Error::InvalidValue)?)
+                .map_err(|()| Error::InvalidLength)?;
+            &mut prepared.get_or_insert((handle, value)).1
+        }
+    };
+
+    let offset = usize::from(offset);
+    if offset > value.len() {
+        return Err(Error::InvalidValue);
+    }
+    value.truncate(offset);
+    value
+        .extend_from_slice(data)
+        .map_err(|()| Error::InvalidLength)
+}
+
+fn execute_write(
+    entries: &mut [Entry],
+    prepared: &mut Option<(Handle, Value)>,
+    flags: u8,
+) -> Result<(), Error> {
+    // Flags: 0x00 cancels all prepared writes, 0x01 writes all pending values
+    match (prepared.take(), flags) {
+        (Some((handle, value)), 0x01) => set_value(entries, handle, &value),
+        (_, 0x00) | (None, 0x01) => Ok(()),
+        _ => Err(Error::InvalidValue),
+    }
+}
+
+fn find_information(
+    entries: &[Entry],
+    range: HandleRange,
+    responder: &mut Sender<'_>,
+) -> Result<(), Error> {
+    let entries = entries_in_range(entries, &range);
+    let width = match entries.first() {
+        Some(entry) => entry.attr.att_type.folded_width(),
+        None => {
+            return responder.send(AttPdu::ErrorRsp {
+                opcode: Opcode::FindInformationReq,
+                handle: range.start(),
+                error_code: ErrorCode::AttributeNotFound,
+            });
+        }
+    };
+
+    responder.send_with(|writer| -> Result<(), Error> {
+        writer.write_u8(Opcode::FindInformationRsp.into())?;
+        writer.write_u8(width.find_information_format())?;
+
+        // All entries in the response must use the same UUID format. The client continues after
+        // the last returned handle, so the list has to end at the first entry of another width.
+        for entry in entries {
+            let uuid = entry.attr.att_type.folded();
+            if uuid.width() != width || writer.space_left() < 2 + width.encoded_len() {
+                break;
+            }
+            entry.attr.handle.to_bytes(writer)?;
+            uuid.to_bytes(writer)?;
+        }
+        Ok(())
+    })
+}
+
+fn is_service(uuid: AttUuid) -> bool {
+    uuid == declarations::PRIMARY_SERVICE || uuid == declarations::SECONDARY_SERVICE
+}
+
+/// Writes the shortest form of `uuid` to `buf` in little-endian byte order and returns the number
+/// of Bytes written.
+fn encode_uuid(uuid: AttUuid, buf: &mut [u8]) -> Result<usize, Error> {
+    let mut writer = ByteWriter::new(buf);
+    let space = writer.space_left();
+    uuid.folded().to_bytes(&mut writer)?;
+    Ok(space - writer.space_left())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::uuid::consts::{characteristics, descriptors, services};
+    use crate::uuid::Uuid128;
+
+    const CUSTOM: Uuid128 = Uuid128::parse_static("a86a62f0-5d26-4538-b364-5654961515c9");
+
+    fn attrs() -> StaticAttributes<8> {
+        let mut attrs = StaticAttributes::new();
+        attrs
+            .push_primary_service(services::BATTERY.into())
+            .unwrap();
+        attrs
+            .push_characteristic(
+                characteristics::BATTERY_LEVEL.into(),
+                Properties::READ,
+                &[48],
+                AttributeAccessPermissions::Readable,
+            )
+            .unwrap();
+        attrs.push_primary_service(CUSTOM.into()).unwrap();
+        attrs
+            .push_characteristic(
+                CUSTOM.into(),
+                Properties::READ | Properties::WRITE,
+                &[0],
+                AttributeAccessPermissions::ReadableAndWriteable,
+            )
+            .unwrap();
+        attrs
+    }
+
+    fn handles(attrs: &mut StaticAttributes<8>, start: u16, end: u16) -> std::vec::Vec<u16> {
+        let mut handles = std::vec::Vec::new();
+        attrs
+            .for_attrs_in_range(
+                HandleRange::new(Handle::from_raw(start), Handle::from_raw(end)),
+                |_, attr| {
+                    handles.push(attr.handle.as_u16());
+                    Ok(())
+                },
+            )
+            .unwrap();
+        handles
+    }
+
+    #[test]
+    fn layout() {
+        let mut attrs = attrs();
+        assert_eq!(handles(&mut attrs, 0x0001, 0xFFFF), [1, 2, 3, 4, 5, 6]);
+        assert_eq!(handles(&mut attrs, 0x0003, 0x0004), [3, 4]);
+        assert!(handles(&mut attrs, 0x0007, 0xFFFF).is_empty());
+
+        let h = Handle::from_raw;
+        assert_eq!(attrs.value(h(0x0001)), Some(&[0x0F, 0x18][..]));
+        assert_eq!(
+            attrs.value(h(0x0002)),
+            Some(&[0x02, 0x03, 0x00, 0x19, 0x2A][..])
+        );
+        assert_eq!(attrs.value(h(0x0004)), Some(&CUSTOM.to_le_bytes()[..]));
+        assert_eq!(attrs.value(h(0x0005)).unwrap()[..3], [0x0A, 0x06, 0x00]);
+        assert_eq!(attrs.value(h(0x0007)), None);
+
+        assert_eq!(attrs.push(CUSTOM.into(), &[], Default::default()), Ok(h(7)));
+        assert_eq!(
+            attrs.push_characteristic(CUSTOM.into(), Properties::READ, &[], Default::default()),
+            Err(Error::Eof)
+        );
+    }
+
+    #[test]
+    fn groups() {
+        let attrs = attrs();
+        let end = |raw| attrs.group_end(Handle::from_raw(raw)).map(|a| a.handle);
+        assert_eq!(end(0x0001), Some(Handle::from_raw(0x0003)));
+        assert_eq!(end(0x0002), None);
+        assert_eq!(end(0x0004), Some(Handle::from_raw(0x0006)));
+        assert_eq!(end(0x0007), None);
+        assert!(attrs.is_grouping_attr(declarations::PRIMARY_SERVICE.into()));
+        assert!(!attrs.is_grouping_attr(declarations::CHARACTERISTIC.into()));
+    }
+
+    #[test]
+    fn writes() {
+        let mut attrs = attrs();
+        let value = Handle::from_raw(0x0006);
+        assert_eq!(
+            attrs.attr_access_permissions(value),
+            AttributeAccessPermissions::ReadableAndWriteable
+        );
+        assert_eq!(
+            attrs.attr_access_permissions(Handle::from_raw(0x0003)),
+            AttributeAccessPermissions::Readable
+        );
+
+        attrs.write_attr(value, &[1, 2, 3], None).unwrap();
+        assert_eq!(attrs.value(value), Some(&[1, 2, 3][..]));
+        assert_eq!(
+            attrs.write_attr(value, &[0; MAX_VALUE_LEN + 1], None),
+            Err(Error::InvalidLength)
+        );
+
+        attrs.prepare_write_attr(value, 1, &[4, 5]).unwrap();
+        attrs.prepare_write_attr(value, 3, &[6]).unwrap();
+        assert_eq!(attrs.value(value), Some(&[1, 2, 3][..]));
+        attrs.execute_write_attr(0x01).unwrap();
+        assert_eq!(attrs.value(value), Some(&[1, 4, 5, 6][..]));
+
+        attrs.prepare_write_attr(value, 0, &[7]).unwrap();
+        attrs.execute_write_attr(0x00).unwrap();
+        assert_eq!(attrs.value(value), Some(&[1, 4, 5, 6][..]));
+    }
+
+    fn dynamic() -> DynamicAttributes<12> {
+        let mut attrs = DynamicAttributes::new();
+        attrs
+            .push_service(services::GENERIC_ATTRIBUTE.into(), |service| {
+                service.characteristic(
+                    characteristics::SERVICE_CHANGED.into(),
+                    Properties::INDICATE,
+                    &[0; 4],
+                    AttributeAccessPermissions::Readable,
+                )?;
+                service.attribute(
+                    descriptors::CLIENT_CHARACTERISTIC_CONFIGURATION.into(),
+                    &[0, 0],
+                    AttributeAccessPermissions::ReadableAndWriteable,
+                )?;
+                Ok(())
+            })
+            .unwrap();
+        attrs
+            .insert_service(
+                Handle::from_raw(0x0010),
+                services::BATTERY.into(),
+                |service| {
+                    service.characteristic(
+                        characteristics::BATTERY_LEVEL.into(),
+                        Properties::READ,
+                        &[48],
+                        AttributeAccessPermissions::Readable,
+                    )?;
+                    Ok(())
+                },
+            )
+            .unwrap();
+        attrs
+    }
+
+    fn dynamic_handles(attrs: &DynamicAttributes<12>) -> std::vec::Vec<u16> {
+        let mut handles = std::vec::Vec::new();
+        attrs
+            .for_each_in_range(
+                HandleRange::new(Handle::from_raw(0x0001), Handle::from_raw(0xFFFF)),
+                |attr| {
+                    handles.push(attr.handle.as_u16());
+                    Ok(())
+                },
+            )
+            .unwrap();
+        handles
+    }
+
+    #[test]
+    fn insert_remove() {
+        let mut attrs = dynamic();
+        assert_eq!(dynamic_handles(&attrs), [1, 2, 3, 4, 0x10, 0x11, 0x12]);
+        assert_eq!(attrs.value(Handle::from_raw(0x0002)).unwrap()[1..3], [3, 0]);
+        assert_eq!(
+            attrs.value(Handle::from_raw(0x0011)).unwrap()[1..3],
+            [0x12, 0]
+        );
+
+        // Insert into the gap between the services
+        let range = attrs
+            .insert_service(Handle::from_raw(0x0008), CUSTOM.into(), |service| {
+                service.characteristic(
+                    CUSTOM.into(),
+                    Properties::WRITE,
+                    &[],
+                    AttributeAccessPermissions::Writeable,
+                )?;
+                Ok(())
+            })
+            .unwrap();
+        assert_eq!((range.start().as_u16(), range.end().as_u16()), (8, 10));
+        assert_eq!(
+            dynamic_handles(&attrs),
+            [1, 2, 3, 4, 8, 9, 10, 0x10, 0x11, 0x12]
+        );
+        let end = |attrs: &DynamicAttributes<12>, raw| {
+            attrs
+                .group_end(Handle::from_raw(raw))
+                .unwrap()
+                .handle
+                .as_u16()
+        };
+        assert_eq!(end(&attrs, 0x0001), 0x0004);
+        assert_eq!(end(&attrs, 0x0008), 0x000A);
+        assert_eq!(end(&attrs, 0x0010), 0x0012);
+
+        let range = attrs.remove_service(Handle::from_raw(0x0001)).unwrap();
+        assert_eq!((range.start().as_u16(), range.end().as_u16()), (1, 4));
+        assert_eq!(dynamic_handles(&attrs), [8, 9, 10, 0x10, 0x11, 0x12]);
+        assert_eq!(
+            attrs.remove_service(Handle::from_raw(0x0009)).err(),
+            Some(Error::InvalidValue)
+        );
+        assert_eq!(end(&attrs, 0x0010), 0x0012);
+    }
+
+    #[test]
+    fn insert_invalid() {
+        let mut attrs = dynamic();
+        let mut insert = |start, chars: usize| {
+            attrs.insert_service(Handle::from_raw(start), CUSTOM.into(), |service| {
+                for _ in 0..chars {
+                    service.characteristic(
+                        CUSTOM.into(),
+                        Properties::READ,
+                        &[],
+                        AttributeAccessPermissions::Readable,
+                    )?;
+                }
+                Ok(())
+            })
+        };
+
+        // Overlaps the GATT service, or the battery service
+        assert_eq!(insert(0x0003, 0).err(), Some(Error::InvalidValue));
+        assert_eq!(insert(0x0010, 0).err(), Some(Error::InvalidValue));
+        assert_eq!(insert(0x000E, 1).err(), Some(Error::InvalidValue));
+        // Doesn't fit
+        assert_eq!(insert(0x0020, 3).err(), Some(Error::Eof));
+        assert_eq!(insert(0x0000, 0).err(), Some(Error::InvalidValue));
+
+        // Failed insertions must not modify the table
+        assert_eq!(dynamic_handles(&attrs), [1, 2, 3, 4, 0x10, 0x11, 0x12]);
+    }
+
+    #[test]
+    fn service_changed() {
+        let mut attrs = dynamic();
+        assert_eq!(
+            attrs.service_changed(),
+            Some((Handle::from_raw(0x0003), [0x01, 0x00, 0x12, 0x00]))
+        );
+        assert_eq!(attrs.service_changed(), None);
+
+        attrs.remove_service(Handle::from_raw(0x0010)).unwrap();
+        assert_eq!(
+            attrs.service_changed(),
+            Some((Handle::from_raw(0x0003), [0x10, 0x00, 0x12, 0x00]))
+        );
+        assert_eq!(
+            attrs.value(Handle::from_raw(0x0003)),
+            Some(&[0x10, 0x00, 0x12, 0x00][..])
+        );
+
+        // Without a Service Changed characteristic, changes are silently dropped
+        attrs.remove_service(Handle::from_raw(0x0001)).unwrap();
+        assert_eq!(attrs.service_changed(), None);
+    }
+}
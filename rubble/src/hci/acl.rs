@@ -0,0 +1,121 @@
+//! HCI ACL data packets.
+
+use crate::link::data::Llid;
+use crate::{bytes::*, Error};
+
+/// Value of the *Packet_Boundary_Flag* in an ACL data packet header.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, defmt::Format)]
+pub enum PacketBoundary {
+    /// First fragment of a non-automatically-flushable L2CAP message (host to controller only).
+    FirstNonFlushable = 0b00,
+
+    /// Continuation fragment of an L2CAP message.
+    Continuation = 0b01,
+
+    /// First fragment of an automatically flushable L2CAP message.
+    ///
+    /// This is used for all L2CAP messages sent from the controller to the host on LE.
+    FirstFlushable = 0b10,
+}
+
+impl PacketBoundary {
+    /// Returns the LLID to use for a data channel PDU containing this fragment.
+    pub fn to_llid(self) -> Llid {
+        match self {
+            PacketBoundary::FirstNonFlushable | PacketBoundary::FirstFlushable => Llid::DataStart,
+            PacketBoundary::Continuation => Llid::DataCont,
+        }
+    }
+
+    /// Returns the boundary flag to use when forwarding a data channel PDU with the given LLID to
+    /// the host.
+    ///
+    /// Returns `None` for LLIDs that don't carry L2CAP data.
+    pub fn from_llid(llid: Llid) -> Option<Self> {
+        match llid {
+            Llid::DataStart => Some(PacketBoundary::FirstFlushable),
+            Llid::DataCont => Some(PacketBoundary::Continuation),
+            Llid::Control | Llid::Reserved => None,
+        }
+    }
+}
+
+/// Header of an HCI ACL data packet.
+///
+/// ```notrust
+/// LSB                                                           MSB
+/// +-------------------+------------+------------+-----------------+
+/// | Connection Handle |  PB Flag   |  BC Flag   |   Data Length   |
+/// |     (12 bits)     |  (2 bits)  |  (2 bits)  |    (16 bits)    |
+/// +-------------------+------------+------------+-----------------+
+/// ```
+///
+/// The broadcast flag is not used on LE and always 0.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, defmt::Format)]
+pub struct AclHeader {
+    /// The connection handle the data belongs to.
+    pub handle: u16,
+
+    /// Whether the packet starts or continues an L2CAP message.
+    pub boundary: PacketBoundary,
+
+    /// Length of the data following the header.
+    pub length: u16,
+}
+
+impl AclHeader {
+    /// Size of the encoded header in Bytes.
+    pub const SIZE: usize = 4;
+}
+
+impl<'a> FromBytes<'a> for AclHeader {
+    fn from_bytes(bytes: &mut ByteReader<'a>) -> Result<Self, Error> {
+        let raw = bytes.read_u16_le()?;
+        let boundary = match (raw >> 12) & 0b11 {
+            0b00 => PacketBoundary::FirstNonFlushable,
+            0b01 => PacketBoundary::Continuation,
+            0b10 => PacketBoundary::FirstFlushable,
+            _ => return Err(Error::InvalidValue),
+        };
+
+        Ok(Self {
+            handle: raw & 0x0FFF,
+            boundary,
+            length: bytes.read_u16_le()?,
+        })
+    }
+}
+
+impl ToBytes for AclHeader {
+    fn to_bytes(&self, writer: &mut ByteWriter<'_>) -> Result<(), Error> {
+        writer.write_u16_le(self.handle & 0x0FFF | (self.boundary as u16) << 12)?;
+        writer.write_u16_le(self.length)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn header_roundtrip() {
+        let header = AclHeader {
+            handle: 0x0123,
+            boundary: PacketBoundary::FirstFlushable,
+            length: 27,
+        };
+        let mut buf = [0; AclHeader::SIZE];
+        header.to_bytes(&mut ByteWriter::new(&mut buf)).unwrap();
+        assert_eq!(buf, [0x23, 0x21, 27, 0]);
+        assert_eq!(
+            AclHeader::from_bytes(&mut ByteReader::new(&buf)).unwrap(),
+            header
+        );
+
+        let reserved = [0x23, 0x31, 0, 0];
+        assert_eq!(
+            AclHeader::from_bytes(&mut ByteReader::new(&reserved)),
+            Err(Error::InvalidValue)
+        );
+    }
+}
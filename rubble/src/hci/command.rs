@@ -0,0 +1,444 @@
+//! HCI commands sent from the host to the controller.
+
+use crate::link::{AddressKind, DeviceAddress};
+use crate::{bytes::*, Error};
+
+enum_with_unknown! {
+    /// HCI command opcodes.
+    ///
+    /// The opcode is made up of the 6-bit *Opcode Group Field* (OGF) in the upper bits, and the
+    /// 10-bit *Opcode Command Field* (OCF) in the lower bits.
+    #[derive(Debug, Copy, Clone, PartialEq, Eq, defmt::Format)]
+    pub enum Opcode(u16) {
+        Disconnect = 0x0406,
+        SetEventMask = 0x0C01,
+        Reset = 0x0C03,
+        ReadLocalVersionInformation = 0x1001,
+        ReadLocalSupportedCommands = 0x1002,
+        ReadLocalSupportedFeatures = 0x1003,
+        ReadBufferSize = 0x1005,
+        ReadBdAddr = 0x1009,
+        LeSetEventMask = 0x2001,
+        LeReadBufferSize = 0x2002,
+        LeReadLocalSupportedFeatures = 0x2003,
+        LeSetAdvertisingParameters = 0x2006,
+        LeSetAdvertisingData = 0x2008,
+        LeSetScanResponseData = 0x2009,
+        LeSetAdvertiseEnable = 0x200A,
+        LeCreateConnection = 0x200D,
+    }
+}
+
+/// Maximum length of advertising and scan response data settable via HCI.
+pub const MAX_ADV_DATA_LEN: usize = 31;
+
+/// An HCI command.
+///
+/// Commands are encoded as a 16-bit opcode, followed by an 8-bit parameter length and the
+/// parameters.
+#[derive(Debug, Copy, Clone, defmt::Format)]
+pub enum Command<'a> {
+    /// Terminates an existing connection.
+    Disconnect {
+        handle: u16,
+        reason: u8,
+    },
+
+    /// Selects the (non-LE) events the host wants to receive.
+    SetEventMask {
+        mask: u64,
+    },
+
+    /// Resets the controller and the Link-Layer.
+    Reset,
+
+    ReadLocalVersionInformation,
+    ReadLocalSupportedCommands,
+    ReadLocalSupportedFeatures,
+    ReadBufferSize,
+
+    /// Reads the public device address of the controller.
+    ReadBdAddr,
+
+    /// Selects the LE subevents the host wants to receive.
+    LeSetEventMask {
+        mask: u64,
+    },
+
+    LeReadBufferSize,
+    LeReadLocalSupportedFeatures,
+
+    /// Configures the advertising parameters used when advertising is enabled.
+    LeSetAdvertisingParameters {
+        /// Minimum advertising interval in units of 0.625 ms.
+        interval_min: u16,
+        /// Maximum advertising interval in units of 0.625 ms.
+        interval_max: u16,
+        /// The advertising PDU type to use (`0x00` = `ADV_IND`).
+        adv_type: u8,
+        own_address_type: u8,
+        /// Peer address for directed advertising.
+        peer_address: DeviceAddress,
+        /// Bitmap of the advertising channels to use.
+        channel_map: u8,
+        filter_policy: u8,
+    },
+
+    /// Sets the AD structures to send in advertising PDUs.
+    LeSetAdvertisingData {
+        data: &'a [u8],
+    },
+
+    /// Sets the AD structures to send in scan response PDUs.
+    LeSetScanResponseData {
+        data: &'a [u8],
+    },
+
+    /// Enables or disables advertising.
+    LeSetAdvertiseEnable {
+        enable: bool,
+    },
+
+    /// Initiates a connection to an advertiser (Central role).
+    LeCreateConnection {
+        /// Scan interval in units of 0.625 ms.
+        scan_interval: u16,
+        /// Scan window in units of 0.625 ms.
+        scan_window: u16,
+        filter_policy: u8,
+        /// Address of the advertiser to connect to.
+        peer_address: DeviceAddress,
+        own_address_type: u8,
+        /// Minimum connection interval in units of 1.25 ms.
+        interval_min: u16,
+        /// Maximum connection interval in units of 1.25 ms.
+        interval_max: u16,
+        latency: u16,
+        /// Supervision timeout in units of 10 ms.
+        supervision_timeout: u16,
+        min_ce_length: u16,
+        max_ce_length: u16,
+    },
+
+    /// A command that is not supported by Rubble.
+    Unknown {
+        opcode: Opcode,
+        params: &'a [u8],
+    },
+}
+
+impl Command<'_> {
+    /// Returns the opcode of this command.
+    pub fn opcode(&self) -> Opcode {
+        match self {
+            Command::Disconnect { .. } => Opcode::Disconnect,
+            Command::SetEventMask { .. } => Opcode::SetEventMask,
+            Command::Reset => Opcode::Reset,
+            Command::ReadLocalVersionInformation => Opcode::ReadLocalVersionInformation,
+            Command::ReadLocalSupportedCommands => Opcode::ReadLocalSupportedCommands,
+            Command::ReadLocalSupportedFeatures => Opcode::ReadLocalSupportedFeatures,
+            Command::ReadBufferSize => Opcode::ReadBufferSize,
+            Command::ReadBdAddr => Opcode::ReadBdAddr,
+            Command::LeSetEventMask { .. } => Opcode::LeSetEventMask,
+            Command::LeReadBufferSize => Opcode::LeReadBufferSize,
+            Command::LeReadLocalSupportedFeatures => Opcode::LeReadLocalSupportedFeatures,
+            Command::LeSetAdvertisingParameters { .. } => Opcode::LeSetAdvertisingParameters,
+            Command::LeSetAdvertisingData { .. } => Opcode::LeSetAdvertisingData,
+            Command::LeSetScanResponseData { .. } => Opcode::LeSetScanResponseData,
+            Command::LeSetAdvertiseEnable { .. } => Opcode::LeSetAdvertiseEnable,
+            Command::LeCreateConnection { .. } => Opcode::LeCreateConnection,
+            Command::Unknown { opcode, .. } => *opcode,
+        }
+    }
+}
+
+/// Reads an address type byte followed by a device address.
+fn read_address(bytes: &mut ByteReader<'_>) -> Result<DeviceAddress, Error> {
+    let kind = match bytes.read_u8()? {
+        0x00 => AddressKind::Public,
+        0x01 => AddressKind::Random,
+        _ => return Err(Error::InvalidValue),
+    };
+    Ok(DeviceAddress::new(bytes.read_array()?, kind))
+}
+
+/// Reads the length-prefixed, zero-padded advertising data used by the HCI commands.
+fn read_adv_data<'a>(bytes: &mut ByteReader<'a>) -> Result<&'a [u8], Error> {
+    let len = usize::from(bytes.read_u8()?);
+    let data = bytes.read_slice(MAX_ADV_DATA_LEN)?;
+    if len > MAX_ADV_DATA_LEN {
+        return Err(Error::InvalidLength);
+    }
+    Ok(&data[..len])
+}
+
+impl<'a> FromBytes<'a> for Command<'a> {
+    fn from_bytes(bytes: &mut ByteReader<'a>) -> Result<Self, Error> {
+        let opcode = Opcode::from(bytes.read_u16_le()?);
+        let length = bytes.read_u8()?;
+        let params = bytes.read_slice(usize::from(length))?;
+        let mut bytes = ByteReader::new(params);
+
+        let cmd = match opcode {
+            Opcode::Disconnect => Command::Disconnect {
+                handle: bytes.read_u16_le()?,
+                reason: bytes.read_u8()?,
+            },
+            Opcode::SetEventMask => Command::SetEventMask {
+                mask: bytes.read_u64_le()?,
+            },
+            Opcode::Reset => Command::Reset,
+            Opcode::ReadLocalVersionInformation => Command::ReadLocalVersionInformation,
+            Opcode::ReadLocalSupportedCommands => Command::ReadLocalSupportedCommands,
+            Opcode::ReadLocalSupportedFeatures => Command::ReadLocalSupportedFeatures,
+            Opcode::ReadBufferSize => Command::ReadBufferSize,
+            Opcode::ReadBdAddr => Command::ReadBdAddr,
+            Opcode::LeSetEventMask => Command::LeSetEventMask {
+                mask: bytes.read_u64_le()?,
+            },
+            Opcode::LeReadBufferSize => Command::LeReadBufferSize,
+            Opcode::LeReadLocalSupportedFeatures => Command::LeReadLocalSupportedFeatures,
+            Opcode::LeSetAdvertisingParameters => Command::LeSetAdvertisingParameters {
+                interval_min: bytes.read_u16_le()?,
+                interval_max: bytes.read_u16_le()?,
+                adv_type: bytes.read_u8()?,
+                own_address_type: bytes.read_u8()?,
+                peer_address: read_address(&mut bytes)?,
+                channel_map: bytes.read_u8()?,
+                filter_policy: bytes.read_u8()?,
+            },
+            Opcode::LeSetAdvertisingData => Command::LeSetAdvertisingData {
+                data: read_adv_data(&mut bytes)?,
+            },
+            Opcode::LeSetScanResponseData => Command::LeSetScanResponseData {
+                data: read_adv_data(&mut bytes)?,
+            },
+            Opcode::LeSetAdvertiseEnable => Command::LeSetAdvertiseEnable {
+                enable: match bytes.read_u8()? {
+                    0x00 => false,
+                    0x01 => true,
+                    _ => return Err(Error::InvalidValue),
+                },
+            },
+            Opcode::LeCreateConnection => Command::LeCreateConnection {
+                scan_interval: bytes.read_u16_le()?,
+                scan_window: bytes.read_u16_le()?,
+                filter_policy: bytes.read_u8()?,
+                peer_address: read_address(&mut bytes)?,
+                own_address_type: bytes.read_u8()?,
+                interval_min: bytes.read_u16_le()?,
+                interval_max: bytes.read_u16_le()?,
+                latency: bytes.read_u16_le()?,
+                supervision_timeout: bytes.read_u16_le()?,
+                min_ce_length: bytes.read_u16_le()?,
+                max_ce_length: bytes.read_u16_le()?,
+            },
+            Opcode::Unknown(_) => Command::Unknown {
+                opcode,
+                params: bytes.read_rest(),
+            },
+        };
+
+        if !bytes.is_empty() {
+            return Err(Error::IncompleteParse);
+        }
+
+        Ok(cmd)
+    }
+}
+
+/// Writes an address type byte followed by a device address.
+fn write_address(writer: &mut ByteWriter<'_>, address: &DeviceAddress) -> Result<(), Error> {
+    writer.write_u8(address.is_random().into())?;
+    writer.write_slice(address.raw())
+}
+
+/// Writes length-prefixed advertising data, padded to `MAX_ADV_DATA_LEN`.
+fn write_adv_data(writer: &mut ByteWriter<'_>, data: &[u8]) -> Result<(), Error> {
+    if data.len() > MAX_ADV_DATA_LEN {
+        return Err(Error::InvalidLength);
+    }
+    writer.write_u8(data.len() as u8)?;
+    writer.write_slice(data)?;
+    writer.write_slice(&[0; MAX_ADV_DATA_LEN][data.len()..])
+}
+
+impl ToBytes for Command<'_> {
+    fn to_bytes(&self, writer: &mut ByteWriter<'_>) -> Result<(), Error> {
+        writer.write_u16_le(self.opcode().into())?;
+
+        // The length is written once the parameters are encoded
+        let mut length_writer = writer.split_off(1)?;
+        let left = writer.space_left();
+        match *self {
+            Command::Disconnect { handle, reason } => {
+                writer.write_u16_le(handle)?;
+                writer.write_u8(reason)?;
+            }
+            Command::SetEventMask { mask } | Command::LeSetEventMask { mask } => {
+                writer.write_u64_le(mask)?;
+            }
+            Command::Reset
+            | Command::ReadLocalVersionInformation
+            | Command::ReadLocalSupportedCommands
+            | Command::ReadLocalSupportedFeatures
+            | Command::ReadBufferSize
+            | Command::ReadBdAddr
+            | Command::LeReadBufferSize
+            | Command::LeReadLocalSupportedFeatures => {}
+            Command::LeSetAdvertisingParameters {
+                interval_min,
+                interval_max,
+                adv_type,
+                own_address_type,
+                peer_address,
+                channel_map,
+                filter_policy,
+            } => {
+                writer.write_u16_le(interval_min)?;
+                writer.write_u16_le(interval_max)?;
+                writer.write_u8(adv_type)?;
+                writer.write_u8(own_address_type)?;
+                write_address(writer, &peer_address)?;
+                writer.write_u8(channel_map)?;
+                writer.write_u8(filter_policy)?;
+            }
+            Command::LeSetAdvertisingData { data } | Command::LeSetScanResponseData { data } => {
+                write_adv_data(writer, data)?;
+            }
+            Command::LeSetAdvertiseEnable { enable } => {
+                writer.write_u8(enable.into())?;
+            }
+            Command::LeCreateConnection {
+                scan_interval,
+                scan_window,
+                filter_policy,
+                peer_address,
+                own_address_type,
+                interval_min,
+                interval_max,
+                latency,
+                supervision_timeout,
+                min_ce_length,
+                max_ce_length,
+            } => {
+                writer.write_u16_le(scan_interval)?;
+                writer.write_u16_le(scan_window)?;
+                writer.write_u8(filter_policy)?;
+                write_address(writer, &peer_address)?;
+                writer.write_u8(own_address_type)?;
+                writer.write_u16_le(interval_min)?;
+                writer.write_u16_le(interval_max)?;
+                writer.write_u16_le(latency)?;
+                writer.write_u16_le(supervision_timeout)?;
+                writer.write_u16_le(min_ce_length)?;
+                writer.write_u16_le(max_ce_length)?;
+            }
+            Command::Unknown { params, .. } => {
+                writer.write_slice(params)?;
+            }
+        }
+
+        let length = left - writer.space_left();
+        length_writer.write_u8(length as u8)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(raw: &[u8]) -> Result<Command<'_>, Error> {
+        Command::from_bytes(&mut ByteReader::new(raw))
+    }
+
+    #[test]
+    fn parse_commands() {
+        assert!(matches!(parse(&[0x03, 0x0C, 0x00]), Ok(Command::Reset)));
+        assert!(matches!(
+            parse(&[0x0A, 0x20, 0x01, 0x01]),
+            Ok(Command::LeSetAdvertiseEnable { enable: true })
+        ));
+        assert_eq!(
+            parse(&[0x0A, 0x20, 0x01, 0x02]).unwrap_err(),
+            Error::InvalidValue
+        );
+        assert_eq!(
+            parse(&[0x03, 0x0C, 0x01, 0x00]).unwrap_err(),
+            Error::IncompleteParse
+        );
+
+        let mut adv_data = [0; 3 + 32];
+        adv_data[..6].copy_from_slice(&[0x08, 0x20, 32, 3, 0x02, 0x01]);
+        adv_data[6] = 0x06;
+        match parse(&adv_data).unwrap() {
+            Command::LeSetAdvertisingData { data } => assert_eq!(data, &[0x02, 0x01, 0x06]),
+            cmd => panic!("unexpected command {:?}", cmd),
+        }
+
+        match parse(&[0x01, 0xFC, 0x02, 0xAB, 0xCD]).unwrap() {
+            Command::Unknown { opcode, params } => {
+                assert_eq!(opcode, Opcode::Unknown(0xFC01));
+                assert_eq!(params, &[0xAB, 0xCD]);
+            }
+            cmd => panic!("unexpected command {:?}", cmd),
+        }
+    }
+
+    #[test]
+    fn encode_roundtrip() {
+        let commands = [
+            Command::Reset,
+            Command::LeSetEventMask { mask: 0x1F },
+            Command::LeSetAdvertisingData {
+                data: &[0x02, 0x01, 0x06],
+            },
+            Command::LeSetAdvertiseEnable { enable: true },
+            Command::Disconnect {
+                handle: 0x0040,
+                reason: 0x13,
+            },
+        ];
+        for cmd in &commands {
+            let mut buf = [0; 64];
+            let mut writer = ByteWriter::new(&mut buf);
+            cmd.to_bytes(&mut writer).unwrap();
+            let len = 64 - writer.space_left();
+            assert_eq!(usize::from(buf[2]) + 3, len);
+
+            let parsed = parse(&buf[..len]).unwrap();
+            assert_eq!(format!("{:?}", parsed), format!("{:?}", cmd));
+        }
+    }
+
+    #[test]
+    fn parse_adv_params() {
+        let raw = [
+            0x06, 0x20, 15, 0xA0, 0x00, 0xB0, 0x00, 0x00, 0x01, 0x00, 1, 2, 3, 4, 5, 6, 0x07, 0x00,
+        ];
+        match parse(&raw).unwrap() {
+            Command::LeSetAdvertisingParameters {
+                interval_min,
+                interval_max,
+                adv_type,
+                own_address_type,
+                peer_address,
+                channel_map,
+                filter_policy,
+            } => {
+                assert_eq!(interval_min, 0xA0);
+                assert_eq!(interval_max, 0xB0);
+                assert_eq!(adv_type, 0);
+                assert_eq!(own_address_type, 1);
+                assert_eq!(
+                    peer_address,
+                    DeviceAddress::new([1, 2, 3, 4, 5, 6], AddressKind::Public)
+                );
+                assert_eq!(channel_map, 0x07);
+                assert_eq!(filter_policy, 0);
+            }
+            cmd => panic!("unexpected command {:?}", cmd),
+        }
+    }
+}
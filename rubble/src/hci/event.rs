@@ -0,0 +1,326 @@
+//! HCI events sent from the controller to the host.
+
+use super::command::Opcode;
+use crate::link::{AddressKind, DeviceAddress};
+use crate::time::Duration;
+use crate::{bytes::*, Error};
+
+enum_with_unknown! {
+    /// HCI status and error codes.
+    ///
+    /// These are used as command results and as reasons for disconnections.
+    #[derive(Debug, Copy, Clone, PartialEq, Eq, defmt::Format)]
+    pub enum Status(u8) {
+        Success = 0x00,
+        UnknownCommand = 0x01,
+        UnknownConnectionIdentifier = 0x02,
+        MemoryCapacityExceeded = 0x07,
+        ConnectionTimeout = 0x08,
+        CommandDisallowed = 0x0C,
+        UnsupportedFeature = 0x11,
+        InvalidParameters = 0x12,
+        RemoteUserTerminated = 0x13,
+        LocalHostTerminated = 0x16,
+    }
+}
+
+enum_with_unknown! {
+    /// HCI event codes.
+    #[derive(Debug, Copy, Clone, PartialEq, Eq, defmt::Format)]
+    pub enum EventCode(u8) {
+        DisconnectionComplete = 0x05,
+        CommandComplete = 0x0E,
+        CommandStatus = 0x0F,
+        NumberOfCompletedPackets = 0x13,
+        LeMeta = 0x3E,
+    }
+}
+
+/// Subevent code of the *LE Connection Complete* event.
+const LE_CONNECTION_COMPLETE: u8 = 0x01;
+
+/// An HCI event.
+///
+/// Events are encoded as an 8-bit event code, followed by an 8-bit parameter length and the
+/// parameters.
+#[derive(Debug, Copy, Clone, defmt::Format)]
+pub enum Event<'a> {
+    /// A connection was terminated.
+    DisconnectionComplete {
+        status: Status,
+        handle: u16,
+        reason: Status,
+    },
+
+    /// A command has finished executing.
+    CommandComplete {
+        /// Number of commands the host may send to the controller.
+        num_packets: u8,
+        opcode: Opcode,
+        /// Command-specific return parameters, starting with a `Status` for most commands.
+        params: &'a [u8],
+    },
+
+    /// A command was received and will be executed asynchronously, or failed.
+    CommandStatus {
+        status: Status,
+        /// Number of commands the host may send to the controller.
+        num_packets: u8,
+        opcode: Opcode,
+    },
+
+    /// ACL data packets have been processed and their buffers can be reused by the host.
+    NumberOfCompletedPackets { handle: u16, completed: u16 },
+
+    /// A connection was established (LE Meta event).
+    LeConnectionComplete {
+        status: Status,
+        handle: u16,
+        /// Address of the Central that initiated the connection.
+        peer_address: DeviceAddress,
+        interval: Duration,
+        latency: u16,
+        supervision_timeout: Duration,
+    },
+
+    /// An event that is not supported by Rubble.
+    ///
+    /// For LE Meta events with an unsupported subevent code, `params` starts with the subevent
+    /// code.
+    Unknown { code: EventCode, params: &'a [u8] },
+}
+
+impl Event<'_> {
+    /// Returns the event code identifying this event.
+    pub fn code(&self) -> EventCode {
+        match self {
+            Event::DisconnectionComplete { .. } => EventCode::DisconnectionComplete,
+            Event::CommandComplete { .. } => EventCode::CommandComplete,
+            Event::CommandStatus { .. } => EventCode::CommandStatus,
+            Event::NumberOfCompletedPackets { .. } => EventCode::NumberOfCompletedPackets,
+            Event::LeConnectionComplete { .. } => EventCode::LeMeta,
+            Event::Unknown { code, .. } => *code,
+        }
+    }
+}
+
+impl ToBytes for Event<'_> {
+    fn to_bytes(&self, writer: &mut ByteWriter<'_>) -> Result<(), Error> {
+        writer.write_u8(self.code().into())?;
+
+        // The length is written once the parameters are encoded
+        let mut length_writer = writer.split_off(1)?;
+        let left = writer.space_left();
+        match *self {
+            Event::DisconnectionComplete {
+                status,
+                handle,
+                reason,
+            } => {
+                writer.write_u8(status.into())?;
+                writer.write_u16_le(handle)?;
+                writer.write_u8(reason.into())?;
+            }
+            Event::CommandComplete {
+                num_packets,
+                opcode,
+                params,
+            } => {
+                writer.write_u8(num_packets)?;
+                writer.write_u16_le(opcode.into())?;
+                writer.write_slice(params)?;
+            }
+            Event::CommandStatus {
+                status,
+                num_packets,
+                opcode,
+            } => {
+                writer.write_u8(status.into())?;
+                writer.write_u8(num_packets)?;
+                writer.write_u16_le(opcode.into())?;
+            }
+            Event::NumberOfCompletedPackets { handle, completed } => {
+                writer.write_u8(1)?;
+                writer.write_u16_le(handle)?;
+                writer.write_u16_le(completed)?;
+            }
+            Event::LeConnectionComplete {
+                status,
+                handle,
+                peer_address,
+                interval,
+                latency,
+                supervision_timeout,
+            } => {
+                writer.write_u8(LE_CONNECTION_COMPLETE)?;
+                writer.write_u8(status.into())?;
+                writer.write_u16_le(handle)?;
+                writer.write_u8(0x01)?; // Role: Rubble is always the Peripheral
+                writer.write_u8(peer_address.is_random().into())?;
+                writer.write_slice(peer_address.raw())?;
+                writer.write_u16_le((interval.as_micros() / 1_250) as u16)?;
+                writer.write_u16_le(latency)?;
+                writer.write_u16_le((supervision_timeout.as_micros() / 10_000) as u16)?;
+                writer.write_u8(0x00)?; // Master clock accuracy (unused for the Peripheral)
+            }
+            Event::Unknown { params, .. } => {
+                writer.write_slice(params)?;
+            }
+        }
+
+        let length = left - writer.space_left();
+        length_writer.write_u8(length as u8)?;
+        Ok(())
+    }
+}
+
+impl<'a> FromBytes<'a> for Event<'a> {
+    fn from_bytes(bytes: &mut ByteReader<'a>) -> Result<Self, Error> {
+        let code = EventCode::from(bytes.read_u8()?);
+        let length = bytes.read_u8()?;
+        let params = bytes.read_slice(usize::from(length))?;
+        let mut bytes = ByteReader::new(params);
+
+        let event = match code {
+            EventCode::DisconnectionComplete => Event::DisconnectionComplete {
+                status: Status::from(bytes.read_u8()?),
+                handle: bytes.read_u16_le()?,
+                reason: Status::from(bytes.read_u8()?),
+            },
+            EventCode::CommandComplete => Event::CommandComplete {
+                num_packets: bytes.read_u8()?,
+                opcode: Opcode::from(bytes.read_u16_le()?),
+                params: bytes.read_rest(),
+            },
+            EventCode::CommandStatus => Event::CommandStatus {
+                status: Status::from(bytes.read_u8()?),
+                num_packets: bytes.read_u8()?,
+                opcode: Opcode::from(bytes.read_u16_le()?),
+            },
+            // Only a single connection is supported, so only events reporting a single handle are
+            // understood.
+            EventCode::NumberOfCompletedPackets if params.first() == Some(&1) => {
+                bytes.skip(1)?;
+                Event::NumberOfCompletedPackets {
+                    handle: bytes.read_u16_le()?,
+                    completed: bytes.read_u16_le()?,
+                }
+            }
+            EventCode::LeMeta if params.first() == Some(&LE_CONNECTION_COMPLETE) => {
+                bytes.skip(1)?;
+                let status = Status::from(bytes.read_u8()?);
+                let handle = bytes.read_u16_le()?;
+                let _role = bytes.read_u8()?;
+                let kind = match bytes.read_u8()? {
+                    0x00 => AddressKind::Public,
+                    _ => AddressKind::Random,
+                };
+                let peer_address = DeviceAddress::new(bytes.read_array()?, kind);
+                let interval = Duration::from_micros(u32::from(bytes.read_u16_le()?) * 1_250);
+                let latency = bytes.read_u16_le()?;
+                let timeout = Duration::from_micros(u32::from(bytes.read_u16_le()?) * 10_000);
+                let _clock_accuracy = bytes.read_u8()?;
+                Event::LeConnectionComplete {
+                    status,
+                    handle,
+                    peer_address,
+                    interval,
+                    latency,
+                    supervision_timeout: timeout,
+                }
+            }
+            _ => Event::Unknown {
+                code,
+                params: bytes.read_rest(),
+            },
+        };
+
+        if !bytes.is_empty() {
+            return Err(Error::IncompleteParse);
+        }
+
+        Ok(event)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encode<'b>(event: Event<'_>, buf: &'b mut [u8]) -> &'b [u8] {
+        let mut writer = ByteWriter::new(buf);
+        event.to_bytes(&mut writer).unwrap();
+        let used = writer.space_left();
+        let len = buf.len() - used;
+        &buf[..len]
+    }
+
+    #[test]
+    fn encode_events() {
+        let mut buf = [0; 32];
+        let event = Event::CommandComplete {
+            num_packets: 1,
+            opcode: Opcode::Reset,
+            params: &[Status::Success.into()],
+        };
+        assert_eq!(encode(event, &mut buf), &[0x0E, 4, 1, 0x03, 0x0C, 0x00]);
+
+        let event = Event::CommandStatus {
+            status: Status::UnsupportedFeature,
+            num_packets: 1,
+            opcode: Opcode::LeCreateConnection,
+        };
+        assert_eq!(encode(event, &mut buf), &[0x0F, 4, 0x11, 1, 0x0D, 0x20]);
+
+        let event = Event::LeConnectionComplete {
+            status: Status::Success,
+            handle: 1,
+            peer_address: DeviceAddress::new([1, 2, 3, 4, 5, 6], AddressKind::Random),
+            interval: Duration::from_micros(30_000),
+            latency: 0,
+            supervision_timeout: Duration::from_millis(720),
+        };
+        assert_eq!(
+            encode(event, &mut buf),
+            &[0x3E, 19, 0x01, 0x00, 1, 0, 0x01, 0x01, 1, 2, 3, 4, 5, 6, 24, 0, 0, 0, 72, 0, 0]
+        );
+        match Event::from_bytes(&mut ByteReader::new(encode(event, &mut buf))).unwrap() {
+            Event::LeConnectionComplete {
+                peer_address,
+                interval,
+                supervision_timeout,
+                ..
+            } => {
+                assert_eq!(
+                    peer_address,
+                    DeviceAddress::new([1, 2, 3, 4, 5, 6], AddressKind::Random)
+                );
+                assert_eq!(interval, Duration::from_micros(30_000));
+                assert_eq!(supervision_timeout, Duration::from_millis(720));
+            }
+            e => panic!("unexpected event {:?}", e),
+        }
+    }
+
+    #[test]
+    fn parse_events() {
+        let raw = [0x13, 5, 1, 0x40, 0x00, 2, 0];
+        match Event::from_bytes(&mut ByteReader::new(&raw)).unwrap() {
+            Event::NumberOfCompletedPackets { handle, completed } => {
+                assert_eq!(handle, 0x40);
+                assert_eq!(completed, 2);
+            }
+            e => panic!("unexpected event {:?}", e),
+        }
+
+        // LE Advertising Report is not supported
+        let raw = [0x3E, 2, 0x02, 0xAB];
+        match Event::from_bytes(&mut ByteReader::new(&raw)).unwrap() {
+            Event::Unknown { code, params } => {
+                assert_eq!(code, EventCode::LeMeta);
+                assert_eq!(params, &[0x02, 0xAB]);
+            }
+            e => panic!("unexpected event {:?}", e),
+        }
+    }
+}
@@ -0,0 +1,453 @@
+//! Host side of the HCI, for running Rubble's upper layers on an external controller.
+
+use super::{
+    write_acl, AclHeader, Command, Event, Opcode, PacketBoundary, PacketType, RxBuffer, Status,
+    Transport, RX_BUF,
+};
+use crate::l2cap::{ChannelMapper, L2CAPState, L2CAPStateTx};
+use crate::link::data::Llid;
+use crate::link::queue::Producer;
+use crate::link::{DeviceAddress, MIN_DATA_PAYLOAD_BUF};
+use crate::{bytes::*, utils::HexSlice, Error};
+
+/// Size of the buffer used to reassemble fragmented L2CAP messages.
+const REASSEMBLY_BUF: usize = 4 + 251;
+
+/// Notable events reported by [`Host::process_one`].
+///
+/// [`Host::process_one`]: struct.Host.html#method.process_one
+#[derive(Debug, Copy, Clone, defmt::Format)]
+pub enum HostEvent {
+    /// A command sent via [`Host::send_command`] has finished.
+    ///
+    /// [`Host::send_command`]: struct.Host.html#method.send_command
+    CommandComplete { opcode: Opcode, status: Status },
+
+    /// A connection was established.
+    Connected {
+        handle: u16,
+        peer_address: DeviceAddress,
+    },
+
+    /// The connection was terminated.
+    Disconnected { handle: u16, reason: Status },
+}
+
+/// An HCI host driving an external controller over a [`Transport`].
+///
+/// This takes the place of Rubble's Link-Layer and [`Responder`]: ACL data received from the
+/// controller is passed to L2CAP, and L2CAP sends its responses as ACL data packets. The
+/// application configures the controller (eg. to start advertising) by sending HCI commands.
+///
+/// Only a single connection is supported.
+///
+/// [`Transport`]: trait.Transport.html
+/// [`Responder`]: ../link/struct.Responder.html
+pub struct Host<M: ChannelMapper, T: Transport> {
+    tx: AclSender<T>,
+    rx_buf: RxBuffer,
+    l2cap: L2CAPState<M>,
+
+    /// Number of HCI commands the controller can currently accept.
+    command_credits: u8,
+
+    /// Buffer for reassembling L2CAP messages from ACL fragments.
+    reassembly: [u8; REASSEMBLY_BUF],
+    reassembly_len: usize,
+}
+
+impl<M: ChannelMapper, T: Transport> Host<M, T> {
+    /// Creates a new HCI host talking to a controller over `transport`.
+    ///
+    /// Until the controller's buffer size is read via `LE Read Buffer Size`, the host assumes that
+    /// the controller can buffer a single ACL data packet with the minimum data PDU payload size.
+    pub fn new(transport: T, l2cap: L2CAPState<M>) -> Self {
+        Self {
+            tx: AclSender {
+                transport,
+                handle: None,
+                max_len: MIN_DATA_PAYLOAD_BUF as u16,
+                credits: 1,
+            },
+            rx_buf: RxBuffer::new(),
+            l2cap,
+            command_credits: 1,
+            reassembly: [0; REASSEMBLY_BUF],
+            reassembly_len: 0,
+        }
+    }
+
+    /// Returns a reference to the transport.
+    pub fn transport(&mut self) -> &mut T {
+        &mut self.tx.transport
+    }
+
+    /// Returns whether a connection is currently established.
+    pub fn is_connected(&self) -> bool {
+        self.tx.handle.is_some()
+    }
+
+    /// Sends an HCI command to the controller.
+    ///
+    /// Returns `Error::Eof` if the controller can not currently accept another command. The
+    /// completion of the command is reported by `process_one`.
+    pub fn send_command(&mut self, cmd: Command<'_>) -> Result<(), Error> {
+        if self.command_credits == 0 {
+            return Err(Error::Eof);
+        }
+
+        let mut buf = [0; RX_BUF];
+        let mut writer = ByteWriter::new(&mut buf);
+        writer.write_u8(PacketType::Command.into())?;
+        cmd.to_bytes(&mut writer)?;
+        let used = writer.space_left();
+        let len = buf.len() - used;
+        debug!("HCI-> {:?}", cmd);
+        self.tx.transport.write(&buf[..len])?;
+        self.command_credits -= 1;
+        Ok(())
+    }
+
+    /// Processes a single packet received from the controller.
+    ///
+    /// Returns `Error::Eof` if no complete packet has been received yet.
+    pub fn process_one(&mut self) -> Result<Option<HostEvent>, Error> {
+        let len = match self.rx_buf.fill(
+            &mut self.tx.transport,
+            &[PacketType::Event, PacketType::AclData],
+        )? {
+            Some(len) => len,
+            None => return Err(Error::Eof),
+        };
+
+        let mut packet = [0; RX_BUF];
+        packet[..len].copy_from_slice(self.rx_buf.packet(len));
+        let result = if packet[0] == u8::from(PacketType::Event) {
+            self.process_event(&packet[1..len])
+        } else {
+            match self.process_acl(&packet[1..len]) {
+                // Keep the packet around if L2CAP couldn't respond yet
+                Ok(false) => return Ok(None),
+                result => result.map(|_| None),
+            }
+        };
+
+        self.rx_buf.consume(len);
+        result
+    }
+
+    /// Obtains access to the L2CAP instance, with the ability to send ACL data.
+    pub fn l2cap(&mut self) -> L2CAPStateTx<'_, M, AclSender<T>> {
+        self.l2cap.tx(&mut self.tx)
+    }
+
+    fn process_event(&mut self, packet: &[u8]) -> Result<Option<HostEvent>, Error> {
+        let event = Event::from_bytes(&mut ByteReader::new(packet))?;
+        debug!("HCI<- {:?}", event);
+
+        Ok(match event {
+            Event::CommandComplete {
+                num_packets,
+                opcode,
+                params,
+            } => {
+                self.command_credits = num_packets;
+                let mut params = ByteReader::new(params);
+                let status = Status::from(params.read_u8()?);
+                if opcode == Opcode::LeReadBufferSize && status == Status::Success {
+                    let max_len = params.read_u16_le()?;
+                    let packets = params.read_u8()?;
+                    // A length of 0 means that the shared BR/EDR buffers must be used, which
+                    // aren't supported, so keep the defaults in that case.
+                    if max_len != 0 {
+                        self.tx.max_len = max_len;
+                        self.tx.credits = u16::from(packets);
+                    }
+                }
+                Some(HostEvent::CommandComplete { opcode, status })
+            }
+            Event::CommandStatus {
+                status,
+                num_packets,
+                opcode,
+            } => {
+                self.command_credits = num_packets;
+                if status == Status::Success {
+                    // Completion will be reported by a different event
+                    None
+                } else {
+                    Some(HostEvent::CommandComplete { opcode, status })
+                }
+            }
+            Event::NumberOfCompletedPackets { handle, completed } => {
+                if Some(handle) == self.tx.handle {
+                    self.tx.credits += completed;
+                }
+                None
+            }
+            Event::LeConnectionComplete {
+                status,
+                handle,
+                peer_address,
+                ..
+            } => {
+                if status == Status::Success {
+                    self.tx.handle = Some(handle);
+                    self.reassembly_len = 0;
+                    Some(HostEvent::Connected {
+                        handle,
+                        peer_address,
+                    })
+                } else {
+                    None
+                }
+            }
+            Event::DisconnectionComplete {
+                status,
+                handle,
+                reason,
+            } => {
+                if status == Status::Success && Some(handle) == self.tx.handle {
+                    self.tx.handle = None;
+                    Some(HostEvent::Disconnected { handle, reason })
+                } else {
+                    None
+                }
+            }
+            Event::Unknown { code, params } => {
+                debug!("HCI: ignoring event {:?}, {:?}", code, HexSlice(params));
+                None
+            }
+        })
+    }
+
+    /// Processes an ACL data packet from the controller.
+    ///
+    /// Returns `Ok(false)` if L2CAP couldn't process the message yet.
+    fn process_acl(&mut self, packet: &[u8]) -> Result<bool, Error> {
+        let mut bytes = ByteReader::new(packet);
+        let header = AclHeader::from_bytes(&mut bytes)?;
+        let data = bytes.read_rest();
+        if Some(header.handle) != self.tx.handle {
+            warn!(
+                "HCI: ACL data for unknown handle {}, dropping",
+                header.handle
+            );
+            return Ok(true);
+        }
+
+        let start = match header.boundary {
+            PacketBoundary::FirstFlushable | PacketBoundary::FirstNonFlushable => 0,
+            PacketBoundary::Continuation if self.reassembly_len != 0 => self.reassembly_len,
+            PacketBoundary::Continuation => {
+                warn!("HCI: unexpected continuation fragment, dropping");
+                return Ok(true);
+            }
+        };
+        let end = start + data.len();
+        if end > REASSEMBLY_BUF {
+            warn!("HCI: L2CAP message exceeds reassembly buffer, dropping");
+            self.reassembly_len = 0;
+            return Ok(true);
+        }
+        self.reassembly[start..end].copy_from_slice(data);
+        self.reassembly_len = end;
+
+        // Wait until the whole message (including the basic L2CAP header) has been received
+        if end < 4 {
+            return Ok(true);
+        }
+        let msg_len = 4 + usize::from(u16::from_le_bytes([self.reassembly[0], self.reassembly[1]]));
+        if end < msg_len {
+            return Ok(true);
+        }
+
+        let consume = self
+            .l2cap
+            .tx(&mut self.tx)
+            .process_start(&self.reassembly[..end]);
+        if consume.should_consume() {
+            self.reassembly_len = 0;
+            consume.into_result().map(|()| true)
+        } else {
+            // Undo the fragment, it will be processed again
+            self.reassembly_len = start;
+            Ok(false)
+        }
+    }
+}
+
+/// A `Producer` sending L2CAP data to the controller as ACL data packets.
+///
+/// This is the HCI counterpart to the TX packet queue of Rubble's Link-Layer.
+pub struct AclSender<T: Transport> {
+    transport: T,
+
+    /// Handle of the current connection.
+    handle: Option<u16>,
+
+    /// Max. length of ACL data the controller accepts.
+    max_len: u16,
+
+    /// Number of ACL packets the controller can currently accept.
+    credits: u16,
+}
+
+impl<T: Transport> Producer for AclSender<T> {
+    fn free_space(&self) -> u8 {
+        match self.handle {
+            Some(_) if self.credits > 0 => self.max_len.min(255) as u8,
+            _ => 0,
+        }
+    }
+
+    fn produce_dyn(
+        &mut self,
+        payload_bytes: u8,
+        f: &mut dyn FnMut(&mut ByteWriter<'_>) -> Result<Llid, Error>,
+    ) -> Result<(), Error> {
+        let handle = match self.handle {
+            Some(handle) if usize::from(payload_bytes) <= usize::from(self.free_space()) => handle,
+            _ => return Err(Error::Eof),
+        };
+
+        let mut payload = [0; 255];
+        let mut writer = ByteWriter::new(&mut payload[..usize::from(payload_bytes)]);
+        let llid = f(&mut writer)?;
+        let used = usize::from(payload_bytes) - writer.space_left();
+        let boundary = match llid {
+            Llid::DataStart => PacketBoundary::FirstNonFlushable,
+            Llid::DataCont => PacketBoundary::Continuation,
+            // LL Control PDUs are handled by the controller
+            Llid::Control | Llid::Reserved => return Err(Error::InvalidValue),
+        };
+
+        let mut buf = [0; 1 + AclHeader::SIZE + 255];
+        let len = 1 + AclHeader::SIZE + used;
+        write_acl(
+            &mut ByteWriter::new(&mut buf),
+            handle,
+            boundary,
+            &payload[..used],
+        )?;
+        trace!("HCI-> ACL {:?}", HexSlice(&buf[..len]));
+        self.transport.write(&buf[..len])?;
+        self.credits -= 1;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::l2cap::BleChannelMap;
+    use crate::link::AddressKind;
+    use crate::time::Duration;
+    use std::vec::Vec;
+
+    #[derive(Default)]
+    struct MockTransport {
+        rx: Vec<u8>,
+        tx: Vec<Vec<u8>>,
+    }
+
+    impl Transport for MockTransport {
+        fn read(&mut self, buf: &mut [u8]) -> usize {
+            let len = buf.len().min(self.rx.len());
+            buf[..len].copy_from_slice(&self.rx[..len]);
+            self.rx.drain(..len);
+            len
+        }
+
+        fn write(&mut self, data: &[u8]) -> Result<(), Error> {
+            self.tx.push(data.to_vec());
+            Ok(())
+        }
+    }
+
+    fn push_event(transport: &mut MockTransport, event: Event<'_>) {
+        let mut buf = [0; 64];
+        let mut writer = ByteWriter::new(&mut buf);
+        writer.write_u8(PacketType::Event.into()).unwrap();
+        event.to_bytes(&mut writer).unwrap();
+        let len = 64 - writer.space_left();
+        transport.rx.extend_from_slice(&buf[..len]);
+    }
+
+    #[test]
+    fn connection_and_acl() {
+        let mut host = Host::new(
+            MockTransport::default(),
+            L2CAPState::new(BleChannelMap::empty()),
+        );
+
+        host.send_command(Command::Reset).unwrap();
+        assert_eq!(
+            host.transport().tx.pop().unwrap(),
+            &[0x01, 0x03, 0x0C, 0x00]
+        );
+        // Only a single command may be outstanding
+        assert_eq!(host.send_command(Command::Reset), Err(Error::Eof));
+
+        push_event(
+            host.transport(),
+            Event::CommandComplete {
+                num_packets: 1,
+                opcode: Opcode::Reset,
+                params: &[Status::Success.into()],
+            },
+        );
+        push_event(
+            host.transport(),
+            Event::LeConnectionComplete {
+                status: Status::Success,
+                handle: 0x40,
+                peer_address: DeviceAddress::new([1, 2, 3, 4, 5, 6], AddressKind::Random),
+                interval: Duration::from_micros(30_000),
+                latency: 0,
+                supervision_timeout: Duration::from_millis(720),
+            },
+        );
+
+        match host.process_one().unwrap() {
+            Some(HostEvent::CommandComplete { opcode, status }) => {
+                assert_eq!(opcode, Opcode::Reset);
+                assert_eq!(status, Status::Success);
+            }
+            e => panic!("unexpected event {:?}", e),
+        }
+        match host.process_one().unwrap() {
+            Some(HostEvent::Connected { handle, .. }) => assert_eq!(handle, 0x40),
+            e => panic!("unexpected event {:?}", e),
+        }
+        assert!(host.is_connected());
+        assert_eq!(host.process_one().unwrap_err(), Error::Eof);
+
+        // ATT Exchange MTU Request, split across 2 ACL data packets
+        host.transport()
+            .rx
+            .extend_from_slice(&[0x02, 0x40, 0x20, 2, 0, 3, 0]);
+        host.transport()
+            .rx
+            .extend_from_slice(&[0x02, 0x40, 0x10, 5, 0, 4, 0, 0x02, 0x17, 0x00]);
+        assert!(host.process_one().unwrap().is_none());
+        assert!(host.transport().tx.is_empty());
+        assert!(host.process_one().unwrap().is_none());
+
+        // The response must be sent as a single ACL data packet on the same handle
+        let response = host.transport().tx.pop().unwrap();
+        let header = AclHeader::from_bytes(&mut ByteReader::new(&response[1..])).unwrap();
+        assert_eq!(response[0], u8::from(PacketType::AclData));
+        assert_eq!(header.handle, 0x40);
+        assert_eq!(header.boundary, PacketBoundary::FirstNonFlushable);
+        assert_eq!(
+            usize::from(header.length),
+            response.len() - 1 - AclHeader::SIZE
+        );
+        assert_eq!(&response[5..9], &[3, 0, 4, 0]);
+        assert_eq!(response[9], 0x03);
+
+        // No buffer is free until the controller reports the packet as completed
+        assert_eq!(host.tx.free_space(), 0);
+    }
+}
@@ -0,0 +1,1026 @@
+//! Host Controller Interface (HCI).
+//!
+//! The HCI is the standardized interface between a Bluetooth *Host* (L2CAP, ATT, GATT, SMP, ...)
+//! and a *Controller* (the Link-Layer and radio). It allows running the host stack on a different
+//! chip than the controller, for example BlueZ on a Linux machine talking to a Rubble-based
+//! controller over UART.
+//!
+//! This module implements the controller side: The [`Controller`] parses HCI commands received
+//! over a [`Transport`], configures the Rubble Link-Layer accordingly, and reports events and
+//! received data back to the host. Data is exchanged as HCI ACL data packets, which carry the
+//! L2CAP data of the data channel PDUs.
+//!
+//! The [`Host`] implements the other side: It runs Rubble's L2CAP (and everything on top of it)
+//! against an external controller, sending commands to it and exchanging L2CAP data via ACL data
+//! packets.
+//!
+//! Packets are framed using the UART transport layer (also known as "H:4"), which prefixes every
+//! packet with a 1-Byte [`PacketType`] indicator.
+//!
+//! # Limitations
+//!
+//! Rubble only implements the Peripheral role, so `LE Create Connection` is rejected. Unless the
+//! host has requested the disconnection, the Link-Layer doesn't report why a connection ended.
+//! Link encryption is not supported, since the HCI LTK request commands aren't implemented, so the
+//! controller rejects the master's `LL_ENC_REQ`.
+//!
+//! [`Controller`]: struct.Controller.html
+//! [`Host`]: struct.Host.html
+//! [`Transport`]: trait.Transport.html
+//! [`PacketType`]: enum.PacketType.html
+
+mod acl;
+mod command;
+mod event;
+mod host;
+
+pub use self::acl::{AclHeader, PacketBoundary};
+pub use self::command::{Command, Opcode, MAX_ADV_DATA_LEN};
+pub use self::event::{Event, EventCode, Status};
+pub use self::host::{AclSender, Host, HostEvent};
+
+use crate::link::advertising::{PduBuf, PduType};
+use crate::link::data::{Llid, Pdu};
+use crate::link::llcp::{ControlPdu, PIN_OR_KEY_MISSING};
+use crate::link::queue::{Consume, Consumer, PacketQueue, Producer};
+use crate::link::{FeatureSet, LinkLayer, NextUpdate, MIN_DATA_PAYLOAD_BUF};
+use crate::phy::AdvertisingChannelMap;
+use crate::time::Duration;
+use crate::utils::{Hex, HexSlice};
+use crate::{bytes::*, config::*, Error, BLUETOOTH_VERSION};
+
+enum_with_unknown! {
+    /// Packet type indicator preceding every packet on the UART transport.
+    #[derive(Debug, Copy, Clone, PartialEq, Eq, defmt::Format)]
+    pub enum PacketType(u8) {
+        Command = 0x01,
+        AclData = 0x02,
+        SyncData = 0x03,
+        Event = 0x04,
+    }
+}
+
+/// A byte-oriented serial link to the HCI host (eg. a UART).
+pub trait Transport {
+    /// Reads available Bytes into `buf` without blocking.
+    ///
+    /// Returns the number of Bytes written to `buf`, which is 0 if no data is available.
+    fn read(&mut self, buf: &mut [u8]) -> usize;
+
+    /// Writes all of `data` to the host.
+    fn write(&mut self, data: &[u8]) -> Result<(), Error>;
+}
+
+/// The connection handle reported for the (single) Link-Layer connection.
+const CONNECTION_HANDLE: u16 = 0x0001;
+
+/// Reasons the host may pass to the `Disconnect` command.
+const DISCONNECT_REASONS: &[u8] = &[0x05, 0x13, 0x14, 0x15, 0x1A, 0x29, 0x3B];
+
+/// Number of ACL data packets the host may have in flight.
+const ACL_PACKETS: u8 = 1;
+
+/// Size of the receive buffer, which fits the largest possible command packet.
+const RX_BUF: usize = 1 + 3 + 255;
+
+/// Buffer for assembling packets received over the transport.
+struct RxBuffer {
+    buf: [u8; RX_BUF],
+    len: usize,
+}
+
+impl RxBuffer {
+    fn new() -> Self {
+        Self {
+            buf: [0; RX_BUF],
+            len: 0,
+        }
+    }
+
+    /// Reads from `transport` until a complete packet is buffered.
+    ///
+    /// Returns the length of the packet (including the packet type indicator), or `None` if no
+    /// complete packet is available yet. If the packet type is not in `accepted`, the buffered data
+    /// is discarded and an error is returned, since the stream can't be resynchronized.
+    fn fill<T: Transport>(
+        &mut self,
+        transport: &mut T,
+        accepted: &[PacketType],
+    ) -> Result<Option<usize>, Error> {
+        loop {
+            if let Some(len) = self.packet_len(accepted)? {
+                if len <= self.len {
+                    return Ok(Some(len));
+                }
+            }
+
+            let read = transport.read(&mut self.buf[self.len..]);
+            if read == 0 {
+                return Ok(None);
+            }
+            self.len += read;
+        }
+    }
+
+    /// Returns the length of the first packet in the buffer, if its header is complete.
+    fn packet_len(&mut self, accepted: &[PacketType]) -> Result<Option<usize>, Error> {
+        let buf = &self.buf[..self.len];
+        let ty = match buf.first() {
+            Some(ty) => PacketType::from(*ty),
+            None => return Ok(None),
+        };
+        let len = match ty {
+            _ if !accepted.contains(&ty) => {
+                warn!("HCI: unexpected packet type {:?}, discarding input", ty);
+                self.len = 0;
+                return Err(Error::InvalidValue);
+            }
+            PacketType::Command if buf.len() >= 4 => 4 + usize::from(buf[3]),
+            PacketType::Event if buf.len() >= 3 => 3 + usize::from(buf[2]),
+            PacketType::AclData if buf.len() > AclHeader::SIZE => {
+                1 + AclHeader::SIZE + usize::from(u16::from_le_bytes([buf[3], buf[4]]))
+            }
+            _ => return Ok(None),
+        };
+
+        if len > RX_BUF {
+            warn!(
+                "HCI: packet of {} Bytes exceeds buffer, discarding input",
+                len
+            );
+            self.len = 0;
+            return Err(Error::InvalidLength);
+        }
+        Ok(Some(len))
+    }
+
+    /// Returns the first `len` Bytes of the buffer.
+    fn packet(&self, len: usize) -> &[u8] {
+        &self.buf[..len]
+    }
+
+    /// Removes the first packet of `len` Bytes from the buffer.
+    fn consume(&mut self, len: usize) {
+        self.buf.copy_within(len..self.len, 0);
+        self.len -= len;
+    }
+}
+
+/// Default advertising interval set by `LE Set Advertising Parameters` (1.28 s).
+const DEFAULT_ADV_INTERVAL: u16 = 0x0800;
+
+/// Length-prefixed advertising or scan response data.
+#[derive(Copy, Clone)]
+struct AdvData {
+    buf: [u8; MAX_ADV_DATA_LEN],
+    len: usize,
+}
+
+impl AdvData {
+    const EMPTY: Self = Self {
+        buf: [0; MAX_ADV_DATA_LEN],
+        len: 0,
+    };
+
+    fn set(&mut self, data: &[u8]) {
+        self.buf[..data.len()].copy_from_slice(data);
+        self.len = data.len();
+    }
+
+    fn as_bytes(&self) -> &[u8] {
+        &self.buf[..self.len]
+    }
+}
+
+/// An HCI controller exposing Rubble's Link-Layer to a host over a [`Transport`].
+///
+/// The controller takes the place of the [`Responder`]: It owns the non-real-time ends of the
+/// packet queues and forwards data channel PDUs between the Link-Layer and the host. The
+/// application should call [`poll`] whenever the transport has received data or the Link-Layer
+/// indicates queued work.
+///
+/// [`Transport`]: trait.Transport.html
+/// [`Responder`]: ../link/struct.Responder.html
+/// [`poll`]: #method.poll
+pub struct Controller<C: Config, T: Transport> {
+    transport: T,
+    rx_buf: RxBuffer,
+
+    /// Queue ends used by the Controller.
+    tx: ConfProducer<C>,
+    rx: ConfConsumer<C>,
+
+    /// Queue ends handed to the Link-Layer when advertising is enabled.
+    ll_queues: Option<(ConfConsumer<C>, ConfProducer<C>)>,
+
+    adv_interval: Duration,
+    adv_data: AdvData,
+    scan_rsp_data: AdvData,
+
+    /// Whether the host has been notified of an established connection.
+    connected: bool,
+
+    /// Whether the host has requested the connection to be terminated.
+    disconnecting: bool,
+}
+
+impl<C: Config, T: Transport> Controller<C, T> {
+    /// Creates a new HCI controller.
+    ///
+    /// # Parameters
+    ///
+    /// * **`transport`**: The serial link to the host.
+    /// * **`tx`**: The queue for packets to transmit to the connected device.
+    /// * **`rx`**: The queue for packets received from the connected device.
+    pub fn new(transport: T, tx: C::PacketQueue, rx: C::PacketQueue) -> Self {
+        let (tx_prod, tx_cons) = tx.split();
+        let (rx_prod, rx_cons) = rx.split();
+        Self {
+            transport,
+            rx_buf: RxBuffer::new(),
+            tx: tx_prod,
+            rx: rx_cons,
+            ll_queues: Some((tx_cons, rx_prod)),
+            adv_interval: adv_interval(DEFAULT_ADV_INTERVAL),
+            adv_data: AdvData::EMPTY,
+            scan_rsp_data: AdvData::EMPTY,
+            connected: false,
+            disconnecting: false,
+        }
+    }
+
+    /// Returns a reference to the transport.
+    pub fn transport(&mut self) -> &mut T {
+        &mut self.transport
+    }
+
+    /// Exchanges pending packets with the host and the Link-Layer.
+    ///
+    /// This reports connection state changes to the host, forwards received data channel PDUs as
+    /// ACL data, and processes all complete packets received over the transport.
+    ///
+    /// If a command changed the Link-Layer's timer configuration, the returned `NextUpdate` must be
+    /// applied by the caller. When it is `NextUpdate::Disable`, the Link-Layer has entered Standby
+    /// (eg. because advertising was disabled or the controller was reset), and the caller also has
+    /// to switch off the radio.
+    ///
+    /// Errors are only returned if the transport fails or the host sends malformed packets that
+    /// make the stream unrecoverable.
+    pub fn poll(
+        &mut self,
+        ll: &mut LinkLayer<C>,
+        radio: &mut C::Transmitter,
+    ) -> Result<Option<NextUpdate>, Error> {
+        let mut next_update = self.update_connection_state(ll)?;
+        while self.rx.has_data() {
+            self.forward_rx()?;
+        }
+
+        while let Some(len) = self.rx_buf.fill(
+            &mut self.transport,
+            &[PacketType::Command, PacketType::AclData],
+        )? {
+            let mut packet = [0; RX_BUF];
+            packet[..len].copy_from_slice(self.rx_buf.packet(len));
+            if packet[0] == u8::from(PacketType::Command) {
+                if let Some(update) = self.process_command(&packet[1..len], ll, radio)? {
+                    next_update = Some(update);
+                }
+            } else if !self.process_acl(&packet[1..len])? {
+                // No space in the TX queue; retry on the next call
+                break;
+            }
+
+            self.rx_buf.consume(len);
+        }
+
+        Ok(next_update)
+    }
+
+    /// Sends connection state changes of the Link-Layer to the host.
+    ///
+    /// When a connection has ended, this takes back the packet queues, so that advertising can be
+    /// enabled again.
+    fn update_connection_state(
+        &mut self,
+        ll: &mut LinkLayer<C>,
+    ) -> Result<Option<NextUpdate>, Error> {
+        match (ll.connection(), self.connected) {
+            (Some(conn), false) => {
+                self.connected = true;
+                self.send_event(Event::LeConnectionComplete {
+                    status: Status::Success,
+                    handle: CONNECTION_HANDLE,
+                    peer_address: *conn.peer_address(),
+                    interval: conn.connection_interval(),
+                    latency: conn.slave_latency(),
+                    supervision_timeout: conn.supervision_timeout(),
+                })?;
+                Ok(None)
+            }
+            (None, true) => {
+                self.connected = false;
+                let reason = if self.disconnecting {
+                    Status::LocalHostTerminated
+                } else {
+                    // The Link-Layer doesn't tell us why the connection ended
+                    Status::ConnectionTimeout
+                };
+                self.disconnecting = false;
+                let update = self.reclaim_queues(ll);
+                self.send_event(Event::DisconnectionComplete {
+                    status: Status::Success,
+                    handle: CONNECTION_HANDLE,
+                    reason,
+                })?;
+                Ok(update)
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// Takes back the packet queues from the Link-Layer and discards the packets left in them.
+    ///
+    /// If a connection fails to be established, the Link-Layer resumes advertising, while HCI hosts
+    /// expect advertising to stay disabled once a connection was created. Advertising is stopped in
+    /// that case.
+    ///
+    /// Returns `NextUpdate::Disable` if the queues were taken back, since the Link-Layer is in
+    /// Standby then.
+    fn reclaim_queues(&mut self, ll: &mut LinkLayer<C>) -> Option<NextUpdate> {
+        let (mut tx, rx) = match ll.take_released_queues() {
+            Some((_, tx, rx)) => (tx, rx),
+            None => ll.stop_advertising()?,
+        };
+
+        // Don't send stale data on the next connection
+        discard(&mut tx);
+        discard(&mut self.rx);
+        self.ll_queues = Some((tx, rx));
+        Some(NextUpdate::Disable)
+    }
+
+    /// Forwards the next received data channel PDU to the host.
+    fn forward_rx(&mut self) -> Result<(), Error> {
+        let transport = &mut self.transport;
+        let tx = &mut self.tx;
+        self.rx.consume_pdu_with(|header, pdu| match pdu {
+            Pdu::Control { data } => {
+                // Control PDUs not handled by the real-time code are not supported
+                let pdu = data.read();
+                let response = match pdu {
+                    ControlPdu::EncReq { .. } => ControlPdu::RejectInd {
+                        error_code: Hex(PIN_OR_KEY_MISSING),
+                    },
+                    _ => ControlPdu::UnknownRsp {
+                        unknown_type: pdu.opcode(),
+                    },
+                };
+                Consume::on_success(tx.produce_with(response.encoded_size(), |writer| {
+                    response.to_bytes(writer)?;
+                    Ok(Llid::Control)
+                }))
+            }
+            Pdu::DataStart { message } | Pdu::DataCont { message } => {
+                let boundary = PacketBoundary::from_llid(header.llid()).unwrap();
+                let mut buf = [0; 1 + AclHeader::SIZE + MIN_DATA_PAYLOAD_BUF];
+                let len = 1 + AclHeader::SIZE + message.len();
+                let result = write_acl(
+                    &mut ByteWriter::new(&mut buf),
+                    CONNECTION_HANDLE,
+                    boundary,
+                    message,
+                )
+                .and_then(|()| {
+                    trace!(logger: C::Logger, "HCI-> ACL {:?}", HexSlice(&buf[..len]));
+                    transport.write(&buf[..len])
+                });
+                Consume::always(result)
+            }
+        })
+    }
+
+    /// Processes an ACL data packet from the host.
+    ///
+    /// Returns `Ok(false)` if there was no space in the TX queue to enqueue the data.
+    fn process_acl(&mut self, packet: &[u8]) -> Result<bool, Error> {
+        let mut bytes = ByteReader::new(packet);
+        let header = AclHeader::from_bytes(&mut bytes)?;
+        let data = bytes.read_rest();
+        if header.handle != CONNECTION_HANDLE || !self.connected {
+            warn!(
+                logger: C::Logger,
+                "HCI: ACL data for unknown handle {}, dropping",
+                header.handle
+            );
+            return Ok(true);
+        }
+        if data.len() > MIN_DATA_PAYLOAD_BUF {
+            warn!(logger: C::Logger, "HCI: ACL data exceeds buffer size, dropping");
+            return Ok(true);
+        }
+
+        if usize::from(self.tx.free_space()) < data.len() {
+            return Ok(false);
+        }
+        self.tx
+            .produce_with(data.len() as u8, |writer| -> Result<_, Error> {
+                writer.write_slice(data)?;
+                Ok(header.boundary.to_llid())
+            })?;
+
+        // The data is now buffered by the Link-Layer, so the host can reuse its buffer
+        self.send_event(Event::NumberOfCompletedPackets {
+            handle: CONNECTION_HANDLE,
+            completed: 1,
+        })?;
+        Ok(true)
+    }
+
+    /// Executes an HCI command and sends the resulting event to the host.
+    fn process_command(
+        &mut self,
+        packet: &[u8],
+        ll: &mut LinkLayer<C>,
+        radio: &mut C::Transmitter,
+    ) -> Result<Option<NextUpdate>, Error> {
+        let cmd = match Command::from_bytes(&mut ByteReader::new(packet)) {
+            Ok(cmd) => cmd,
+            Err(e) => {
+                warn!(logger: C::Logger, "HCI: malformed command {:?}: {:?}", HexSlice(packet), e);
+                let opcode = Opcode::from(u16::from_le_bytes([packet[0], packet[1]]));
+                return self.command_complete(opcode, Status::InvalidParameters, &[]);
+            }
+        };
+        debug!(logger: C::Logger, "HCI<- {:?}", cmd);
+
+        let opcode = cmd.opcode();
+        let mut params = [0; 64];
+        let mut writer = ByteWriter::new(&mut params);
+        let status = match cmd {
+            Command::Reset => {
+                // The connection is dropped without sending `Disconnection Complete`
+                ll.abort_connection();
+                self.connected = false;
+                self.disconnecting = false;
+                let update = self.reclaim_queues(ll);
+
+                ll.set_advertising_channels(AdvertisingChannelMap::ALL);
+
+                self.adv_interval = adv_interval(DEFAULT_ADV_INTERVAL);
+                self.adv_data = AdvData::EMPTY;
+                self.scan_rsp_data = AdvData::EMPTY;
+                self.command_complete(opcode, Status::Success, &[])?;
+                return Ok(update);
+            }
+            Command::SetEventMask { .. } | Command::LeSetEventMask { .. } => {
+                // All events are always sent
+                Status::Success
+            }
+            Command::ReadLocalVersionInformation => {
+                let version = u8::from(BLUETOOTH_VERSION);
+                writer.write_u8(version)?; // HCI version
+                writer.write_u16_le(0)?; // HCI revision
+                writer.write_u8(version)?; // LMP/PAL version
+                writer.write_u16_le(C::COMPANY_ID.as_u16())?; // Manufacturer name
+                writer.write_u16_le(C::SUBVERSION)?; // LMP/PAL subversion
+                Status::Success
+            }
+            Command::ReadLocalSupportedCommands => {
+                writer.write_slice(&supported_commands())?;
+                Status::Success
+            }
+            Command::ReadLocalSupportedFeatures => {
+                // "BR/EDR Not Supported" and "LE Supported (Controller)"
+                writer.write_u64_le(1 << 37 | 1 << 38)?;
+                Status::Success
+            }
+            Command::ReadBufferSize => {
+                // ACL data packets are exchanged using the LE buffers
+                writer.write_u16_le(0)?;
+                writer.write_u8(0)?;
+                writer.write_u16_le(0)?;
+                writer.write_u16_le(0)?;
+                Status::Success
+            }
+            Command::LeReadBufferSize => {
+                writer.write_u16_le(MIN_DATA_PAYLOAD_BUF as u16)?;
+                writer.write_u8(ACL_PACKETS)?;
+                Status::Success
+            }
+            Command::ReadBdAddr => {
+                let addr = ll.device_address();
+                if addr.is_random() {
+                    // No public address
+                    writer.write_slice(&[0; 6])?;
+                } else {
+                    writer.write_slice(addr.raw())?;
+                }
+                Status::Success
+            }
+            Command::LeReadLocalSupportedFeatures => {
+                let features = (C::FEATURES & FeatureSet::supported()) - FeatureSet::LE_ENCRYPTION;
+                let features = features.offerable();
+                features.to_bytes(&mut writer)?;
+                Status::Success
+            }
+            Command::LeSetAdvertisingParameters {
+                interval_min,
+                interval_max,
+                adv_type,
+                channel_map,
+                ..
+            } => {
+                let channels = AdvertisingChannelMap::from_raw(channel_map);
+                if ll.is_advertising() {
+                    Status::CommandDisallowed
+                } else if !(0x0020..=0x4000).contains(&interval_min)
+                    || interval_max < interval_min
+                    || channels.is_err()
+                {
+                    Status::InvalidParameters
+                } else if adv_type != 0x00 {
+                    // Only connectable undirected advertising (`ADV_IND`) is supported
+                    Status::UnsupportedFeature
+                } else {
+                    self.adv_interval = adv_interval(interval_min);
+                    if let Ok(channels) = channels {
+                        ll.set_advertising_channels(channels);
+                    }
+                    Status::Success
+                }
+            }
+            Command::LeSetAdvertisingData { data } => {
+                self.adv_data.set(data);
+                Status::Success
+            }
+            Command::LeSetScanResponseData { data } => {
+                self.scan_rsp_data.set(data);
+                Status::Success
+            }
+            Command::LeSetAdvertiseEnable { enable: true } => {
+                if ll.is_advertising() {
+                    Status::Success
+                } else if let Some((tx, rx)) = self.ll_queues.take() {
+                    let addr = *ll.device_address();
+                    let pdu =
+                        PduBuf::from_raw_adv_data(PduType::AdvInd, addr, self.adv_data.as_bytes())?;
+                    let scan_rsp = PduBuf::from_raw_adv_data(
+                        PduType::ScanRsp,
+                        addr,
+                        self.scan_rsp_data.as_bytes(),
+                    )?;
+                    let update =
+                        ll.start_advertise_pdu(self.adv_interval, pdu, scan_rsp, radio, tx, rx)?;
+                    self.command_complete(opcode, Status::Success, &[])?;
+                    return Ok(Some(update));
+                } else {
+                    // The Link-Layer has consumed the queues
+                    Status::CommandDisallowed
+                }
+            }
+            Command::LeSetAdvertiseEnable { enable: false } => {
+                let update = if ll.is_advertising() {
+                    self.reclaim_queues(ll)
+                } else {
+                    None
+                };
+                self.command_complete(opcode, Status::Success, &[])?;
+                return Ok(update);
+            }
+            Command::Disconnect { handle, reason } => {
+                let pdu = ControlPdu::TerminateInd {
+                    error_code: Hex(reason),
+                };
+                let status = if handle != CONNECTION_HANDLE || !self.connected {
+                    Status::UnknownConnectionIdentifier
+                } else if !DISCONNECT_REASONS.contains(&reason) {
+                    Status::InvalidParameters
+                } else if self.disconnecting {
+                    Status::CommandDisallowed
+                } else if self.tx.free_space() < pdu.encoded_size() {
+                    Status::MemoryCapacityExceeded
+                } else {
+                    // `Disconnection Complete` is sent once the Link-Layer has closed the
+                    // connection, after the peer has acknowledged the `LL_TERMINATE_IND`
+                    self.tx
+                        .produce_with(pdu.encoded_size(), |writer| -> Result<_, Error> {
+                            pdu.to_bytes(writer)?;
+                            Ok(Llid::Control)
+                        })?;
+                    self.disconnecting = true;
+                    Status::Success
+                };
+                return self.command_status(opcode, status);
+            }
+            Command::LeCreateConnection { .. } => {
+                // Rubble doesn't implement the Central role
+                return self.command_status(opcode, Status::UnsupportedFeature);
+            }
+            Command::Unknown { .. } => {
+                warn!(logger: C::Logger, "HCI: unknown command {:?}", opcode);
+                Status::UnknownCommand
+            }
+        };
+
+        let used = writer.space_left();
+        let len = params.len() - used;
+        self.command_complete(opcode, status, &params[..len])
+    }
+
+    fn command_complete(
+        &mut self,
+        opcode: Opcode,
+        status: Status,
+        params: &[u8],
+    ) -> Result<Option<NextUpdate>, Error> {
+        let mut buf = [0; 1 + 64];
+        buf[0] = status.into();
+        buf[1..=params.len()].copy_from_slice(params);
+        self.send_event(Event::CommandComplete {
+            num_packets: 1,
+            opcode,
+            params: &buf[..=params.len()],
+        })?;
+        Ok(None)
+    }
+
+    fn command_status(
+        &mut self,
+        opcode: Opcode,
+        status: Status,
+    ) -> Result<Option<NextUpdate>, Error> {
+        self.send_event(Event::CommandStatus {
+            status,
+            num_packets: 1,
+            opcode,
+        })?;
+        Ok(None)
+    }
+
+    fn send_event(&mut self, event: Event<'_>) -> Result<(), Error> {
+        debug!(logger: C::Logger, "HCI-> {:?}", event);
+        let mut buf = [0; 1 + 2 + 255];
+        let mut writer = ByteWriter::new(&mut buf);
+        writer.write_u8(PacketType::Event.into())?;
+        event.to_bytes(&mut writer)?;
+        let used = writer.space_left();
+        let len = buf.len() - used;
+        self.transport.write(&buf[..len])
+    }
+}
+
+/// Writes an ACL data packet (including the packet type indicator) containing `data`.
+fn write_acl(
+    writer: &mut ByteWriter<'_>,
+    handle: u16,
+    boundary: PacketBoundary,
+    data: &[u8],
+) -> Result<(), Error> {
+    writer.write_u8(PacketType::AclData.into())?;
+    AclHeader {
+        handle,
+        boundary,
+        length: data.len() as u16,
+    }
+    .to_bytes(writer)?;
+    writer.write_slice(data)
+}
+
+/// Removes all packets from `queue`.
+fn discard(queue: &mut impl Consumer) {
+    while queue
+        .consume_raw_with(|_, _| Consume::always(Ok(())))
+        .is_ok()
+    {}
+}
+
+/// Converts an advertising interval in units of 0.625 ms to a `Duration`.
+fn adv_interval(units: u16) -> Duration {
+    Duration::from_micros(u32::from(units) * 625)
+}
+
+/// Returns the *Supported Commands* bitmap reported by `Read Local Supported Commands`.
+fn supported_commands() -> [u8; 64] {
+    // (octet, bit) positions as listed in the Core Specification, Vol 4, Part E, 6.27
+    const SUPPORTED: &[(usize, u8)] = &[
+        (0, 5),  // Disconnect
+        (5, 6),  // Set Event Mask
+        (5, 7),  // Reset
+        (14, 3), // Read Local Version Information
+        (14, 5), // Read Local Supported Features
+        (14, 7), // Read Buffer Size
+        (15, 1), // Read BD_ADDR
+        (25, 0), // LE Set Event Mask
+        (25, 1), // LE Read Buffer Size
+        (25, 2), // LE Read Local Supported Features
+        (25, 5), // LE Set Advertising Parameters
+        (25, 7), // LE Set Advertising Data
+        (26, 0), // LE Set Scan Response Data
+        (26, 1), // LE Set Advertise Enable
+    ];
+
+    let mut commands = [0; 64];
+    for &(octet, bit) in SUPPORTED {
+        commands[octet] |= 1 << bit;
+    }
+    commands
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::att::NoAttributes;
+    use crate::l2cap::BleChannelMap;
+    use crate::link::advertising;
+    use crate::link::queue::SimpleQueue;
+    use crate::link::{data, AddressKind, DeviceAddress, SeqNum, Transmitter};
+    use crate::log::NoLogger;
+    use crate::phy::{AdvertisingChannel, DataChannel};
+    use crate::security::NoSecurity;
+    use crate::test_rng::TestRng;
+    use crate::time::{Instant, Timer};
+    use std::vec::Vec;
+
+    const ADDRESS: DeviceAddress = DeviceAddress::new([1, 0, 0, 0, 0, 0xC0], AddressKind::Random);
+    const PEER: DeviceAddress = DeviceAddress::new([2, 0, 0, 0, 0, 0], AddressKind::Public);
+
+    struct TestConfig;
+
+    impl Config for TestConfig {
+        type Timer = TestTimer;
+        type Transmitter = TestRadio;
+        type ChannelMapper = BleChannelMap<NoAttributes, NoSecurity>;
+        type PacketQueue = &'static mut SimpleQueue;
+        type Rng = TestRng;
+        type Logger = NoLogger;
+    }
+
+    /// A timer that stays at time 0, which is fine since no timer events are run.
+    struct TestTimer;
+
+    impl Timer for TestTimer {
+        fn now(&self) -> Instant {
+            Instant::from_raw_micros(0)
+        }
+    }
+
+    /// Records the payload of the last transmitted data channel PDU.
+    struct TestRadio {
+        buf: [u8; 37],
+        data: Option<Vec<u8>>,
+    }
+
+    impl Transmitter for TestRadio {
+        fn tx_payload_buf(&mut self) -> &mut [u8] {
+            &mut self.buf
+        }
+
+        fn transmit_advertising(&mut self, _: advertising::Header, _: AdvertisingChannel) {}
+
+        fn transmit_data(&mut self, _: u32, _: u32, header: data::Header, _: DataChannel) {
+            let len = usize::from(header.payload_length());
+            self.data = Some(self.buf[..len].to_vec());
+        }
+
+        fn set_tx_power(&mut self, dbm: i8) -> i8 {
+            dbm
+        }
+    }
+
+    #[derive(Default)]
+    struct MockTransport {
+        rx: Vec<u8>,
+        tx: Vec<Vec<u8>>,
+    }
+
+    impl Transport for MockTransport {
+        fn read(&mut self, buf: &mut [u8]) -> usize {
+            let len = buf.len().min(self.rx.len());
+            buf[..len].copy_from_slice(&self.rx[..len]);
+            self.rx.drain(..len);
+            len
+        }
+
+        fn write(&mut self, data: &[u8]) -> Result<(), Error> {
+            self.tx.push(data.to_vec());
+            Ok(())
+        }
+    }
+
+    struct Setup {
+        ll: LinkLayer<TestConfig>,
+        radio: TestRadio,
+        controller: Controller<TestConfig, MockTransport>,
+    }
+
+    impl Setup {
+        fn new() -> Self {
+            let queue = || Box::leak(Box::new(SimpleQueue::new()));
+            Self {
+                ll: LinkLayer::new(ADDRESS, TestTimer),
+                radio: TestRadio {
+                    buf: [0; 37],
+                    data: None,
+                },
+                controller: Controller::new(MockTransport::default(), queue(), queue()),
+            }
+        }
+
+        fn poll(&mut self) -> Option<NextUpdate> {
+            self.controller.poll(&mut self.ll, &mut self.radio).unwrap()
+        }
+
+        /// Sends a command to the controller and returns the status it reports.
+        fn command(&mut self, opcode: Opcode, params: &[u8]) -> (Status, Option<NextUpdate>) {
+            let rx = &mut self.controller.transport.rx;
+            rx.push(PacketType::Command.into());
+            rx.extend_from_slice(&u16::from(opcode).to_le_bytes());
+            rx.push(params.len() as u8);
+            rx.extend_from_slice(params);
+
+            let update = self.poll();
+            let events = self.events();
+            assert_eq!(events.len(), 1);
+            let event = &events[0];
+            let status = match EventCode::from(event[1]) {
+                EventCode::CommandComplete => {
+                    assert_eq!(event[4..6], u16::from(opcode).to_le_bytes());
+                    event[6]
+                }
+                EventCode::CommandStatus => {
+                    assert_eq!(event[5..7], u16::from(opcode).to_le_bytes());
+                    event[3]
+                }
+                code => panic!("unexpected event {:?}", code),
+            };
+            (Status::from(status), update)
+        }
+
+        /// Returns the events sent to the host since the last call.
+        fn events(&mut self) -> Vec<Vec<u8>> {
+            for event in &self.controller.transport.tx {
+                assert_eq!(event[0], u8::from(PacketType::Event));
+                assert_eq!(usize::from(event[2]), event.len() - 3);
+            }
+            core::mem::take(&mut self.controller.transport.tx)
+        }
+
+        fn enable_advertising(&mut self, enable: bool) -> (Status, Option<NextUpdate>) {
+            self.command(Opcode::LeSetAdvertiseEnable, &[enable as u8])
+        }
+
+        fn disconnect(&mut self, handle: u16, reason: u8) -> Status {
+            let mut params = [0; 3];
+            params[..2].copy_from_slice(&handle.to_le_bytes());
+            params[2] = reason;
+            self.command(Opcode::Disconnect, &params).0
+        }
+
+        /// Sends a `CONNECT_REQ` from `PEER` to the advertising Link-Layer.
+        fn connect(&mut self) {
+            let mut payload = Vec::new();
+            payload.extend_from_slice(PEER.raw());
+            payload.extend_from_slice(ADDRESS.raw());
+            payload.extend_from_slice(&0x5065_A1B2_u32.to_le_bytes());
+            payload.extend_from_slice(&[0x55, 0x55, 0x55]); // CRC init
+            payload.push(1); // Window size
+            payload.extend_from_slice(&0_u16.to_le_bytes()); // Window offset
+            payload.extend_from_slice(&24_u16.to_le_bytes()); // Interval (30 ms)
+            payload.extend_from_slice(&0_u16.to_le_bytes()); // Latency
+            payload.extend_from_slice(&50_u16.to_le_bytes()); // Timeout (500 ms)
+            payload.extend_from_slice(&[0xFF, 0xFF, 0xFF, 0xFF, 0x1F]);
+            payload.push(7);
+
+            let mut header = advertising::Header::new(PduType::ConnectReq);
+            header.set_rx_add(ADDRESS.is_random());
+            header.set_payload_length(payload.len() as u8);
+            let now = self.ll.timer().now();
+            let _ = self
+                .ll
+                .process_adv_packet(now, &mut self.radio, header, &payload, true);
+            assert!(self.ll.is_connected());
+
+            assert!(self.poll().is_none());
+            let events = self.events();
+            assert_eq!(events.len(), 1);
+            assert_eq!(EventCode::from(events[0][1]), EventCode::LeMeta);
+        }
+    }
+
+    #[test]
+    fn advertise_enable_disable() {
+        let mut setup = Setup::new();
+        let (status, update) = setup.enable_advertising(true);
+        assert_eq!(status, Status::Success);
+        assert!(matches!(update, Some(NextUpdate::At(_))));
+        assert!(setup.ll.is_advertising());
+
+        // Parameters can't be changed while advertising
+        let mut params = [0; 15];
+        params[..2].copy_from_slice(&0x0100_u16.to_le_bytes());
+        params[2..4].copy_from_slice(&0x0100_u16.to_le_bytes());
+        params[13] = 0b111;
+        assert_eq!(
+            setup.command(Opcode::LeSetAdvertisingParameters, &params).0,
+            Status::CommandDisallowed
+        );
+
+        let (status, update) = setup.enable_advertising(false);
+        assert_eq!(status, Status::Success);
+        assert!(matches!(update, Some(NextUpdate::Disable)));
+        assert!(!setup.ll.is_advertising());
+        assert!(setup.ll.next_event().is_none());
+
+        // Disabling again has no effect
+        let (status, update) = setup.enable_advertising(false);
+        assert_eq!(status, Status::Success);
+        assert!(update.is_none());
+
+        assert_eq!(
+            setup.command(Opcode::LeSetAdvertisingParameters, &params).0,
+            Status::Success
+        );
+        assert_eq!(setup.enable_advertising(true).0, Status::Success);
+        assert!(setup.ll.is_advertising());
+    }
+
+    #[test]
+    fn reset() {
+        let mut setup = Setup::new();
+        setup.enable_advertising(true);
+        let (status, update) = setup.command(Opcode::Reset, &[]);
+        assert_eq!(status, Status::Success);
+        assert!(matches!(update, Some(NextUpdate::Disable)));
+        assert!(!setup.ll.is_advertising());
+
+        setup.enable_advertising(true);
+        setup.connect();
+
+        // The connection is dropped without reporting it to the host
+        let (status, update) = setup.command(Opcode::Reset, &[]);
+        assert_eq!(status, Status::Success);
+        assert!(matches!(update, Some(NextUpdate::Disable)));
+        assert!(!setup.ll.is_connected());
+        assert!(setup.ll.next_event().is_none());
+        assert!(setup.poll().is_none());
+        assert!(setup.events().is_empty());
+
+        assert_eq!(setup.enable_advertising(true).0, Status::Success);
+        assert!(setup.ll.is_advertising());
+    }
+
+    #[test]
+    fn disconnect() {
+        let mut setup = Setup::new();
+        assert_eq!(
+            setup.disconnect(CONNECTION_HANDLE, 0x13),
+            Status::UnknownConnectionIdentifier
+        );
+
+        setup.enable_advertising(true);
+        setup.connect();
+        assert_eq!(
+            setup.disconnect(CONNECTION_HANDLE + 1, 0x13),
+            Status::UnknownConnectionIdentifier
+        );
+        assert_eq!(
+            setup.disconnect(CONNECTION_HANDLE, 0x16),
+            Status::InvalidParameters
+        );
+        assert_eq!(setup.disconnect(CONNECTION_HANDLE, 0x13), Status::Success);
+        assert_eq!(
+            setup.disconnect(CONNECTION_HANDLE, 0x13),
+            Status::CommandDisallowed
+        );
+
+        // The Link-Layer sends `LL_TERMINATE_IND` once the master has acknowledged its first packet,
+        // and closes the connection when the `LL_TERMINATE_IND` is acknowledged
+        for &seq in &[SeqNum::ZERO, SeqNum::ONE, SeqNum::ZERO] {
+            assert!(setup.ll.is_connected());
+            let mut header = data::Header::new(Llid::DataCont);
+            header.set_sn(seq);
+            header.set_nesn(seq);
+            let now = setup.ll.timer().now();
+            let _ = setup
+                .ll
+                .process_data_packet(now, &mut setup.radio, header, &[], true);
+        }
+        assert_eq!(setup.radio.data.as_deref(), Some(&[0x02, 0x13][..]));
+        assert!(!setup.ll.is_connected());
+
+        assert!(matches!(setup.poll(), Some(NextUpdate::Disable)));
+        let events = setup.events();
+        assert_eq!(events.len(), 1);
+        assert_eq!(
+            EventCode::from(events[0][1]),
+            EventCode::DisconnectionComplete
+        );
+        assert_eq!(events[0][4..6], CONNECTION_HANDLE.to_le_bytes());
+        assert_eq!(Status::from(events[0][6]), Status::LocalHostTerminated);
+
+        // The queues have been taken back
+        assert_eq!(setup.enable_advertising(true).0, Status::Success);
+        assert!(setup.ll.is_advertising());
+    }
+}
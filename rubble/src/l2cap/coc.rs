@@ -0,0 +1,700 @@
+//! LE credit-based connection-oriented channels (CoC).
+//!
+//! Connection-oriented channels are dynamically allocated L2CAP channels that are established by
+//! sending an *LE Credit Based Connection Request* on the LE signaling channel. The request names a
+//! [`Psm`] identifying the protocol or service to connect to.
+//!
+//! Flow control on these channels works using credits: Every L2CAP PDU (a *K-frame*) sent over the
+//! channel consumes one credit, and new credits are granted by the receiver once it has processed
+//! the data. Upper-layer messages (*SDUs*) can be larger than a single K-frame and are segmented
+//! by the sender and reassembled by the receiver.
+//!
+//! Only channels initiated by the peer are supported at the moment.
+//!
+//! [`Psm`]: struct.Psm.html
+
+use super::signaling::{send_command, Command, CommandKind};
+use super::{Channel, Header, L2capError};
+use crate::link::data::Llid;
+use crate::link::queue::{Consume, Producer};
+use crate::link::MIN_DATA_PAYLOAD_BUF;
+use crate::{bytes::*, utils::HexSlice, Error};
+use core::cmp;
+use heapless::Vec;
+
+/// Maximum K-frame payload size supported by Rubble.
+///
+/// This is the space left in a data channel PDU after the L2CAP header. Since L2CAP fragmentation
+/// and reassembly is not implemented, K-frames must fit into a single data channel PDU.
+const MAX_MPS: u16 = MIN_DATA_PAYLOAD_BUF as u16 - Header::SIZE as u16;
+
+/// Minimum MTU and MPS values allowed for LE credit-based channels.
+const MIN_MTU: u16 = 23;
+
+/// Size of an *LE Flow Control Credit* C-frame, including the L2CAP header.
+const CREDIT_FRAME_SIZE: u8 = Header::SIZE + 4 + 4;
+
+/// A Protocol/Service Multiplexer (PSM) identifying the protocol to use on a connection-oriented
+/// channel.
+///
+/// On LE, PSMs in range `0x0001`-`0x007F` are assigned by the Bluetooth SIG, while `0x0080`-`0x00FF`
+/// can be dynamically allocated.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, defmt::Format)]
+pub struct Psm(pub u16);
+
+impl Psm {
+    /// Internet Protocol Support Profile (IPSP).
+    pub const IPSP: Self = Psm(0x0023);
+
+    /// Object Transfer Service (OTS).
+    pub const OTS: Self = Psm(0x0025);
+
+    /// Enhanced ATT bearer (EATT).
+    pub const EATT: Self = Psm(0x0027);
+}
+
+enum_with_unknown! {
+    /// Result of an *LE Credit Based Connection Request*.
+    #[derive(Debug, Copy, Clone, PartialEq, Eq, defmt::Format)]
+    pub enum ConnectionResult(u16) {
+        /// The connection was established.
+        Success = 0x0000,
+        /// No service is registered on the requested PSM.
+        PsmNotSupported = 0x0002,
+        /// No resources are available to create the channel.
+        NoResources = 0x0004,
+        InsufficientAuthentication = 0x0005,
+        InsufficientAuthorization = 0x0006,
+        InsufficientEncryptionKeySize = 0x0007,
+        InsufficientEncryption = 0x0008,
+        /// The source CID of the request is not in the dynamic range.
+        InvalidSourceCid = 0x0009,
+        /// The source CID of the request is already in use.
+        SourceCidAlreadyAllocated = 0x000A,
+        /// The MTU or MPS of the request is not acceptable.
+        UnacceptableParameters = 0x000B,
+    }
+}
+
+/// Parameters of the local endpoint of a newly established channel.
+#[derive(Debug, Copy, Clone)]
+pub struct LocalChannel {
+    /// The locally allocated channel identifier.
+    pub channel: Channel,
+    /// Maximum SDU size that can be received.
+    pub mtu: u16,
+    /// Maximum K-frame payload size that can be received.
+    pub mps: u16,
+    /// Number of K-frames the peer may send initially.
+    pub initial_credits: u16,
+}
+
+/// Trait for managers of LE credit-based connection-oriented channels.
+///
+/// The L2CAP implementation calls into this trait when signaling commands concerning these
+/// channels, or K-frames addressed to them, are received. Applications usually want to use
+/// [`CreditChannels`] instead of implementing this themselves.
+///
+/// [`CreditChannels`]: struct.CreditChannels.html
+pub trait CocManager {
+    /// Handles an *LE Credit Based Connection Request*.
+    ///
+    /// # Parameters
+    ///
+    /// * **`psm`**: The PSM to connect to.
+    /// * **`remote`**: The channel identifier allocated by the peer.
+    /// * **`mtu`**, **`mps`**: Maximum SDU and K-frame payload size the peer can receive.
+    /// * **`initial_credits`**: Number of K-frames we may send to the peer.
+    fn connect(
+        &mut self,
+        psm: Psm,
+        remote: Channel,
+        mtu: u16,
+        mps: u16,
+        initial_credits: u16,
+    ) -> Result<LocalChannel, ConnectionResult>;
+
+    /// Adds `credits` to the channel whose remote endpoint is `remote`.
+    fn add_credits(&mut self, remote: Channel, credits: u16);
+
+    /// Closes a channel on request of the peer.
+    ///
+    /// Returns `false` if no channel with the given endpoints exists.
+    fn disconnect(&mut self, local: Channel, remote: Channel) -> bool;
+
+    /// Processes a K-frame addressed to the local channel `local`.
+    ///
+    /// Signaling commands (eg. new credits for the peer) can be enqueued in `tx`.
+    fn process_frame(&mut self, local: Channel, frame: &[u8], tx: &mut dyn Producer)
+        -> Consume<()>;
+
+    /// Returns the transmit state of the channel with local endpoint `local`.
+    fn channel_tx(&mut self, local: Channel) -> Option<&mut ChannelTx>;
+
+    /// Gives the open channels an opportunity to send pending data via `tx`.
+    ///
+    /// The default implementation does nothing.
+    fn poll(&mut self, tx: &mut dyn Producer) {
+        let _ = tx;
+    }
+}
+
+/// A `CocManager` that does not support any connection-oriented channels.
+pub struct NoCoc;
+
+impl CocManager for NoCoc {
+    fn connect(
+        &mut self,
+        _psm: Psm,
+        _remote: Channel,
+        _mtu: u16,
+        _mps: u16,
+        _initial_credits: u16,
+    ) -> Result<LocalChannel, ConnectionResult> {
+        Err(ConnectionResult::PsmNotSupported)
+    }
+
+    fn add_credits(&mut self, _remote: Channel, _credits: u16) {}
+
+    fn disconnect(&mut self, _local: Channel, _remote: Channel) -> bool {
+        false
+    }
+
+    fn process_frame(
+        &mut self,
+        local: Channel,
+        frame: &[u8],
+        _tx: &mut dyn Producer,
+    ) -> Consume<()> {
+        warn!(
+            "ignoring K-frame sent to unconnected channel {:?}: {:?}",
+            local,
+            HexSlice(frame)
+        );
+        Consume::always(Ok(()))
+    }
+
+    fn channel_tx(&mut self, _local: Channel) -> Option<&mut ChannelTx> {
+        None
+    }
+}
+
+/// Application callbacks for LE credit-based channels managed by [`CreditChannels`].
+///
+/// [`CreditChannels`]: struct.CreditChannels.html
+pub trait CocHandler {
+    /// Called when the peer wants to establish a channel to `psm`.
+    ///
+    /// Returns whether the connection is accepted. If it is, `channel` is the local channel
+    /// identifier that will be used to refer to the channel.
+    fn accept(&mut self, psm: Psm, channel: Channel) -> bool;
+
+    /// Called when a complete SDU was received on `channel`.
+    fn sdu_received(&mut self, channel: Channel, sdu: &[u8]);
+
+    /// Called when `channel` has been closed.
+    fn disconnected(&mut self, channel: Channel) {
+        let _ = channel;
+    }
+
+    /// Called when data can be sent on `channel` using `sender`.
+    ///
+    /// This happens right after an SDU was received on the channel, after the peer has sent any
+    /// signaling command (eg. to grant more credits), and when the application calls
+    /// `L2CAPStateTx::poll_coc`. Handlers that respond to received SDUs should do so here, and
+    /// continue sending SDUs that were only partially sent by `CocSender::write`.
+    ///
+    /// The default implementation does nothing.
+    fn send_ready(&mut self, channel: Channel, sender: &mut CocSender<'_>) {
+        let _ = (channel, sender);
+    }
+}
+
+/// Transmit state of a credit-based channel.
+#[derive(Debug)]
+pub struct ChannelTx {
+    remote: Channel,
+    mtu: u16,
+    mps: u16,
+    credits: u16,
+    /// Number of Bytes of the current SDU that still need to be sent.
+    sdu_remaining: u16,
+}
+
+struct CocChannel<const MTU: usize> {
+    local: Channel,
+    tx: ChannelTx,
+    /// Number of K-frames the peer is still allowed to send.
+    rx_credits: u16,
+    /// Number of K-frames received for the current SDU.
+    rx_frames: u16,
+    /// Length of the SDU being reassembled, if a first K-frame was received.
+    rx_sdu_len: Option<u16>,
+    rx_pos: usize,
+    rx_buf: [u8; MTU],
+}
+
+/// Manages up to `N` LE credit-based channels with an MTU of `MTU` Bytes.
+///
+/// Each channel needs `MTU` Bytes of memory to reassemble incoming SDUs. `MTU` must be at least 23.
+///
+/// Completely received SDUs are passed to a [`CocHandler`], which also decides which PSMs are
+/// available. Credits are returned to the peer after each complete SDU.
+///
+/// [`CocHandler`]: trait.CocHandler.html
+pub struct CreditChannels<H: CocHandler, const MTU: usize, const N: usize> {
+    handler: H,
+    channels: Vec<CocChannel<MTU>, N>,
+    next_identifier: u8,
+}
+
+impl<H: CocHandler, const MTU: usize, const N: usize> CreditChannels<H, MTU, N> {
+    /// Creates a new channel manager with no open channels.
+    pub fn new(handler: H) -> Self {
+        assert!(
+            MTU >= usize::from(MIN_MTU) && MTU <= usize::from(u16::MAX),
+            "invalid CoC MTU"
+        );
+
+        Self {
+            handler,
+            channels: Vec::new(),
+            next_identifier: 1,
+        }
+    }
+
+    /// Provides mutable access to the `CocHandler`.
+    pub fn handler(&mut self) -> &mut H {
+        &mut self.handler
+    }
+
+    /// Number of K-frames needed to receive an SDU of maximum size.
+    fn credits_per_sdu() -> u16 {
+        // The first K-frame also contains the 2-Byte SDU length
+        let bytes = MTU + 2;
+        let mps = usize::from(MAX_MPS);
+        bytes.div_ceil(mps) as u16
+    }
+
+    fn identifier(&mut self) -> u8 {
+        let id = self.next_identifier;
+        self.next_identifier = self.next_identifier.wrapping_add(1).max(1);
+        id
+    }
+
+    /// Closes the channel at `index` after a protocol violation by the peer.
+    fn close(&mut self, index: usize, tx: &mut dyn Producer) {
+        let chan = self.channels.swap_remove(index);
+        warn!("closing CoC {:?} due to protocol violation", chan.local);
+
+        let identifier = self.identifier();
+        send_command(
+            tx,
+            Command {
+                identifier,
+                kind: CommandKind::DisconnectionReq {
+                    dcid: chan.tx.remote,
+                    scid: chan.local,
+                },
+            },
+        )
+        .ok();
+        self.handler.disconnected(chan.local);
+    }
+}
+
+impl<H: CocHandler, const MTU: usize, const N: usize> CocManager for CreditChannels<H, MTU, N> {
+    fn connect(
+        &mut self,
+        psm: Psm,
+        remote: Channel,
+        mtu: u16,
+        mps: u16,
+        initial_credits: u16,
+    ) -> Result<LocalChannel, ConnectionResult> {
+        if mtu < MIN_MTU || mps < MIN_MTU {
+            return Err(ConnectionResult::UnacceptableParameters);
+        }
+        if !remote.is_dynamic() {
+            return Err(ConnectionResult::InvalidSourceCid);
+        }
+        if self.channels.iter().any(|c| c.tx.remote == remote) {
+            return Err(ConnectionResult::SourceCidAlreadyAllocated);
+        }
+        if self.channels.is_full() {
+            return Err(ConnectionResult::NoResources);
+        }
+
+        let local = (0x0040..=0x007F)
+            .map(Channel)
+            .find(|cid| self.channels.iter().all(|c| c.local != *cid))
+            .ok_or(ConnectionResult::NoResources)?;
+
+        if !self.handler.accept(psm, local) {
+            return Err(ConnectionResult::PsmNotSupported);
+        }
+
+        let credits = Self::credits_per_sdu();
+        let chan = CocChannel {
+            local,
+            tx: ChannelTx {
+                remote,
+                mtu,
+                mps,
+                credits: initial_credits,
+                sdu_remaining: 0,
+            },
+            rx_credits: credits,
+            rx_frames: 0,
+            rx_sdu_len: None,
+            rx_pos: 0,
+            rx_buf: [0; MTU],
+        };
+        self.channels
+            .push(chan)
+            .map_err(|_| ConnectionResult::NoResources)?;
+
+        debug!("CoC {:?} <-> {:?} opened on {:?}", local, remote, psm);
+        Ok(LocalChannel {
+            channel: local,
+            mtu: MTU as u16,
+            mps: MAX_MPS,
+            initial_credits: credits,
+        })
+    }
+
+    fn add_credits(&mut self, remote: Channel, credits: u16) {
+        if let Some(chan) = self.channels.iter_mut().find(|c| c.tx.remote == remote) {
+            chan.tx.credits = chan.tx.credits.saturating_add(credits);
+        }
+    }
+
+    fn disconnect(&mut self, local: Channel, remote: Channel) -> bool {
+        let pos = self
+            .channels
+            .iter()
+            .position(|c| c.local == local && c.tx.remote == remote);
+        match pos {
+            Some(index) => {
+                self.channels.swap_remove(index);
+                self.handler.disconnected(local);
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn process_frame(
+        &mut self,
+        local: Channel,
+        frame: &[u8],
+        tx: &mut dyn Producer,
+    ) -> Consume<()> {
+        let index = match self.channels.iter().position(|c| c.local == local) {
+            Some(index) => index,
+            None => {
+                warn!(
+                    "ignoring K-frame sent to unconnected channel {:?}: {:?}",
+                    local,
+                    HexSlice(frame)
+                );
+                return Consume::always(Ok(()));
+            }
+        };
+
+        // Make sure we can return credits (or disconnect) after processing the frame.
+        if tx.free_space() < CREDIT_FRAME_SIZE {
+            return Consume::never(Err(Error::TxBusy));
+        }
+
+        let chan = &mut self.channels[index];
+        if chan.rx_credits == 0 || frame.len() > usize::from(MAX_MPS) {
+            self.close(index, tx);
+            return Consume::always(Ok(()));
+        }
+        chan.rx_credits -= 1;
+        chan.rx_frames += 1;
+
+        let mut bytes = ByteReader::new(frame);
+        let sdu_len = match chan.rx_sdu_len {
+            Some(len) => len,
+            None => match bytes.read_u16_le() {
+                Ok(len) if usize::from(len) <= MTU => {
+                    chan.rx_sdu_len = Some(len);
+                    chan.rx_pos = 0;
+                    len
+                }
+                _ => {
+                    self.close(index, tx);
+                    return Consume::always(Ok(()));
+                }
+            },
+        };
+
+        let data = bytes.read_rest();
+        let end = chan.rx_pos + data.len();
+        if end > usize::from(sdu_len) {
+            self.close(index, tx);
+            return Consume::always(Ok(()));
+        }
+        chan.rx_buf[chan.rx_pos..end].copy_from_slice(data);
+        chan.rx_pos = end;
+
+        if end == usize::from(sdu_len) {
+            let credits = chan.rx_frames;
+            chan.rx_sdu_len = None;
+            chan.rx_frames = 0;
+            chan.rx_credits += credits;
+            self.handler.sdu_received(local, &chan.rx_buf[..end]);
+
+            let identifier = self.identifier();
+            let result = send_command(
+                tx,
+                Command {
+                    identifier,
+                    kind: CommandKind::FlowControlCredit {
+                        cid: local,
+                        credits,
+                    },
+                },
+            );
+            if result.is_ok() {
+                let chan = &mut self.channels[index];
+                self.handler
+                    .send_ready(local, &mut CocSender::new(&mut chan.tx, tx));
+            }
+            return Consume::always(result);
+        }
+
+        Consume::always(Ok(()))
+    }
+
+    fn channel_tx(&mut self, local: Channel) -> Option<&mut ChannelTx> {
+        self.channels
+            .iter_mut()
+            .find(|c| c.local == local)
+            .map(|c| &mut c.tx)
+    }
+
+    fn poll(&mut self, tx: &mut dyn Producer) {
+        for chan in &mut self.channels {
+            self.handler
+                .send_ready(chan.local, &mut CocSender::new(&mut chan.tx, &mut *tx));
+        }
+    }
+}
+
+/// Sends SDUs over an LE credit-based channel.
+///
+/// Obtained from [`L2CAPStateTx::coc`].
+///
+/// [`L2CAPStateTx::coc`]: ../struct.L2CAPStateTx.html#method.coc
+pub struct CocSender<'a> {
+    chan: &'a mut ChannelTx,
+    tx: &'a mut dyn Producer,
+}
+
+impl<'a> CocSender<'a> {
+    pub(super) fn new(chan: &'a mut ChannelTx, tx: &'a mut dyn Producer) -> Self {
+        Self { chan, tx }
+    }
+
+    /// Returns the number of K-frames that can be sent before the peer has to grant more credits.
+    pub fn credits(&self) -> u16 {
+        self.chan.credits
+    }
+
+    /// Returns the maximum SDU size the peer can receive.
+    pub fn mtu(&self) -> u16 {
+        self.chan.mtu
+    }
+
+    /// Returns whether an SDU was only partially sent by [`write`](#method.write).
+    pub fn is_sdu_in_progress(&self) -> bool {
+        self.chan.sdu_remaining != 0
+    }
+
+    /// Segments an SDU into K-frames and enqueues as many of them as possible.
+    ///
+    /// When no SDU is in progress, `data` is the complete SDU to send. Otherwise, `data` must be
+    /// the rest of the SDU that was not sent by previous calls.
+    ///
+    /// Returns the number of Bytes of `data` that were enqueued. If this is less than the length of
+    /// `data`, the peer ran out of credits or the TX queue is full, and `write` has to be called
+    /// again with the remaining data later.
+    ///
+    /// # Errors
+    ///
+    /// * `L2capError::NoCredits` or `L2capError::TxBusy` if not a single K-frame could be
+    ///   enqueued.
+    /// * `L2capError::SduTooLarge` if the SDU exceeds the peer's MTU.
+    /// * `L2capError::SduMismatch` if `data` doesn't match the rest of the SDU in progress.
+    pub fn write(&mut self, data: &[u8]) -> Result<usize, L2capError> {
+        let mut first = self.chan.sdu_remaining == 0;
+        if first {
+            if data.len() > usize::from(self.chan.mtu) {
+                return Err(L2capError::SduTooLarge);
+            }
+        } else if data.len() != usize::from(self.chan.sdu_remaining) {
+            return Err(L2capError::SduMismatch);
+        }
+
+        let mps = usize::from(cmp::min(self.chan.mps, MAX_MPS));
+        let remote = self.chan.remote;
+        let mut written = 0;
+        let mut sent_frame = false;
+        while self.chan.credits > 0 {
+            let sdu_header = if first { 2 } else { 0 };
+            let chunk = &data[written..cmp::min(data.len(), written + mps - sdu_header)];
+            let frame_len = sdu_header + chunk.len();
+
+            let result = self.tx.produce_dyn(
+                Header::SIZE + frame_len as u8,
+                &mut |writer: &mut ByteWriter<'_>| {
+                    Header {
+                        length: frame_len as u16,
+                        channel: remote,
+                    }
+                    .to_bytes(writer)?;
+                    if first {
+                        writer.write_u16_le(data.len() as u16)?;
+                    }
+                    writer.write_slice(chunk)?;
+                    Ok(Llid::DataStart)
+                },
+            );
+            match result {
+                Ok(()) => {}
+                Err(Error::Eof) => break,
+                Err(_) => return Err(L2capError::Encoding),
+            }
+
+            self.chan.credits -= 1;
+            if first {
+                self.chan.sdu_remaining = data.len() as u16;
+                first = false;
+            }
+            self.chan.sdu_remaining -= chunk.len() as u16;
+            written += chunk.len();
+            sent_frame = true;
+
+            if self.chan.sdu_remaining == 0 {
+                break;
+            }
+        }
+
+        if sent_frame {
+            Ok(written)
+        } else if self.chan.credits == 0 {
+            Err(L2capError::NoCredits)
+        } else {
+            Err(L2capError::TxBusy)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::link::queue::{Consumer, PacketQueue, SimpleQueue};
+
+    #[derive(Default)]
+    struct Handler {
+        received: usize,
+        last_sdu: [u8; 32],
+    }
+
+    impl CocHandler for Handler {
+        fn accept(&mut self, psm: Psm, _channel: Channel) -> bool {
+            psm == Psm(0x0080)
+        }
+
+        fn sdu_received(&mut self, _channel: Channel, sdu: &[u8]) {
+            self.received += 1;
+            self.last_sdu[..sdu.len()].copy_from_slice(sdu);
+        }
+    }
+
+    #[test]
+    fn reassembly_and_credits() {
+        let mut queue = SimpleQueue::new();
+        let (mut tx, mut rx) = (&mut queue).split();
+        let mut coc = CreditChannels::<_, 64, 1>::new(Handler::default());
+
+        assert_eq!(
+            coc.connect(Psm(0x0081), Channel(0x0040), 64, 23, 10)
+                .unwrap_err(),
+            ConnectionResult::PsmNotSupported
+        );
+        let local = coc
+            .connect(Psm(0x0080), Channel(0x0041), 64, 23, 10)
+            .unwrap();
+        assert_eq!(local.channel, Channel(0x0040));
+        assert_eq!(local.mps, 23);
+        assert_eq!(local.initial_credits, 3); // 66 Bytes in 23-Byte frames
+        assert_eq!(
+            coc.connect(Psm(0x0080), Channel(0x0042), 64, 23, 10)
+                .unwrap_err(),
+            ConnectionResult::NoResources
+        );
+
+        // SDU of 30 Bytes, split into 2 K-frames
+        let sdu: [u8; 30] = core::array::from_fn(|i| i as u8);
+        let mut first = [0; 23];
+        first[..2].copy_from_slice(&30u16.to_le_bytes());
+        first[2..].copy_from_slice(&sdu[..21]);
+        assert!(coc
+            .process_frame(local.channel, &first, &mut tx)
+            .should_consume());
+        assert_eq!(coc.handler().received, 0);
+        assert!(!rx.has_data());
+
+        coc.process_frame(local.channel, &sdu[21..], &mut tx);
+        assert_eq!(coc.handler().received, 1);
+        assert_eq!(&coc.handler().last_sdu[..30], &sdu[..]);
+
+        // The 2 credits used by the SDU are returned to the peer
+        rx.consume_raw_with(|_, raw| {
+            assert_eq!(raw, &[8, 0, 0x05, 0x00, 0x16, 1, 4, 0, 0x40, 0x00, 2, 0]);
+            Consume::always(Ok(()))
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn segmentation() {
+        let mut queue = SimpleQueue::new();
+        let (mut tx, mut rx) = (&mut queue).split();
+        let mut coc = CreditChannels::<_, 64, 1>::new(Handler::default());
+        let local = coc
+            .connect(Psm(0x0080), Channel(0x0041), 64, 23, 1)
+            .unwrap();
+
+        let sdu = [0xAB; 30];
+        let mut sender = CocSender::new(coc.channel_tx(local.channel).unwrap(), &mut tx);
+        assert_eq!(sender.write(&sdu[..]), Ok(21));
+        assert_eq!(sender.credits(), 0);
+        assert!(sender.is_sdu_in_progress());
+        assert_eq!(sender.write(&sdu[21..]), Err(L2capError::NoCredits));
+
+        rx.consume_raw_with(|_, raw| {
+            assert_eq!(&raw[..6], &[23, 0, 0x41, 0x00, 30, 0]);
+            assert_eq!(raw.len(), 4 + 23);
+            Consume::always(Ok(()))
+        })
+        .unwrap();
+
+        coc.add_credits(Channel(0x0041), 1);
+        let mut sender = CocSender::new(coc.channel_tx(local.channel).unwrap(), &mut tx);
+        assert_eq!(sender.write(&sdu[..]), Err(L2capError::SduMismatch));
+        assert_eq!(sender.write(&sdu[21..]), Ok(9));
+        assert!(!sender.is_sdu_in_progress());
+
+        rx.consume_raw_with(|_, raw| {
+            assert_eq!(&raw[..4], &[9, 0, 0x41, 0x00]);
+            assert_eq!(raw.len(), 4 + 9);
+            Consume::always(Ok(()))
+        })
+        .unwrap();
+    }
+}
@@ -0,0 +1,414 @@
+//! Peripheral-initiated connection parameter updates.
+//!
+//! Mobile operating systems usually connect with parameters that suit the phone rather than the
+//! peripheral, and expect the peripheral to ask for the parameters it prefers itself. The
+//! [`ConnParamUpdater`] automates this: Once the peer has stopped exchanging ATT PDUs for a while
+//! (which usually means that GATT discovery has finished), it sends an *L2CAP Connection
+//! Parameter Update Request* on the LE Signaling Channel containing the same parameters that are
+//! exposed via the GAP *Peripheral Preferred Connection Parameters* (PPCP) characteristic. If the
+//! central rejects the request or doesn't respond, it is retried with exponential backoff.
+//!
+//! [`ConnParamUpdater`]: struct.ConnParamUpdater.html
+
+use super::signaling::{send_command, Command, CommandKind};
+use crate::link::queue::Producer;
+use crate::time::{Duration, Instant};
+use crate::{bytes::*, Error};
+
+/// Time without ATT traffic after which the connection is considered idle.
+///
+/// The request is only sent once the connection was idle for this long, so that it doesn't
+/// interfere with service discovery.
+pub const QUIET_PERIOD: Duration = Duration::from_micros(5_000_000);
+
+/// Time to wait for the central's response before retrying.
+///
+/// The L2CAP response timeout (RTX) may be anywhere between 1 and 60 seconds.
+const RESPONSE_TIMEOUT: Duration = Duration::from_micros(30_000_000);
+
+/// Delay before the first retry. Every further retry doubles it.
+const INITIAL_BACKOFF: Duration = Duration::from_micros(5_000_000);
+
+/// Number of requests to send before giving up.
+const MAX_ATTEMPTS: u8 = 4;
+
+/// Connection parameters preferred by a peripheral.
+///
+/// This is both the value of the GAP *Peripheral Preferred Connection Parameters* characteristic
+/// and the payload of an L2CAP Connection Parameter Update Request. All values use the units of
+/// the Link-Layer.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, defmt::Format)]
+pub struct ConnectionParameters {
+    /// Minimum connection interval in units of 1.25 ms.
+    pub min_interval: u16,
+    /// Maximum connection interval in units of 1.25 ms.
+    pub max_interval: u16,
+    /// Number of connection events the peripheral may skip.
+    pub slave_latency: u16,
+    /// Supervision timeout in units of 10 ms.
+    pub supervision_timeout: u16,
+}
+
+impl ConnectionParameters {
+    /// Size of the encoded parameters (and of the PPCP characteristic value) in Bytes.
+    pub const SIZE: usize = 8;
+
+    /// Returns whether the parameters are within the ranges allowed by the specification.
+    ///
+    /// This includes the requirement that the supervision timeout is longer than twice the
+    /// effective connection interval when making full use of the slave latency.
+    pub fn is_valid(&self) -> bool {
+        let intervals = (6..=3200).contains(&self.min_interval)
+            && (self.min_interval..=3200).contains(&self.max_interval);
+        let timeout = (10..=3200).contains(&self.supervision_timeout);
+        let latency = self.slave_latency <= 499;
+
+        // timeout * 10 ms > (1 + latency) * max_interval * 1.25 ms * 2
+        let effective = (1 + u32::from(self.slave_latency)) * u32::from(self.max_interval);
+        intervals && timeout && latency && u32::from(self.supervision_timeout) * 4 > effective
+    }
+
+    /// Encodes the parameters as a PPCP characteristic value.
+    pub fn to_array(&self) -> [u8; Self::SIZE] {
+        let mut buf = [0; Self::SIZE];
+        self.to_bytes(&mut ByteWriter::new(&mut buf)).unwrap();
+        buf
+    }
+}
+
+impl FromBytes<'_> for ConnectionParameters {
+    fn from_bytes(bytes: &mut ByteReader<'_>) -> Result<Self, Error> {
+        Ok(Self {
+            min_interval: bytes.read_u16_le()?,
+            max_interval: bytes.read_u16_le()?,
+            slave_latency: bytes.read_u16_le()?,
+            supervision_timeout: bytes.read_u16_le()?,
+        })
+    }
+}
+
+impl ToBytes for ConnectionParameters {
+    fn to_bytes(&self, writer: &mut ByteWriter<'_>) -> Result<(), Error> {
+        writer.write_u16_le(self.min_interval)?;
+        writer.write_u16_le(self.max_interval)?;
+        writer.write_u16_le(self.slave_latency)?;
+        writer.write_u16_le(self.supervision_timeout)?;
+        Ok(())
+    }
+}
+
+/// Progress of a [`ConnParamUpdater`].
+///
+/// [`ConnParamUpdater`]: struct.ConnParamUpdater.html
+#[derive(Debug, Copy, Clone, PartialEq, Eq, defmt::Format)]
+pub enum UpdateState {
+    /// Waiting for the connection to become idle before (re)sending the request.
+    Waiting,
+    /// A request was sent and the central's response is outstanding.
+    Pending,
+    /// The central accepted the parameters.
+    Accepted,
+    /// The central rejected every attempt, or doesn't support the procedure.
+    Failed,
+}
+
+/// Requests the peripheral's preferred connection parameters once the connection is idle.
+///
+/// Add an updater to the channel map with `BleChannelMap::with_conn_params`, and call
+/// `L2CAPStateTx::poll_conn_params` periodically (eg. whenever the Link-Layer's timer fires) to
+/// let it send and retry its request. [`reset`] has to be called when a new connection is
+/// established.
+///
+/// Note that accepting a request only means that the central will initiate a Link-Layer
+/// Connection Update procedure; the new parameters are applied by the Link-Layer once that
+/// procedure completes.
+///
+/// [`reset`]: #method.reset
+#[derive(Debug)]
+pub struct ConnParamUpdater {
+    params: ConnectionParameters,
+    state: UpdateState,
+
+    /// Whether ATT PDUs were received since the last call to `poll`.
+    activity: bool,
+
+    /// Start of the current waiting or pending period.
+    ///
+    /// `None` if it starts with the next call to `poll`.
+    since: Option<Instant>,
+
+    /// Time to stay in `UpdateState::Waiting` before sending the request.
+    delay: Duration,
+
+    /// Number of requests sent so far.
+    attempts: u8,
+
+    /// Identifier of the last request sent.
+    identifier: u8,
+}
+
+impl ConnParamUpdater {
+    /// Creates an updater that will request `params`.
+    ///
+    /// `params` should be equal to the value of the PPCP characteristic in the GAP service (see
+    /// [`ConnectionParameters::to_array`]).
+    ///
+    /// [`ConnectionParameters::to_array`]: struct.ConnectionParameters.html#method.to_array
+    pub fn new(params: ConnectionParameters) -> Self {
+        assert!(params.is_valid(), "invalid connection parameters");
+        Self {
+            params,
+            state: UpdateState::Waiting,
+            activity: false,
+            since: None,
+            delay: QUIET_PERIOD,
+            attempts: 0,
+            // Identifiers in the upper half are used to avoid colliding with `CocManager`s
+            identifier: 0x7F,
+        }
+    }
+
+    /// Returns the connection parameters this updater requests.
+    pub fn params(&self) -> &ConnectionParameters {
+        &self.params
+    }
+
+    /// Returns the current progress of the update.
+    pub fn state(&self) -> UpdateState {
+        self.state
+    }
+
+    /// Returns the number of requests sent since the last call to [`reset`].
+    ///
+    /// [`reset`]: #method.reset
+    pub fn attempts(&self) -> u8 {
+        self.attempts
+    }
+
+    /// Restarts the procedure for a new connection.
+    pub fn reset(&mut self) {
+        self.state = UpdateState::Waiting;
+        self.activity = false;
+        self.since = None;
+        self.delay = QUIET_PERIOD;
+        self.attempts = 0;
+    }
+
+    /// Returns the time at which `poll` has to be called next, if known.
+    ///
+    /// ATT traffic postpones this time.
+    pub fn deadline(&self) -> Option<Instant> {
+        let since = self.since?;
+        match self.state {
+            UpdateState::Waiting => Some(since + self.delay),
+            UpdateState::Pending => Some(since + RESPONSE_TIMEOUT),
+            UpdateState::Accepted | UpdateState::Failed => None,
+        }
+    }
+
+    /// Notes that an ATT PDU was exchanged.
+    pub(super) fn activity(&mut self) {
+        self.activity = true;
+    }
+
+    /// Sends or retries the request if it is due.
+    ///
+    /// Returns an error if the request could not be enqueued, in which case it will be sent by a
+    /// later call.
+    pub fn poll(&mut self, now: Instant, tx: &mut dyn Producer) -> Result<(), Error> {
+        let activity = core::mem::replace(&mut self.activity, false);
+        let since = *self.since.get_or_insert(now);
+        let elapsed = now.duration_since(since);
+        match self.state {
+            UpdateState::Waiting => {
+                if activity {
+                    // Restart the quiet period
+                    self.since = Some(now);
+                    return Ok(());
+                }
+                if elapsed < self.delay {
+                    return Ok(());
+                }
+
+                let identifier = self.identifier.wrapping_add(1).max(0x80);
+                send_command(
+                    tx,
+                    Command {
+                        identifier,
+                        kind: CommandKind::ConnectionParameterUpdateReq {
+                            params: self.params,
+                        },
+                    },
+                )?;
+
+                self.identifier = identifier;
+                self.attempts += 1;
+                self.state = UpdateState::Pending;
+                self.since = Some(now);
+                debug!(
+                    "requesting connection parameters {:?} (attempt {})",
+                    self.params, self.attempts
+                );
+            }
+            UpdateState::Pending => {
+                if elapsed >= RESPONSE_TIMEOUT {
+                    warn!("connection parameter update request timed out");
+                    self.retry();
+                }
+            }
+            UpdateState::Accepted | UpdateState::Failed => {}
+        }
+        Ok(())
+    }
+
+    /// Processes the central's response to the request with the given identifier.
+    pub(super) fn process_response(&mut self, identifier: u8, accepted: bool) {
+        if self.state != UpdateState::Pending || identifier != self.identifier {
+            return;
+        }
+
+        if accepted {
+            debug!("connection parameters accepted");
+            self.state = UpdateState::Accepted;
+        } else {
+            debug!("connection parameters rejected");
+            self.retry();
+        }
+    }
+
+    /// Processes a Command Reject sent in response to the request with the given identifier.
+    ///
+    /// The central doesn't support the procedure, so there is no point in retrying.
+    pub(super) fn process_reject(&mut self, identifier: u8) {
+        if self.state == UpdateState::Pending && identifier == self.identifier {
+            warn!("central does not support connection parameter update requests");
+            self.state = UpdateState::Failed;
+        }
+    }
+
+    fn retry(&mut self) {
+        if self.attempts >= MAX_ATTEMPTS {
+            self.state = UpdateState::Failed;
+            return;
+        }
+
+        self.state = UpdateState::Waiting;
+        self.since = None;
+        self.delay = INITIAL_BACKOFF.saturating_mul(1 << (self.attempts - 1));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::l2cap::{BleChannelMap, ChannelMapper, L2CAPState};
+    use crate::link::queue::{Consume, Consumer, PacketQueue, SimpleQueue};
+
+    const PARAMS: ConnectionParameters = ConnectionParameters {
+        min_interval: 24,
+        max_interval: 40,
+        slave_latency: 0,
+        supervision_timeout: 400,
+    };
+
+    fn at(secs: u32) -> Instant {
+        Instant::from_raw_micros(secs * 1_000_000)
+    }
+
+    /// Removes the next signaling command from the queue and returns its code and identifier.
+    fn receive(rx: &mut impl Consumer) -> Option<(u8, u8)> {
+        rx.consume_raw_with(|_, raw| {
+            assert_eq!(&raw[2..4], &[0x05, 0x00]);
+            Consume::always(Ok((raw[4], raw[5])))
+        })
+        .ok()
+    }
+
+    #[test]
+    fn encoding() {
+        assert_eq!(PARAMS.to_array(), [24, 0, 40, 0, 0, 0, 0x90, 0x01],);
+        assert!(PARAMS.is_valid());
+        assert!(!ConnectionParameters {
+            supervision_timeout: 10,
+            ..PARAMS
+        }
+        .is_valid());
+        assert!(!ConnectionParameters {
+            max_interval: 20,
+            ..PARAMS
+        }
+        .is_valid());
+    }
+
+    #[test]
+    fn waits_for_quiet_period() {
+        let mut queue = SimpleQueue::new();
+        let (mut tx, mut rx) = (&mut queue).split();
+        let mut updater = ConnParamUpdater::new(PARAMS);
+
+        updater.poll(at(0), &mut tx).unwrap();
+        updater.activity();
+        updater.poll(at(4), &mut tx).unwrap();
+        updater.poll(at(8), &mut tx).unwrap();
+        assert_eq!(updater.state(), UpdateState::Waiting);
+        assert!(receive(&mut rx).is_none());
+
+        updater.poll(at(9), &mut tx).unwrap();
+        assert_eq!(updater.state(), UpdateState::Pending);
+        assert_eq!(receive(&mut rx), Some((0x12, 0x80)));
+    }
+
+    #[test]
+    fn accepted() {
+        let mut queue = SimpleQueue::new();
+        let (mut tx, mut rx) = (&mut queue).split();
+        let mut l2cap =
+            L2CAPState::new(BleChannelMap::empty().with_conn_params(ConnParamUpdater::new(PARAMS)));
+
+        l2cap.tx(&mut tx).poll_conn_params(at(0)).unwrap();
+        l2cap.tx(&mut tx).poll_conn_params(at(5)).unwrap();
+        assert_eq!(receive(&mut rx), Some((0x12, 0x80)));
+
+        // Connection Parameter Update Response, result = accepted
+        let rsp = [6, 0, 0x05, 0x00, 0x13, 0x80, 2, 0, 0, 0];
+        l2cap.tx(&mut tx).process_start(&rsp);
+        let updater = l2cap.channel_mapper().conn_params().unwrap();
+        assert_eq!(updater.state(), UpdateState::Accepted);
+        assert!(updater.deadline().is_none());
+    }
+
+    #[test]
+    fn backoff() {
+        let mut queue = SimpleQueue::new();
+        let (mut tx, mut rx) = (&mut queue).split();
+        let mut updater = ConnParamUpdater::new(PARAMS);
+
+        updater.poll(at(0), &mut tx).unwrap();
+        updater.poll(at(5), &mut tx).unwrap();
+        assert_eq!(receive(&mut rx), Some((0x12, 0x80)));
+        updater.process_response(0x80, false);
+        assert_eq!(updater.state(), UpdateState::Waiting);
+
+        // Retried after 5 s
+        updater.poll(at(6), &mut tx).unwrap();
+        assert_eq!(updater.deadline().unwrap().raw_micros(), 11_000_000);
+        updater.poll(at(11), &mut tx).unwrap();
+        assert_eq!(receive(&mut rx), Some((0x12, 0x81)));
+
+        // No response: retried 10 s after the timeout
+        updater.poll(at(41), &mut tx).unwrap();
+        assert_eq!(updater.state(), UpdateState::Waiting);
+        updater.poll(at(42), &mut tx).unwrap();
+        updater.poll(at(52), &mut tx).unwrap();
+        assert_eq!(receive(&mut rx), Some((0x12, 0x82)));
+
+        // A Command Reject means that the central doesn't support the procedure
+        updater.process_reject(0x82);
+        assert_eq!(updater.state(), UpdateState::Failed);
+        assert_eq!(updater.attempts(), 3);
+
+        updater.reset();
+        assert_eq!(updater.state(), UpdateState::Waiting);
+        assert_eq!(updater.attempts(), 0);
+    }
+}
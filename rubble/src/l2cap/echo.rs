@@ -0,0 +1,221 @@
+//! Diagnostic echo on the LE Signaling Channel.
+//!
+//! The *Echo Request* and *Echo Response* signaling commands are only defined for BR/EDR, but
+//! they are useful on LE as well: They travel through the whole stack on both devices (Link-Layer,
+//! packet queues, L2CAP), so the time until the response arrives is the end-to-end latency that
+//! an application protocol would see.
+//!
+//! Since LE devices aren't required to understand these commands, this is opt-in: Only if an
+//! [`EchoProbe`] is installed (via `BleChannelMap::with_echo`) are *Echo Requests* from the peer
+//! answered, and can requests be sent to the peer to measure the round-trip time. A peer that
+//! doesn't support them responds with a *Command Reject*, which is detected as well.
+//!
+//! [`EchoProbe`]: struct.EchoProbe.html
+
+use super::signaling::{send_command, Command, CommandKind};
+use crate::link::queue::Producer;
+use crate::time::{Duration, Instant};
+use crate::Error;
+
+/// State of the last *Echo Request* sent by an [`EchoProbe`].
+///
+/// [`EchoProbe`]: struct.EchoProbe.html
+#[derive(Debug, Copy, Clone, PartialEq, Eq, defmt::Format)]
+pub enum EchoState {
+    /// No request is outstanding.
+    Idle,
+    /// A request was sent and the response is outstanding.
+    Pending,
+    /// The response was received, and the round-trip time is determined by the next `poll`.
+    Answered,
+    /// The peer rejected the request because it doesn't support echo commands.
+    Unsupported,
+}
+
+/// Answers *Echo Requests* from the peer and measures the round-trip time of its own requests.
+///
+/// Send a request with `L2CAPStateTx::send_echo`, and call `L2CAPStateTx::poll_echo` after the
+/// `Responder` has processed incoming packets to obtain the round-trip time once the response is
+/// in. The time between processing the response and calling `poll_echo` is included in the
+/// measurement, so it should be called right after `Responder::process_one`.
+///
+/// The result can be recorded with `Connection::record_echo_rtt`, which makes it available in the
+/// `ConnectionStats` along with the Link-Layer statistics.
+#[derive(Debug)]
+pub struct EchoProbe {
+    state: EchoState,
+
+    /// Time at which the outstanding request was sent.
+    sent: Instant,
+
+    /// Identifier of the last request sent.
+    identifier: u8,
+
+    /// Round-trip time of the last answered request.
+    rtt: Option<Duration>,
+}
+
+impl EchoProbe {
+    /// Creates a probe that hasn't sent any requests yet.
+    pub fn new() -> Self {
+        Self {
+            state: EchoState::Idle,
+            sent: Instant::from_raw_micros(0),
+            // Identifiers in `0x40..0x80` are used to avoid colliding with `ConnParamUpdater`s
+            identifier: 0x7F,
+            rtt: None,
+        }
+    }
+
+    /// Returns the state of the last request.
+    pub fn state(&self) -> EchoState {
+        self.state
+    }
+
+    /// Returns the round-trip time of the last answered request, if any.
+    pub fn rtt(&self) -> Option<Duration> {
+        self.rtt
+    }
+
+    /// Forgets the state of the previous connection.
+    pub fn reset(&mut self) {
+        self.state = EchoState::Idle;
+        self.rtt = None;
+    }
+
+    /// Sends an *Echo Request* carrying `data`, which the peer may echo back.
+    ///
+    /// If a request is still outstanding, its response will be ignored. Returns an error if the
+    /// request could not be enqueued.
+    pub fn send(&mut self, now: Instant, data: &[u8], tx: &mut dyn Producer) -> Result<(), Error> {
+        let identifier = if self.identifier >= 0x7F {
+            0x40
+        } else {
+            self.identifier + 1
+        };
+        send_command(
+            tx,
+            Command {
+                identifier,
+                kind: CommandKind::EchoReq { data },
+            },
+        )?;
+
+        self.identifier = identifier;
+        self.state = EchoState::Pending;
+        self.sent = now;
+        Ok(())
+    }
+
+    /// Returns the round-trip time if the response to the outstanding request has arrived.
+    pub fn poll(&mut self, now: Instant) -> Option<Duration> {
+        if self.state != EchoState::Answered {
+            return None;
+        }
+
+        let rtt = now.duration_since(self.sent);
+        debug!("echo round-trip time: {:?}", rtt);
+        self.state = EchoState::Idle;
+        self.rtt = Some(rtt);
+        Some(rtt)
+    }
+
+    /// Processes an *Echo Response* with the given identifier.
+    pub(super) fn process_response(&mut self, identifier: u8) {
+        if self.state == EchoState::Pending && identifier == self.identifier {
+            self.state = EchoState::Answered;
+        }
+    }
+
+    /// Processes a *Command Reject* sent in response to the request with the given identifier.
+    pub(super) fn process_reject(&mut self, identifier: u8) {
+        if self.state == EchoState::Pending && identifier == self.identifier {
+            warn!("peer does not support echo requests");
+            self.state = EchoState::Unsupported;
+        }
+    }
+}
+
+impl Default for EchoProbe {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::l2cap::{BleChannelMap, ChannelMapper, L2CAPState};
+    use crate::link::queue::{Consume, Consumer, PacketQueue, SimpleQueue};
+    use heapless::Vec;
+
+    fn at(millis: u32) -> Instant {
+        Instant::from_raw_micros(millis * 1_000)
+    }
+
+    /// Removes the next signaling command from the queue and returns it without the L2CAP header.
+    fn receive(rx: &mut impl Consumer) -> Option<Vec<u8, 23>> {
+        rx.consume_raw_with(|_, raw| {
+            assert_eq!(&raw[2..4], &[0x05, 0x00]);
+            Consume::always(Ok(Vec::from_slice(&raw[4..]).unwrap()))
+        })
+        .ok()
+    }
+
+    #[test]
+    fn answers_requests() {
+        let mut queue = SimpleQueue::new();
+        let (mut tx, mut rx) = (&mut queue).split();
+        let req = [7, 0, 0x05, 0x00, 0x08, 0x11, 3, 0, 1, 2, 3];
+
+        let mut l2cap = L2CAPState::new(BleChannelMap::empty());
+        l2cap.tx(&mut tx).process_start(&req);
+        // Command Reject, Command not understood
+        assert_eq!(
+            &receive(&mut rx).unwrap()[..],
+            &[0x01, 0x11, 2, 0, 0x00, 0x00]
+        );
+
+        let mut l2cap = L2CAPState::new(BleChannelMap::empty().with_echo(EchoProbe::new()));
+        l2cap.tx(&mut tx).process_start(&req);
+        assert_eq!(&receive(&mut rx).unwrap()[..], &[0x09, 0x11, 3, 0, 1, 2, 3]);
+    }
+
+    #[test]
+    fn round_trip_time() {
+        let mut queue = SimpleQueue::new();
+        let (mut tx, mut rx) = (&mut queue).split();
+        let mut l2cap = L2CAPState::new(BleChannelMap::empty().with_echo(EchoProbe::new()));
+
+        l2cap.tx(&mut tx).send_echo(at(10), &[0xAB]).unwrap();
+        assert_eq!(&receive(&mut rx).unwrap()[..], &[0x08, 0x40, 1, 0, 0xAB]);
+        assert_eq!(l2cap.tx(&mut tx).poll_echo(at(20)), None);
+
+        // Responses to other requests are ignored
+        l2cap
+            .tx(&mut tx)
+            .process_start(&[4, 0, 0x05, 0x00, 0x09, 0x41, 0, 0]);
+        assert_eq!(l2cap.tx(&mut tx).poll_echo(at(30)), None);
+
+        l2cap
+            .tx(&mut tx)
+            .process_start(&[5, 0, 0x05, 0x00, 0x09, 0x40, 1, 0, 0xAB]);
+        assert_eq!(
+            l2cap.tx(&mut tx).poll_echo(at(45)),
+            Some(Duration::from_millis(35))
+        );
+        let probe = l2cap.channel_mapper().echo().unwrap();
+        assert_eq!(probe.state(), EchoState::Idle);
+        assert_eq!(probe.rtt(), Some(Duration::from_millis(35)));
+
+        // Command Reject, Command not understood
+        l2cap.tx(&mut tx).send_echo(at(50), &[]).unwrap();
+        assert_eq!(&receive(&mut rx).unwrap()[..], &[0x08, 0x41, 0, 0]);
+        l2cap
+            .tx(&mut tx)
+            .process_start(&[6, 0, 0x05, 0x00, 0x01, 0x41, 2, 0, 0, 0]);
+        let probe = l2cap.channel_mapper().echo().unwrap();
+        assert_eq!(probe.state(), EchoState::Unsupported);
+        assert_eq!(probe.rtt(), Some(Duration::from_millis(35)));
+    }
+}
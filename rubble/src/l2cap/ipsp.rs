@@ -0,0 +1,247 @@
+//! Internet Protocol Support Profile (IPSP) transport.
+//!
+//! IPSP transfers IPv6 packets over an LE credit-based connection-oriented channel on the IPSP
+//! [`Psm`]. The packets are compressed using 6LoWPAN header compression (RFC 7668), which is not
+//! handled here: Packets are passed to and from the application as-is, so that an IP stack with
+//! 6LoWPAN support (such as smoltcp) can be hooked up.
+//!
+//! Rubble only implements the *IPSP Node* role, which accepts a channel opened by an *IPSP
+//! Router*. A node must expose the *Internet Protocol Support Service* (see [`IPSS_UUID`]) via GATT
+//! so that routers can discover it.
+//!
+//! To use IPSP, add an [`IpspChannels`] manager to the [`BleChannelMap`] via
+//! [`BleChannelMap::with_coc`]. Received packets are passed to the [`PacketSink`]. Outgoing
+//! packets are sent by obtaining a [`CocSender`] for [`IpspNode::channel`] from
+//! [`L2CAPStateTx::coc`].
+//!
+//! [`Psm`]: ../struct.Psm.html
+//! [`IPSS_UUID`]: constant.IPSS_UUID.html
+//! [`IpspChannels`]: type.IpspChannels.html
+//! [`BleChannelMap`]: ../struct.BleChannelMap.html
+//! [`BleChannelMap::with_coc`]: ../struct.BleChannelMap.html#method.with_coc
+//! [`PacketSink`]: trait.PacketSink.html
+//! [`CocSender`]: ../struct.CocSender.html
+//! [`IpspNode::channel`]: struct.IpspNode.html#method.channel
+//! [`L2CAPStateTx::coc`]: ../struct.L2CAPStateTx.html#method.coc
+
+use super::{Channel, CocHandler, CreditChannels, Psm};
+use crate::uuid::{consts::services, Uuid16};
+
+/// The MTU required for IPSP channels.
+///
+/// This is the minimum MTU required by IPv6.
+pub const IPSP_MTU: usize = 1280;
+
+/// UUID of the *Internet Protocol Support Service* (IPSS).
+pub const IPSS_UUID: Uuid16 = services::INTERNET_PROTOCOL_SUPPORT;
+
+/// Channel manager for a single IPSP channel.
+pub type IpspChannels<S> = CreditChannels<IpspNode<S>, IPSP_MTU, 1>;
+
+/// Application interface for receiving IPv6 packets.
+pub trait PacketSink {
+    /// Called when a 6LoWPAN-compressed IPv6 packet has been received.
+    fn packet_received(&mut self, packet: &[u8]);
+
+    /// Called when an IPSP router has opened the IPSP channel.
+    fn link_up(&mut self) {}
+
+    /// Called when the IPSP channel has been closed.
+    fn link_down(&mut self) {}
+}
+
+/// An IPSP node, accepting a single channel from an IPSP router.
+pub struct IpspNode<S: PacketSink> {
+    sink: S,
+    channel: Option<Channel>,
+}
+
+impl<S: PacketSink> IpspNode<S> {
+    /// Creates an IPSP node that passes received packets to `sink`.
+    pub fn new(sink: S) -> Self {
+        Self {
+            sink,
+            channel: None,
+        }
+    }
+
+    /// Creates the channel manager for use with a `BleChannelMap`.
+    pub fn into_channels(self) -> IpspChannels<S> {
+        CreditChannels::new(self)
+    }
+
+    /// Returns the local channel identifier of the IPSP channel, if one is open.
+    ///
+    /// This can be passed to `L2CAPStateTx::coc` to send packets.
+    pub fn channel(&self) -> Option<Channel> {
+        self.channel
+    }
+
+    /// Provides mutable access to the `PacketSink`.
+    pub fn sink(&mut self) -> &mut S {
+        &mut self.sink
+    }
+}
+
+impl<S: PacketSink> CocHandler for IpspNode<S> {
+    fn accept(&mut self, psm: Psm, channel: Channel) -> bool {
+        if psm != Psm::IPSP || self.channel.is_some() {
+            return false;
+        }
+
+        self.channel = Some(channel);
+        self.sink.link_up();
+        true
+    }
+
+    fn sdu_received(&mut self, _channel: Channel, sdu: &[u8]) {
+        self.sink.packet_received(sdu);
+    }
+
+    fn disconnected(&mut self, channel: Channel) {
+        if self.channel == Some(channel) {
+            self.channel = None;
+            self.sink.link_down();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::l2cap::{CocManager, CocSender, ConnectionResult, L2capError};
+    use crate::link::queue::{Consume, Consumer, PacketQueue, SimpleQueue};
+
+    #[derive(Default)]
+    struct Sink {
+        up: bool,
+        packets: std::vec::Vec<std::vec::Vec<u8>>,
+    }
+
+    impl PacketSink for Sink {
+        fn packet_received(&mut self, packet: &[u8]) {
+            self.packets.push(packet.to_vec());
+        }
+
+        fn link_up(&mut self) {
+            self.up = true;
+        }
+
+        fn link_down(&mut self) {
+            self.up = false;
+        }
+    }
+
+    /// An uncompressed IPv6 packet: The 6LoWPAN `IPv6` dispatch, the IPv6 header and a payload.
+    fn ipv6_packet() -> [u8; 60] {
+        let mut packet = [0; 60];
+        packet[0] = 0x41;
+        packet[1] = 0x60;
+        for (i, b) in packet[2..].iter_mut().enumerate().skip(1) {
+            *b = i as u8;
+        }
+        packet
+    }
+
+    #[test]
+    fn packet_roundtrip() {
+        let mut queue = SimpleQueue::new();
+        let (mut tx, mut rx) = (&mut queue).split();
+        let mut ipsp = IpspNode::new(Sink::default()).into_channels();
+
+        assert_eq!(
+            ipsp.connect(Psm(0x0080), Channel(0x0040), 1280, 23, 10)
+                .unwrap_err(),
+            ConnectionResult::PsmNotSupported
+        );
+        let local = ipsp
+            .connect(Psm::IPSP, Channel(0x0041), 1280, 23, 10)
+            .unwrap();
+        assert!(ipsp.handler().sink().up);
+        assert_eq!(ipsp.handler().channel(), Some(local.channel));
+        assert_eq!(usize::from(local.mtu), IPSP_MTU);
+
+        // Only one router may connect
+        assert_eq!(
+            ipsp.connect(Psm::IPSP, Channel(0x0042), 1280, 23, 10)
+                .unwrap_err(),
+            ConnectionResult::NoResources
+        );
+
+        // Router -> node, in 3 K-frames
+        let packet = ipv6_packet();
+        let mut first = [0; 23];
+        first[..2].copy_from_slice(&60u16.to_le_bytes());
+        first[2..].copy_from_slice(&packet[..21]);
+        for frame in [&first[..], &packet[21..44], &packet[44..]].iter() {
+            assert!(ipsp
+                .process_frame(local.channel, frame, &mut tx)
+                .should_consume());
+        }
+        assert_eq!(ipsp.handler().sink().packets, [packet.to_vec()]);
+
+        // The credits are returned to the router
+        rx.consume_raw_with(|_, raw| {
+            assert_eq!(raw, &[8, 0, 0x05, 0x00, 0x16, 1, 4, 0, 0x40, 0x00, 3, 0]);
+            Consume::always(Ok(()))
+        })
+        .unwrap();
+
+        // Node -> router, one K-frame at a time
+        let mut sdu = std::vec::Vec::new();
+        let mut sender = CocSender::new(ipsp.channel_tx(local.channel).unwrap(), &mut tx);
+        let mut sent = 0;
+        while sent < packet.len() {
+            sent += sender.write(&packet[sent..]).unwrap();
+            rx.consume_raw_with(|_, raw| {
+                assert_eq!(&raw[2..4], &[0x41, 0x00]);
+                sdu.extend_from_slice(&raw[4..]);
+                Consume::always(Ok(()))
+            })
+            .unwrap();
+        }
+        assert_eq!(&sdu[..2], &60u16.to_le_bytes());
+        assert_eq!(&sdu[2..], &packet[..]);
+        assert_eq!(sender.credits(), 7);
+    }
+
+    #[test]
+    fn oversized_packets() {
+        let mut queue = SimpleQueue::new();
+        let (mut tx, mut rx) = (&mut queue).split();
+        let mut ipsp = IpspNode::new(Sink::default()).into_channels();
+        let local = ipsp
+            .connect(Psm::IPSP, Channel(0x0041), 1280, 23, 10)
+            .unwrap();
+
+        // Packets exceeding the router's MTU are not sent
+        let mut sender = CocSender::new(ipsp.channel_tx(local.channel).unwrap(), &mut tx);
+        assert_eq!(
+            sender.write(&[0; IPSP_MTU + 1]),
+            Err(L2capError::SduTooLarge)
+        );
+        assert!(!sender.is_sdu_in_progress());
+        assert!(!rx.has_data());
+
+        // A router sending a packet exceeding our MTU is disconnected
+        let mut first = [0; 23];
+        first[..2].copy_from_slice(&(IPSP_MTU as u16 + 1).to_le_bytes());
+        ipsp.process_frame(local.channel, &first, &mut tx);
+        assert!(ipsp.handler().sink().packets.is_empty());
+        assert!(!ipsp.handler().sink().up);
+        assert_eq!(ipsp.handler().channel(), None);
+        rx.consume_raw_with(|_, raw| {
+            assert_eq!(
+                raw,
+                &[8, 0, 0x05, 0x00, 0x06, 1, 4, 0, 0x41, 0x00, 0x40, 0x00]
+            );
+            Consume::always(Ok(()))
+        })
+        .unwrap();
+
+        // The router may connect again
+        ipsp.connect(Psm::IPSP, Channel(0x0042), 1280, 23, 10)
+            .unwrap();
+        assert!(ipsp.handler().sink().up);
+    }
+}
@@ -0,0 +1,188 @@
+//! A connection-oriented channel that sends every received SDU back to the peer.
+//!
+//! The loopback channel is the smallest complete user of the LE credit-based channel API, and can
+//! serve as a template for protocols built on top of it: [`Loopback`] is a [`CocHandler`] that
+//! accepts a single channel on a configurable [`Psm`], stores each received SDU, and sends it back
+//! from [`CocHandler::send_ready`] as the peer grants credits. It is also useful for testing
+//! segmentation and throughput of connection-oriented channels with a central.
+//!
+//! To use it, add [`LoopbackChannels`] to the [`BleChannelMap`] via [`BleChannelMap::with_coc`]:
+//!
+//! ```
+//! use rubble::l2cap::{loopback::Loopback, BleChannelMap, Psm};
+//!
+//! let channels = Loopback::<64>::new(Psm(0x0080)).into_channels();
+//! let mapper = BleChannelMap::empty().with_coc(channels);
+//! ```
+//!
+//! [`Loopback`]: struct.Loopback.html
+//! [`CocHandler`]: ../trait.CocHandler.html
+//! [`Psm`]: ../struct.Psm.html
+//! [`CocHandler::send_ready`]: ../trait.CocHandler.html#method.send_ready
+//! [`LoopbackChannels`]: type.LoopbackChannels.html
+//! [`BleChannelMap`]: ../struct.BleChannelMap.html
+//! [`BleChannelMap::with_coc`]: ../struct.BleChannelMap.html#method.with_coc
+
+use super::{Channel, CocHandler, CocSender, CreditChannels, L2capError, Psm};
+
+/// Channel manager for a single loopback channel with an MTU of `MTU` Bytes.
+pub type LoopbackChannels<const MTU: usize> = CreditChannels<Loopback<MTU>, MTU, 1>;
+
+/// A `CocHandler` that echoes SDUs received on a single channel.
+///
+/// Only one SDU is buffered. SDUs received while the previous one is still being sent back are
+/// dropped.
+pub struct Loopback<const MTU: usize> {
+    psm: Psm,
+    channel: Option<Channel>,
+    /// Length of the SDU waiting to be sent back.
+    pending: Option<usize>,
+    /// Number of Bytes of the pending SDU that were already sent.
+    sent: usize,
+    buf: [u8; MTU],
+    echoed: u32,
+    dropped: u32,
+}
+
+impl<const MTU: usize> Loopback<MTU> {
+    /// Creates a loopback handler accepting channels to `psm`.
+    pub fn new(psm: Psm) -> Self {
+        Self {
+            psm,
+            channel: None,
+            pending: None,
+            sent: 0,
+            buf: [0; MTU],
+            echoed: 0,
+            dropped: 0,
+        }
+    }
+
+    /// Creates the channel manager for use with a `BleChannelMap`.
+    pub fn into_channels(self) -> LoopbackChannels<MTU> {
+        CreditChannels::new(self)
+    }
+
+    /// Returns the local channel identifier of the loopback channel, if one is open.
+    pub fn channel(&self) -> Option<Channel> {
+        self.channel
+    }
+
+    /// Returns the number of SDUs that were completely sent back.
+    pub fn echoed(&self) -> u32 {
+        self.echoed
+    }
+
+    /// Returns the number of received SDUs that could not be sent back.
+    pub fn dropped(&self) -> u32 {
+        self.dropped
+    }
+}
+
+impl<const MTU: usize> CocHandler for Loopback<MTU> {
+    fn accept(&mut self, psm: Psm, channel: Channel) -> bool {
+        if psm != self.psm || self.channel.is_some() {
+            return false;
+        }
+
+        self.channel = Some(channel);
+        self.pending = None;
+        true
+    }
+
+    fn sdu_received(&mut self, _channel: Channel, sdu: &[u8]) {
+        if self.pending.is_some() {
+            warn!("loopback busy, dropping {}-Byte SDU", sdu.len());
+            self.dropped += 1;
+            return;
+        }
+
+        // `CreditChannels` never reassembles SDUs larger than `MTU`
+        self.buf[..sdu.len()].copy_from_slice(sdu);
+        self.pending = Some(sdu.len());
+        self.sent = 0;
+    }
+
+    fn disconnected(&mut self, channel: Channel) {
+        if self.channel == Some(channel) {
+            self.channel = None;
+            self.pending = None;
+        }
+    }
+
+    fn send_ready(&mut self, _channel: Channel, sender: &mut CocSender<'_>) {
+        let len = match self.pending {
+            Some(len) => len,
+            None => return,
+        };
+
+        match sender.write(&self.buf[self.sent..len]) {
+            Ok(written) => {
+                self.sent += written;
+                if self.sent == len {
+                    self.pending = None;
+                    self.echoed += 1;
+                }
+            }
+            // Retried once the peer grants credits or the TX queue has space again
+            Err(L2capError::NoCredits) | Err(L2capError::TxBusy) => {}
+            Err(e) => {
+                warn!("can't echo {}-Byte SDU: {:?}", len, e);
+                self.pending = None;
+                self.dropped += 1;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::l2cap::CocManager;
+    use crate::link::queue::{Consume, Consumer, PacketQueue, SimpleQueue};
+
+    #[test]
+    fn echo() {
+        let mut queue = SimpleQueue::new();
+        let (mut tx, mut rx) = (&mut queue).split();
+        let mut coc = Loopback::<32>::new(Psm(0x0080)).into_channels();
+
+        let local = coc
+            .connect(Psm(0x0080), Channel(0x0041), 64, 23, 10)
+            .unwrap();
+        assert_eq!(coc.handler().channel(), Some(local.channel));
+        assert!(coc
+            .connect(Psm(0x0080), Channel(0x0042), 64, 23, 10)
+            .is_err());
+
+        let sdu = [5, 0, 1, 2, 3, 4, 5];
+        assert!(coc
+            .process_frame(local.channel, &sdu, &mut tx)
+            .should_consume());
+
+        // The queue only fits the returned credits, so the SDU is sent back later
+        rx.consume_raw_with(|_, raw| {
+            assert_eq!(raw[2..4], [0x05, 0x00]);
+            Consume::always(Ok(()))
+        })
+        .unwrap();
+        assert!(!rx.has_data());
+        assert_eq!(coc.handler().echoed(), 0);
+
+        // Received while the first SDU is still pending
+        coc.process_frame(local.channel, &sdu, &mut tx);
+        assert_eq!(coc.handler().dropped(), 1);
+        rx.consume_raw_with(|_, _| Consume::always(Ok(()))).unwrap();
+
+        coc.poll(&mut tx);
+        rx.consume_raw_with(|_, raw| {
+            assert_eq!(raw, &[7, 0, 0x41, 0x00, 5, 0, 1, 2, 3, 4, 5]);
+            Consume::always(Ok(()))
+        })
+        .unwrap();
+        assert_eq!(coc.handler().echoed(), 1);
+
+        assert!(coc.disconnect(local.channel, Channel(0x0041)));
+        assert_eq!(coc.handler().channel(), None);
+    }
+}
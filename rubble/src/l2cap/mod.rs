@@ -18,17 +18,40 @@
 //! Bluetooth SIG or allocated dynamically for use with the Service Discovery Protocol (SDP). The
 //! preallocated numbers are hosted online [here][l2c].
 //!
+//! # Transport
+//!
+//! L2CAP doesn't depend on Rubble's Link-Layer directly. Received messages are passed to
+//! [`L2CAPStateTx::process_start`], and outgoing data is written to a [`Producer`] that accepts
+//! data channel payloads tagged with an LLID. With the built-in Link-Layer, the [`Responder`]
+//! connects L2CAP to the packet queues. When using an external controller, the [`hci::Host`]
+//! instead exchanges the data as HCI ACL data packets.
+//!
+//! [`L2CAPStateTx::process_start`]: struct.L2CAPStateTx.html#method.process_start
+//! [`Producer`]: ../link/queue/trait.Producer.html
+//! [`Responder`]: ../link/struct.Responder.html
+//! [`hci::Host`]: ../hci/struct.Host.html
 //! [l2c]: https://www.bluetooth.com/specifications/assigned-numbers/logical-link-control
 
+mod coc;
+pub mod conn_params;
+pub mod echo;
+pub mod ipsp;
+pub mod loopback;
 mod signaling;
 
+pub use self::coc::*;
+pub use self::conn_params::{ConnParamUpdater, ConnectionParameters};
+pub use self::echo::EchoProbe;
+
 use self::signaling::SignalingState;
 use crate::att::{self, AttributeProvider, AttributeServer, NoAttributes};
 use crate::link::queue::{Consume, Producer};
-use crate::link::{data::Llid, MIN_DATA_PAYLOAD_BUF};
-use crate::security::{NoSecurity, SecurityLevel, SecurityManager};
+use crate::link::{data::Llid, MAX_DATA_PAYLOAD_BUF, MIN_DATA_PAYLOAD_BUF};
+use crate::security::{NoSecurity, SecurityLevel, SecurityManager, SecurityManagerTx};
+use crate::time::{Duration, Instant};
 use crate::{bytes::*, utils::HexSlice, Error};
 use core::fmt;
+use core::marker::PhantomData;
 use core::ops::{Deref, DerefMut};
 
 /// An L2CAP channel identifier (CID).
@@ -83,6 +106,12 @@ impl Channel {
         !self.is_connectionless()
     }
 
+    /// Returns whether this channel identifier lies in the range used for dynamically allocated
+    /// LE channels (`0x0040`-`0x007F`).
+    pub fn is_dynamic(&self) -> bool {
+        matches!(self.0, 0x0040..=0x007F)
+    }
+
     /// Returns whether this channel is connectionless.
     ///
     /// L2CAP PDUs addressed to connectionless channels are called *G-frames*.
@@ -120,13 +149,81 @@ pub trait ChannelMapper {
     /// The attribute provider used by the ATT server.
     type AttributeProvider: AttributeProvider;
 
+    /// The security level supported by the LE Security Manager.
+    type SecurityLevel: SecurityLevel;
+
     /// Look up what's connected to `channel` (eg. the `Protocol` to which to forward).
     fn lookup(&mut self, channel: Channel) -> Option<ChannelData<'_, dyn ProtocolObj + '_>>;
 
     /// Returns information about the Attribute Protocol on channel `0x0004`.
     fn att(&mut self) -> ChannelData<'_, AttributeServer<Self::AttributeProvider>>;
+
+    /// Returns information about the LE Security Manager on channel `0x0006`.
+    fn security(&mut self) -> ChannelData<'_, SecurityManager<Self::SecurityLevel>>;
+
+    /// Returns the manager of LE credit-based connection-oriented channels.
+    ///
+    /// K-frames received on dynamically allocated channels are forwarded to it. The default
+    /// implementation returns `None`, which means that no such channels are supported.
+    fn coc(&mut self) -> Option<&mut dyn CocManager> {
+        None
+    }
+
+    /// Returns the updater requesting the peripheral's preferred connection parameters.
+    ///
+    /// It is notified of all ATT traffic so that it can wait for GATT discovery to finish. The
+    /// default implementation returns `None`, which disables automatic parameter updates.
+    fn conn_params(&mut self) -> Option<&mut ConnParamUpdater> {
+        None
+    }
+
+    /// Returns the probe answering and sending diagnostic *Echo Requests*.
+    ///
+    /// The default implementation returns `None`, which makes the device reject *Echo Requests*.
+    fn echo(&mut self) -> Option<&mut EchoProbe> {
+        None
+    }
 }
 
+/// Errors returned by L2CAP operations.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, defmt::Format)]
+#[non_exhaustive]
+pub enum L2capError {
+    /// The TX packet queue doesn't have enough free space for the PDU.
+    ///
+    /// The operation can be retried once `Cmd::tx_space_freed` is set.
+    TxBusy,
+
+    /// The peer has not granted any credits on a credit-based channel.
+    ///
+    /// The operation can be retried once the peer has sent an *LE Flow Control Credit* packet.
+    NoCredits,
+
+    /// The SDU is larger than the MTU of the peer.
+    SduTooLarge,
+
+    /// The data doesn't match the rest of the SDU whose transmission is in progress.
+    SduMismatch,
+
+    /// The PDU could not be encoded.
+    Encoding,
+}
+
+impl fmt::Display for L2capError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            L2capError::TxBusy => "not enough space in TX queue",
+            L2capError::NoCredits => "no credits available",
+            L2capError::SduTooLarge => "SDU exceeds the peer's MTU",
+            L2capError::SduMismatch => "data does not match the SDU in progress",
+            L2capError::Encoding => "PDU could not be encoded",
+        })
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for L2capError {}
+
 /// Data associated with a connected L2CAP channel.
 pub struct ChannelData<'a, P: ?Sized> {
     /// Channel to which responses should be addressed.
@@ -143,20 +240,22 @@ pub struct ChannelData<'a, P: ?Sized> {
     /// This is the number of bytes that must be available to the protocol in the TX buffer to
     /// guarantee that all of the protocol's PDUs will fit.
     pdu: u8,
+
+    /// The largest PDU the protocol may currently send, as reported by `Protocol::max_pdu_size`.
+    max_pdu: u16,
 }
 
 impl<'a> ChannelData<'a, dyn ProtocolObj + 'a> {
     /// Creates a `ChannelData` carrying a dynamically-dispatched `dyn ProtocolObj` from a concrete
     /// `Protocol` implementor `T`.
     fn new_dyn<T: Protocol + 'a>(response_channel: Channel, protocol: &'a mut T) -> Self {
-        assert!(
-            usize::from(T::RSP_PDU_SIZE + Header::SIZE) <= MIN_DATA_PAYLOAD_BUF,
-            "protocol min PDU is smaller than data channel PDU (L2CAP reassembly NYI)"
-        );
+        #[allow(clippy::let_unit_value)]
+        let () = RspFits::<T>::CHECK;
 
         ChannelData {
             response_channel,
             pdu: T::RSP_PDU_SIZE,
+            max_pdu: protocol.max_pdu_size(),
             protocol,
         }
     }
@@ -164,14 +263,13 @@ impl<'a> ChannelData<'a, dyn ProtocolObj + 'a> {
 
 impl<'a, P: Protocol> ChannelData<'a, P> {
     fn new(response_channel: Channel, protocol: &'a mut P) -> Self {
-        assert!(
-            usize::from(P::RSP_PDU_SIZE + Header::SIZE) <= MIN_DATA_PAYLOAD_BUF,
-            "protocol min PDU is smaller than data channel PDU (L2CAP reassembly NYI)"
-        );
+        #[allow(clippy::let_unit_value)]
+        let () = RspFits::<P>::CHECK;
 
         ChannelData {
             response_channel,
             pdu: P::RSP_PDU_SIZE,
+            max_pdu: protocol.max_pdu_size(),
             protocol,
         }
     }
@@ -195,6 +293,17 @@ impl<'a, P: ?Sized> ChannelData<'a, P> {
         self.pdu
     }
 
+    /// Returns the largest PDU size the protocol may currently use on this connection.
+    ///
+    /// This is at least [`pdu_size`], and may be larger if the protocol negotiated a larger MTU
+    /// with the peer. The space actually provided to the protocol is further limited by the
+    /// Link-Layer payload length and the space in the TX buffer.
+    ///
+    /// [`pdu_size`]: #method.pdu_size
+    pub fn max_pdu_size(&self) -> u16 {
+        self.max_pdu.max(u16::from(self.pdu))
+    }
+
     /// Returns the protocol connected to the channel.
     pub fn protocol(&mut self) -> &mut P {
         self.protocol
@@ -209,17 +318,24 @@ impl<'a, P: ?Sized> ChannelData<'a, P> {
     }
 }
 
-/// A fixed BLE channel map that provides only the required channel endpoints and does not allow
-/// dynamic channels.
+/// A BLE channel map that provides the required fixed channel endpoints and, optionally, LE
+/// credit-based connection-oriented channels.
 ///
-/// The channels are mapped as follows (no other channels are supported):
+/// The fixed channels are mapped as follows:
 ///
 /// * `0x0004`: Attribute protocol (ATT).
 /// * `0x0005`: LE L2CAP signaling channel.
 /// * `0x0006`: LE Security Manager protocol.
-pub struct BleChannelMap<A: AttributeProvider, S: SecurityLevel> {
+///
+/// Dynamic channels (`0x0040`-`0x007F`) are managed by the [`CocManager`] `C`. By default,
+/// `NoCoc` is used, which rejects all connection requests. Use [`with_coc`] to add support for
+/// credit-based channels.
+///
+/// [`CocManager`]: trait.CocManager.html
+/// [`with_coc`]: #method.with_coc
+pub struct BleChannelMap<A: AttributeProvider, S: SecurityLevel, C: CocManager = NoCoc> {
     att: AttributeServer<A>,
-    signaling: SignalingState,
+    signaling: SignalingState<C>,
     sm: SecurityManager<S>,
 }
 
@@ -232,7 +348,7 @@ impl BleChannelMap<NoAttributes, NoSecurity> {
     pub fn empty() -> Self {
         Self {
             att: AttributeServer::new(NoAttributes),
-            signaling: SignalingState::new(),
+            signaling: SignalingState::new(NoCoc),
             sm: SecurityManager::no_security(),
         }
     }
@@ -242,19 +358,77 @@ impl<A: AttributeProvider> BleChannelMap<A, NoSecurity> {
     pub fn with_attributes(att: A) -> Self {
         Self {
             att: AttributeServer::new(att),
-            signaling: SignalingState::new(),
+            signaling: SignalingState::new(NoCoc),
             sm: SecurityManager::no_security(),
         }
     }
+}
 
+impl<A: AttributeProvider, S: SecurityLevel, C: CocManager> BleChannelMap<A, S, C> {
     /// Provides mutable access to the underlying `AttributeProvider`.
     pub fn attribute_provider(&mut self) -> &mut A {
         self.att.provider()
     }
+
+    /// Replaces the manager of LE credit-based connection-oriented channels.
+    ///
+    /// This should be done before a connection is established, since any open channels managed by
+    /// the old `CocManager` are dropped.
+    pub fn with_coc<D: CocManager>(self, coc: D) -> BleChannelMap<A, S, D> {
+        BleChannelMap {
+            att: self.att,
+            signaling: self.signaling.with_coc(coc),
+            sm: self.sm,
+        }
+    }
+
+    /// Requests the peripheral's preferred connection parameters once the connection is idle.
+    ///
+    /// See [`ConnParamUpdater`] for details.
+    ///
+    /// [`ConnParamUpdater`]: struct.ConnParamUpdater.html
+    pub fn with_conn_params(mut self, updater: ConnParamUpdater) -> Self {
+        self.signaling.set_conn_params(updater);
+        self
+    }
+
+    /// Answers diagnostic *Echo Requests* and allows sending them to measure the round-trip time.
+    ///
+    /// See [`EchoProbe`] for details.
+    ///
+    /// [`EchoProbe`]: struct.EchoProbe.html
+    pub fn with_echo(mut self, probe: EchoProbe) -> Self {
+        self.signaling.set_echo(probe);
+        self
+    }
+
+    /// Provides mutable access to the `CocManager`.
+    pub fn coc_manager(&mut self) -> &mut C {
+        self.signaling.coc()
+    }
+
+    /// Replaces the security level supported by the LE Security Manager.
+    ///
+    /// By default, `NoSecurity` is used, which rejects all pairing requests.
+    pub fn with_security<T: SecurityLevel>(self, security: T) -> BleChannelMap<A, T, C> {
+        BleChannelMap {
+            att: self.att,
+            signaling: self.signaling,
+            sm: SecurityManager::new(security),
+        }
+    }
+
+    /// Provides mutable access to the `SecurityManager`.
+    pub fn security_manager(&mut self) -> &mut SecurityManager<S> {
+        &mut self.sm
+    }
 }
 
-impl<A: AttributeProvider, S: SecurityLevel> ChannelMapper for BleChannelMap<A, S> {
+impl<A: AttributeProvider, S: SecurityLevel, C: CocManager> ChannelMapper
+    for BleChannelMap<A, S, C>
+{
     type AttributeProvider = A;
+    type SecurityLevel = S;
 
     fn lookup(&mut self, channel: Channel) -> Option<ChannelData<'_, dyn ProtocolObj + '_>> {
         match channel {
@@ -268,11 +442,63 @@ impl<A: AttributeProvider, S: SecurityLevel> ChannelMapper for BleChannelMap<A,
     fn att(&mut self) -> ChannelData<'_, AttributeServer<Self::AttributeProvider>> {
         ChannelData::new(Channel::ATT, &mut self.att)
     }
+
+    fn security(&mut self) -> ChannelData<'_, SecurityManager<Self::SecurityLevel>> {
+        ChannelData::new(Channel::LE_SECURITY_MANAGER, &mut self.sm)
+    }
+
+    fn coc(&mut self) -> Option<&mut dyn CocManager> {
+        Some(self.signaling.coc())
+    }
+
+    fn conn_params(&mut self) -> Option<&mut ConnParamUpdater> {
+        self.signaling.conn_params()
+    }
+
+    fn echo(&mut self) -> Option<&mut EchoProbe> {
+        self.signaling.echo()
+    }
 }
 
 /// Trait for protocols that sit on top of L2CAP (object-safe part).
 ///
 /// A protocol can be connected to an L2CAP channel via a `ChannelMapper`.
+///
+/// # Example
+///
+/// A protocol that sends every message back to the peer:
+///
+/// ```
+/// use rubble::l2cap::{Protocol, ProtocolObj, Sender};
+/// use rubble::Error;
+///
+/// struct Echo;
+///
+/// impl ProtocolObj for Echo {
+///     fn process_message(
+///         &mut self,
+///         message: &[u8],
+///         mut responder: Sender<'_>,
+///     ) -> Result<(), Error> {
+///         // Only `pdu_budget` Bytes fit into the response
+///         if message.len() > usize::from(responder.pdu_budget()) {
+///             // Not an error of the protocol: The peer just gets no response
+///             return Ok(());
+///         }
+///         responder.send(message)
+///     }
+/// }
+///
+/// impl Protocol for Echo {
+///     // Messages are only passed to `process_message` once this much space is available
+///     const RSP_PDU_SIZE: u8 = 23;
+/// }
+/// ```
+///
+/// Connection-oriented channels don't use this trait. Protocols on top of them implement
+/// `CocHandler` instead (see the [`loopback`] module for an example).
+///
+/// [`loopback`]: loopback/index.html
 pub trait ProtocolObj {
     /// Process a message sent to the protocol.
     ///
@@ -297,10 +523,19 @@ pub trait Protocol: ProtocolObj {
     /// Incoming PDUs will only be forwarded to the protocol if there is at least this much space in
     /// the TX buffer.
     const RSP_PDU_SIZE: u8;
+
+    /// Returns the maximum size of PDUs the protocol may currently send.
+    ///
+    /// This can exceed `RSP_PDU_SIZE` when the protocol has negotiated a larger MTU with the peer.
+    /// The `Sender` passed to the protocol then provides as much of that as fits into a Link-Layer
+    /// packet. The default implementation returns `RSP_PDU_SIZE`.
+    fn max_pdu_size(&self) -> u16 {
+        u16::from(Self::RSP_PDU_SIZE)
+    }
 }
 
 /// Header used by *all* L2CAP PDUs.
-#[derive(Debug)]
+#[derive(Debug, defmt::Format)]
 struct Header {
     /// Length of the payload following the length and channel fields (after reassembly).
     length: u16,
@@ -336,12 +571,8 @@ struct Message<P> {
 
 impl<'a, P: FromBytes<'a>> FromBytes<'a> for Message<P> {
     fn from_bytes(bytes: &mut ByteReader<'a>) -> Result<Self, Error> {
+        // `payload` is only the first fragment if `header.length` exceeds the remaining Bytes
         let header = Header::from_bytes(bytes)?;
-        assert_eq!(
-            header.length as usize,
-            bytes.bytes_left(),
-            "L2CAP reassembly not yet implemented"
-        );
 
         Ok(Self {
             header,
@@ -358,16 +589,51 @@ impl<P: ToBytes> ToBytes for Message<P> {
     }
 }
 
+/// Fails compilation if the responses of protocol `P` don't fit in a minimum-size data channel
+/// PDU, which is all the peer has to support.
+struct RspFits<P>(PhantomData<P>);
+
+impl<P: Protocol> RspFits<P> {
+    const CHECK: () = assert!(
+        P::RSP_PDU_SIZE as usize + Header::SIZE as usize <= MIN_DATA_PAYLOAD_BUF,
+        "protocol min PDU is smaller than data channel PDU (L2CAP reassembly NYI)"
+    );
+}
+
 /// L2CAP channel manager and responder.
 #[derive(Debug)]
 pub struct L2CAPState<M: ChannelMapper> {
     mapper: M,
+
+    /// Maximum payload length of outgoing Link-Layer data channel PDUs.
+    max_payload: u8,
 }
 
 impl<M: ChannelMapper> L2CAPState<M> {
     /// Creates a new L2CAP state using the given channel configuration.
     pub fn new(mapper: M) -> Self {
-        Self { mapper }
+        Self {
+            mapper,
+            max_payload: MIN_DATA_PAYLOAD_BUF as u8,
+        }
+    }
+
+    /// Sets the maximum payload length of outgoing Link-Layer data channel PDUs.
+    ///
+    /// This should be called when the connection's payload length changes (eg. through the Data
+    /// Length Update procedure). Since L2CAP fragmentation is not implemented, an outgoing L2CAP
+    /// message must fit into a single data channel PDU, so this limits the size of all PDUs sent
+    /// by the protocols on top of L2CAP. `len` is clamped to the range allowed by the
+    /// specification (27-251 Bytes).
+    pub fn set_max_payload_len(&mut self, len: u8) {
+        self.max_payload = len
+            .max(MIN_DATA_PAYLOAD_BUF as u8)
+            .min(MAX_DATA_PAYLOAD_BUF as u8);
+    }
+
+    /// Returns the maximum payload length of outgoing Link-Layer data channel PDUs.
+    pub fn max_payload_len(&self) -> u8 {
+        self.max_payload
     }
 
     /// Gives this instance the ability to transmit packets.
@@ -386,7 +652,10 @@ impl<M: ChannelMapper> L2CAPState<M> {
 /// This can be done either in response to an incoming packet (via `ProtocolObj::process_msg`), or
 /// as a device-initiated packet (eg. an attribute notification).
 pub struct Sender<'a> {
-    /// The protocol's max. outgoing PDU size.
+    /// Number of Bytes available for the outgoing PDU.
+    ///
+    /// This is at least the protocol's `RSP_PDU_SIZE`, and at most what fits into a single
+    /// Link-Layer packet.
     pdu: u8,
 
     /// Data PDU channel.
@@ -400,8 +669,16 @@ impl<'a> Sender<'a> {
     /// Creates a `Sender` from a `Producer`, ensuring that sufficient free space is available to
     /// fit a PDU described by `chdata`.
     ///
+    /// `max_payload` is the maximum Link-Layer payload length of the connection. The PDU budget is
+    /// the largest size allowed by the protocol that also fits into `max_payload` and into the
+    /// free space in `tx`.
+    ///
     /// If there is not enough space in `tx`, returns `None`.
-    fn new<T: ?Sized>(chdata: &ChannelData<'_, T>, tx: &'a mut dyn Producer) -> Option<Self> {
+    fn new<T: ?Sized>(
+        chdata: &ChannelData<'_, T>,
+        tx: &'a mut dyn Producer,
+        max_payload: u8,
+    ) -> Option<Self> {
         let free = tx.free_space();
         let needed = chdata.pdu_size() + Header::SIZE;
         if free < needed {
@@ -410,7 +687,11 @@ impl<'a> Sender<'a> {
         }
 
         let resp_channel = chdata.response_channel();
-        let pdu = chdata.pdu_size();
+        let available = free.min(max_payload) - Header::SIZE;
+        let pdu = chdata
+            .max_pdu_size()
+            .min(u16::from(available))
+            .max(u16::from(chdata.pdu_size())) as u8;
         Some(Sender {
             pdu,
             tx,
@@ -418,6 +699,11 @@ impl<'a> Sender<'a> {
         })
     }
 
+    /// Returns the number of Bytes available for the PDU passed to `send` or `send_with`.
+    pub fn pdu_budget(&self) -> u8 {
+        self.pdu
+    }
+
     /// Enqueues an L2CAP message to be sent over the data connection.
     ///
     /// L2CAP header (including the destination endpoint's channel) and the data channel PDU header
@@ -433,8 +719,10 @@ impl<'a> Sender<'a> {
     /// L2CAP header and data channel PDU header will be added automatically. The closure `f` only
     /// has to write the protocol PDU to transmit over L2CAP.
     ///
-    /// The L2CAP implementation will ensure that there are exactly `Protocol::RSP_PDU_SIZE` Bytes
-    /// available in the `ByteWriter` passed to the closure.
+    /// The `ByteWriter` passed to the closure provides exactly [`pdu_budget`] Bytes, which is at
+    /// least `Protocol::RSP_PDU_SIZE`.
+    ///
+    /// [`pdu_budget`]: #method.pdu_budget
     pub fn send_with<T, E>(
         &mut self,
         f: impl FnOnce(&mut ByteWriter<'_>) -> Result<T, E>,
@@ -514,9 +802,17 @@ impl<'a, M: ChannelMapper, P: Producer> L2CAPStateTx<'a, M, P> {
             Err(e) => return Consume::always(Err(e)),
         };
 
-        if usize::from(msg.header.length) != msg.payload.len() {
-            // Lengths mismatch => Reassembly needed
-            unimplemented!("L2CAP reassembly");
+        let length = usize::from(msg.header.length);
+        if length < msg.payload.len() {
+            return Consume::always(Err(Error::InvalidLength));
+        } else if length > msg.payload.len() {
+            // Reassembly needed, which isn't implemented yet
+            warn!(
+                "dropping fragmented L2CAP message ({}/{} Bytes)",
+                msg.payload.len(),
+                length
+            );
+            return Consume::always(Ok(()));
         }
 
         self.dispatch(msg.header.channel, msg.payload)
@@ -524,22 +820,45 @@ impl<'a, M: ChannelMapper, P: Producer> L2CAPStateTx<'a, M, P> {
 
     /// Process continuation of an L2CAP message.
     ///
-    /// This is not yet implemented and will always panic.
-    pub fn process_cont(&mut self, _data: &[u8]) -> Consume<()> {
-        unimplemented!("reassembly")
+    /// Reassembly is not yet implemented, so this drops the fragment.
+    pub fn process_cont(&mut self, data: &[u8]) -> Consume<()> {
+        warn!("dropping L2CAP continuation fragment: {:?}", HexSlice(data));
+        Consume::always(Ok(()))
     }
 
     /// Dispatches a fully reassembled L2CAP message to the protocol listening on the addressed
     /// channel.
     fn dispatch(&mut self, channel: Channel, payload: &[u8]) -> Consume<()> {
+        if channel.is_dynamic() {
+            if let Some(coc) = self.l2cap.mapper.coc() {
+                return coc.process_frame(channel, payload, self.tx);
+            }
+        }
+
+        if channel == Channel::ATT {
+            if let Some(updater) = self.l2cap.mapper.conn_params() {
+                updater.activity();
+            }
+        }
+
+        let max_payload = self.l2cap.max_payload;
         if let Some(mut chdata) = self.l2cap.mapper.lookup(channel) {
-            let sender = if let Some(sender) = Sender::new(&chdata, self.tx) {
+            let sender = if let Some(sender) = Sender::new(&chdata, self.tx, max_payload) {
                 sender
             } else {
-                return Consume::never(Ok(()));
+                return Consume::never(Err(Error::TxBusy));
             };
 
-            Consume::always(chdata.protocol().process_message(payload, sender))
+            let consume = match chdata.protocol().process_message(payload, sender) {
+                // The protocol isn't ready, keep the message in the RX queue
+                Err(Error::RxBusy) => Consume::never(Err(Error::RxBusy)),
+                result => Consume::always(result),
+            };
+            if channel == Channel::LE_SIGNALING {
+                // The peer might have granted new credits
+                self.poll_coc();
+            }
+            consume
         } else {
             warn!(
                 "ignoring message sent to unconnected channel {:?}: {:?}",
@@ -558,10 +877,127 @@ impl<'a, M: ChannelMapper, P: Producer> L2CAPStateTx<'a, M, P> {
     ///
     /// Returns `None` if there's not enough space in the TX packet queue to send an ATT PDU. If
     /// that happens, calling this method again at a later time (after the Link-Layer had time to
-    /// transmit more packets) might succeed.
+    /// transmit more packets) might succeed. Also returns `None` if the ATT bearer was closed due
+    /// to a transaction timeout (see `AttributeServer::check_timeout`).
     pub fn att(&mut self) -> Option<att::AttributeServerTx<'_, M::AttributeProvider>> {
-        let att = self.l2cap.mapper.att();
-        Sender::new(&att, self.tx).map(move |sender| att.into_protocol().with_sender(sender))
+        let max_payload = self.l2cap.max_payload;
+        let mut att = self.l2cap.mapper.att();
+        if att.protocol().is_timed_out() {
+            return None;
+        }
+        Sender::new(&att, self.tx, max_payload)
+            .map(move |sender| att.into_protocol().with_sender(sender))
+    }
+
+    /// Sends the response to a deferred ATT read once the application has supplied the value.
+    ///
+    /// Returns whether a response was sent, or `L2capError::TxBusy` if it doesn't fit in the TX
+    /// queue. See `AttributeProvider::is_deferred` for details.
+    pub fn send_deferred(&mut self) -> Result<bool, L2capError> {
+        if !self.l2cap.mapper.att().protocol().has_deferred_response() {
+            return Ok(false);
+        }
+        match self.att() {
+            Some(att) => Ok(att.send_deferred()),
+            None => Err(L2capError::TxBusy),
+        }
+    }
+
+    /// Prepares for sending Security Manager PDUs.
+    ///
+    /// This is used to distribute keys to the peer after pairing. Returns `None` if there's not
+    /// enough space in the TX packet queue to send an SMP PDU.
+    pub fn security(&mut self) -> Option<SecurityManagerTx<'_, M::SecurityLevel>> {
+        let max_payload = self.l2cap.max_payload;
+        let sm = self.l2cap.mapper.security();
+        Sender::new(&sm, self.tx, max_payload)
+            .map(move |sender| sm.into_protocol().with_sender(sender))
+    }
+
+    /// Like [`att`], but consumes `self` so that the returned `AttributeServerTx` can outlive it.
+    ///
+    /// [`att`]: #method.att
+    pub fn into_att(self) -> Option<att::AttributeServerTx<'a, M::AttributeProvider>> {
+        let max_payload = self.l2cap.max_payload;
+        let mut att = self.l2cap.mapper.att();
+        if att.protocol().is_timed_out() {
+            return None;
+        }
+        Sender::new(&att, self.tx, max_payload)
+            .map(move |sender| att.into_protocol().with_sender(sender))
+    }
+
+    /// Like [`security`], but consumes `self` so that the returned `SecurityManagerTx` can
+    /// outlive it.
+    ///
+    /// [`security`]: #method.security
+    pub fn into_security(self) -> Option<SecurityManagerTx<'a, M::SecurityLevel>> {
+        let max_payload = self.l2cap.max_payload;
+        let sm = self.l2cap.mapper.security();
+        Sender::new(&sm, self.tx, max_payload)
+            .map(move |sender| sm.into_protocol().with_sender(sender))
+    }
+
+    /// Prepares for sending data over the LE credit-based channel with local endpoint `channel`.
+    ///
+    /// Returns `None` if no such channel is open.
+    pub fn coc(&mut self, channel: Channel) -> Option<CocSender<'_>> {
+        let tx: &mut dyn Producer = self.tx;
+        let chan = self.l2cap.mapper.coc()?.channel_tx(channel)?;
+        Some(CocSender::new(chan, tx))
+    }
+
+    /// Lets the `CocHandler`s of open connection-oriented channels send pending data.
+    ///
+    /// This is done automatically when an SDU or a signaling command is received, and should be
+    /// called when the application has new data for a handler to send (see
+    /// `CocHandler::send_ready`).
+    pub fn poll_coc(&mut self) {
+        if let Some(coc) = self.l2cap.mapper.coc() {
+            coc.poll(self.tx);
+        }
+    }
+
+    /// Lets the [`ConnParamUpdater`] send or retry its request if it is due.
+    ///
+    /// This should be called periodically while connected. Does nothing if the channel mapper
+    /// has no `ConnParamUpdater`.
+    ///
+    /// [`ConnParamUpdater`]: struct.ConnParamUpdater.html
+    pub fn poll_conn_params(&mut self, now: Instant) -> Result<(), Error> {
+        match self.l2cap.mapper.conn_params() {
+            Some(updater) => updater.poll(now, self.tx),
+            None => Ok(()),
+        }
+    }
+
+    /// Sends a diagnostic *Echo Request* carrying `data` to the peer.
+    ///
+    /// Returns `Error::InvalidValue` if the channel mapper has no [`EchoProbe`], and an error if
+    /// the request could not be enqueued.
+    ///
+    /// [`EchoProbe`]: struct.EchoProbe.html
+    pub fn send_echo(&mut self, now: Instant, data: &[u8]) -> Result<(), Error> {
+        match self.l2cap.mapper.echo() {
+            Some(probe) => probe.send(now, data, self.tx),
+            None => Err(Error::InvalidValue),
+        }
+    }
+
+    /// Returns the round-trip time of the last *Echo Request* once its response has arrived.
+    ///
+    /// Returns `None` if the response is still outstanding, or if the channel mapper has no
+    /// `EchoProbe`.
+    pub fn poll_echo(&mut self, now: Instant) -> Option<Duration> {
+        self.l2cap.mapper.echo()?.poll(now)
+    }
+
+    /// Checks whether an outstanding ATT indication has timed out.
+    ///
+    /// Returns `true` if the ATT bearer is closed, in which case the connection should be closed.
+    /// See `AttributeServer::check_timeout` for details.
+    pub fn check_att_timeout(&mut self, now: Instant) -> bool {
+        self.l2cap.mapper.att().protocol().check_timeout(now)
     }
 }
 
@@ -578,3 +1014,46 @@ impl<'a, M: ChannelMapper, P: Producer> DerefMut for L2CAPStateTx<'a, M, P> {
         &mut self.l2cap
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::link::queue::{PacketQueue, SimpleQueue};
+
+    /// A protocol that negotiated a large MTU.
+    struct Large;
+
+    impl ProtocolObj for Large {
+        fn process_message(&mut self, _: &[u8], _: Sender<'_>) -> Result<(), Error> {
+            Ok(())
+        }
+    }
+
+    impl Protocol for Large {
+        const RSP_PDU_SIZE: u8 = 10;
+
+        fn max_pdu_size(&self) -> u16 {
+            100
+        }
+    }
+
+    #[test]
+    fn pdu_budget() {
+        let mut queue = SimpleQueue::new();
+        let (mut tx, _) = (&mut queue).split();
+        let mut protocol = Large;
+        let chdata = ChannelData::new(Channel::ATT, &mut protocol);
+        assert_eq!(chdata.max_pdu_size(), 100);
+
+        // Limited by the space in the queue
+        let mut budget = |max_payload| {
+            Sender::new(&chdata, &mut tx, max_payload)
+                .unwrap()
+                .pdu_budget()
+        };
+        assert_eq!(budget(MAX_DATA_PAYLOAD_BUF as u8), 23);
+        // Limited by the Link-Layer payload length, but never below `RSP_PDU_SIZE`
+        assert_eq!(budget(20), 16);
+        assert_eq!(budget(12), 10);
+    }
+}
@@ -1,15 +1,25 @@
 //! L2CAP Signaling channel PDUs and functions (`0x0005`).
 
-use super::{Protocol, ProtocolObj, Sender};
-use crate::Error;
+use super::coc::{CocManager, ConnectionResult, Psm};
+use super::conn_params::{ConnParamUpdater, ConnectionParameters};
+use super::echo::EchoProbe;
+use super::{Channel, Header, Protocol, ProtocolObj, Sender};
+use crate::link::data::Llid;
+use crate::link::queue::Producer;
+use crate::{bytes::*, utils::HexSlice, Error};
+
+/// The signaling MTU used by this implementation (which is also the minimum allowed on LE).
+const SIGNALING_MTU: u8 = 23;
 
 enum_with_unknown! {
     /// LE Signaling Channel opcodes.
     #[derive(Debug, Copy, Clone, defmt::Format)]
-    enum Code(u8) {
+    pub(super) enum Code(u8) {
         CommandReject = 0x01,
         DisconnectionReq = 0x06,
         DisconnectionRsp = 0x07,
+        EchoReq = 0x08,
+        EchoRsp = 0x09,
         ConnectionParameterUpdateReq = 0x12,
         ConnectionParameterUpdateRsp = 0x13,
         CreditBasedConnectionReq = 0x14,
@@ -21,29 +31,424 @@ enum_with_unknown! {
 enum_with_unknown! {
     /// Reasons for a `CommandReject` response.
     #[derive(Debug, Copy, Clone, defmt::Format)]
-    enum RejectReason(u16) {
+    pub(super) enum RejectReason(u16) {
         CommandNotUnderstood = 0x0000,
         SignalingMtuExceeded = 0x0001,
         InvalidCid = 0x0002,
     }
 }
 
+enum_with_unknown! {
+    /// Results of a `ConnectionParameterUpdateRsp`.
+    #[derive(Debug, Copy, Clone, PartialEq, Eq, defmt::Format)]
+    pub(super) enum ConnParamUpdateResult(u16) {
+        Accepted = 0x0000,
+        Rejected = 0x0001,
+    }
+}
+
+/// A signaling command, sent in a C-frame on the LE Signaling Channel.
+///
+/// On LE, every C-frame contains exactly one command.
+#[derive(Debug, defmt::Format)]
+pub(super) struct Command<'a> {
+    /// Identifier used to match responses with requests.
+    ///
+    /// Responses must use the identifier of the request they respond to. Requests must use a
+    /// nonzero identifier that differs from the one used by the last request.
+    pub(super) identifier: u8,
+    pub(super) kind: CommandKind<'a>,
+}
+
+#[derive(Debug, defmt::Format)]
+pub(super) enum CommandKind<'a> {
+    CommandReject {
+        reason: RejectReason,
+        data: &'a [u8],
+    },
+    DisconnectionReq {
+        dcid: Channel,
+        scid: Channel,
+    },
+    DisconnectionRsp {
+        dcid: Channel,
+        scid: Channel,
+    },
+    EchoReq {
+        data: &'a [u8],
+    },
+    EchoRsp {
+        data: &'a [u8],
+    },
+    ConnectionParameterUpdateReq {
+        params: ConnectionParameters,
+    },
+    ConnectionParameterUpdateRsp {
+        result: ConnParamUpdateResult,
+    },
+    CreditBasedConnectionReq {
+        le_psm: Psm,
+        scid: Channel,
+        mtu: u16,
+        mps: u16,
+        initial_credits: u16,
+    },
+    CreditBasedConnectionRsp {
+        dcid: Channel,
+        mtu: u16,
+        mps: u16,
+        initial_credits: u16,
+        result: ConnectionResult,
+    },
+    FlowControlCredit {
+        cid: Channel,
+        credits: u16,
+    },
+    Unknown {
+        code: Code,
+        data: &'a [u8],
+    },
+}
+
+impl CommandKind<'_> {
+    fn code(&self) -> Code {
+        match self {
+            CommandKind::CommandReject { .. } => Code::CommandReject,
+            CommandKind::DisconnectionReq { .. } => Code::DisconnectionReq,
+            CommandKind::DisconnectionRsp { .. } => Code::DisconnectionRsp,
+            CommandKind::EchoReq { .. } => Code::EchoReq,
+            CommandKind::EchoRsp { .. } => Code::EchoRsp,
+            CommandKind::ConnectionParameterUpdateReq { .. } => Code::ConnectionParameterUpdateReq,
+            CommandKind::ConnectionParameterUpdateRsp { .. } => Code::ConnectionParameterUpdateRsp,
+            CommandKind::CreditBasedConnectionReq { .. } => Code::CreditBasedConnectionReq,
+            CommandKind::CreditBasedConnectionRsp { .. } => Code::CreditBasedConnectionRsp,
+            CommandKind::FlowControlCredit { .. } => Code::FlowControlCredit,
+            CommandKind::Unknown { code, .. } => *code,
+        }
+    }
+}
+
+impl<'a> FromBytes<'a> for Command<'a> {
+    fn from_bytes(bytes: &mut ByteReader<'a>) -> Result<Self, Error> {
+        let code = Code::from(bytes.read_u8()?);
+        let identifier = bytes.read_u8()?;
+        let length = bytes.read_u16_le()?;
+        let data = bytes.read_slice(usize::from(length))?;
+        let mut bytes = ByteReader::new(data);
+
+        let kind = match code {
+            Code::CommandReject => CommandKind::CommandReject {
+                reason: RejectReason::from(bytes.read_u16_le()?),
+                data: bytes.read_rest(),
+            },
+            Code::DisconnectionReq => CommandKind::DisconnectionReq {
+                dcid: Channel::from_bytes(&mut bytes)?,
+                scid: Channel::from_bytes(&mut bytes)?,
+            },
+            Code::DisconnectionRsp => CommandKind::DisconnectionRsp {
+                dcid: Channel::from_bytes(&mut bytes)?,
+                scid: Channel::from_bytes(&mut bytes)?,
+            },
+            Code::EchoReq => CommandKind::EchoReq {
+                data: bytes.read_rest(),
+            },
+            Code::EchoRsp => CommandKind::EchoRsp {
+                data: bytes.read_rest(),
+            },
+            Code::ConnectionParameterUpdateReq => CommandKind::ConnectionParameterUpdateReq {
+                params: ConnectionParameters::from_bytes(&mut bytes)?,
+            },
+            Code::ConnectionParameterUpdateRsp => CommandKind::ConnectionParameterUpdateRsp {
+                result: ConnParamUpdateResult::from(bytes.read_u16_le()?),
+            },
+            Code::CreditBasedConnectionReq => CommandKind::CreditBasedConnectionReq {
+                le_psm: Psm(bytes.read_u16_le()?),
+                scid: Channel::from_bytes(&mut bytes)?,
+                mtu: bytes.read_u16_le()?,
+                mps: bytes.read_u16_le()?,
+                initial_credits: bytes.read_u16_le()?,
+            },
+            Code::CreditBasedConnectionRsp => CommandKind::CreditBasedConnectionRsp {
+                dcid: Channel::from_bytes(&mut bytes)?,
+                mtu: bytes.read_u16_le()?,
+                mps: bytes.read_u16_le()?,
+                initial_credits: bytes.read_u16_le()?,
+                result: ConnectionResult::from(bytes.read_u16_le()?),
+            },
+            Code::FlowControlCredit => CommandKind::FlowControlCredit {
+                cid: Channel::from_bytes(&mut bytes)?,
+                credits: bytes.read_u16_le()?,
+            },
+            _ => CommandKind::Unknown {
+                code,
+                data: bytes.read_rest(),
+            },
+        };
+
+        if !bytes.is_empty() {
+            return Err(Error::IncompleteParse);
+        }
+
+        Ok(Self { identifier, kind })
+    }
+}
+
+impl ToBytes for Command<'_> {
+    fn to_bytes(&self, writer: &mut ByteWriter<'_>) -> Result<(), Error> {
+        writer.write_u8(self.kind.code().into())?;
+        writer.write_u8(self.identifier)?;
+
+        // The length is written once the data is encoded
+        let mut length_writer = writer.split_off(2)?;
+        let left = writer.space_left();
+        match &self.kind {
+            CommandKind::CommandReject { reason, data } => {
+                writer.write_u16_le((*reason).into())?;
+                writer.write_slice(data)?;
+            }
+            CommandKind::DisconnectionReq { dcid, scid }
+            | CommandKind::DisconnectionRsp { dcid, scid } => {
+                dcid.to_bytes(writer)?;
+                scid.to_bytes(writer)?;
+            }
+            CommandKind::ConnectionParameterUpdateReq { params } => {
+                params.to_bytes(writer)?;
+            }
+            CommandKind::ConnectionParameterUpdateRsp { result } => {
+                writer.write_u16_le((*result).into())?;
+            }
+            CommandKind::CreditBasedConnectionReq {
+                le_psm,
+                scid,
+                mtu,
+                mps,
+                initial_credits,
+            } => {
+                writer.write_u16_le(le_psm.0)?;
+                scid.to_bytes(writer)?;
+                writer.write_u16_le(*mtu)?;
+                writer.write_u16_le(*mps)?;
+                writer.write_u16_le(*initial_credits)?;
+            }
+            CommandKind::CreditBasedConnectionRsp {
+                dcid,
+                mtu,
+                mps,
+                initial_credits,
+                result,
+            } => {
+                dcid.to_bytes(writer)?;
+                writer.write_u16_le(*mtu)?;
+                writer.write_u16_le(*mps)?;
+                writer.write_u16_le(*initial_credits)?;
+                writer.write_u16_le((*result).into())?;
+            }
+            CommandKind::FlowControlCredit { cid, credits } => {
+                cid.to_bytes(writer)?;
+                writer.write_u16_le(*credits)?;
+            }
+            CommandKind::EchoReq { data }
+            | CommandKind::EchoRsp { data }
+            | CommandKind::Unknown { data, .. } => {
+                writer.write_slice(data)?;
+            }
+        }
+
+        let length = left - writer.space_left();
+        length_writer.write_u16_le(length as u16)?;
+        Ok(())
+    }
+}
+
+/// Enqueues a signaling command originating from this device.
+///
+/// This is used when a command has to be sent outside of the processing of an incoming signaling
+/// command, for example when returning credits to the peer.
+pub(super) fn send_command(tx: &mut dyn Producer, command: Command<'_>) -> Result<(), Error> {
+    let mut buf = [0; SIGNALING_MTU as usize];
+    let mut writer = ByteWriter::new(&mut buf);
+    command.to_bytes(&mut writer)?;
+    let left = writer.space_left();
+    let used = buf.len() - left;
+
+    tx.produce_dyn(Header::SIZE + used as u8, &mut |writer| {
+        Header {
+            length: used as u16,
+            channel: Channel::LE_SIGNALING,
+        }
+        .to_bytes(writer)?;
+        writer.write_slice(&buf[..used])?;
+        Ok(Llid::DataStart)
+    })
+}
+
 /// The `Protocol` implementor listening on the LE Signaling Channel `0x0005`.
-pub struct SignalingState {}
+///
+/// This also owns the [`CocManager`] handling LE credit-based connection-oriented channels, since
+/// these channels are established and controlled via signaling commands.
+///
+/// It also owns the optional [`ConnParamUpdater`] and [`EchoProbe`], which need to see the
+/// peer's responses.
+///
+/// [`CocManager`]: ../trait.CocManager.html
+/// [`ConnParamUpdater`]: ../struct.ConnParamUpdater.html
+/// [`EchoProbe`]: ../struct.EchoProbe.html
+pub struct SignalingState<C: CocManager> {
+    coc: C,
+    conn_params: Option<ConnParamUpdater>,
+    echo: Option<EchoProbe>,
+}
+
+impl<C: CocManager> SignalingState<C> {
+    pub fn new(coc: C) -> Self {
+        Self {
+            coc,
+            conn_params: None,
+            echo: None,
+        }
+    }
+
+    /// Replaces the credit-based channel manager, keeping the rest of the state.
+    pub fn with_coc<D: CocManager>(self, coc: D) -> SignalingState<D> {
+        SignalingState {
+            coc,
+            conn_params: self.conn_params,
+            echo: self.echo,
+        }
+    }
 
-impl SignalingState {
-    pub fn new() -> Self {
-        Self {}
+    /// Returns a reference to the credit-based channel manager.
+    pub fn coc(&mut self) -> &mut C {
+        &mut self.coc
+    }
+
+    /// Sets the updater requesting the peripheral's preferred connection parameters.
+    pub fn set_conn_params(&mut self, updater: ConnParamUpdater) {
+        self.conn_params = Some(updater);
+    }
+
+    /// Returns a reference to the connection parameter updater, if there is one.
+    pub fn conn_params(&mut self) -> Option<&mut ConnParamUpdater> {
+        self.conn_params.as_mut()
+    }
+
+    /// Sets the probe answering and sending *Echo Requests*.
+    pub fn set_echo(&mut self, probe: EchoProbe) {
+        self.echo = Some(probe);
+    }
+
+    /// Returns a reference to the echo probe, if there is one.
+    pub fn echo(&mut self) -> Option<&mut EchoProbe> {
+        self.echo.as_mut()
     }
 }
 
-impl ProtocolObj for SignalingState {
-    fn process_message(&mut self, message: &[u8], responder: Sender<'_>) -> Result<(), Error> {
-        let _ = (message, responder);
-        unimplemented!();
+impl<C: CocManager> ProtocolObj for SignalingState<C> {
+    fn process_message(&mut self, message: &[u8], mut responder: Sender<'_>) -> Result<(), Error> {
+        let cmd = Command::from_bytes(&mut ByteReader::new(message))?;
+        trace!("signaling cmd {:?}, {:?}", cmd, HexSlice(message));
+
+        let identifier = cmd.identifier;
+        let response = match cmd.kind {
+            CommandKind::CreditBasedConnectionReq {
+                le_psm,
+                scid,
+                mtu,
+                mps,
+                initial_credits,
+            } => {
+                let kind = match self.coc.connect(le_psm, scid, mtu, mps, initial_credits) {
+                    Ok(local) => CommandKind::CreditBasedConnectionRsp {
+                        dcid: local.channel,
+                        mtu: local.mtu,
+                        mps: local.mps,
+                        initial_credits: local.initial_credits,
+                        result: ConnectionResult::Success,
+                    },
+                    Err(result) => CommandKind::CreditBasedConnectionRsp {
+                        dcid: Channel::NULL,
+                        mtu: 0,
+                        mps: 0,
+                        initial_credits: 0,
+                        result,
+                    },
+                };
+                Some(kind)
+            }
+            CommandKind::FlowControlCredit { cid, credits } => {
+                self.coc.add_credits(cid, credits);
+                None
+            }
+            CommandKind::DisconnectionReq { dcid, scid } => {
+                if self.coc.disconnect(dcid, scid) {
+                    Some(CommandKind::DisconnectionRsp { dcid, scid })
+                } else {
+                    Some(CommandKind::CommandReject {
+                        reason: RejectReason::InvalidCid,
+                        data: &message[4..8],
+                    })
+                }
+            }
+            CommandKind::ConnectionParameterUpdateReq { .. } => {
+                // Only the central may accept these requests
+                Some(CommandKind::CommandReject {
+                    reason: RejectReason::CommandNotUnderstood,
+                    data: &[],
+                })
+            }
+            CommandKind::ConnectionParameterUpdateRsp { result } => {
+                if let Some(updater) = &mut self.conn_params {
+                    updater.process_response(identifier, result == ConnParamUpdateResult::Accepted);
+                }
+                None
+            }
+            CommandKind::CommandReject { reason, .. } => {
+                debug!("signaling command {} rejected: {:?}", identifier, reason);
+                if let Some(updater) = &mut self.conn_params {
+                    updater.process_reject(identifier);
+                }
+                if let Some(probe) = &mut self.echo {
+                    probe.process_reject(identifier);
+                }
+                None
+            }
+            CommandKind::EchoReq { data } => match self.echo {
+                Some(_) => Some(CommandKind::EchoRsp { data }),
+                None => Some(CommandKind::CommandReject {
+                    reason: RejectReason::CommandNotUnderstood,
+                    data: &[],
+                }),
+            },
+            CommandKind::EchoRsp { .. } => {
+                if let Some(probe) = &mut self.echo {
+                    probe.process_response(identifier);
+                }
+                None
+            }
+            CommandKind::DisconnectionRsp { .. } | CommandKind::CreditBasedConnectionRsp { .. } => {
+                // We don't send any requests that would result in these
+                None
+            }
+            CommandKind::Unknown { code, data } => {
+                warn!(
+                    "[NYI] signaling command {:?}, {:?}; rejecting",
+                    code,
+                    HexSlice(data)
+                );
+                Some(CommandKind::CommandReject {
+                    reason: RejectReason::CommandNotUnderstood,
+                    data: &[],
+                })
+            }
+        };
+
+        if let Some(kind) = response {
+            responder.send(Command { identifier, kind })?;
+        }
+        Ok(())
     }
 }
 
-impl Protocol for SignalingState {
-    const RSP_PDU_SIZE: u8 = 23;
+impl<C: CocManager> Protocol for SignalingState<C> {
+    const RSP_PDU_SIZE: u8 = SIGNALING_MTU;
 }
@@ -15,8 +15,8 @@
 //! * A processor for [`link::Cmd`], which tells the support code when to call Rubble's functions
 //!   again.
 
-// We're `#[no_std]`, except when we're testing
-#![cfg_attr(not(test), no_std)]
+// We're `#[no_std]`, except when we're testing or the `std` feature is enabled
+#![cfg_attr(not(any(test, feature = "std")), no_std)]
 // Deny a few warnings in doctests, since rustdoc `allow`s many warnings by default
 #![doc(test(attr(deny(unused_imports, unused_must_use))))]
 #![warn(rust_2018_idioms)]
@@ -24,20 +24,31 @@
 #![allow(clippy::trivially_copy_pass_by_ref)]
 
 #[macro_use]
-mod log;
+pub mod log;
 #[macro_use]
 mod utils;
 pub mod att;
 pub mod beacon;
 pub mod bytes;
 pub mod config;
+#[cfg(test)]
+mod conformance;
 pub mod ecdh;
 mod error;
+#[doc(hidden)]
+pub mod fuzz;
+pub mod gap;
 pub mod gatt;
+pub mod hci;
 pub mod l2cap;
 pub mod link;
+#[cfg(feature = "metrics")]
+pub mod metrics;
 pub mod phy;
+pub mod reconnect;
 pub mod security;
+#[cfg(test)]
+mod test_rng;
 pub mod time;
 pub mod uuid;
 
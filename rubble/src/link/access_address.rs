@@ -0,0 +1,161 @@
+use crate::link::advertising;
+use crate::Error;
+use core::convert::TryFrom;
+use core::fmt;
+use rand_core::{CryptoRng, RngCore};
+
+/// A data channel Access Address.
+///
+/// Every connection uses its own Access Address, which is randomly generated by the initiator and
+/// sent to the advertiser in the `CONNECT_IND` PDU. To ensure that the radio can reliably find
+/// the address in its input, the specification (Vol 6, Part B, 2.1.2) places some restrictions on
+/// it, which are enforced by the constructors of this type:
+///
+/// * It must not be the advertising channel Access Address, and must differ from it in more than
+///   one bit.
+/// * Its four octets must not all be equal.
+/// * It must not contain more than six consecutive zeros or ones.
+/// * It must not contain more than 24 transitions between zeros and ones.
+/// * Its six most significant bits must contain at least two transitions.
+///
+/// The additional requirements for the LE Coded PHY are not checked, since Rubble only supports
+/// the LE 1M PHY.
+#[derive(Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(try_from = "u32", into = "u32")
+)]
+pub struct AccessAddress(u32);
+
+impl AccessAddress {
+    /// Creates an `AccessAddress` from its raw 32-bit value.
+    ///
+    /// Returns `Error::InvalidValue` if `raw` violates any of the requirements of the
+    /// specification.
+    pub fn new(raw: u32) -> Result<Self, Error> {
+        if is_valid(raw) {
+            Ok(AccessAddress(raw))
+        } else {
+            Err(Error::InvalidValue)
+        }
+    }
+
+    /// Generates a new random Access Address.
+    ///
+    /// The specification recommends using a new address for every connection.
+    pub fn generate<R: RngCore + CryptoRng>(rng: &mut R) -> Self {
+        loop {
+            if let Ok(address) = Self::new(rng.next_u32()) {
+                return address;
+            }
+        }
+    }
+
+    /// Returns the raw 32-bit value of this Access Address.
+    pub fn raw(&self) -> u32 {
+        self.0
+    }
+}
+
+/// Checks `raw` against the requirements for data channel Access Addresses.
+fn is_valid(raw: u32) -> bool {
+    // Must differ from the advertising Access Address in at least 2 bits
+    if (raw ^ advertising::ACCESS_ADDRESS).count_ones() <= 1 {
+        return false;
+    }
+
+    let [b0, b1, b2, b3] = raw.to_le_bytes();
+    if b0 == b1 && b1 == b2 && b2 == b3 {
+        return false;
+    }
+
+    let mut run = 1;
+    for i in 1..32 {
+        if (raw >> i) & 1 == (raw >> (i - 1)) & 1 {
+            run += 1;
+            if run > 6 {
+                return false;
+            }
+        } else {
+            run = 1;
+        }
+    }
+
+    // Bit `i` is set if bits `i` and `i + 1` of `raw` differ
+    let transitions = (raw ^ (raw >> 1)) & 0x7FFF_FFFF;
+    transitions.count_ones() <= 24 && (transitions >> 26).count_ones() >= 2
+}
+
+impl TryFrom<u32> for AccessAddress {
+    type Error = Error;
+
+    fn try_from(raw: u32) -> Result<Self, Error> {
+        Self::new(raw)
+    }
+}
+
+impl From<AccessAddress> for u32 {
+    fn from(address: AccessAddress) -> Self {
+        address.0
+    }
+}
+
+impl fmt::Debug for AccessAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "AccessAddress({:#010x})", self.0)
+    }
+}
+
+impl defmt::Format for AccessAddress {
+    fn format(&self, fmt: defmt::Formatter<'_>) {
+        defmt::write!(fmt, "AccessAddress({=u32:#010x})", self.0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_rng::TestRng;
+
+    #[test]
+    fn validity() {
+        assert!(AccessAddress::new(0x50C6_5A17).is_ok());
+        assert!(AccessAddress::new(0x7176_4129).is_ok());
+
+        let invalid = [
+            advertising::ACCESS_ADDRESS,
+            advertising::ACCESS_ADDRESS ^ 1,
+            0x1212_1212, // all octets equal
+            0x1234_1280, // 7 consecutive zeros
+            0x5A5A_A5A5, // too many transitions
+            0x0C89_BED6, // only one transition in the 6 MSbs
+        ];
+        for &raw in &invalid {
+            assert_eq!(
+                AccessAddress::new(raw),
+                Err(Error::InvalidValue),
+                "{:#x}",
+                raw
+            );
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "serde")]
+    fn deserialize() {
+        let address: AccessAddress = serde_json::from_str("1355176471").unwrap();
+        assert_eq!(address.raw(), 0x50C6_5A17);
+        assert!(serde_json::from_str::<AccessAddress>("2391391958").is_err());
+    }
+
+    #[test]
+    fn generate() {
+        // Step through all `u32` values in a scrambled order
+        let mut rng = TestRng::new(0, 0x9E37_79B9);
+        for _ in 0..100 {
+            let address = AccessAddress::generate(&mut rng);
+            assert!(is_valid(address.raw()));
+        }
+    }
+}
@@ -6,10 +6,12 @@
 //!
 //! [gap]: https://www.bluetooth.com/specifications/assigned-numbers/generic-access-profile
 
-use crate::link::CompanyId;
+use crate::link::{AddressKind, CompanyId, DeviceAddress};
+use crate::time::Duration;
 use crate::uuid::{IsUuid, Uuid128, Uuid16, Uuid32, UuidKind};
 use crate::{bytes::*, Error};
 use bitflags::bitflags;
+use core::{convert::TryFrom, fmt};
 
 /// A list of AD structures can be sent along with an advertising packet or scan response.
 ///
@@ -22,7 +24,8 @@ use bitflags::bitflags;
 /// From a very unrepresentative scan, most devices seem to include Flags and Manufacturer Data, and
 /// optionally a device name, of course.
 #[non_exhaustive]
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, defmt::Format)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 pub enum AdStructure<'a> {
     /// Device flags and baseband capabilities.
     ///
@@ -39,7 +42,23 @@ pub enum AdStructure<'a> {
     /// Service data with 16-bit service UUID.
     ServiceData16 {
         /// The 16-bit service UUID.
-        uuid: u16,
+        uuid: Uuid16,
+        /// The associated service data. May be empty.
+        data: &'a [u8],
+    },
+
+    /// Service data with 32-bit service UUID.
+    ServiceData32 {
+        /// The 32-bit service UUID.
+        uuid: Uuid32,
+        /// The associated service data. May be empty.
+        data: &'a [u8],
+    },
+
+    /// Service data with 128-bit service UUID.
+    ServiceData128 {
+        /// The 128-bit service UUID.
+        uuid: Uuid128,
         /// The associated service data. May be empty.
         data: &'a [u8],
     },
@@ -52,6 +71,31 @@ pub enum AdStructure<'a> {
     /// Sets the shortened device name.
     ShortenedLocalName(&'a str),
 
+    /// Transmitted power level of the packet containing this structure, in dBm.
+    ///
+    /// This can be used by scanners to estimate the path loss.
+    TxPowerLevel(i8),
+
+    /// External appearance of the device.
+    ///
+    /// The raw value is a GAP appearance category as listed in the assigned numbers document (see
+    /// also [`gatt::characteristic::Appearance`]).
+    ///
+    /// [`gatt::characteristic::Appearance`]: ../../gatt/characteristic/enum.Appearance.html
+    Appearance(u16),
+
+    /// The advertising interval used by the device.
+    ///
+    /// This is encoded in units of 0.625 ms, so the interval has to be a multiple of 625 µs and
+    /// must not exceed 40.96 seconds.
+    AdvertisingInterval(Duration),
+
+    /// The LE roles supported by the device and which one is preferred.
+    LeRole(LeRole),
+
+    /// List of devices this advertisement is intended for.
+    TargetAddresses(TargetAddresses<'a>),
+
     /// Set manufacturer specific data
     ManufacturerSpecificData {
         company_identifier: CompanyId,
@@ -67,6 +111,30 @@ pub enum AdStructure<'a> {
     },
 }
 
+impl<'a> AdStructure<'a> {
+    /// Returns the AD type byte that identifies this structure on the air.
+    pub fn ad_type(&self) -> u8 {
+        match self {
+            AdStructure::Flags(_) => Type::FLAGS,
+            AdStructure::ServiceUuids16(uuids) => uuids.type_(),
+            AdStructure::ServiceUuids32(uuids) => uuids.type_(),
+            AdStructure::ServiceUuids128(uuids) => uuids.type_(),
+            AdStructure::ServiceData16 { .. } => Type::SERVICE_DATA_16BIT_UUID,
+            AdStructure::ServiceData32 { .. } => Type::SERVICE_DATA_32BIT_UUID,
+            AdStructure::ServiceData128 { .. } => Type::SERVICE_DATA_128BIT_UUID,
+            AdStructure::CompleteLocalName(_) => Type::COMPLETE_LOCAL_NAME,
+            AdStructure::ShortenedLocalName(_) => Type::SHORTENED_LOCAL_NAME,
+            AdStructure::TxPowerLevel(_) => Type::TX_POWER_LEVEL,
+            AdStructure::Appearance(_) => Type::APPEARANCE,
+            AdStructure::AdvertisingInterval(_) => Type::ADVERTISING_INTERVAL,
+            AdStructure::LeRole(_) => Type::LE_ROLE,
+            AdStructure::TargetAddresses(addrs) => addrs.type_(),
+            AdStructure::ManufacturerSpecificData { .. } => Type::MANUFACTURER_SPECIFIC_DATA,
+            AdStructure::Unknown { ty, .. } => *ty,
+        }
+    }
+}
+
 impl<'a> ToBytes for AdStructure<'a> {
     /// Lowers this AD structure into a Byte buffer.
     ///
@@ -90,8 +158,17 @@ impl<'a> ToBytes for AdStructure<'a> {
             AdStructure::ServiceUuids128(uuids) => uuids.to_bytes(buf)?,
             AdStructure::ServiceData16 { uuid, data } => {
                 buf.write_u8(Type::SERVICE_DATA_16BIT_UUID)?;
-                buf.write_u8(*uuid as u8)?;
-                buf.write_u8((*uuid >> 8) as u8)?;
+                uuid.to_bytes(buf)?;
+                buf.write_slice(data)?;
+            }
+            AdStructure::ServiceData32 { uuid, data } => {
+                buf.write_u8(Type::SERVICE_DATA_32BIT_UUID)?;
+                uuid.to_bytes(buf)?;
+                buf.write_slice(data)?;
+            }
+            AdStructure::ServiceData128 { uuid, data } => {
+                buf.write_u8(Type::SERVICE_DATA_128BIT_UUID)?;
+                uuid.to_bytes(buf)?;
                 buf.write_slice(data)?;
             }
             AdStructure::CompleteLocalName(name) => {
@@ -102,6 +179,29 @@ impl<'a> ToBytes for AdStructure<'a> {
                 buf.write_u8(Type::SHORTENED_LOCAL_NAME)?;
                 buf.write_slice(name.as_bytes())?;
             }
+            AdStructure::TxPowerLevel(dbm) => {
+                buf.write_u8(Type::TX_POWER_LEVEL)?;
+                buf.write_u8(*dbm as u8)?;
+            }
+            AdStructure::Appearance(appearance) => {
+                buf.write_u8(Type::APPEARANCE)?;
+                buf.write_u16_le(*appearance)?;
+            }
+            AdStructure::AdvertisingInterval(interval) => {
+                // Unit: 0.625 ms
+                if interval.as_micros() % 625 != 0 {
+                    return Err(Error::InvalidValue);
+                }
+                let units =
+                    u16::try_from(interval.as_micros() / 625).map_err(|_| Error::InvalidValue)?;
+                buf.write_u8(Type::ADVERTISING_INTERVAL)?;
+                buf.write_u16_le(units)?;
+            }
+            AdStructure::LeRole(role) => {
+                buf.write_u8(Type::LE_ROLE)?;
+                buf.write_u8(role.as_raw())?;
+            }
+            AdStructure::TargetAddresses(addrs) => addrs.to_bytes(buf)?,
             AdStructure::ManufacturerSpecificData {
                 company_identifier,
                 payload,
@@ -151,18 +251,377 @@ impl<'a> FromBytes<'a> for AdStructure<'a> {
                 let uuids = ServiceUuids::<Uuid16>::from_bytes(&mut ByteReader::new(ty_and_data))?;
                 AdStructure::ServiceUuids16(uuids)
             }
+            Type::COMPLETE_LIST_OF_32BIT_SERVICE_UUIDS
+            | Type::INCOMPLETE_LIST_OF_32BIT_SERVICE_UUIDS => {
+                let uuids = ServiceUuids::<Uuid32>::from_bytes(&mut ByteReader::new(ty_and_data))?;
+                AdStructure::ServiceUuids32(uuids)
+            }
+            Type::COMPLETE_LIST_OF_128BIT_SERVICE_UUIDS
+            | Type::INCOMPLETE_LIST_OF_128BIT_SERVICE_UUIDS => {
+                let uuids = ServiceUuids::<Uuid128>::from_bytes(&mut ByteReader::new(ty_and_data))?;
+                AdStructure::ServiceUuids128(uuids)
+            }
+            Type::COMPLETE_LOCAL_NAME | Type::SHORTENED_LOCAL_NAME => {
+                match core::str::from_utf8(data) {
+                    Ok(name) if ty == Type::COMPLETE_LOCAL_NAME => {
+                        AdStructure::CompleteLocalName(name)
+                    }
+                    Ok(name) => AdStructure::ShortenedLocalName(name),
+                    // Keep invalid names around as raw data instead of rejecting the whole packet
+                    Err(_) => AdStructure::Unknown { ty, data },
+                }
+            }
+            Type::SERVICE_DATA_16BIT_UUID => {
+                let mut bytes = ByteReader::new(data);
+                AdStructure::ServiceData16 {
+                    uuid: Uuid16::from_bytes(&mut bytes)?,
+                    data: bytes.read_rest(),
+                }
+            }
+            Type::SERVICE_DATA_32BIT_UUID => {
+                let mut bytes = ByteReader::new(data);
+                AdStructure::ServiceData32 {
+                    uuid: Uuid32::from_bytes(&mut bytes)?,
+                    data: bytes.read_rest(),
+                }
+            }
+            Type::SERVICE_DATA_128BIT_UUID => {
+                let mut bytes = ByteReader::new(data);
+                AdStructure::ServiceData128 {
+                    uuid: Uuid128::from_le_bytes(bytes.read_array()?),
+                    data: bytes.read_rest(),
+                }
+            }
+            Type::TX_POWER_LEVEL => {
+                if data.len() != 1 {
+                    return Err(Error::InvalidLength);
+                }
+
+                AdStructure::TxPowerLevel(data[0] as i8)
+            }
+            Type::APPEARANCE => {
+                if data.len() != 2 {
+                    return Err(Error::InvalidLength);
+                }
+
+                AdStructure::Appearance(u16::from_le_bytes([data[0], data[1]]))
+            }
+            Type::ADVERTISING_INTERVAL => {
+                if data.len() != 2 {
+                    return Err(Error::InvalidLength);
+                }
+
+                let units = u16::from_le_bytes([data[0], data[1]]);
+                AdStructure::AdvertisingInterval(Duration::from_micros(u32::from(units) * 625))
+            }
+            Type::LE_ROLE => {
+                if data.len() != 1 {
+                    return Err(Error::InvalidLength);
+                }
+
+                AdStructure::LeRole(LeRole::from(data[0]))
+            }
+            Type::PUBLIC_TARGET_ADDRESS | Type::RANDOM_TARGET_ADDRESS => {
+                let addrs = TargetAddresses::from_bytes(&mut ByteReader::new(ty_and_data))?;
+                AdStructure::TargetAddresses(addrs)
+            }
+            Type::MANUFACTURER_SPECIFIC_DATA => {
+                let mut bytes = ByteReader::new(data);
+                AdStructure::ManufacturerSpecificData {
+                    company_identifier: CompanyId::from_raw(bytes.read_u16_le()?),
+                    payload: bytes.read_rest(),
+                }
+            }
             _ => AdStructure::Unknown { ty, data },
         })
     }
 }
 
+/// Maximum length of the advertising data in legacy advertising PDUs and scan responses.
+pub const LEGACY_ADV_DATA_LEN: usize = 31;
+
+/// Upper bound on the length of the advertising data that fits into a single extended advertising
+/// PDU.
+///
+/// Extended advertising PDUs have a payload of at most 255 Bytes, which always includes the 1-Byte
+/// extended header length and advertising mode field. Any other extended header fields that are
+/// present reduce the space left for advertising data further.
+pub const MAX_ADV_DATA_LEN: usize = 254;
+
+/// Builder for a list of AD structures that keeps track of the available space.
+///
+/// Advertising data is limited in size: Legacy advertising PDUs and scan responses can carry at
+/// most [`LEGACY_ADV_DATA_LEN`] Bytes of AD structures. `AdvertisingData` encodes AD structures as
+/// they are pushed, and reports which structure exceeded the budget if the data doesn't fit.
+///
+/// The encoded data can be turned into an advertising PDU using [`PduBuf::from_adv_data`].
+///
+/// [`LEGACY_ADV_DATA_LEN`]: constant.LEGACY_ADV_DATA_LEN.html
+/// [`PduBuf::from_adv_data`]: ../advertising/struct.PduBuf.html#method.from_adv_data
+#[derive(Copy, Clone)]
+pub struct AdvertisingData {
+    buf: [u8; MAX_ADV_DATA_LEN],
+    used: usize,
+    budget: usize,
+    count: usize,
+}
+
+impl AdvertisingData {
+    /// Creates an empty `AdvertisingData` builder for use with legacy advertising PDUs.
+    ///
+    /// The budget is set to [`LEGACY_ADV_DATA_LEN`] Bytes.
+    ///
+    /// [`LEGACY_ADV_DATA_LEN`]: constant.LEGACY_ADV_DATA_LEN.html
+    pub fn new() -> Self {
+        Self::with_budget(LEGACY_ADV_DATA_LEN)
+    }
+
+    /// Creates an empty `AdvertisingData` builder that can hold up to `budget` Bytes.
+    ///
+    /// This can be used to build data for extended advertising PDUs.
+    ///
+    /// # Panics
+    ///
+    /// This will panic if `budget` exceeds [`MAX_ADV_DATA_LEN`].
+    ///
+    /// [`MAX_ADV_DATA_LEN`]: constant.MAX_ADV_DATA_LEN.html
+    pub fn with_budget(budget: usize) -> Self {
+        assert!(budget <= MAX_ADV_DATA_LEN);
+        Self {
+            buf: [0; MAX_ADV_DATA_LEN],
+            used: 0,
+            budget,
+            count: 0,
+        }
+    }
+
+    /// Appends an AD structure to the advertising data.
+    ///
+    /// If `ad` doesn't fit into the remaining budget, or can't be encoded, an error is returned
+    /// and `self` is left unchanged. This includes `AdStructure::Unknown` structures that use the
+    /// type of a known structure, but whose data isn't valid for it.
+    pub fn push(&mut self, ad: AdStructure<'_>) -> Result<&mut Self, AdvertisingDataError> {
+        let index = self.count;
+        let ad_type = ad.ad_type();
+        let mut writer = ByteWriter::new(&mut self.buf[self.used..self.budget]);
+        let available = writer.space_left();
+        match ad.to_bytes(&mut writer) {
+            Ok(()) => {
+                let written = available - writer.space_left();
+
+                // Make sure the structure can be decoded again, so that `iter` never fails.
+                let mut reader = ByteReader::new(&self.buf[self.used..self.used + written]);
+                let valid = AdStructure::from_bytes(&mut reader).is_ok() && reader.is_empty();
+                if !valid {
+                    return Err(AdvertisingDataError::Encoding {
+                        index,
+                        ad_type,
+                        error: Error::InvalidValue,
+                    });
+                }
+
+                self.used += written;
+                self.count += 1;
+                Ok(self)
+            }
+            Err(Error::Eof) => {
+                // Find out how much space the structure would have needed.
+                let mut scratch = [0; 1 + 255];
+                let capacity = scratch.len();
+                let mut writer = ByteWriter::new(&mut scratch);
+                let required = match ad.to_bytes(&mut writer) {
+                    Ok(()) => capacity - writer.space_left(),
+                    Err(_) => capacity,
+                };
+                Err(AdvertisingDataError::DoesNotFit {
+                    index,
+                    ad_type,
+                    required,
+                    available,
+                })
+            }
+            Err(error) => Err(AdvertisingDataError::Encoding {
+                index,
+                ad_type,
+                error,
+            }),
+        }
+    }
+
+    /// Appends all AD structures in `ads`, stopping at the first one that can not be added.
+    pub fn extend<'a>(
+        &mut self,
+        ads: impl IntoIterator<Item = AdStructure<'a>>,
+    ) -> Result<&mut Self, AdvertisingDataError> {
+        for ad in ads {
+            self.push(ad)?;
+        }
+        Ok(self)
+    }
+
+    /// Returns the number of AD structures added so far.
+    pub fn count(&self) -> usize {
+        self.count
+    }
+
+    /// Returns the number of Bytes still available for more AD structures.
+    pub fn space_left(&self) -> usize {
+        self.budget - self.used
+    }
+
+    /// Returns the encoded advertising data.
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.buf[..self.used]
+    }
+
+    /// Returns an iterator over the AD structures stored in `self`.
+    pub fn iter(&self) -> impl Iterator<Item = AdStructure<'_>> + '_ {
+        let mut bytes = ByteReader::new(self.as_bytes());
+        core::iter::from_fn(move || {
+            if bytes.is_empty() {
+                None
+            } else {
+                // `push` only accepts structures that decode successfully, so this can not fail.
+                Some(AdStructure::from_bytes(&mut bytes).unwrap())
+            }
+        })
+    }
+}
+
+impl Default for AdvertisingData {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl fmt::Debug for AdvertisingData {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_list().entries(self.iter()).finish()
+    }
+}
+
+/// The encoded structures are logged as raw bytes, since `defmt` needs to know the number of
+/// elements up front.
+impl defmt::Format for AdvertisingData {
+    fn format(&self, fmt: defmt::Formatter<'_>) {
+        defmt::write!(fmt, "AdvertisingData({=[u8]:x})", self.as_bytes());
+    }
+}
+
+impl ToBytes for AdvertisingData {
+    fn to_bytes(&self, buffer: &mut ByteWriter<'_>) -> Result<(), Error> {
+        buffer.write_slice(self.as_bytes())
+    }
+}
+
+/// Parses a list of AD structures, as contained in advertising and scan response PDUs.
+///
+/// The returned iterator yields one item per AD structure in `bytes`. If a structure is malformed,
+/// the error is yielded and iteration ends, since the following structures can't be located
+/// anymore. Structures of unknown type are returned as `AdStructure::Unknown`. A length Byte of 0
+/// marks the end of the significant part of the data, so any Bytes after it are ignored.
+///
+/// Unlike `AdvertisingData::iter`, this can be used on untrusted data, such as captured packets.
+pub fn parse_ad_structures(bytes: &[u8]) -> impl Iterator<Item = Result<AdStructure<'_>, Error>> {
+    let mut bytes = ByteReader::new(bytes);
+    let mut failed = false;
+    core::iter::from_fn(move || {
+        if failed || bytes.as_raw_bytes().first().map_or(true, |&len| len == 0) {
+            return None;
+        }
+        let result = AdStructure::from_bytes(&mut bytes);
+        failed = result.is_err();
+        Some(result)
+    })
+}
+
+/// Returns the significant part of advertising or scan response data, without padding.
+///
+/// The significant part ends before the first length Byte of 0. Structures are not validated, so
+/// the result may still be malformed.
+pub(crate) fn significant_part(bytes: &[u8]) -> &[u8] {
+    let mut pos = 0;
+    while let Some(&len) = bytes.get(pos) {
+        if len == 0 {
+            return &bytes[..pos];
+        }
+        pos += 1 + usize::from(len);
+    }
+    bytes
+}
+
+/// Error returned when an AD structure can not be added to an [`AdvertisingData`] list.
+///
+/// [`AdvertisingData`]: struct.AdvertisingData.html
+#[derive(Debug, PartialEq, Eq, defmt::Format)]
+pub enum AdvertisingDataError {
+    /// The AD structure does not fit into the remaining space.
+    DoesNotFit {
+        /// Index of the offending AD structure (the number of structures added before it).
+        index: usize,
+        /// AD type of the offending structure.
+        ad_type: u8,
+        /// Number of Bytes needed to encode the structure, including the length and type Bytes.
+        required: usize,
+        /// Number of Bytes that were left in the budget.
+        available: usize,
+    },
+
+    /// The AD structure contains a value that can not be encoded.
+    Encoding {
+        /// Index of the offending AD structure (the number of structures added before it).
+        index: usize,
+        /// AD type of the offending structure.
+        ad_type: u8,
+        /// The underlying encoding error.
+        error: Error,
+    },
+}
+
+impl fmt::Display for AdvertisingDataError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AdvertisingDataError::DoesNotFit {
+                index,
+                ad_type,
+                required,
+                available,
+            } => write!(
+                f,
+                "AD structure #{} (type 0x{:02X}) needs {} Bytes, but only {} are left",
+                index, ad_type, required, available
+            ),
+            AdvertisingDataError::Encoding {
+                index,
+                ad_type,
+                error,
+            } => write!(
+                f,
+                "AD structure #{} (type 0x{:02X}) could not be encoded: {}",
+                index, ad_type, error
+            ),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for AdvertisingDataError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            AdvertisingDataError::DoesNotFit { .. } => None,
+            AdvertisingDataError::Encoding { error, .. } => Some(error),
+        }
+    }
+}
+
 /// List of service UUIDs offered by the device.
 ///
 /// The list can be marked as complete or incomplete. For an incomplete list,
 /// more UUIDs can be sent in the scan response.
 ///
 /// The `ServiceUuids` type can handle 16-, 32-, and full-size 128-bit UUIDs.
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, defmt::Format)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 pub struct ServiceUuids<'a, T: IsUuid> {
     complete: bool,
     data: BytesOr<'a, [T]>,
@@ -248,6 +707,95 @@ impl<'a, T: IsUuid> ToBytes for ServiceUuids<'a, T> {
     }
 }
 
+/// List of device addresses an advertisement is directed at.
+///
+/// All addresses in the list are either public or random device addresses, a device that wants to
+/// target both kinds has to send two separate lists.
+#[derive(Debug, Copy, Clone, defmt::Format)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct TargetAddresses<'a> {
+    kind: AddressKind,
+    /// Raw address bytes, 6 Bytes per address.
+    data: &'a [u8],
+}
+
+impl<'a> TargetAddresses<'a> {
+    /// Creates a `TargetAddresses` list from a list of raw device addresses of the given kind.
+    ///
+    /// Each address is given in the order it is sent over the air (LSB first).
+    pub fn from_raw(kind: AddressKind, addrs: &'a [[u8; 6]]) -> Self {
+        Self {
+            kind,
+            data: zerocopy::AsBytes::as_bytes(addrs),
+        }
+    }
+
+    /// Returns the kind of the addresses in this list.
+    pub fn kind(&self) -> AddressKind {
+        self.kind
+    }
+
+    fn type_(&self) -> u8 {
+        match self.kind {
+            AddressKind::Public => Type::PUBLIC_TARGET_ADDRESS,
+            AddressKind::Random => Type::RANDOM_TARGET_ADDRESS,
+        }
+    }
+
+    /// Returns an iterator over the device addresses stored in `self`.
+    pub fn iter(&self) -> impl Iterator<Item = DeviceAddress> + 'a {
+        let kind = self.kind;
+        self.data.chunks_exact(6).map(move |chunk| {
+            let mut raw = [0; 6];
+            raw.copy_from_slice(chunk);
+            DeviceAddress::new(raw, kind)
+        })
+    }
+}
+
+/// Decodes `TargetAddresses` from a byte sequence containing:
+///
+/// * **`TYPE`**: Either "Public Target Address" or "Random Target Address".
+/// * **`ADDR`**...: n*6 Bytes of device addresses.
+impl<'a> FromBytes<'a> for TargetAddresses<'a> {
+    fn from_bytes(bytes: &mut ByteReader<'a>) -> Result<Self, Error> {
+        let kind = match bytes.read_u8()? {
+            Type::PUBLIC_TARGET_ADDRESS => AddressKind::Public,
+            Type::RANDOM_TARGET_ADDRESS => AddressKind::Random,
+            _ => return Err(Error::InvalidValue),
+        };
+
+        let data = bytes.read_rest();
+        if !data.chunks_exact(6).remainder().is_empty() {
+            return Err(Error::InvalidLength);
+        }
+
+        Ok(Self { kind, data })
+    }
+}
+
+impl<'a> ToBytes for TargetAddresses<'a> {
+    fn to_bytes(&self, buffer: &mut ByteWriter<'_>) -> Result<(), Error> {
+        buffer.write_u8(self.type_())?;
+        buffer.write_slice(self.data)
+    }
+}
+
+enum_with_unknown! {
+    /// LE roles supported by a device, sent in the *LE Role* AD structure.
+    #[derive(Debug, Copy, Clone, PartialEq, Eq, defmt::Format)]
+    pub enum LeRole(u8) {
+        /// Only the Peripheral role is supported.
+        PeripheralOnly = 0x00,
+        /// Only the Central role is supported.
+        CentralOnly = 0x01,
+        /// Both roles are supported, Peripheral is preferred for connection establishment.
+        PeripheralPreferred = 0x02,
+        /// Both roles are supported, Central is preferred for connection establishment.
+        CentralPreferred = 0x03,
+    }
+}
+
 bitflags! {
     /// BR/EDR and LE compatibility flags.
     ///
@@ -261,6 +809,26 @@ bitflags! {
     }
 }
 
+impl defmt::Format for Flags {
+    fn format(&self, fmt: defmt::Formatter<'_>) {
+        defmt::write!(fmt, "Flags({=u8:b})", self.bits());
+    }
+}
+
+#[cfg(feature = "serde")]
+impl serde::Serialize for Flags {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.bits().serialize(serializer)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for Flags {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        u8::deserialize(deserializer).map(Self::from_bits_truncate)
+    }
+}
+
 impl Flags {
     /// Returns flags suitable for discoverable devices that want to establish a connection.
     ///
@@ -317,7 +885,7 @@ impl<'a> From<Flags> for AdStructure<'a> {
 /// Data Type constants.
 ///
 /// <https://www.bluetooth.com/specifications/assigned-numbers/generic-access-profile>
-enum Type {}
+pub(crate) enum Type {}
 
 #[allow(unused)]
 impl Type {
@@ -337,7 +905,7 @@ impl Type {
     const SIMPLE_PAIRING_RANDOMIZER_R: u8 = 0x0F;
     const SIMPLE_PAIRING_RANDOMIZER_R192: u8 = 0x0F;
     const DEVICE_ID: u8 = 0x10;
-    const SECURITY_MANAGER_TK_VALUE: u8 = 0x10;
+    pub(crate) const SECURITY_MANAGER_TK_VALUE: u8 = 0x10;
     const SECURITY_MANAGER_OUT_OF_BAND_FLAGS: u8 = 0x11;
     const SLAVE_CONNECTION_INTERVAL_RANGE: u8 = 0x12;
     const LIST_OF_16BIT_SERVICE_SOLICITATION_UUIDS: u8 = 0x14;
@@ -348,15 +916,15 @@ impl Type {
     const RANDOM_TARGET_ADDRESS: u8 = 0x18;
     const APPEARANCE: u8 = 0x19;
     const ADVERTISING_INTERVAL: u8 = 0x1A;
-    const LE_BLUETOOTH_DEVICE_ADDRESS: u8 = 0x1B;
+    pub(crate) const LE_BLUETOOTH_DEVICE_ADDRESS: u8 = 0x1B;
     const LE_ROLE: u8 = 0x1C;
     const SIMPLE_PAIRING_HASH_C256: u8 = 0x1D;
     const SIMPLE_PAIRING_RANDOMIZER_R256: u8 = 0x1E;
     const LIST_OF_32BIT_SERVICE_SOLICITATION_UUIDS: u8 = 0x1F;
     const SERVICE_DATA_32BIT_UUID: u8 = 0x20;
     const SERVICE_DATA_128BIT_UUID: u8 = 0x21;
-    const LE_SECURE_CONNECTIONS_CONFIRMATION_VALUE: u8 = 0x22;
-    const LE_SECURE_CONNECTIONS_RANDOM_VALUE: u8 = 0x23;
+    pub(crate) const LE_SECURE_CONNECTIONS_CONFIRMATION_VALUE: u8 = 0x22;
+    pub(crate) const LE_SECURE_CONNECTIONS_RANDOM_VALUE: u8 = 0x23;
     const URI: u8 = 0x24;
     const INDOOR_POSITIONING: u8 = 0x25;
     const TRANSPORT_DISCOVERY_DATA: u8 = 0x26;
@@ -369,3 +937,220 @@ impl Type {
     const _3D_INFORMATION_DATA: u8 = 0x3D;
     const MANUFACTURER_SPECIFIC_DATA: u8 = 0xFF;
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn roundtrip(ad: AdStructure<'_>, expected: &[u8]) {
+        let mut buf = [0; 31];
+        let mut writer = ByteWriter::new(&mut buf);
+        ad.to_bytes(&mut writer).unwrap();
+        let used = 31 - writer.space_left();
+        assert_eq!(&buf[..used], expected);
+
+        let mut reader = ByteReader::new(&buf[..used]);
+        let decoded = AdStructure::from_bytes(&mut reader).unwrap();
+        assert!(reader.is_empty());
+
+        let mut buf2 = [0; 31];
+        let mut writer = ByteWriter::new(&mut buf2);
+        decoded.to_bytes(&mut writer).unwrap();
+        assert_eq!(&buf2[..used], expected);
+    }
+
+    #[test]
+    fn parse_list() {
+        let data = [0x02, 0x01, 0x06, 0x02, 0x3D, 0xAB, 0x00, 0x12];
+        let mut ads = parse_ad_structures(&data);
+        assert_eq!(ads.next().unwrap().unwrap().ad_type(), Type::FLAGS);
+        let unknown = ads.next().unwrap().unwrap();
+        assert_eq!(unknown.ad_type(), Type::THREE_D_INFORMATION_DATA);
+        assert!(ads.next().is_none());
+
+        let truncated = [0x02, 0x01, 0x06, 0x05, 0x09, b'a'];
+        let mut ads = parse_ad_structures(&truncated);
+        assert!(ads.next().unwrap().is_ok());
+        assert_eq!(ads.next().unwrap().unwrap_err(), Error::Eof);
+        assert!(ads.next().is_none());
+    }
+
+    #[test]
+    #[cfg(feature = "serde")]
+    fn serialize() {
+        let mut reader = ByteReader::new(&[0x02, 0x01, 0x06, 0x03, 0x03, 0x0F, 0x18]);
+        let flags = AdStructure::from_bytes(&mut reader).unwrap();
+        let uuids = AdStructure::from_bytes(&mut reader).unwrap();
+        assert_eq!(serde_json::to_string(&flags).unwrap(), r#"{"Flags":6}"#);
+        assert_eq!(
+            serde_json::to_string(&uuids).unwrap(),
+            r#"{"ServiceUuids16":{"complete":true,"data":[6159]}}"#
+        );
+    }
+
+    #[test]
+    fn tx_power_and_appearance() {
+        roundtrip(AdStructure::TxPowerLevel(-4), &[0x02, 0x0A, 0xFC]);
+        roundtrip(AdStructure::Appearance(961), &[0x03, 0x19, 0xC1, 0x03]);
+    }
+
+    #[test]
+    fn service_uuids_32() {
+        let uuids = [Uuid32(0x12345678), Uuid16(0x180F).to_uuid32()];
+        roundtrip(
+            AdStructure::ServiceUuids32(ServiceUuids::from_uuids(false, &uuids)),
+            &[0x09, 0x04, 0x78, 0x56, 0x34, 0x12, 0x0F, 0x18, 0x00, 0x00],
+        );
+
+        let mut reader = ByteReader::new(&[0x05, 0x05, 0x78, 0x56, 0x34, 0x12]);
+        match AdStructure::from_bytes(&mut reader).unwrap() {
+            AdStructure::ServiceUuids32(uuids) => {
+                assert!(uuids.is_complete());
+                let uuid = uuids.iter().next().unwrap();
+                assert_eq!(uuid, Uuid32(0x12345678));
+                assert_eq!(
+                    uuid.to_uuid128().to_u128(),
+                    0x12345678_0000_1000_8000_00805F9B34FB
+                );
+            }
+            _ => panic!("decoded wrong AD structure"),
+        }
+
+        // The list must consist of whole UUIDs
+        let mut reader = ByteReader::new(&[0x04, 0x05, 0x78, 0x56, 0x34]);
+        assert!(AdStructure::from_bytes(&mut reader).is_err());
+    }
+
+    #[test]
+    fn service_data() {
+        roundtrip(
+            AdStructure::ServiceData16 {
+                uuid: Uuid16(0x180F),
+                data: &[0x64],
+            },
+            &[0x04, 0x16, 0x0F, 0x18, 0x64],
+        );
+        roundtrip(
+            AdStructure::ServiceData32 {
+                uuid: Uuid32(0x12345678),
+                data: &[],
+            },
+            &[0x05, 0x20, 0x78, 0x56, 0x34, 0x12],
+        );
+    }
+
+    #[test]
+    fn advertising_interval() {
+        roundtrip(
+            AdStructure::AdvertisingInterval(Duration::from_millis(100)),
+            &[0x03, 0x1A, 0xA0, 0x00],
+        );
+
+        let mut buf = [0; 31];
+        let ad = AdStructure::AdvertisingInterval(Duration::from_micros(1000));
+        assert_eq!(
+            ad.to_bytes(&mut ByteWriter::new(&mut buf)),
+            Err(Error::InvalidValue)
+        );
+    }
+
+    #[test]
+    fn le_role_and_target_addresses() {
+        roundtrip(
+            AdStructure::LeRole(LeRole::PeripheralPreferred),
+            &[0x02, 0x1C, 0x02],
+        );
+
+        let addrs = [[1, 2, 3, 4, 5, 6], [7, 8, 9, 10, 11, 12]];
+        let ad =
+            AdStructure::TargetAddresses(TargetAddresses::from_raw(AddressKind::Random, &addrs));
+        roundtrip(ad, &[13, 0x18, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12]);
+
+        let mut reader = ByteReader::new(&[7, 0x17, 1, 2, 3, 4, 5, 6]);
+        match AdStructure::from_bytes(&mut reader).unwrap() {
+            AdStructure::TargetAddresses(addrs) => {
+                let mut iter = addrs.iter();
+                assert_eq!(
+                    iter.next(),
+                    Some(DeviceAddress::new([1, 2, 3, 4, 5, 6], AddressKind::Public))
+                );
+                assert_eq!(iter.next(), None);
+            }
+            _ => panic!("decoded wrong AD structure"),
+        }
+    }
+
+    #[test]
+    fn adv_data_budget() {
+        let mut data = AdvertisingData::new();
+        data.push(AdStructure::Flags(Flags::discoverable()))
+            .unwrap()
+            .push(AdStructure::CompleteLocalName("Rubble"))
+            .unwrap();
+        assert_eq!(data.count(), 2);
+        assert_eq!(data.space_left(), 31 - 3 - 8);
+
+        let long_name = AdStructure::CompleteLocalName("A name that is far too long");
+        let err = data.push(long_name).unwrap_err();
+        assert_eq!(
+            err,
+            AdvertisingDataError::DoesNotFit {
+                index: 2,
+                ad_type: Type::COMPLETE_LOCAL_NAME,
+                required: 29,
+                available: 20,
+            }
+        );
+        assert_eq!(data.count(), 2);
+        assert_eq!(data.space_left(), 20);
+        assert_eq!(data.iter().count(), 2);
+
+        let mut data = AdvertisingData::with_budget(MAX_ADV_DATA_LEN);
+        data.push(long_name).unwrap();
+    }
+
+    #[test]
+    fn adv_data_rejects_malformed_unknown() {
+        let mut data = AdvertisingData::new();
+        let flags = AdStructure::Flags(Flags::discoverable());
+        data.push(flags).unwrap();
+
+        // Flags must be exactly 1 Byte long
+        let err = data
+            .push(AdStructure::Unknown {
+                ty: Type::FLAGS,
+                data: &[],
+            })
+            .unwrap_err();
+        assert_eq!(
+            err,
+            AdvertisingDataError::Encoding {
+                index: 1,
+                ad_type: Type::FLAGS,
+                error: Error::InvalidValue,
+            }
+        );
+        assert_eq!(data.count(), 1);
+        assert_eq!(data.space_left(), 31 - 3);
+
+        // Unknown types are kept as they are
+        let unknown = AdStructure::Unknown {
+            ty: Type::THREE_D_INFORMATION_DATA,
+            data: &[0xAB],
+        };
+        data.push(unknown).unwrap();
+        assert_eq!(data.iter().count(), 2);
+        assert_eq!(format!("{:?}", data).matches("Unknown").count(), 1);
+    }
+
+    #[test]
+    fn manufacturer_data() {
+        roundtrip(
+            AdStructure::ManufacturerSpecificData {
+                company_identifier: CompanyId::from_raw(0x0059),
+                payload: &[0xAA, 0xBB],
+            },
+            &[0x05, 0xFF, 0x59, 0x00, 0xAA, 0xBB],
+        );
+    }
+}
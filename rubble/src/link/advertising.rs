@@ -7,8 +7,8 @@
 //! Note that while the types in here do not completely eliminate illegal values to be created, they
 //! do employ a range of sanity checks that prevent bogus packets from being sent by the stack.
 
-use crate::link::ad_structure::{AdStructure, Flags};
-use crate::link::{channel_map::ChannelMap, AddressKind, DeviceAddress};
+use crate::link::ad_structure::{significant_part, AdStructure, AdvertisingData, Flags};
+use crate::link::{channel_map::ChannelMap, AccessAddress, AddressKind, DeviceAddress};
 use crate::utils::{Hex, HexSlice};
 use crate::{bytes::*, time::Duration, Error};
 use core::{convert::TryInto, fmt, iter};
@@ -28,8 +28,115 @@ pub const MAX_PAYLOAD_SIZE: usize = 37;
 /// Access Address to use for all advertising channel packets.
 pub const ACCESS_ADDRESS: u32 = 0x8E89BED6;
 
+/// Max. value of the pseudo-random `advDelay` added to every advertising interval.
+pub const MAX_ADV_DELAY: Duration = Duration::from_micros(10_000);
+
+/// Generator for the pseudo-random `advDelay` added to every advertising interval.
+///
+/// This uses a xorshift PRNG seeded with the device address, which is sufficient to keep
+/// advertisers with the same interval from colliding repeatedly.
+pub(crate) struct AdvDelay {
+    state: u32,
+}
+
+impl AdvDelay {
+    pub(crate) fn new(addr: &DeviceAddress) -> Self {
+        let raw = addr.raw();
+        let seed = u32::from_le_bytes([raw[0], raw[1], raw[2], raw[3]])
+            ^ u32::from(u16::from_le_bytes([raw[4], raw[5]])) << 8;
+        Self { state: seed | 1 }
+    }
+
+    /// Returns the next delay in range `0..=MAX_ADV_DELAY`.
+    pub(crate) fn next(&mut self) -> Duration {
+        self.state ^= self.state << 13;
+        self.state ^= self.state >> 17;
+        self.state ^= self.state << 5;
+        Duration::from_micros(self.state % (MAX_ADV_DELAY.as_micros() + 1))
+    }
+}
+
+/// Reasons an advertising interval can be invalid.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, defmt::Format)]
+pub enum IntervalError {
+    /// The interval is shorter than `AdvertisingInterval::MIN`.
+    TooShort,
+    /// The interval is longer than `AdvertisingInterval::MAX`.
+    TooLong,
+    /// The interval is not a multiple of `AdvertisingInterval::STEP`.
+    Misaligned,
+}
+
+impl fmt::Display for IntervalError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            IntervalError::TooShort => "advertising interval too short",
+            IntervalError::TooLong => "advertising interval too long",
+            IntervalError::Misaligned => "advertising interval not a multiple of 0.625 ms",
+        })
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for IntervalError {}
+
+impl From<IntervalError> for Error {
+    fn from(_: IntervalError) -> Self {
+        Error::InvalidValue
+    }
+}
+
+/// An advertising interval that is valid according to the specification.
+///
+/// The interval between two advertising events is this interval plus a pseudo-random delay of up
+/// to `MAX_ADV_DELAY`, which prevents repeated collisions with other advertisers.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, defmt::Format)]
+pub struct AdvertisingInterval(Duration);
+
+impl AdvertisingInterval {
+    /// The granularity of advertising intervals (0.625 ms).
+    pub const STEP: Duration = Duration::from_micros(625);
+
+    /// The shortest allowed advertising interval (20 ms).
+    pub const MIN: Self = Self(Duration::from_micros(0x0020 * 625));
+
+    /// The longest allowed advertising interval (10.24 s).
+    pub const MAX: Self = Self(Duration::from_micros(0x4000 * 625));
+
+    /// Validates an advertising interval given as a `Duration`.
+    pub fn new(interval: Duration) -> Result<Self, IntervalError> {
+        if interval < Self::MIN.0 {
+            Err(IntervalError::TooShort)
+        } else if interval > Self::MAX.0 {
+            Err(IntervalError::TooLong)
+        } else if interval.as_micros() % Self::STEP.as_micros() != 0 {
+            Err(IntervalError::Misaligned)
+        } else {
+            Ok(Self(interval))
+        }
+    }
+
+    /// Validates an advertising interval given in units of 0.625 ms, as used by HCI.
+    pub fn from_units(units: u16) -> Result<Self, IntervalError> {
+        Self::new(Duration::from_micros(
+            u32::from(units) * Self::STEP.as_micros(),
+        ))
+    }
+
+    /// Returns the interval as a `Duration`.
+    pub fn duration(&self) -> Duration {
+        self.0
+    }
+
+    /// Returns the interval in units of 0.625 ms.
+    pub fn units(&self) -> u16 {
+        (self.0.as_micros() / Self::STEP.as_micros()) as u16
+    }
+}
+
 /// A parsed advertising channel PDU.
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, defmt::Format)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 pub enum Pdu<'a> {
     /// Connectable and scannable advertisement.
     ConnectableUndirected {
@@ -111,14 +218,33 @@ pub enum Pdu<'a> {
 }
 
 impl<'a> Pdu<'a> {
+    /// Parses a raw advertising channel PDU, consisting of the 2-Byte header and the payload.
+    ///
+    /// `raw` must not contain the preamble, access address or CRC. Its length has to match the
+    /// payload length stored in the header.
+    pub fn parse(raw: &'a [u8]) -> Result<Self, Error> {
+        if raw.len() < 2 {
+            return Err(Error::Eof);
+        }
+        let header = Header::parse(raw);
+        Self::from_header_and_payload(header, &mut ByteReader::new(&raw[2..]))
+    }
+
     /// Constructs a PDU by parsing `payload`.
+    ///
+    /// Advertising and scan response data is not validated here, so that PDUs with malformed AD
+    /// structures can still be processed. Errors are reported by [`advertising_data`] instead.
+    ///
+    /// [`advertising_data`]: #method.advertising_data
     pub fn from_header_and_payload(
         header: Header,
         payload: &mut ByteReader<'a>,
     ) -> Result<Self, Error> {
         use self::Pdu::*;
 
-        if usize::from(header.payload_length()) != payload.bytes_left() {
+        if usize::from(header.payload_length()) != payload.bytes_left()
+            || !(6..=37).contains(&header.payload_length())
+        {
             return Err(Error::InvalidLength);
         }
 
@@ -132,7 +258,7 @@ impl<'a> Pdu<'a> {
                     };
                     DeviceAddress::new(payload.read_array::<[u8; 6]>()?, kind)
                 },
-                advertising_data: BytesOr::from_bytes(payload)?,
+                advertising_data: read_ad_data(payload),
             },
             PduType::AdvDirectInd => ConnectableDirected {
                 advertiser_addr: {
@@ -161,7 +287,7 @@ impl<'a> Pdu<'a> {
                     };
                     DeviceAddress::new(payload.read_array::<[u8; 6]>()?, kind)
                 },
-                advertising_data: BytesOr::from_bytes(payload)?,
+                advertising_data: read_ad_data(payload),
             },
             PduType::AdvScanInd => ScannableUndirected {
                 advertiser_addr: {
@@ -172,7 +298,7 @@ impl<'a> Pdu<'a> {
                     };
                     DeviceAddress::new(payload.read_array::<[u8; 6]>()?, kind)
                 },
-                advertising_data: BytesOr::from_bytes(payload)?,
+                advertising_data: read_ad_data(payload),
             },
             PduType::ScanReq => ScanRequest {
                 scanner_addr: {
@@ -204,7 +330,7 @@ impl<'a> Pdu<'a> {
                     };
                     DeviceAddress::new(payload.read_array::<[u8; 6]>()?, kind)
                 },
-                scan_data: BytesOr::from_bytes(payload)?,
+                scan_data: read_ad_data(payload),
             },
             PduType::ConnectReq => ConnectRequest {
                 // Initiator sends this PDU
@@ -300,8 +426,11 @@ impl<'a> Pdu<'a> {
 
     /// Returns an iterator over all AD structures encoded in the PDU.
     ///
+    /// If an AD structure is malformed, the error is yielded and iteration ends. Padding after the
+    /// significant part of the data is skipped.
+    ///
     /// If this PDU doesn't support attaching AD structures, this will return `None`.
-    pub fn advertising_data(&self) -> Option<impl Iterator<Item = AdStructure<'a>>> {
+    pub fn advertising_data(&self) -> Option<impl Iterator<Item = Result<AdStructure<'a>, Error>>> {
         use self::Pdu::*;
 
         match self {
@@ -313,13 +442,18 @@ impl<'a> Pdu<'a> {
             }
             | ScannableUndirected {
                 advertising_data, ..
-            } => Some(advertising_data.iter()),
-            ScanResponse { scan_data, .. } => Some(scan_data.iter()),
+            } => Some(advertising_data.try_iter()),
+            ScanResponse { scan_data, .. } => Some(scan_data.try_iter()),
             ScanRequest { .. } | ConnectableDirected { .. } | ConnectRequest { .. } => None,
         }
     }
 }
 
+/// Reads the AD structures at the end of an advertising channel PDU, without validating them.
+fn read_ad_data<'a>(payload: &mut ByteReader<'a>) -> BytesOr<'a, [AdStructure<'a>]> {
+    BytesOr::unchecked(significant_part(payload.read_rest()))
+}
+
 /// Decodes an advertising channel PDU (consisting of header and payload) from raw bytes.
 impl<'a> FromBytes<'a> for Pdu<'a> {
     fn from_bytes(bytes: &mut ByteReader<'a>) -> Result<Self, Error> {
@@ -329,9 +463,10 @@ impl<'a> FromBytes<'a> for Pdu<'a> {
 }
 
 /// Connection parameters sent along with a `ConnectRequest` PDU (also known as `LLData`).
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Debug, defmt::Format)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 pub struct ConnectRequestData {
-    access_address: Hex<u32>,
+    access_address: AccessAddress,
     crc_init: Hex<u32>,
     /// Transmit window size in µs.
     win_size: Duration,
@@ -352,9 +487,10 @@ impl ConnectRequestData {
     /// Returns the Access Address to use for data channel communication.
     ///
     /// The address is randomly generated by the initiator (the device sending the connection
-    /// request) according to the requirements in the Bluetooth specification.
-    pub fn access_address(&self) -> u32 {
-        self.access_address.0
+    /// request) according to the requirements in the Bluetooth specification. Connection requests
+    /// with an invalid Access Address are rejected when parsing.
+    pub fn access_address(&self) -> AccessAddress {
+        self.access_address
     }
 
     /// Returns the initialization value for the CRC calculation.
@@ -371,7 +507,7 @@ impl ConnectRequestData {
 
     /// Returns the channel hop distance.
     ///
-    /// This must be in range `5..=16`.
+    /// This is always in range `5..=16`.
     pub fn hop(&self) -> u8 {
         self.hop
     }
@@ -407,8 +543,8 @@ impl ConnectRequestData {
 impl FromBytes<'_> for ConnectRequestData {
     fn from_bytes(bytes: &mut ByteReader<'_>) -> Result<Self, Error> {
         let sca;
-        Ok(Self {
-            access_address: Hex(bytes.read_u32_le()?),
+        let data = Self {
+            access_address: AccessAddress::new(bytes.read_u32_le()?)?,
             crc_init: {
                 let mut le_bytes = [0u8; 4];
                 le_bytes[..3].copy_from_slice(bytes.read_slice(3)?);
@@ -444,7 +580,19 @@ impl FromBytes<'_> for ConnectRequestData {
                     _ => unreachable!(), // only 3 bits
                 }
             },
-        })
+        };
+
+        // These are supplied by the initiator, so reject values that would break channel
+        // selection or timing
+        if !data.chm.is_valid()
+            || !(5..=16).contains(&data.hop)
+            || data.interval < Duration::from_micros(7_500)
+            || data.interval > Duration::from_secs(4)
+        {
+            return Err(Error::InvalidValue);
+        }
+
+        Ok(data)
     }
 }
 
@@ -452,7 +600,8 @@ impl FromBytes<'_> for ConnectRequestData {
 /// million).
 ///
 /// The lower the PPM, the higher the accuracy.
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Debug, defmt::Format)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum SleepClockAccuracy {
     Ppm251To500,
     Ppm151To250,
@@ -502,6 +651,54 @@ impl PduBuf {
         })
     }
 
+    /// Creates an advertising PDU of type `ty` carrying pre-built advertising data.
+    ///
+    /// `ty` must be one of the PDU types that carry advertising data (`ADV_IND`,
+    /// `ADV_NONCONN_IND`, `ADV_SCAN_IND`, or `SCAN_RSP`), otherwise `Error::InvalidValue` is
+    /// returned. Since the budget of `adv_data` is checked when building it, this only fails when
+    /// the budget exceeds what fits into a legacy advertising PDU.
+    pub fn from_adv_data(
+        ty: PduType,
+        advertiser_addr: DeviceAddress,
+        adv_data: &AdvertisingData,
+    ) -> Result<Self, Error> {
+        Self::from_raw_adv_data(ty, advertiser_addr, adv_data.as_bytes())
+    }
+
+    /// Creates an advertising PDU of type `ty` carrying already encoded advertising data.
+    ///
+    /// This is like [`from_adv_data`], but takes the AD structures as raw Bytes (for example, when
+    /// they are supplied by an HCI host). `adv_data` is not validated. `Error::Eof` is returned if
+    /// it doesn't fit into the PDU.
+    ///
+    /// [`from_adv_data`]: #method.from_adv_data
+    pub fn from_raw_adv_data(
+        ty: PduType,
+        advertiser_addr: DeviceAddress,
+        adv_data: &[u8],
+    ) -> Result<Self, Error> {
+        match ty {
+            PduType::AdvInd | PduType::AdvNonconnInd | PduType::AdvScanInd | PduType::ScanRsp => {}
+            _ => return Err(Error::InvalidValue),
+        }
+
+        let mut payload = [0; MAX_PAYLOAD_SIZE];
+        let mut buf = ByteWriter::new(&mut payload[..]);
+        buf.write_slice(advertiser_addr.raw()).unwrap();
+        buf.write_slice(adv_data)?;
+
+        let left = buf.space_left();
+        let used = payload.len() - left;
+        let mut header = Header::new(ty);
+        header.set_payload_length(used as u8);
+        header.set_tx_add(advertiser_addr.is_random());
+        header.set_rx_add(false);
+        Ok(Self {
+            header,
+            payload_buf: payload,
+        })
+    }
+
     /// Creates a connectable undirected advertising PDU (`ADV_IND`).
     ///
     /// # Parameters
@@ -558,7 +755,7 @@ impl PduBuf {
 
     /// Creates a scannable undirected advertising PDU (`ADV_SCAN_IND`).
     ///
-    /// Note that scanning is not supported at the moment.
+    /// This is used by `ScannableBeacon`, which answers scan requests.
     pub fn scannable_undirected(
         advertiser_addr: DeviceAddress,
         advertiser_data: &[AdStructure<'_>],
@@ -652,6 +849,22 @@ impl PduBuf {
         let len = self.header.payload_length() as usize;
         &self.payload_buf[..len]
     }
+
+    /// Returns the initiator address if this is a connectable directed advertising PDU.
+    pub(crate) fn directed_to(&self) -> Option<DeviceAddress> {
+        if self.header.type_() != PduType::AdvDirectInd {
+            return None;
+        }
+
+        let mut raw = [0; 6];
+        raw.copy_from_slice(&self.payload_buf[6..12]);
+        let kind = if self.header.rx_add() {
+            AddressKind::Random
+        } else {
+            AddressKind::Public
+        };
+        Some(DeviceAddress::new(raw, kind))
+    }
 }
 
 impl fmt::Debug for PduBuf {
@@ -660,6 +873,12 @@ impl fmt::Debug for PduBuf {
     }
 }
 
+impl defmt::Format for PduBuf {
+    fn format(&self, fmt: defmt::Formatter<'_>) {
+        defmt::write!(fmt, "({}, {=[u8]:x})", self.header(), self.payload());
+    }
+}
+
 /// 16-bit Advertising Channel PDU header preceding the Payload.
 ///
 /// The header looks like this:
@@ -678,6 +897,7 @@ impl fmt::Debug for PduBuf {
 /// Length may be in range 6 to 37 (inclusive). With the 2-Byte header this is exactly the max.
 /// on-air packet size.
 #[derive(Copy, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 pub struct Header(u16);
 
 const TXADD_MASK: u16 = 0b00000000_01000000;
@@ -754,7 +974,8 @@ impl Header {
 
     /// Sets the payload length of this PDU.
     ///
-    /// The `length` must be in range 6...37, otherwise this function panics.
+    /// The `length` must be in range 6...37, otherwise this function panics. The headers of PDUs
+    /// successfully parsed by `Pdu::from_header_and_payload` always satisfy this.
     pub fn set_payload_length(&mut self, length: u8) {
         assert!(6 <= length && length <= 37);
 
@@ -774,6 +995,19 @@ impl fmt::Debug for Header {
     }
 }
 
+impl defmt::Format for Header {
+    fn format(&self, fmt: defmt::Formatter<'_>) {
+        defmt::write!(
+            fmt,
+            "Header {{ PDU Type: {}, TxAdd: {=bool}, RxAdd: {=bool}, len: {=u8} }}",
+            self.type_(),
+            self.tx_add(),
+            self.rx_add(),
+            self.payload_length(),
+        );
+    }
+}
+
 impl<'a> FromBytes<'a> for Header {
     fn from_bytes(bytes: &mut ByteReader<'a>) -> Result<Self, Error> {
         let raw = bytes.read_u16_le()?;
@@ -791,7 +1025,7 @@ enum_with_unknown! {
     /// 4-bit PDU type in [`Header`].
     ///
     /// For more details, see [`PduBuf`].
-    #[derive(Debug, PartialEq, Eq)]
+    #[derive(Debug, PartialEq, Eq, defmt::Format)]
     pub enum PduType(u8) {
         /// Connectable undirected advertising event (`ADV_IND`).
         AdvInd = 0b0000,
@@ -839,3 +1073,74 @@ impl PduType {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_raw() {
+        let raw = [0x02, 9, 1, 2, 3, 4, 5, 6, 0x02, 0x01, 0x06];
+        let pdu = Pdu::parse(&raw).unwrap();
+        assert_eq!(pdu.sender().raw(), &[1, 2, 3, 4, 5, 6]);
+        assert_eq!(pdu.advertising_data().unwrap().count(), 1);
+
+        assert_eq!(Pdu::parse(&raw[..1]).unwrap_err(), Error::Eof);
+        assert_eq!(Pdu::parse(&raw[..10]).unwrap_err(), Error::InvalidLength);
+    }
+
+    #[test]
+    fn malformed_ad_data() {
+        // Flags, followed by a structure whose length exceeds the PDU
+        let raw = [
+            0x02, 12, 1, 2, 3, 4, 5, 6, 0x02, 0x01, 0x06, 0x09, 0xFF, 0x01,
+        ];
+        let pdu = Pdu::parse(&raw).unwrap();
+        let mut ads = pdu.advertising_data().unwrap();
+        assert_eq!(ads.next().unwrap().unwrap().ad_type(), 0x01);
+        assert_eq!(ads.next().unwrap().unwrap_err(), Error::Eof);
+        assert!(ads.next().is_none());
+        // Formatting stops at the malformed structure
+        assert_eq!(pdu.advertising_data().unwrap().count(), 2);
+        let _ = format!("{:?}", pdu);
+
+        // Truncated structure with valid length but malformed contents
+        let raw = [0x02, 10, 1, 2, 3, 4, 5, 6, 0x03, 0x01, 0x06, 0x00];
+        let pdu = Pdu::parse(&raw).unwrap();
+        let mut ads = pdu.advertising_data().unwrap();
+        assert_eq!(ads.next().unwrap().unwrap_err(), Error::InvalidLength);
+        assert!(ads.next().is_none());
+
+        // Zero padding is not part of the data
+        let raw = [0x06, 11, 1, 2, 3, 4, 5, 6, 0x02, 0x01, 0x06, 0x00, 0x00];
+        let pdu = Pdu::parse(&raw).unwrap();
+        assert_eq!(pdu.advertising_data().unwrap().count(), 1);
+        assert!(pdu.advertising_data().unwrap().all(|ad| ad.is_ok()));
+    }
+
+    #[test]
+    fn advertising_interval() {
+        assert_eq!(
+            AdvertisingInterval::new(Duration::from_millis(20))
+                .unwrap()
+                .units(),
+            0x0020
+        );
+        assert_eq!(
+            AdvertisingInterval::from_units(0x4000).unwrap(),
+            AdvertisingInterval::MAX
+        );
+        assert_eq!(
+            AdvertisingInterval::new(Duration::from_micros(19_375)),
+            Err(IntervalError::TooShort)
+        );
+        assert_eq!(
+            AdvertisingInterval::from_units(0x4001),
+            Err(IntervalError::TooLong)
+        );
+        assert_eq!(
+            AdvertisingInterval::new(Duration::from_micros(20_001)),
+            Err(IntervalError::Misaligned)
+        );
+    }
+}
@@ -0,0 +1,256 @@
+//! Channel classification for adaptive frequency hopping (AFH).
+//!
+//! Interference (eg. from Wi-Fi) usually affects a few adjacent data channels. The Link-Layer
+//! records the reception quality of every data channel in a [`ChannelQuality`] table, and a
+//! [`ChannelClassifier`] derives a reduced [`ChannelMap`] from it that avoids the bad channels.
+//!
+//! Only the Central may change the channel map of a connection (by sending an
+//! `LL_CHANNEL_MAP_IND`). Rubble currently only implements the Peripheral role, so the result of
+//! the classification can't be applied to a connection yet. The per-channel statistics are still
+//! useful for diagnosing interference; they are available via `ConnectionStats::channels`.
+//!
+//! [`ChannelQuality`]: struct.ChannelQuality.html
+//! [`ChannelClassifier`]: trait.ChannelClassifier.html
+//! [`ChannelMap`]: ../struct.ChannelMap.html
+
+use super::ChannelMap;
+use crate::phy::DataChannel;
+
+/// Number of data channels.
+const NUM_CHANNELS: usize = 37;
+
+/// Reception statistics of a single data channel.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq, defmt::Format)]
+pub struct ChannelStats {
+    /// Number of connection events on this channel in which a packet was expected.
+    pub events: u16,
+
+    /// Number of those events in which the first packet failed the CRC check.
+    pub crc_errors: u16,
+
+    /// Number of those events in which no packet was received at all.
+    pub missed: u16,
+}
+
+impl ChannelStats {
+    /// Returns the number of events in which no valid packet was received.
+    pub fn errors(&self) -> u16 {
+        self.crc_errors + self.missed
+    }
+
+    /// Returns the share of failed events in percent, or `None` if no events were recorded.
+    pub fn error_rate(&self) -> Option<u8> {
+        if self.events == 0 {
+            None
+        } else {
+            Some((u32::from(self.errors()) * 100 / u32::from(self.events)) as u8)
+        }
+    }
+
+    /// Makes room for another event, halving all counts if necessary.
+    fn add_event(&mut self) {
+        if self.events == u16::max_value() {
+            // Halving keeps the error rate intact
+            self.events /= 2;
+            self.crc_errors /= 2;
+            self.missed /= 2;
+        }
+        self.events += 1;
+    }
+}
+
+/// Per-channel reception statistics of a connection.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct ChannelQuality {
+    channels: [ChannelStats; NUM_CHANNELS],
+}
+
+impl ChannelQuality {
+    /// Creates an empty table.
+    pub fn new() -> Self {
+        Self {
+            channels: [ChannelStats::default(); NUM_CHANNELS],
+        }
+    }
+
+    /// Records a connection event on `channel` whose first packet was received.
+    ///
+    /// `crc_ok` is whether the packet passed the CRC check.
+    pub fn record_packet(&mut self, channel: DataChannel, crc_ok: bool) {
+        let stats = &mut self.channels[usize::from(channel.index())];
+        stats.add_event();
+        if !crc_ok {
+            stats.crc_errors += 1;
+        }
+    }
+
+    /// Records a connection event on `channel` in which no packet was received.
+    pub fn record_missed(&mut self, channel: DataChannel) {
+        let stats = &mut self.channels[usize::from(channel.index())];
+        stats.add_event();
+        stats.missed += 1;
+    }
+
+    /// Returns an iterator over the statistics of all data channels, by channel index.
+    pub fn iter(&self) -> impl Iterator<Item = (DataChannel, &ChannelStats)> {
+        self.channels
+            .iter()
+            .enumerate()
+            .map(|(i, stats)| (DataChannel::new(i as u8), stats))
+    }
+
+    /// Returns the channel with the highest error rate, among those with at least `min_events`
+    /// recorded events.
+    pub fn worst(&self, min_events: u16) -> Option<(DataChannel, &ChannelStats)> {
+        self.iter()
+            .filter(|(_, stats)| stats.events != 0 && stats.events >= min_events)
+            .max_by_key(|(_, stats)| stats.error_rate())
+    }
+
+    /// Returns the statistics of `channel`.
+    pub fn get(&self, channel: DataChannel) -> &ChannelStats {
+        &self.channels[usize::from(channel.index())]
+    }
+
+    /// Clears the statistics of all channels.
+    ///
+    /// This should be done after the channel map was changed, so that channels are reevaluated
+    /// with fresh samples.
+    pub fn reset(&mut self) {
+        *self = Self::new();
+    }
+}
+
+impl Default for ChannelQuality {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl defmt::Format for ChannelQuality {
+    fn format(&self, fmt: defmt::Formatter<'_>) {
+        defmt::write!(fmt, "ChannelQuality {{ channels: {} }}", &self.channels[..]);
+    }
+}
+
+/// A policy deciding which data channels to use.
+///
+/// Applications can implement this trait to plug in their own classification, for example one
+/// that also takes energy measurements into account.
+pub trait ChannelClassifier {
+    /// Computes a new channel map from the reception statistics.
+    ///
+    /// `current` is the channel map in use. Returns `None` if the map should not be changed. A
+    /// returned map must mark at least 2 channels as used.
+    fn classify(&mut self, quality: &ChannelQuality, current: &ChannelMap) -> Option<ChannelMap>;
+}
+
+/// A `ChannelClassifier` excluding channels with a high rate of CRC errors and missed packets.
+///
+/// Excluded channels are not used anymore and thus can't recover. Resetting the statistics and
+/// returning to all channels from time to time is left to the application.
+#[derive(Debug, Copy, Clone, defmt::Format)]
+pub struct ErrorRateClassifier {
+    /// Error rate in percent above which a channel is excluded.
+    pub max_error_rate: u8,
+
+    /// Number of events that have to be recorded on a channel before it is classified.
+    pub min_events: u16,
+
+    /// Minimum number of channels to keep in the map.
+    ///
+    /// The specification requires at least 2. If fewer channels would remain, the map is not
+    /// changed.
+    pub min_channels: u8,
+}
+
+impl Default for ErrorRateClassifier {
+    fn default() -> Self {
+        Self {
+            max_error_rate: 30,
+            min_events: 20,
+            min_channels: 8,
+        }
+    }
+}
+
+impl ChannelClassifier for ErrorRateClassifier {
+    fn classify(&mut self, quality: &ChannelQuality, current: &ChannelMap) -> Option<ChannelMap> {
+        let mut raw = current.to_raw();
+        for channel in current.iter_used() {
+            let stats = quality.get(channel);
+            if stats.events < self.min_events {
+                continue;
+            }
+            if stats.error_rate().unwrap_or(0) > self.max_error_rate {
+                let index = channel.index();
+                raw[usize::from(index / 8)] &= !(1 << (index % 8));
+            }
+        }
+
+        let map = ChannelMap::from_raw(raw);
+        if map == *current
+            || map.num_used_channels() < self.min_channels.max(ChannelMap::MIN_USED_CHANNELS)
+        {
+            None
+        } else {
+            Some(map)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(quality: &mut ChannelQuality, channel: u8, events: u16, errors: u16) {
+        for i in 0..events {
+            quality.record_packet(DataChannel::new(channel), i >= errors);
+        }
+    }
+
+    #[test]
+    fn error_rate() {
+        let mut quality = ChannelQuality::new();
+        assert_eq!(quality.get(DataChannel::new(3)).error_rate(), None);
+        record(&mut quality, 3, 38, 8);
+        quality.record_missed(DataChannel::new(3));
+        quality.record_missed(DataChannel::new(3));
+        assert_eq!(quality.get(DataChannel::new(3)).error_rate(), Some(25));
+        record(&mut quality, 4, 10, 1);
+        assert_eq!(quality.worst(0).unwrap().0, DataChannel::new(3));
+        assert_eq!(quality.worst(50), None);
+        quality.reset();
+        assert_eq!(quality.get(DataChannel::new(3)).events, 0);
+    }
+
+    #[test]
+    fn excludes_bad_channels() {
+        let all = ChannelMap::with_all_channels();
+        let mut classifier = ErrorRateClassifier::default();
+        let mut quality = ChannelQuality::new();
+        for channel in 2..37 {
+            record(&mut quality, channel, 20, 0);
+        }
+        assert_eq!(classifier.classify(&quality, &all), None);
+
+        // Channel 1 has too few samples to be classified
+        record(&mut quality, 0, 20, 20);
+        record(&mut quality, 1, 19, 19);
+        let map = classifier.classify(&quality, &all).unwrap();
+        assert_eq!(map.num_used_channels(), 36);
+        assert!(!map.is_used(DataChannel::new(0)));
+        assert!(map.is_used(DataChannel::new(1)));
+    }
+
+    #[test]
+    fn keeps_minimum() {
+        let all = ChannelMap::with_all_channels();
+        let mut classifier = ErrorRateClassifier::default();
+        let mut quality = ChannelQuality::new();
+        for channel in 0..30 {
+            record(&mut quality, channel, 20, 20);
+        }
+        assert_eq!(classifier.classify(&quality, &all), None);
+    }
+}
@@ -0,0 +1,157 @@
+//! Compile-time selection of the Link-Layer role.
+//!
+//! A [`LinkLayer`] is created in one of the roles defined by the specification, which is encoded
+//! in its type. Connection handling is only compiled in for roles that can accept connections, so
+//! a binary that only broadcasts doesn't pay for it in code size: The branches dealing with
+//! connections are removed as dead code once the `LinkLayer` is monomorphized for
+//! [`Broadcaster`].
+//!
+//! Rubble doesn't implement scanning or initiating connections, so only the [`Broadcaster`] and
+//! [`Peripheral`] roles are available. Optional Link-Layer features (encryption, Data Length
+//! Extension, 2M PHY, ...) are selected at compile time as well, via `Config::FEATURES`. They
+//! only apply to connections and are never used by broadcasters.
+//!
+//! [`LinkLayer`]: ../struct.LinkLayer.html
+//! [`Broadcaster`]: struct.Broadcaster.html
+//! [`Peripheral`]: struct.Peripheral.html
+
+use crate::config::{BufferSizes, Config};
+use crate::link::advertising::AdvDelay;
+use crate::link::filter::PeerFilter;
+use crate::link::{ConnectionHandle, DeviceAddress, LinkLayer, State};
+use crate::phy::AdvertisingChannelMap;
+use core::marker::PhantomData;
+
+/// A Link-Layer role, selected via [`LinkLayerBuilder`].
+///
+/// This is implemented by the marker types [`Broadcaster`] and [`Peripheral`].
+///
+/// [`LinkLayerBuilder`]: struct.LinkLayerBuilder.html
+/// [`Broadcaster`]: struct.Broadcaster.html
+/// [`Peripheral`]: struct.Peripheral.html
+pub trait Role {
+    /// Whether devices in this role accept connection requests.
+    const CONNECTABLE: bool;
+}
+
+/// The *Broadcaster* role: Sends non-connectable advertisements and never enters a connection.
+///
+/// Broadcasters don't need packet queues. They are started with `LinkLayer::start_broadcast`.
+#[derive(Debug)]
+pub enum Broadcaster {}
+
+impl Role for Broadcaster {
+    const CONNECTABLE: bool = false;
+}
+
+/// The *Peripheral* role: Sends connectable advertisements and accepts connections.
+///
+/// This is the role used by `LinkLayer::new`. Peripherals are started with
+/// `LinkLayer::start_advertise`.
+#[derive(Debug)]
+pub enum Peripheral {}
+
+impl Role for Peripheral {
+    const CONNECTABLE: bool = true;
+}
+
+/// Creates a [`LinkLayer`] with a role selected at compile time.
+///
+/// ```ignore
+/// let mut ll = LinkLayerBuilder::<AppConfig>::new(device_address, timer)
+///     .broadcaster()
+///     .tx_power(4)
+///     .build();
+/// ll.start_broadcast(interval, &ad_structures, &mut radio)?;
+/// ```
+///
+/// [`LinkLayer`]: ../struct.LinkLayer.html
+pub struct LinkLayerBuilder<C: Config, R: Role = Peripheral> {
+    dev_addr: DeviceAddress,
+    timer: C::Timer,
+    tx_power: i8,
+    peer_filter: Option<PeerFilter>,
+    adv_channels: AdvertisingChannelMap,
+    role: PhantomData<R>,
+}
+
+impl<C: Config> LinkLayerBuilder<C> {
+    /// Starts building a peripheral Link-Layer using the device address `dev_addr`.
+    pub fn new(dev_addr: DeviceAddress, timer: C::Timer) -> Self {
+        Self {
+            dev_addr,
+            timer,
+            tx_power: C::TX_POWER,
+            peer_filter: None,
+            adv_channels: AdvertisingChannelMap::ALL,
+            role: PhantomData,
+        }
+    }
+}
+
+impl<C: Config, R: Role> LinkLayerBuilder<C, R> {
+    /// Selects the *Broadcaster* role.
+    pub fn broadcaster(self) -> LinkLayerBuilder<C, Broadcaster> {
+        self.with_role()
+    }
+
+    /// Selects the *Peripheral* role (the default).
+    pub fn peripheral(self) -> LinkLayerBuilder<C, Peripheral> {
+        self.with_role()
+    }
+
+    /// Sets the initial transmit power in dBm, overriding `Config::TX_POWER`.
+    pub fn tx_power(mut self, dbm: i8) -> Self {
+        self.tx_power = dbm;
+        self
+    }
+
+    /// Restricts which devices may scan and connect to this device.
+    ///
+    /// See `LinkLayer::set_peer_filter` for details.
+    pub fn peer_filter(mut self, filter: PeerFilter) -> Self {
+        self.peer_filter = Some(filter);
+        self
+    }
+
+    /// Restricts advertising to a subset of the advertising channels.
+    ///
+    /// See `LinkLayer::set_advertising_channels` for details.
+    pub fn advertising_channels(mut self, channels: AdvertisingChannelMap) -> Self {
+        self.adv_channels = channels;
+        self
+    }
+
+    /// Creates the `LinkLayer`.
+    pub fn build(self) -> LinkLayer<C, R> {
+        #[allow(clippy::let_unit_value)]
+        let () = BufferSizes::<C>::CHECK;
+
+        trace!(logger: C::Logger, "new LinkLayer, dev={:?}", self.dev_addr);
+        LinkLayer {
+            dev_addr: self.dev_addr,
+            state: State::Standby,
+            timer: self.timer,
+            tx_power: self.tx_power,
+            next_event: None,
+            peer_filter: self.peer_filter,
+            adv_channels: self.adv_channels,
+            adv_delay: AdvDelay::new(&self.dev_addr),
+            resume_adv: None,
+            next_handle: ConnectionHandle::from_raw(0),
+            released: None,
+            role: PhantomData,
+        }
+    }
+
+    fn with_role<S: Role>(self) -> LinkLayerBuilder<C, S> {
+        LinkLayerBuilder {
+            dev_addr: self.dev_addr,
+            timer: self.timer,
+            tx_power: self.tx_power,
+            peer_filter: self.peer_filter,
+            adv_channels: self.adv_channels,
+            role: PhantomData,
+        }
+    }
+}
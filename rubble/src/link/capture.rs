@@ -0,0 +1,224 @@
+//! Capturing of the PDUs sent and received by the Link-Layer.
+//!
+//! The `LinkLayer` passes every PDU it transmits or receives to [`Config::capture_pdu`] as a
+//! [`CapturedPdu`]. Implementations can convert it to a `pcap` record with
+//! [`CapturedPdu::write_pcap_record`] and stream it to a host (eg. via RTT or UART). Prefixing the
+//! stream with the header written by [`write_pcap_header`] yields a capture file that can be
+//! opened in Wireshark.
+//!
+//! The records use the `LINKTYPE_BLUETOOTH_LE_LL_WITH_PHDR` link type, which stores the RF
+//! channel, Access Address and CRC status alongside each packet. It does not record the direction
+//! of a packet, which is only available via [`CapturedPdu::direction`].
+//!
+//! [`Config::capture_pdu`]: ../../config/trait.Config.html#method.capture_pdu
+//! [`CapturedPdu`]: struct.CapturedPdu.html
+//! [`CapturedPdu::write_pcap_record`]: struct.CapturedPdu.html#method.write_pcap_record
+//! [`CapturedPdu::direction`]: struct.CapturedPdu.html#structfield.direction
+//! [`write_pcap_header`]: fn.write_pcap_header.html
+
+use crate::bytes::ByteWriter;
+use crate::link::advertising::{self, ACCESS_ADDRESS, CRC_PRESET};
+use crate::phy::{crc24, crc_bytes, AdvertisingChannel};
+use crate::time::Instant;
+use crate::Error;
+
+/// `pcap` link type of the records (`LINKTYPE_BLUETOOTH_LE_LL_WITH_PHDR`).
+const LINKTYPE_BLUETOOTH_LE_LL_WITH_PHDR: u32 = 256;
+
+/// Size of a `pcap` record header.
+const RECORD_HEADER_LEN: usize = 16;
+
+/// Size of the pseudo-header preceding each packet.
+const PHDR_LEN: usize = 10;
+
+/// Pseudo-header flags.
+const FLAG_DEWHITENED: u16 = 0x0001;
+const FLAG_REF_AA_VALID: u16 = 0x0010;
+const FLAG_CRC_CHECKED: u16 = 0x0400;
+const FLAG_CRC_VALID: u16 = 0x0800;
+
+/// Max. size of a record written by `CapturedPdu::write_pcap_record`.
+pub const MAX_PCAP_RECORD: usize = RECORD_HEADER_LEN + PHDR_LEN + 4 + 2 + 255 + 3;
+
+/// Size of the `pcap` file header written by `write_pcap_header`.
+pub const PCAP_HEADER_LEN: usize = 24;
+
+/// Writes the `pcap` file header that needs to precede the records written by
+/// `CapturedPdu::write_pcap_record`.
+///
+/// Returns the number of Bytes written (`PCAP_HEADER_LEN`). If `buf` is too small, `Error::Eof`
+/// is returned.
+pub fn write_pcap_header(buf: &mut [u8]) -> Result<usize, Error> {
+    let mut writer = ByteWriter::new(buf);
+    writer.write_u32_le(0xA1B2_C3D4)?; // magic (microsecond timestamps)
+    writer.write_u16_le(2)?; // major version
+    writer.write_u16_le(4)?; // minor version
+    writer.write_u32_le(0)?; // timezone offset
+    writer.write_u32_le(0)?; // timestamp accuracy
+    writer.write_u32_le(MAX_PCAP_RECORD as u32)?; // snapshot length
+    writer.write_u32_le(LINKTYPE_BLUETOOTH_LE_LL_WITH_PHDR)?;
+    Ok(PCAP_HEADER_LEN)
+}
+
+/// Whether a captured PDU was received or transmitted.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, defmt::Format)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Direction {
+    /// The PDU was received from the peer.
+    Rx,
+    /// The PDU was transmitted by the local device.
+    Tx,
+}
+
+/// A PDU sent or received by the Link-Layer.
+#[derive(Debug, Copy, Clone, defmt::Format)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct CapturedPdu<'a> {
+    /// Whether the PDU was received or transmitted.
+    pub direction: Direction,
+
+    /// The time at which the PDU was sent or received.
+    ///
+    /// For received PDUs, this is the end of the packet. For transmitted PDUs, this is the time
+    /// the transmission was scheduled for.
+    pub timestamp: Instant,
+
+    /// The RF channel (0-39) the PDU was sent on.
+    pub rf_channel: u8,
+
+    /// The Access Address of the packet.
+    pub access_address: u32,
+
+    /// The CRC initialization value used for the packet.
+    pub crc_init: u32,
+
+    /// Whether the CRC of a received packet was correct. Always `true` for transmitted PDUs.
+    pub crc_ok: bool,
+
+    /// The raw 16-bit PDU header.
+    pub header: u16,
+
+    /// The PDU payload following the header.
+    pub payload: &'a [u8],
+}
+
+impl<'a> CapturedPdu<'a> {
+    /// Creates a `CapturedPdu` describing an advertising channel PDU.
+    pub(crate) fn advertising(
+        direction: Direction,
+        timestamp: Instant,
+        channel: AdvertisingChannel,
+        header: advertising::Header,
+        payload: &'a [u8],
+        crc_ok: bool,
+    ) -> Self {
+        Self {
+            direction,
+            timestamp,
+            rf_channel: channel.rf_channel(),
+            access_address: ACCESS_ADDRESS,
+            crc_init: CRC_PRESET,
+            crc_ok,
+            header: header.to_u16(),
+            payload,
+        }
+    }
+
+    /// Writes a `pcap` record containing this PDU into `buf`.
+    ///
+    /// Returns the number of Bytes written, which is at most `MAX_PCAP_RECORD`. If `buf` is too
+    /// small, `Error::Eof` is returned.
+    ///
+    /// Since the Link-Layer doesn't see the CRC of packets, it is recomputed from the PDU. For
+    /// received packets with a bad CRC, the record is flagged accordingly.
+    pub fn write_pcap_record(&self, buf: &mut [u8]) -> Result<usize, Error> {
+        let header = self.header.to_le_bytes();
+        let crc = crc24(crc24(self.crc_init, &header), self.payload);
+
+        let packet_len = 4 + 2 + self.payload.len() + 3;
+        let len = (PHDR_LEN + packet_len) as u32;
+        let micros = self.timestamp.raw_micros();
+
+        let mut flags = FLAG_DEWHITENED | FLAG_REF_AA_VALID | FLAG_CRC_CHECKED;
+        if self.crc_ok {
+            flags |= FLAG_CRC_VALID;
+        }
+
+        let mut writer = ByteWriter::new(buf);
+        let space = writer.space_left();
+
+        // Record header
+        writer.write_u32_le(micros / 1_000_000)?;
+        writer.write_u32_le(micros % 1_000_000)?;
+        writer.write_u32_le(len)?;
+        writer.write_u32_le(len)?;
+
+        // Pseudo-header
+        writer.write_u8(self.rf_channel)?;
+        writer.write_u8(0)?; // signal power (invalid)
+        writer.write_u8(0)?; // noise power (invalid)
+        writer.write_u8(0)?; // access address offenses (invalid)
+        writer.write_u32_le(self.access_address)?;
+        writer.write_u16_le(flags)?;
+
+        // Packet
+        writer.write_u32_le(self.access_address)?;
+        writer.write_slice(&header)?;
+        writer.write_slice(self.payload)?;
+        writer.write_slice(&crc_bytes(crc))?;
+
+        Ok(space - writer.space_left())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::link::advertising::PduType;
+
+    #[test]
+    fn pcap_record() {
+        let header = advertising::Header::parse(&[0x42, 0x06]);
+        let payload = [0x11, 0x22, 0x33, 0x44, 0x55, 0x66];
+        assert_eq!(header.type_(), PduType::AdvNonconnInd);
+        let pdu = CapturedPdu::advertising(
+            Direction::Tx,
+            Instant::from_raw_micros(2_000_123),
+            AdvertisingChannel::first(),
+            header,
+            &payload,
+            true,
+        );
+
+        let mut buf = [0; MAX_PCAP_RECORD];
+        let len = pdu.write_pcap_record(&mut buf).unwrap();
+        let record = &buf[..len];
+        assert_eq!(len, RECORD_HEADER_LEN + PHDR_LEN + 4 + 2 + 6 + 3);
+
+        assert_eq!(record[..4], 2u32.to_le_bytes());
+        assert_eq!(record[4..8], 123u32.to_le_bytes());
+        assert_eq!(
+            record[8..12],
+            ((len - RECORD_HEADER_LEN) as u32).to_le_bytes()
+        );
+        assert_eq!(record[16], 0); // RF channel of channel index 37
+        assert_eq!(record[20..24], ACCESS_ADDRESS.to_le_bytes());
+        assert_eq!(record[26..30], ACCESS_ADDRESS.to_le_bytes());
+        assert_eq!(record[30..32], [0x42, 0x06]);
+        assert_eq!(record[32..38], payload);
+
+        // The CRC covers header and payload, so running it over everything must yield 0
+        let crc = crc24(CRC_PRESET, &record[30..]);
+        assert_eq!(crc, 0);
+
+        assert!(pdu.write_pcap_record(&mut buf[..len - 1]).is_err());
+    }
+
+    #[test]
+    fn pcap_header() {
+        let mut buf = [0; PCAP_HEADER_LEN];
+        assert_eq!(write_pcap_header(&mut buf), Ok(PCAP_HEADER_LEN));
+        assert_eq!(buf[..4], [0xD4, 0xC3, 0xB2, 0xA1]);
+        assert_eq!(buf[20..], [0x00, 0x01, 0x00, 0x00]);
+    }
+}
@@ -0,0 +1,275 @@
+//! AES-CCM encryption of Data Channel PDUs.
+//!
+//! Once the *Encryption Start* procedure has completed, the payload of every non-empty Data
+//! Channel PDU is encrypted with the session key and followed by a 4-Byte *Message Integrity
+//! Check* (MIC). The nonce is made up of a per-direction packet counter and the IV exchanged in
+//! `LL_ENC_REQ` and `LL_ENC_RSP`. This is specified in Vol 6, Part E of the Bluetooth Core
+//! Specification.
+
+use crate::security::AesProvider;
+use crate::Error;
+
+/// Length of the Message Integrity Check appended to encrypted payloads.
+pub const MIC_LEN: usize = 4;
+
+/// Bits of the first header byte that are authenticated (`LLID`, `CP` and `RFU`).
+///
+/// `NESN`, `SN` and `MD` may change when a PDU is retransmitted, so they are masked out.
+const HEADER_MASK: u8 = 0b1110_0011;
+
+/// The session key and IV of an encrypted connection.
+///
+/// This type does not implement `Debug` to keep the key out of logs.
+#[derive(Copy, Clone)]
+pub struct Ccm {
+    /// Session key, most significant octet first (as used by `AesProvider`).
+    key: [u8; 16],
+
+    /// `IVm || IVs`, least significant octet first (the order used in the nonce).
+    iv: [u8; 8],
+}
+
+impl Ccm {
+    /// Size of the key and IV as encoded by `to_raw`.
+    pub(crate) const RAW_LEN: usize = 16 + 8;
+
+    /// Creates a cipher from the session key `key` and the IV `iv`.
+    ///
+    /// `iv` contains `IVm` in the lower and `IVs` in the upper 32 bits, like
+    /// `SessionKey::iv`.
+    pub fn new(key: u128, iv: u64) -> Self {
+        Self {
+            key: key.to_be_bytes(),
+            iv: iv.to_le_bytes(),
+        }
+    }
+
+    /// Encodes key and IV, so that the host can pass them to the Link-Layer via the TX queue.
+    pub(crate) fn to_raw(self) -> [u8; Self::RAW_LEN] {
+        let mut raw = [0; Self::RAW_LEN];
+        raw[..16].copy_from_slice(&self.key);
+        raw[16..].copy_from_slice(&self.iv);
+        raw
+    }
+
+    /// Decodes key and IV encoded by `to_raw`.
+    pub(crate) fn from_raw(raw: &[u8]) -> Option<Self> {
+        if raw.len() != Self::RAW_LEN {
+            return None;
+        }
+
+        let mut this = Self {
+            key: [0; 16],
+            iv: [0; 8],
+        };
+        this.key.copy_from_slice(&raw[..16]);
+        this.iv.copy_from_slice(&raw[16..]);
+        Some(this)
+    }
+
+    /// Encrypts `payload` in place and returns the MIC to append to it.
+    ///
+    /// # Parameters
+    ///
+    /// * **`aes`**: The AES implementation to use.
+    /// * **`counter`**: Packet counter of the sender (only the lower 39 bits are used).
+    /// * **`from_master`**: Whether the PDU is sent by the master.
+    /// * **`header`**: First Byte of the PDU header.
+    /// * **`payload`**: The unencrypted payload, at most 251 Bytes.
+    pub fn encrypt(
+        &self,
+        aes: &mut dyn AesProvider,
+        counter: u64,
+        from_master: bool,
+        header: u8,
+        payload: &mut [u8],
+    ) -> [u8; MIC_LEN] {
+        let nonce = self.nonce(counter, from_master);
+        let mic = self.mic(aes, &nonce, header, payload);
+        self.apply_keystream(aes, &nonce, payload);
+        mic
+    }
+
+    /// Decrypts `payload` in place and checks its `mic`.
+    ///
+    /// Returns `Error::InvalidValue` if the MIC doesn't match, in which case the contents of
+    /// `payload` must not be used. The parameters are the same as for [`encrypt`].
+    ///
+    /// [`encrypt`]: #method.encrypt
+    pub fn decrypt(
+        &self,
+        aes: &mut dyn AesProvider,
+        counter: u64,
+        from_master: bool,
+        header: u8,
+        payload: &mut [u8],
+        mic: &[u8],
+    ) -> Result<(), Error> {
+        let nonce = self.nonce(counter, from_master);
+        self.apply_keystream(aes, &nonce, payload);
+        let expected = self.mic(aes, &nonce, header, payload);
+
+        // Compare without exiting early, to not reveal how many Bytes matched
+        let diff = expected
+            .iter()
+            .zip(mic)
+            .fold(0, |diff, (a, b)| diff | (a ^ b));
+        if mic.len() == MIC_LEN && diff == 0 {
+            Ok(())
+        } else {
+            Err(Error::InvalidValue)
+        }
+    }
+
+    /// Builds the 13-Byte CCM nonce for a packet.
+    fn nonce(&self, counter: u64, from_master: bool) -> [u8; 13] {
+        let mut nonce = [0; 13];
+        nonce[..5].copy_from_slice(&counter.to_le_bytes()[..5]);
+        // The 39-bit counter is followed by the direction bit
+        nonce[4] = nonce[4] & 0x7F | u8::from(from_master) << 7;
+        nonce[5..].copy_from_slice(&self.iv);
+        nonce
+    }
+
+    /// Computes the MIC of an unencrypted `payload` (CBC-MAC over `B0`, `B1`, payload blocks).
+    fn mic(
+        &self,
+        aes: &mut dyn AesProvider,
+        nonce: &[u8; 13],
+        header: u8,
+        payload: &[u8],
+    ) -> [u8; MIC_LEN] {
+        // B0: Flags (8-Byte MIC, 2-Byte length), nonce, payload length
+        let mut x = [0; 16];
+        x[0] = 0x49;
+        x[1..14].copy_from_slice(nonce);
+        x[14..].copy_from_slice(&(payload.len() as u16).to_be_bytes());
+        aes.encrypt_block(&self.key, &mut x);
+
+        // B1: Length of the additional data (1), followed by the masked header Byte
+        x[1] ^= 0x01;
+        x[2] ^= header & HEADER_MASK;
+        aes.encrypt_block(&self.key, &mut x);
+
+        for block in payload.chunks(16) {
+            for (x, b) in x.iter_mut().zip(block) {
+                *x ^= b;
+            }
+            aes.encrypt_block(&self.key, &mut x);
+        }
+
+        let s0 = self.keystream(aes, nonce, 0);
+        let mut mic = [0; MIC_LEN];
+        for (i, mic) in mic.iter_mut().enumerate() {
+            *mic = x[i] ^ s0[i];
+        }
+        mic
+    }
+
+    /// Encrypts or decrypts `payload` in CTR mode.
+    fn apply_keystream(&self, aes: &mut dyn AesProvider, nonce: &[u8; 13], payload: &mut [u8]) {
+        for (i, block) in payload.chunks_mut(16).enumerate() {
+            let s = self.keystream(aes, nonce, i as u16 + 1);
+            for (b, s) in block.iter_mut().zip(&s) {
+                *b ^= s;
+            }
+        }
+    }
+
+    /// Computes the keystream block `S_i` = `e(key, A_i)`.
+    fn keystream(&self, aes: &mut dyn AesProvider, nonce: &[u8; 13], i: u16) -> [u8; 16] {
+        let mut a = [0; 16];
+        a[0] = 0x01;
+        a[1..14].copy_from_slice(nonce);
+        a[14..].copy_from_slice(&i.to_be_bytes());
+        aes.encrypt_block(&self.key, &mut a);
+        a
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::security::{AesProvider, SoftAes};
+
+    // Sample data from Vol 6, Part C, Section 1 of the Bluetooth Core Specification.
+    const LTK: u128 = 0x4C68384139F574D836BCF34E9DFB01BF;
+    const SKD_M: u64 = 0xACBDCEDFE0F10213;
+    const SKD_S: u64 = 0x0213243546576879;
+    const IV_M: u32 = 0xBADCAB24;
+    const IV_S: u32 = 0xDEAFBABE;
+
+    fn sample() -> Ccm {
+        let skd = u128::from(SKD_S) << 64 | u128::from(SKD_M);
+        let mut sk = skd.to_be_bytes();
+        SoftAes.encrypt_block(&LTK.to_be_bytes(), &mut sk);
+        let sk = u128::from_be_bytes(sk);
+        assert_eq!(sk, 0x99AD1B5226A37E3E058E3B8E27C2C666);
+        Ccm::new(sk, u64::from(IV_S) << 32 | u64::from(IV_M))
+    }
+
+    #[test]
+    fn sample_start_enc_rsp() {
+        let ccm = sample();
+
+        // LL_START_ENC_RSP sent by the master with packet counter 0
+        let mut payload = [0x06];
+        let mic = ccm.encrypt(&mut SoftAes, 0, true, 0x0F, &mut payload);
+        assert_eq!(payload, [0x9F]);
+        assert_eq!(mic, [0xCD, 0xA7, 0xF4, 0x48]);
+
+        ccm.decrypt(&mut SoftAes, 0, true, 0x0F, &mut payload, &mic)
+            .unwrap();
+        assert_eq!(payload, [0x06]);
+    }
+
+    #[test]
+    fn roundtrip() {
+        let ccm = sample();
+        let plain: [u8; 40] = core::array::from_fn(|i| i as u8);
+
+        let mut payload = plain;
+        let mic = ccm.encrypt(&mut SoftAes, 7, false, 0x02, &mut payload);
+        assert_ne!(payload, plain);
+
+        // NESN, SN and MD are not authenticated
+        let mut decrypted = payload;
+        ccm.decrypt(&mut SoftAes, 7, false, 0x1E, &mut decrypted, &mic)
+            .unwrap();
+        assert_eq!(decrypted, plain);
+
+        // Wrong counter, direction or LLID
+        for &(counter, from_master, header) in
+            &[(8, false, 0x02), (7, true, 0x02), (7, false, 0x01)]
+        {
+            let mut decrypted = payload;
+            assert_eq!(
+                ccm.decrypt(
+                    &mut SoftAes,
+                    counter,
+                    from_master,
+                    header,
+                    &mut decrypted,
+                    &mic
+                ),
+                Err(Error::InvalidValue)
+            );
+        }
+
+        let mut tampered = payload;
+        tampered[39] ^= 1;
+        assert!(ccm
+            .decrypt(&mut SoftAes, 7, false, 0x02, &mut tampered, &mic)
+            .is_err());
+    }
+
+    #[test]
+    fn raw() {
+        let ccm = sample();
+        let raw = ccm.to_raw();
+        let decoded = Ccm::from_raw(&raw).unwrap();
+        assert_eq!(decoded.key, ccm.key);
+        assert_eq!(decoded.iv, ccm.iv);
+        assert!(Ccm::from_raw(&raw[1..]).is_none());
+    }
+}
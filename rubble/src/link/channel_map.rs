@@ -1,16 +1,26 @@
-use crate::{bytes::RawRepr, phy::DataChannel};
+use crate::{bytes::RawRepr, phy::DataChannel, Error};
 use core::fmt;
 
 /// A map marking data channels as used or unused.
 ///
-/// A channel map must mark at least 2 channels as used.
+/// A channel map must mark at least [`MIN_USED_CHANNELS`] channels as used.
+///
+/// [`MIN_USED_CHANNELS`]: #associatedconstant.MIN_USED_CHANNELS
 #[derive(Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(from = "[u8; 5]", into = "[u8; 5]")
+)]
 pub struct ChannelMap {
     raw: [u8; 5],
     num_used_channels: u8,
 }
 
 impl ChannelMap {
+    /// The minimum number of used channels required by the specification.
+    pub const MIN_USED_CHANNELS: u8 = 2;
+
     /// Create a new channel map from the raw format used in Connection Request PDUs (`ChM`).
     ///
     /// The first byte (LSB) contains flags for data channels 0 to 7, where the least significant
@@ -40,6 +50,29 @@ impl ChannelMap {
         }
     }
 
+    /// Creates a channel map that marks only the given data channels as used.
+    ///
+    /// This can be used to restrict a connection to a subset of channels, eg. for regulatory
+    /// testing. Returns `Error::InvalidValue` if fewer than `MIN_USED_CHANNELS` distinct channels
+    /// are given.
+    pub fn from_used(channels: impl IntoIterator<Item = DataChannel>) -> Result<Self, Error> {
+        let mut raw = [0; 5];
+        for channel in channels {
+            raw[usize::from(channel.index() / 8)] |= 1 << (channel.index() % 8);
+        }
+        let map = Self::from_raw(raw);
+        if map.is_valid() {
+            Ok(map)
+        } else {
+            Err(Error::InvalidValue)
+        }
+    }
+
+    /// Returns whether this map marks at least `MIN_USED_CHANNELS` channels as used.
+    pub fn is_valid(&self) -> bool {
+        self.num_used_channels >= Self::MIN_USED_CHANNELS
+    }
+
     /// Returns the number of data channels marked as used by this map.
     pub fn num_used_channels(&self) -> u8 {
         self.num_used_channels
@@ -92,12 +125,35 @@ impl fmt::Display for ChannelMap {
     }
 }
 
+impl From<[u8; 5]> for ChannelMap {
+    fn from(raw: [u8; 5]) -> Self {
+        Self::from_raw(raw)
+    }
+}
+
+impl From<ChannelMap> for [u8; 5] {
+    fn from(map: ChannelMap) -> Self {
+        map.to_raw()
+    }
+}
+
 impl fmt::Debug for ChannelMap {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(f, "{} ({:?})", self, self.raw)
     }
 }
 
+impl defmt::Format for ChannelMap {
+    fn format(&self, fmt: defmt::Formatter<'_>) {
+        defmt::write!(
+            fmt,
+            "ChannelMap({=u8} used, {=[u8]:x})",
+            self.num_used_channels,
+            &self.raw[..]
+        );
+    }
+}
+
 impl RawRepr<[u8; 5]> for ChannelMap {
     fn from_raw(raw: [u8; 5]) -> Self {
         ChannelMap::from_raw(raw)
@@ -135,6 +191,22 @@ mod tests {
         assert_eq!(map, ChannelMap::with_all_channels());
     }
 
+    #[test]
+    fn from_used() {
+        let map = ChannelMap::from_used([DataChannel::new(3), DataChannel::new(36)]).unwrap();
+        assert_eq!(map.to_raw(), [0x08, 0, 0, 0, 0x10]);
+        assert!(map
+            .iter_used()
+            .eq(vec![DataChannel::new(3), DataChannel::new(36)]));
+
+        // Duplicates are only counted once
+        assert_eq!(
+            ChannelMap::from_used([DataChannel::new(3), DataChannel::new(3)]),
+            Err(Error::InvalidValue)
+        );
+        assert!(!ChannelMap::from_raw([0x01, 0, 0, 0, 0]).is_valid());
+    }
+
     #[test]
     fn all_channels() {
         let map = ChannelMap::with_all_channels();
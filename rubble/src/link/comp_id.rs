@@ -5,7 +5,8 @@ use core::fmt;
 use crate::bytes::RawRepr;
 
 /// Company identifier for use in link layer Control PDUs.
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct CompanyId(u16);
 
 impl RawRepr<u16> for CompanyId {
@@ -31,13 +32,20 @@ impl defmt::Format for CompanyId {
 }
 
 impl CompanyId {
+    /// The value used by devices that don't have a company identifier assigned by the Bluetooth
+    /// SIG (`0xFFFF`).
+    ///
+    /// This may only be used for internal and interoperability testing. Products have to use the
+    /// company identifier of their manufacturer.
+    pub const UNASSIGNED: Self = Self(0xFFFF);
+
     /// Create a company ID from a raw `u16`.
-    pub fn from_raw(raw: u16) -> Self {
+    pub const fn from_raw(raw: u16) -> Self {
         Self(raw)
     }
 
     /// Get the raw `u16` representing this company identifier.
-    pub fn as_u16(&self) -> u16 {
+    pub const fn as_u16(&self) -> u16 {
         self.0
     }
 
@@ -1,17 +1,28 @@
 //! Link-Layer connection management and LLCP implementation.
 
+use crate::link::afh::ChannelQuality;
+use crate::link::capture::{CapturedPdu, Direction};
+use crate::link::ccm::{Ccm, MIC_LEN};
 use crate::link::data::{self, Header, Llid, Pdu};
-use crate::link::llcp::{ConnectionUpdateData, ControlPdu};
-use crate::link::queue::{Consume, Consumer, Producer};
+use crate::link::llcp::{
+    self, ConnectionUpdateData, ControlOpcode, ControlPdu, Procedures, VersionInfo,
+    POWER_DELTA_MAX, POWER_NOT_AVAILABLE,
+};
+use crate::link::queue::{Consume, Consumer, Producer, RawSlot};
 use crate::link::{
-    advertising::ConnectRequestData, channel_map::ChannelMap, Cmd, CompanyId, FeatureSet,
-    NextUpdate, RadioCmd, SeqNum, Transmitter,
+    advertising::ConnectRequestData, channel_map::ChannelMap, Cmd, ConnectionHandle, DeviceAddress,
+    FeatureSet, NextUpdate, Peer, RadioCmd, SeqNum, Transmitter, MAX_DATA_PAYLOAD_BUF,
+    MIN_DATA_PAYLOAD_BUF,
 };
+use crate::security::AesProvider;
 use crate::time::{Duration, Instant, Timer};
 use crate::utils::{Hex, HexSlice};
 use crate::{bytes::*, config::*, phy::DataChannel, Error, BLUETOOTH_VERSION};
 use core::{marker::PhantomData, num::Wrapping};
 
+/// Number of events a channel needs before it is considered for the logged link quality summary.
+const STATS_MIN_CHANNEL_EVENTS: u16 = 10;
+
 /// Connection state and parameters.
 pub struct Connection<C: Config> {
     access_address: u32,
@@ -24,6 +35,12 @@ pub struct Connection<C: Config> {
     /// Connection event interval (duration between the start of 2 subsequent connection events).
     conn_interval: Duration,
 
+    /// Number of connection events the slave is allowed to skip.
+    slave_latency: u16,
+
+    /// Maximum time between 2 received packets before the connection is considered lost.
+    supervision_timeout: Duration,
+
     /// Connection event counter (`connEventCount(er)` in the spec).
     conn_event_count: Wrapping<u16>,
 
@@ -35,6 +52,30 @@ pub struct Connection<C: Config> {
     /// Actual data channel on which the next data packets will be exchanged.
     channel: DataChannel,
 
+    /// Anchor point of the last connection event in which a packet was received, and the event
+    /// counter of that connection event.
+    ///
+    /// This is `None` until the first packet is received after connection establishment or a
+    /// connection update, since those move the anchor point.
+    last_anchor: Option<(Instant, u16)>,
+
+    /// Deviation of the last anchor point from the time predicted by the previous one.
+    window_widening: Duration,
+
+    /// Time at which the first packet of the current connection event was received, while the
+    /// event is extended to exchange more packets.
+    event_first_rx: Option<Instant>,
+
+    /// Whether the connection is optimized for throughput (see `set_throughput_mode`).
+    throughput_mode: bool,
+
+    /// Share of the supervision timeout (in percent) after which an idle connection is kept alive
+    /// with `LL_PING_REQ`, or 0 if disabled (see `set_keep_alive`).
+    keep_alive: u8,
+
+    /// Time at which the last non-empty PDU was received or sent.
+    last_activity: Instant,
+
     // Acknowledgement / Flow Control state
     /// `SN` bit to be used
     transmit_seq_num: SeqNum,
@@ -49,11 +90,48 @@ pub struct Connection<C: Config> {
     tx: ConfConsumer<C>,
     rx: ConfProducer<C>,
 
+    /// RX queue storage the radio was told to receive the next packet into.
+    rx_slot: Option<RawSlot>,
+
     /// LLCP connection update data received in a previous LL Control PDU.
     ///
     /// Contains the *instant* at which it should be applied to the Link Layer state.
     update_data: Option<LlcpUpdate>,
 
+    /// LLCP procedures in progress.
+    procedures: Procedures,
+
+    /// Handle assigned to the connection by the `LinkLayer`.
+    handle: ConnectionHandle,
+
+    /// Address of the master device that initiated the connection.
+    peer_addr: DeviceAddress,
+
+    /// Features used on the connection (supported by both devices).
+    ///
+    /// `None` until the features have been exchanged.
+    features: Option<FeatureSet>,
+
+    /// Version information sent by the peer in `LL_VERSION_IND`.
+    ///
+    /// `None` until the peer has initiated the version exchange.
+    peer_version: Option<VersionInfo>,
+
+    /// Link quality counters.
+    stats: ConnectionStats,
+
+    /// Transmit power in dBm, as last applied to the `Transmitter`.
+    tx_power: i8,
+
+    /// Progress of the encryption procedures.
+    encryption: Encryption,
+
+    /// Session key and packet counters, set once the host has supplied the key.
+    cipher: Option<Cipher>,
+
+    /// Whether we've sent an `LL_TERMINATE_IND`. The connection ends once it is acknowledged.
+    terminating: bool,
+
     _p: PhantomData<C>,
 }
 
@@ -66,26 +144,41 @@ impl<C: Config> Connection<C> {
     /// # Parameters
     ///
     /// * **`lldata`**: Data contained in the `CONNECT_REQ` advertising PDU.
+    /// * **`handle`**: Handle identifying the connection.
+    /// * **`peer_addr`**: Address of the initiator that sent the `CONNECT_REQ`.
     /// * **`rx_end`**: Instant at which the `CONNECT_REQ` PDU was fully received.
     /// * **`tx`**: Channel for packets to transmit.
     /// * **`rx`**: Channel for received packets.
+    /// * **`tx_power`**: Transmit power in use when the connection was established, in dBm.
     pub(crate) fn create(
         lldata: &ConnectRequestData,
+        handle: ConnectionHandle,
+        peer_addr: DeviceAddress,
         rx_end: Instant,
         tx: ConfConsumer<C>,
         rx: ConfProducer<C>,
+        tx_power: i8,
     ) -> (Self, Cmd) {
         let mut this = Self {
-            access_address: lldata.access_address(),
+            access_address: lldata.access_address().raw(),
             crc_init: lldata.crc_init(),
             channel_map: *lldata.channel_map(),
             hop: lldata.hop(),
             conn_interval: lldata.interval(),
+            slave_latency: lldata.slave_latency(),
+            supervision_timeout: lldata.supervision_timeout(),
             conn_event_count: Wrapping(0),
 
             unmapped_channel: DataChannel::new(0),
             channel: DataChannel::new(0),
 
+            last_anchor: None,
+            window_widening: Duration::from_micros(0),
+            event_first_rx: None,
+            throughput_mode: false,
+            keep_alive: 0,
+            last_activity: rx_end,
+
             transmit_seq_num: SeqNum::ZERO,
             next_expected_seq_num: SeqNum::ZERO,
             last_header: Header::new(Llid::DataCont),
@@ -93,7 +186,18 @@ impl<C: Config> Connection<C> {
 
             tx,
             rx,
+            rx_slot: None,
             update_data: None,
+            procedures: Procedures::new(),
+            handle,
+            peer_addr,
+            features: None,
+            peer_version: None,
+            stats: ConnectionStats::default(),
+            tx_power,
+            encryption: Encryption::Off,
+            cipher: None,
+            terminating: false,
 
             _p: PhantomData,
         };
@@ -105,18 +209,24 @@ impl<C: Config> Connection<C> {
             next_update: NextUpdate::At(
                 rx_end + lldata.end_of_tx_window() + Duration::from_micros(500),
             ),
-            radio: RadioCmd::ListenData {
-                channel: this.channel,
-                access_address: this.access_address,
-                crc_init: this.crc_init,
-                timeout: false,
-            },
+            radio: this.listen_data(false),
             queued_work: false,
+            tx_space_freed: false,
         };
 
         (this, cmd)
     }
 
+    /// Returns the transmit power in dBm.
+    pub(crate) fn tx_power(&self) -> i8 {
+        self.tx_power
+    }
+
+    /// Records that the transmit power was changed to `dbm` outside of the connection.
+    pub(crate) fn set_tx_power(&mut self, dbm: i8) {
+        self.tx_power = dbm;
+    }
+
     /// Called by the `LinkLayer` when a data channel packet is received.
     ///
     /// Returns `Err(())` when the connection is ended (not necessarily due to an error condition).
@@ -128,6 +238,14 @@ impl<C: Config> Connection<C> {
         payload: &[u8],
         crc_ok: bool,
     ) -> Result<Cmd, ()> {
+        self.capture(Direction::Rx, rx_end, header, payload, crc_ok);
+        self.check_procedure_timeout(rx_end)?;
+        let tx_time = rx_end + Duration::T_IFS;
+        if crc_ok && self.event_first_rx.is_none() {
+            // The length in the header can only be trusted if the CRC is correct
+            self.update_anchor(rx_end - airtime(payload.len()));
+        }
+
         // If the sequence number of the packet is the same as our next expected sequence number,
         // the packet contains new data that we should try to process. However, if the CRC is bad,
         // we'll never try to process the data and instead request a retransmission.
@@ -141,17 +259,50 @@ impl<C: Config> Connection<C> {
 
         let is_empty = header.llid() == Llid::DataCont && payload.is_empty();
 
+        self.stats.packets_received = self.stats.packets_received.wrapping_add(1);
+        self.stats.consecutive_missed_events = 0;
+        if self.event_first_rx.is_none() {
+            self.stats.channels.record_packet(self.channel, crc_ok);
+        }
+        if !crc_ok {
+            self.stats.crc_errors = self.stats.crc_errors.wrapping_add(1);
+        } else if !is_new {
+            self.stats.rx_retransmissions = self.stats.rx_retransmissions.wrapping_add(1);
+        }
+
+        if acknowledged && self.terminating {
+            info!(logger: C::Logger, "LL_TERMINATE_IND acknowledged, closing connection");
+            return Err(());
+        }
+
         if acknowledged {
             self.received_packet = true;
             self.transmit_seq_num += SeqNum::ONE;
         }
 
+        // Once the master encrypts its PDUs, decrypt new ones. Empty PDUs are never encrypted. A
+        // PDU with an invalid MIC ends the connection.
+        let mut plain = [0; MAX_DATA_PAYLOAD_BUF];
+        let decrypted = is_new && !payload.is_empty() && matches!(self.cipher, Some(c) if c.rx);
+        let (header, payload) = if decrypted {
+            self.decrypt(tx, header, payload, &mut plain)?
+        } else {
+            (header, payload)
+        };
+        let expected_seq_num = self.next_expected_seq_num;
+
         // Whether we've already sent a response packet.
         let mut responded = false;
         // Whether we've pushed more work into the RX queue.
         let mut queued_work = false;
+        // Whether we've taken a packet out of the TX queue.
+        let mut tx_space_freed = false;
 
         if is_new {
+            if !is_empty {
+                self.last_activity = rx_end;
+            }
+
             if is_empty {
                 // Always acknowledge empty packets, no need to process them
                 self.next_expected_seq_num += SeqNum::ONE;
@@ -163,9 +314,20 @@ impl<C: Config> Connection<C> {
                     // Some LLCPDUs don't need a response, those can always be processed and
                     // ACKed. For those that do, the other device must have ACKed the last
                     // packet we sent, because we'll directly use the radio's TX buffer to send
-                    // back the LLCP response.
+                    // back the LLCP response. PDUs passed on to the host also need space in the
+                    // RX queue.
+
+                    let forward = self.forwards_to_host(&pdu, tx);
+                    let result = if forward && self.rx.free_space() < header.payload_length() {
+                        Err(LlcpError::NoSpace)
+                    } else {
+                        self.process_control_pdu(pdu, forward, acknowledged, rx_end, tx)
+                    };
+                    if forward && result.is_ok() && self.enqueue_rx(header, payload).is_ok() {
+                        queued_work = true;
+                    }
 
-                    match self.process_control_pdu(pdu, acknowledged) {
+                    match result {
                         Ok(Some(response)) => {
                             self.next_expected_seq_num += SeqNum::ONE;
 
@@ -177,17 +339,17 @@ impl<C: Config> Connection<C> {
                             let mut header = Header::new(Llid::Control);
                             let pl_len = (left - payload_writer.space_left()) as u8;
                             header.set_payload_length(pl_len);
-                            self.send(header, tx);
+                            self.send(header, tx, tx_time);
                             responded = true;
 
-                            info!("LLCP<- {:?}", pdu);
-                            info!("LLCP-> {:?}", response);
+                            info!(logger: C::Logger, "LLCP<- {:?}", pdu);
+                            info!(logger: C::Logger, "LLCP-> {:?}", response);
                         }
                         Ok(None) => {
                             self.next_expected_seq_num += SeqNum::ONE;
 
-                            info!("LLCP<- {:?}", pdu);
-                            info!("LLCP-> (no response)");
+                            info!(logger: C::Logger, "LLCP<- {:?}", pdu);
+                            info!(logger: C::Logger, "LLCP-> (no response)");
                         }
                         Err(LlcpError::ConnectionLost) => {
                             return Err(());
@@ -203,18 +365,21 @@ impl<C: Config> Connection<C> {
                 // Try to buffer the packet. If it fails, we don't acknowledge it, so it will be
                 // resent until we have space.
 
-                let result: Result<(), Error> =
-                    self.rx.produce_with(header.payload_length(), |writer| {
-                        writer.write_slice(payload)?;
-                        Ok(header.llid())
-                    });
-
-                if result.is_ok() {
+                if self.enqueue_rx(header, payload).is_ok() {
                     // Acknowledge the packet
                     self.next_expected_seq_num += SeqNum::ONE;
                     queued_work = true;
                 } else {
-                    trace!("NACK (no space in rx buffer)");
+                    self.stats.rx_overflows = self.stats.rx_overflows.wrapping_add(1);
+                    trace!(logger: C::Logger, "NACK (no space in rx buffer)");
+                }
+            }
+
+            // The packet counter only advances when the PDU is acknowledged, since the master
+            // retransmits a NACKed PDU with the same counter
+            if decrypted && self.next_expected_seq_num != expected_seq_num {
+                if let Some(cipher) = &mut self.cipher {
+                    cipher.rx_counter += 1;
                 }
             }
         }
@@ -223,17 +388,32 @@ impl<C: Config> Connection<C> {
             if !responded {
                 // Send a new data packet.
 
-                // Try to acquire PDU from the tx queue, fall back to an empty PDU.
+                // Start a queued LLCP procedure if possible, then try to acquire PDU from the tx
+                // queue, and fall back to an empty PDU.
                 let mut payload_writer = ByteWriter::new(tx.tx_payload_buf());
-                let header = match self.tx.consume_raw_with(|header, pl| {
-                    payload_writer.write_slice(pl).expect("TX buf out of space");
-                    Consume::always(Ok(header))
-                }) {
-                    Ok(h) => h,
-                    Err(_) => Header::new(Llid::DataCont),
+                let queued = if self.encryption.may_send(Llid::Control) {
+                    self.procedures.start_queued(&mut payload_writer, rx_end)
+                } else {
+                    None
+                };
+                let header = match queued {
+                    Some(len) => {
+                        let mut header = Header::new(Llid::Control);
+                        header.set_payload_length(len);
+                        header
+                    }
+                    None => match self.consume_tx(&mut payload_writer, rx_end) {
+                        Ok(h) => {
+                            tx_space_freed = true;
+                            h.unwrap_or_else(|| Header::new(Llid::DataCont))
+                        }
+                        Err(_) => self
+                            .ping_if_idle(&mut payload_writer, rx_end)
+                            .unwrap_or_else(|| Header::new(Llid::DataCont)),
+                    },
                 };
 
-                self.send(header, tx);
+                self.send(header, tx, tx_time);
             }
         } else {
             // Last packet not acknowledged, resend.
@@ -246,7 +426,10 @@ impl<C: Config> Connection<C> {
                     self.last_header,
                     self.channel,
                 );
-                trace!("<<RESENT>>");
+                let pl = &tx.tx_payload_buf()[..usize::from(self.last_header.payload_length())];
+                self.capture(Direction::Tx, tx_time, self.last_header, pl, true);
+                self.stats.tx_retransmissions = self.stats.tx_retransmissions.wrapping_add(1);
+                trace!(logger: C::Logger, "<<RESENT>>");
             } else {
                 // We've never received (and thus sent) a data packet before, so we can't
                 // *re*transmit anything. Send empty PDU instead.
@@ -256,40 +439,47 @@ impl<C: Config> Connection<C> {
                 let pdu = Pdu::empty();
                 let mut payload_writer = ByteWriter::new(tx.tx_payload_buf());
                 pdu.to_bytes(&mut payload_writer).unwrap();
-                self.send(Header::new(pdu.llid()), tx);
+                self.send(Header::new(pdu.llid()), tx, tx_time);
             }
         }
 
         let last_channel = self.channel;
 
-        // FIXME: Don't hop if one of the MD bits is set to true (also don't log then)
+        let first_rx = self.event_first_rx.unwrap_or(rx_end);
+        if self.throughput_mode
+            && crc_ok
+            && (header.md() || self.last_header.md())
+            && self.event_has_time(first_rx, rx_end)
         {
-            // Connection event closes
-            self.conn_event_count += Wrapping(1);
+            // One side has more data, continue the connection event on the same channel
+            self.event_first_rx = Some(first_rx);
+            trace!(
+                logger: C::Logger,
+                "#{} DATA({})<- MD, {:?}, {:?}",
+                self.conn_event_count.0,
+                last_channel.index(),
+                header,
+                HexSlice(payload)
+            );
 
-            if let Some(update) = self.update_data.take() {
-                if update.instant() == self.conn_event_count.0 {
-                    // Next conn event will the the first one with these parameters.
-                    let result = self.apply_llcp_update(update, rx_end);
-                    info!("LLCP patch applied: {:?} -> {:?}", update, result);
-                    if let Some(mut cmd) = result {
-                        cmd.queued_work = queued_work;
-                        return Ok(cmd);
-                    }
-                } else {
-                    // Put it back
-                    self.update_data = Some(update);
-                }
-            }
+            return Ok(Cmd {
+                next_update: NextUpdate::At(rx_end + self.md_timeout()),
+                radio: self.listen_data(false),
+                queued_work,
+                tx_space_freed,
+            });
+        }
 
-            // Hop channels after applying LLCP update because it might change the channel map used
-            // by the next event
-            self.hop_channel();
+        if let Some(mut cmd) = self.close_event(first_rx) {
+            cmd.queued_work = queued_work;
+            cmd.tx_space_freed = tx_space_freed;
+            return Ok(cmd);
         }
 
         trace!(
+            logger: C::Logger,
             "#{} DATA({}->{})<- {}{:?}, {:?}",
-            self.conn_event_count,
+            self.conn_event_count.0,
             last_channel.index(),
             self.channel.index(),
             if crc_ok { "" } else { "BADCRC, " },
@@ -298,14 +488,10 @@ impl<C: Config> Connection<C> {
         );
 
         Ok(Cmd {
-            next_update: NextUpdate::At(rx_end + self.conn_event_timeout()),
-            radio: RadioCmd::ListenData {
-                channel: self.channel,
-                access_address: self.access_address,
-                crc_init: self.crc_init,
-                timeout: false,
-            },
+            next_update: NextUpdate::At(first_rx + self.conn_event_timeout()),
+            radio: self.listen_data(false),
             queued_work,
+            tx_space_freed,
         })
     }
 
@@ -313,15 +499,40 @@ impl<C: Config> Connection<C> {
     /// earlier).
     ///
     /// Returns `Err(())` when the connection is closed or lost. In that case, the Link-Layer will
-    /// return to standby state.
+    /// return to standby state, or resume advertising if the connection was never established.
     pub(crate) fn timer_update(&mut self, timer: &mut C::Timer) -> Result<Cmd, ()> {
-        if self.received_packet {
+        self.check_procedure_timeout(timer.now())?;
+
+        if let Some(first_rx) = self.event_first_rx {
+            // The master didn't continue the extended connection event, close it
+            let last_channel = self.channel;
+            let cmd = self.close_event(first_rx).unwrap_or_else(|| Cmd {
+                next_update: NextUpdate::At(first_rx + self.conn_event_timeout()),
+                radio: self.listen_data(false),
+                queued_work: false,
+                tx_space_freed: false,
+            });
+            trace!(
+                logger: C::Logger,
+                "DATA({}->{}): conn event #{} closed",
+                last_channel.index(),
+                self.channel.index(),
+                self.conn_event_count.0,
+            );
+            Ok(cmd)
+        } else if self.received_packet {
             // No packet from master, skip this connection event and listen on the next channel
 
             let last_channel = self.channel;
+            self.stats.channels.record_missed(last_channel);
             self.hop_channel();
             self.conn_event_count += Wrapping(1);
+            self.stats.missed_events = self.stats.missed_events.wrapping_add(1);
+            self.stats.consecutive_missed_events =
+                self.stats.consecutive_missed_events.saturating_add(1);
+            self.log_stats();
             trace!(
+                logger: C::Logger,
                 "DATA({}->{}): missed conn event #{}",
                 last_channel.index(),
                 self.channel.index(),
@@ -330,26 +541,150 @@ impl<C: Config> Connection<C> {
 
             Ok(Cmd {
                 next_update: NextUpdate::At(timer.now() + self.conn_event_timeout()),
-                radio: RadioCmd::ListenData {
-                    channel: self.channel,
-                    access_address: self.access_address,
-                    crc_init: self.crc_init,
-                    timeout: true,
-                },
+                radio: self.listen_data(true),
                 queued_work: false,
+                tx_space_freed: false,
             })
         } else {
-            // Master did not transmit the first packet during this transmit window.
-
-            // TODO: Move the transmit window forward by the `connInterval`.
-            // (do we also need to hop channels here?)
+            // Master did not transmit the first packet in this connection event. Keep listening
+            // during the next ones until `CONN_ESTABLISHMENT_EVENTS` have passed.
 
             self.conn_event_count += Wrapping(1);
-            trace!("missed transmit window");
-            Err(())
+            if self.conn_event_count.0 >= C::CONN_ESTABLISHMENT_EVENTS {
+                trace!(logger: C::Logger, "connection establishment failed");
+                return Err(());
+            }
+
+            let last_channel = self.channel;
+            self.hop_channel();
+            trace!(
+                logger: C::Logger,
+                "DATA({}->{}): missed transmit window #{}",
+                last_channel.index(),
+                self.channel.index(),
+                self.conn_event_count.0,
+            );
+
+            Ok(Cmd {
+                next_update: NextUpdate::At(timer.now() + self.conn_interval),
+                radio: self.listen_data(true),
+                queued_work: false,
+                tx_space_freed: false,
+            })
         }
     }
 
+    /// Returns whether a packet has been received from the master, which establishes the
+    /// connection.
+    pub(crate) fn is_established(&self) -> bool {
+        self.received_packet
+    }
+
+    /// Tears down the connection and returns the packet queues handed to `create`.
+    pub(crate) fn into_queues(self) -> (ConfConsumer<C>, ConfProducer<C>) {
+        (self.tx, self.rx)
+    }
+
+    /// Ends the connection if the peer did not respond to our LLCP procedure in time.
+    fn check_procedure_timeout(&self, now: Instant) -> Result<(), ()> {
+        self.procedures.check_timeout(now).map_err(|procedure| {
+            error!(
+                logger: C::Logger,
+                "LLCP procedure {:?} timed out, closing connection", procedure
+            );
+        })
+    }
+
+    /// Logs a link quality summary every `Config::STATS_LOG_INTERVAL` connection events.
+    fn log_stats(&self) {
+        let interval = C::STATS_LOG_INTERVAL;
+        if interval == 0 || self.conn_event_count.0 % interval != 0 {
+            return;
+        }
+
+        let stats = &self.stats;
+        let worst = stats
+            .channels
+            .worst(STATS_MIN_CHANNEL_EVENTS)
+            .map(|(channel, stats)| (channel.index(), stats.error_rate().unwrap_or(0)));
+        info!(
+            logger: C::Logger,
+            "link quality: {} packets, {} CRC errors, {} missed events, worst channel (index, error %): {:?}",
+            stats.packets_received,
+            stats.crc_errors,
+            stats.missed_events,
+            worst,
+        );
+    }
+
+    /// Closes the current connection event and hops to the channel of the next one.
+    ///
+    /// `first_rx` is the time at which the first packet of the event was received. Returns a `Cmd`
+    /// if an LLCP update was applied that overrides the usual one.
+    fn close_event(&mut self, first_rx: Instant) -> Option<Cmd> {
+        self.event_first_rx = None;
+        self.conn_event_count += Wrapping(1);
+        self.log_stats();
+
+        if let Some(update) = self.update_data.take() {
+            if update.instant() == self.conn_event_count.0 {
+                // Next conn event will the the first one with these parameters.
+                let result = self.apply_llcp_update(update, first_rx);
+                info!(logger: C::Logger, "LLCP patch applied: {:?} -> {:?}", update, result);
+                if result.is_some() {
+                    return result;
+                }
+            } else {
+                // Put it back
+                self.update_data = Some(update);
+            }
+        }
+
+        // Hop channels after applying LLCP update because it might change the channel map used
+        // by the next event
+        self.hop_channel();
+        None
+    }
+
+    /// Returns whether the connection event whose first packet was received at `first_rx` can be
+    /// extended after receiving a packet at `rx_end`.
+    ///
+    /// There must be enough time left for our response, another packet from the master and our
+    /// response to that, before the next connection event starts.
+    fn event_has_time(&self, first_rx: Instant, rx_end: Instant) -> bool {
+        let exchange = Duration::T_IFS + airtime(MIN_DATA_PAYLOAD_BUF);
+        let needed = exchange.saturating_mul(3) + Duration::from_micros(500);
+        rx_end.duration_since(first_rx) + needed < self.conn_interval
+    }
+
+    /// Returns how long to wait for the master's next packet in an extended connection event,
+    /// starting at the end of its last packet.
+    fn md_timeout(&self) -> Duration {
+        let response = airtime(usize::from(self.last_header.payload_length()));
+        Duration::T_IFS.saturating_mul(2)
+            + response
+            + airtime(MIN_DATA_PAYLOAD_BUF)
+            + Duration::from_micros(500)
+    }
+
+    /// Records the anchor point of the current connection event.
+    fn update_anchor(&mut self, anchor: Instant) {
+        if let Some(predicted) = self.predicted_anchor() {
+            let deviation = anchor.raw_micros().wrapping_sub(predicted.raw_micros()) as i32;
+            self.window_widening = Duration::from_micros(deviation.unsigned_abs());
+        }
+        self.last_anchor = Some((anchor, self.conn_event_count.0));
+    }
+
+    /// Returns the expected anchor point of the connection event `conn_event_count`, based on the
+    /// last anchor point.
+    fn predicted_anchor(&self) -> Option<Instant> {
+        self.last_anchor.map(|(anchor, counter)| {
+            let events = u32::from(self.conn_event_count.0.wrapping_sub(counter));
+            anchor + Duration::from_micros(self.conn_interval.as_micros() * events)
+        })
+    }
+
     fn conn_event_timeout(&self) -> Duration {
         // Time out ~500µs after the anchor point of the next conn event.
         self.conn_interval + Duration::from_micros(500)
@@ -357,10 +692,30 @@ impl<C: Config> Connection<C> {
 
     /// Whether we want to send more data during this connection event.
     ///
-    /// Note that this *has to* change to `false` eventually, even if there's more data to be sent,
-    /// because the connection event must close at least `T_IFS` before the next one occurs.
+    /// This is only the case in throughput mode. The connection event is still closed in time for
+    /// the next one, even if there's more data to be sent (see `event_has_time`).
     fn has_more_data(&self) -> bool {
-        false
+        self.throughput_mode && self.tx.has_data()
+    }
+
+    /// Returns the `RadioCmd` to listen for the next packet of this connection.
+    ///
+    /// This reserves space in the RX queue, so that the radio can receive the packet directly into
+    /// it if supported.
+    fn listen_data(&mut self, timeout: bool) -> RadioCmd {
+        // Encrypted PDUs are decrypted into a separate buffer, so they're never received in place
+        self.rx_slot = if matches!(self.cipher, Some(c) if c.rx) {
+            None
+        } else {
+            self.rx.reserve_raw()
+        };
+        RadioCmd::ListenData {
+            channel: self.channel,
+            access_address: self.access_address,
+            crc_init: self.crc_init,
+            timeout,
+            rx_buf: self.rx_slot,
+        }
     }
 
     /// Advances the `unmapped_channel` and `channel` fields to the next data channel on which a
@@ -380,17 +735,207 @@ impl<C: Config> Connection<C> {
         };
     }
 
+    /// Takes the next PDU out of the TX queue and writes its payload to `writer`.
+    ///
+    /// LL Control PDUs that initiate a procedure while another one is in progress are moved into
+    /// the procedure queue instead, in which case `Ok(None)` is returned. PDUs requiring a feature
+    /// the master doesn't support are dropped.
+    ///
+    /// The host answers `LL_ENC_REQ` by queueing `LL_ENC_RSP`, followed by an `LL_START_ENC_REQ`
+    /// carrying the session key. The key is installed here and only the opcode is sent. While the
+    /// encryption procedures are in progress, PDUs that may not be sent stay in the queue, and
+    /// `Err(Error::Eof)` is returned. If a PDU is dropped, `Ok(None)` is returned.
+    fn consume_tx(
+        &mut self,
+        writer: &mut ByteWriter<'_>,
+        now: Instant,
+    ) -> Result<Option<Header>, Error> {
+        let procedures = &mut self.procedures;
+        let features = self.features;
+        let encryption = &mut self.encryption;
+        let cipher = &mut self.cipher;
+        let terminating = &mut self.terminating;
+        self.tx.consume_raw_with(|header, pl| {
+            if !encryption.may_send(header.llid()) {
+                return Consume::never(Err(Error::Eof));
+            }
+
+            if header.llid() == Llid::Control {
+                let opcode = ControlOpcode::from(pl.first().copied().unwrap_or_default());
+                if let (Some(features), Some(required)) = (features, opcode.required_feature()) {
+                    if !features.contains(required) {
+                        warn!(logger: C::Logger, "dropping {:?}, not supported by peer", opcode);
+                        return Consume::always(Ok(None));
+                    }
+                }
+
+                match opcode {
+                    ControlOpcode::StartEncReq => {
+                        let ccm = match (*encryption, Ccm::from_raw(&pl[1..])) {
+                            (Encryption::Requested { .. }, Some(ccm)) => ccm,
+                            _ => {
+                                warn!(logger: C::Logger, "dropping unexpected {:?}", opcode);
+                                return Consume::always(Ok(None));
+                            }
+                        };
+
+                        // The master's PDUs are encrypted from now on
+                        *cipher = Some(Cipher::new(ccm));
+                        *encryption = Encryption::Starting;
+                        writer.write_u8(opcode.into()).expect("TX buf out of space");
+                        let mut header = Header::new(Llid::Control);
+                        header.set_payload_length(1);
+                        return Consume::always(Ok(Some(header)));
+                    }
+                    ControlOpcode::RejectInd | ControlOpcode::RejectIndExt
+                        if matches!(*encryption, Encryption::Requested { .. }) =>
+                    {
+                        // The host has no key for the master's `LL_ENC_REQ`
+                        *encryption = Encryption::Off;
+                        *cipher = None;
+                    }
+                    _ => {}
+                }
+
+                match procedures.start_local(pl, now) {
+                    Ok(true) => {}
+                    Ok(false) => return Consume::always(Ok(None)),
+                    Err(e) => return Consume::never(Err(e)),
+                }
+                if opcode == ControlOpcode::TerminateInd {
+                    *terminating = true;
+                }
+            }
+
+            writer.write_slice(pl).expect("TX buf out of space");
+            #[cfg(feature = "metrics")]
+            crate::metrics::packet_sent(now + Duration::T_IFS);
+            Consume::always(Ok(Some(header)))
+        })
+    }
+
+    /// Writes an `LL_PING_REQ` to `writer` if the connection has been idle for longer than the
+    /// configured share of the supervision timeout (see `set_keep_alive`).
+    ///
+    /// Returns the header of the PDU if one was written.
+    fn ping_if_idle(&mut self, writer: &mut ByteWriter<'_>, now: Instant) -> Option<Header> {
+        if self.keep_alive == 0
+            || !matches!(self.encryption, Encryption::Off | Encryption::On)
+            || self.procedures.local().is_some()
+            || self.procedures.has_queued()
+            || !Self::ping_offered()
+            || self
+                .features
+                .map_or(false, |f| !f.contains(FeatureSet::LE_PING))
+        {
+            return None;
+        }
+
+        let idle = now.checked_duration_since(self.last_activity)?;
+        let limit = u64::from(self.supervision_timeout.as_micros()) * u64::from(self.keep_alive);
+        if u64::from(idle.as_micros()) * 100 < limit {
+            return None;
+        }
+
+        let pdu = ControlPdu::PingReq;
+        pdu.to_bytes(writer).ok()?;
+        self.procedures
+            .start_local(&[pdu.opcode().into()], now)
+            .ok()?;
+        trace!(logger: C::Logger, "keep-alive: idle for {:?}, sending {:?}", idle, pdu);
+
+        let mut header = Header::new(Llid::Control);
+        header.set_payload_length(pdu.encoded_size());
+        Some(header)
+    }
+
     /// Sends a new PDU to the connected device (ie. a non-retransmitted PDU).
-    fn send(&mut self, mut header: Header, tx: &mut C::Transmitter) {
+    fn send(&mut self, mut header: Header, tx: &mut C::Transmitter, tx_time: Instant) {
         header.set_md(self.has_more_data());
         header.set_nesn(self.next_expected_seq_num);
         header.set_sn(self.transmit_seq_num);
+        if header.payload_length() != 0 {
+            if let Some(cipher) = self.cipher.as_mut().filter(|c| c.tx) {
+                header = cipher.encrypt(tx, header);
+            }
+        }
         self.last_header = header;
+        if header.payload_length() != 0 {
+            self.last_activity = tx_time;
+        }
 
         tx.transmit_data(self.access_address, self.crc_init, header, self.channel);
 
         let pl = &tx.tx_payload_buf()[..usize::from(header.payload_length())];
-        trace!("DATA->{:?}, {:?}", header, HexSlice(pl));
+        self.capture(Direction::Tx, tx_time, header, pl, true);
+        trace!(logger: C::Logger, "DATA->{:?}, {:?}", header, HexSlice(pl));
+    }
+
+    /// Decrypts the payload of a PDU sent by the master into `buf` and checks its MIC.
+    ///
+    /// Returns the header and payload of the decrypted PDU, or `Err(())` if the MIC is invalid,
+    /// which ends the connection.
+    fn decrypt<'a>(
+        &self,
+        tx: &mut C::Transmitter,
+        mut header: Header,
+        payload: &[u8],
+        buf: &'a mut [u8; MAX_DATA_PAYLOAD_BUF],
+    ) -> Result<(Header, &'a [u8]), ()> {
+        let cipher = self.cipher.ok_or(())?;
+        let len = payload.len().saturating_sub(MIC_LEN);
+        let (data, mic) = payload.split_at(len);
+        let plain = buf.get_mut(..len).ok_or(())?;
+        plain.copy_from_slice(data);
+        cipher
+            .ccm
+            .decrypt(
+                &mut TransmitterAes(tx),
+                cipher.rx_counter,
+                true,
+                header.to_u16() as u8,
+                plain,
+                mic,
+            )
+            .map_err(|_| error!(logger: C::Logger, "MIC check failed, closing connection"))?;
+
+        header.set_payload_length(len as u8);
+        Ok((header, plain))
+    }
+
+    /// Passes a received PDU on to the host via the RX queue.
+    fn enqueue_rx(&mut self, header: Header, payload: &[u8]) -> Result<(), Error> {
+        let in_place = matches!(self.rx_slot, Some(slot) if slot.contains_payload(payload));
+        if in_place {
+            // The radio received the packet directly into the queue
+            self.rx.commit_raw()
+        } else {
+            self.rx.produce_with(header.payload_length(), |writer| {
+                writer.write_slice(payload)?;
+                Ok(header.llid())
+            })
+        }
+    }
+
+    /// Passes a PDU sent or received on the current data channel to `Config::capture_pdu`.
+    fn capture(
+        &self,
+        direction: Direction,
+        timestamp: Instant,
+        header: Header,
+        payload: &[u8],
+        crc_ok: bool,
+    ) {
+        C::capture_pdu(&CapturedPdu {
+            direction,
+            timestamp,
+            rf_channel: self.channel.rf_channel(),
+            access_address: self.access_address,
+            crc_init: self.crc_init,
+            crc_ok,
+            header: header.to_u16(),
+            payload,
+        });
     }
 
     /// Tries to process and acknowledge an LL Control PDU.
@@ -404,51 +949,183 @@ impl<C: Config> Connection<C> {
     /// # Parameters
     ///
     /// * **`pdu`**: The LL Control PDU (LLCPDU) to process.
+    /// * **`forward`**: Whether the PDU is passed on to the host (see `forwards_to_host`).
     /// * **`can_respond`**: Whether the radio's TX buffer may be overwritten to send a response. If
     ///   this is `false`, this method may choose not to acknowledge the PDU and wait for a
     ///   retransmission instead.
+    /// * **`rx_end`**: Instant at which the PDU was received.
+    /// * **`tx`**: The transmitter, used to honor power change requests.
     fn process_control_pdu(
         &mut self,
         pdu: ControlPdu<'_>,
+        forward: bool,
         can_respond: bool,
+        rx_end: Instant,
+        tx: &mut C::Transmitter,
     ) -> Result<Option<ControlPdu<'static>>, LlcpError> {
+        if let ControlPdu::FeatureRsp { features_used } = pdu {
+            self.features = Some(features_used & Self::offered_features(tx));
+        }
+
+        // Responses to our own procedures never need a response. `LL_CONNECTION_UPDATE_IND`
+        // completes `LL_CONNECTION_PARAM_REQ`, but still has to be applied.
+        if self.procedures.complete_local(&pdu) {
+            info!(logger: C::Logger, "LLCP procedure completed by {:?}", pdu);
+            if !matches!(pdu, ControlPdu::ConnectionUpdateReq(_)) {
+                return Ok(None);
+            }
+        }
+
         let response = match pdu {
             ControlPdu::ConnectionUpdateReq(data) => {
-                self.prepare_llcp_update(LlcpUpdate::ConnUpdate(*data))?;
+                if data.interval() < Duration::from_micros(7_500)
+                    || data.interval() > Duration::from_secs(4)
+                {
+                    error!(logger: C::Logger, "invalid connection interval {:?}", data.interval());
+                    return Err(LlcpError::ConnectionLost);
+                }
+
+                self.prepare_llcp_update(LlcpUpdate::ConnUpdate(*data), rx_end)?;
                 return Ok(None);
             }
             ControlPdu::ChannelMapReq(req) => {
-                self.prepare_llcp_update(LlcpUpdate::ChannelMap {
-                    map: req.map.value(),
-                    instant: req.instant,
-                })?;
+                // The spec requires at least `ChannelMap::MIN_USED_CHANNELS` (2) used channels
+                if !req.map.value().is_valid() {
+                    error!(logger: C::Logger, "invalid channel map {:?}", req.map.value());
+                    return Err(LlcpError::ConnectionLost);
+                }
+
+                self.prepare_llcp_update(
+                    LlcpUpdate::ChannelMap {
+                        map: req.map.value(),
+                        instant: req.instant,
+                    },
+                    rx_end,
+                )?;
                 return Ok(None);
             }
             ControlPdu::TerminateInd { error_code } => {
                 info!(
+                    logger: C::Logger,
                     "closing connection due to termination request: code {:?}",
                     error_code
                 );
                 return Err(LlcpError::ConnectionLost);
             }
-            ControlPdu::FeatureReq { features_master } => ControlPdu::FeatureRsp {
-                features_used: features_master & FeatureSet::supported(),
-            },
-            ControlPdu::VersionInd { .. } => {
-                // FIXME this should be something real, and defined somewhere else
-                let comp_id = 0xFFFF;
-                // FIXME this should correlate with the Cargo package version
-                let sub_vers_nr = 0x0000;
+            ControlPdu::FeatureReq { features_master } => {
+                let features_used = features_master & Self::offered_features(tx);
+                self.features = Some(features_used);
+
+                ControlPdu::FeatureRsp { features_used }
+            }
+            ControlPdu::PowerControlReq { phy, delta, .. } => {
+                let offered =
+                    Self::offered_features(tx).contains(FeatureSet::LE_POWER_CONTROL_REQUEST);
+                match tx.tx_power_range().filter(|_| offered) {
+                    // Power control is only supported on the LE 1M PHY, which is all we can use
+                    Some((min, max)) if phy == 0b001 => {
+                        let target = if delta == POWER_DELTA_MAX {
+                            max
+                        } else {
+                            self.tx_power.saturating_add(delta).max(min).min(max)
+                        };
+                        let old = self.tx_power;
+                        self.tx_power = tx.set_tx_power(target);
+
+                        ControlPdu::PowerControlRsp {
+                            min: self.tx_power <= min,
+                            max: self.tx_power >= max,
+                            delta: self.tx_power.wrapping_sub(old),
+                            tx_power: self.tx_power,
+                            apr: 0xFF,
+                        }
+                    }
+                    Some(_) => ControlPdu::PowerControlRsp {
+                        min: false,
+                        max: false,
+                        delta: 0,
+                        tx_power: POWER_NOT_AVAILABLE,
+                        apr: 0xFF,
+                    },
+                    // Power control isn't offered, or the transmitter can't change its power
+                    None => ControlPdu::UnknownRsp {
+                        unknown_type: pdu.opcode(),
+                    },
+                }
+            }
+            ControlPdu::PingReq if Self::ping_offered() => ControlPdu::PingRsp,
+            ControlPdu::PowerControlRsp { .. } | ControlPdu::PowerChangeInd { .. } => {
+                // Responses to our power hints and changes of the peer's power are informational
+                return Ok(None);
+            }
+            ControlPdu::VersionInd {
+                vers_nr,
+                comp_id,
+                sub_vers_nr,
+            } => {
+                // Only one `LL_VERSION_IND` may be sent per connection, so repeated exchanges
+                // aren't answered again
+                let answered = self.peer_version.is_some();
+                self.peer_version = Some(VersionInfo {
+                    version: vers_nr,
+                    company: comp_id,
+                    subversion: sub_vers_nr.0,
+                });
+                if answered {
+                    return Ok(None);
+                }
 
                 ControlPdu::VersionInd {
                     vers_nr: BLUETOOTH_VERSION,
-                    comp_id: CompanyId::from_raw(comp_id),
-                    sub_vers_nr: Hex(sub_vers_nr),
+                    comp_id: C::COMPANY_ID,
+                    sub_vers_nr: Hex(C::SUBVERSION),
                 }
             }
-            _ => ControlPdu::UnknownRsp {
-                unknown_type: pdu.opcode(),
-            },
+            ControlPdu::EncReq { .. } if forward => {
+                // The host answers with `LL_ENC_RSP` and the session key, or rejects the request
+                let refresh = self.encryption == Encryption::Paused;
+                self.encryption = Encryption::Requested { refresh };
+                return Ok(None);
+            }
+            ControlPdu::StartEncRsp if forward => {
+                if !can_respond {
+                    return Err(LlcpError::NoSpace);
+                }
+
+                // Our response is the first PDU we encrypt
+                if let Some(cipher) = &mut self.cipher {
+                    cipher.tx = true;
+                }
+                self.encryption = Encryption::On;
+                ControlPdu::StartEncRsp
+            }
+            ControlPdu::PauseEncReq if self.encryption == Encryption::On => {
+                if !can_respond {
+                    return Err(LlcpError::NoSpace);
+                }
+
+                // Our response is still encrypted, but the master's next PDUs aren't
+                if let Some(cipher) = &mut self.cipher {
+                    cipher.rx = false;
+                }
+                self.encryption = Encryption::Pausing;
+                ControlPdu::PauseEncRsp
+            }
+            ControlPdu::PauseEncRsp if forward => {
+                if let Some(cipher) = &mut self.cipher {
+                    cipher.tx = false;
+                }
+                self.encryption = Encryption::Paused;
+                return Ok(None);
+            }
+            _ if pdu.opcode().is_response() => {
+                // Unsolicited responses (or ones to procedures that already timed out) are ignored
+                return Ok(None);
+            }
+            _ => llcp::reject(
+                pdu.opcode(),
+                self.features.unwrap_or_else(FeatureSet::empty),
+            ),
         };
 
         // If we land here, we have a PDU we want to send
@@ -459,18 +1136,59 @@ impl<C: Config> Connection<C> {
         }
     }
 
+    /// Returns the features we offer to the master.
+    ///
+    /// These are the features in `Config::FEATURES` that are available with `tx`. `LE_PING` is
+    /// only offered together with `LE_ENCRYPTION` (see `FeatureSet::offerable`).
+    fn offered_features(tx: &C::Transmitter) -> FeatureSet {
+        (C::FEATURES & FeatureSet::available(tx)).offerable()
+    }
+
+    /// Returns whether we offer the *LE Ping* procedure.
+    ///
+    /// Unlike `offered_features`, this doesn't depend on the transmitter.
+    fn ping_offered() -> bool {
+        (C::FEATURES & FeatureSet::supported())
+            .offerable()
+            .contains(FeatureSet::LE_PING)
+    }
+
+    /// Returns whether the LL Control PDU `pdu` is passed on to the host via the RX queue.
+    ///
+    /// The host answers `LL_ENC_REQ` with the session key, and is informed once encryption has been
+    /// enabled or paused, so that it can update the security level of the link.
+    fn forwards_to_host(&self, pdu: &ControlPdu<'_>, tx: &C::Transmitter) -> bool {
+        match pdu {
+            ControlPdu::EncReq { .. } => {
+                Self::offered_features(tx).contains(FeatureSet::LE_ENCRYPTION)
+                    && matches!(self.encryption, Encryption::Off | Encryption::Paused)
+            }
+            ControlPdu::StartEncRsp => self.encryption == Encryption::Starting,
+            ControlPdu::PauseEncRsp => self.encryption == Encryption::Pausing,
+            _ => false,
+        }
+    }
+
     /// Stores `update` in the link layer state so that it will be applied once its *instant* is
     /// reached.
-    fn prepare_llcp_update(&mut self, update: LlcpUpdate) -> Result<(), LlcpError> {
+    ///
+    /// This starts a procedure initiated by the peer, which completes at the instant.
+    fn prepare_llcp_update(
+        &mut self,
+        update: LlcpUpdate,
+        rx_end: Instant,
+    ) -> Result<(), LlcpError> {
         // TODO: check that instant is <32767 in the future
         if let Some(data) = self.update_data {
             error!(
+                logger: C::Logger,
                 "got update data {:?} while update {:?} is already queued",
                 update, data
             );
             Err(LlcpError::ConnectionLost)
         } else {
             self.update_data = Some(update);
+            self.procedures.start_remote(update.opcode(), rx_end);
             Ok(())
         }
     }
@@ -480,10 +1198,14 @@ impl<C: Config> Connection<C> {
     /// Returns a `Cmd` when the usual Link Layer `Cmd` should be overridden. In that case, this
     /// method must also perform channel hopping.
     fn apply_llcp_update(&mut self, update: LlcpUpdate, rx_end: Instant) -> Option<Cmd> {
+        self.procedures.complete_remote();
         match update {
             LlcpUpdate::ConnUpdate(data) => {
                 let old_conn_interval = self.conn_interval;
                 self.conn_interval = data.interval();
+                self.slave_latency = data.latency();
+                self.supervision_timeout = data.timeout();
+                self.last_anchor = None;
 
                 self.hop_channel();
 
@@ -493,14 +1215,10 @@ impl<C: Config> Connection<C> {
                         rx_end + old_conn_interval + data.win_offset() + data.win_size(),
                     ),
                     // Listen for the transmit window
-                    radio: RadioCmd::ListenData {
-                        channel: self.channel,
-                        access_address: self.access_address,
-                        crc_init: self.crc_init,
-                        timeout: false,
-                    },
+                    radio: self.listen_data(false),
                     // This function never queues work, but the caller might change this to `true`
                     queued_work: false,
+                    tx_space_freed: false,
                 })
             }
             LlcpUpdate::ChannelMap { map, .. } => {
@@ -525,6 +1243,243 @@ impl<C: Config> Connection<C> {
     pub fn connection_interval(&self) -> Duration {
         self.conn_interval
     }
+
+    /// Returns the number of connection events the Peripheral is allowed to skip.
+    pub fn slave_latency(&self) -> u16 {
+        self.slave_latency
+    }
+
+    /// Returns the supervision timeout of the connection.
+    pub fn supervision_timeout(&self) -> Duration {
+        self.supervision_timeout
+    }
+
+    /// Returns the device address of the connected peer (the Central).
+    pub fn peer_address(&self) -> &DeviceAddress {
+        &self.peer_addr
+    }
+
+    /// Returns whether the connection is encrypted.
+    ///
+    /// Encryption is started by the Central with `LL_ENC_REQ`, which the `Responder` answers using
+    /// the key provided by the `SecurityManager`.
+    pub fn is_encrypted(&self) -> bool {
+        self.encryption == Encryption::On
+    }
+
+    /// Returns the handle identifying this connection.
+    pub fn handle(&self) -> ConnectionHandle {
+        self.handle
+    }
+
+    /// Returns the connected peer, to be passed to `Responder::connected`.
+    ///
+    /// The peer is identified by its address. Bonded devices using a Resolvable Private Address
+    /// have to be recognized with `Peer::resolve`.
+    pub fn peer(&self) -> Peer {
+        Peer::new(self.handle, self.peer_addr)
+    }
+
+    /// Enables or disables throughput mode.
+    ///
+    /// In throughput mode, the `MD` (More Data) bit is set on outgoing packets as long as the TX
+    /// queue contains more packets, and connection events are extended to exchange several packets
+    /// as long as either side has more data and there is time left before the next event. No
+    /// connection events are skipped, even if the slave latency would allow it.
+    ///
+    /// This increases power consumption, so it should only be enabled while transferring large
+    /// amounts of data (eg. during a firmware update) and disabled once the burst completes.
+    pub fn set_throughput_mode(&mut self, enabled: bool) {
+        self.throughput_mode = enabled;
+    }
+
+    /// Returns whether throughput mode is enabled (see `set_throughput_mode`).
+    pub fn throughput_mode(&self) -> bool {
+        self.throughput_mode
+    }
+
+    /// Configures the keep-alive of this connection.
+    ///
+    /// Rubble answers every packet of the Central, so the connection doesn't time out while idle.
+    /// However, some Centrals drop connections on which no data is exchanged for a while. With the
+    /// keep-alive enabled, an `LL_PING_REQ` is sent once no non-empty PDU has been sent or received
+    /// for `percent` percent of the supervision timeout, which the Central has to answer with an
+    /// `LL_PING_RSP`.
+    ///
+    /// Pings are only sent if `Config::FEATURES` contains `LE_PING` and `LE_ENCRYPTION`, and the
+    /// Central supports `LE_PING` too (or the features haven't been exchanged yet). Otherwise, the connection is only kept
+    /// alive by empty PDUs. A value of 0 disables the keep-alive, which is the default for every
+    /// new connection.
+    ///
+    /// # Panics
+    ///
+    /// This will panic if `percent` is 100 or more, since the connection would already have timed
+    /// out by then.
+    pub fn set_keep_alive(&mut self, percent: u8) {
+        assert!(
+            percent < 100,
+            "keep-alive must be sent before the supervision timeout"
+        );
+        self.keep_alive = percent;
+    }
+
+    /// Returns the keep-alive configured via `set_keep_alive`, in percent of the supervision
+    /// timeout.
+    pub fn keep_alive(&self) -> u8 {
+        self.keep_alive
+    }
+
+    /// Returns the Link-Layer features used on this connection.
+    ///
+    /// These are the features supported by both devices and enabled via `Config::FEATURES`. This
+    /// returns `None` until the features have been exchanged. Afterwards, procedures requiring
+    /// features the peer doesn't support are not initiated.
+    pub fn features(&self) -> Option<FeatureSet> {
+        self.features
+    }
+
+    /// Returns the version information the peer sent in `LL_VERSION_IND`.
+    ///
+    /// This returns `None` until the peer has initiated the version exchange. Rubble answers with
+    /// the values configured via `Config::COMPANY_ID` and `Config::SUBVERSION`.
+    pub fn peer_version(&self) -> Option<VersionInfo> {
+        self.peer_version
+    }
+
+    /// Returns link quality statistics collected since the connection was established.
+    pub fn stats(&self) -> &ConnectionStats {
+        &self.stats
+    }
+
+    /// Records the round-trip time measured with an L2CAP `EchoProbe`, so that it is reported in
+    /// `ConnectionStats::echo_rtt`.
+    pub fn record_echo_rtt(&mut self, rtt: Duration) {
+        self.stats.echo_rtt = Some(rtt);
+    }
+
+    /// Returns timing information about the connection.
+    ///
+    /// This can be used to schedule work shortly before the next connection event, so that its
+    /// results can be sent with minimal latency.
+    pub fn info(&self) -> ConnectionInfo {
+        ConnectionInfo {
+            anchor: self.last_anchor.map(|(anchor, _)| anchor),
+            next_event: self.predicted_anchor(),
+            event_counter: self.conn_event_count.0,
+            channel: self.channel,
+            window_widening: self.window_widening,
+        }
+    }
+}
+
+/// Returns the time needed to transmit a data channel PDU with a `payload_len`-Byte payload on the
+/// LE 1M PHY.
+fn airtime(payload_len: usize) -> Duration {
+    // Preamble, Access Address, header, payload and CRC at 1 µs per bit
+    Duration::from_micros((1 + 4 + 2 + payload_len as u32 + 3) * 8)
+}
+
+/// A snapshot of the timing of a connection, returned by `Connection::info`.
+///
+/// The anchor point of a connection event is the time at which the Central starts transmitting its
+/// first packet in that event. Anchor points are measured from received packets, and are only
+/// available once a packet has been received after the connection was established or its
+/// parameters were updated.
+#[derive(Debug, Copy, Clone, defmt::Format)]
+pub struct ConnectionInfo {
+    /// Anchor point of the last connection event in which a packet was received.
+    pub anchor: Option<Instant>,
+
+    /// Expected anchor point of the next connection event.
+    ///
+    /// The Peripheral will start listening for the Central's packet shortly before this.
+    pub next_event: Option<Instant>,
+
+    /// Connection event counter (`connEventCounter`) of the next connection event.
+    pub event_counter: u16,
+
+    /// Data channel used by the next connection event.
+    pub channel: DataChannel,
+
+    /// Deviation of the last anchor point from the time predicted by the anchor point before it.
+    ///
+    /// This is caused by the combined clock drift of both devices, and is the amount of window
+    /// widening that was needed to receive the last packet.
+    pub window_widening: Duration,
+}
+
+/// Link quality counters of a connection.
+///
+/// All counters start at 0 when the connection is established and wrap around on overflow. To
+/// compute rates over a period of time, applications can keep a copy and compare it to a later
+/// snapshot.
+///
+/// Outside of throughput mode, every connection event consists of a single packet exchange, so
+/// each received packet corresponds to one connection event.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq, defmt::Format)]
+pub struct ConnectionStats {
+    /// Number of data channel packets received from the Central, including corrupted ones.
+    pub packets_received: u32,
+
+    /// Number of received packets that failed the CRC check.
+    ///
+    /// A high rate of CRC errors indicates interference or a weak signal. Using smaller payloads
+    /// can help in that case.
+    pub crc_errors: u32,
+
+    /// Number of packets that the Central retransmitted because it didn't receive our
+    /// acknowledgement (detected via the `SN` bit).
+    pub rx_retransmissions: u32,
+
+    /// Number of packets we retransmitted because the Central didn't acknowledge them (detected via
+    /// the `NESN` bit), or because its packet was corrupted.
+    pub tx_retransmissions: u32,
+
+    /// Number of received packets that were not acknowledged because the RX packet queue was full.
+    ///
+    /// If this is high, the application doesn't process incoming packets fast enough.
+    pub rx_overflows: u32,
+
+    /// Number of connection events in which no packet was received before the event timed out.
+    ///
+    /// This means that the anchor point of the connection event was missed.
+    pub missed_events: u32,
+
+    /// Number of connection events missed in a row, up to now.
+    ///
+    /// If this keeps increasing, the connection will be lost once the supervision timeout expires.
+    pub consecutive_missed_events: u16,
+
+    /// Reception statistics per data channel.
+    ///
+    /// Interference from Wi-Fi or other 2.4 GHz sources shows up as high error rates on a few
+    /// adjacent channels, while a weak signal affects all channels alike.
+    pub channels: ChannelQuality,
+
+    /// Round-trip time of the last L2CAP *Echo Request*, as recorded by
+    /// [`Connection::record_echo_rtt`].
+    ///
+    /// Unlike the other statistics, this is measured by the host, and includes the time the
+    /// packets spent in the packet queues on this device.
+    ///
+    /// [`Connection::record_echo_rtt`]: struct.Connection.html#method.record_echo_rtt
+    pub echo_rtt: Option<Duration>,
+}
+
+impl ConnectionStats {
+    /// Returns the share of received packets that failed the CRC check, in percent.
+    ///
+    /// Returns `None` if no packets were received yet. If this stays high, and
+    /// [`channels`](#structfield.channels) doesn't show that only a few channels are affected, the
+    /// link is likely too weak, and moving the devices closer (or reconnecting with a higher
+    /// transmit power) might help.
+    pub fn crc_error_rate(&self) -> Option<u8> {
+        if self.packets_received == 0 {
+            None
+        } else {
+            Some((u64::from(self.crc_errors) * 100 / u64::from(self.packets_received)) as u8)
+        }
+    }
 }
 
 #[derive(Debug, Copy, Clone)]
@@ -537,7 +1492,7 @@ enum LlcpError {
 }
 
 /// A Link-Layer state update that may be applied with a delay.
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, defmt::Format)]
 enum LlcpUpdate {
     /// Update connection parameters and await the configured transmit window.
     ///
@@ -563,4 +1518,123 @@ impl LlcpUpdate {
             LlcpUpdate::ChannelMap { instant, .. } => *instant,
         }
     }
+
+    /// Returns the opcode of the LL Control PDU that requested this update.
+    fn opcode(&self) -> ControlOpcode {
+        match self {
+            LlcpUpdate::ConnUpdate(_) => ControlOpcode::ConnectionUpdateReq,
+            LlcpUpdate::ChannelMap { .. } => ControlOpcode::ChannelMapReq,
+        }
+    }
+}
+
+/// Progress of the *Encryption Start* and *Encryption Pause* procedures.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum Encryption {
+    /// The link is not encrypted.
+    Off,
+
+    /// The master sent `LL_ENC_REQ`, which was passed on to the host. The host queues
+    /// `LL_ENC_RSP` followed by the session key, or rejects the request.
+    ///
+    /// `refresh` is set if the link was encrypted before and encryption was paused to change the
+    /// key.
+    Requested { refresh: bool },
+
+    /// `LL_START_ENC_REQ` was sent. Received PDUs are decrypted, but ours are still sent
+    /// unencrypted until the master's `LL_START_ENC_RSP` arrives.
+    Starting,
+
+    /// The link is encrypted in both directions.
+    On,
+
+    /// The master sent `LL_PAUSE_ENC_REQ` and we answered with an encrypted `LL_PAUSE_ENC_RSP`.
+    /// Received PDUs are no longer decrypted.
+    Pausing,
+
+    /// Encryption was paused to change the key, and the master will restart it with `LL_ENC_REQ`.
+    Paused,
+}
+
+impl Encryption {
+    /// Returns whether a PDU with `llid` may be taken out of the TX queue.
+    ///
+    /// While the key is being changed, data stays queued until the link is encrypted again, so
+    /// that it isn't sent in plain text. LL Control PDUs are still sent, since the host's answer to
+    /// `LL_ENC_REQ` is among them. Note that this can't overtake data queued before it: If the host
+    /// queues data after the master paused encryption, the key refresh stalls until the master
+    /// gives up and ends the connection.
+    fn may_send(self, llid: Llid) -> bool {
+        match self {
+            Encryption::Off | Encryption::On | Encryption::Requested { refresh: false } => true,
+            // Only the master's `LL_START_ENC_RSP` may be answered
+            Encryption::Starting => false,
+            Encryption::Requested { refresh: true } | Encryption::Pausing | Encryption::Paused => {
+                llid == Llid::Control
+            }
+        }
+    }
+}
+
+/// Session key and packet counters of an encrypted connection.
+#[derive(Copy, Clone)]
+struct Cipher {
+    ccm: Ccm,
+
+    /// Whether received PDUs are decrypted.
+    rx: bool,
+
+    /// Whether sent PDUs are encrypted.
+    tx: bool,
+
+    /// Packet counter of the next new PDU received from the master.
+    rx_counter: u64,
+
+    /// Packet counter of the next new PDU we send.
+    tx_counter: u64,
+}
+
+impl Cipher {
+    /// Creates a cipher that decrypts received PDUs, but doesn't encrypt ours yet.
+    fn new(ccm: Ccm) -> Self {
+        Self {
+            ccm,
+            rx: true,
+            tx: false,
+            rx_counter: 0,
+            tx_counter: 0,
+        }
+    }
+
+    /// Encrypts the payload in the TX buffer of `tx` and appends the MIC.
+    ///
+    /// Returns `header` with the payload length updated to include the MIC.
+    fn encrypt<T: Transmitter>(&mut self, tx: &mut T, mut header: Header) -> Header {
+        let len = usize::from(header.payload_length());
+        let mut buf = [0; MAX_DATA_PAYLOAD_BUF];
+        buf[..len].copy_from_slice(&tx.tx_payload_buf()[..len]);
+        let mic = self.ccm.encrypt(
+            &mut TransmitterAes(tx),
+            self.tx_counter,
+            false,
+            header.to_u16() as u8,
+            &mut buf[..len],
+        );
+        self.tx_counter += 1;
+
+        let payload = tx.tx_payload_buf();
+        payload[..len].copy_from_slice(&buf[..len]);
+        payload[len..len + MIC_LEN].copy_from_slice(&mic);
+        header.set_payload_length((len + MIC_LEN) as u8);
+        header
+    }
+}
+
+/// Makes the AES implementation of a `Transmitter` usable by the `ccm` module.
+struct TransmitterAes<'a, T>(&'a mut T);
+
+impl<T: Transmitter> AesProvider for TransmitterAes<'_, T> {
+    fn encrypt_block(&mut self, key: &[u8; 16], block: &mut [u8; 16]) {
+        Transmitter::encrypt_block(self.0, key, block)
+    }
 }
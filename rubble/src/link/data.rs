@@ -60,6 +60,7 @@ use core::{convert::TryInto, fmt};
 /// not equal to `nextExpectedSeqNum`, this packet is a retransmission, so `nextExpectedSeqNum`
 /// should not be changed.
 #[derive(Copy, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 pub struct Header(u16);
 
 impl Header {
@@ -174,6 +175,20 @@ impl fmt::Debug for Header {
     }
 }
 
+impl defmt::Format for Header {
+    fn format(&self, fmt: defmt::Formatter<'_>) {
+        defmt::write!(
+            fmt,
+            "Header {{ LLID: {}, NESN: {}, SN: {}, MD: {=bool}, Length: {=u8} }}",
+            self.llid(),
+            self.nesn(),
+            self.sn(),
+            self.md(),
+            self.payload_length(),
+        );
+    }
+}
+
 impl<'a> FromBytes<'a> for Header {
     fn from_bytes(bytes: &mut ByteReader<'a>) -> Result<Self, Error> {
         let raw = bytes.read_u16_le()?;
@@ -188,7 +203,8 @@ impl ToBytes for Header {
 }
 
 /// Values of the LLID field in `Header`.
-#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, defmt::Format)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum Llid {
     /// Reserved for future use.
     Reserved = 0b00,
@@ -204,7 +220,8 @@ pub enum Llid {
 }
 
 /// Structured representation of a data channel PDU.
-#[derive(Debug)]
+#[derive(Debug, defmt::Format)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 pub enum Pdu<'a, L> {
     /// Continuation of an L2CAP message (or empty PDU).
     DataCont { message: L },
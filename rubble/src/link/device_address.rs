@@ -1,7 +1,30 @@
+//! Bluetooth device addresses.
+//!
+//! Besides the plain [`DeviceAddress`], this module provides newtypes for the 3 kinds of random
+//! addresses defined by the specification. Their constructors enforce the required bit patterns,
+//! so a value of one of these types is always a valid address of that kind:
+//!
+//! * [`StaticAddress`]: A random address that stays the same at least until the device is power
+//!   cycled. Its 2 most significant bits are `0b11`.
+//! * [`NonResolvableAddress`]: A private address that can not be traced back to the device. Its 2
+//!   most significant bits are `0b00`.
+//! * [`RpaAddress`]: A *Resolvable Private Address* that can be recognized by peers knowing the
+//!   device's **I**dentity **R**esolving **K**ey (IRK). Its 2 most significant bits are `0b01`.
+//!
+//! [`DeviceAddress`]: struct.DeviceAddress.html
+//! [`StaticAddress`]: struct.StaticAddress.html
+//! [`NonResolvableAddress`]: struct.NonResolvableAddress.html
+//! [`RpaAddress`]: struct.RpaAddress.html
+
+use crate::security::{toolbox::ah, AesProvider};
+use crate::Error;
+use core::convert::TryFrom;
 use core::fmt;
+use rand_core::{CryptoRng, RngCore};
 
 /// Specifies whether a device address is randomly generated or a LAN MAC address.
-#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+#[derive(Copy, Clone, Eq, PartialEq, Debug, defmt::Format)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum AddressKind {
     /// Publicly registered IEEE 802-2001 LAN MAC address.
     Public,
@@ -11,6 +34,7 @@ pub enum AddressKind {
 
 /// A Bluetooth device address.
 #[derive(Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct DeviceAddress {
     bytes: [u8; 6],
     kind: AddressKind,
@@ -20,7 +44,7 @@ impl DeviceAddress {
     /// Create a new device address from 6 raw Bytes and an address kind specifier.
     ///
     /// The `bytes` array contains the address Bytes as they are sent over the air (LSB first).
-    pub fn new(bytes: [u8; 6], kind: AddressKind) -> Self {
+    pub const fn new(bytes: [u8; 6], kind: AddressKind) -> Self {
         DeviceAddress { bytes, kind }
     }
 
@@ -38,6 +62,23 @@ impl DeviceAddress {
     pub fn raw(&self) -> &[u8; 6] {
         &self.bytes
     }
+
+    /// Returns the kind of random address this is, as indicated by its 2 most significant bits.
+    ///
+    /// Returns `None` for public addresses and random addresses using the reserved bit pattern
+    /// `0b10`. This does not check whether the rest of the address is valid for its kind.
+    pub fn random_kind(&self) -> Option<RandomAddressKind> {
+        if !self.is_random() {
+            return None;
+        }
+
+        match self.bytes[5] >> 6 {
+            0b00 => Some(RandomAddressKind::NonResolvable),
+            0b01 => Some(RandomAddressKind::Resolvable),
+            0b11 => Some(RandomAddressKind::Static),
+            _ => None,
+        }
+    }
 }
 
 impl fmt::Debug for DeviceAddress {
@@ -47,6 +88,23 @@ impl fmt::Debug for DeviceAddress {
     }
 }
 
+impl defmt::Format for DeviceAddress {
+    fn format(&self, fmt: defmt::Formatter<'_>) {
+        let b = &self.bytes;
+        defmt::write!(
+            fmt,
+            "DeviceAddress({=u8:02x}:{=u8:02x}:{=u8:02x}:{=u8:02x}:{=u8:02x}:{=u8:02x}, {})",
+            b[5],
+            b[4],
+            b[3],
+            b[2],
+            b[1],
+            b[0],
+            self.kind
+        );
+    }
+}
+
 impl fmt::Display for DeviceAddress {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         // Note: Bluetooth device addresses are usually displayed with MSB
@@ -62,9 +120,304 @@ impl fmt::Display for DeviceAddress {
     }
 }
 
+/// The kinds of random device addresses.
+#[derive(Copy, Clone, Eq, PartialEq, Debug, defmt::Format)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum RandomAddressKind {
+    /// A static address (`StaticAddress`).
+    Static,
+    /// A non-resolvable private address (`NonResolvableAddress`).
+    NonResolvable,
+    /// A resolvable private address (`RpaAddress`).
+    Resolvable,
+}
+
+/// Returns whether the lowest `bits` bits of the little-endian `bytes` contain at least one 0 and
+/// one 1, as required for the random part of all random addresses.
+fn random_part_valid(bytes: &[u8], bits: u32) -> bool {
+    let mut buf = [0; 8];
+    buf[..bytes.len()].copy_from_slice(bytes);
+    let mask = (1 << bits) - 1;
+    let random = u64::from_le_bytes(buf) & mask;
+    random != 0 && random != mask
+}
+
+/// Validates a random address of the given `kind`.
+fn check_random(address: &DeviceAddress, kind: RandomAddressKind) -> Result<(), Error> {
+    if address.random_kind() != Some(kind) {
+        return Err(Error::InvalidValue);
+    }
+
+    let valid = match kind {
+        RandomAddressKind::Static | RandomAddressKind::NonResolvable => {
+            random_part_valid(address.raw(), 46)
+        }
+        RandomAddressKind::Resolvable => random_part_valid(&address.raw()[3..], 22),
+    };
+
+    if valid {
+        Ok(())
+    } else {
+        Err(Error::InvalidValue)
+    }
+}
+
+/// Generates a random address of the given `kind` (which must not be `Resolvable`).
+fn generate_random<R: RngCore + CryptoRng>(rng: &mut R, kind: RandomAddressKind) -> DeviceAddress {
+    let msbs = match kind {
+        RandomAddressKind::Static => 0b11,
+        RandomAddressKind::NonResolvable => 0b00,
+        RandomAddressKind::Resolvable => unreachable!(),
+    };
+
+    loop {
+        let mut bytes = [0; 6];
+        rng.fill_bytes(&mut bytes);
+        bytes[5] = (bytes[5] & 0x3F) | msbs << 6;
+        let address = DeviceAddress::new(bytes, AddressKind::Random);
+        if check_random(&address, kind).is_ok() {
+            return address;
+        }
+    }
+}
+
+/// A random static device address.
+///
+/// Static addresses may be generated on every power cycle, or stored persistently. They must not
+/// change while the device is powered.
+#[derive(Copy, Clone, PartialEq, Eq, Debug, defmt::Format)]
+pub struct StaticAddress(DeviceAddress);
+
+impl StaticAddress {
+    /// Generates a new random static address.
+    pub fn generate<R: RngCore + CryptoRng>(rng: &mut R) -> Self {
+        StaticAddress(generate_random(rng, RandomAddressKind::Static))
+    }
+
+    /// Creates a static address from 6 raw Bytes (LSB first).
+    ///
+    /// Returns `Error::InvalidValue` if the 2 most significant bits are not `0b11`, or if the
+    /// remaining bits are all 0 or all 1.
+    pub fn from_bytes(bytes: [u8; 6]) -> Result<Self, Error> {
+        Self::try_from(DeviceAddress::new(bytes, AddressKind::Random))
+    }
+
+    /// Returns the raw bytes making up this address (LSB first).
+    pub fn raw(&self) -> &[u8; 6] {
+        self.0.raw()
+    }
+}
+
+/// A random non-resolvable private address.
+///
+/// The specification additionally requires that this address is not equal to the public address
+/// of the device, which is not checked here.
+#[derive(Copy, Clone, PartialEq, Eq, Debug, defmt::Format)]
+pub struct NonResolvableAddress(DeviceAddress);
+
+impl NonResolvableAddress {
+    /// Generates a new random non-resolvable private address.
+    pub fn generate<R: RngCore + CryptoRng>(rng: &mut R) -> Self {
+        NonResolvableAddress(generate_random(rng, RandomAddressKind::NonResolvable))
+    }
+
+    /// Creates a non-resolvable private address from 6 raw Bytes (LSB first).
+    ///
+    /// Returns `Error::InvalidValue` if the 2 most significant bits are not `0b00`, or if the
+    /// remaining bits are all 0 or all 1.
+    pub fn from_bytes(bytes: [u8; 6]) -> Result<Self, Error> {
+        Self::try_from(DeviceAddress::new(bytes, AddressKind::Random))
+    }
+
+    /// Returns the raw bytes making up this address (LSB first).
+    pub fn raw(&self) -> &[u8; 6] {
+        self.0.raw()
+    }
+}
+
+/// A resolvable private address (RPA).
+///
+/// An RPA consists of a 24-bit random part `prand` in the upper 3 Bytes, whose 2 most significant
+/// bits are `0b01`, and a 24-bit `hash` of `prand` and the IRK in the lower 3 Bytes.
+#[derive(Copy, Clone, PartialEq, Eq, Debug, defmt::Format)]
+pub struct RpaAddress(DeviceAddress);
+
+impl RpaAddress {
+    /// Generates a new resolvable private address from the Identity Resolving Key `irk`.
+    pub fn generate<R: RngCore + CryptoRng>(
+        irk: u128,
+        aes: &mut dyn AesProvider,
+        rng: &mut R,
+    ) -> Self {
+        let prand = loop {
+            let random = rng.next_u32() & 0x3F_FFFF;
+            // The random part must not be all 0s or all 1s
+            if random != 0 && random != 0x3F_FFFF {
+                break random | 0x40_0000;
+            }
+        };
+
+        let hash = ah(aes, irk, prand);
+        let mut bytes = [0; 6];
+        bytes[..3].copy_from_slice(&hash.to_le_bytes()[..3]);
+        bytes[3..].copy_from_slice(&prand.to_le_bytes()[..3]);
+        RpaAddress(DeviceAddress::new(bytes, AddressKind::Random))
+    }
+
+    /// Creates a resolvable private address from 6 raw Bytes (LSB first).
+    ///
+    /// Returns `Error::InvalidValue` if the 2 most significant bits are not `0b01`, or if the
+    /// remaining bits of `prand` are all 0 or all 1. The hash is not checked, since that requires
+    /// the IRK (see [`resolves`]).
+    ///
+    /// [`resolves`]: #method.resolves
+    pub fn from_bytes(bytes: [u8; 6]) -> Result<Self, Error> {
+        Self::try_from(DeviceAddress::new(bytes, AddressKind::Random))
+    }
+
+    /// Returns the raw bytes making up this address (LSB first).
+    pub fn raw(&self) -> &[u8; 6] {
+        self.0.raw()
+    }
+
+    /// Returns the 24-bit random part of the address (including the 2 address type bits).
+    pub fn prand(&self) -> u32 {
+        let raw = self.raw();
+        u32::from_le_bytes([raw[3], raw[4], raw[5], 0])
+    }
+
+    /// Returns the 24-bit hash part of the address.
+    pub fn hash(&self) -> u32 {
+        let raw = self.raw();
+        u32::from_le_bytes([raw[0], raw[1], raw[2], 0])
+    }
+
+    /// Returns whether this address was generated from the Identity Resolving Key `irk`.
+    pub fn resolves(&self, aes: &mut dyn AesProvider, irk: u128) -> bool {
+        ah(aes, irk, self.prand()) == self.hash()
+    }
+}
+
+macro_rules! random_address_conversions {
+    ($($ty:ident => $kind:ident,)+) => {
+        $(
+            impl From<$ty> for DeviceAddress {
+                fn from(address: $ty) -> Self {
+                    address.0
+                }
+            }
+
+            impl TryFrom<DeviceAddress> for $ty {
+                type Error = Error;
+
+                fn try_from(address: DeviceAddress) -> Result<Self, Error> {
+                    check_random(&address, RandomAddressKind::$kind)?;
+                    Ok($ty(address))
+                }
+            }
+
+            impl fmt::Display for $ty {
+                fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                    fmt::Display::fmt(&self.0, f)
+                }
+            }
+        )+
+    };
+}
+
+random_address_conversions! {
+    StaticAddress => Static,
+    NonResolvableAddress => NonResolvable,
+    RpaAddress => Resolvable,
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::security::SoftAes;
+    use crate::test_rng::TestRng;
+
+    #[test]
+    fn random_kinds() {
+        let addr = |msb, kind| DeviceAddress::new([1, 2, 3, 4, 5, msb], kind);
+        assert_eq!(addr(0xC5, AddressKind::Public).random_kind(), None);
+        assert_eq!(
+            addr(0xC5, AddressKind::Random).random_kind(),
+            Some(RandomAddressKind::Static)
+        );
+        assert_eq!(
+            addr(0x05, AddressKind::Random).random_kind(),
+            Some(RandomAddressKind::NonResolvable)
+        );
+        assert_eq!(
+            addr(0x45, AddressKind::Random).random_kind(),
+            Some(RandomAddressKind::Resolvable)
+        );
+        assert_eq!(addr(0x85, AddressKind::Random).random_kind(), None);
+    }
+
+    #[test]
+    fn validation() {
+        assert!(StaticAddress::from_bytes([1, 2, 3, 4, 5, 0xC6]).is_ok());
+        assert_eq!(
+            StaticAddress::from_bytes([1, 2, 3, 4, 5, 0x46]),
+            Err(Error::InvalidValue)
+        );
+        assert_eq!(
+            StaticAddress::from_bytes([0xFF; 6]),
+            Err(Error::InvalidValue)
+        );
+        assert_eq!(
+            StaticAddress::from_bytes([0, 0, 0, 0, 0, 0xC0]),
+            Err(Error::InvalidValue)
+        );
+        let public = DeviceAddress::new([1, 2, 3, 4, 5, 0xC6], AddressKind::Public);
+        assert_eq!(StaticAddress::try_from(public), Err(Error::InvalidValue));
+
+        assert!(NonResolvableAddress::from_bytes([1, 2, 3, 4, 5, 6]).is_ok());
+        assert_eq!(
+            NonResolvableAddress::from_bytes([0; 6]),
+            Err(Error::InvalidValue)
+        );
+
+        // The hash part doesn't count towards the random part of an RPA
+        assert!(RpaAddress::from_bytes([0, 0, 0, 1, 0, 0x40]).is_ok());
+        assert_eq!(
+            RpaAddress::from_bytes([1, 2, 3, 0, 0, 0x40]),
+            Err(Error::InvalidValue)
+        );
+        assert_eq!(
+            RpaAddress::from_bytes([1, 2, 3, 0xFF, 0xFF, 0x7F]),
+            Err(Error::InvalidValue)
+        );
+    }
+
+    #[test]
+    fn generate() {
+        let mut rng = TestRng::counting(0);
+        let addr = StaticAddress::generate(&mut rng);
+        assert_eq!(
+            DeviceAddress::from(addr).random_kind(),
+            Some(RandomAddressKind::Static)
+        );
+        let addr = NonResolvableAddress::generate(&mut rng);
+        assert_eq!(
+            DeviceAddress::from(addr).random_kind(),
+            Some(RandomAddressKind::NonResolvable)
+        );
+
+        let irk = 0xec0234a357c8ad05341010a60a397d9b;
+        let rpa = RpaAddress::generate(irk, &mut SoftAes, &mut rng);
+        assert_eq!(rpa.prand() >> 22, 0b01);
+        assert!(rpa.resolves(&mut SoftAes, irk));
+        assert!(!rpa.resolves(&mut SoftAes, irk ^ 1));
+
+        // Spec sample data (Vol 3, Part H, D.7)
+        let rpa = RpaAddress::from_bytes([0xaa, 0xfb, 0x0d, 0x94, 0x81, 0x70]).unwrap();
+        assert_eq!(rpa.prand(), 0x708194);
+        assert_eq!(rpa.hash(), 0x0dfbaa);
+        assert!(rpa.resolves(&mut SoftAes, irk));
+    }
 
     #[test]
     fn debug_representation() {
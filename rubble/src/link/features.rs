@@ -1,3 +1,5 @@
+use crate::link::{llcp::VersionNumber, Transmitter};
+use crate::BLUETOOTH_VERSION;
 use crate::{bytes::*, Error};
 use bitflags::bitflags;
 
@@ -8,7 +10,7 @@ bitflags! {
         ///
         /// Setting this bit means that the implementation must support the following:
         /// * The following types of LL Control PDUs: `LL_ENC_REQ`, `LL_ENC_RSP`,
-        ///   `LL_START_ENC_REQ`, `LL_START_END_RSP`, `LL_PAUSE_ENC_REQ`, `LL_PAUSE_ENC_RSP`.
+        ///   `LL_START_ENC_REQ`, `LL_START_ENC_RSP`, `LL_PAUSE_ENC_REQ`, `LL_PAUSE_ENC_RSP`.
         /// * *Encryption Start* and *Encryption Pause* procedures.
         ///
         /// Note that the Security Manager Protocol also needs to be implemented for this to be
@@ -46,7 +48,8 @@ bitflags! {
         ///
         /// If a Link-Layer is in idle state, it will transmit empty PDUs, which are never
         /// authenticated with a MIC. Supporting this feature allows configuring a timeout between
-        /// authenticated packets, since dummy data can then be sent via `LL_PING_REQ`.
+        /// authenticated packets, since dummy data can then be sent via `LL_PING_REQ`. This is
+        /// why it is only offered together with `LE_ENCRYPTION` (see `FeatureSet::offerable`).
         const LE_PING = 1 << 4;
 
         /// Link-Layer PDU length update (support for data channel PDUs with more than 31 Bytes).
@@ -61,13 +64,79 @@ bitflags! {
 
         /// Extended scan filter policies.
         const EXT_SCANNER_FILTER_POLICIES = 1 << 7;
+
+        /// Supports the LE Power Control procedure (`LL_POWER_CONTROL_REQ`).
+        ///
+        /// Newer versions of the specification require this to be set together with
+        /// `LE_POWER_CHANGE_INDICATION`. This was introduced in Bluetooth 5.2, so Rubble doesn't
+        /// offer it while it reports an older version (see `FeatureSet::available`).
+        const LE_POWER_CONTROL_REQUEST = 1 << 33;
+
+        /// Supports sending and receiving `LL_POWER_CHANGE_IND`.
+        const LE_POWER_CHANGE_INDICATION = 1 << 34;
     }
 }
 
 impl FeatureSet {
-    /// Returns the feature set supported by Rubble.
-    pub fn supported() -> Self {
-        FeatureSet::empty()
+    /// Returns the feature set supported by Rubble, independent of the hardware in use.
+    ///
+    /// With `LE_ENCRYPTION`, the host has to answer `LL_ENC_REQ` with the key to use. The
+    /// `Responder` looks it up with `SecurityManager::encryption_request`.
+    pub const fn supported() -> Self {
+        FeatureSet::from_bits_truncate(
+            FeatureSet::LE_ENCRYPTION.bits()
+                | FeatureSet::EXTENDED_REJECT_INDICATION.bits()
+                | FeatureSet::LE_PING.bits(),
+        )
+    }
+
+    /// Returns the feature set that can be used with `transmitter`.
+    ///
+    /// This is `supported()`, plus the LE Power Control features if the transmitter can change its
+    /// transmit power. LE Power Control was introduced in Bluetooth 5.2, so it is only offered
+    /// once [`BLUETOOTH_VERSION`] is at least 5.2. Until then, this is just `supported()`.
+    ///
+    /// [`BLUETOOTH_VERSION`]: ../constant.BLUETOOTH_VERSION.html
+    pub fn available<T: Transmitter>(transmitter: &T) -> Self {
+        let mut features = Self::supported();
+        let power_control = u8::from(BLUETOOTH_VERSION) >= u8::from(VersionNumber::V5_2);
+        if power_control && transmitter.tx_power_range().is_some() {
+            features |=
+                FeatureSet::LE_POWER_CONTROL_REQUEST | FeatureSet::LE_POWER_CHANGE_INDICATION;
+        }
+        features
+    }
+
+    /// Returns `self` without the features that require a feature not contained in `self`.
+    ///
+    /// `LE_PING` is removed unless `LE_ENCRYPTION` is present, since pings are only needed to
+    /// exchange packets authenticated with a MIC.
+    pub fn offerable(self) -> Self {
+        if self.contains(FeatureSet::LE_ENCRYPTION) {
+            self
+        } else {
+            self - FeatureSet::LE_PING
+        }
+    }
+}
+
+impl defmt::Format for FeatureSet {
+    fn format(&self, fmt: defmt::Formatter<'_>) {
+        defmt::write!(fmt, "FeatureSet({=u64:x})", self.bits());
+    }
+}
+
+#[cfg(feature = "serde")]
+impl serde::Serialize for FeatureSet {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.bits().serialize(serializer)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for FeatureSet {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        u64::deserialize(deserializer).map(Self::from_bits_truncate)
     }
 }
 
@@ -93,3 +162,21 @@ impl RawRepr<u64> for FeatureSet {
         self.bits()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ping_requires_encryption() {
+        assert!(FeatureSet::supported().contains(FeatureSet::LE_PING | FeatureSet::LE_ENCRYPTION));
+        assert_eq!(FeatureSet::supported().offerable(), FeatureSet::supported());
+
+        let unencrypted = FeatureSet::supported() - FeatureSet::LE_ENCRYPTION;
+        assert_eq!(
+            unencrypted.offerable(),
+            FeatureSet::EXTENDED_REJECT_INDICATION
+        );
+        assert_eq!(FeatureSet::LE_PING.offerable(), FeatureSet::empty());
+    }
+}
@@ -1,13 +1,36 @@
 //! Link-Layer Device Filtering.
+//!
+//! Devices can be filtered by their device address using an [`AddressFilter`], and advertisements
+//! can additionally be filtered by the AD structures they carry using an [`AdFilter`]. A
+//! [`PeerFilter`] restricts which devices may scan and connect to an advertising device, and can be
+//! checked by the radio hardware.
+//!
+//! [`AddressFilter`]: trait.AddressFilter.html
+//! [`AdFilter`]: trait.AdFilter.html
+//! [`PeerFilter`]: struct.PeerFilter.html
 
-use super::DeviceAddress;
+use super::ad_structure::AdStructure;
+use super::{AddressKind, CompanyId, DeviceAddress};
+use crate::uuid::Uuid128;
+use crate::Error;
 use core::{iter, slice};
 
 pub trait AddressFilter {
     fn matches(&self, address: DeviceAddress) -> bool;
 }
 
-/// An `AddressFilter` that allows all devices (ie. no whitelist in use).
+/// A filter that checks the advertising data attached to an advertisement.
+///
+/// AD filters are evaluated before a received advertisement is passed to the application, so they
+/// should be cheap to check.
+pub trait AdFilter {
+    /// Returns whether an advertisement carrying `adv_data` should be reported.
+    fn matches<'a, I>(&self, adv_data: I) -> bool
+    where
+        I: Iterator<Item = AdStructure<'a>>;
+}
+
+/// A filter that allows all devices (ie. no whitelist in use) and all advertising data.
 pub struct AllowAll;
 
 impl AddressFilter for AllowAll {
@@ -16,6 +39,104 @@ impl AddressFilter for AllowAll {
     }
 }
 
+impl AdFilter for AllowAll {
+    fn matches<'a, I>(&self, _adv_data: I) -> bool
+    where
+        I: Iterator<Item = AdStructure<'a>>,
+    {
+        true
+    }
+}
+
+/// An `AdFilter` that matches advertisements listing a specific service UUID.
+///
+/// Both the lists of service UUIDs and the *Service Data* AD structures are checked. UUIDs are
+/// compared in their 128-bit form, so a 16-bit UUID will also match its 128-bit counterpart.
+pub struct ServiceUuidFilter {
+    uuid: Uuid128,
+}
+
+impl ServiceUuidFilter {
+    /// Creates a filter that matches advertisements containing `uuid`.
+    pub fn new(uuid: impl Into<Uuid128>) -> Self {
+        Self { uuid: uuid.into() }
+    }
+}
+
+impl AdFilter for ServiceUuidFilter {
+    fn matches<'a, I>(&self, mut adv_data: I) -> bool
+    where
+        I: Iterator<Item = AdStructure<'a>>,
+    {
+        let uuid = self.uuid;
+        adv_data.any(|ad| match ad {
+            AdStructure::ServiceUuids16(uuids) => uuids.iter().any(|u| Uuid128::from(u) == uuid),
+            AdStructure::ServiceUuids32(uuids) => uuids.iter().any(|u| Uuid128::from(u) == uuid),
+            AdStructure::ServiceUuids128(uuids) => uuids.iter().any(|u| u == uuid),
+            AdStructure::ServiceData16 { uuid: u, .. } => Uuid128::from(u) == uuid,
+            AdStructure::ServiceData32 { uuid: u, .. } => Uuid128::from(u) == uuid,
+            AdStructure::ServiceData128 { uuid: u, .. } => u == uuid,
+            _ => false,
+        })
+    }
+}
+
+/// An `AdFilter` that matches advertisements whose local name starts with a given prefix.
+///
+/// Both complete and shortened local names are checked.
+pub struct NamePrefixFilter<'p> {
+    prefix: &'p str,
+}
+
+impl<'p> NamePrefixFilter<'p> {
+    /// Creates a filter that matches devices whose name starts with `prefix`.
+    pub fn new(prefix: &'p str) -> Self {
+        Self { prefix }
+    }
+}
+
+impl<'p> AdFilter for NamePrefixFilter<'p> {
+    fn matches<'a, I>(&self, mut adv_data: I) -> bool
+    where
+        I: Iterator<Item = AdStructure<'a>>,
+    {
+        adv_data.any(|ad| match ad {
+            AdStructure::CompleteLocalName(name) | AdStructure::ShortenedLocalName(name) => {
+                name.starts_with(self.prefix)
+            }
+            _ => false,
+        })
+    }
+}
+
+/// An `AdFilter` that matches advertisements carrying manufacturer specific data of a specific
+/// company.
+pub struct ManufacturerFilter {
+    company: CompanyId,
+}
+
+impl ManufacturerFilter {
+    /// Creates a filter that matches manufacturer specific data sent with `company` as the
+    /// company identifier.
+    pub fn new(company: CompanyId) -> Self {
+        Self { company }
+    }
+}
+
+impl AdFilter for ManufacturerFilter {
+    fn matches<'a, I>(&self, mut adv_data: I) -> bool
+    where
+        I: Iterator<Item = AdStructure<'a>>,
+    {
+        adv_data.any(|ad| match ad {
+            AdStructure::ManufacturerSpecificData {
+                company_identifier, ..
+            } => company_identifier.as_u16() == self.company.as_u16(),
+            _ => false,
+        })
+    }
+}
+
 /// An `AddressFilter` that checks device addresses against a whitelist.
 ///
 /// This is a software filter, which allows checking against arbitrarily many device addresses, but
@@ -67,6 +188,75 @@ impl<I: Iterator<Item = DeviceAddress> + Clone> AddressFilter for WhitelistFilte
     }
 }
 
+/// A small whitelist of peer device addresses that radios can check in hardware.
+///
+/// When advertising with a `PeerFilter` installed via `LinkLayer::set_peer_filter`, only *Scan
+/// Requests* and *Connect Requests* sent by one of the listed devices are answered. The filter is
+/// passed to the radio driver in `RadioCmd::ListenAdvertising`, so that radios with device address
+/// matching hardware can drop requests from other devices early. The Link-Layer always checks the
+/// filter in software as well.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, defmt::Format)]
+pub struct PeerFilter {
+    addresses: [DeviceAddress; PeerFilter::MAX_LEN],
+    len: u8,
+}
+
+impl PeerFilter {
+    /// The maximum number of addresses in a `PeerFilter`.
+    ///
+    /// This matches the number of address match slots of the nRF radios.
+    pub const MAX_LEN: usize = 8;
+
+    /// Creates an empty filter that doesn't match any device.
+    pub const fn new() -> Self {
+        Self {
+            addresses: [DeviceAddress::new([0; 6], AddressKind::Public); Self::MAX_LEN],
+            len: 0,
+        }
+    }
+
+    /// Creates a filter from a slice of device addresses.
+    ///
+    /// Returns `Error::InvalidLength` if `addresses` contains more than `MAX_LEN` addresses.
+    pub fn from_slice(addresses: &[DeviceAddress]) -> Result<Self, Error> {
+        let mut this = Self::new();
+        for &address in addresses {
+            this.push(address).map_err(|_| Error::InvalidLength)?;
+        }
+        Ok(this)
+    }
+
+    /// Adds `address` to the filter.
+    ///
+    /// Returns `Error::Eof` if the filter already contains `MAX_LEN` addresses.
+    pub fn push(&mut self, address: DeviceAddress) -> Result<(), Error> {
+        let slot = self
+            .addresses
+            .get_mut(usize::from(self.len))
+            .ok_or(Error::Eof)?;
+        *slot = address;
+        self.len += 1;
+        Ok(())
+    }
+
+    /// Returns the device addresses allowed by this filter.
+    pub fn addresses(&self) -> &[DeviceAddress] {
+        &self.addresses[..usize::from(self.len)]
+    }
+}
+
+impl Default for PeerFilter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl AddressFilter for PeerFilter {
+    fn matches(&self, address: DeviceAddress) -> bool {
+        self.addresses().contains(&address)
+    }
+}
+
 /// Advertising filter policy. Governs which devices may scan and connect to an advertising device.
 pub struct AdvFilter<S: AddressFilter, C: AddressFilter> {
     scan: S,
@@ -96,18 +286,167 @@ impl<S: AddressFilter, C: AddressFilter> AdvFilter<S, C> {
 /// Scanner filter policy. Governs which devices will be scanned by this device.
 ///
 /// This can be used for active and passive scanning. Advertisements sent by devices not matched by
-/// the filter will be ignored.
-pub struct ScanFilter<S: AddressFilter> {
+/// the filter will be ignored, as will advertisements whose advertising data isn't matched by the
+/// `AdFilter`.
+pub struct ScanFilter<S: AddressFilter, A: AdFilter = AllowAll> {
     scan: S,
+    ad: A,
 }
 
 impl<S: AddressFilter> ScanFilter<S> {
     /// Creates a new scanner filter policy from an `AddressFilter`.
     pub fn new(scan: S) -> Self {
-        Self { scan }
+        Self::with_ad_filter(scan, AllowAll)
+    }
+}
+
+impl<S: AddressFilter, A: AdFilter> ScanFilter<S, A> {
+    /// Creates a new scanner filter policy from an `AddressFilter` and an `AdFilter`.
+    pub fn with_ad_filter(scan: S, ad: A) -> Self {
+        Self { scan, ad }
     }
 
     pub fn should_scan(&self, device: DeviceAddress) -> bool {
         self.scan.matches(device)
     }
+
+    /// Returns whether an advertisement with the given advertising data should be reported.
+    pub fn should_report<'a, I>(&self, adv_data: I) -> bool
+    where
+        I: Iterator<Item = AdStructure<'a>>,
+    {
+        self.ad.matches(adv_data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bytes::{ByteReader, ByteWriter, FromBytes, ToBytes};
+    use crate::uuid::{Uuid16, Uuid32};
+
+    /// Checks raw advertising data against `filter`.
+    fn matches(filter: &impl AdFilter, adv_data: &[u8]) -> bool {
+        let mut bytes = ByteReader::new(adv_data);
+        let ads = iter::from_fn(|| {
+            if bytes.is_empty() {
+                None
+            } else {
+                Some(AdStructure::from_bytes(&mut bytes).unwrap())
+            }
+        });
+        filter.matches(ads)
+    }
+
+    const FLAGS: [u8; 3] = [0x02, 0x01, 0x06];
+
+    #[test]
+    fn service_uuid_16() {
+        let filter = ServiceUuidFilter::new(Uuid16(0x180F));
+
+        // Complete and incomplete lists of 16-bit UUIDs
+        assert!(matches(&filter, &[0x03, 0x03, 0x0F, 0x18]));
+        assert!(matches(&filter, &[0x05, 0x02, 0x0D, 0x18, 0x0F, 0x18]));
+        assert!(!matches(&filter, &[0x05, 0x03, 0x0D, 0x18, 0x0A, 0x18]));
+
+        // The same UUID in a list of 32-bit or 128-bit UUIDs
+        assert!(matches(&filter, &[0x05, 0x05, 0x0F, 0x18, 0x00, 0x00]));
+        assert!(!matches(&filter, &[0x05, 0x05, 0x0F, 0x18, 0x01, 0x00]));
+        let mut uuids128 = [0; 18];
+        uuids128[..2].copy_from_slice(&[0x11, 0x07]);
+        let mut writer = ByteWriter::new(&mut uuids128[2..]);
+        Uuid128::from(Uuid16(0x180F)).to_bytes(&mut writer).unwrap();
+        assert!(matches(&filter, &uuids128));
+
+        // Service data
+        assert!(matches(&filter, &[0x04, 0x16, 0x0F, 0x18, 50]));
+        assert!(!matches(&filter, &[0x04, 0x16, 0x0D, 0x18, 50]));
+
+        // No UUIDs, or the UUID in a different AD structure
+        assert!(!matches(&filter, &FLAGS));
+        assert!(!matches(&filter, &[]));
+        assert!(!matches(&filter, &[0x05, 0xFF, 0x0F, 0x18, 0x0F, 0x18]));
+    }
+
+    #[test]
+    fn service_uuid_32() {
+        let filter = ServiceUuidFilter::new(Uuid32(0x1234_5678));
+        assert!(matches(&filter, &[0x05, 0x04, 0x78, 0x56, 0x34, 0x12]));
+        assert!(matches(
+            &filter,
+            &[0x09, 0x05, 0x0F, 0x18, 0x00, 0x00, 0x78, 0x56, 0x34, 0x12]
+        ));
+        assert!(!matches(&filter, &[0x05, 0x05, 0x78, 0x56, 0x34, 0x13]));
+        assert!(matches(&filter, &[0x06, 0x20, 0x78, 0x56, 0x34, 0x12, 1]));
+        assert!(!matches(&filter, &[0x06, 0x20, 0x78, 0x56, 0x34, 0x13, 1]));
+        assert!(!matches(&filter, &[0x03, 0x03, 0x78, 0x56]));
+    }
+
+    #[test]
+    fn service_uuid_128() {
+        let uuid = Uuid128::parse_static("a86a62f0-5d26-4538-b364-5654961515c9");
+        let other = Uuid128::parse_static("a86a62f0-5d26-4538-b364-5654961515ca");
+        let filter = ServiceUuidFilter::new(uuid);
+
+        let mut list = [0; 2 + 32];
+        list[..2].copy_from_slice(&[0x21, 0x06]);
+        let mut writer = ByteWriter::new(&mut list[2..]);
+        other.to_bytes(&mut writer).unwrap();
+        uuid.to_bytes(&mut writer).unwrap();
+        assert!(matches(&filter, &list));
+        let mut writer = ByteWriter::new(&mut list[18..]);
+        other.to_bytes(&mut writer).unwrap();
+        assert!(!matches(&filter, &list));
+
+        let mut data = [0; 2 + 16 + 1];
+        data[..2].copy_from_slice(&[0x12, 0x21]);
+        let mut writer = ByteWriter::new(&mut data[2..18]);
+        uuid.to_bytes(&mut writer).unwrap();
+        assert!(matches(&filter, &data));
+
+        // A 128-bit UUID not based on the Base UUID never matches a 16-bit UUID
+        assert!(!matches(&filter, &[0x03, 0x03, 0xc9, 0x15]));
+    }
+
+    #[test]
+    fn name_prefix() {
+        let filter = NamePrefixFilter::new("Rub");
+        assert!(matches(&filter, b"\x07\x09Rubble"));
+        assert!(matches(&filter, b"\x04\x09Rub"));
+        assert!(!matches(&filter, b"\x07\x09rubble"));
+        assert!(!matches(&filter, b"\x03\x09Ru"));
+
+        // Shortened names are checked as well
+        assert!(matches(&filter, b"\x04\x08Rub"));
+        assert!(!matches(&filter, b"\x03\x08Ru"));
+
+        // Other AD structures are ignored
+        assert!(matches(&filter, b"\x02\x01\x06\x07\x09Rubble"));
+        assert!(!matches(&filter, b"\x09\xFF\x59\x00Rubble"));
+        assert!(!matches(&filter, &FLAGS));
+
+        // An empty prefix matches all named devices
+        let filter = NamePrefixFilter::new("");
+        assert!(matches(&filter, b"\x01\x09"));
+        assert!(!matches(&filter, &FLAGS));
+    }
+
+    #[test]
+    fn manufacturer() {
+        let filter = ManufacturerFilter::new(CompanyId::from_raw(0x0059));
+        assert!(matches(&filter, &[0x05, 0xFF, 0x59, 0x00, 1, 2]));
+        assert!(matches(&filter, &[0x03, 0xFF, 0x59, 0x00]));
+        assert!(!matches(&filter, &[0x05, 0xFF, 0x4C, 0x00, 0x59, 0x00]));
+        assert!(!matches(&filter, &[0x05, 0xFF, 0x00, 0x59, 1, 2]));
+
+        // Any of several manufacturer specific data structures may match
+        assert!(matches(
+            &filter,
+            &[0x03, 0xFF, 0x4C, 0x00, 0x02, 0x01, 0x06, 0x03, 0xFF, 0x59, 0x00]
+        ));
+
+        // The company ID must be in manufacturer specific data
+        assert!(!matches(&filter, &[0x03, 0x03, 0x59, 0x00]));
+        assert!(!matches(&filter, &FLAGS));
+    }
 }
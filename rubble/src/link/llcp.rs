@@ -1,12 +1,17 @@
-//! Defines packet structures used by the Link Layer Control Protocol.
+//! Defines packet structures used by the Link Layer Control Protocol, and keeps track of the
+//! procedures in progress.
 
-use crate::link::{channel_map::ChannelMap, comp_id::CompanyId, features::FeatureSet};
-use crate::{bytes::*, time::Duration, utils::Hex, Error};
+use crate::link::{
+    channel_map::ChannelMap, comp_id::CompanyId, features::FeatureSet, MIN_DATA_PAYLOAD_BUF,
+};
+use crate::time::{Duration, Instant};
+use crate::{bytes::*, utils::Hex, Error};
 use core::{cmp, convert::TryInto};
 
 /// A connection parameter update request or response (`LL_CONNECTION_PARAM_REQ`/
 /// `LL_CONNECTION_PARAM_RSP`).
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, defmt::Format)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct ConnectionParamRequest {
     interval_min: u16,
     interval_max: u16,
@@ -175,8 +180,68 @@ pub struct ChannelMapReq {
     pub instant: u16,
 }
 
+// `#[repr(packed)]` structs can't derive `defmt::Format`, since that would reference unaligned
+// fields.
+impl defmt::Format for ConnectionUpdateData {
+    fn format(&self, fmt: defmt::Formatter<'_>) {
+        defmt::write!(
+            fmt,
+            "ConnectionUpdateData {{ win_size: {}, win_offset: {}, interval: {}, latency: {=u16}, timeout: {}, instant: {=u16} }}",
+            self.win_size(),
+            self.win_offset(),
+            self.interval(),
+            self.latency(),
+            self.timeout(),
+            self.instant(),
+        );
+    }
+}
+
+#[cfg(feature = "serde")]
+impl serde::Serialize for ConnectionUpdateData {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        use serde::ser::SerializeStruct;
+
+        let mut s = serializer.serialize_struct("ConnectionUpdateData", 6)?;
+        s.serialize_field("win_size", &self.win_size())?;
+        s.serialize_field("win_offset", &self.win_offset())?;
+        s.serialize_field("interval", &self.interval())?;
+        s.serialize_field("latency", &self.latency())?;
+        s.serialize_field("timeout", &self.timeout())?;
+        s.serialize_field("instant", &self.instant())?;
+        s.end()
+    }
+}
+
+impl defmt::Format for ChannelMapReq {
+    fn format(&self, fmt: defmt::Formatter<'_>) {
+        let map = self.map;
+        let instant = self.instant;
+        defmt::write!(
+            fmt,
+            "ChannelMapReq {{ map: {}, instant: {=u16} }}",
+            map,
+            instant
+        );
+    }
+}
+
+#[cfg(feature = "serde")]
+impl serde::Serialize for ChannelMapReq {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        use serde::ser::SerializeStruct;
+
+        let (map, instant) = (self.map, self.instant);
+        let mut s = serializer.serialize_struct("ChannelMapReq", 2)?;
+        s.serialize_field("map", &map)?;
+        s.serialize_field("instant", &instant)?;
+        s.end()
+    }
+}
+
 /// A structured representation of an LL Control PDU used by the Link Layer Control Protocol (LLCP).
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, defmt::Format)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 pub enum ControlPdu<'a> {
     /// `0x00`/`LL_CONNECTION_UPDATE_REQ` - Update connection parameters.
     ///
@@ -195,6 +260,40 @@ pub enum ControlPdu<'a> {
         error_code: Hex<u8>,
     },
 
+    /// `0x03`/`LL_ENC_REQ` - Master requests to start encrypting the connection.
+    ///
+    /// The Link-Layer passes this on to the host, which looks up the key and answers with
+    /// `LL_ENC_RSP` (see `SecurityManager::encryption_request`).
+    EncReq {
+        /// 64-bit random number identifying the LTK.
+        rand: u64,
+
+        /// Encrypted Diversifier (EDIV) identifying the LTK.
+        ediv: u16,
+
+        /// The master's part of the session key diversifier (`SKDm`).
+        skd_m: u64,
+
+        /// The master's part of the initialization vector (`IVm`).
+        iv_m: u32,
+    },
+
+    /// `0x04`/`LL_ENC_RSP` - The slave's response to `LL_ENC_REQ`.
+    EncRsp {
+        /// The slave's part of the session key diversifier (`SKDs`).
+        skd_s: u64,
+
+        /// The slave's part of the initialization vector (`IVs`).
+        iv_s: u32,
+    },
+
+    /// `0x05`/`LL_START_ENC_REQ` - Sent unencrypted by the slave once it knows the session key.
+    StartEncReq,
+
+    /// `0x06`/`LL_START_ENC_RSP` - Sent encrypted by both devices to complete the *Encryption
+    /// Start* procedure.
+    StartEncRsp,
+
     /// `0x07`/`LL_UNKNOWN_RSP` - Response to unknown/unsupported LL Control PDUs.
     ///
     /// This is returned as a response to an incoming LL Control PDU when the opcode is
@@ -217,6 +316,14 @@ pub enum ControlPdu<'a> {
         features_used: FeatureSet,
     },
 
+    /// `0x0A`/`LL_PAUSE_ENC_REQ` - Master requests to pause encryption to change the key.
+    PauseEncReq,
+
+    /// `0x0B`/`LL_PAUSE_ENC_RSP` - Response to `LL_PAUSE_ENC_REQ`.
+    ///
+    /// The slave sends it encrypted, the master answers with an unencrypted `LL_PAUSE_ENC_RSP`.
+    PauseEncRsp,
+
     /// `0x0C`/`LL_VERSION_IND` - Bluetooth version indication (sent by both master and slave).
     ///
     /// When either master or slave receive this PDU, they should respond with their version if they
@@ -227,9 +334,87 @@ pub enum ControlPdu<'a> {
         sub_vers_nr: Hex<u16>,
     },
 
+    /// `0x0D`/`LL_REJECT_IND` - Rejects a procedure.
+    ///
+    /// This does not say which procedure is rejected, and is only used for encryption procedures
+    /// when the peer does not support `LL_REJECT_EXT_IND`.
+    RejectInd {
+        /// Reason for the rejection.
+        error_code: Hex<u8>,
+    },
+
     ConnectionParamReq(ConnectionParamRequest),
     ConnectionParamRsp(ConnectionParamRequest),
 
+    /// `0x11`/`LL_REJECT_EXT_IND` - Rejects the procedure initiated by an LL Control PDU.
+    ///
+    /// Only sent if both devices support the `EXTENDED_REJECT_INDICATION` feature.
+    RejectIndExt {
+        /// Opcode of the rejected PDU.
+        reject_opcode: ControlOpcode,
+
+        /// Reason for the rejection.
+        error_code: Hex<u8>,
+    },
+
+    /// `0x12`/`LL_PING_REQ` - Asks the recipient to respond with `LL_PING_RSP`.
+    ///
+    /// Only sent if both devices support the `LE_PING` feature.
+    PingReq,
+
+    /// `0x13`/`LL_PING_RSP` - Response to `LL_PING_REQ`.
+    PingRsp,
+
+    /// `0x23`/`LL_POWER_CONTROL_REQ` - Requests a change of the recipient's transmit power.
+    PowerControlReq {
+        /// The PHY the request applies to (`1` = LE 1M).
+        phy: u8,
+
+        /// Requested change of the recipient's transmit power in dB.
+        ///
+        /// `POWER_DELTA_MAX` requests the maximum power level.
+        delta: i8,
+
+        /// Transmit power of the sender in dBm, or `POWER_NOT_AVAILABLE`.
+        tx_power: i8,
+    },
+
+    /// `0x24`/`LL_POWER_CONTROL_RSP` - Response to `LL_POWER_CONTROL_REQ`.
+    PowerControlRsp {
+        /// Whether the sender is at its minimum supported power level.
+        min: bool,
+
+        /// Whether the sender is at its maximum supported power level.
+        max: bool,
+
+        /// Change of the sender's transmit power in dB.
+        delta: i8,
+
+        /// New transmit power of the sender in dBm, or `POWER_NOT_AVAILABLE`.
+        tx_power: i8,
+
+        /// Acceptable power reduction of the sender in dB, or `0xFF` if unknown.
+        apr: u8,
+    },
+
+    /// `0x25`/`LL_POWER_CHANGE_IND` - Informs the peer that the sender's transmit power changed.
+    PowerChangeInd {
+        /// Bitfield of the PHYs the change applies to.
+        phys: u8,
+
+        /// Whether the sender is at its minimum supported power level.
+        min: bool,
+
+        /// Whether the sender is at its maximum supported power level.
+        max: bool,
+
+        /// Change of the sender's transmit power in dB.
+        delta: i8,
+
+        /// New transmit power of the sender in dBm, or `POWER_NOT_AVAILABLE`.
+        tx_power: i8,
+    },
+
     /// Catch-all variant for unsupported opcodes.
     Unknown {
         /// The opcode we don't support. This can also be the `Unknown` variant.
@@ -247,12 +432,25 @@ impl ControlPdu<'_> {
             ControlPdu::ConnectionUpdateReq { .. } => ControlOpcode::ConnectionUpdateReq,
             ControlPdu::ChannelMapReq { .. } => ControlOpcode::ChannelMapReq,
             ControlPdu::TerminateInd { .. } => ControlOpcode::TerminateInd,
+            ControlPdu::EncReq { .. } => ControlOpcode::EncReq,
+            ControlPdu::EncRsp { .. } => ControlOpcode::EncRsp,
+            ControlPdu::StartEncReq => ControlOpcode::StartEncReq,
+            ControlPdu::StartEncRsp => ControlOpcode::StartEncRsp,
             ControlPdu::UnknownRsp { .. } => ControlOpcode::UnknownRsp,
             ControlPdu::FeatureReq { .. } => ControlOpcode::FeatureReq,
             ControlPdu::FeatureRsp { .. } => ControlOpcode::FeatureRsp,
+            ControlPdu::PauseEncReq => ControlOpcode::PauseEncReq,
+            ControlPdu::PauseEncRsp => ControlOpcode::PauseEncRsp,
             ControlPdu::VersionInd { .. } => ControlOpcode::VersionInd,
+            ControlPdu::RejectInd { .. } => ControlOpcode::RejectInd,
             ControlPdu::ConnectionParamReq(_) => ControlOpcode::ConnectionParamReq,
             ControlPdu::ConnectionParamRsp(_) => ControlOpcode::ConnectionParamRsp,
+            ControlPdu::RejectIndExt { .. } => ControlOpcode::RejectIndExt,
+            ControlPdu::PingReq => ControlOpcode::PingReq,
+            ControlPdu::PingRsp => ControlOpcode::PingRsp,
+            ControlPdu::PowerControlReq { .. } => ControlOpcode::PowerControlReq,
+            ControlPdu::PowerControlRsp { .. } => ControlOpcode::PowerControlRsp,
+            ControlPdu::PowerChangeInd { .. } => ControlOpcode::PowerChangeInd,
             ControlPdu::Unknown { opcode, .. } => *opcode,
         }
     }
@@ -284,6 +482,9 @@ impl ControlPdu<'_> {
             PingReq => 0,
             PingRsp => 0,
             LengthReq | LengthRsp => 2 + 2 + 2 + 2,
+            PowerControlReq => 1 + 1 + 1,
+            PowerControlRsp => 1 + 1 + 1 + 1,
+            PowerChangeInd => 1 + 1 + 1 + 1,
             Unknown(_) => {
                 if let ControlPdu::Unknown {
                     ctr_data,
@@ -310,6 +511,18 @@ impl<'a> FromBytes<'a> for ControlPdu<'a> {
             ControlOpcode::TerminateInd => ControlPdu::TerminateInd {
                 error_code: Hex(bytes.read_u8()?),
             },
+            ControlOpcode::EncReq => ControlPdu::EncReq {
+                rand: bytes.read_u64_le()?,
+                ediv: bytes.read_u16_le()?,
+                skd_m: bytes.read_u64_le()?,
+                iv_m: bytes.read_u32_le()?,
+            },
+            ControlOpcode::EncRsp => ControlPdu::EncRsp {
+                skd_s: bytes.read_u64_le()?,
+                iv_s: bytes.read_u32_le()?,
+            },
+            ControlOpcode::StartEncReq => ControlPdu::StartEncReq,
+            ControlOpcode::StartEncRsp => ControlPdu::StartEncRsp,
             ControlOpcode::UnknownRsp => ControlPdu::UnknownRsp {
                 unknown_type: ControlOpcode::from(bytes.read_u8()?),
             },
@@ -319,11 +532,48 @@ impl<'a> FromBytes<'a> for ControlPdu<'a> {
             ControlOpcode::FeatureRsp => ControlPdu::FeatureRsp {
                 features_used: FeatureSet::from_bytes(bytes)?,
             },
+            ControlOpcode::PauseEncReq => ControlPdu::PauseEncReq,
+            ControlOpcode::PauseEncRsp => ControlPdu::PauseEncRsp,
             ControlOpcode::VersionInd => ControlPdu::VersionInd {
                 vers_nr: VersionNumber::from(bytes.read_u8()?),
                 comp_id: CompanyId::from_raw(bytes.read_u16_le()?),
                 sub_vers_nr: Hex(bytes.read_u16_le()?),
             },
+            ControlOpcode::RejectInd => ControlPdu::RejectInd {
+                error_code: Hex(bytes.read_u8()?),
+            },
+            ControlOpcode::RejectIndExt => ControlPdu::RejectIndExt {
+                reject_opcode: ControlOpcode::from(bytes.read_u8()?),
+                error_code: Hex(bytes.read_u8()?),
+            },
+            ControlOpcode::PingReq => ControlPdu::PingReq,
+            ControlOpcode::PingRsp => ControlPdu::PingRsp,
+            ControlOpcode::PowerControlReq => ControlPdu::PowerControlReq {
+                phy: bytes.read_u8()?,
+                delta: bytes.read_u8()? as i8,
+                tx_power: bytes.read_u8()? as i8,
+            },
+            ControlOpcode::PowerControlRsp => {
+                let min_max = bytes.read_u8()?;
+                ControlPdu::PowerControlRsp {
+                    min: min_max & 0b01 != 0,
+                    max: min_max & 0b10 != 0,
+                    delta: bytes.read_u8()? as i8,
+                    tx_power: bytes.read_u8()? as i8,
+                    apr: bytes.read_u8()?,
+                }
+            }
+            ControlOpcode::PowerChangeInd => {
+                let phys = bytes.read_u8()?;
+                let min_max = bytes.read_u8()?;
+                ControlPdu::PowerChangeInd {
+                    phys,
+                    min: min_max & 0b01 != 0,
+                    max: min_max & 0b10 != 0,
+                    delta: bytes.read_u8()? as i8,
+                    tx_power: bytes.read_u8()? as i8,
+                }
+            }
             _ => ControlPdu::Unknown {
                 opcode,
                 ctr_data: bytes.read_rest(),
@@ -354,6 +604,27 @@ impl<'a> ToBytes for ControlPdu<'a> {
                 buffer.write_u8(error_code.0)?;
                 Ok(())
             }
+            ControlPdu::EncReq {
+                rand,
+                ediv,
+                skd_m,
+                iv_m,
+            } => {
+                buffer.write_u64_le(*rand)?;
+                buffer.write_u16_le(*ediv)?;
+                buffer.write_u64_le(*skd_m)?;
+                buffer.write_u32_le(*iv_m)?;
+                Ok(())
+            }
+            ControlPdu::EncRsp { skd_s, iv_s } => {
+                buffer.write_u64_le(*skd_s)?;
+                buffer.write_u32_le(*iv_s)?;
+                Ok(())
+            }
+            ControlPdu::StartEncReq
+            | ControlPdu::StartEncRsp
+            | ControlPdu::PauseEncReq
+            | ControlPdu::PauseEncRsp => Ok(()),
             ControlPdu::UnknownRsp { unknown_type } => {
                 buffer.write_u8(u8::from(*unknown_type))?;
                 Ok(())
@@ -370,9 +641,58 @@ impl<'a> ToBytes for ControlPdu<'a> {
                 buffer.write_u16_le(sub_vers_nr.0)?;
                 Ok(())
             }
+            ControlPdu::RejectInd { error_code } => {
+                buffer.write_u8(error_code.0)?;
+                Ok(())
+            }
             ControlPdu::ConnectionParamReq(data) | ControlPdu::ConnectionParamRsp(data) => {
                 data.to_bytes(buffer)
             }
+            ControlPdu::RejectIndExt {
+                reject_opcode,
+                error_code,
+            } => {
+                buffer.write_u8(u8::from(*reject_opcode))?;
+                buffer.write_u8(error_code.0)?;
+                Ok(())
+            }
+            ControlPdu::PingReq | ControlPdu::PingRsp => Ok(()),
+            ControlPdu::PowerControlReq {
+                phy,
+                delta,
+                tx_power,
+            } => {
+                buffer.write_u8(*phy)?;
+                buffer.write_u8(*delta as u8)?;
+                buffer.write_u8(*tx_power as u8)?;
+                Ok(())
+            }
+            ControlPdu::PowerControlRsp {
+                min,
+                max,
+                delta,
+                tx_power,
+                apr,
+            } => {
+                buffer.write_u8(min_max(*min, *max))?;
+                buffer.write_u8(*delta as u8)?;
+                buffer.write_u8(*tx_power as u8)?;
+                buffer.write_u8(*apr)?;
+                Ok(())
+            }
+            ControlPdu::PowerChangeInd {
+                phys,
+                min,
+                max,
+                delta,
+                tx_power,
+            } => {
+                buffer.write_u8(*phys)?;
+                buffer.write_u8(min_max(*min, *max))?;
+                buffer.write_u8(*delta as u8)?;
+                buffer.write_u8(*tx_power as u8)?;
+                Ok(())
+            }
             ControlPdu::Unknown { ctr_data, .. } => {
                 buffer.write_slice(ctr_data)?;
                 Ok(())
@@ -407,9 +727,88 @@ enum_with_unknown! {
         PingRsp = 0x13,
         LengthReq = 0x14,
         LengthRsp = 0x15,
+        PowerControlReq = 0x23,
+        PowerControlRsp = 0x24,
+        PowerChangeInd = 0x25,
+    }
+}
+
+impl ControlOpcode {
+    /// Returns the feature both devices must support for PDUs with this opcode to be sent.
+    pub fn required_feature(&self) -> Option<FeatureSet> {
+        Some(match self {
+            ControlOpcode::EncReq | ControlOpcode::PauseEncReq => FeatureSet::LE_ENCRYPTION,
+            ControlOpcode::SlaveFeatureReq => FeatureSet::SLAVE_FEATURE_EXCHANGE,
+            ControlOpcode::ConnectionParamReq => FeatureSet::CONN_PARAM_REQ,
+            ControlOpcode::RejectIndExt => FeatureSet::EXTENDED_REJECT_INDICATION,
+            ControlOpcode::PingReq => FeatureSet::LE_PING,
+            ControlOpcode::LengthReq => FeatureSet::LE_PACKET_LENGTH_EXTENSION,
+            ControlOpcode::PowerControlReq => FeatureSet::LE_POWER_CONTROL_REQUEST,
+            ControlOpcode::PowerChangeInd => FeatureSet::LE_POWER_CHANGE_INDICATION,
+            _ => return None,
+        })
+    }
+
+    /// Returns whether PDUs with this opcode are sent in response to another LL Control PDU.
+    ///
+    /// Responses must never be answered with `LL_UNKNOWN_RSP` or a reject PDU.
+    pub fn is_response(&self) -> bool {
+        matches!(
+            self,
+            ControlOpcode::EncRsp
+                | ControlOpcode::StartEncRsp
+                | ControlOpcode::UnknownRsp
+                | ControlOpcode::FeatureRsp
+                | ControlOpcode::PauseEncRsp
+                | ControlOpcode::RejectInd
+                | ControlOpcode::ConnectionParamRsp
+                | ControlOpcode::RejectIndExt
+                | ControlOpcode::PingRsp
+                | ControlOpcode::LengthRsp
+                | ControlOpcode::PowerControlRsp
+        )
+    }
+}
+
+/// Value of the `Delta` field of `LL_POWER_CONTROL_REQ` requesting the maximum power level.
+pub const POWER_DELTA_MAX: i8 = 127;
+
+/// Value of `TxPower` fields indicating that the transmit power is not available.
+pub const POWER_NOT_AVAILABLE: i8 = 127;
+
+/// Error code of reject PDUs for procedures that are known, but not supported.
+pub const UNSUPPORTED_REMOTE_FEATURE: u8 = 0x1A;
+
+/// Returns the PDU rejecting the procedure initiated by an LL Control PDU with `opcode`.
+///
+/// Unknown opcodes are answered with `LL_UNKNOWN_RSP`. Known ones are rejected with
+/// `LL_REJECT_EXT_IND` if both devices support it according to `features`. Otherwise,
+/// encryption requests are rejected with the legacy `LL_REJECT_IND`, and all other procedures with
+/// `LL_UNKNOWN_RSP`.
+pub fn reject(opcode: ControlOpcode, features: FeatureSet) -> ControlPdu<'static> {
+    let error_code = Hex(UNSUPPORTED_REMOTE_FEATURE);
+    match opcode {
+        ControlOpcode::Unknown(_) => ControlPdu::UnknownRsp {
+            unknown_type: opcode,
+        },
+        _ if features.contains(FeatureSet::EXTENDED_REJECT_INDICATION) => {
+            ControlPdu::RejectIndExt {
+                reject_opcode: opcode,
+                error_code,
+            }
+        }
+        ControlOpcode::EncReq => ControlPdu::RejectInd { error_code },
+        _ => ControlPdu::UnknownRsp {
+            unknown_type: opcode,
+        },
     }
 }
 
+/// Encodes the `MinMax` field of LE Power Control PDUs.
+fn min_max(min: bool, max: bool) -> u8 {
+    u8::from(min) | u8::from(max) << 1
+}
+
 enum_with_unknown! {
     /// Enumeration of all possible `VersNr` for `LL_VERSION_IND` PDUs.
     ///
@@ -421,6 +820,246 @@ enum_with_unknown! {
         V4_2 = 8,
         V5_0 = 9,
         V5_1 = 10,
+        V5_2 = 11,
+    }
+}
+
+/// Error code rejecting an `LL_ENC_REQ` for which no key is known (*PIN or Key Missing*).
+pub const PIN_OR_KEY_MISSING: u8 = 0x06;
+
+/// Version information exchanged via `LL_VERSION_IND`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, defmt::Format)]
+pub struct VersionInfo {
+    /// Version of the Bluetooth Core Specification implemented by the Link-Layer.
+    pub version: VersionNumber,
+
+    /// Company identifier of the manufacturer of the Link-Layer.
+    pub company: CompanyId,
+
+    /// Implementation-defined revision of the Link-Layer.
+    pub subversion: u16,
+}
+
+/// Time after which a procedure fails if the peer hasn't sent the expected response.
+///
+/// When this happens, the connection is considered lost.
+pub const PROCEDURE_RESPONSE_TIMEOUT: Duration = Duration::from_micros(40_000_000);
+
+/// Returns the opcode of the PDU that completes a procedure initiated with `opcode`.
+///
+/// Returns `None` if `opcode` does not initiate a procedure that waits for a response.
+fn response_opcode(opcode: ControlOpcode) -> Option<ControlOpcode> {
+    Some(match opcode {
+        ControlOpcode::EncReq => ControlOpcode::EncRsp,
+        ControlOpcode::FeatureReq | ControlOpcode::SlaveFeatureReq => ControlOpcode::FeatureRsp,
+        ControlOpcode::PauseEncReq => ControlOpcode::PauseEncRsp,
+        ControlOpcode::VersionInd => ControlOpcode::VersionInd,
+        // The master answers with `LL_CONNECTION_UPDATE_IND` to accept the parameters
+        ControlOpcode::ConnectionParamReq => ControlOpcode::ConnectionUpdateReq,
+        ControlOpcode::PingReq => ControlOpcode::PingRsp,
+        ControlOpcode::LengthReq => ControlOpcode::LengthRsp,
+        ControlOpcode::PowerControlReq => ControlOpcode::PowerControlRsp,
+        _ => return None,
+    })
+}
+
+/// Returns whether the procedure initiated with `opcode` involves an *instant*.
+fn has_instant(opcode: ControlOpcode) -> bool {
+    matches!(
+        opcode,
+        ControlOpcode::ConnectionUpdateReq
+            | ControlOpcode::ChannelMapReq
+            | ControlOpcode::ConnectionParamReq
+    )
+}
+
+/// An LL Control procedure in progress.
+#[derive(Debug, Copy, Clone, defmt::Format)]
+pub struct Procedure {
+    /// Opcode of the PDU that initiated the procedure.
+    pub opcode: ControlOpcode,
+
+    /// Time at which the procedure was initiated.
+    pub started: Instant,
+}
+
+/// An LL Control PDU that is held back until the active local procedure completes.
+#[derive(Copy, Clone)]
+struct QueuedPdu {
+    buf: [u8; MIN_DATA_PAYLOAD_BUF],
+    len: u8,
+}
+
+/// Tracks the LL Control procedures in progress on a connection.
+///
+/// Each device may only have a single procedure of its own in progress. A procedure we initiate
+/// while another one is still active is queued and started once the active one completes. The
+/// same applies to procedures involving an *instant* while the peer's instant-based procedure is
+/// still pending.
+///
+/// A local procedure fails if the peer does not respond within [`PROCEDURE_RESPONSE_TIMEOUT`],
+/// which ends the connection.
+///
+/// [`PROCEDURE_RESPONSE_TIMEOUT`]: constant.PROCEDURE_RESPONSE_TIMEOUT.html
+pub struct Procedures {
+    /// Procedure initiated by us, waiting for the peer's response.
+    local: Option<Procedure>,
+
+    /// Procedure initiated by the peer that has not completed yet.
+    remote: Option<Procedure>,
+
+    /// Local procedure waiting for `local` (or `remote`) to complete.
+    queued: Option<QueuedPdu>,
+}
+
+impl Procedures {
+    /// Creates a tracker with no procedures in progress.
+    pub fn new() -> Self {
+        Self {
+            local: None,
+            remote: None,
+            queued: None,
+        }
+    }
+
+    /// Returns the procedure initiated by us that is in progress.
+    pub fn local(&self) -> Option<Procedure> {
+        self.local
+    }
+
+    /// Returns the procedure initiated by the peer that is in progress.
+    pub fn remote(&self) -> Option<Procedure> {
+        self.remote
+    }
+
+    /// Returns whether a local procedure is waiting to be started.
+    pub fn has_queued(&self) -> bool {
+        self.queued.is_some()
+    }
+
+    /// Returns whether a procedure initiated with `opcode` could be started now.
+    fn can_start(&self, opcode: ControlOpcode) -> bool {
+        self.local.is_none() && !(has_instant(opcode) && self.remote.is_some())
+    }
+
+    /// Registers the outgoing LL Control PDU `pdu` (including its opcode).
+    ///
+    /// Returns `Ok(true)` if `pdu` may be sent now, and `Ok(false)` if it initiates a procedure that
+    /// has to wait for the active one to complete. In the latter case, `pdu` is copied into the
+    /// queue and will be returned by `start_queued` later. If the queue is already occupied,
+    /// `Error::TxBusy` is returned instead and `pdu` should be retried later.
+    pub fn start_local(&mut self, pdu: &[u8], now: Instant) -> Result<bool, Error> {
+        let opcode = match pdu.first() {
+            Some(&opcode) => ControlOpcode::from(opcode),
+            // Malformed, but doesn't start a procedure either
+            None => return Ok(true),
+        };
+
+        if response_opcode(opcode).is_none() {
+            // Not a procedure we have to wait for (eg. `LL_TERMINATE_IND`)
+            return Ok(true);
+        }
+
+        if self.queued.is_none() && self.can_start(opcode) {
+            self.local = Some(Procedure {
+                opcode,
+                started: now,
+            });
+            return Ok(true);
+        }
+
+        if self.queued.is_some() {
+            return Err(Error::TxBusy);
+        }
+        if pdu.len() > MIN_DATA_PAYLOAD_BUF {
+            return Err(Error::InvalidLength);
+        }
+        let mut buf = [0; MIN_DATA_PAYLOAD_BUF];
+        buf[..pdu.len()].copy_from_slice(pdu);
+        self.queued = Some(QueuedPdu {
+            buf,
+            len: pdu.len() as u8,
+        });
+        Ok(false)
+    }
+
+    /// Starts the queued local procedure, if there is one and it can be started now.
+    ///
+    /// The PDU initiating the procedure is written to `writer`, and its length is returned.
+    pub fn start_queued(&mut self, writer: &mut ByteWriter<'_>, now: Instant) -> Option<u8> {
+        let queued = self.queued?;
+        let pdu = &queued.buf[..usize::from(queued.len)];
+        let opcode = ControlOpcode::from(pdu[0]);
+        if !self.can_start(opcode) {
+            return None;
+        }
+
+        writer.write_slice(pdu).ok()?;
+        self.queued = None;
+        self.local = Some(Procedure {
+            opcode,
+            started: now,
+        });
+        Some(queued.len)
+    }
+
+    /// Processes an incoming LL Control PDU.
+    ///
+    /// Returns `true` if `pdu` is the peer's response to our local procedure, which completes it.
+    pub fn complete_local(&mut self, pdu: &ControlPdu<'_>) -> bool {
+        let local = match self.local {
+            Some(local) => local,
+            None => return false,
+        };
+
+        let completes = match pdu {
+            ControlPdu::UnknownRsp { unknown_type } => *unknown_type == local.opcode,
+            ControlPdu::RejectIndExt { reject_opcode, .. } => *reject_opcode == local.opcode,
+            // The legacy reject PDU does not say which procedure it rejects
+            ControlPdu::RejectInd { .. } => true,
+            _ => response_opcode(local.opcode) == Some(pdu.opcode()),
+        };
+
+        if completes {
+            self.local = None;
+        }
+        completes
+    }
+
+    /// Records that the peer initiated a procedure with the PDU `opcode`.
+    ///
+    /// Only procedures that do not complete immediately need to be registered.
+    pub fn start_remote(&mut self, opcode: ControlOpcode, now: Instant) {
+        self.remote = Some(Procedure {
+            opcode,
+            started: now,
+        });
+    }
+
+    /// Records that the procedure initiated by the peer has completed.
+    pub fn complete_remote(&mut self) {
+        self.remote = None;
+    }
+
+    /// Checks whether the local procedure has timed out at `now`.
+    ///
+    /// Returns the procedure if the peer has not responded within
+    /// [`PROCEDURE_RESPONSE_TIMEOUT`], in which case the connection must be considered lost.
+    ///
+    /// [`PROCEDURE_RESPONSE_TIMEOUT`]: constant.PROCEDURE_RESPONSE_TIMEOUT.html
+    pub fn check_timeout(&self, now: Instant) -> Result<(), Procedure> {
+        match self.local {
+            Some(local) if now.duration_since(local.started) >= PROCEDURE_RESPONSE_TIMEOUT => {
+                Err(local)
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+impl Default for Procedures {
+    fn default() -> Self {
+        Self::new()
     }
 }
 
@@ -461,10 +1100,253 @@ mod tests {
         assert_eq!(max, Duration::from_micros(7_500));
     }
 
+    #[test]
+    fn encryption_roundtrip() {
+        let pdu = ControlPdu::EncReq {
+            rand: 0x0807060504030201,
+            ediv: 0x0A09,
+            skd_m: 0xACBDCEDFE0F10213,
+            iv_m: 0xBADCAB24,
+        };
+        let mut buf = [0; 27];
+        let mut writer = ByteWriter::new(&mut buf);
+        pdu.to_bytes(&mut writer).unwrap();
+        let len = 27 - writer.space_left();
+        assert_eq!(len, usize::from(pdu.encoded_size()));
+        assert_eq!(
+            &buf[..len],
+            &[
+                0x03, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 0x13, 0x02, 0xF1, 0xE0, 0xDF, 0xCE, 0xBD,
+                0xAC, 0x24, 0xAB, 0xDC, 0xBA
+            ]
+        );
+        match ControlPdu::from_bytes(&mut ByteReader::new(&buf[..len])).unwrap() {
+            ControlPdu::EncReq {
+                rand,
+                ediv,
+                skd_m,
+                iv_m,
+            } => {
+                assert_eq!(rand, 0x0807060504030201);
+                assert_eq!(ediv, 0x0A09);
+                assert_eq!(skd_m, 0xACBDCEDFE0F10213);
+                assert_eq!(iv_m, 0xBADCAB24);
+            }
+            pdu => panic!("unexpected PDU {:?}", pdu),
+        }
+
+        let pdu = ControlPdu::EncRsp {
+            skd_s: 0x0213243546576879,
+            iv_s: 0xDEAFBABE,
+        };
+        let mut writer = ByteWriter::new(&mut buf);
+        pdu.to_bytes(&mut writer).unwrap();
+        let len = 27 - writer.space_left();
+        assert_eq!(len, usize::from(pdu.encoded_size()));
+        match ControlPdu::from_bytes(&mut ByteReader::new(&buf[..len])).unwrap() {
+            ControlPdu::EncRsp { skd_s, iv_s } => {
+                assert_eq!(skd_s, 0x0213243546576879);
+                assert_eq!(iv_s, 0xDEAFBABE);
+            }
+            pdu => panic!("unexpected PDU {:?}", pdu),
+        }
+
+        for &opcode in &[0x05, 0x06, 0x0A, 0x0B] {
+            let bytes = [opcode];
+            let pdu = ControlPdu::from_bytes(&mut ByteReader::new(&bytes)).unwrap();
+            assert_eq!(u8::from(pdu.opcode()), opcode);
+            assert!(!matches!(pdu, ControlPdu::Unknown { .. }));
+            assert_eq!(pdu.encoded_size(), 1);
+        }
+
+        let pdu = ControlPdu::RejectInd {
+            error_code: Hex(PIN_OR_KEY_MISSING),
+        };
+        let mut writer = ByteWriter::new(&mut buf);
+        pdu.to_bytes(&mut writer).unwrap();
+        assert_eq!(&buf[..usize::from(pdu.encoded_size())], &[0x0D, 0x06]);
+    }
+
+    #[test]
+    fn power_control_roundtrip() {
+        let pdu = ControlPdu::PowerControlRsp {
+            min: false,
+            max: true,
+            delta: 4,
+            tx_power: -8,
+            apr: 0xFF,
+        };
+        let mut buf = [0; 8];
+        let mut writer = ByteWriter::new(&mut buf);
+        pdu.to_bytes(&mut writer).unwrap();
+        let len = 8 - writer.space_left();
+        assert_eq!(len, usize::from(pdu.encoded_size()));
+        assert_eq!(&buf[..len], &[0x24, 0b10, 4, 0xF8, 0xFF]);
+
+        match ControlPdu::from_bytes(&mut ByteReader::new(&buf[..len])).unwrap() {
+            ControlPdu::PowerControlRsp {
+                min,
+                max,
+                delta,
+                tx_power,
+                apr,
+            } => {
+                assert!(!min);
+                assert!(max);
+                assert_eq!(delta, 4);
+                assert_eq!(tx_power, -8);
+                assert_eq!(apr, 0xFF);
+            }
+            pdu => panic!("unexpected PDU {:?}", pdu),
+        }
+
+        let raw = [0x23, 0x01, 0x7F, 0x00];
+        match ControlPdu::from_bytes(&mut ByteReader::new(&raw)).unwrap() {
+            ControlPdu::PowerControlReq {
+                phy,
+                delta,
+                tx_power,
+            } => {
+                assert_eq!(phy, 1);
+                assert_eq!(delta, POWER_DELTA_MAX);
+                assert_eq!(tx_power, 0);
+            }
+            pdu => panic!("unexpected PDU {:?}", pdu),
+        }
+    }
+
     #[test]
     #[should_panic(expected = "min <= max")]
     fn update_req_set_conn_interval_minmax() {
         let mut req = ConnectionParamRequest::new();
         req.set_conn_interval(Duration::from_secs(8), Duration::from_secs(7));
     }
+
+    #[test]
+    fn reject_flavor() {
+        let legacy = FeatureSet::empty();
+        let ext = FeatureSet::EXTENDED_REJECT_INDICATION;
+
+        for &features in &[legacy, ext] {
+            match reject(ControlOpcode::from(0xF0), features) {
+                ControlPdu::UnknownRsp { unknown_type } => assert_eq!(u8::from(unknown_type), 0xF0),
+                pdu => panic!("unexpected PDU {:?}", pdu),
+            }
+        }
+
+        match reject(ControlOpcode::LengthReq, legacy) {
+            ControlPdu::UnknownRsp { unknown_type } => {
+                assert_eq!(unknown_type, ControlOpcode::LengthReq)
+            }
+            pdu => panic!("unexpected PDU {:?}", pdu),
+        }
+        match reject(ControlOpcode::EncReq, legacy) {
+            ControlPdu::RejectInd { error_code } => assert_eq!(error_code.0, 0x1A),
+            pdu => panic!("unexpected PDU {:?}", pdu),
+        }
+
+        let pdu = reject(ControlOpcode::LengthReq, ext);
+        let mut buf = [0; 3];
+        pdu.to_bytes(&mut ByteWriter::new(&mut buf)).unwrap();
+        assert_eq!(usize::from(pdu.encoded_size()), buf.len());
+        assert_eq!(buf, [0x11, 0x14, 0x1A]);
+        match ControlPdu::from_bytes(&mut ByteReader::new(&buf)).unwrap() {
+            ControlPdu::RejectIndExt {
+                reject_opcode,
+                error_code,
+            } => {
+                assert_eq!(reject_opcode, ControlOpcode::LengthReq);
+                assert_eq!(error_code.0, UNSUPPORTED_REMOTE_FEATURE);
+            }
+            pdu => panic!("unexpected PDU {:?}", pdu),
+        }
+    }
+
+    #[test]
+    fn procedure_response_timeout() {
+        let start = Instant::from_raw_micros(1_000);
+        let mut procs = Procedures::new();
+        assert_eq!(procs.start_local(&[0x12], start), Ok(true));
+        assert_eq!(procs.local().unwrap().opcode, ControlOpcode::PingReq);
+
+        assert!(procs.check_timeout(start + Duration::from_secs(39)).is_ok());
+        let timed_out = procs.check_timeout(start + PROCEDURE_RESPONSE_TIMEOUT);
+        assert_eq!(timed_out.unwrap_err().opcode, ControlOpcode::PingReq);
+
+        let rsp = ControlPdu::Unknown {
+            opcode: ControlOpcode::PingRsp,
+            ctr_data: &[],
+        };
+        assert!(procs.complete_local(&rsp));
+        assert!(procs.local().is_none());
+        assert!(procs
+            .check_timeout(start + PROCEDURE_RESPONSE_TIMEOUT)
+            .is_ok());
+    }
+
+    #[test]
+    fn procedure_collision() {
+        let now = Instant::from_raw_micros(0);
+        let mut procs = Procedures::new();
+
+        // `LL_TERMINATE_IND` doesn't wait for a response
+        assert_eq!(procs.start_local(&[0x02, 0x13], now), Ok(true));
+        assert!(procs.local().is_none());
+
+        assert_eq!(procs.start_local(&[0x23, 0x01, 0x7F, 0x00], now), Ok(true));
+        assert_eq!(procs.start_local(&[0x12], now), Ok(false));
+        assert!(procs.has_queued());
+        assert_eq!(procs.start_local(&[0x14], now), Err(Error::TxBusy));
+
+        let mut buf = [0; MIN_DATA_PAYLOAD_BUF];
+        assert_eq!(
+            procs.start_queued(&mut ByteWriter::new(&mut buf), now),
+            None
+        );
+
+        // Responses to other procedures don't complete ours
+        let rsp = ControlPdu::UnknownRsp {
+            unknown_type: ControlOpcode::PingReq,
+        };
+        assert!(!procs.complete_local(&rsp));
+        let rsp = ControlPdu::UnknownRsp {
+            unknown_type: ControlOpcode::PowerControlReq,
+        };
+        assert!(procs.complete_local(&rsp));
+
+        assert_eq!(
+            procs.start_queued(&mut ByteWriter::new(&mut buf), now),
+            Some(1)
+        );
+        assert_eq!(buf[0], 0x12);
+        assert!(!procs.has_queued());
+        assert_eq!(procs.local().unwrap().opcode, ControlOpcode::PingReq);
+    }
+
+    #[test]
+    fn instant_collision() {
+        let now = Instant::from_raw_micros(0);
+        let mut procs = Procedures::new();
+        procs.start_remote(ControlOpcode::ChannelMapReq, now);
+
+        // `LL_CONNECTION_PARAM_REQ` has to wait for the master's update to be applied
+        let mut req = [0; 24];
+        req[0] = 0x0F;
+        assert_eq!(procs.start_local(&req, now), Ok(false));
+
+        let mut buf = [0; MIN_DATA_PAYLOAD_BUF];
+        assert_eq!(
+            procs.start_queued(&mut ByteWriter::new(&mut buf), now),
+            None
+        );
+        procs.complete_remote();
+        assert_eq!(
+            procs.start_queued(&mut ByteWriter::new(&mut buf), now),
+            Some(24)
+        );
+        assert_eq!(
+            procs.local().unwrap().opcode,
+            ControlOpcode::ConnectionParamReq
+        );
+    }
 }
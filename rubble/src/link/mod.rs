@@ -117,8 +117,13 @@
 //! its maximum value is 31, resulting in a 27 octet Payload (the maximum) and a 32-bit `MIC`. 4.2
 //! added the possibility of larger packets.
 
+mod access_address;
 pub mod ad_structure;
 pub mod advertising;
+pub mod afh;
+mod builder;
+pub mod capture;
+pub mod ccm;
 mod channel_map;
 mod comp_id;
 mod connection;
@@ -127,21 +132,32 @@ mod device_address;
 mod features;
 pub mod filter;
 pub mod llcp;
+mod peer;
 pub mod queue;
 mod responder;
 mod seq_num;
 
+pub use self::access_address::AccessAddress;
+pub use self::builder::{Broadcaster, LinkLayerBuilder, Peripheral, Role};
+pub use self::channel_map::ChannelMap;
 pub use self::comp_id::*;
-pub use self::connection::Connection;
+pub use self::connection::{Connection, ConnectionInfo, ConnectionStats};
 pub use self::device_address::*;
 pub use self::features::*;
+pub use self::peer::{ConnectionHandle, Peer, PeerId};
 pub use self::responder::*;
+pub use self::seq_num::SeqNum;
 
-use self::advertising::{Pdu, PduBuf};
-use self::{ad_structure::AdStructure, seq_num::SeqNum};
-use crate::phy::{AdvertisingChannel, DataChannel};
+use self::ad_structure::AdStructure;
+use self::advertising::{AdvDelay, AdvertisingInterval, Pdu, PduBuf};
+use self::capture::{CapturedPdu, Direction};
+use self::filter::{AddressFilter, PeerFilter};
+use self::queue::RawSlot;
+use crate::phy::{AdvertisingChannel, AdvertisingChannelMap, DataChannel};
+use crate::security::{AesProvider, SoftAes};
 use crate::time::{Duration, Instant, Timer};
 use crate::{bytes::ByteReader, config::*, utils::HexSlice, Error};
+use core::{fmt, marker::PhantomData, mem};
 
 /// The CRC polynomial to use for CRC24 generation.
 ///
@@ -174,16 +190,34 @@ pub const MIN_DATA_PDU_BUF: usize = MIN_DATA_PAYLOAD_BUF + 2;
 /// The Advertising PDU header has a length field that is limited to 37 octets, while data channel
 /// PDUs in Bluetooth 4.0 and 4.1 only have a 5-bit length field, limiting the user payload to 27
 /// octets (after subtracting the optional 4-Byte MIC). Bluetooth 4.2 added the optional Packet
-/// Length Extension, which allows data channel PDUs containing up to 251 user payload bytes,
-/// however Rubble doesn't support that yet.
+/// Length Extension, which allows data channel PDUs containing up to 251 user payload bytes (see
+/// `MAX_DATA_PAYLOAD_BUF`).
 pub const MIN_PAYLOAD_BUF: usize = 37;
 
 /// Min. size a Link-Layer PDU buffer must have (to cover both advertising and data channels).
 ///
-/// Bluetooth 4.2 also allows exchanging larger PDUs using the Packet Length Extension, but Rubble
-/// does not currently support that.
+/// Bluetooth 4.2 also allows exchanging larger PDUs using the Packet Length Extension. Radio
+/// buffers need `MAX_PDU_BUF` Bytes to receive any such PDU.
 pub const MIN_PDU_BUF: usize = MIN_PAYLOAD_BUF + 2 /* 16-bit header */;
 
+/// Max. size of a data channel PDU payload when using the Packet Length Extension.
+///
+/// This is the largest user payload that may be sent in a single data channel PDU (not including
+/// the optional 4-Byte MIC).
+pub const MAX_DATA_PAYLOAD_BUF: usize = 251;
+
+/// Size of a data PDU buffer that can hold any unencrypted PDU allowed by the Packet Length
+/// Extension.
+///
+/// This is `MAX_DATA_PAYLOAD_BUF` plus the size of the data PDU header (2 Bytes), and the largest
+/// slot size supported by `queue::RingQueue`.
+pub const MAX_DATA_PDU_BUF: usize = MAX_DATA_PAYLOAD_BUF + 2;
+
+/// Size of a Link-Layer PDU buffer that can hold any PDU allowed by the Packet Length Extension.
+///
+/// This is `MAX_DATA_PAYLOAD_BUF` plus the MIC (4 Bytes) and the PDU header (2 Bytes).
+pub const MAX_PDU_BUF: usize = MAX_DATA_PAYLOAD_BUF + 4 + 2;
+
 /// Min. size a buffer for Link-Layer packets must have to comply with the spec.
 ///
 /// The packet contains everything that ends up being transmitted over the air: Preamble, Access
@@ -196,6 +230,31 @@ pub const MIN_PACKET_BUF: usize =
     MIN_PDU_BUF +
     3 /* crc */;
 
+/// Errors returned by Link-Layer operations.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, defmt::Format)]
+#[non_exhaustive]
+pub enum LinkError {
+    /// The TX packet queue doesn't have enough free space for the PDU.
+    ///
+    /// The operation can be retried once `Cmd::tx_space_freed` is set.
+    TxBusy,
+
+    /// The PDU could not be encoded.
+    Encoding,
+}
+
+impl fmt::Display for LinkError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            LinkError::TxBusy => "not enough space in TX queue",
+            LinkError::Encoding => "PDU could not be encoded",
+        })
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for LinkError {}
+
 /// Link-Layer state machine, according to the Bluetooth spec.
 enum State<C: Config> {
     /// Radio silence: Not listening, not transmitting anything.
@@ -203,14 +262,18 @@ enum State<C: Config> {
 
     /// Device is advertising and wants to establish a connection.
     Advertising {
-        /// Advertising interval.
-        // TODO: check spec for allowed/recommended values and check for them
+        /// Time of the next advertising event.
         next_adv: Instant,
+
+        /// Advertising interval, without the random `advDelay`.
         interval: Duration,
 
         /// Precomputed PDU payload to copy into the transmitter's buffer.
         pdu: advertising::PduBuf,
 
+        /// Precomputed `SCAN_RSP` PDU to send in response to scan requests.
+        scan_rsp: advertising::PduBuf,
+
         /// Next advertising channel to use for a message.
         // FIXME: spec check; no idea what order or change delay
         channel: AdvertisingChannel,
@@ -226,36 +289,73 @@ enum State<C: Config> {
 ///
 /// Users of this struct must provide an interface to the platform's hardware by implementing
 /// [`Config`].
-pub struct LinkLayer<C: Config> {
+///
+/// The [`Role`] of the Link-Layer is selected when it is created with a [`LinkLayerBuilder`].
+/// `LinkLayer::new` creates a [`Peripheral`].
+///
+/// [`Role`]: trait.Role.html
+/// [`LinkLayerBuilder`]: struct.LinkLayerBuilder.html
+/// [`Peripheral`]: struct.Peripheral.html
+pub struct LinkLayer<C: Config, R: Role = Peripheral> {
     dev_addr: DeviceAddress,
     state: State<C>,
     timer: C::Timer,
+
+    /// Transmit power in dBm.
+    ///
+    /// This is the requested power until it was applied to the `Transmitter`.
+    tx_power: i8,
+
+    /// Time at which the Link-Layer needs to run next.
+    next_event: Option<Instant>,
+
+    /// Devices allowed to scan and connect while advertising, or `None` to allow all devices.
+    peer_filter: Option<PeerFilter>,
+
+    /// Advertising channels to use.
+    adv_channels: AdvertisingChannelMap,
+
+    /// Source of the random delay between advertising events.
+    adv_delay: AdvDelay,
+
+    /// Advertising parameters to resume with if the current connection fails to be established.
+    resume_adv: Option<AdvParams>,
+
+    /// Handle to assign to the next connection.
+    next_handle: ConnectionHandle,
+
+    /// Peer and packet queues of the last connection, kept after it has ended.
+    released: Option<(Peer, ConfConsumer<C>, ConfProducer<C>)>,
+
+    role: PhantomData<R>,
+}
+
+/// Parameters of an advertising Link-Layer, kept while a connection is being established.
+struct AdvParams {
+    interval: Duration,
+    pdu: PduBuf,
+    scan_rsp: PduBuf,
 }
 
 impl<C: Config> LinkLayer<C> {
-    /// Creates a new Link-Layer.
+    /// Creates a new peripheral Link-Layer.
+    ///
+    /// Use a [`LinkLayerBuilder`] to select a different role or to change the initial settings.
     ///
     /// # Parameters
     ///
     /// * **`dev_addr`**: The device address to broadcast as.
     /// * **`timer`**: A `Timer` implementation.
-    /// * **`tx`**: Input queue of packets to transmit when connected.
-    /// * **`rx`**: Output queue of received packets when connected.
+    ///
+    /// [`LinkLayerBuilder`]: struct.LinkLayerBuilder.html
     pub fn new(dev_addr: DeviceAddress, timer: C::Timer) -> Self {
-        trace!("new LinkLayer, dev={:?}", dev_addr);
-        Self {
-            dev_addr,
-            state: State::Standby,
-            timer,
-        }
-    }
-
-    /// Returns a reference to the timer instance used by the Link-Layer.
-    pub fn timer(&mut self) -> &mut C::Timer {
-        &mut self.timer
+        LinkLayerBuilder::new(dev_addr, timer).build()
     }
 
     /// Starts advertising this device, optionally sending data along with the advertising PDU.
+    ///
+    /// Returns `Error::InvalidValue` if `interval` is not a valid advertising interval. Use
+    /// `AdvertisingInterval::new` to find out why an interval is rejected.
     pub fn start_advertise(
         &mut self,
         interval: Duration,
@@ -263,22 +363,290 @@ impl<C: Config> LinkLayer<C> {
         transmitter: &mut C::Transmitter,
         tx: ConfConsumer<C>,
         rx: ConfProducer<C>,
+    ) -> Result<NextUpdate, Error> {
+        AdvertisingInterval::new(interval)?;
+        let pdu = PduBuf::discoverable(self.dev_addr, data)?;
+        let scan_rsp = PduBuf::scan_response(self.dev_addr, &[])?; // TODO make this configurable
+        debug!(logger: C::Logger, "start_advertise: adv_data = {:?}", data);
+        self.start_advertise_pdu(interval, pdu, scan_rsp, transmitter, tx, rx)
+    }
+
+    /// Starts advertising using prebuilt advertising and scan response PDUs.
+    ///
+    /// `pdu` is broadcast once per advertising interval and must be a connectable undirected
+    /// advertising PDU (`ADV_IND`), and `scan_rsp` is sent in response to scan requests. Both must
+    /// use the device address of this Link-Layer as the advertiser address.
+    ///
+    /// Like `start_advertise`, this returns `Error::InvalidValue` if `interval` is not a valid
+    /// advertising interval.
+    pub fn start_advertise_pdu(
+        &mut self,
+        interval: Duration,
+        pdu: PduBuf,
+        scan_rsp: PduBuf,
+        transmitter: &mut C::Transmitter,
+        tx: ConfConsumer<C>,
+        rx: ConfProducer<C>,
     ) -> Result<NextUpdate, Error> {
         // TODO tear down existing connection?
+        self.start_adv(interval, pdu, scan_rsp, transmitter, Some((tx, rx)))
+    }
 
-        let pdu = PduBuf::discoverable(self.dev_addr, data)?;
-        debug!("start_advertise: adv_data = {:?}", data);
-        debug!("start_advertise: PDU = {:?}", pdu);
+    /// Starts connectable directed advertising (`ADV_DIRECT_IND`) to the device `peer`.
+    ///
+    /// This is used to quickly reconnect to a known device. Only `peer` may connect, and scan
+    /// requests are ignored, since directed advertisements don't carry any data. The low duty cycle
+    /// variant is used, so `interval` has to be a valid advertising interval, otherwise
+    /// `Error::InvalidValue` is returned. Directed advertising continues until a connection is
+    /// established or `stop_advertising` is called.
+    pub fn start_directed_advertise(
+        &mut self,
+        interval: Duration,
+        peer: DeviceAddress,
+        transmitter: &mut C::Transmitter,
+        tx: ConfConsumer<C>,
+        rx: ConfProducer<C>,
+    ) -> Result<NextUpdate, Error> {
+        let pdu = PduBuf::connectable_directed(self.dev_addr, peer);
+        // Never sent, since directed advertisements can't be scanned
+        let scan_rsp = PduBuf::scan_response(self.dev_addr, &[])?;
+        debug!(logger: C::Logger, "start_directed_advertise: peer = {:?}", peer);
+        self.start_adv(interval, pdu, scan_rsp, transmitter, Some((tx, rx)))
+    }
+
+    /// Stops advertising and returns the packet queues passed to `start_advertise`.
+    ///
+    /// The Link-Layer enters Standby and cancels its scheduled timer event. The radio might still
+    /// be listening for scan and connect requests, so the caller has to switch it off as if it had
+    /// received a `RadioCmd::Off`. The returned queues can be passed to `start_advertise` again.
+    ///
+    /// If a connection is currently being established, it is abandoned and the Link-Layer will not
+    /// resume advertising. The central will notice this as a failed connection attempt.
+    ///
+    /// Returns `None` and leaves the state unchanged if the Link-Layer isn't advertising.
+    pub fn stop_advertising(&mut self) -> Option<(ConfConsumer<C>, ConfProducer<C>)> {
+        self.stop_adv().flatten()
+    }
+
+    /// Ends the current connection immediately, without notifying the peer.
+    ///
+    /// The Link-Layer enters Standby as if the connection had timed out, so the packet queues can be
+    /// taken back with `take_released_queues`. Like with `stop_advertising`, the radio has to be
+    /// switched off by the caller. The peer notices the loss of the connection once its supervision
+    /// timeout expires.
+    ///
+    /// Returns `false` and leaves the state unchanged if the Link-Layer isn't connected.
+    pub fn abort_connection(&mut self) -> bool {
+        if !self.is_connected() {
+            return false;
+        }
+
+        debug!(logger: C::Logger, "abort_connection, standby");
+        self.release_connection();
+        self.next_event = None;
+        self.timer.cancel();
+        C::on_idle(None);
+        true
+    }
+
+    /// Returns the packet queues of the last connection after it has ended.
+    ///
+    /// When a connection ends, the Link-Layer enters Standby and keeps the packet queues, along
+    /// with the peer the connection was established with, so that advertising can be restarted.
+    /// Returns `None` while connected, or if the queues were already taken.
+    pub fn take_released_queues(&mut self) -> Option<(Peer, ConfConsumer<C>, ConfProducer<C>)> {
+        self.released.take()
+    }
+}
+
+impl<C: Config> LinkLayer<C, Broadcaster> {
+    /// Starts broadcasting non-connectable advertisements (`ADV_NONCONN_IND`) carrying `data`.
+    ///
+    /// Returns `Error::InvalidValue` if `interval` is not a valid advertising interval.
+    pub fn start_broadcast(
+        &mut self,
+        interval: Duration,
+        data: &[AdStructure<'_>],
+        transmitter: &mut C::Transmitter,
+    ) -> Result<NextUpdate, Error> {
+        let pdu = PduBuf::beacon(self.dev_addr, data)?;
+        // Never sent, since non-connectable advertisements can't be scanned
+        let scan_rsp = PduBuf::scan_response(self.dev_addr, &[])?;
+        debug!(logger: C::Logger, "start_broadcast: adv_data = {:?}", data);
+        self.start_adv(interval, pdu, scan_rsp, transmitter, None)
+    }
+
+    /// Stops broadcasting.
+    ///
+    /// Like with `LinkLayer::stop_advertising`, the radio has to be switched off by the caller.
+    /// Returns `false` if the Link-Layer wasn't broadcasting.
+    pub fn stop_broadcast(&mut self) -> bool {
+        self.stop_adv().is_some()
+    }
+}
+
+impl<C: Config, R: Role> LinkLayer<C, R> {
+    /// Returns a reference to the timer instance used by the Link-Layer.
+    pub fn timer(&mut self) -> &mut C::Timer {
+        &mut self.timer
+    }
+
+    /// Changes the transmit power to `dbm`.
+    ///
+    /// The transmitter might not support the exact power level. The actually used level is
+    /// returned. The initial transmit power is `Config::TX_POWER`, which is applied when advertising
+    /// starts.
+    pub fn set_tx_power(&mut self, transmitter: &mut C::Transmitter, dbm: i8) -> i8 {
+        self.tx_power = transmitter.set_tx_power(dbm);
+        if let State::Connection(conn) = &mut self.state {
+            conn.set_tx_power(self.tx_power);
+        }
+        self.tx_power
+    }
+
+    /// Returns the transmit power in dBm.
+    ///
+    /// While connected, the peer may change the transmit power using the LE Power Control
+    /// procedure.
+    pub fn tx_power(&self) -> i8 {
+        match &self.state {
+            State::Connection(conn) => conn.tx_power(),
+            _ => self.tx_power,
+        }
+    }
+
+    /// Returns a *TX Power Level* AD structure containing the current transmit power.
+    ///
+    /// To report the calibrated value in advertising data, call `set_tx_power` before building the
+    /// advertising data.
+    pub fn tx_power_level(&self) -> AdStructure<'static> {
+        AdStructure::TxPowerLevel(self.tx_power)
+    }
+
+    /// Restricts which devices may scan and connect to this device while advertising.
+    ///
+    /// Scan and connect requests from devices not in `filter` are ignored. The filter is also
+    /// passed to the radio in `RadioCmd::ListenAdvertising`, so that it can drop these requests in
+    /// hardware. Passing `None` allows all devices (the default).
+    ///
+    /// This takes effect on the next advertising event.
+    pub fn set_peer_filter(&mut self, filter: Option<PeerFilter>) {
+        self.peer_filter = filter;
+    }
+
+    /// Returns the installed peer filter.
+    pub fn peer_filter(&self) -> Option<&PeerFilter> {
+        self.peer_filter.as_ref()
+    }
+
+    /// Restricts advertising to the channels in `channels`.
+    ///
+    /// This is meant for regulatory testing and for debugging interference, and takes effect on the
+    /// next advertising event. By default, all advertising channels are used.
+    ///
+    /// The data channels of a connection are selected by the central, so they can't be restricted
+    /// here. A central can be asked to restrict them by using a `ChannelMap` created with
+    /// `ChannelMap::from_used`.
+    pub fn set_advertising_channels(&mut self, channels: AdvertisingChannelMap) {
+        self.adv_channels = channels;
+    }
+
+    /// Returns the advertising channels in use.
+    pub fn advertising_channels(&self) -> AdvertisingChannelMap {
+        self.adv_channels
+    }
+
+    /// Enters the Advertising state and sends the first advertising PDU.
+    ///
+    /// `data_queues` are the packet queues used by a connection, which broadcasters don't have.
+    fn start_adv(
+        &mut self,
+        interval: Duration,
+        pdu: PduBuf,
+        scan_rsp: PduBuf,
+        transmitter: &mut C::Transmitter,
+        data_queues: Option<(ConfConsumer<C>, ConfProducer<C>)>,
+    ) -> Result<NextUpdate, Error> {
+        let interval = AdvertisingInterval::new(interval)?.duration();
+        self.tx_power = transmitter.set_tx_power(self.tx_power);
+
+        debug!(logger: C::Logger, "start_advertise: PDU = {:?}", pdu);
+        self.released = None;
         self.state = State::Advertising {
             next_adv: self.timer().now(),
             interval,
             pdu,
+            scan_rsp,
             channel: AdvertisingChannel::first(),
-            data_queues: Some((tx, rx)),
+            data_queues,
         };
         Ok(self.update_timer(transmitter).next_update)
     }
 
+    /// Replaces the advertising data while advertising.
+    ///
+    /// The `ADV_IND` PDU (`ADV_NONCONN_IND` for broadcasters) is rebuilt like in `start_advertise`
+    /// and is sent from the next advertising event on. The advertising interval, the time of the
+    /// next event and the packet queues are kept. Advertising events run inside of `update_timer`, so they always use either the old or
+    /// the new PDU, never a mix of both.
+    ///
+    /// If a connection is currently being established, the data is used when advertising resumes
+    /// after the connection failed to be established.
+    ///
+    /// Returns `Error::InvalidValue` if the Link-Layer isn't advertising, and `Error::Eof` if the
+    /// data doesn't fit into an advertising PDU. In both cases, the PDU is left unchanged.
+    pub fn update_adv_data(&mut self, data: &[AdStructure<'_>]) -> Result<(), Error> {
+        let new_pdu = if R::CONNECTABLE {
+            PduBuf::discoverable(self.dev_addr, data)?
+        } else {
+            PduBuf::beacon(self.dev_addr, data)?
+        };
+        let pdu = match (&mut self.state, &mut self.resume_adv) {
+            (State::Advertising { pdu, .. }, _) => pdu,
+            (State::Connection(conn), Some(adv)) if !conn.is_established() => &mut adv.pdu,
+            _ => return Err(Error::InvalidValue),
+        };
+        debug!(logger: C::Logger, "update_adv_data: adv_data = {:?}", data);
+        *pdu = new_pdu;
+        Ok(())
+    }
+
+    /// Enters Standby if the Link-Layer is advertising, and returns the packet queues it held.
+    ///
+    /// Returns `None` and leaves the state unchanged if the Link-Layer isn't advertising.
+    fn stop_adv(&mut self) -> Option<Option<(ConfConsumer<C>, ConfProducer<C>)>> {
+        let queues = match mem::replace(&mut self.state, State::Standby) {
+            State::Advertising { data_queues, .. } => data_queues,
+            State::Connection(conn) if !conn.is_established() && self.resume_adv.is_some() => {
+                Some(conn.into_queues())
+            }
+            state => {
+                self.state = state;
+                return None;
+            }
+        };
+
+        debug!(logger: C::Logger, "stop_advertising, standby");
+        self.resume_adv = None;
+        self.next_event = None;
+        self.timer.cancel();
+        C::on_idle(None);
+        Some(queues)
+    }
+
+    /// Returns the time at which the Link-Layer needs to run next.
+    ///
+    /// Between radio events, the application can use this to decide how long it may sleep. Returns
+    /// `None` if no event is scheduled. `Config::on_idle` is invoked whenever this changes.
+    pub fn next_event(&self) -> Option<Instant> {
+        self.next_event
+    }
+
+    /// Returns the device address used by this Link-Layer.
+    pub fn device_address(&self) -> &DeviceAddress {
+        &self.dev_addr
+    }
+
     /// Process an incoming packet from an advertising channel.
     ///
     /// The access address of the packet must be `ADVERTISING_ADDRESS`.
@@ -298,32 +666,98 @@ impl<C: Config> LinkLayer<C> {
         payload: &[u8],
         crc_ok: bool,
     ) -> Cmd {
+        let cmd = self.handle_adv_packet(rx_end, tx, header, payload, crc_ok);
+        self.enter_idle(cmd)
+    }
+
+    fn handle_adv_packet(
+        &mut self,
+        rx_end: Instant,
+        tx: &mut C::Transmitter,
+        header: advertising::Header,
+        payload: &[u8],
+        crc_ok: bool,
+    ) -> Cmd {
+        if let State::Advertising { channel, .. } = self.state {
+            C::capture_pdu(&CapturedPdu::advertising(
+                Direction::Rx,
+                rx_end,
+                channel,
+                header,
+                payload,
+                crc_ok,
+            ));
+        }
+
         let pdu = advertising::Pdu::from_header_and_payload(header, &mut ByteReader::new(payload));
 
         if let Ok(pdu) = pdu {
             if let State::Advertising {
                 channel,
+                pdu: adv_pdu,
+                scan_rsp,
                 data_queues,
                 ..
             } = &mut self.state
             {
-                if crc_ok && pdu.receiver() == Some(&self.dev_addr) {
+                let directed_to = adv_pdu.directed_to();
+                let allowed = match (&self.peer_filter, directed_to) {
+                    (_, Some(peer)) => *pdu.sender() == peer,
+                    (Some(filter), None) => filter.matches(*pdu.sender()),
+                    (None, None) => true,
+                };
+                if crc_ok && allowed && pdu.receiver() == Some(&self.dev_addr) {
                     // Got a packet addressed at us, can be a scan or connect request
                     match pdu {
-                        Pdu::ScanRequest { .. } => {
-                            let scan_data = &[]; // TODO make this configurable
-                            let response = PduBuf::scan_response(self.dev_addr, scan_data).unwrap();
-                            tx.transmit_advertising(response.header(), *channel);
+                        Pdu::ScanRequest { .. } if directed_to.is_none() => {
+                            let payload = scan_rsp.payload();
+                            tx.tx_payload_buf()[..payload.len()].copy_from_slice(payload);
+                            tx.transmit_advertising(scan_rsp.header(), *channel);
+                            C::capture_pdu(&CapturedPdu::advertising(
+                                Direction::Tx,
+                                rx_end + Duration::T_IFS,
+                                *channel,
+                                scan_rsp.header(),
+                                payload,
+                                true,
+                            ));
 
                             // Log after responding to meet timing
-                            debug!("-> SCAN RESP: {:?}", response);
+                            debug!(logger: C::Logger, "-> SCAN RESP: {:?}", scan_rsp);
                         }
-                        Pdu::ConnectRequest { lldata, .. } => {
-                            trace!("ADV<- CONN! {:?}", pdu);
+                        Pdu::ConnectRequest {
+                            lldata,
+                            initiator_addr,
+                            ..
+                        } if R::CONNECTABLE => {
+                            trace!(logger: C::Logger, "ADV<- CONN! {:?}", pdu);
 
                             let (tx, rx) = data_queues.take().unwrap();
-                            let (conn, cmd) = Connection::create(&lldata, rx_end, tx, rx);
-                            self.state = State::Connection(conn);
+                            let handle = self.next_handle;
+                            self.next_handle = handle.next();
+                            let (conn, cmd) = Connection::create(
+                                &lldata,
+                                handle,
+                                initiator_addr,
+                                rx_end,
+                                tx,
+                                rx,
+                                self.tx_power,
+                            );
+                            let adv = mem::replace(&mut self.state, State::Connection(conn));
+                            if let State::Advertising {
+                                interval,
+                                pdu,
+                                scan_rsp,
+                                ..
+                            } = adv
+                            {
+                                self.resume_adv = Some(AdvParams {
+                                    interval,
+                                    pdu,
+                                    scan_rsp,
+                                });
+                            }
                             return cmd;
                         }
                         _ => {}
@@ -333,6 +767,7 @@ impl<C: Config> LinkLayer<C> {
         }
 
         trace!(
+            logger: C::Logger,
             "ADV<- {}{:?}, {:?}\n{:?}\n",
             if crc_ok { "" } else { "BADCRC " },
             header,
@@ -345,10 +780,14 @@ impl<C: Config> LinkLayer<C> {
             State::Connection { .. } => unreachable!("process_adv_packet called while connected"),
             State::Advertising { channel, .. } => {
                 Cmd {
-                    radio: RadioCmd::ListenAdvertising { channel },
+                    radio: RadioCmd::ListenAdvertising {
+                        channel,
+                        peer_filter: self.peer_filter,
+                    },
                     // no change
                     next_update: NextUpdate::Keep,
                     queued_work: false,
+                    tx_space_freed: false,
                 }
             }
         }
@@ -363,34 +802,40 @@ impl<C: Config> LinkLayer<C> {
         payload: &[u8],
         crc_ok: bool,
     ) -> Cmd {
-        if let State::Connection(conn) = &mut self.state {
-            match conn.process_data_packet(rx_end, tx, header, payload, crc_ok) {
-                Ok(cmd) => cmd,
-                Err(()) => {
-                    debug!("connection ended, standby");
-                    self.state = State::Standby;
-                    Cmd {
-                        next_update: NextUpdate::Disable,
-                        radio: RadioCmd::Off,
-                        // FIXME(#70) this might need to be changed to `true`
-                        queued_work: false,
+        let cmd = match &mut self.state {
+            State::Connection(conn) if R::CONNECTABLE => {
+                match conn.process_data_packet(rx_end, tx, header, payload, crc_ok) {
+                    Ok(cmd) => cmd,
+                    Err(()) => {
+                        debug!(logger: C::Logger, "connection ended, standby");
+                        self.release_connection();
+                        Cmd {
+                            next_update: NextUpdate::Disable,
+                            radio: RadioCmd::Off,
+                            // FIXME(#70) this might need to be changed to `true`
+                            queued_work: false,
+                            tx_space_freed: false,
+                        }
                     }
                 }
             }
-        } else {
-            unreachable!("received data channel PDU while not in connected state");
-        }
+            _ => unreachable!("received data channel PDU while not in connected state"),
+        };
+        #[cfg(feature = "metrics")]
+        crate::metrics::packet_processed(rx_end, self.timer.now(), cmd.queued_work);
+        self.enter_idle(cmd)
     }
 
     /// Update the Link-Layer state after the timer expires.
     ///
-    /// This should be called whenever the timer set by the last returned `Cmd` has expired.
+    /// This should be called whenever the timer event scheduled via `Timer::schedule_at` (or, for
+    /// timers that can't schedule events, the time requested by the last returned `Cmd`) occurs.
     ///
     /// # Parameters
     ///
     /// * `tx`: A `Transmitter` for sending packets.
     pub fn update_timer(&mut self, tx: &mut C::Transmitter) -> Cmd {
-        match &mut self.state {
+        let cmd = match &mut self.state {
             State::Advertising {
                 next_adv,
                 interval,
@@ -398,7 +843,7 @@ impl<C: Config> LinkLayer<C> {
                 channel,
                 ..
             } => {
-                *channel = channel.cycle();
+                *channel = self.adv_channels.next_after(*channel);
                 let payload = pdu.payload();
                 let buf = tx.tx_payload_buf();
                 buf[..payload.len()].copy_from_slice(payload);
@@ -406,30 +851,89 @@ impl<C: Config> LinkLayer<C> {
                 // FIXME According to the spec, this has to broadcast on all advertising channels
 
                 tx.transmit_advertising(pdu.header(), *channel);
+                C::capture_pdu(&CapturedPdu::advertising(
+                    Direction::Tx,
+                    self.timer.now(),
+                    *channel,
+                    pdu.header(),
+                    payload,
+                    true,
+                ));
 
-                *next_adv += *interval;
+                *next_adv += *interval + self.adv_delay.next();
 
                 Cmd {
-                    radio: RadioCmd::ListenAdvertising { channel: *channel },
+                    radio: RadioCmd::ListenAdvertising {
+                        channel: *channel,
+                        peer_filter: self.peer_filter,
+                    },
                     next_update: NextUpdate::At(*next_adv),
                     queued_work: false,
+                    tx_space_freed: false,
                 }
             }
-            State::Connection(conn) => match conn.timer_update(&mut self.timer) {
+            State::Connection(conn) if R::CONNECTABLE => match conn.timer_update(&mut self.timer) {
                 Ok(cmd) => cmd,
+                Err(()) if !conn.is_established() && self.resume_adv.is_some() => {
+                    debug!(logger: C::Logger, "connection not established, advertising");
+                    let adv = self.resume_adv.take().unwrap();
+                    let queues = match mem::replace(&mut self.state, State::Standby) {
+                        State::Connection(conn) => conn.into_queues(),
+                        _ => unreachable!(),
+                    };
+                    self.state = State::Advertising {
+                        next_adv: self.timer.now(),
+                        interval: adv.interval,
+                        pdu: adv.pdu,
+                        scan_rsp: adv.scan_rsp,
+                        channel: AdvertisingChannel::first(),
+                        data_queues: Some(queues),
+                    };
+                    return self.update_timer(tx);
+                }
                 Err(()) => {
-                    debug!("connection ended (timer), standby");
-                    self.state = State::Standby;
+                    debug!(logger: C::Logger, "connection ended (timer), standby");
+                    self.release_connection();
                     Cmd {
                         next_update: NextUpdate::Disable,
                         radio: RadioCmd::Off,
                         // FIXME(#70) this might need to be changed to `true`
                         queued_work: false,
+                        tx_space_freed: false,
                     }
                 }
             },
+            State::Connection(_) => unreachable!("non-connectable LL entered a connection"),
             State::Standby => unreachable!("LL in standby received timer event"),
+        };
+        self.enter_idle(cmd)
+    }
+
+    /// Enters Standby after the connection has ended, keeping its packet queues.
+    fn release_connection(&mut self) {
+        if let State::Connection(conn) = mem::replace(&mut self.state, State::Standby) {
+            let peer = conn.peer();
+            let (tx, rx) = conn.into_queues();
+            self.released = Some((peer, tx, rx));
         }
+        self.resume_adv = None;
+    }
+
+    /// Schedules the next timer event according to `cmd` and notifies the application.
+    fn enter_idle(&mut self, cmd: Cmd) -> Cmd {
+        match cmd.next_update {
+            NextUpdate::Disable => {
+                self.next_event = None;
+                self.timer.cancel();
+            }
+            NextUpdate::Keep => {}
+            NextUpdate::At(instant) => {
+                self.next_event = Some(instant);
+                self.timer.schedule_at(instant);
+            }
+        }
+        C::on_idle(self.next_event);
+        cmd
     }
 
     /// Returns a reference to the connection state.
@@ -443,6 +947,17 @@ impl<C: Config> LinkLayer<C> {
         }
     }
 
+    /// Returns a mutable reference to the connection state, if the Link-Layer is connected.
+    ///
+    /// This can be used to change connection settings such as `Connection::set_throughput_mode`.
+    pub fn connection_mut(&mut self) -> Option<&mut Connection<C>> {
+        if let State::Connection(conn) = &mut self.state {
+            Some(conn)
+        } else {
+            None
+        }
+    }
+
     /// Returns whether the Link-Layer is currently broadcasting advertisement packets.
     pub fn is_advertising(&self) -> bool {
         matches!(self.state, State::Advertising { .. })
@@ -458,7 +973,7 @@ impl<C: Config> LinkLayer<C> {
 ///
 /// Specifies how the radio should be configured and when/if to call `LinkLayer::update` again.
 #[must_use]
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, defmt::Format)]
 pub struct Cmd {
     /// Radio configuration request.
     pub radio: RadioCmd,
@@ -467,6 +982,9 @@ pub struct Cmd {
     ///
     /// If this is `None`, `update` doesn't need to be called because the Link-Layer is in Standby
     /// state.
+    ///
+    /// The `LinkLayer` has already passed this to `Timer::schedule_at` or `Timer::cancel`, so this
+    /// only needs to be handled by the application if its `Timer` can't schedule events.
     pub next_update: NextUpdate,
 
     /// Whether the Link-Layer code has enqueued more work into the packet queue.
@@ -475,10 +993,16 @@ pub struct Cmd {
     /// calling the `Responder`. The apps idle loop might unconditionally do that, in which case
     /// checking this flag is not necessary.
     pub queued_work: bool,
+
+    /// Whether the Link-Layer has taken a packet out of the TX queue, freeing up space in it.
+    ///
+    /// If this is `true`, operations that previously failed with `Error::TxBusy` (including
+    /// incoming packets the `Responder` couldn't answer) can be retried.
+    pub tx_space_freed: bool,
 }
 
 /// Specifies when the Link Layer's `update` method should be called the next time.
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, defmt::Format)]
 pub enum NextUpdate {
     /// Disable timer and do not call `update`.
     Disable,
@@ -495,7 +1019,7 @@ pub enum NextUpdate {
 /// Specifies if and how the radio should listen for transmissions.
 ///
 /// Returned by the Link-Layer update and processing methods to reconfigure the radio as needed.
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, defmt::Format)]
 pub enum RadioCmd {
     /// Turn the radio off and don't call `LinkLayer::process_*` methods.
     ///
@@ -507,6 +1031,13 @@ pub enum RadioCmd {
     ListenAdvertising {
         /// The advertising channel to listen on.
         channel: AdvertisingChannel,
+
+        /// The devices whose scan and connect requests should be received, or `None` to receive
+        /// packets from all devices.
+        ///
+        /// The Link-Layer ignores requests from other devices, so radios may drop them early,
+        /// eg. by using hardware address matching. Radios without such a feature can ignore this.
+        peer_filter: Option<PeerFilter>,
     },
 
     /// Listen on a data channel. If a matching packet is received, pass it to
@@ -528,6 +1059,14 @@ pub enum RadioCmd {
 
         /// Flag to indicate if the last connection event timed out.
         timeout: bool,
+
+        /// Storage in the RX packet queue that the packet may be received into.
+        ///
+        /// If this is `Some` and the radio is able to receive into arbitrary memory (eg. via DMA),
+        /// it should receive the PDU (header followed by payload) directly into this slot, clamping
+        /// its length to the slot's size. The Link-Layer will then enqueue the packet without
+        /// copying it. If this is `None`, the radio must use its own buffer.
+        rx_buf: Option<RawSlot>,
     },
 }
 
@@ -584,4 +1123,36 @@ pub trait Transmitter {
         header: data::Header,
         channel: DataChannel,
     );
+
+    /// Sets the transmit power used for all following transmissions.
+    ///
+    /// Implementations should pick the highest supported power level that doesn't exceed `dbm`, or
+    /// the lowest supported level if `dbm` is below the supported range.
+    ///
+    /// Returns the power level that is actually used, in dBm.
+    fn set_tx_power(&mut self, dbm: i8) -> i8;
+
+    /// Returns the lowest and highest supported transmit power in dBm.
+    ///
+    /// Returning `Some` enables the LE Power Control procedure, allowing the connected peer to
+    /// request transmit power changes. The default implementation returns `None`, which disables
+    /// the procedure.
+    fn tx_power_range(&self) -> Option<(i8, i8)> {
+        None
+    }
+
+    /// Encrypts a single 128-bit `block` in place using the AES-128 `key`.
+    ///
+    /// This is used to encrypt and decrypt Data Channel PDUs once the connection is encrypted
+    /// (see the [`ccm`] module). Both `key` and `block` are stored most significant octet first,
+    /// like in `AesProvider::encrypt_block`.
+    ///
+    /// The default implementation uses `SoftAes`. Since encrypted PDUs have to be processed within
+    /// the inter-frame spacing, radios with an AES accelerator (like the *ECB* peripheral of the
+    /// nRF52) should override this.
+    ///
+    /// [`ccm`]: ccm/index.html
+    fn encrypt_block(&mut self, key: &[u8; 16], block: &mut [u8; 16]) {
+        SoftAes.encrypt_block(key, block)
+    }
 }
@@ -0,0 +1,231 @@
+//! Identification of connections and of the devices on their other end.
+//!
+//! A [`ConnectionHandle`] refers to a single connection and becomes stale once that connection
+//! ends. A [`PeerId`] refers to the remote device itself, and stays the same across reconnections
+//! as long as the device uses its identity address, or a private address that can be resolved
+//! with the IRK it distributed when bonding.
+//!
+//! Both are bundled in a [`Peer`], which is what the ATT and Security Manager callbacks receive.
+//!
+//! [`ConnectionHandle`]: struct.ConnectionHandle.html
+//! [`PeerId`]: enum.PeerId.html
+//! [`Peer`]: struct.Peer.html
+
+use crate::link::{DeviceAddress, RandomAddressKind};
+use crate::security::{privacy, AesProvider, Bond, LinkSecurity};
+
+/// Identifies a connection established by the `LinkLayer`.
+///
+/// The `LinkLayer` assigns ascending handles to connections as they are created, so a device that
+/// reconnects gets a new handle. Like HCI connection handles, they are in range `0x0000..=0x0EFF`
+/// and wrap around after that.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, defmt::Format)]
+pub struct ConnectionHandle(u16);
+
+impl ConnectionHandle {
+    /// The largest valid handle.
+    pub const MAX: Self = ConnectionHandle(0x0EFF);
+
+    /// Creates a connection handle from its raw value.
+    ///
+    /// # Panics
+    ///
+    /// This will panic if `raw` is larger than `ConnectionHandle::MAX`.
+    pub fn from_raw(raw: u16) -> Self {
+        assert!(raw <= Self::MAX.0, "invalid connection handle {:#06x}", raw);
+        ConnectionHandle(raw)
+    }
+
+    /// Returns the raw handle value.
+    pub fn as_u16(&self) -> u16 {
+        self.0
+    }
+
+    /// Returns the handle to assign to the connection created after this one.
+    pub(crate) fn next(self) -> Self {
+        if self == Self::MAX {
+            ConnectionHandle(0)
+        } else {
+            ConnectionHandle(self.0 + 1)
+        }
+    }
+}
+
+/// Identifies a remote device across connections.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, defmt::Format)]
+pub enum PeerId {
+    /// The public or static random identity address of the device.
+    ///
+    /// This is either the address the device connected with, or the identity address it
+    /// distributed when bonding.
+    Identity(DeviceAddress),
+
+    /// A private address that has not been resolved.
+    ///
+    /// Devices usually change their private address regularly, so the same device will be seen
+    /// with a different `PeerId` after reconnecting.
+    Private(DeviceAddress),
+}
+
+impl PeerId {
+    /// Identifies a device by the address it is connected with.
+    ///
+    /// Public and static random addresses are identity addresses, all other random addresses are
+    /// private.
+    pub fn from_address(address: DeviceAddress) -> Self {
+        match address.random_kind() {
+            None if !address.is_random() => PeerId::Identity(address),
+            Some(RandomAddressKind::Static) => PeerId::Identity(address),
+            _ => PeerId::Private(address),
+        }
+    }
+
+    /// Identifies the device that `bond` was established with.
+    ///
+    /// If the device distributed its identity address, that is used. Otherwise, this is the same as
+    /// `from_address` with the address the device had when pairing.
+    pub fn from_bond(bond: &Bond) -> Self {
+        match bond.peer_identity {
+            Some(identity) => PeerId::Identity(identity),
+            None => Self::from_address(bond.peer),
+        }
+    }
+
+    /// Returns the address identifying the device.
+    pub fn address(&self) -> &DeviceAddress {
+        match self {
+            PeerId::Identity(address) | PeerId::Private(address) => address,
+        }
+    }
+
+    /// Returns whether this is an identity address that stays the same across reconnections.
+    pub fn is_identity(&self) -> bool {
+        matches!(self, PeerId::Identity(_))
+    }
+}
+
+/// The remote device of a connection, as passed to ATT and Security Manager callbacks.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, defmt::Format)]
+pub struct Peer {
+    /// Handle of the connection to the device.
+    pub handle: ConnectionHandle,
+
+    /// The address the device connected with.
+    pub address: DeviceAddress,
+
+    /// Identity of the device.
+    pub id: PeerId,
+
+    /// Security level of the connection, raised once the link is encrypted (see
+    /// `SecurityManager::encryption_changed`).
+    pub security: LinkSecurity,
+
+    /// Size of the encryption key in Bytes, or 0 while the link is unencrypted.
+    pub key_size: u8,
+}
+
+impl Peer {
+    /// Creates a `Peer` for the device that connected with `address`.
+    ///
+    /// The `id` is derived from `address`. If the device has bonded with us before and uses a
+    /// Resolvable Private Address, `resolve` has to be called to find out its identity.
+    pub fn new(handle: ConnectionHandle, address: DeviceAddress) -> Self {
+        Self {
+            handle,
+            address,
+            id: PeerId::from_address(address),
+            security: LinkSecurity::Unencrypted,
+            key_size: 0,
+        }
+    }
+
+    /// Checks whether the peer is the device `bond` was established with, and if so, sets `id` to
+    /// the identity from `bond`.
+    ///
+    /// This matches if the peer still uses the same address as when pairing, if it connected with
+    /// its identity address, or if its Resolvable Private Address was generated with the IRK it
+    /// distributed. To recognize bonded devices, this should be called with every stored `Bond`
+    /// when a connection is established, until it returns `true`.
+    pub fn resolve(&mut self, aes: &mut dyn AesProvider, bond: &Bond) -> bool {
+        let matches = self.address == bond.peer
+            || bond.peer_identity == Some(self.address)
+            || bond
+                .peer_irk
+                .map_or(false, |irk| privacy::resolve(aes, irk, &self.address));
+        if matches {
+            self.id = PeerId::from_bond(bond);
+        }
+        matches
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::link::AddressKind;
+    use crate::security::{toolbox::ah, SoftAes};
+
+    const IRK: u128 = 0xec0234a357c8ad05341010a60a397d9b;
+
+    /// Builds the Resolvable Private Address with the random part `prand` from `IRK`.
+    fn rpa(prand: u32) -> DeviceAddress {
+        let mut bytes = [0; 6];
+        bytes[..3].copy_from_slice(&ah(&mut SoftAes, IRK, prand).to_le_bytes()[..3]);
+        bytes[3..].copy_from_slice(&prand.to_le_bytes()[..3]);
+        DeviceAddress::new(bytes, AddressKind::Random)
+    }
+
+    #[test]
+    fn handles_wrap() {
+        assert_eq!(ConnectionHandle::from_raw(0).next().as_u16(), 1);
+        assert_eq!(ConnectionHandle::MAX.next(), ConnectionHandle::from_raw(0));
+    }
+
+    #[test]
+    fn peer_id_from_address() {
+        let public = DeviceAddress::new([1, 2, 3, 4, 5, 6], AddressKind::Public);
+        let static_addr = DeviceAddress::new([1, 2, 3, 4, 5, 0xc6], AddressKind::Random);
+        let non_resolvable = DeviceAddress::new([1, 2, 3, 4, 5, 0x06], AddressKind::Random);
+        assert_eq!(PeerId::from_address(public), PeerId::Identity(public));
+        assert_eq!(
+            PeerId::from_address(static_addr),
+            PeerId::Identity(static_addr)
+        );
+        assert_eq!(
+            PeerId::from_address(non_resolvable),
+            PeerId::Private(non_resolvable)
+        );
+        assert_eq!(
+            PeerId::from_address(rpa(0x708194)),
+            PeerId::Private(rpa(0x708194))
+        );
+    }
+
+    #[test]
+    fn resolve_bonded_peer() {
+        let identity = DeviceAddress::new([1, 2, 3, 4, 5, 6], AddressKind::Public);
+        let bond = Bond {
+            peer: rpa(0x708194),
+            authenticated: false,
+            key_size: 16,
+            local_ltk: None,
+            local_csrk: None,
+            peer_ltk: None,
+            peer_irk: Some(IRK),
+            peer_identity: Some(identity),
+            peer_csrk: None,
+        };
+
+        // Reconnecting with a new RPA
+        let mut peer = Peer::new(ConnectionHandle::from_raw(1), rpa(0x512345));
+        assert_eq!(peer.id, PeerId::Private(rpa(0x512345)));
+        assert!(peer.resolve(&mut SoftAes, &bond));
+        assert_eq!(peer.id, PeerId::Identity(identity));
+        assert_eq!(peer.address, rpa(0x512345));
+
+        let other = DeviceAddress::new([6, 5, 4, 3, 2, 1], AddressKind::Public);
+        let mut peer = Peer::new(ConnectionHandle::from_raw(2), other);
+        assert!(!peer.resolve(&mut SoftAes, &bond));
+        assert_eq!(peer.id, PeerId::Identity(other));
+    }
+}
@@ -11,17 +11,36 @@
 //!   splitting a [`PacketQueue`].
 //! * The [`SimpleQueue`], [`SimpleProducer`] and [`SimpleConsumer`] types, a minimal implementation
 //!   of the queue interface defined by [`PacketQueue`], [`Producer`] and [`Consumer`].
+//! * The [`RingQueue`], [`RingProducer`] and [`RingConsumer`] types, which implement a queue that
+//!   can hold a configurable number of packets of configurable size.
+//! * [`QueueWaker`] (behind the **`async`** Cargo feature), which notifies async tasks waiting on
+//!   the queues.
 
 use crate::link::data::{self, Llid};
-use crate::link::{MIN_DATA_PAYLOAD_BUF, MIN_DATA_PDU_BUF};
+use crate::link::{MAX_DATA_PDU_BUF, MIN_DATA_PAYLOAD_BUF, MIN_DATA_PDU_BUF};
 use crate::{bytes::*, Error};
+use core::cell::UnsafeCell;
+use core::ptr::NonNull;
+use core::sync::atomic::{AtomicUsize, Ordering};
 use heapless::spsc;
 
+#[cfg(feature = "async")]
+use core::{sync::atomic::AtomicU8, task::Waker};
+
 /// A splittable SPSC queue for data channel PDUs.
 ///
 /// Implementations of this trait must fit at least one data channel packet with a total size of
 /// [`MIN_DATA_PDU_BUF`] bytes (header and payload).
+///
+/// [`MIN_DATA_PDU_BUF`]: ../constant.MIN_DATA_PDU_BUF.html
 pub trait PacketQueue {
+    /// The largest payload a single packet in the queue can carry.
+    ///
+    /// This limits the size of the PDUs that can be received and transmitted when the Data Length
+    /// Extension is used. It must be in range `MIN_DATA_PAYLOAD_BUF..=MAX_DATA_PAYLOAD_BUF`, which
+    /// is checked at compile time when the queue is used by the stack.
+    const MAX_PAYLOAD: usize = MIN_DATA_PAYLOAD_BUF;
+
     /// Producing (writing) half of the queue.
     type Producer: Producer;
 
@@ -68,6 +87,29 @@ pub trait Producer {
         f: &mut dyn FnMut(&mut ByteWriter<'_>) -> Result<Llid, Error>,
     ) -> Result<(), Error>;
 
+    /// Reserves storage for the next packet, so that it can be written in place.
+    ///
+    /// This enables zero-copy reception: The radio can receive a data channel PDU directly into
+    /// the queue's storage, which is then enqueued with `commit_raw`, avoiding a copy from the
+    /// radio's buffer. The storage is laid out like a data channel PDU: The 2-Byte header is
+    /// followed by up to `size() - 2` Bytes of payload.
+    ///
+    /// The storage stays valid and will not be accessed by the consumer until the packet is
+    /// committed. Calling this method again without committing returns the same storage.
+    ///
+    /// Returns `None` if the queue is full or does not support in-place writes (the default).
+    fn reserve_raw(&mut self) -> Option<RawSlot> {
+        None
+    }
+
+    /// Enqueues the packet written into the storage returned by `reserve_raw`.
+    ///
+    /// Returns `Error::InvalidLength` if the packet's header specifies a payload that doesn't fit
+    /// in the storage, and `Error::Eof` if no storage was reserved.
+    fn commit_raw(&mut self) -> Result<(), Error> {
+        Err(Error::Eof)
+    }
+
     /// Enqueues a PDU with known size using a closure.
     ///
     /// This will check if `payload_bytes` are available in the queue, and bail with `Error::Eof` if
@@ -89,7 +131,7 @@ pub trait Producer {
 
         let mut f = Some(f);
         let mut r = None;
-        self.produce_dyn(payload_bytes, &mut |bytes| {
+        let result = self.produce_dyn(payload_bytes, &mut |bytes| {
             let f = f.take().unwrap();
             let result = f(bytes);
             if let Ok(llid) = result {
@@ -99,10 +141,60 @@ pub trait Producer {
                 r = Some(result.map(|_| ()));
                 Err(Error::InvalidValue)
             }
-        })
-        .ok();
+        });
+
+        match r {
+            Some(r) => r,
+            // `f` wasn't called, eg. because the queue is full
+            None => Err(result.unwrap_err().into()),
+        }
+    }
+}
+
+/// Queue storage for a single packet, returned by `Producer::reserve_raw`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct RawSlot {
+    ptr: NonNull<u8>,
+    len: usize,
+}
+
+impl defmt::Format for RawSlot {
+    fn format(&self, fmt: defmt::Formatter<'_>) {
+        defmt::write!(
+            fmt,
+            "RawSlot {{ ptr: {}, len: {=usize} }}",
+            self.ptr.as_ptr() as *const u8,
+            self.len
+        );
+    }
+}
+
+// A `RawSlot` is only an address. Accessing the storage is synchronized by the queue.
+unsafe impl Send for RawSlot {}
+
+impl RawSlot {
+    /// Creates a `RawSlot` referring to `len` Bytes of storage at `ptr`.
+    pub fn new(ptr: NonNull<u8>, len: usize) -> Self {
+        Self { ptr, len }
+    }
+
+    /// Returns a pointer to the start of the storage, where the PDU header is placed.
+    ///
+    /// The payload follows directly after the 2-Byte header.
+    pub fn as_ptr(&self) -> *mut u8 {
+        self.ptr.as_ptr()
+    }
 
-        r.unwrap()
+    /// Returns the size of the storage in Bytes, including the header.
+    pub fn size(&self) -> usize {
+        self.len
+    }
+
+    /// Returns whether `payload` is located in this slot's storage.
+    ///
+    /// The Link-Layer uses this to detect whether a received packet was written in place.
+    pub fn contains_payload(&self, payload: &[u8]) -> bool {
+        payload.as_ptr() == self.as_ptr().wrapping_add(2)
     }
 }
 
@@ -258,14 +350,7 @@ impl<'a> Producer for SimpleProducer<'a> {
         }
 
         let mut buf = [0; MIN_DATA_PDU_BUF];
-        let mut writer = ByteWriter::new(&mut buf[2..]);
-        let free = writer.space_left();
-        let llid = f(&mut writer)?;
-        let used = free - writer.space_left();
-
-        let mut header = data::Header::new(llid);
-        header.set_payload_length(used as u8);
-        header.to_bytes(&mut ByteWriter::new(&mut buf[..2]))?;
+        write_packet(&mut buf, f)?;
 
         self.inner.enqueue(buf).map_err(|_| ()).unwrap();
         Ok(())
@@ -287,11 +372,7 @@ impl<'a> Consumer for SimpleConsumer<'a> {
         f: impl FnOnce(data::Header, &[u8]) -> Consume<R>,
     ) -> Result<R, Error> {
         if let Some(packet) = self.inner.peek() {
-            let mut bytes = ByteReader::new(packet);
-            let raw_header: [u8; 2] = bytes.read_array().unwrap();
-            let header = data::Header::parse(&raw_header);
-            let pl_len = usize::from(header.payload_length());
-            let raw_payload = bytes.read_slice(pl_len)?;
+            let (header, raw_payload) = read_packet(packet)?;
 
             let res = f(header, raw_payload);
             if res.should_consume {
@@ -304,6 +385,356 @@ impl<'a> Consumer for SimpleConsumer<'a> {
     }
 }
 
+/// Encodes a packet produced by `f` into a queue slot.
+fn write_packet(
+    slot: &mut [u8],
+    f: &mut dyn FnMut(&mut ByteWriter<'_>) -> Result<Llid, Error>,
+) -> Result<(), Error> {
+    let mut writer = ByteWriter::new(&mut slot[2..]);
+    let free = writer.space_left();
+    let llid = f(&mut writer)?;
+    let used = free - writer.space_left();
+
+    let mut header = data::Header::new(llid);
+    header.set_payload_length(used as u8);
+    header.to_bytes(&mut ByteWriter::new(&mut slot[..2]))
+}
+
+/// Decodes the header and payload of a packet stored in a queue slot.
+fn read_packet(slot: &[u8]) -> Result<(data::Header, &[u8]), Error> {
+    let mut bytes = ByteReader::new(slot);
+    let raw_header: [u8; 2] = bytes.read_array()?;
+    let header = data::Header::parse(&raw_header);
+    let pl_len = usize::from(header.payload_length());
+    Ok((header, bytes.read_slice(pl_len)?))
+}
+
+/// A packet queue that can hold up to `N` packets of up to `B` Bytes each.
+///
+/// `B` is the size of a whole data channel PDU, including the 2-Byte header. By default, every
+/// packet can carry a payload of up to [`MIN_DATA_PAYLOAD_BUF`] Bytes like in [`SimpleQueue`].
+/// When using the Data Length Extension, `B` can be increased up to [`MAX_DATA_PDU_BUF`] to
+/// exchange larger PDUs. Sizes outside of `MIN_DATA_PDU_BUF..=MAX_DATA_PDU_BUF` are rejected at
+/// compile time.
+///
+/// Only atomic loads and stores are needed, so this queue can be used on thumbv6 cores.
+///
+/// With a single-packet queue, the application can only enqueue a new packet after the Link-Layer
+/// has sent the previous one, which happens at most once per connection event. Larger queues allow
+/// the application to enqueue bursts of data (eg. several notifications, or the fragments of a
+/// large L2CAP message) in one go, and allow the Link-Layer to buffer incoming packets instead of
+/// NACKing them while the application is busy. For example, at a 7.5 ms connection interval and a
+/// 20 Byte ATT payload, a full TX queue of 4 packets can keep the connection busy for 30 ms without
+/// any involvement from the application. The sustained rate is still limited by the Link-Layer,
+/// which currently transfers one packet per connection event.
+///
+/// Each queue needs `N * B` Bytes of storage.
+///
+/// This queue supports `Producer::reserve_raw`, so radio drivers can receive packets directly into
+/// it without copying them.
+///
+/// [`SimpleQueue`]: struct.SimpleQueue.html
+/// [`MIN_DATA_PAYLOAD_BUF`]: ../constant.MIN_DATA_PAYLOAD_BUF.html
+/// [`MAX_DATA_PDU_BUF`]: ../constant.MAX_DATA_PDU_BUF.html
+pub struct RingQueue<const N: usize, const B: usize = MIN_DATA_PDU_BUF> {
+    slots: UnsafeCell<[[u8; B]; N]>,
+
+    /// Number of packets dequeued so far, modulo `2 * N`. Only written by the consumer.
+    head: AtomicUsize,
+
+    /// Number of packets enqueued so far, modulo `2 * N`. Only written by the producer.
+    tail: AtomicUsize,
+}
+
+// The queue can only be accessed through the producer and consumer, which are created from a
+// `&mut RingQueue`, so there is at most one of each. They synchronize via `head` and `tail`.
+unsafe impl<const N: usize, const B: usize> Sync for RingQueue<N, B> {}
+
+impl<const N: usize, const B: usize> RingQueue<N, B> {
+    /// Fails compilation if the slot size `B` is out of range.
+    const SLOT_SIZE_OK: () = assert!(
+        B >= MIN_DATA_PDU_BUF && B <= MAX_DATA_PDU_BUF,
+        "`RingQueue` slot size must be in range `MIN_DATA_PDU_BUF..=MAX_DATA_PDU_BUF`"
+    );
+
+    /// Creates a new, empty queue.
+    pub const fn new() -> Self {
+        #[allow(clippy::let_unit_value)]
+        let () = Self::SLOT_SIZE_OK;
+
+        Self {
+            slots: UnsafeCell::new([[0; B]; N]),
+            head: AtomicUsize::new(0),
+            tail: AtomicUsize::new(0),
+        }
+    }
+
+    /// Returns the index following `index`.
+    ///
+    /// Indices count modulo `2 * N` instead of wrapping at `usize::MAX`, so that `index % N` stays
+    /// consistent for any `N`, and full and empty queues can still be told apart.
+    fn next_index(index: usize) -> usize {
+        if index + 1 == 2 * N {
+            0
+        } else {
+            index + 1
+        }
+    }
+
+    /// Returns the number of packets stored in the queue.
+    fn len(head: usize, tail: usize) -> usize {
+        if tail >= head {
+            tail - head
+        } else {
+            tail + 2 * N - head
+        }
+    }
+
+    /// Returns a pointer to the slot storing the packet with the given index.
+    fn slot(&self, index: usize) -> *mut [u8; B] {
+        // Only a pointer to the individual slot is created here, the producer and consumer never
+        // access the same slot at the same time.
+        unsafe { (self.slots.get() as *mut [u8; B]).add(index % N) }
+    }
+}
+
+impl<const N: usize, const B: usize> Default for RingQueue<N, B> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<'a, const N: usize, const B: usize> PacketQueue for &'a mut RingQueue<N, B> {
+    const MAX_PAYLOAD: usize = B - 2;
+
+    type Producer = RingProducer<'a, N, B>;
+
+    type Consumer = RingConsumer<'a, N, B>;
+
+    fn split(self) -> (Self::Producer, Self::Consumer) {
+        assert!(N > 0, "`RingQueue` must have space for at least one packet");
+
+        let queue = &*self;
+        (RingProducer { queue }, RingConsumer { queue })
+    }
+}
+
+/// Producer (writer) half returned by `RingQueue::split`.
+pub struct RingProducer<'a, const N: usize, const B: usize = MIN_DATA_PDU_BUF> {
+    queue: &'a RingQueue<N, B>,
+}
+
+impl<'a, const N: usize, const B: usize> RingProducer<'a, N, B> {
+    fn is_full(&self) -> bool {
+        let head = self.queue.head.load(Ordering::Acquire);
+        let tail = self.queue.tail.load(Ordering::Relaxed);
+        RingQueue::<N>::len(head, tail) >= N
+    }
+}
+
+impl<'a, const N: usize, const B: usize> Producer for RingProducer<'a, N, B> {
+    fn free_space(&self) -> u8 {
+        // All slots have the same size, so there's either space for a packet or not
+        if self.is_full() {
+            0
+        } else {
+            (B - 2) as u8
+        }
+    }
+
+    fn produce_dyn(
+        &mut self,
+        payload_bytes: u8,
+        f: &mut dyn FnMut(&mut ByteWriter<'_>) -> Result<Llid, Error>,
+    ) -> Result<(), Error> {
+        assert!(usize::from(payload_bytes) <= B - 2);
+
+        if self.is_full() {
+            return Err(Error::Eof);
+        }
+
+        let tail = self.queue.tail.load(Ordering::Relaxed);
+        // The slot at `tail` is not full, so the consumer won't access it.
+        let slot = unsafe { &mut *self.queue.slot(tail) };
+        write_packet(slot, f)?;
+
+        self.queue
+            .tail
+            .store(RingQueue::<N>::next_index(tail), Ordering::Release);
+        Ok(())
+    }
+
+    fn reserve_raw(&mut self) -> Option<RawSlot> {
+        if self.is_full() {
+            return None;
+        }
+
+        let tail = self.queue.tail.load(Ordering::Relaxed);
+        let ptr = self.queue.slot(tail) as *mut u8;
+        Some(RawSlot::new(NonNull::new(ptr)?, B))
+    }
+
+    fn commit_raw(&mut self) -> Result<(), Error> {
+        if self.is_full() {
+            return Err(Error::Eof);
+        }
+
+        let tail = self.queue.tail.load(Ordering::Relaxed);
+        // The slot at `tail` is not full, so the consumer won't access it.
+        let slot = unsafe { &*self.queue.slot(tail) };
+        let header = data::Header::parse(&slot[..2]);
+        if usize::from(header.payload_length()) > B - 2 {
+            return Err(Error::InvalidLength);
+        }
+
+        self.queue
+            .tail
+            .store(tail.wrapping_add(1), Ordering::Release);
+        Ok(())
+    }
+}
+
+/// Consumer (reader) half returned by `RingQueue::split`.
+pub struct RingConsumer<'a, const N: usize, const B: usize = MIN_DATA_PDU_BUF> {
+    queue: &'a RingQueue<N, B>,
+}
+
+impl<'a, const N: usize, const B: usize> Consumer for RingConsumer<'a, N, B> {
+    fn has_data(&self) -> bool {
+        let head = self.queue.head.load(Ordering::Relaxed);
+        let tail = self.queue.tail.load(Ordering::Acquire);
+        head != tail
+    }
+
+    fn consume_raw_with<R>(
+        &mut self,
+        f: impl FnOnce(data::Header, &[u8]) -> Consume<R>,
+    ) -> Result<R, Error> {
+        if !self.has_data() {
+            return Err(Error::Eof);
+        }
+
+        let head = self.queue.head.load(Ordering::Relaxed);
+        // The slot at `head` is full, so the producer won't access it.
+        let slot = unsafe { &*self.queue.slot(head) };
+        let (header, raw_payload) = read_packet(slot)?;
+
+        let res = f(header, raw_payload);
+        if res.should_consume {
+            self.queue
+                .head
+                .store(RingQueue::<N>::next_index(head), Ordering::Release);
+        }
+        res.result
+    }
+}
+
+/// Notifies an async task of activity on the packet queues.
+///
+/// The async methods of the [`Responder`] register themselves with a `QueueWaker` while they wait
+/// for packets to arrive or for space to become available in the TX queue. Since the queues are
+/// modified by the real-time Link-Layer code, the application has to call [`wake`] whenever the
+/// Link-Layer has processed a radio event (eg. every time it returns a [`Cmd`]).
+///
+/// A `QueueWaker` is usually stored in a `static`. Only a single task may wait on it at a time.
+///
+/// [`Responder`]: ../struct.Responder.html
+/// [`wake`]: #method.wake
+/// [`Cmd`]: ../struct.Cmd.html
+#[cfg(feature = "async")]
+pub struct QueueWaker {
+    state: AtomicU8,
+    waker: UnsafeCell<Option<Waker>>,
+}
+
+#[cfg(feature = "async")]
+const WAITING: u8 = 0;
+#[cfg(feature = "async")]
+const REGISTERING: u8 = 0b01;
+#[cfg(feature = "async")]
+const WAKING: u8 = 0b10;
+
+// Access to `waker` is synchronized via `state`.
+#[cfg(feature = "async")]
+unsafe impl Sync for QueueWaker {}
+
+#[cfg(feature = "async")]
+impl QueueWaker {
+    /// Creates a new `QueueWaker` with no registered task.
+    pub const fn new() -> Self {
+        Self {
+            state: AtomicU8::new(WAITING),
+            waker: UnsafeCell::new(None),
+        }
+    }
+
+    /// Registers `waker` to be woken by the next call to `wake`.
+    ///
+    /// This replaces any previously registered waker.
+    pub fn register(&self, waker: &Waker) {
+        match self
+            .state
+            .compare_exchange(WAITING, REGISTERING, Ordering::Acquire, Ordering::Acquire)
+            .unwrap_or_else(|state| state)
+        {
+            WAITING => {
+                // We hold the lock, no other access to `waker` can happen.
+                unsafe {
+                    let slot = &mut *self.waker.get();
+                    match slot {
+                        Some(old) if old.will_wake(waker) => {}
+                        _ => *slot = Some(waker.clone()),
+                    }
+                }
+
+                if self
+                    .state
+                    .compare_exchange(REGISTERING, WAITING, Ordering::AcqRel, Ordering::Acquire)
+                    .is_err()
+                {
+                    // `wake` was called while we were registering, so it couldn't take the waker.
+                    // Do it ourselves.
+                    let waker = unsafe { (*self.waker.get()).take() };
+                    self.state.swap(WAITING, Ordering::AcqRel);
+                    if let Some(waker) = waker {
+                        waker.wake();
+                    }
+                }
+            }
+            WAKING => {
+                // `wake` is running concurrently, make sure the task is polled again.
+                waker.wake_by_ref();
+            }
+            _ => {
+                // Concurrent calls to `register` are not supported. Waking the task is always
+                // correct, it'll just poll again.
+                waker.wake_by_ref();
+            }
+        }
+    }
+
+    /// Wakes the registered task, if any.
+    ///
+    /// This is safe to call from interrupt handlers.
+    pub fn wake(&self) {
+        if let WAITING = self.state.fetch_or(WAKING, Ordering::AcqRel) {
+            // We hold the lock, no other access to `waker` can happen.
+            let waker = unsafe { (*self.waker.get()).take() };
+            self.state.fetch_and(!WAKING, Ordering::Release);
+            if let Some(waker) = waker {
+                waker.wake();
+            }
+        }
+    }
+}
+
+#[cfg(feature = "async")]
+impl Default for QueueWaker {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 /// Runs Rubble's packet queue testsuite against the given `PacketQueue`.
 ///
 /// This can be used when implementing your own packet queue. Simply create a `#[test]` function as
@@ -315,7 +746,7 @@ impl<'a> Consumer for SimpleConsumer<'a> {
 ///
 /// Simultaneously, this function ensures that `PacketQueue` implementors can be used by a generic
 /// function, something that sometimes doesn't work when invariant lifetimes are involved.
-pub fn run_tests(queue: impl PacketQueue) {
+pub fn run_tests<Q: PacketQueue>(queue: Q) {
     fn assert_empty(c: &mut impl Consumer) {
         assert!(!c.has_data(), "empty queue `has_data()` returned true");
 
@@ -359,7 +790,7 @@ pub fn run_tests(queue: impl PacketQueue) {
     p.produce_with(MIN_DATA_PAYLOAD_BUF as u8, |writer| -> Result<_, Error> {
         assert_eq!(
             writer.space_left(),
-            MIN_DATA_PAYLOAD_BUF,
+            Q::MAX_PAYLOAD,
             "produce_with didn't pass ByteWriter with correct buffer"
         );
         writer.write_slice(&[0; MIN_DATA_PAYLOAD_BUF]).unwrap();
@@ -403,7 +834,7 @@ pub fn run_tests(queue: impl PacketQueue) {
     p.produce_with(0, |writer| -> Result<_, Error> {
         assert_eq!(
             writer.space_left(),
-            MIN_DATA_PAYLOAD_BUF,
+            Q::MAX_PAYLOAD,
             "produce_with didn't pass ByteWriter with correct buffer"
         );
         Ok(Llid::DataStart)
@@ -420,7 +851,7 @@ pub fn run_tests(queue: impl PacketQueue) {
         assert_eq!(usize::from(header.payload_length()), 0);
         assert_eq!(
             data,
-            &[][..],
+            &[0u8; 0][..],
             "consume_raw_with didn't yield correct empty payload"
         );
         Consume::never(Ok(()))
@@ -449,3 +880,148 @@ pub fn run_tests(queue: impl PacketQueue) {
 fn simple_queue() {
     run_tests(&mut SimpleQueue::new());
 }
+
+#[test]
+fn ring_queue() {
+    run_tests(&mut RingQueue::<1>::new());
+    run_tests(&mut RingQueue::<4>::new());
+
+    let mut queue = RingQueue::<3>::new();
+    let (mut p, mut c) = (&mut queue).split();
+
+    // Fill and drain the queue a few times, so that the indices wrap around
+    for round in 0..3u8 {
+        for i in 0..3 {
+            p.produce_with(1, |writer| -> Result<_, Error> {
+                writer.write_u8(round * 3 + i)?;
+                Ok(Llid::DataStart)
+            })
+            .unwrap();
+        }
+        assert_eq!(p.free_space(), 0);
+        assert_eq!(
+            p.produce_with(0, |_| -> Result<_, Error> { Ok(Llid::DataCont) }),
+            Err(Error::Eof)
+        );
+
+        for i in 0..3 {
+            let byte = c
+                .consume_raw_with(|_, data| Consume::always(Ok(data[0])))
+                .unwrap();
+            assert_eq!(byte, round * 3 + i);
+        }
+        assert!(!c.has_data());
+    }
+
+    // Keep 2 packets in flight, so that the indices pass the end of the queue at every position
+    for i in 0..20u8 {
+        p.produce_with(1, |writer| -> Result<_, Error> {
+            writer.write_u8(i)?;
+            Ok(Llid::DataStart)
+        })
+        .unwrap();
+        if i > 0 {
+            let byte = c
+                .consume_raw_with(|_, data| Consume::always(Ok(data[0])))
+                .unwrap();
+            assert_eq!(byte, i - 1);
+        }
+        assert_ne!(p.free_space(), 0);
+    }
+}
+
+#[cfg(feature = "async")]
+#[test]
+fn queue_waker() {
+    use std::sync::{atomic::AtomicUsize, Arc};
+    use std::task::Wake;
+
+    struct CountingWaker(AtomicUsize);
+
+    impl Wake for CountingWaker {
+        fn wake(self: Arc<Self>) {
+            self.0.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    let count = Arc::new(CountingWaker(AtomicUsize::new(0)));
+    let waker = Waker::from(count.clone());
+    let queue_waker = QueueWaker::new();
+
+    // Nothing registered yet
+    queue_waker.wake();
+    assert_eq!(count.0.load(Ordering::SeqCst), 0);
+
+    queue_waker.register(&waker);
+    queue_waker.register(&waker);
+    queue_waker.wake();
+    assert_eq!(count.0.load(Ordering::SeqCst), 1);
+
+    // The waker is removed after waking
+    queue_waker.wake();
+    assert_eq!(count.0.load(Ordering::SeqCst), 1);
+}
+
+#[test]
+fn ring_queue_in_place() {
+    let mut queue = RingQueue::<1>::new();
+    let (mut p, mut c) = (&mut queue).split();
+
+    let slot = p.reserve_raw().unwrap();
+    assert_eq!(slot.size(), MIN_DATA_PDU_BUF);
+    assert_eq!(p.reserve_raw(), Some(slot));
+
+    // Simulate the radio receiving a packet into the slot
+    let mut header = data::Header::new(Llid::DataStart);
+    header.set_payload_length(3);
+    let raw = header.to_u16().to_le_bytes();
+    unsafe {
+        let ptr = slot.as_ptr();
+        ptr.copy_from_nonoverlapping(raw.as_ptr(), 2);
+        ptr.add(2).copy_from_nonoverlapping([1, 2, 3].as_ptr(), 3);
+    }
+    assert!(!c.has_data());
+    p.commit_raw().unwrap();
+
+    assert_eq!(p.reserve_raw(), None);
+    assert_eq!(p.commit_raw(), Err(Error::Eof));
+    c.consume_raw_with(|header, data| -> Consume<()> {
+        assert_eq!(header.llid(), Llid::DataStart);
+        assert_eq!(data, &[1, 2, 3]);
+        Consume::always(Ok(()))
+    })
+    .unwrap();
+
+    // Packets that don't fit in the slot are rejected
+    let slot = p.reserve_raw().unwrap();
+    header.set_payload_length(MIN_DATA_PAYLOAD_BUF as u8 + 1);
+    let raw = header.to_u16().to_le_bytes();
+    unsafe { slot.as_ptr().copy_from_nonoverlapping(raw.as_ptr(), 2) };
+    assert_eq!(p.commit_raw(), Err(Error::InvalidLength));
+    assert!(!c.has_data());
+}
+
+#[test]
+fn ring_queue_extended() {
+    run_tests(&mut RingQueue::<2, MAX_DATA_PDU_BUF>::new());
+    assert_eq!(
+        <&mut RingQueue<2, MAX_DATA_PDU_BUF> as PacketQueue>::MAX_PAYLOAD,
+        251
+    );
+
+    let mut queue = RingQueue::<2, MAX_DATA_PDU_BUF>::new();
+    let (mut p, mut c) = (&mut queue).split();
+    assert_eq!(p.free_space(), 251);
+    assert_eq!(p.reserve_raw().unwrap().size(), MAX_DATA_PDU_BUF);
+    p.produce_with(251, |writer| -> Result<_, Error> {
+        writer.write_slice(&[0xAB; 251])?;
+        Ok(Llid::DataStart)
+    })
+    .unwrap();
+    c.consume_raw_with(|header, data| -> Consume<()> {
+        assert_eq!(header.payload_length(), 251);
+        assert!(data.iter().all(|&b| b == 0xAB));
+        Consume::always(Ok(()))
+    })
+    .unwrap();
+}
@@ -1,8 +1,22 @@
-use crate::l2cap::{L2CAPState, L2CAPStateTx};
+use crate::att::AttributeServerTx;
+use crate::l2cap::{ChannelMapper, L2CAPState, L2CAPStateTx};
+use crate::link::ccm::Ccm;
 use crate::link::data::{Llid, Pdu};
-use crate::link::llcp::ControlPdu;
+use crate::link::llcp::{ControlOpcode, ControlPdu, PIN_OR_KEY_MISSING};
 use crate::link::queue::{Consume, Consumer, Producer};
-use crate::{bytes::ToBytes, config::*, utils::HexSlice, Error};
+use crate::link::{DeviceAddress, LinkError, Peer};
+use crate::security::{EncryptionRequest, SecurityManagerTx};
+use crate::{bytes::ToBytes, config::*, utils::Hex, utils::HexSlice, Error};
+
+#[cfg(feature = "async")]
+use crate::link::queue::QueueWaker;
+#[cfg(feature = "metrics")]
+use crate::time::Instant;
+
+type ConfAttributes<C> = <<C as Config>::ChannelMapper as ChannelMapper>::AttributeProvider;
+type ConfSecurity<C> = <<C as Config>::ChannelMapper as ChannelMapper>::SecurityLevel;
+#[cfg(feature = "async")]
+use core::{future::poll_fn, task::Poll};
 
 /// Data channel packet processor.
 ///
@@ -13,10 +27,27 @@ use crate::{bytes::ToBytes, config::*, utils::HexSlice, Error};
 /// Some *LL Control PDUs* sent as part of the Link Layer Control Protocol (LLCP) are answered by
 /// the responder directly, and all L2CAP data is forwarded to an `L2CAPState<M>`. Note that most
 /// LLCPDUs are handled directly by the real-time code.
+///
+/// When the Central starts encryption with `LL_ENC_REQ`, the responder looks up the key with
+/// `SecurityManager::encryption_request` and passes it to the Link-Layer. This requires the
+/// responder to be informed of the connection via [`connected`]. Once the link is encrypted (or
+/// encryption is paused), the responder calls `SecurityManager::encryption_changed` and passes the
+/// updated peer to `AttributeServer::set_peer`. After *LE Legacy Pairing*, the master encrypts the
+/// link with the STK, and the responder then distributes the negotiated keys.
+///
+/// With the `async` feature enabled, the responder can also be driven from an async task using
+/// [`process_one_async`] instead of polling it from the idle loop.
+///
+/// [`connected`]: #method.connected
+/// [`process_one_async`]: #method.process_one_async
 pub struct Responder<C: Config> {
     tx: ConfProducer<C>,
     rx: Option<ConfConsumer<C>>,
     l2cap: L2CAPState<C::ChannelMapper>,
+    /// Session key to pass to the Link-Layer once `LL_ENC_RSP` has been queued.
+    session_key: Option<Ccm>,
+    #[cfg(feature = "metrics")]
+    metrics_clock: Option<fn() -> Instant>,
 }
 
 impl<C: Config> Responder<C> {
@@ -30,21 +61,123 @@ impl<C: Config> Responder<C> {
             tx,
             rx: Some(rx),
             l2cap,
+            session_key: None,
+            #[cfg(feature = "metrics")]
+            metrics_clock: None,
         }
     }
 
+    /// Sets the clock used to time packet processing for the latency metrics.
+    ///
+    /// `clock` must return timestamps of the Link-Layer's `Timer`, usually read from a
+    /// `StampSource`. Without a clock, the queue wait and processing time of the `Responder` are
+    /// not recorded. Refer to the [`metrics`] module for details.
+    ///
+    /// [`metrics`]: ../../metrics/index.html
+    #[cfg(feature = "metrics")]
+    pub fn set_metrics_clock(&mut self, clock: fn() -> Instant) {
+        self.metrics_clock = Some(clock);
+    }
+
     /// Returns `true` when this responder has work to do.
     ///
     /// If this returns `true`, `process` may be called to process incoming packets and send
     /// outgoing ones.
     pub fn has_work(&mut self) -> bool {
-        self.with_rx(|rx, _| rx.has_data())
+        if self.session_key.is_some() || self.has_keys_to_distribute() {
+            return true;
+        }
+        let mut att = self.l2cap.channel_mapper().att();
+        let server = att.protocol();
+        if server.has_deferred_response() {
+            return true;
+        }
+        !server.is_stalled() && self.with_rx(|rx, _| rx.has_data())
     }
 
     /// Processes a single incoming packet in the packet queue.
     ///
-    /// Returns `Error::Eof` if there are no incoming packets in the RX queue.
+    /// Errors fall into three classes:
+    ///
+    /// * **No work**: `Error::Eof` is returned if there are no incoming packets in the RX queue.
+    /// * **Retry**: `Error::TxBusy` is returned if the response to the packet doesn't fit in the TX
+    ///   queue. The packet is kept in the RX queue and processing should be retried once
+    ///   `Cmd::tx_space_freed` is set. Similarly, `Error::RxBusy` is returned and the packet is
+    ///   kept if the `AttributeProvider` stalls an ATT *Write Command* (see
+    ///   `AttributeProvider::write_command_action`).
+    /// * **Fatal**: Errors for which `Error::is_fatal` returns `true` mean that the stack failed to
+    ///   encode a PDU the peer is waiting for, so the procedure in progress can never complete.
+    ///   The responder asks the peer to close the connection by sending an `LL_TERMINATE_IND` (if
+    ///   it fits in the TX queue), and returns the error so that it can be logged.
+    ///
+    /// Malformed packets sent by the peer are logged and dropped without returning an error, and
+    /// the connection is kept. No packet sent by the peer causes a panic, so there's no need to
+    /// `unwrap` the result: Calling `process_one` in a loop until it returns `Error::Eof` or
+    /// `Error::TxBusy` is safe, even in release builds of firmware that can't recover from a
+    /// panic.
+    ///
+    /// If the application has supplied the value for a deferred ATT read (see
+    /// `AttributeServer::complete_read`), the response is sent instead of processing a packet.
+    /// The same applies to the session key requested by the Central's `LL_ENC_REQ`, if it didn't
+    /// fit in the TX queue along with the `LL_ENC_RSP`, and to the keys distributed after pairing
+    /// once the link is encrypted with the STK.
     pub fn process_one(&mut self) -> Result<(), Error> {
+        if self.send_session_key()? {
+            return Ok(());
+        }
+        if self.has_keys_to_distribute() {
+            let security = self.security().ok_or(Error::TxBusy)?;
+            if security.distribute_key()? {
+                return Ok(());
+            }
+        }
+        if self.l2cap().send_deferred()? {
+            return Ok(());
+        }
+        if !self.with_rx(|rx, _| rx.has_data()) {
+            return Err(Error::Eof);
+        }
+
+        let result = self.process_timed();
+        match result {
+            Ok(()) | Err(Error::TxBusy) | Err(Error::RxBusy) => result,
+            Err(e) if e.is_fatal() => {
+                error!(logger: C::Logger, "fatal error, terminating connection: {:?}", e);
+                // 0x13 = Remote User Terminated Connection
+                if self.terminate(0x13).is_err() {
+                    warn!(logger: C::Logger, "no space for LL_TERMINATE_IND");
+                }
+                Err(e)
+            }
+            Err(e) => {
+                // The packet was consumed, so processing can continue with the next one
+                warn!(logger: C::Logger, "dropped malformed packet: {:?}", e);
+                Ok(())
+            }
+        }
+    }
+
+    /// Processes the next packet in the RX queue, recording its latency if enabled.
+    fn process_timed(&mut self) -> Result<(), Error> {
+        #[cfg(feature = "metrics")]
+        {
+            if let Some(clock) = self.metrics_clock {
+                let started = clock();
+                let tx_space = self.tx.free_space();
+                let result = self.process_packet();
+                if result.is_ok() {
+                    let responded = self.tx.free_space() < tx_space;
+                    crate::metrics::packet_handled(started, clock(), responded);
+                }
+                return result;
+            }
+        }
+
+        self.process_packet()
+    }
+
+    /// Processes the next packet in the RX queue.
+    fn process_packet(&mut self) -> Result<(), Error> {
         self.with_rx(|rx, this| {
             rx.consume_pdu_with(|_, pdu| match pdu {
                 Pdu::Control { data } => {
@@ -52,41 +185,279 @@ impl<C: Config> Responder<C> {
                     // https://github.com/jonas-schievink/rubble/issues/26
 
                     let pdu = data.read();
-                    info!("<- LL Control PDU: {:?}", pdu);
+                    info!(logger: C::Logger, "<- LL Control PDU: {:?}", pdu);
                     let response = match pdu {
-                        // These PDUs are handled by the real-time code:
+                        // These PDUs are answered by the real-time code and never queued
                         ControlPdu::FeatureReq { .. } | ControlPdu::VersionInd { .. } => {
-                            unreachable!("LLCPDU not handled by LL");
+                            warn!(logger: C::Logger, "LLCPDU not handled by LL, dropping");
+                            return Consume::always(Ok(()));
+                        }
+                        ControlPdu::EncReq {
+                            rand,
+                            ediv,
+                            skd_m,
+                            iv_m,
+                        } => {
+                            let rsp_size = ControlPdu::EncRsp { skd_s: 0, iv_s: 0 }.encoded_size();
+                            if this.tx.free_space() < rsp_size {
+                                return Consume::never(Err(Error::TxBusy));
+                            }
+
+                            let req = EncryptionRequest {
+                                rand,
+                                ediv,
+                                skd_m,
+                                iv_m,
+                            };
+                            let mut security = this.l2cap.channel_mapper().security();
+                            match security.protocol().encryption_request(&req) {
+                                Some(key) => {
+                                    this.session_key = Some(Ccm::new(key.key, key.iv));
+                                    ControlPdu::EncRsp {
+                                        skd_s: key.skd_s,
+                                        iv_s: key.iv_s,
+                                    }
+                                }
+                                // The legacy reject PDU may always be used for this procedure
+                                None => ControlPdu::RejectInd {
+                                    error_code: Hex(PIN_OR_KEY_MISSING),
+                                },
+                            }
+                        }
+                        ControlPdu::StartEncRsp | ControlPdu::PauseEncRsp => {
+                            // Passed on by the Link-Layer after it enabled or paused encryption
+                            this.encryption_changed(matches!(pdu, ControlPdu::StartEncRsp));
+                            return Consume::always(Ok(()));
                         }
                         _ => ControlPdu::UnknownRsp {
                             unknown_type: pdu.opcode(),
                         },
                     };
-                    info!("-> Response: {:?}", response);
+                    info!(logger: C::Logger, "-> Response: {:?}", response);
 
                     // Consume the LL Control PDU iff we can fit the response in the TX buffer:
+                    if this.tx.free_space() < response.encoded_size() {
+                        return Consume::never(Err(Error::TxBusy));
+                    }
                     Consume::on_success(this.tx.produce_with(response.encoded_size(), |writer| {
                         response.to_bytes(writer)?;
                         Ok(Llid::Control)
                     }))
                 }
                 Pdu::DataStart { message } => {
-                    info!("L2start: {:?}", HexSlice(message));
+                    info!(logger: C::Logger, "L2start: {:?}", HexSlice(message));
                     this.l2cap().process_start(message)
                 }
                 Pdu::DataCont { message } => {
-                    info!("L2cont {:?}", HexSlice(message));
+                    info!(logger: C::Logger, "L2cont {:?}", HexSlice(message));
                     this.l2cap().process_cont(message)
                 }
             })
         })
     }
 
+    /// Returns `true` when a packet with a payload of `payload_bytes` fits in the TX queue.
+    pub fn has_tx_space(&self, payload_bytes: u8) -> bool {
+        self.tx.free_space() >= payload_bytes
+    }
+
+    /// Waits until there is an incoming packet to process.
+    ///
+    /// The returned future resolves once `has_work` returns `true`. `events` must be woken
+    /// whenever the Link-Layer has processed a radio event.
+    #[cfg(feature = "async")]
+    pub async fn wait_for_work(&mut self, events: &QueueWaker) {
+        poll_fn(|cx| {
+            events.register(cx.waker());
+            if self.has_work() {
+                Poll::Ready(())
+            } else {
+                Poll::Pending
+            }
+        })
+        .await
+    }
+
+    /// Waits until a packet with a payload of `payload_bytes` can be enqueued in the TX queue.
+    ///
+    /// `events` must be woken whenever the Link-Layer has processed a radio event.
+    #[cfg(feature = "async")]
+    pub async fn wait_for_tx_space(&mut self, events: &QueueWaker, payload_bytes: u8) {
+        poll_fn(|cx| {
+            events.register(cx.waker());
+            if self.has_tx_space(payload_bytes) {
+                Poll::Ready(())
+            } else {
+                Poll::Pending
+            }
+        })
+        .await
+    }
+
+    /// Waits for an incoming packet and processes it.
+    ///
+    /// This is the async counterpart to `process_one`. If the response to the packet doesn't fit
+    /// in the TX queue, this waits for the Link-Layer to transmit queued packets and tries again,
+    /// so neither `Error::Eof` nor `Error::TxBusy` are ever returned. Packets stalled by the
+    /// `AttributeProvider` are retried once `has_work` returns `true` again, so `Error::RxBusy`
+    /// isn't returned either.
+    ///
+    /// `events` must be woken whenever the Link-Layer has processed a radio event.
+    #[cfg(feature = "async")]
+    pub async fn process_one_async(&mut self, events: &QueueWaker) -> Result<(), Error> {
+        loop {
+            self.wait_for_work(events).await;
+            match self.process_one() {
+                Err(Error::TxBusy) => {
+                    // The packet is still in the RX queue. Wait for the next radio event, which
+                    // might free up space in the TX queue.
+                    let mut woken = false;
+                    poll_fn(|cx| {
+                        if woken {
+                            Poll::Ready(())
+                        } else {
+                            woken = true;
+                            events.register(cx.waker());
+                            Poll::Pending
+                        }
+                    })
+                    .await
+                }
+                // Stalled until the provider is ready, which `wait_for_work` waits for
+                Err(Error::RxBusy) => {}
+                result => return result,
+            }
+        }
+    }
+
+    /// Asks the connected device to change its transmit power by `delta` dB.
+    ///
+    /// This sends an `LL_POWER_CONTROL_REQ` for the LE 1M PHY, which can be used to pass on power
+    /// hints derived from the RSSI of received packets. `tx_power` is our own transmit power in
+    /// dBm. The peer's response is handled by the Link-Layer.
+    ///
+    /// This should only be used when the peer supports the LE Power Control procedure. Returns
+    /// `LinkError::TxBusy` if the TX queue is full.
+    pub fn send_power_control_request(&mut self, delta: i8, tx_power: i8) -> Result<(), LinkError> {
+        let pdu = ControlPdu::PowerControlReq {
+            phy: 0b001,
+            delta,
+            tx_power,
+        };
+        if !self.has_tx_space(pdu.encoded_size()) {
+            return Err(LinkError::TxBusy);
+        }
+        info!(logger: C::Logger, "-> LL Control PDU: {:?}", pdu);
+        self.tx
+            .produce_with(pdu.encoded_size(), |writer| -> Result<_, Error> {
+                pdu.to_bytes(writer)?;
+                Ok(Llid::Control)
+            })
+            .map_err(|_| LinkError::Encoding)
+    }
+
+    /// Passes the session key to the Link-Layer after `LL_ENC_RSP` was queued.
+    ///
+    /// The key is queued behind an `LL_START_ENC_REQ` opcode. The Link-Layer installs it and only
+    /// sends the opcode. Returns `Ok(true)` if the key was queued, and `Err(Error::TxBusy)` if it
+    /// doesn't fit in the TX queue yet.
+    fn send_session_key(&mut self) -> Result<bool, Error> {
+        let ccm = match self.session_key {
+            Some(ccm) => ccm,
+            None => return Ok(false),
+        };
+
+        let len = 1 + Ccm::RAW_LEN as u8;
+        if self.tx.free_space() < len {
+            return Err(Error::TxBusy);
+        }
+        self.tx.produce_with(len, |writer| -> Result<_, Error> {
+            writer.write_u8(ControlOpcode::StartEncReq.into())?;
+            writer.write_slice(&ccm.to_raw())?;
+            Ok(Llid::Control)
+        })?;
+        self.session_key = None;
+        Ok(true)
+    }
+
+    /// Updates the security level of the link after the Link-Layer enabled or paused encryption.
+    fn encryption_changed(&mut self, encrypted: bool) {
+        info!(logger: C::Logger, "link encryption: {:?}", encrypted);
+        let mut security = self.l2cap.channel_mapper().security();
+        let manager = security.protocol();
+        manager.encryption_changed(encrypted);
+        let peer = manager.peer().copied();
+
+        // Don't clear a peer that was only passed to the ATT server
+        if peer.is_some() {
+            self.l2cap.channel_mapper().att().protocol().set_peer(peer);
+        }
+    }
+
+    /// Informs the host stack about a newly established connection.
+    ///
+    /// This must be called whenever the Link-Layer has established a connection, passing our
+    /// `local` device address and the `peer` returned by `Connection::peer`. It is passed on to
+    /// `SecurityManager::connected` and `AttributeServer::set_peer`.
+    pub fn connected(&mut self, local: DeviceAddress, peer: Peer) {
+        self.session_key = None;
+        let mapper = self.l2cap.channel_mapper();
+        mapper.security().protocol().connected(local, peer);
+        mapper.att().protocol().set_peer(Some(peer));
+    }
+
+    /// Returns whether the `SecurityManager` has keys to send after pairing.
+    fn has_keys_to_distribute(&mut self) -> bool {
+        let mut security = self.l2cap.channel_mapper().security();
+        security.protocol().has_keys_to_distribute()
+    }
+
+    /// Asks the connected device to close the connection by sending an `LL_TERMINATE_IND`.
+    ///
+    /// `error_code` is the HCI error code indicating the reason, usually `0x13` (*Remote User
+    /// Terminated Connection*). The Link-Layer closes the connection once the peer has acknowledged
+    /// the `LL_TERMINATE_IND`.
+    ///
+    /// Returns `LinkError::TxBusy` if the TX queue is full.
+    pub fn terminate(&mut self, error_code: u8) -> Result<(), LinkError> {
+        let pdu = ControlPdu::TerminateInd {
+            error_code: Hex(error_code),
+        };
+        if !self.has_tx_space(pdu.encoded_size()) {
+            return Err(LinkError::TxBusy);
+        }
+        info!(logger: C::Logger, "-> LL Control PDU: {:?}", pdu);
+        self.tx
+            .produce_with(pdu.encoded_size(), |writer| -> Result<_, Error> {
+                pdu.to_bytes(writer)?;
+                Ok(Llid::Control)
+            })
+            .map_err(|_| LinkError::Encoding)
+    }
+
     /// Obtains access to the L2CAP instance.
+    ///
+    /// This does not require an incoming packet, so it can be used to initiate procedures from the
+    /// device side (eg. sending notifications or L2CAP signaling requests) at any time.
     pub fn l2cap(&mut self) -> L2CAPStateTx<'_, C::ChannelMapper, ConfProducer<C>> {
         self.l2cap.tx(&mut self.tx)
     }
 
+    /// Prepares for sending a device-initiated ATT PDU, such as an attribute notification.
+    ///
+    /// Returns `None` if there's not enough space in the TX queue to send an ATT PDU. Calling this
+    /// again after the Link-Layer has transmitted queued packets might succeed.
+    pub fn att(&mut self) -> Option<AttributeServerTx<'_, ConfAttributes<C>>> {
+        self.l2cap.tx(&mut self.tx).into_att()
+    }
+
+    /// Prepares for sending a device-initiated Security Manager PDU.
+    ///
+    /// Returns `None` if there's not enough space in the TX queue to send an SMP PDU.
+    pub fn security(&mut self) -> Option<SecurityManagerTx<'_, ConfSecurity<C>>> {
+        self.l2cap.tx(&mut self.tx).into_security()
+    }
+
     /// A helper method that splits `self` into the `rx` and the remaining `Self`.
     ///
     /// This can possibly be removed after *RFC 2229 (Closures Capture Disjoint Fields)* is
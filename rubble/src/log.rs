@@ -1,49 +1,179 @@
-#[cfg(feature = "log")]
-macro_rules! error {
-    ($($t:tt)*) => {{ log::error!($($t)*); }};
+//! Logging facilities.
+//!
+//! Rubble logs through two independent channels:
+//!
+//! * The global backend selected via Cargo features. With the `log` feature, messages are passed
+//!   to the `log` crate, and with `defmt-log` to `defmt`. With `defmt`, format strings are interned
+//!   and only the arguments are transferred, which keeps logging cheap enough to use from the
+//!   real-time Link-Layer code. If neither feature is enabled, the arguments are type-checked, but
+//!   nothing is logged.
+//! * The [`Logger`] configured via [`Config::Logger`]. This receives the messages emitted by all
+//!   parts of the stack that are parameterized with a [`Config`] (the Link-Layer, the
+//!   [`Responder`] and the HCI [`Controller`]), which allows routing them to an arbitrary sink
+//!   without enabling any Cargo features, and allows two stacks in the same binary to log
+//!   separately.
+//!
+//! [`Logger`]: trait.Logger.html
+//! [`Config::Logger`]: ../config/trait.Config.html#associatedtype.Logger
+//! [`Config`]: ../config/trait.Config.html
+//! [`Responder`]: ../link/responder/struct.Responder.html
+//! [`Controller`]: ../hci/struct.Controller.html
+
+use core::fmt;
+
+#[cfg(all(feature = "log", feature = "defmt-log"))]
+compile_error!("the `log` and `defmt-log` features are mutually exclusive");
+
+/// Severity of a log message.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, defmt::Format)]
+pub enum Level {
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
 }
 
-#[cfg(feature = "log")]
-macro_rules! warn {
-    ($($t:tt)*) => {{ log::warn!($($t)*); }};
+/// A sink for the log messages of a Rubble stack.
+///
+/// The sink in use is selected by [`Config::Logger`]. Like the rest of the configuration, loggers
+/// are types rather than values, so implementations that need state (eg. a UART handle) have to
+/// keep it in a `static`.
+///
+/// Messages are formatted lazily: Nothing is formatted unless `enabled` returns `true` for their
+/// level.
+///
+/// [`Config::Logger`]: ../config/trait.Config.html#associatedtype.Logger
+pub trait Logger {
+    /// Returns whether messages of the given `level` should be passed to `log`.
+    ///
+    /// This is called before formatting each message. The default implementation enables all
+    /// levels.
+    fn enabled(_level: Level) -> bool {
+        true
+    }
+
+    /// Logs a message of the given `level`.
+    ///
+    /// This is called from the context the message originates from, which for the Link-Layer is
+    /// usually an interrupt handler, so it must not block for long.
+    fn log(level: Level, args: fmt::Arguments<'_>);
 }
 
-#[cfg(feature = "log")]
-macro_rules! info {
-    ($($t:tt)*) => {{ log::info!($($t)*); }};
+/// A `Logger` that discards all messages.
+pub struct NoLogger;
+
+impl Logger for NoLogger {
+    fn enabled(_level: Level) -> bool {
+        false
+    }
+
+    fn log(_level: Level, _args: fmt::Arguments<'_>) {}
 }
 
+// The macros below take an optional `logger: <type>,` prefix. If present, the message is also
+// passed to that `Logger` (usually `C::Logger`).
+
 #[cfg(feature = "log")]
-macro_rules! debug {
-    ($($t:tt)*) => {{ log::debug!($($t)*); }};
+macro_rules! log_global {
+    (Error, $($t:tt)*) => {{ log::error!($($t)*); }};
+    (Warn, $($t:tt)*) => {{ log::warn!($($t)*); }};
+    (Info, $($t:tt)*) => {{ log::info!($($t)*); }};
+    (Debug, $($t:tt)*) => {{ log::debug!($($t)*); }};
+    (Trace, $($t:tt)*) => {{ log::trace!($($t)*); }};
 }
 
-#[cfg(feature = "log")]
-macro_rules! trace {
-    ($($t:tt)*) => {{ log::trace!($($t)*); }};
+#[cfg(feature = "defmt-log")]
+macro_rules! log_global {
+    (Error, $($t:tt)*) => {{ defmt::error!($($t)*); }};
+    (Warn, $($t:tt)*) => {{ defmt::warn!($($t)*); }};
+    (Info, $($t:tt)*) => {{ defmt::info!($($t)*); }};
+    (Debug, $($t:tt)*) => {{ defmt::debug!($($t)*); }};
+    (Trace, $($t:tt)*) => {{ defmt::trace!($($t)*); }};
+}
+
+#[cfg(not(any(feature = "log", feature = "defmt-log")))]
+macro_rules! log_global {
+    ($level:ident, $($t:tt)*) => {{ format_args!($($t)*); }};
+}
+
+macro_rules! log_to {
+    ($logger:ty, $level:ident, $($t:tt)*) => {{
+        use crate::log::{Level, Logger};
+        if <$logger as Logger>::enabled(Level::$level) {
+            <$logger as Logger>::log(Level::$level, format_args!($($t)*));
+        }
+    }};
 }
 
-#[cfg(not(feature = "log"))]
 macro_rules! error {
-    ($($t:tt)*) => {{ format_args!($($t)*); }};
+    (logger: $logger:ty, $($t:tt)*) => {{
+        log_global!(Error, $($t)*);
+        log_to!($logger, Error, $($t)*);
+    }};
+    ($($t:tt)*) => { log_global!(Error, $($t)*) };
 }
 
-#[cfg(not(feature = "log"))]
 macro_rules! warn {
-    ($($t:tt)*) => {{ format_args!($($t)*); }};
+    (logger: $logger:ty, $($t:tt)*) => {{
+        log_global!(Warn, $($t)*);
+        log_to!($logger, Warn, $($t)*);
+    }};
+    ($($t:tt)*) => { log_global!(Warn, $($t)*) };
 }
 
-#[cfg(not(feature = "log"))]
 macro_rules! info {
-    ($($t:tt)*) => {{ format_args!($($t)*); }};
+    (logger: $logger:ty, $($t:tt)*) => {{
+        log_global!(Info, $($t)*);
+        log_to!($logger, Info, $($t)*);
+    }};
+    ($($t:tt)*) => { log_global!(Info, $($t)*) };
 }
 
-#[cfg(not(feature = "log"))]
 macro_rules! debug {
-    ($($t:tt)*) => {{ format_args!($($t)*); }};
+    (logger: $logger:ty, $($t:tt)*) => {{
+        log_global!(Debug, $($t)*);
+        log_to!($logger, Debug, $($t)*);
+    }};
+    ($($t:tt)*) => { log_global!(Debug, $($t)*) };
 }
 
-#[cfg(not(feature = "log"))]
 macro_rules! trace {
-    ($($t:tt)*) => {{ format_args!($($t)*); }};
+    (logger: $logger:ty, $($t:tt)*) => {{
+        log_global!(Trace, $($t)*);
+        log_to!($logger, Trace, $($t)*);
+    }};
+    ($($t:tt)*) => { log_global!(Trace, $($t)*) };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    static MESSAGES: Mutex<Vec<(Level, String)>> = Mutex::new(Vec::new());
+
+    struct TestLogger;
+
+    impl Logger for TestLogger {
+        fn enabled(level: Level) -> bool {
+            level <= Level::Info
+        }
+
+        fn log(level: Level, args: fmt::Arguments<'_>) {
+            MESSAGES.lock().unwrap().push((level, args.to_string()));
+        }
+    }
+
+    #[test]
+    fn logger() {
+        info!(logger: TestLogger, "connected after {} attempts", 2);
+        trace!(logger: TestLogger, "filtered out");
+        warn!("global only");
+
+        assert_eq!(
+            *MESSAGES.lock().unwrap(),
+            [(Level::Info, "connected after 2 attempts".to_string())]
+        );
+    }
 }
@@ -0,0 +1,292 @@
+//! Latency instrumentation of the data path.
+//!
+//! This module is only available with the `metrics` Cargo feature. It records how long a packet
+//! takes to travel through the stack, so that applications can verify that their MCU meets the
+//! timing requirements of BLE:
+//!
+//! * **Link-Layer processing** (`Metrics::link_layer`): From the end of the received packet (the
+//!   `rx_end` passed to `LinkLayer::process_data_packet`) until the Link-Layer has prepared the
+//!   response and handed the received packet to the RX queue. The radio starts transmitting the
+//!   response `Duration::T_IFS` (150 µs) after the end of the received packet, so this must stay
+//!   well below that.
+//! * **Queue wait** (`Metrics::queue_wait`): From the packet being put into the RX queue until the
+//!   `Responder` starts processing it.
+//! * **Responder processing** (`Metrics::responder`): The time `Responder::process_one` takes to
+//!   process the packet (eg. to answer an ATT request).
+//! * **TX wait** (`Metrics::tx_wait`): From the `Responder` putting its response into the TX queue
+//!   until the Link-Layer transmits it. This is usually dominated by the time until the next
+//!   connection event.
+//!
+//! The sum of the last three stages is the latency an L2CAP request sees on top of the connection
+//! interval. If the queue wait is close to a connection interval, the `Responder` is not run
+//! often enough to answer requests within the next connection event.
+//!
+//! The Link-Layer stages are timed with its `Timer`. The `Responder` doesn't have access to a
+//! timer, so the queue wait and Responder processing are only recorded when a clock is passed to
+//! `Responder::set_metrics_clock`. The clock must return timestamps based on the same timer, for
+//! example from the `StampSource` provided by the radio driver.
+//!
+//! All stacks in a program share the same metrics. Every histogram is only updated from one
+//! context (the Link-Layer or the `Responder`), using plain atomic loads and stores, so recording
+//! works on targets without atomic read-modify-write operations. When several packets are queued
+//! before the `Responder` runs, the queue wait is measured from the last of them.
+
+use crate::time::{Duration, Instant};
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+
+/// Number of buckets in a `Histogram`.
+pub const BUCKETS: usize = BUCKET_BOUNDS.len() + 1;
+
+/// Exclusive upper bounds of all but the last `Histogram` bucket, in microseconds.
+///
+/// The last bucket holds all durations of at least 50 ms.
+pub const BUCKET_BOUNDS: [u32; 11] = [
+    25, 50, 100, 150, 250, 500, 1_000, 2_500, 5_000, 10_000, 50_000,
+];
+
+/// A snapshot of the distribution of durations recorded for a stage.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, defmt::Format)]
+pub struct Histogram {
+    counts: [u32; BUCKETS],
+    max: Duration,
+}
+
+impl Histogram {
+    /// Returns the number of recorded durations.
+    pub fn count(&self) -> u32 {
+        self.counts
+            .iter()
+            .fold(0, |sum, &count| sum.wrapping_add(count))
+    }
+
+    /// Returns the longest recorded duration.
+    pub fn max(&self) -> Duration {
+        self.max
+    }
+
+    /// Returns the number of recorded durations per bucket.
+    ///
+    /// Bucket `i` holds durations below `BUCKET_BOUNDS[i]` (and at least the bound of the previous
+    /// bucket).
+    pub fn buckets(&self) -> &[u32; BUCKETS] {
+        &self.counts
+    }
+
+    /// Returns the number of recorded durations of at least `limit`.
+    ///
+    /// Durations are only counted in buckets that start at or above `limit`, so this is exact if
+    /// `limit` is one of the `BUCKET_BOUNDS` (like `Duration::T_IFS`), and an underestimate
+    /// otherwise.
+    pub fn count_at_least(&self, limit: Duration) -> u32 {
+        let limit = limit.as_micros();
+        let first = if limit == 0 {
+            0
+        } else {
+            // Bucket `i + 1` starts at `BUCKET_BOUNDS[i]`
+            BUCKET_BOUNDS
+                .iter()
+                .position(|&bound| bound >= limit)
+                .map_or(BUCKETS, |index| index + 1)
+        };
+        self.counts[first..]
+            .iter()
+            .fold(0, |sum, &count| sum.wrapping_add(count))
+    }
+}
+
+/// Latency histograms of all stages of the data path, as returned by [`metrics`].
+///
+/// [`metrics`]: fn.metrics.html
+#[derive(Debug, Copy, Clone, PartialEq, Eq, defmt::Format)]
+pub struct Metrics {
+    /// Received packet end to Link-Layer processing done.
+    pub link_layer: Histogram,
+    /// Packet put into the RX queue to `Responder` processing start.
+    pub queue_wait: Histogram,
+    /// `Responder` processing start to end.
+    pub responder: Histogram,
+    /// Response put into the TX queue to its transmission.
+    pub tx_wait: Histogram,
+}
+
+/// Returns the latency histograms recorded since startup or the last call to [`reset`].
+///
+/// [`reset`]: fn.reset.html
+pub fn metrics() -> Metrics {
+    Metrics {
+        link_layer: LINK_LAYER.snapshot(),
+        queue_wait: QUEUE_WAIT.snapshot(),
+        responder: RESPONDER.snapshot(),
+        tx_wait: TX_WAIT.snapshot(),
+    }
+}
+
+/// Clears all histograms.
+///
+/// Durations recorded while this runs might be lost.
+pub fn reset() {
+    for recorder in &[&LINK_LAYER, &QUEUE_WAIT, &RESPONDER, &TX_WAIT] {
+        recorder.reset();
+    }
+    QUEUED_AT.clear();
+    RESPONDED_AT.clear();
+}
+
+static LINK_LAYER: Recorder = Recorder::new();
+static QUEUE_WAIT: Recorder = Recorder::new();
+static RESPONDER: Recorder = Recorder::new();
+static TX_WAIT: Recorder = Recorder::new();
+
+/// When the Link-Layer last put a packet into the RX queue.
+static QUEUED_AT: Stamp = Stamp::new();
+/// When the `Responder` last put a response into the TX queue.
+static RESPONDED_AT: Stamp = Stamp::new();
+
+/// Called by the Link-Layer once it has processed a received packet.
+pub(crate) fn packet_processed(rx_end: Instant, now: Instant, queued: bool) {
+    LINK_LAYER.record_between(rx_end, now);
+    if queued {
+        QUEUED_AT.set(now);
+    }
+}
+
+/// Called by the Link-Layer when it transmits a packet taken from the TX queue.
+pub(crate) fn packet_sent(tx_time: Instant) {
+    if let Some(responded_at) = RESPONDED_AT.take() {
+        TX_WAIT.record_between(responded_at, tx_time);
+    }
+}
+
+/// Called by the `Responder` after processing a packet it started processing at `started`.
+///
+/// `responded` indicates whether a response was put into the TX queue.
+pub(crate) fn packet_handled(started: Instant, finished: Instant, responded: bool) {
+    if let Some(queued_at) = QUEUED_AT.take() {
+        QUEUE_WAIT.record_between(queued_at, started);
+    }
+    RESPONDER.record_between(started, finished);
+    if responded {
+        RESPONDED_AT.set(finished);
+    }
+}
+
+/// A histogram that is updated from a single context and can be read from any context.
+struct Recorder {
+    counts: [AtomicU32; BUCKETS],
+    max: AtomicU32,
+}
+
+impl Recorder {
+    const fn new() -> Self {
+        // Only used as an initializer, every use creates a new atomic
+        #[allow(clippy::declare_interior_mutable_const)]
+        const ZERO: AtomicU32 = AtomicU32::new(0);
+        Self {
+            counts: [ZERO; BUCKETS],
+            max: ZERO,
+        }
+    }
+
+    /// Records the time between `earlier` and `later`.
+    ///
+    /// Timestamps handed between contexts might be out of order, those are ignored.
+    fn record_between(&self, earlier: Instant, later: Instant) {
+        if let Some(duration) = later.checked_duration_since(earlier) {
+            self.record(duration);
+        }
+    }
+
+    fn record(&self, duration: Duration) {
+        let micros = duration.as_micros();
+        let bucket = BUCKET_BOUNDS
+            .iter()
+            .position(|&bound| micros < bound)
+            .unwrap_or(BUCKETS - 1);
+
+        // There's only one writer, so this doesn't need an atomic read-modify-write operation
+        let count = &self.counts[bucket];
+        count.store(
+            count.load(Ordering::Relaxed).wrapping_add(1),
+            Ordering::Relaxed,
+        );
+        if micros > self.max.load(Ordering::Relaxed) {
+            self.max.store(micros, Ordering::Relaxed);
+        }
+    }
+
+    fn snapshot(&self) -> Histogram {
+        let mut counts = [0; BUCKETS];
+        for (count, recorded) in counts.iter_mut().zip(&self.counts) {
+            *count = recorded.load(Ordering::Relaxed);
+        }
+        Histogram {
+            counts,
+            max: Duration::from_micros(self.max.load(Ordering::Relaxed)),
+        }
+    }
+
+    fn reset(&self) {
+        for count in &self.counts {
+            count.store(0, Ordering::Relaxed);
+        }
+        self.max.store(0, Ordering::Relaxed);
+    }
+}
+
+/// A timestamp handed from one context to another.
+struct Stamp {
+    micros: AtomicU32,
+    valid: AtomicBool,
+}
+
+impl Stamp {
+    const fn new() -> Self {
+        Self {
+            micros: AtomicU32::new(0),
+            valid: AtomicBool::new(false),
+        }
+    }
+
+    fn set(&self, at: Instant) {
+        self.micros.store(at.raw_micros(), Ordering::Relaxed);
+        self.valid.store(true, Ordering::Release);
+    }
+
+    fn take(&self) -> Option<Instant> {
+        if !self.valid.load(Ordering::Acquire) {
+            return None;
+        }
+        let at = Instant::from_raw_micros(self.micros.load(Ordering::Relaxed));
+        self.valid.store(false, Ordering::Relaxed);
+        Some(at)
+    }
+
+    fn clear(&self) {
+        self.valid.store(false, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn histogram() {
+        let recorder = Recorder::new();
+        for &micros in &[0, 24, 25, 149, 150, 151, 49_999, 50_000, 1_000_000] {
+            recorder.record(Duration::from_micros(micros));
+        }
+
+        let histogram = recorder.snapshot();
+        assert_eq!(histogram.count(), 9);
+        assert_eq!(histogram.max(), Duration::from_micros(1_000_000));
+        assert_eq!(histogram.buckets(), &[2, 1, 0, 1, 2, 0, 0, 0, 0, 0, 1, 2]);
+        assert_eq!(histogram.count_at_least(Duration::T_IFS), 5);
+        assert_eq!(histogram.count_at_least(Duration::from_micros(0)), 9);
+        assert_eq!(histogram.count_at_least(Duration::from_micros(50_000)), 2);
+        assert_eq!(histogram.count_at_least(Duration::from_micros(60_000)), 0);
+
+        recorder.reset();
+        assert_eq!(recorder.snapshot().count(), 0);
+    }
+}
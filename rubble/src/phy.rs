@@ -8,6 +8,15 @@
 //! that indices 0..=36 refer to data channels and 37..=39 refer to the advertising channels
 //! (presumably to simplify channel hopping). The Link-Layer is only interested in these channel
 //! indices, so only those are implemented here.
+//!
+//! For radios without BLE support, software implementations of data whitening ([`whiten`]) and
+//! the CRC ([`crc24`]) are provided.
+//!
+//! [`whiten`]: fn.whiten.html
+//! [`crc24`]: fn.crc24.html
+
+use crate::link::CRC_POLY;
+use crate::Error;
 
 /// Returns the center frequency in MHz corresponding to an RF channel.
 fn rf_channel_freq(rf_channel: u8) -> u16 {
@@ -20,8 +29,58 @@ fn whitening_iv(channel_idx: u8) -> u8 {
     0b01000000 | channel_idx
 }
 
+/// Applies data whitening to `data`, or removes it.
+///
+/// `iv` is the initial value of the whitening LFSR as returned by `AdvertisingChannel::whitening_iv`
+/// or `DataChannel::whitening_iv`. Whitening is applied to the PDU and CRC, and needs to be applied
+/// to them in a single call, since the LFSR state carries over between Bytes.
+pub fn whiten(iv: u8, data: &mut [u8]) {
+    // `iv` stores position 0 in bit 6 and position 6 in bit 0. Convert it so that bit N holds
+    // position N.
+    let mut lfsr = iv.reverse_bits() >> 1;
+    for byte in data {
+        for bit in 0..8 {
+            // Polynomial x^7 + x^4 + 1
+            let out = (lfsr >> 6) & 1;
+            lfsr = ((lfsr << 1) & 0x7F) | out;
+            lfsr ^= out << 4;
+            *byte ^= out << bit;
+        }
+    }
+}
+
+/// Calculates the 24-bit CRC of `data`, starting with the shift register set to `crc_init`.
+///
+/// The bits of `data` are processed in transmission order (LSb first). Position 0 of the shift
+/// register is the LSb of `crc_init` and of the returned value.
+pub fn crc24(crc_init: u32, data: &[u8]) -> u32 {
+    let mut state = crc_init & 0xFF_FFFF;
+    for byte in data {
+        for bit in 0..8 {
+            let feedback = ((state >> 23) ^ u32::from(byte >> bit)) & 1;
+            state = (state << 1) & 0xFF_FFFF;
+            if feedback != 0 {
+                state ^= CRC_POLY & 0xFF_FFFF;
+            }
+        }
+    }
+    state
+}
+
+/// Converts a CRC returned by `crc24` to the 3 Bytes sent over the air.
+///
+/// The CRC is transmitted starting with position 23, while Bytes are sent LSb first.
+pub fn crc_bytes(crc: u32) -> [u8; 3] {
+    [
+        ((crc >> 16) as u8).reverse_bits(),
+        ((crc >> 8) as u8).reverse_bits(),
+        (crc as u8).reverse_bits(),
+    ]
+}
+
 /// One of the three advertising channels (channel indices 37, 38 or 39).
 #[derive(Copy, Clone, Debug, defmt::Format)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 pub struct AdvertisingChannel(u8);
 
 impl AdvertisingChannel {
@@ -87,10 +146,72 @@ impl AdvertisingChannel {
     }
 }
 
+/// The set of advertising channels a device advertises on.
+///
+/// By default, all 3 advertising channels are used. Advertising on fewer channels makes a device
+/// harder to find and more susceptible to interference, so this should only be restricted for
+/// testing, eg. to measure a single channel in an RF chamber.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, defmt::Format)]
+pub struct AdvertisingChannelMap(u8);
+
+impl AdvertisingChannelMap {
+    /// Advertise on all 3 advertising channels.
+    pub const ALL: Self = AdvertisingChannelMap(0b111);
+
+    /// Creates a map from the bitmap used by the HCI (`Advertising_Channel_Map`).
+    ///
+    /// Bit 0 enables channel 37, bit 1 channel 38 and bit 2 channel 39. Returns
+    /// `Error::InvalidValue` if no channel is enabled or any other bit is set.
+    pub fn from_raw(raw: u8) -> Result<Self, Error> {
+        if raw == 0 || raw & !0b111 != 0 {
+            Err(Error::InvalidValue)
+        } else {
+            Ok(AdvertisingChannelMap(raw))
+        }
+    }
+
+    /// Creates a map that only enables `channel`.
+    pub fn single(channel: AdvertisingChannel) -> Self {
+        AdvertisingChannelMap(1 << (channel.0 - 37))
+    }
+
+    /// Returns the bitmap used by the HCI.
+    pub fn to_raw(&self) -> u8 {
+        self.0
+    }
+
+    /// Returns whether `channel` is enabled.
+    pub fn contains(&self, channel: AdvertisingChannel) -> bool {
+        self.0 & (1 << (channel.0 - 37)) != 0
+    }
+
+    /// Returns an iterator over the enabled channels in ascending order.
+    pub fn iter(&self) -> impl Iterator<Item = AdvertisingChannel> {
+        let map = *self;
+        AdvertisingChannel::iter_all().filter(move |channel| map.contains(*channel))
+    }
+
+    /// Returns the next enabled channel after `channel`, wrapping around after channel 39.
+    pub fn next_after(&self, channel: AdvertisingChannel) -> AdvertisingChannel {
+        let mut next = channel.cycle();
+        while !self.contains(next) {
+            next = next.cycle();
+        }
+        next
+    }
+}
+
+impl Default for AdvertisingChannelMap {
+    fn default() -> Self {
+        Self::ALL
+    }
+}
+
 /// One of 37 data channels on which data channel PDUs are sent between connected devices.
 ///
 /// (channel indices 0..=36)
 #[derive(Copy, Clone, Debug, PartialEq, Eq, defmt::Format)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 pub struct DataChannel(u8);
 
 impl DataChannel {
@@ -145,9 +266,149 @@ impl DataChannel {
 /// You probably won't need to implement this trait, unless you're working with hardware that has
 /// absolutely no special support for BLE. Usually, the Link-Layer `Transmitter` should be
 /// implemented.
+///
+/// Such radios are driven by a software Link-Layer backend (see the `rubble-softmac` crate), which
+/// assembles the complete packet including preamble, Access Address and CRC, and applies data
+/// whitening. The radio only has to transmit and receive raw bits using 1 Mbit/s GFSK modulation.
 pub trait Radio {
     /// Transmit every Byte in `buf` over the air, LSb first, at `freq` MHz.
     ///
-    /// TODO: Document all radio requirements
+    /// `buf` contains the complete packet starting with the preamble. Transmission should start
+    /// immediately.
     fn transmit(&mut self, buf: &mut [u8], freq: u16);
+
+    /// Transmit `buf` like `transmit`, but start exactly `T_IFS` after the end of the last
+    /// received packet.
+    ///
+    /// This is used to respond to received packets. Since software can't reliably meet the
+    /// required ±2 µs accuracy, implementations should use a hardware timer or the transceiver's
+    /// own scheduling support.
+    fn transmit_response(&mut self, buf: &mut [u8], freq: u16);
+
+    /// Starts listening for packets at `freq` MHz.
+    ///
+    /// The radio should only accept packets starting with `access_address` (transmitted LSb
+    /// first), and should deliver the bytes following the Access Address to the software
+    /// Link-Layer backend.
+    fn listen(&mut self, freq: u16, access_address: u32);
+
+    /// Stops any ongoing transmission or reception.
+    fn stop(&mut self);
+
+    /// Sets the transmit power used for all following transmissions.
+    ///
+    /// Like `Transmitter::set_tx_power`, this returns the power level in dBm that is actually used.
+    fn set_tx_power(&mut self, dbm: i8) -> i8;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::link::advertising::CRC_PRESET;
+
+    /// Whitening implementation commonly used by BLE sniffers, taking the channel index.
+    fn reference_whiten(channel: u8, data: &mut [u8]) {
+        let mut coeff = channel.reverse_bits() | 2;
+        for byte in data {
+            let mut m = 1u8;
+            while m != 0 {
+                if coeff & 0x80 != 0 {
+                    coeff ^= 0x11;
+                    *byte ^= m;
+                }
+                coeff <<= 1;
+                m <<= 1;
+            }
+        }
+    }
+
+    #[test]
+    fn whitening_matches_reference() {
+        let ivs = AdvertisingChannel::iter_all()
+            .map(|ch| (ch.channel(), ch.whitening_iv()))
+            .chain((0..=36).map(|i| (i, DataChannel::new(i).whitening_iv())));
+        for (channel, iv) in ivs {
+            let mut data = [0u8; 40];
+            let mut expected = data;
+            whiten(iv, &mut data);
+            reference_whiten(channel, &mut expected);
+            assert_eq!(data, expected);
+        }
+    }
+
+    #[test]
+    fn whitening_roundtrip() {
+        let iv = DataChannel::new(17).whitening_iv();
+        let original = [0x0E, 0x07, 0x03, 0x00, 0x04, 0x00, 0x0A, 0x03, 0x00];
+        let mut data = original;
+        whiten(iv, &mut data);
+        assert_ne!(data, original);
+        whiten(iv, &mut data);
+        assert_eq!(data, original);
+    }
+
+    /// Bit-reversed CRC implementation commonly used by BLE sniffers.
+    fn reference_crc(crc_init: u32, data: &[u8]) -> [u8; 3] {
+        let mut state = crc_init.reverse_bits() >> 8;
+        for &byte in data {
+            let mut cur = byte;
+            for _ in 0..8 {
+                let next_bit = (state ^ u32::from(cur)) & 1;
+                cur >>= 1;
+                state >>= 1;
+                if next_bit != 0 {
+                    state |= 1 << 23;
+                    state ^= 0x5A6000;
+                }
+            }
+        }
+        [state as u8, (state >> 8) as u8, (state >> 16) as u8]
+    }
+
+    #[test]
+    fn crc_matches_reference() {
+        let pdus: &[&[u8]] = &[
+            &[],
+            &[0x00, 0x00],
+            &[0x40, 0x06, 0xD6, 0xBE, 0x89, 0x8E, 0x00, 0x11],
+            &[
+                0x03, 0x09, 0x08, 0x52, 0x75, 0x62, 0x62, 0x6C, 0x65, 0x01, 0xFF,
+            ],
+        ];
+        for &init in &[CRC_PRESET, 0x123456, 0xFFFFFF] {
+            for pdu in pdus {
+                assert_eq!(crc_bytes(crc24(init, pdu)), reference_crc(init, pdu));
+            }
+        }
+    }
+
+    #[test]
+    fn crc_residue() {
+        // Running the CRC over a packet including its CRC leaves the shift register cleared
+        let mut packet = [0x02, 0x05, 0x10, 0x20, 0x30, 0x40, 0x50, 0, 0, 0];
+        let crc = crc24(CRC_PRESET, &packet[..7]);
+        packet[7..].copy_from_slice(&crc_bytes(crc));
+        assert_eq!(crc24(CRC_PRESET, &packet), 0);
+    }
+
+    #[test]
+    fn advertising_channel_map() {
+        let channels =
+            |map: AdvertisingChannelMap| map.iter().map(|ch| ch.channel()).collect::<Vec<_>>();
+        assert_eq!(channels(AdvertisingChannelMap::ALL), [37, 38, 39]);
+        assert_eq!(AdvertisingChannelMap::from_raw(0), Err(Error::InvalidValue));
+        assert_eq!(
+            AdvertisingChannelMap::from_raw(0x08),
+            Err(Error::InvalidValue)
+        );
+
+        let map = AdvertisingChannelMap::from_raw(0b101).unwrap();
+        assert_eq!(channels(map), [37, 39]);
+        assert_eq!(map.next_after(AdvertisingChannel::first()).channel(), 39);
+        assert_eq!(map.next_after(AdvertisingChannel(39)).channel(), 37);
+
+        let single = AdvertisingChannelMap::single(AdvertisingChannel(38));
+        assert_eq!(single.to_raw(), 0b010);
+        assert_eq!(single.next_after(AdvertisingChannel(38)).channel(), 38);
+    }
 }
@@ -0,0 +1,188 @@
+//! Automatic reconnection to bonded devices.
+//!
+//! Peripherals like keyboards or wearables are expected to reconnect to their bonded central
+//! quickly after a connection is lost, without being discoverable to everyone else. The usual
+//! procedure is:
+//!
+//! 1. Send connectable directed advertisements to the central the connection was lost with, for a
+//!    short time.
+//! 2. If the central doesn't reconnect, send connectable undirected advertisements that only
+//!    bonded devices may respond to.
+//!
+//! [`ReconnectPolicy`] implements this on top of a peripheral `LinkLayer` and a `BondManager`.
+//! When the connection was established with a device that isn't bonded, it restarts advertising
+//! like before the connection.
+//!
+//! [`ReconnectPolicy`]: struct.ReconnectPolicy.html
+
+use crate::config::{ConfConsumer, ConfProducer, Config};
+use crate::link::ad_structure::AdvertisingData;
+use crate::link::advertising::{PduBuf, PduType};
+use crate::link::filter::PeerFilter;
+use crate::link::{DeviceAddress, LinkLayer, NextUpdate, Peer};
+use crate::security::BondManager;
+use crate::time::{Duration, Instant, Timer};
+use crate::Error;
+
+/// Restarts advertising after a connection has ended, preferring bonded devices.
+///
+/// [`update`] has to be called after every call into the `LinkLayer` (`update_timer`,
+/// `process_adv_packet` and `process_data_packet`). It notices when a connection has ended and
+/// takes back the packet queues to advertise with (see `LinkLayer::take_released_queues`).
+///
+/// If the connection was established with a bonded device, directed advertising is used for the
+/// configured time, followed by undirected advertising with a peer filter containing the bonded
+/// devices (see `BondManager::peer_filter`). The peer filter installed by the application is
+/// restored once a device reconnects. Otherwise, advertising is restarted with the advertising
+/// data passed to [`new`] and the application's peer filter.
+///
+/// ```ignore
+/// let mut reconnect = ReconnectPolicy::new(interval, Duration::from_secs(5), adv_data);
+/// loop {
+///     let cmd = ll.update_timer(&mut radio);
+///     // ...
+///     if let Some(next) = reconnect.update(&mut ll, &mut radio, &bonds)? {
+///         // Schedule the timer like after `start_advertise`
+///     }
+/// }
+/// ```
+///
+/// [`update`]: #method.update
+/// [`new`]: #method.new
+#[derive(Debug)]
+pub struct ReconnectPolicy {
+    interval: Duration,
+    directed_timeout: Duration,
+    adv_data: AdvertisingData,
+    state: State,
+    /// The peer filter installed by the application, restored after reconnecting.
+    app_filter: Option<PeerFilter>,
+}
+
+#[derive(Debug, Copy, Clone)]
+enum State {
+    /// Connected, or advertising as set up by the application.
+    Idle,
+    /// Directed advertising to a bonded device.
+    Directed { peer: DeviceAddress, since: Instant },
+    /// Undirected advertising, only allowing bonded devices to connect.
+    Bonded,
+}
+
+impl ReconnectPolicy {
+    /// Creates a reconnection policy.
+    ///
+    /// # Parameters
+    ///
+    /// * **`interval`**: The advertising interval to use.
+    /// * **`directed_timeout`**: How long to use directed advertising before falling back to
+    ///   undirected advertising.
+    /// * **`adv_data`**: The advertising data to send when advertising undirected. This should be
+    ///   the same data passed to `LinkLayer::start_advertise`.
+    pub fn new(interval: Duration, directed_timeout: Duration, adv_data: AdvertisingData) -> Self {
+        Self {
+            interval,
+            directed_timeout,
+            adv_data,
+            state: State::Idle,
+            app_filter: None,
+        }
+    }
+
+    /// Returns whether this policy is currently advertising to reconnect to a bonded device.
+    pub fn is_reconnecting(&self) -> bool {
+        !matches!(self.state, State::Idle)
+    }
+
+    /// Restarts or changes advertising as needed.
+    ///
+    /// Returns the time of the next Link-Layer update if advertising was (re)started, like
+    /// `LinkLayer::start_advertise`. When directed advertising times out, it is stopped before
+    /// advertising is restarted, so the radio has to be reconfigured as if it had received a
+    /// `RadioCmd::Off`.
+    ///
+    /// Returns `Error::InvalidValue` if the advertising interval is invalid, and `Error::Eof` if the
+    /// advertising data doesn't fit into an advertising PDU.
+    pub fn update<C: Config, const N: usize>(
+        &mut self,
+        ll: &mut LinkLayer<C>,
+        transmitter: &mut C::Transmitter,
+        bonds: &BondManager<N>,
+    ) -> Result<Option<NextUpdate>, Error> {
+        // Connections that fail to be established resume the advertising they were created from
+        let established = ll.connection().map_or(false, |conn| conn.is_established());
+        if established {
+            if let State::Bonded = self.state {
+                ll.set_peer_filter(self.app_filter);
+            }
+            self.state = State::Idle;
+            return Ok(None);
+        }
+        if self.is_reconnecting() && !ll.is_advertising() && !ll.is_connected() {
+            // The application stopped advertising, or the device disconnected again before this
+            // was called
+            if let State::Bonded = self.state {
+                ll.set_peer_filter(self.app_filter);
+            }
+            self.state = State::Idle;
+        }
+
+        match self.state {
+            State::Idle => {
+                let (peer, tx, rx) = match ll.take_released_queues() {
+                    Some(released) => released,
+                    None => return Ok(None),
+                };
+                if is_bonded(bonds, &peer) {
+                    info!(logger: C::Logger, "reconnecting to {:?}", peer.address);
+                    self.app_filter = ll.peer_filter().copied();
+                    self.state = State::Directed {
+                        peer: peer.address,
+                        since: ll.timer().now(),
+                    };
+                    ll.start_directed_advertise(self.interval, peer.address, transmitter, tx, rx)
+                        .map(Some)
+                } else {
+                    self.start_undirected(ll, transmitter, tx, rx).map(Some)
+                }
+            }
+            State::Directed { peer, since } => {
+                if ll.timer().now().duration_since(since) < self.directed_timeout {
+                    return Ok(None);
+                }
+                let (tx, rx) = match ll.stop_advertising() {
+                    Some(queues) => queues,
+                    None => return Ok(None),
+                };
+
+                debug!(
+                    logger: C::Logger,
+                    "{:?} didn't reconnect, advertising to bonded devices",
+                    peer
+                );
+                ll.set_peer_filter(Some(bonds.peer_filter()));
+                self.state = State::Bonded;
+                self.start_undirected(ll, transmitter, tx, rx).map(Some)
+            }
+            State::Bonded => Ok(None),
+        }
+    }
+
+    fn start_undirected<C: Config>(
+        &self,
+        ll: &mut LinkLayer<C>,
+        transmitter: &mut C::Transmitter,
+        tx: ConfConsumer<C>,
+        rx: ConfProducer<C>,
+    ) -> Result<NextUpdate, Error> {
+        let address = *ll.device_address();
+        let pdu = PduBuf::from_adv_data(PduType::AdvInd, address, &self.adv_data)?;
+        let scan_rsp = PduBuf::scan_response(address, &[])?;
+        ll.start_advertise_pdu(self.interval, pdu, scan_rsp, transmitter, tx, rx)
+    }
+}
+
+/// Returns whether `peer` (identified by the address it connected with) is bonded.
+fn is_bonded<const N: usize>(bonds: &BondManager<N>, peer: &Peer) -> bool {
+    bonds.get(&peer.id).is_some() || bonds.bonds().iter().any(|bond| bond.peer == peer.address)
+}
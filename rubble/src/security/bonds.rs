@@ -0,0 +1,262 @@
+//! Management of the bonds established with peer devices.
+//!
+//! The Security Manager hands the keys of newly bonded devices to a [`BondStore`], and doesn't
+//! need them otherwise. [`BondManager`] is a `BondStore` that keeps them in RAM and additionally
+//! provides the operations a device needs to manage its bonds: Looking up the bond of a connected
+//! device, deleting individual bonds or all of them (eg. when the user holds down a button), and
+//! deriving the set of devices allowed to connect.
+//!
+//! Persisting the bonds across resets is up to the application: It can write out [`bonds`] when
+//! they change, and add them back with [`restore`] on startup.
+//!
+//! [`BondStore`]: ../trait.BondStore.html
+//! [`BondManager`]: struct.BondManager.html
+//! [`bonds`]: struct.BondManager.html#method.bonds
+//! [`restore`]: struct.BondManager.html#method.restore
+
+use super::{AesProvider, Bond, BondStore};
+use crate::link::filter::PeerFilter;
+use crate::link::{DeviceAddress, Peer, PeerId};
+use crate::Error;
+use heapless::Vec;
+
+/// A `BondStore` holding up to `N` bonds, which can be listed and deleted.
+///
+/// Bonds are identified by the [`PeerId`] derived from them (see `PeerId::from_bond`), so pairing
+/// with a bonded device again replaces its old bond. When all `N` slots are in use, bonding with a
+/// new device replaces the bond that was established first.
+///
+/// Any other state the application keeps for a bonded device, like the values of *Client
+/// Characteristic Configuration* descriptors (subscriptions) written by it, has to be deleted by
+/// the application along with the bond.
+///
+/// [`PeerId`]: ../../link/enum.PeerId.html
+#[derive(Debug)]
+pub struct BondManager<const N: usize> {
+    /// Bonds, in the order they were established.
+    bonds: Vec<Bond, N>,
+}
+
+impl<const N: usize> BondManager<N> {
+    /// Creates a `BondManager` without any bonds.
+    pub const fn new() -> Self {
+        Self { bonds: Vec::new() }
+    }
+
+    /// Adds a bond that was previously persisted by the application.
+    ///
+    /// Returns `Error::Eof` if all `N` slots are in use.
+    pub fn restore(&mut self, bond: Bond) -> Result<(), Error> {
+        self.delete(&PeerId::from_bond(&bond));
+        self.bonds.push(bond).map_err(|_| Error::Eof)
+    }
+
+    /// Returns all bonds, in the order they were established.
+    pub fn bonds(&self) -> &[Bond] {
+        &self.bonds
+    }
+
+    /// Returns an iterator over the identities of all bonded devices.
+    pub fn peers(&self) -> impl Iterator<Item = PeerId> + '_ {
+        self.bonds.iter().map(PeerId::from_bond)
+    }
+
+    /// Returns the bond established with the device identified by `peer`.
+    pub fn get(&self, peer: &PeerId) -> Option<&Bond> {
+        self.bonds
+            .iter()
+            .find(|bond| PeerId::from_bond(bond) == *peer)
+    }
+
+    /// Looks up the bond of a connected `peer`, resolving its address if necessary.
+    ///
+    /// If the peer is bonded, its `id` is set to the identity from the bond (see `Peer::resolve`).
+    /// This should be called when a connection is established, before the `Peer` is passed to the
+    /// ATT server and Security Manager.
+    pub fn resolve(&self, aes: &mut dyn AesProvider, peer: &mut Peer) -> Option<&Bond> {
+        self.bonds.iter().find(|bond| peer.resolve(aes, bond))
+    }
+
+    /// Deletes the bond established with the device identified by `peer`.
+    ///
+    /// Returns the deleted bond, or `None` if the device isn't bonded.
+    pub fn delete(&mut self, peer: &PeerId) -> Option<Bond> {
+        let index = self
+            .bonds
+            .iter()
+            .position(|bond| PeerId::from_bond(bond) == *peer)?;
+        Some(self.bonds.remove(index))
+    }
+
+    /// Deletes all bonds and returns how many there were.
+    pub fn delete_all(&mut self) -> usize {
+        let count = self.bonds.len();
+        self.bonds.clear();
+        info!("deleted {} bonds", count);
+        count
+    }
+
+    /// Creates a `PeerFilter` that only allows bonded devices to connect.
+    ///
+    /// The filter contains the identity addresses of bonded devices. If there are more than
+    /// `PeerFilter::MAX_LEN`, the most recently bonded ones are included.
+    ///
+    /// Devices that connect with a Resolvable Private Address are not matched by the filter, since
+    /// their address changes periodically. Allowing them requires resolving their address with the
+    /// IRKs returned by [`resolving_list`].
+    ///
+    /// [`resolving_list`]: #method.resolving_list
+    pub fn peer_filter(&self) -> PeerFilter {
+        let mut filter = PeerFilter::new();
+        let identities = self
+            .bonds
+            .iter()
+            .rev()
+            .filter_map(|bond| match PeerId::from_bond(bond) {
+                PeerId::Identity(address) => Some(address),
+                PeerId::Private(_) => None,
+            });
+        for address in identities.take(PeerFilter::MAX_LEN) {
+            filter.push(address).ok();
+        }
+        filter
+    }
+
+    /// Returns the identity address and IRK of every bonded device that distributed its IRK.
+    ///
+    /// These are the entries of the *resolving list* of a controller, which is used to recognize
+    /// bonded devices that use Resolvable Private Addresses.
+    pub fn resolving_list(&self) -> impl Iterator<Item = (DeviceAddress, u128)> + '_ {
+        self.bonds.iter().filter_map(|bond| {
+            let irk = bond.peer_irk?;
+            match PeerId::from_bond(bond) {
+                PeerId::Identity(address) => Some((address, irk)),
+                PeerId::Private(_) => None,
+            }
+        })
+    }
+}
+
+impl<const N: usize> Default for BondManager<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N: usize> BondStore for BondManager<N> {
+    fn store(&mut self, bond: &Bond) {
+        let id = PeerId::from_bond(bond);
+        if self.delete(&id).is_none() && self.bonds.is_full() {
+            let oldest = self.bonds.remove(0);
+            warn!(
+                "bond table full, replacing bond with {:?}",
+                PeerId::from_bond(&oldest)
+            );
+        }
+        self.bonds.push(*bond).ok();
+    }
+
+    fn lookup_ltk(&self, peer: &Peer, ediv: u16, rand: u64) -> Option<Bond> {
+        self.bonds
+            .iter()
+            .rev()
+            .copied()
+            .find(|bond| match bond.local_ltk {
+                // *LE Secure Connections* keys aren't identified by EDIV and Rand
+                Some(ltk) if ediv == 0 && rand == 0 => {
+                    ltk.ediv == 0
+                        && ltk.rand == 0
+                        && (PeerId::from_bond(bond) == peer.id || bond.peer == peer.address)
+                }
+                Some(ltk) => ltk.ediv == ediv && ltk.rand == rand,
+                None => false,
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::link::{AddressKind, ConnectionHandle};
+    use crate::security::{LongTermKey, SoftAes};
+
+    const IRK: u128 = 0xec0234a357c8ad05341010a60a397d9b;
+
+    fn bond(peer: u8, identity: Option<u8>, irk: Option<u128>) -> Bond {
+        let mut bond = Bond::new(DeviceAddress::new([peer; 6], AddressKind::Public), false);
+        bond.peer_identity = identity.map(|id| DeviceAddress::new([id; 6], AddressKind::Public));
+        bond.peer_irk = irk;
+        bond
+    }
+
+    fn address(byte: u8) -> DeviceAddress {
+        DeviceAddress::new([byte; 6], AddressKind::Public)
+    }
+
+    #[test]
+    fn store_and_delete() {
+        let mut bonds = BondManager::<2>::new();
+        bonds.store(&bond(1, None, None));
+        bonds.store(&bond(2, None, None));
+        // Bonding again replaces the old bond
+        bonds.store(&bond(1, None, None));
+        assert_eq!(
+            bonds.peers().collect::<Vec<_, 2>>(),
+            [PeerId::Identity(address(2)), PeerId::Identity(address(1))]
+        );
+
+        // The oldest bond is replaced when the table is full
+        bonds.store(&bond(3, None, None));
+        assert!(bonds.get(&PeerId::Identity(address(2))).is_none());
+        assert_eq!(bonds.restore(bond(4, None, None)), Err(Error::Eof));
+
+        assert!(bonds.delete(&PeerId::Identity(address(1))).is_some());
+        assert!(bonds.delete(&PeerId::Identity(address(1))).is_none());
+        assert_eq!(bonds.bonds().len(), 1);
+        assert_eq!(bonds.delete_all(), 1);
+        assert!(bonds.bonds().is_empty());
+    }
+
+    #[test]
+    fn filter_and_resolving_list() {
+        let mut bonds = BondManager::<4>::new();
+        let rpa = DeviceAddress::new([0xaa, 0xfb, 0x0d, 0x94, 0x81, 0x70], AddressKind::Random);
+        bonds.store(&bond(1, Some(9), Some(IRK)));
+        bonds.store(&bond(2, None, None));
+        bonds.restore(Bond::new(rpa, false)).unwrap();
+
+        assert_eq!(bonds.peer_filter().addresses(), &[address(2), address(9)]);
+        assert_eq!(
+            bonds.resolving_list().collect::<Vec<_, 4>>(),
+            [(address(9), IRK)]
+        );
+
+        // A peer connecting with a new RPA is recognized via the IRK
+        let mut peer = Peer::new(ConnectionHandle::from_raw(0), rpa);
+        let bond = bonds.resolve(&mut SoftAes, &mut peer).unwrap();
+        assert_eq!(bond.peer, address(1));
+        assert_eq!(peer.id, PeerId::Identity(address(9)));
+    }
+
+    #[test]
+    fn lookup_ltk() {
+        let ltk = |ediv, rand| LongTermKey { ltk: 1, ediv, rand };
+        let mut bonds = BondManager::<2>::new();
+        let mut legacy = bond(1, None, None);
+        legacy.local_ltk = Some(ltk(0x1234, 5));
+        let mut secure = bond(2, Some(9), None);
+        secure.local_ltk = Some(ltk(0, 0));
+        bonds.store(&legacy);
+        bonds.store(&secure);
+
+        // Legacy keys are identified by EDIV and Rand, regardless of the peer
+        let peer = Peer::new(ConnectionHandle::from_raw(0), address(3));
+        assert_eq!(bonds.lookup_ltk(&peer, 0x1234, 5).unwrap().peer, address(1));
+        assert!(bonds.lookup_ltk(&peer, 0x1234, 6).is_none());
+        assert!(bonds.lookup_ltk(&peer, 0, 0).is_none());
+
+        // Secure Connections keys are found by the peer
+        let peer = Peer::new(ConnectionHandle::from_raw(0), address(9));
+        assert_eq!(bonds.lookup_ltk(&peer, 0, 0).unwrap().peer, address(2));
+    }
+}
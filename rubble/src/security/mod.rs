@@ -0,0 +1,2412 @@
+//! The LE Security Manager protocol.
+//!
+//! The Security Manager is a mandatory part of BLE and is connected to L2CAP channel `0x0006` when
+//! the Link-Layer connection is established.
+//!
+//! # BLE Security
+//!
+//! As is tradition, BLE security is a complexity nightmare. This section hopes to clear up a few
+//! things and tries to define terms used throughout the code and specficiation.
+//!
+//! ## Pairing and Bonding
+//!
+//! * **Pairing** is the process of generating and exchanging connection-specific keys in order to
+//!   accomplish an encrypted Link-Layer connection.
+//!
+//!   This is done by having the *Security Managers* of the devices talk to each other to perform
+//!   the key exchange, and then using *LL Control PDUs* to enable the negotiated encryption
+//!   parameters.
+//!
+//! * **Bonding** means permanently storing the shared keys derived by *Pairing* in order to reuse
+//!   them for later connections.
+//!
+//!   The way keys are stored is inherently platform- and application-dependent, we just have to
+//!   provide interfaces to export and import key sets.
+//!
+//! Most times, when talking about *pairing*, the *bonding* part is implied. If it were not, you
+//! would constantly have to re-pair devices when reconnecting them.
+//!
+//! ## LE Legacy Pairing vs. LE Secure Connections
+//!
+//! Bluetooth's security track record is an actual record in that it is so atrociously bad that this
+//! protocol should have never seen the light of the day. Alas, here we are.
+//!
+//! LE security is generally able to utilize *AES-128-CCM* for encryption, which isn't broken by
+//! itself (unlike the "export-grade" encryption used by earlier Bluetooth versions). However, the
+//! way the AES key is exchanged differs between *LE Legacy Pairing* and *LE Secure Connections*
+//! pairing, which hugely impacts actual security.
+//!
+//! ### LE Legacy Pairing
+//!
+//! For BLE 4.0 and 4.1, only the *LE Legacy Pairing* (as it is now known as) was available. Like
+//! every awfully designed protocol, they've rolled their own crypto and use their own key exchange
+//! procedure (with the usual catastrophic consequences). First, a shared 128-bit **T**emporary
+//! **K**ey (TK) is obtained, which is then used to generate the 128-bit **S**hort-**T**erm **K**ey
+//! (STK) that is used to initially encrypt the connection while other keys are exchanged.
+//!
+//! The STK is generated from the TK by mixing in random values from master (`Mrand`) and slave
+//! (`Srand`), which are exchanged in plain text. If a passive eavesdropper manages to obtain TK,
+//! they only need to listen for the `Mrand` and `Srand` value and can then compute the STK and
+//! decrypt the connection.
+//!
+//! There are 3 methods of determining the TK:
+//! * *"Just Works"*: TK=0
+//! * *Passkey Entry*: A 6-digit number is displayed on one device and input on the other device.
+//!   The number is directly used as the TK (after zero-padding it to 128 bits).
+//! * *Out-of-Band* (OOB): The 128-bit TK is provided by an external mechanism (eg. NFC).
+//!
+//! "Just Works" obviously is broken without any effort other than listening for the exchanged
+//! `Mrand` and `Srand` values.
+//!
+//! The Passkey Entry method only allows 1000000 different TKs (equivalent to using 20-bit keys)
+//! and does not do any key derivation. This makes it trivial to brute-force the TK by running the
+//! STK derivation up to a million times.
+//!
+//! **The only way to perform *LE Legacy Pairing* with meaningful protection against passive
+//! eavesdropping is by using a secure Out-of-Band channel for agreeing on the TK.**
+//!
+//! ### LE Secure Connections pairing
+//!
+//! Added with BLE 4.2, this finally uses established cryptography to do everything. It uses ECDH on
+//! the P-256 curve (aka "secp256r1" or "prime256v1").
+//!
+//! Using ECDH immediately protects against passive eavesdropping. MITM-protection works similarly
+//! to what *LE Legacy Pairing* attempted to do, but is actually relevant here since the base key
+//! exchange isn't broken to begin with. There are several user confirmation processes that can
+//! offer MITM-protection:
+//!
+//! * *"Just Works"*: No MITM-protection. Uses the *Numeric Comparison* protocol internally, with
+//!   automatic confirmation.
+//! * *Numeric Comparison*: Both devices display a 6-digit confirmation value and the user is
+//!   required to compare them and confirm on each device if they're equal.
+//! * *Passkey Entry*: Either a generated passkey is displayed on one device and input on the other,
+//!   or the user inputs the same passkey into both devices.
+//! * *Out-of-Band* (OOB): An Out-of-Band mechanism is used to exchange random nonces and confirm
+//!   values. The mechanism has to be secure against MITM.
+//!
+//! ## LE Privacy
+//!
+//! BLE devices are normally extremely easy to track. Since many people use BLE devices, and device
+//! addresses are device-unique, they can be very easily used to identify and track people just by
+//! recording BLE advertisements.
+//!
+//! The LE privacy feature can prevent this by changing the device address over time. Bonded devices
+//! can still *resolve* this address by using a shared **I**dentity **R**esolving **K**ey (IRK).
+//!
+//! This feature is not related to encryption or authentication of connections.
+
+pub mod bonds;
+pub mod oob;
+pub mod privacy;
+pub(crate) mod toolbox;
+
+pub use self::bonds::BondManager;
+pub use self::oob::OobData;
+pub use self::toolbox::{AesProvider, SoftAes};
+
+use crate::config::Config;
+use crate::l2cap::{Protocol, ProtocolObj, Sender};
+use crate::link::{AddressKind, DeviceAddress, Peer, PeerId};
+use crate::{bytes::*, utils::HexSlice, Error};
+use bitflags::bitflags;
+use core::fmt;
+use rand_core::{CryptoRng, RngCore};
+use zerocopy::Unaligned;
+
+/// Errors returned by Security Manager operations.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, defmt::Format)]
+#[non_exhaustive]
+pub enum SmError {
+    /// The TX packet queue doesn't have enough free space for the PDU.
+    ///
+    /// The operation can be retried once `Cmd::tx_space_freed` is set.
+    TxBusy,
+
+    /// The PDU could not be encoded.
+    Encoding,
+}
+
+impl From<Error> for SmError {
+    fn from(e: Error) -> Self {
+        match e {
+            Error::TxBusy => SmError::TxBusy,
+            _ => SmError::Encoding,
+        }
+    }
+}
+
+impl fmt::Display for SmError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            SmError::TxBusy => "not enough space in TX queue",
+            SmError::Encoding => "PDU could not be encoded",
+        })
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for SmError {}
+
+/// Supported security levels.
+pub trait SecurityLevel {
+    /// The L2CAP MTU required by this security level.
+    const MTU: u8;
+
+    /// Returns the pairing configuration and cryptographic providers used by this security level.
+    ///
+    /// If this returns `None` (the default), all pairing attempts will be rejected.
+    fn pairing(&mut self) -> Option<PairingContext<'_>> {
+        None
+    }
+}
+
+/// *LE Secure Connections* are not supported and will not be established.
+#[derive(Debug)]
+pub struct NoSecurity;
+impl SecurityLevel for NoSecurity {
+    /// 23 Bytes when *LE Secure Connections* are unsupported
+    const MTU: u8 = 23;
+}
+
+/// Indicates support for *LE Secure Connections*.
+#[derive(Debug)]
+pub struct SecureConnections;
+impl SecurityLevel for SecureConnections {
+    /// 65 Bytes when *LE Secure Connections* are supported
+    const MTU: u8 = 65;
+}
+
+/// Supports *LE Legacy Pairing*.
+///
+/// This exists for compatibility with peers that do not support *LE Secure Connections*. Keep in
+/// mind that this offers no protection against passive eavesdropping unless the TK is exchanged
+/// via a secure Out-of-Band mechanism (refer to the module docs for details).
+///
+/// The pairing method is selected based on the [`PairingConfig`] and the capabilities of the
+/// peer. By default, no [`PairingDelegate`] is used, which only allows the *"Just Works"* method.
+///
+/// Keys exchanged with bonded devices are passed to a [`BondStore`]. By default, `NoBondStore` is
+/// used, which discards them.
+///
+/// [`PairingConfig`]: struct.PairingConfig.html
+/// [`PairingDelegate`]: trait.PairingDelegate.html
+/// [`BondStore`]: trait.BondStore.html
+pub struct LegacyPairing<
+    A: AesProvider,
+    R: RngCore + CryptoRng,
+    D: PairingDelegate = NoDelegate,
+    B: BondStore = NoBondStore,
+> {
+    config: PairingConfig,
+    aes: A,
+    rng: R,
+    delegate: D,
+    bonds: B,
+}
+
+/// [`LegacyPairing`] using the random number generator of stack configuration `C`.
+///
+/// [`LegacyPairing`]: struct.LegacyPairing.html
+pub type ConfLegacyPairing<C, A, D = NoDelegate, B = NoBondStore> =
+    LegacyPairing<A, <C as Config>::Rng, D, B>;
+
+impl<A: AesProvider, R: RngCore + CryptoRng> LegacyPairing<A, R> {
+    /// Creates a new *LE Legacy Pairing* security level.
+    ///
+    /// # Parameters
+    ///
+    /// * **`config`**: Device-specific pairing configuration.
+    /// * **`aes`**: The AES-128 implementation to use for the security functions.
+    /// * **`rng`**: A cryptographically secure random number generator, used for the random values
+    ///   exchanged during pairing and for key generation. This is usually the [`Config::Rng`] of
+    ///   the stack configuration, in which case the type can be named via [`ConfLegacyPairing`].
+    ///
+    /// [`Config::Rng`]: ../config/trait.Config.html#associatedtype.Rng
+    /// [`ConfLegacyPairing`]: type.ConfLegacyPairing.html
+    pub fn new(config: PairingConfig, aes: A, rng: R) -> Self {
+        Self {
+            config,
+            aes,
+            rng,
+            delegate: NoDelegate,
+            bonds: NoBondStore,
+        }
+    }
+}
+
+impl<A, R, D, B> LegacyPairing<A, R, D, B>
+where
+    A: AesProvider,
+    R: RngCore + CryptoRng,
+    D: PairingDelegate,
+    B: BondStore,
+{
+    /// Replaces the `PairingDelegate` that interacts with the user.
+    ///
+    /// The delegate is required for the *Passkey Entry* and *Out-of-Band* pairing methods.
+    pub fn with_delegate<E: PairingDelegate>(self, delegate: E) -> LegacyPairing<A, R, E, B> {
+        LegacyPairing {
+            config: self.config,
+            aes: self.aes,
+            rng: self.rng,
+            delegate,
+            bonds: self.bonds,
+        }
+    }
+
+    /// Replaces the `BondStore` that keeps the keys of bonded devices.
+    pub fn with_bond_store<C: BondStore>(self, bonds: C) -> LegacyPairing<A, R, D, C> {
+        LegacyPairing {
+            config: self.config,
+            aes: self.aes,
+            rng: self.rng,
+            delegate: self.delegate,
+            bonds,
+        }
+    }
+
+    /// Provides mutable access to the `PairingDelegate`.
+    pub fn delegate(&mut self) -> &mut D {
+        &mut self.delegate
+    }
+
+    /// Provides mutable access to the `BondStore`.
+    pub fn bond_store(&mut self) -> &mut B {
+        &mut self.bonds
+    }
+}
+
+impl<A, R, D, B> SecurityLevel for LegacyPairing<A, R, D, B>
+where
+    A: AesProvider,
+    R: RngCore + CryptoRng,
+    D: PairingDelegate,
+    B: BondStore,
+{
+    /// *LE Legacy Pairing* uses the minimum MTU of 23 Bytes
+    const MTU: u8 = 23;
+
+    fn pairing(&mut self) -> Option<PairingContext<'_>> {
+        Some(PairingContext {
+            config: &self.config,
+            aes: &mut self.aes,
+            rng: &mut self.rng,
+            delegate: &mut self.delegate,
+            bonds: &mut self.bonds,
+        })
+    }
+}
+
+/// Application callbacks for user interaction during pairing.
+///
+/// Devices with a display or keyboard can implement this trait to take part in authenticated
+/// (MITM-protected) pairing. Which callbacks are invoked depends on the I/O capabilities of both
+/// devices, as configured via [`PairingConfig::io_capabilities`]. Every callback is passed the peer
+/// that is being paired with, as passed to `SecurityManager::connected`.
+///
+/// [`PairingConfig::io_capabilities`]: struct.PairingConfig.html#method.io_capabilities
+pub trait PairingDelegate {
+    /// Displays the 6-digit `passkey` to the user, who then has to enter it on the peer device.
+    ///
+    /// `passkey` is in range `0..=999999` and should be displayed with leading zeros.
+    fn display_passkey(&mut self, peer: &Peer, passkey: u32) {
+        let _ = (peer, passkey);
+    }
+
+    /// Asks the user to enter the passkey displayed on the peer device.
+    ///
+    /// Once the user has entered all digits, the passkey must be passed to
+    /// [`SecurityManagerTx::enter_passkey`]. If the user cancels the entry, pass a value that is
+    /// out of range (eg. `u32::MAX`) to abort pairing.
+    ///
+    /// [`SecurityManagerTx::enter_passkey`]: struct.SecurityManagerTx.html#method.enter_passkey
+    fn request_passkey(&mut self, peer: &Peer) {
+        let _ = peer;
+    }
+
+    /// Returns the 128-bit Temporary Key obtained via an Out-of-Band mechanism (eg. NFC).
+    ///
+    /// If this returns `None` (the default), no OOB data is available and the pairing method is
+    /// chosen based on the I/O capabilities instead. The OOB method is only used if the peer has
+    /// OOB data as well.
+    ///
+    /// [`OobData`] encodes the TK for an NFC tag and decodes the data read from a peer's tag.
+    ///
+    /// [`OobData`]: oob/struct.OobData.html
+    fn oob_data(&mut self, peer: &Peer) -> Option<u128> {
+        let _ = peer;
+        None
+    }
+
+    /// Called when pairing fails or is aborted by either device.
+    fn pairing_failed(&mut self, peer: &Peer, reason: Reason) {
+        let _ = (peer, reason);
+    }
+}
+
+/// A `PairingDelegate` that does not interact with the user.
+///
+/// Only the *"Just Works"* method can be used when this is selected.
+#[derive(Debug)]
+pub struct NoDelegate;
+
+impl PairingDelegate for NoDelegate {}
+
+/// Storage for the keys of bonded devices.
+///
+/// How keys are stored is up to the application. Typically, they are written to non-volatile
+/// memory so that they persist across resets. [`BondManager`] keeps them in RAM and allows listing
+/// and deleting them.
+///
+/// [`BondManager`]: bonds/struct.BondManager.html
+pub trait BondStore {
+    /// Stores the keys exchanged with a newly bonded device.
+    ///
+    /// This is called once the key distribution phase is complete. If keys for the same peer are
+    /// stored already, they should be replaced. `PeerId::from_bond` returns the identity the keys
+    /// should be stored under.
+    fn store(&mut self, bond: &Bond);
+
+    /// Looks up the bond whose local LTK the master requested to encrypt the link with.
+    ///
+    /// `ediv` and `rand` are the values sent in the *LL_ENC_REQ*. For keys generated by *LE Legacy
+    /// Pairing*, they identify the LTK distributed by us (`Bond::local_ltk`). Keys generated by
+    /// *LE Secure Connections* pairing are requested with both values set to 0, so the bond has to
+    /// be found by the connected `peer` instead.
+    ///
+    /// By default, no bonds are found, and bonded devices have to pair again on every connection.
+    fn lookup_ltk(&self, peer: &Peer, ediv: u16, rand: u64) -> Option<Bond> {
+        let _ = (peer, ediv, rand);
+        None
+    }
+}
+
+/// A `BondStore` that discards all keys.
+///
+/// Bonded devices will have to pair again on every connection.
+#[derive(Debug)]
+pub struct NoBondStore;
+
+impl BondStore for NoBondStore {
+    fn store(&mut self, _bond: &Bond) {}
+}
+
+/// Pairing configuration and cryptographic providers, as returned by [`SecurityLevel::pairing`].
+///
+/// [`SecurityLevel::pairing`]: trait.SecurityLevel.html#method.pairing
+pub struct PairingContext<'a> {
+    pub config: &'a PairingConfig,
+    pub aes: &'a mut dyn AesProvider,
+    /// A cryptographically secure random number generator.
+    pub rng: &'a mut dyn RngCore,
+    pub delegate: &'a mut dyn PairingDelegate,
+    pub bonds: &'a mut dyn BondStore,
+}
+
+/// Smallest encryption key size allowed by the specification, in Bytes.
+pub const MIN_KEY_SIZE: u8 = 7;
+
+/// Largest encryption key size, in Bytes (the full size of an AES-128 key).
+pub const MAX_KEY_SIZE: u8 = 16;
+
+/// Device-specific pairing configuration.
+#[derive(Copy, Clone)]
+pub struct PairingConfig {
+    bonding: BondingType,
+    io: IoCapabilities,
+    mitm: bool,
+    min_key_size: u8,
+    identity: Option<(u128, DeviceAddress)>,
+}
+
+impl PairingConfig {
+    /// Creates the default pairing configuration.
+    ///
+    /// The default configuration allows bonding, does not require MITM protection, declares no
+    /// I/O capabilities (`NoInputNoOutput`), and only accepts 16-Byte encryption keys.
+    pub fn new() -> Self {
+        Self {
+            bonding: BondingType::Bonding,
+            io: IoCapabilities::NoInputNoOutput,
+            mitm: false,
+            min_key_size: MAX_KEY_SIZE,
+            identity: None,
+        }
+    }
+
+    /// Sets the I/O capabilities of this device.
+    ///
+    /// These determine whether the *Passkey Entry* method can be used, and whether this device
+    /// displays or inputs the passkey. Any capabilities declared here must be backed by the
+    /// [`PairingDelegate`].
+    ///
+    /// [`PairingDelegate`]: trait.PairingDelegate.html
+    pub fn io_capabilities(mut self, io: IoCapabilities) -> Self {
+        self.io = io;
+        self
+    }
+
+    /// Sets whether MITM protection is required.
+    ///
+    /// If `true`, pairing fails when the I/O capabilities of both devices only allow the
+    /// unauthenticated *"Just Works"* method.
+    pub fn mitm(mut self, mitm: bool) -> Self {
+        self.mitm = mitm;
+        self
+    }
+
+    /// Sets the minimum encryption key size to accept, in Bytes.
+    ///
+    /// The key size is negotiated during the pairing feature exchange, and is the smaller of the
+    /// maximum sizes supported by both devices. Pairing with a peer that only supports keys
+    /// shorter than `size` fails with `Reason::EncryptionKeySize`. Shortened keys are weaker, so
+    /// this should only be lowered for compatibility with old peers.
+    ///
+    /// # Panics
+    ///
+    /// This will panic if `size` is not in range `7..=16`.
+    pub fn min_key_size(mut self, size: u8) -> Self {
+        assert!(
+            (MIN_KEY_SIZE..=MAX_KEY_SIZE).contains(&size),
+            "invalid encryption key size {}",
+            size
+        );
+        self.min_key_size = size;
+        self
+    }
+
+    /// Sets whether this device wants to bond with peers.
+    ///
+    /// Bonding is only performed when both devices request it. If it is, the Long Term Key (LTK)
+    /// and Connection Signature Resolving Key (CSRK) will be distributed to the peer after pairing.
+    pub fn bonding(mut self, bonding: BondingType) -> Self {
+        self.bonding = bonding;
+        self
+    }
+
+    /// Sets the Identity Resolving Key (IRK) and identity address of this device.
+    ///
+    /// These are distributed to bonded peers, allowing them to resolve the private addresses used
+    /// by this device. `address` must be a public or static random address.
+    pub fn identity(mut self, irk: u128, address: DeviceAddress) -> Self {
+        self.identity = Some((irk, address));
+        self
+    }
+}
+
+impl fmt::Debug for PairingConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PairingConfig")
+            .field("bonding", &self.bonding)
+            .field("io", &self.io)
+            .field("mitm", &self.mitm)
+            .field("min_key_size", &self.min_key_size)
+            .field("identity", &self.identity.map(|(_, address)| address))
+            .finish()
+    }
+}
+
+impl Default for PairingConfig {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A Long Term Key (LTK) along with the values identifying it.
+///
+/// This is generated by the slave during *LE Legacy Pairing* and distributed to the master when
+/// bonding. The master will later use `ediv` and `rand` to request encryption with `ltk`.
+#[derive(Copy, Clone)]
+pub struct LongTermKey {
+    /// The 128-bit LTK.
+    pub ltk: u128,
+    /// Encrypted Diversifier (EDIV) identifying the LTK.
+    pub ediv: u16,
+    /// 64-bit random number identifying the LTK.
+    pub rand: u64,
+}
+
+impl fmt::Debug for LongTermKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // Don't leak the key into logs
+        f.debug_struct("LongTermKey")
+            .field("ediv", &self.ediv)
+            .field("rand", &self.rand)
+            .finish()
+    }
+}
+
+impl defmt::Format for LongTermKey {
+    fn format(&self, fmt: defmt::Formatter<'_>) {
+        defmt::write!(
+            fmt,
+            "LongTermKey {{ ediv: {=u16}, rand: {=u64} }}",
+            self.ediv,
+            self.rand
+        );
+    }
+}
+
+/// Key set shared with a bonded peer.
+///
+/// Only the keys agreed upon during the pairing feature exchange are present.
+#[derive(Copy, Clone)]
+pub struct Bond {
+    /// Address of the peer device at the time of pairing.
+    pub peer: DeviceAddress,
+    /// Whether the keys were generated by an authenticated (MITM-protected) pairing method.
+    pub authenticated: bool,
+    /// The encryption key size negotiated during pairing, in Bytes.
+    pub key_size: u8,
+    /// The LTK distributed by the local device.
+    pub local_ltk: Option<LongTermKey>,
+    /// The CSRK distributed by the local device, used to sign data sent by us.
+    pub local_csrk: Option<u128>,
+    /// The LTK distributed by the peer.
+    pub peer_ltk: Option<LongTermKey>,
+    /// The Identity Resolving Key (IRK) distributed by the peer.
+    pub peer_irk: Option<u128>,
+    /// The public or static random identity address of the peer.
+    pub peer_identity: Option<DeviceAddress>,
+    /// The CSRK distributed by the peer, used to verify signed data sent by it.
+    pub peer_csrk: Option<u128>,
+}
+
+impl Bond {
+    fn new(peer: DeviceAddress, authenticated: bool) -> Self {
+        Self {
+            peer,
+            authenticated,
+            key_size: MAX_KEY_SIZE,
+            local_ltk: None,
+            local_csrk: None,
+            peer_ltk: None,
+            peer_irk: None,
+            peer_identity: None,
+            peer_csrk: None,
+        }
+    }
+}
+
+impl fmt::Debug for Bond {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // Only print which keys are present, not the keys themselves
+        f.debug_struct("Bond")
+            .field("peer", &self.peer)
+            .field("authenticated", &self.authenticated)
+            .field("key_size", &self.key_size)
+            .field("local_ltk", &self.local_ltk)
+            .field("local_csrk", &self.local_csrk.is_some())
+            .field("peer_ltk", &self.peer_ltk)
+            .field("peer_irk", &self.peer_irk.is_some())
+            .field("peer_identity", &self.peer_identity)
+            .field("peer_csrk", &self.peer_csrk.is_some())
+            .finish()
+    }
+}
+
+impl defmt::Format for Bond {
+    fn format(&self, fmt: defmt::Formatter<'_>) {
+        defmt::write!(
+            fmt,
+            "Bond {{ peer: {}, authenticated: {=bool}, key_size: {=u8}, local_ltk: {}, \
+             local_csrk: {=bool}, peer_ltk: {}, peer_irk: {=bool}, peer_identity: {}, \
+             peer_csrk: {=bool} }}",
+            self.peer,
+            self.authenticated,
+            self.key_size,
+            self.local_ltk,
+            self.local_csrk.is_some(),
+            self.peer_ltk,
+            self.peer_irk.is_some(),
+            self.peer_identity,
+            self.peer_csrk.is_some(),
+        );
+    }
+}
+
+/// Security level of a Link-Layer connection.
+///
+/// Levels are ordered, so a link that is `Authenticated` also satisfies `Encrypted`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, defmt::Format)]
+pub enum LinkSecurity {
+    /// The link is not encrypted.
+    Unencrypted,
+    /// The link is encrypted with a key generated by an unauthenticated pairing method.
+    Encrypted,
+    /// The link is encrypted with a key generated by an authenticated (MITM-protected) pairing
+    /// method.
+    Authenticated,
+}
+
+/// Parameters of an *LL_ENC_REQ* sent by the master to start encryption.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, defmt::Format)]
+pub struct EncryptionRequest {
+    /// 64-bit random number identifying the LTK.
+    pub rand: u64,
+    /// Encrypted Diversifier (EDIV) identifying the LTK.
+    pub ediv: u16,
+    /// The master's part of the session key diversifier (`SKDm`).
+    pub skd_m: u64,
+    /// The master's part of the initialization vector (`IVm`).
+    pub iv_m: u32,
+}
+
+/// Key material for encrypting a connection, as returned by
+/// [`SecurityManager::encryption_request`].
+///
+/// [`SecurityManager::encryption_request`]: struct.SecurityManager.html#method.encryption_request
+#[derive(Copy, Clone)]
+pub struct SessionKey {
+    /// The slave's part of the session key diversifier (`SKDs`), sent in the *LL_ENC_RSP*.
+    pub skd_s: u64,
+    /// The slave's part of the initialization vector (`IVs`), sent in the *LL_ENC_RSP*.
+    pub iv_s: u32,
+    /// The AES-CCM session key, derived from the LTK or STK.
+    pub key: u128,
+    /// The AES-CCM initialization vector (`IVm || IVs`, with `IVm` in the lower half).
+    pub iv: u64,
+}
+
+impl fmt::Debug for SessionKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // Don't leak the key into logs
+        f.debug_struct("SessionKey")
+            .field("skd_s", &self.skd_s)
+            .field("iv_s", &self.iv_s)
+            .field("iv", &self.iv)
+            .finish()
+    }
+}
+
+impl defmt::Format for SessionKey {
+    fn format(&self, fmt: defmt::Formatter<'_>) {
+        defmt::write!(
+            fmt,
+            "SessionKey {{ skd_s: {=u64}, iv_s: {=u32}, iv: {=u64} }}",
+            self.skd_s,
+            self.iv_s,
+            self.iv
+        );
+    }
+}
+
+/// Pairing state of the Security Manager.
+#[derive(Debug, Copy, Clone, defmt::Format)]
+enum PairingState {
+    Idle,
+
+    /// *Pairing Response* sent, waiting for the master's confirm value (`Mconfirm`) and the TK.
+    ///
+    /// When using *Passkey Entry*, the master's confirm value might arrive before the user has
+    /// entered the passkey.
+    WaitConfirm {
+        data: LegacyData,
+        mconfirm: Option<u128>,
+    },
+
+    /// Our confirm value was sent, waiting for the master's random value (`Mrand`).
+    WaitRandom {
+        data: LegacyData,
+        tk: u128,
+        mconfirm: u128,
+        srand: u128,
+    },
+
+    /// The STK has been generated. Once the link is encrypted, the keys are distributed.
+    ///
+    /// The slave distributes its keys first, then the master sends its keys.
+    KeyDistribution {
+        stk: u128,
+        /// Whether the pairing method provides MITM protection.
+        authenticated: bool,
+        /// The negotiated encryption key size in Bytes.
+        key_size: u8,
+        /// Keys distributed by the local device.
+        local: KeyDistribution,
+        /// Index of the next key PDU to send (see `KeyPdu`).
+        next: u8,
+        /// Keys still expected from the master.
+        remote: KeyDistribution,
+    },
+}
+
+/// State kept during *LE Legacy Pairing*.
+#[derive(Copy, Clone)]
+struct LegacyData {
+    /// The *Pairing Request* PDU, including opcode.
+    preq: [u8; 7],
+    /// The *Pairing Response* PDU, including opcode.
+    pres: [u8; 7],
+    /// The Temporary Key, if already known.
+    tk: Option<u128>,
+    /// Keys the master distributes to us.
+    initiator_dist: KeyDistribution,
+    /// Keys to distribute to the master.
+    responder_dist: KeyDistribution,
+    /// Whether both sides requested bonding.
+    bonding: bool,
+    /// Whether the pairing method provides MITM protection.
+    authenticated: bool,
+    /// The negotiated encryption key size in Bytes.
+    key_size: u8,
+}
+
+impl fmt::Debug for LegacyData {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("LegacyData")
+            .field("preq", &HexSlice(&self.preq[..]))
+            .field("pres", &HexSlice(&self.pres[..]))
+            .field("initiator_dist", &self.initiator_dist)
+            .field("responder_dist", &self.responder_dist)
+            .field("bonding", &self.bonding)
+            .field("authenticated", &self.authenticated)
+            .field("key_size", &self.key_size)
+            .finish()
+    }
+}
+
+impl defmt::Format for LegacyData {
+    fn format(&self, fmt: defmt::Formatter<'_>) {
+        defmt::write!(
+            fmt,
+            "LegacyData {{ preq: {=[u8]:x}, pres: {=[u8]:x}, initiator_dist: {}, \
+             responder_dist: {}, bonding: {=bool}, authenticated: {=bool}, key_size: {=u8} }}",
+            &self.preq[..],
+            &self.pres[..],
+            self.initiator_dist,
+            self.responder_dist,
+            self.bonding,
+            self.authenticated,
+            self.key_size,
+        );
+    }
+}
+
+/// The LE Security Manager.
+///
+/// Manages pairing and key generation and exchange.
+///
+/// Rubble only implements the slave (responder) role of the pairing process.
+pub struct SecurityManager<S: SecurityLevel> {
+    security: S,
+    state: PairingState,
+
+    /// Local address and peer of the current connection.
+    connection: Option<(DeviceAddress, Peer)>,
+
+    bond: Option<Bond>,
+
+    /// Security level and size of the key returned by the last `encryption_request`.
+    key_security: LinkSecurity,
+    key_size: u8,
+
+    /// Whether the key returned by the last `encryption_request` is the STK.
+    key_is_stk: bool,
+
+    /// Whether the link is encrypted with the STK, which is required for distributing keys.
+    stk_encrypted: bool,
+}
+
+impl SecurityManager<NoSecurity> {
+    pub fn no_security() -> Self {
+        Self::new(NoSecurity)
+    }
+}
+
+impl<S: SecurityLevel> SecurityManager<S> {
+    /// Creates a Security Manager operating at security level `security`.
+    pub fn new(security: S) -> Self {
+        Self {
+            security,
+            state: PairingState::Idle,
+            connection: None,
+            bond: None,
+            key_security: LinkSecurity::Unencrypted,
+            key_size: 0,
+            key_is_stk: false,
+            stk_encrypted: false,
+        }
+    }
+
+    /// Informs the Security Manager about a newly established connection.
+    ///
+    /// This must be called whenever a new connection is established. It resets all pairing state,
+    /// and stores the device addresses, which are needed to compute the confirm values during
+    /// pairing. The peer can be obtained via [`Connection::peer`]. [`Responder::connected`] calls
+    /// this.
+    ///
+    /// [`Connection::peer`]: ../link/struct.Connection.html#method.peer
+    /// [`Responder::connected`]: ../link/struct.Responder.html#method.connected
+    pub fn connected(&mut self, local: DeviceAddress, peer: Peer) {
+        self.state = PairingState::Idle;
+        self.connection = Some((local, peer));
+        self.key_security = LinkSecurity::Unencrypted;
+        self.key_size = 0;
+        self.key_is_stk = false;
+        self.stk_encrypted = false;
+    }
+
+    /// Returns the peer of the current connection.
+    ///
+    /// When the peer distributes its identity address during bonding, its `id` is updated
+    /// accordingly.
+    pub fn peer(&self) -> Option<&Peer> {
+        self.connection.as_ref().map(|(_, peer)| peer)
+    }
+
+    /// Returns the Short Term Key (STK) generated by *LE Legacy Pairing*.
+    ///
+    /// The master encrypts the connection with this key (see [`encryption_request`]) before the
+    /// remaining keys can be distributed using [`SecurityManagerTx::distribute_key`].
+    ///
+    /// [`encryption_request`]: #method.encryption_request
+    /// [`SecurityManagerTx::distribute_key`]: struct.SecurityManagerTx.html#method.distribute_key
+    pub fn stk(&self) -> Option<u128> {
+        match self.state {
+            PairingState::KeyDistribution { stk, .. } => Some(stk),
+            _ => None,
+        }
+    }
+
+    /// Looks up the key to encrypt the connection with, when the master sends an *LL_ENC_REQ*.
+    ///
+    /// During *LE Legacy Pairing*, the master requests encryption with the STK, setting `ediv` and
+    /// `rand` to 0. When reconnecting, a bonded master requests encryption with the LTK from an
+    /// earlier pairing instead, which is looked up via `BondStore::lookup_ltk`. This encrypts the
+    /// link without pairing again. If the bond is found, the peer's `id` is set to the identity
+    /// from the bond.
+    ///
+    /// Returns the values to send in the *LL_ENC_RSP* along with the session key, or `None` if no
+    /// key is known. In that case, the Link-Layer has to reject the request with *LL_REJECT_IND*
+    /// (`PIN or Key Missing`), and the master will usually start pairing. Once the Link-Layer has
+    /// finished the encryption start procedure, it has to call [`encryption_changed`].
+    ///
+    /// [`encryption_changed`]: #method.encryption_changed
+    pub fn encryption_request(&mut self, req: &EncryptionRequest) -> Option<SessionKey> {
+        let peer = &mut self.connection.as_mut()?.1;
+        let ctx = self.security.pairing()?;
+
+        let (ltk, authenticated, key_size, is_stk) = match self.state {
+            PairingState::KeyDistribution {
+                stk,
+                authenticated,
+                key_size,
+                ..
+            } if req.ediv == 0 && req.rand == 0 => (stk, authenticated, key_size, true),
+            _ => {
+                let bond = match ctx.bonds.lookup_ltk(peer, req.ediv, req.rand) {
+                    Some(bond) => bond,
+                    None => {
+                        warn!("SMP: no LTK for {:?}", peer.id);
+                        return None;
+                    }
+                };
+                peer.id = PeerId::from_bond(&bond);
+                let ltk = bond.local_ltk?.ltk;
+                (ltk, bond.authenticated, bond.key_size, false)
+            }
+        };
+
+        let skd_s = ctx.rng.next_u64();
+        let iv_s = ctx.rng.next_u32();
+        let skd = u128::from(skd_s) << 64 | u128::from(req.skd_m);
+        let key = SessionKey {
+            skd_s,
+            iv_s,
+            key: toolbox::e(ctx.aes, ltk, skd),
+            iv: u64::from(iv_s) << 32 | u64::from(req.iv_m),
+        };
+
+        self.key_security = if authenticated {
+            LinkSecurity::Authenticated
+        } else {
+            LinkSecurity::Encrypted
+        };
+        self.key_size = key_size;
+        self.key_is_stk = is_stk;
+        Some(key)
+    }
+
+    /// Informs the Security Manager that the Link-Layer has enabled or disabled encryption.
+    ///
+    /// When `encrypted` is `true`, the link is encrypted with the key returned by the last call to
+    /// [`encryption_request`], and the `security` and `key_size` of the [`peer`] are updated. The
+    /// updated peer should then be passed to `AttributeServer::set_peer`, which grants access to
+    /// attributes requiring an encrypted link. If the key is the STK, the keys negotiated during
+    /// pairing can now be exchanged (see [`has_keys_to_distribute`]).
+    ///
+    /// [`has_keys_to_distribute`]: #method.has_keys_to_distribute
+    /// [`encryption_request`]: #method.encryption_request
+    /// [`peer`]: #method.peer
+    pub fn encryption_changed(&mut self, encrypted: bool) {
+        let (security, key_size) = if encrypted {
+            (self.key_security, self.key_size)
+        } else {
+            (LinkSecurity::Unencrypted, 0)
+        };
+        self.stk_encrypted = encrypted && self.key_is_stk;
+        if let Some((_, peer)) = &mut self.connection {
+            debug!("SMP: link security {:?}, {}-Byte key", security, key_size);
+            peer.security = security;
+            peer.key_size = key_size;
+        }
+    }
+
+    /// Returns whether [`SecurityManagerTx::distribute_key`] has a key to send.
+    ///
+    /// This is the case after *LE Legacy Pairing* once the link is encrypted with the STK, until
+    /// all local keys have been sent. The `Responder` distributes the keys automatically.
+    ///
+    /// [`SecurityManagerTx::distribute_key`]: struct.SecurityManagerTx.html#method.distribute_key
+    pub fn has_keys_to_distribute(&self) -> bool {
+        match self.state {
+            PairingState::KeyDistribution { local, next, .. } => {
+                self.stk_encrypted && KeyPdu::next(local, next).is_some()
+            }
+            _ => false,
+        }
+    }
+
+    /// Returns the keys shared with the last bonded device, if any.
+    pub fn bond(&self) -> Option<&Bond> {
+        self.bond.as_ref()
+    }
+
+    /// Prepares for sending Security Manager PDUs using `sender`.
+    pub fn with_sender<'a>(&'a mut self, sender: Sender<'a>) -> SecurityManagerTx<'a, S> {
+        SecurityManagerTx { sm: self, sender }
+    }
+
+    /// Processes an SMP command and returns the response to send (if any).
+    ///
+    /// `raw` is the complete command as sent over the air.
+    fn handle(&mut self, cmd: Command<'_>, raw: &[u8]) -> Option<Command<'static>> {
+        let reason = match self.try_handle(cmd, raw) {
+            Ok(response) => return response,
+            Err(reason) => reason,
+        };
+
+        if let Command::PairingFailed(_) = cmd {
+            self.abort(reason);
+            None
+        } else {
+            Some(self.abort(reason))
+        }
+    }
+
+    /// Aborts the pairing procedure and returns the *Pairing Failed* command to send.
+    fn abort(&mut self, reason: Reason) -> Command<'static> {
+        self.state = PairingState::Idle;
+        if let (Some((_, peer)), Some(ctx)) = (&self.connection, self.security.pairing()) {
+            ctx.delegate.pairing_failed(peer, reason);
+        }
+        Command::PairingFailed(reason)
+    }
+
+    /// Returns the local address (of the responder) and the peer (the initiator).
+    fn connection(&self) -> Result<(DeviceAddress, Peer), Reason> {
+        match self.connection {
+            // We are always the responder
+            Some(connection) => Ok(connection),
+            None => {
+                warn!("SMP: addresses unknown, call `SecurityManager::connected`");
+                Err(Reason::UnspecifiedReason)
+            }
+        }
+    }
+
+    fn try_handle(
+        &mut self,
+        cmd: Command<'_>,
+        raw: &[u8],
+    ) -> Result<Option<Command<'static>>, Reason> {
+        let (ra, peer) = self.connection()?;
+        let ia = peer.address;
+
+        let is_key = matches!(
+            cmd,
+            Command::EncryptionInformation(_)
+                | Command::MasterIdentification { .. }
+                | Command::IdentityInformation(_)
+                | Command::IdentityAddressInformation(_)
+                | Command::SigningInformation(_)
+        );
+        if is_key && !self.stk_encrypted {
+            // Keys must never be sent in plain text
+            warn!("SMP: key received before the link was encrypted with the STK");
+            return Err(Reason::UnspecifiedReason);
+        }
+
+        match (cmd, self.state) {
+            (Command::PairingRequest(req), _) => {
+                let ctx = self.security.pairing().ok_or(Reason::PairingNotSupported)?;
+
+                if !(MIN_KEY_SIZE..=MAX_KEY_SIZE).contains(&req.max_keysize) {
+                    return Err(Reason::InvalidParameters);
+                }
+                let key_size = req.max_keysize;
+                if key_size < ctx.config.min_key_size {
+                    warn!(
+                        "SMP: {}-Byte keys are below the minimum of {} Bytes",
+                        key_size, ctx.config.min_key_size
+                    );
+                    return Err(Reason::EncryptionKeySize);
+                }
+
+                let oob_data = ctx.delegate.oob_data(&peer);
+                let method = if req.oob.value() == Oob::Present && oob_data.is_some() {
+                    Method::OutOfBand
+                } else if !req.auth_req.value().mitm() && !ctx.config.mitm {
+                    Method::JustWorks
+                } else {
+                    Method::select(req.io.value(), ctx.config.io)?
+                };
+                if ctx.config.mitm && method == Method::JustWorks {
+                    warn!("SMP: MITM protection required, but not possible");
+                    return Err(Reason::AuthenticationRequirements);
+                }
+
+                let bonding = req.auth_req.value().bonding_type() == BondingType::Bonding
+                    && ctx.config.bonding == BondingType::Bonding;
+                let (initiator_dist, responder_dist) = if bonding {
+                    let mut local = KeyDistribution::ENC_KEY | KeyDistribution::SIGN_KEY;
+                    if ctx.config.identity.is_some() {
+                        local |= KeyDistribution::ID_KEY;
+                    }
+                    let remote = KeyDistribution::ENC_KEY
+                        | KeyDistribution::ID_KEY
+                        | KeyDistribution::SIGN_KEY;
+                    (
+                        req.initiator_dist.value() & remote,
+                        req.responder_dist.value() & local,
+                    )
+                } else {
+                    (KeyDistribution::empty(), KeyDistribution::empty())
+                };
+
+                let mut auth_req = AuthReq(0);
+                auth_req.set_bonding_type(ctx.config.bonding);
+                auth_req.set_mitm(ctx.config.mitm);
+                let oob = if oob_data.is_some() {
+                    Oob::Present
+                } else {
+                    Oob::NotPresent
+                };
+                let rsp = PairingParams {
+                    io: Field::new(ctx.config.io.into()),
+                    oob: Field::new(oob.into()),
+                    auth_req: Field::new(auth_req.as_raw()),
+                    max_keysize: MAX_KEY_SIZE,
+                    initiator_dist: Field::new(initiator_dist.bits()),
+                    responder_dist: Field::new(responder_dist.bits()),
+                };
+
+                let mut preq = [0; 7];
+                preq.copy_from_slice(raw.get(..7).ok_or(Reason::InvalidParameters)?);
+                let mut pres = [0; 7];
+                Command::PairingResponse(rsp)
+                    .to_bytes(&mut ByteWriter::new(&mut pres))
+                    .map_err(|_| Reason::UnspecifiedReason)?;
+
+                debug!("SMP: pairing using {:?}", method);
+                let tk = match method {
+                    // "Just Works" uses a TK of 0
+                    Method::JustWorks => Some(0),
+                    Method::OutOfBand => oob_data,
+                    Method::PasskeyDisplay => {
+                        let passkey = ctx.rng.next_u32() % 1_000_000;
+                        ctx.delegate.display_passkey(&peer, passkey);
+                        Some(passkey.into())
+                    }
+                    Method::PasskeyInput => {
+                        ctx.delegate.request_passkey(&peer);
+                        None
+                    }
+                };
+
+                self.state = PairingState::WaitConfirm {
+                    data: LegacyData {
+                        preq,
+                        pres,
+                        tk,
+                        initiator_dist,
+                        responder_dist,
+                        bonding,
+                        authenticated: method != Method::JustWorks,
+                        key_size,
+                    },
+                    mconfirm: None,
+                };
+                Ok(Some(Command::PairingResponse(rsp)))
+            }
+            (Command::PairingConfirm(mconfirm), PairingState::WaitConfirm { data, .. }) => {
+                match data.tk {
+                    Some(tk) => self.confirm(data, tk, mconfirm).map(Some),
+                    None => {
+                        // Respond once the user has entered the passkey
+                        self.state = PairingState::WaitConfirm {
+                            data,
+                            mconfirm: Some(mconfirm),
+                        };
+                        Ok(None)
+                    }
+                }
+            }
+            (
+                Command::PairingRandom(mrand),
+                PairingState::WaitRandom {
+                    data,
+                    tk,
+                    mconfirm,
+                    srand,
+                },
+            ) => {
+                let ctx = self.security.pairing().ok_or(Reason::UnspecifiedReason)?;
+
+                let expected = toolbox::c1(ctx.aes, tk, mrand, &data.preq, &data.pres, &ia, &ra);
+                if expected != mconfirm {
+                    warn!("SMP: confirm value mismatch");
+                    return Err(Reason::ConfirmValueFailed);
+                }
+
+                let stk = shorten_key(toolbox::s1(ctx.aes, tk, srand, mrand), data.key_size);
+
+                self.bond = if data.bonding {
+                    let mut bond = Bond::new(ia, data.authenticated);
+                    bond.key_size = data.key_size;
+                    if data.responder_dist.contains(KeyDistribution::ENC_KEY) {
+                        bond.local_ltk = Some(LongTermKey {
+                            ltk: shorten_key(random_u128(ctx.rng), data.key_size),
+                            ediv: ctx.rng.next_u32() as u16,
+                            rand: ctx.rng.next_u64(),
+                        });
+                    }
+                    if data.responder_dist.contains(KeyDistribution::SIGN_KEY) {
+                        bond.local_csrk = Some(random_u128(ctx.rng));
+                    }
+                    Some(bond)
+                } else {
+                    None
+                };
+
+                debug!("SMP: STK generated, waiting for encryption");
+                self.state = PairingState::KeyDistribution {
+                    stk,
+                    authenticated: data.authenticated,
+                    key_size: data.key_size,
+                    local: data.responder_dist,
+                    next: 0,
+                    remote: data.initiator_dist,
+                };
+                self.stk_encrypted = false;
+                Ok(Some(Command::PairingRandom(srand)))
+            }
+            (Command::EncryptionInformation(ltk), PairingState::KeyDistribution { remote, .. })
+                if remote.contains(KeyDistribution::ENC_KEY) =>
+            {
+                let bond = self.bond.as_mut().ok_or(Reason::UnspecifiedReason)?;
+                // EDIV and Rand follow in the *Master Identification* command
+                bond.peer_ltk = Some(LongTermKey {
+                    ltk,
+                    ediv: 0,
+                    rand: 0,
+                });
+                Ok(None)
+            }
+            (
+                Command::MasterIdentification { ediv, rand },
+                PairingState::KeyDistribution { remote, .. },
+            ) if remote.contains(KeyDistribution::ENC_KEY) => {
+                let bond = self.bond.as_mut().ok_or(Reason::UnspecifiedReason)?;
+                let ltk = bond.peer_ltk.as_mut().ok_or(Reason::UnspecifiedReason)?;
+                ltk.ediv = ediv;
+                ltk.rand = rand;
+                self.key_received(KeyDistribution::ENC_KEY);
+                Ok(None)
+            }
+            (Command::IdentityInformation(irk), PairingState::KeyDistribution { remote, .. })
+                if remote.contains(KeyDistribution::ID_KEY) =>
+            {
+                let bond = self.bond.as_mut().ok_or(Reason::UnspecifiedReason)?;
+                bond.peer_irk = Some(irk);
+                Ok(None)
+            }
+            (
+                Command::IdentityAddressInformation(address),
+                PairingState::KeyDistribution { remote, .. },
+            ) if remote.contains(KeyDistribution::ID_KEY) => {
+                let bond = self.bond.as_mut().ok_or(Reason::UnspecifiedReason)?;
+                if bond.peer_irk.is_none() {
+                    return Err(Reason::UnspecifiedReason);
+                }
+                bond.peer_identity = Some(address);
+                if let Some((_, peer)) = &mut self.connection {
+                    peer.id = PeerId::Identity(address);
+                }
+                self.key_received(KeyDistribution::ID_KEY);
+                Ok(None)
+            }
+            (Command::SigningInformation(csrk), PairingState::KeyDistribution { remote, .. })
+                if remote.contains(KeyDistribution::SIGN_KEY) =>
+            {
+                let bond = self.bond.as_mut().ok_or(Reason::UnspecifiedReason)?;
+                bond.peer_csrk = Some(csrk);
+                self.key_received(KeyDistribution::SIGN_KEY);
+                Ok(None)
+            }
+            (Command::PairingFailed(reason), _) => {
+                warn!("SMP: pairing failed: {:?}", reason);
+                Err(reason)
+            }
+            (Command::Unknown { code, data }, _) => {
+                warn!("[NYI] SMP cmd {:?}: {:?}", code, HexSlice(data));
+                Err(Reason::CommandNotSupported)
+            }
+            (cmd, state) => {
+                warn!("SMP: unexpected {:?} in state {:?}", cmd, state);
+                Err(Reason::UnspecifiedReason)
+            }
+        }
+    }
+
+    /// Marks all keys of type `key` as received from the master.
+    fn key_received(&mut self, key: KeyDistribution) {
+        if let PairingState::KeyDistribution { remote, .. } = &mut self.state {
+            remote.remove(key);
+        }
+        self.finish_if_done();
+    }
+
+    /// Completes the key distribution phase if all keys have been exchanged.
+    fn finish_if_done(&mut self) {
+        let done = match self.state {
+            PairingState::KeyDistribution {
+                local,
+                next,
+                remote,
+                ..
+            } => KeyPdu::next(local, next).is_none() && remote.is_empty(),
+            _ => false,
+        };
+
+        if done {
+            debug!("SMP: key distribution complete: {:?}", self.bond);
+            self.state = PairingState::Idle;
+            if let (Some(bond), Some(ctx)) = (&self.bond, self.security.pairing()) {
+                ctx.bonds.store(bond);
+            }
+        }
+    }
+
+    /// Returns the next key distribution command to send, and marks it as sent.
+    ///
+    /// Returns `None` while the link isn't encrypted with the STK.
+    fn next_key(&mut self) -> Option<Command<'static>> {
+        if !self.stk_encrypted {
+            return None;
+        }
+        let (index, pdu) = match self.state {
+            PairingState::KeyDistribution { local, next, .. } => KeyPdu::next(local, next)?,
+            _ => return None,
+        };
+
+        if let PairingState::KeyDistribution { next, .. } = &mut self.state {
+            *next = index + 1;
+        }
+
+        let cmd = self.local_key(pdu);
+        if cmd.is_none() {
+            // Should be unreachable, since we only agree to distribute keys we have
+            error!("SMP: key for {:?} missing", pdu);
+        }
+        cmd
+    }
+
+    /// Returns the command carrying the local key sent in `pdu`.
+    fn local_key(&mut self, pdu: KeyPdu) -> Option<Command<'static>> {
+        let bond = self.bond.as_ref()?;
+        Some(match pdu {
+            KeyPdu::EncryptionInformation => Command::EncryptionInformation(bond.local_ltk?.ltk),
+            KeyPdu::MasterIdentification => {
+                let ltk = bond.local_ltk?;
+                Command::MasterIdentification {
+                    ediv: ltk.ediv,
+                    rand: ltk.rand,
+                }
+            }
+            KeyPdu::IdentityInformation => {
+                let (irk, _) = self.security.pairing()?.config.identity?;
+                Command::IdentityInformation(irk)
+            }
+            KeyPdu::IdentityAddressInformation => {
+                let (_, address) = self.security.pairing()?.config.identity?;
+                Command::IdentityAddressInformation(address)
+            }
+            KeyPdu::SigningInformation => Command::SigningInformation(bond.local_csrk?),
+        })
+    }
+
+    /// Generates `Srand` and the local confirm value once both TK and `Mconfirm` are known.
+    fn confirm(
+        &mut self,
+        data: LegacyData,
+        tk: u128,
+        mconfirm: u128,
+    ) -> Result<Command<'static>, Reason> {
+        let (ra, peer) = self.connection()?;
+        let ia = peer.address;
+        let ctx = self.security.pairing().ok_or(Reason::UnspecifiedReason)?;
+
+        let srand = random_u128(ctx.rng);
+        let sconfirm = toolbox::c1(ctx.aes, tk, srand, &data.preq, &data.pres, &ia, &ra);
+        self.state = PairingState::WaitRandom {
+            data,
+            tk,
+            mconfirm,
+            srand,
+        };
+        Ok(Command::PairingConfirm(sconfirm))
+    }
+
+    /// Processes the passkey entered by the user and returns the command to send (if any).
+    fn passkey_entered(&mut self, passkey: u32) -> Option<Command<'static>> {
+        let (mut data, mconfirm) = match self.state {
+            PairingState::WaitConfirm { data, mconfirm } if data.tk.is_none() => (data, mconfirm),
+            _ => {
+                warn!("SMP: passkey entered, but none was requested");
+                return None;
+            }
+        };
+
+        if passkey > 999_999 {
+            return Some(self.abort(Reason::PasskeyEntryFailed));
+        }
+
+        let tk = u128::from(passkey);
+        data.tk = Some(tk);
+        match mconfirm {
+            Some(mconfirm) => match self.confirm(data, tk, mconfirm) {
+                Ok(cmd) => Some(cmd),
+                Err(reason) => Some(self.abort(reason)),
+            },
+            None => {
+                self.state = PairingState::WaitConfirm { data, mconfirm };
+                None
+            }
+        }
+    }
+}
+
+/// The key distribution PDUs, in the order they are sent.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, defmt::Format)]
+enum KeyPdu {
+    EncryptionInformation,
+    MasterIdentification,
+    IdentityInformation,
+    IdentityAddressInformation,
+    SigningInformation,
+}
+
+impl KeyPdu {
+    const ALL: [Self; 5] = [
+        KeyPdu::EncryptionInformation,
+        KeyPdu::MasterIdentification,
+        KeyPdu::IdentityInformation,
+        KeyPdu::IdentityAddressInformation,
+        KeyPdu::SigningInformation,
+    ];
+
+    /// Returns the key type whose distribution includes this PDU.
+    fn key(self) -> KeyDistribution {
+        match self {
+            KeyPdu::EncryptionInformation | KeyPdu::MasterIdentification => {
+                KeyDistribution::ENC_KEY
+            }
+            KeyPdu::IdentityInformation | KeyPdu::IdentityAddressInformation => {
+                KeyDistribution::ID_KEY
+            }
+            KeyPdu::SigningInformation => KeyDistribution::SIGN_KEY,
+        }
+    }
+
+    /// Returns the index and type of the first PDU at or after index `from` that has to be sent
+    /// to distribute `keys`.
+    fn next(keys: KeyDistribution, from: u8) -> Option<(u8, Self)> {
+        (from..)
+            .zip(Self::ALL.iter().skip(from.into()))
+            .find(|(_, pdu)| keys.contains(pdu.key()))
+            .map(|(i, pdu)| (i, *pdu))
+    }
+}
+
+/// *LE Legacy Pairing* methods for obtaining the Temporary Key (TK).
+#[derive(Debug, Copy, Clone, PartialEq, Eq, defmt::Format)]
+enum Method {
+    JustWorks,
+    OutOfBand,
+    /// *Passkey Entry*, with the local device displaying the passkey.
+    PasskeyDisplay,
+    /// *Passkey Entry*, with the user inputting the passkey on the local device.
+    PasskeyInput,
+}
+
+impl Method {
+    /// Selects the pairing method based on the I/O capabilities of initiator and responder.
+    ///
+    /// This implements the mapping table in Vol 3, Part H, 2.3.5.1 of the specification.
+    fn select(initiator: IoCapabilities, responder: IoCapabilities) -> Result<Self, Reason> {
+        use self::IoCapabilities::*;
+
+        Ok(match (initiator, responder) {
+            (Unknown(_), _) | (_, Unknown(_)) => return Err(Reason::InvalidParameters),
+            (NoInputNoOutput, _) | (_, NoInputNoOutput) => Method::JustWorks,
+            (DisplayOnly, DisplayOnly)
+            | (DisplayOnly, DisplayYesNo)
+            | (DisplayYesNo, DisplayOnly)
+            | (DisplayYesNo, DisplayYesNo) => Method::JustWorks,
+            (KeyboardOnly, DisplayOnly)
+            | (KeyboardOnly, DisplayYesNo)
+            | (KeyboardOnly, KeyboardDisplay)
+            | (KeyboardDisplay, DisplayOnly)
+            | (KeyboardDisplay, DisplayYesNo) => Method::PasskeyDisplay,
+            (_, KeyboardOnly) | (_, KeyboardDisplay) => Method::PasskeyInput,
+        })
+    }
+}
+
+/// Shortens `key` to `size` Bytes by clearing its most significant Bytes.
+fn shorten_key(key: u128, size: u8) -> u128 {
+    if size >= MAX_KEY_SIZE {
+        key
+    } else {
+        key & ((1 << (u32::from(size) * 8)) - 1)
+    }
+}
+
+fn random_u128(rng: &mut dyn RngCore) -> u128 {
+    let mut bytes = [0; 16];
+    rng.fill_bytes(&mut bytes);
+    u128::from_le_bytes(bytes)
+}
+
+impl<S: SecurityLevel> fmt::Debug for SecurityManager<S> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SecurityManager")
+            .field("state", &self.state)
+            .field("connection", &self.connection)
+            .finish()
+    }
+}
+
+impl<S: SecurityLevel> ProtocolObj for SecurityManager<S> {
+    fn process_message(&mut self, message: &[u8], mut responder: Sender<'_>) -> Result<(), Error> {
+        let cmd = Command::from_bytes(&mut ByteReader::new(message))?;
+        trace!("SMP cmd {:?}, {:?}", cmd, HexSlice(message));
+
+        if let Some(response) = self.handle(cmd, message) {
+            trace!("SMP rsp {:?}", response);
+            responder.send(response)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl<S: SecurityLevel> Protocol for SecurityManager<S> {
+    const RSP_PDU_SIZE: u8 = S::MTU;
+}
+
+/// A `SecurityManager` with the ability to send a PDU.
+///
+/// Returned by [`L2CAPStateTx::security`].
+///
+/// [`L2CAPStateTx::security`]: ../l2cap/struct.L2CAPStateTx.html#method.security
+pub struct SecurityManagerTx<'a, S: SecurityLevel> {
+    sm: &'a mut SecurityManager<S>,
+    sender: Sender<'a>,
+}
+
+impl<'a, S: SecurityLevel> SecurityManagerTx<'a, S> {
+    /// Supplies the passkey entered by the user after [`PairingDelegate::request_passkey`] was
+    /// called.
+    ///
+    /// Passing a value larger than `999999` aborts pairing.
+    ///
+    /// [`PairingDelegate::request_passkey`]: trait.PairingDelegate.html#method.request_passkey
+    pub fn enter_passkey(mut self, passkey: u32) -> Result<(), SmError> {
+        if let Some(cmd) = self.sm.passkey_entered(passkey) {
+            self.sender.send(cmd)?;
+        }
+        Ok(())
+    }
+
+    /// Sends the next key that should be distributed to the master after pairing.
+    ///
+    /// Keys are only sent once the Link-Layer has encrypted the connection using the key returned
+    /// by [`SecurityManager::stk`], since the SMP PDUs carry them in plain text. Only the keys
+    /// negotiated during the pairing feature exchange are sent. The `Responder` calls this
+    /// automatically after the link was encrypted.
+    ///
+    /// Returns `Ok(true)` if a PDU was sent, and `Ok(false)` if there is nothing (more) to
+    /// distribute or the link isn't encrypted with the STK yet. Once all keys have been sent and all keys of the master have been received, the
+    /// bond is passed to the [`BondStore`].
+    ///
+    /// [`SecurityManager::stk`]: struct.SecurityManager.html#method.stk
+    /// [`BondStore`]: trait.BondStore.html
+    pub fn distribute_key(mut self) -> Result<bool, SmError> {
+        match self.sm.next_key() {
+            Some(cmd) => {
+                self.sender.send(cmd)?;
+                self.sm.finish_if_done();
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+}
+
+/// Contents of a *Pairing Request* or *Pairing Response* command.
+///
+/// The fields describe the features of the device sending the command.
+#[derive(Debug, Copy, Clone, Unaligned, zerocopy::FromBytes, defmt::Format)]
+#[repr(C)]
+struct PairingParams {
+    /// The I/O capabilities of the device.
+    io: Field<u8, IoCapabilities>,
+    /// Whether the device has OOB pairing data available.
+    oob: Field<u8, Oob>,
+    /// Authentication requirements of the device.
+    auth_req: Field<u8, AuthReq>,
+    /// Maximum supported encryption key size in range 7..=16 Bytes.
+    ///
+    /// We always support 16-Byte keys. Smaller keys are only accepted down to the configured
+    /// `PairingConfig::min_key_size`.
+    max_keysize: u8,
+    /// Set of keys the initiator (the device sending the *Pairing Request*) will distribute to the
+    /// responder.
+    initiator_dist: Field<u8, KeyDistribution>,
+    /// Set of keys the responder will distribute to the initiator.
+    responder_dist: Field<u8, KeyDistribution>,
+}
+
+impl ToBytes for PairingParams {
+    fn to_bytes(&self, writer: &mut ByteWriter<'_>) -> Result<(), Error> {
+        writer.write_u8(*self.io.raw())?;
+        writer.write_u8(*self.oob.raw())?;
+        writer.write_u8(*self.auth_req.raw())?;
+        writer.write_u8(self.max_keysize)?;
+        writer.write_u8(*self.initiator_dist.raw())?;
+        writer.write_u8(*self.responder_dist.raw())?;
+        Ok(())
+    }
+}
+
+/// An SMP command.
+#[derive(Debug, Copy, Clone, defmt::Format)]
+enum Command<'a> {
+    PairingRequest(PairingParams),
+    PairingResponse(PairingParams),
+    PairingConfirm(u128),
+    PairingRandom(u128),
+    PairingFailed(Reason),
+    EncryptionInformation(u128),
+    MasterIdentification { ediv: u16, rand: u64 },
+    IdentityInformation(u128),
+    IdentityAddressInformation(DeviceAddress),
+    SigningInformation(u128),
+    Unknown { code: CommandCode, data: &'a [u8] },
+}
+
+impl Command<'_> {
+    fn code(&self) -> CommandCode {
+        match self {
+            Command::PairingRequest(_) => CommandCode::PairingRequest,
+            Command::PairingResponse(_) => CommandCode::PairingResponse,
+            Command::PairingConfirm(_) => CommandCode::PairingConfirm,
+            Command::PairingRandom(_) => CommandCode::PairingRandom,
+            Command::PairingFailed(_) => CommandCode::PairingFailed,
+            Command::EncryptionInformation(_) => CommandCode::EncryptionInformation,
+            Command::MasterIdentification { .. } => CommandCode::MasterIdentification,
+            Command::IdentityInformation(_) => CommandCode::IdentityInformation,
+            Command::IdentityAddressInformation(_) => CommandCode::IdentityAddressInformation,
+            Command::SigningInformation(_) => CommandCode::SigningInformation,
+            Command::Unknown { code, .. } => *code,
+        }
+    }
+}
+
+impl<'a> FromBytes<'a> for Command<'a> {
+    fn from_bytes(bytes: &mut ByteReader<'a>) -> Result<Self, Error> {
+        let code = CommandCode::from(bytes.read_u8()?);
+        Ok(match code {
+            CommandCode::PairingRequest => Command::PairingRequest(*bytes.read_obj()?),
+            CommandCode::PairingResponse => Command::PairingResponse(*bytes.read_obj()?),
+            CommandCode::PairingConfirm => {
+                Command::PairingConfirm(u128::from_le_bytes(bytes.read_array()?))
+            }
+            CommandCode::PairingRandom => {
+                Command::PairingRandom(u128::from_le_bytes(bytes.read_array()?))
+            }
+            CommandCode::PairingFailed => Command::PairingFailed(Reason::from(bytes.read_u8()?)),
+            CommandCode::EncryptionInformation => {
+                Command::EncryptionInformation(u128::from_le_bytes(bytes.read_array()?))
+            }
+            CommandCode::MasterIdentification => Command::MasterIdentification {
+                ediv: bytes.read_u16_le()?,
+                rand: bytes.read_u64_le()?,
+            },
+            CommandCode::IdentityInformation => {
+                Command::IdentityInformation(u128::from_le_bytes(bytes.read_array()?))
+            }
+            CommandCode::IdentityAddressInformation => {
+                let kind = match bytes.read_u8()? {
+                    0x00 => AddressKind::Public,
+                    0x01 => AddressKind::Random,
+                    _ => return Err(Error::InvalidValue),
+                };
+                Command::IdentityAddressInformation(DeviceAddress::new(bytes.read_array()?, kind))
+            }
+            CommandCode::SigningInformation => {
+                Command::SigningInformation(u128::from_le_bytes(bytes.read_array()?))
+            }
+            _ => Command::Unknown {
+                code,
+                data: bytes.read_rest(),
+            },
+        })
+    }
+}
+
+impl ToBytes for Command<'_> {
+    fn to_bytes(&self, writer: &mut ByteWriter<'_>) -> Result<(), Error> {
+        writer.write_u8(self.code().into())?;
+        match self {
+            Command::PairingRequest(params) | Command::PairingResponse(params) => {
+                params.to_bytes(writer)
+            }
+            Command::PairingConfirm(value)
+            | Command::PairingRandom(value)
+            | Command::EncryptionInformation(value)
+            | Command::IdentityInformation(value)
+            | Command::SigningInformation(value) => writer.write_slice(&value.to_le_bytes()),
+            Command::IdentityAddressInformation(address) => {
+                writer.write_u8(address.is_random().into())?;
+                writer.write_slice(address.raw())
+            }
+            Command::PairingFailed(reason) => writer.write_u8((*reason).into()),
+            Command::MasterIdentification { ediv, rand } => {
+                writer.write_u16_le(*ediv)?;
+                writer.write_u64_le(*rand)
+            }
+            Command::Unknown { data, .. } => writer.write_slice(data),
+        }
+    }
+}
+
+enum_with_unknown! {
+    #[derive(Debug, Copy, Clone, defmt::Format)]
+    enum CommandCode(u8) {
+        PairingRequest = 0x01,
+        PairingResponse = 0x02,
+        PairingConfirm = 0x03,
+        PairingRandom = 0x04,
+        PairingFailed = 0x05,
+        EncryptionInformation = 0x06,
+        MasterIdentification = 0x07,
+        IdentityInformation = 0x08,
+        IdentityAddressInformation = 0x09,
+        SigningInformation = 0x0A,
+        SecurityRequest = 0x0B,
+        PairingPublicKey = 0x0C,
+        PairingDhKeyCheck = 0x0D,
+        PairingKeypressNotification = 0x0E,
+    }
+}
+
+enum_with_unknown! {
+    /// Reason codes sent in a *Pairing Failed* command.
+    #[derive(Debug, Copy, Clone, PartialEq, Eq, defmt::Format)]
+    pub enum Reason(u8) {
+        /// The user input of the passkey failed (eg. it was canceled).
+        PasskeyEntryFailed = 0x01,
+        /// The OOB data is not available.
+        OobNotAvailable = 0x02,
+        /// The pairing procedure cannot be performed as the authentication requirements cannot be
+        /// met due to IO capabilities of one or both devices.
+        AuthenticationRequirements = 0x03,
+        /// The confirm value does not match the calculated compare value.
+        ConfirmValueFailed = 0x04,
+        /// Pairing is not supported by the device.
+        PairingNotSupported = 0x05,
+        /// The resultant encryption key size is insufficient for the security requirements.
+        EncryptionKeySize = 0x06,
+        /// The SMP command received is not supported on this device.
+        CommandNotSupported = 0x07,
+        /// Pairing failed due to an unspecified reason.
+        UnspecifiedReason = 0x08,
+        /// Pairing or authentication has been disallowed because too little time has elapsed since
+        /// the last pairing or security request.
+        RepeatedAttempts = 0x09,
+        /// The command length is invalid or a parameter is outside of the specified range.
+        InvalidParameters = 0x0A,
+        /// The DHKey Check value received doesn't match the one calculated by the local device.
+        DhKeyCheckFailed = 0x0B,
+        /// The confirm values in the numeric comparison protocol do not match.
+        NumericComparisonFailed = 0x0C,
+    }
+}
+
+enum_with_unknown! {
+    /// Describes the I/O capabilities of a device that can be used for the pairing process.
+    #[derive(Debug, Copy, Clone, defmt::Format)]
+    pub enum IoCapabilities(u8) {
+        /// Device can display a 6-digit number, but has no input capabilities.
+        DisplayOnly = 0x00,
+
+        /// Device can display a 6-digit number and the user can input "Yes" or "No".
+        DisplayYesNo = 0x01,
+
+        /// Device does not have output capability, but the user can input a passcode.
+        KeyboardOnly = 0x02,
+
+        /// Device has no meaningful input and output capabilities.
+        NoInputNoOutput = 0x03,
+
+        /// Device can display a 6-digit passcode and allows passcode entry via a keyboard.
+        KeyboardDisplay = 0x04,
+    }
+}
+
+enum_with_unknown! {
+    #[derive(Debug, Copy, Clone, PartialEq, Eq, defmt::Format)]
+    pub enum Oob(u8) {
+        NotPresent = 0x00,
+        Present = 0x01,
+    }
+}
+
+/// Authentication requirements exchanged during pairing requests.
+#[derive(Copy, Clone)]
+pub struct AuthReq(u8);
+
+impl AuthReq {
+    const BITS_BONDING: u8 = 0b0000_0011;
+    const BITS_MITM: u8 = 0b0000_0100;
+    const BITS_SC: u8 = 0b0000_1000;
+    const BITS_KEYPRESS: u8 = 0b0001_0000;
+
+    /// Returns the requested bonding.
+    pub fn bonding_type(&self) -> BondingType {
+        BondingType::from(self.0 & Self::BITS_BONDING)
+    }
+
+    pub fn set_bonding_type(&mut self, ty: BondingType) {
+        self.0 = (self.0 & !Self::BITS_BONDING) | u8::from(ty);
+    }
+
+    /// Returns whether MITM protection is requested.
+    pub fn mitm(&self) -> bool {
+        self.0 & Self::BITS_MITM != 0
+    }
+
+    pub fn set_mitm(&mut self, mitm: bool) {
+        self.0 = (self.0 & !Self::BITS_MITM) | if mitm { Self::BITS_MITM } else { 0 };
+    }
+
+    /// Returns whether *LE Secure Connection* pairing is supported and requested.
+    ///
+    /// If this returns `false`, *LE Legacy Pairing* will be used. Note that Rubble only supports
+    /// *LE Legacy Pairing* when explicitly configured with [`LegacyPairing`], since it has serious
+    /// security problems (refer to the module docs for more info).
+    ///
+    /// [`LegacyPairing`]: struct.LegacyPairing.html
+    pub fn secure_connection(&self) -> bool {
+        self.0 & Self::BITS_SC != 0
+    }
+
+    /// Sets whether *LE Secure Connection* pairing is supported and requested.
+    pub fn set_secure_connection(&mut self, sc: bool) {
+        self.0 = (self.0 & !Self::BITS_SC) | if sc { Self::BITS_SC } else { 0 };
+    }
+
+    pub fn keypress(&self) -> bool {
+        self.0 & Self::BITS_KEYPRESS != 0
+    }
+
+    pub fn set_keypress(&mut self, keypress: bool) {
+        self.0 = (self.0 & !Self::BITS_KEYPRESS) | if keypress { Self::BITS_KEYPRESS } else { 0 };
+    }
+}
+
+impl fmt::Debug for AuthReq {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AuthReq")
+            .field("bonding_type", &self.bonding_type())
+            .field("mitm", &self.mitm())
+            .field("secure_connection", &self.secure_connection())
+            .field("keypress", &self.keypress())
+            .finish()
+    }
+}
+
+impl defmt::Format for AuthReq {
+    fn format(&self, fmt: defmt::Formatter<'_>) {
+        defmt::write!(
+            fmt,
+            "AuthReq {{ bonding_type: {}, mitm: {=bool}, secure_connection: {=bool}, keypress: {=bool} }}",
+            self.bonding_type(),
+            self.mitm(),
+            self.secure_connection(),
+            self.keypress(),
+        );
+    }
+}
+
+impl RawRepr<u8> for AuthReq {
+    fn from_raw(raw: u8) -> Self {
+        Self(raw)
+    }
+
+    fn as_raw(&self) -> u8 {
+        self.0
+    }
+}
+
+enum_with_unknown! {
+    /// Whether to perform bonding in addition to pairing.
+    ///
+    /// If `Bonding` is selected, the exchanged keys are permanently stored on both devices. This
+    /// is usually what you want.
+    #[derive(Debug, Copy, Clone, PartialEq, Eq, defmt::Format)]
+    pub enum BondingType(u8) {
+        /// No bonding should be performed; the exchanged keys should not be permanently stored.
+        ///
+        /// This is usually not what you want since it requires the user to perform pairing every
+        /// time the devices connect again.
+        NoBonding = 0b00,
+
+        /// Permanently store the exchanged keys to allow resuming encryption on future connections.
+        Bonding = 0b01,
+    }
+}
+
+bitflags! {
+    /// Indicates which types of keys a device requests for distribution.
+    struct KeyDistribution: u8 {
+        const ENC_KEY = 1 << 0;
+        const ID_KEY = 1 << 1;
+        const SIGN_KEY = 1 << 2;
+        const LINK_KEY = 1 << 3;
+    }
+}
+
+impl defmt::Format for KeyDistribution {
+    fn format(&self, fmt: defmt::Formatter<'_>) {
+        defmt::write!(fmt, "KeyDistribution({=u8:b})", self.bits());
+    }
+}
+
+impl RawRepr<u8> for KeyDistribution {
+    fn from_raw(raw: u8) -> Self {
+        Self::from_bits_truncate(raw)
+    }
+
+    fn as_raw(&self) -> u8 {
+        self.bits()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::link::ad_structure::LeRole;
+    use crate::link::{AddressKind, ConnectionHandle};
+    use crate::security::bonds::BondManager;
+    use crate::test_rng::TestRng;
+
+    fn encode(cmd: Command<'_>, buf: &mut [u8; 23]) -> usize {
+        let mut writer = ByteWriter::new(buf);
+        cmd.to_bytes(&mut writer).unwrap();
+        23 - writer.space_left()
+    }
+
+    fn manager() -> SecurityManager<LegacyPairing<SoftAes, TestRng>> {
+        let mut sm = SecurityManager::new(LegacyPairing::new(
+            PairingConfig::new(),
+            SoftAes,
+            TestRng::counting(0),
+        ));
+        sm.connected(LOCAL, peer());
+        sm
+    }
+
+    const LOCAL: DeviceAddress = DeviceAddress::new([1, 2, 3, 4, 5, 6], AddressKind::Random);
+    const PEER: DeviceAddress = DeviceAddress::new([6, 5, 4, 3, 2, 1], AddressKind::Public);
+
+    fn peer() -> Peer {
+        Peer::new(ConnectionHandle::from_raw(0), PEER)
+    }
+
+    /// NoInputNoOutput, bonding, 16-Byte keys, responder should distribute all keys.
+    const PREQ: [u8; 7] = [0x01, 0x03, 0x00, 0x01, 16, 0x00, 0x0F];
+
+    /// Performs the pairing feature exchange and returns the encoded *Pairing Response*.
+    fn exchange_features<S: SecurityLevel>(sm: &mut SecurityManager<S>) -> [u8; 7] {
+        let cmd = Command::from_bytes(&mut ByteReader::new(&PREQ)).unwrap();
+        let rsp = match sm.handle(cmd, &PREQ) {
+            Some(Command::PairingResponse(rsp)) => rsp,
+            rsp => panic!("unexpected response {:?}", rsp),
+        };
+        // No identity is configured, and link keys are not supported
+        assert_eq!(
+            rsp.responder_dist.value(),
+            KeyDistribution::ENC_KEY | KeyDistribution::SIGN_KEY
+        );
+
+        let mut buf = [0; 23];
+        assert_eq!(encode(Command::PairingResponse(rsp), &mut buf), 7);
+        let mut pres = [0; 7];
+        pres.copy_from_slice(&buf[..7]);
+        pres
+    }
+
+    #[test]
+    fn legacy_just_works() {
+        let mut sm = manager();
+        let pres = exchange_features(&mut sm);
+
+        let mrand = 0x1234_5678_9abc_def0_1234_5678_9abc_def0;
+        let mconfirm = toolbox::c1(&mut SoftAes, 0, mrand, &PREQ, &pres, &PEER, &LOCAL);
+        let sconfirm = match sm.handle(Command::PairingConfirm(mconfirm), &[]) {
+            Some(Command::PairingConfirm(sconfirm)) => sconfirm,
+            rsp => panic!("unexpected response {:?}", rsp),
+        };
+        let srand = match sm.handle(Command::PairingRandom(mrand), &[]) {
+            Some(Command::PairingRandom(srand)) => srand,
+            rsp => panic!("unexpected response {:?}", rsp),
+        };
+
+        // The master checks our confirm value and derives the same STK
+        assert_eq!(
+            sconfirm,
+            toolbox::c1(&mut SoftAes, 0, srand, &PREQ, &pres, &PEER, &LOCAL)
+        );
+        assert_eq!(sm.stk(), Some(toolbox::s1(&mut SoftAes, 0, srand, mrand)));
+
+        let bond = sm.bond().unwrap();
+        assert_eq!(bond.peer, PEER);
+        assert!(bond.local_ltk.is_some());
+    }
+
+    #[test]
+    fn encryption_request() {
+        let mut sm = manager();
+        let pres = exchange_features(&mut sm);
+
+        let mrand = 0x1234_5678_9abc_def0_1234_5678_9abc_def0;
+        let mconfirm = toolbox::c1(&mut SoftAes, 0, mrand, &PREQ, &pres, &PEER, &LOCAL);
+        sm.handle(Command::PairingConfirm(mconfirm), &[]).unwrap();
+        sm.handle(Command::PairingRandom(mrand), &[]).unwrap();
+        let stk = sm.stk().unwrap();
+        assert!(!sm.has_keys_to_distribute());
+
+        // The STK is requested with EDIV and Rand set to 0
+        let req = EncryptionRequest {
+            rand: 0,
+            ediv: 0,
+            skd_m: 0xACBDCEDFE0F10213,
+            iv_m: 0xBADCAB24,
+        };
+        let key = sm.encryption_request(&req).unwrap();
+        let skd = u128::from(key.skd_s) << 64 | u128::from(req.skd_m);
+        assert_eq!(key.key, toolbox::e(&mut SoftAes, stk, skd));
+        assert_eq!(key.iv, u64::from(key.iv_s) << 32 | u64::from(req.iv_m));
+
+        // Keys are only distributed once the link is encrypted with the STK
+        assert!(!sm.has_keys_to_distribute());
+        sm.encryption_changed(true);
+        assert!(sm.has_keys_to_distribute());
+    }
+
+    #[test]
+    fn legacy_confirm_mismatch() {
+        let mut sm = manager();
+        let pres = exchange_features(&mut sm);
+
+        let mrand = 0x1234_5678_9abc_def0_1234_5678_9abc_def0;
+        let mconfirm = toolbox::c1(&mut SoftAes, 0, mrand, &PREQ, &pres, &PEER, &LOCAL);
+        sm.handle(Command::PairingConfirm(mconfirm), &[]).unwrap();
+        match sm.handle(Command::PairingRandom(mrand + 1), &[]) {
+            Some(Command::PairingFailed(Reason::ConfirmValueFailed)) => {}
+            rsp => panic!("unexpected response {:?}", rsp),
+        }
+        assert!(sm.stk().is_none());
+    }
+
+    #[derive(Default)]
+    struct Keyboard {
+        requested: bool,
+    }
+
+    impl PairingDelegate for Keyboard {
+        fn request_passkey(&mut self, peer: &Peer) {
+            assert_eq!(peer.address, PEER);
+            self.requested = true;
+        }
+    }
+
+    #[test]
+    fn legacy_passkey_input() {
+        let config = PairingConfig::new()
+            .io_capabilities(IoCapabilities::KeyboardOnly)
+            .mitm(true);
+        let security = LegacyPairing::new(config, SoftAes, TestRng::counting(0))
+            .with_delegate(Keyboard::default());
+        let mut sm = SecurityManager::new(security);
+        sm.connected(LOCAL, peer());
+
+        // DisplayOnly master requesting bonding and MITM protection
+        let preq = [0x01, 0x00, 0x00, 0x05, 16, 0x00, 0x01];
+        let cmd = Command::from_bytes(&mut ByteReader::new(&preq)).unwrap();
+        let mut pres = [0; 7];
+        match sm.handle(cmd, &preq) {
+            Some(rsp @ Command::PairingResponse(_)) => {
+                rsp.to_bytes(&mut ByteWriter::new(&mut pres)).unwrap()
+            }
+            rsp => panic!("unexpected response {:?}", rsp),
+        }
+        assert!(sm.security.delegate().requested);
+
+        // The master's confirm value arrives before the user is done typing
+        let passkey = 123456;
+        let mrand = 0xfedc_ba98_7654_3210;
+        let mconfirm = toolbox::c1(&mut SoftAes, passkey, mrand, &preq, &pres, &PEER, &LOCAL);
+        assert!(sm.handle(Command::PairingConfirm(mconfirm), &[]).is_none());
+
+        let srand = match sm.passkey_entered(passkey as u32) {
+            Some(Command::PairingConfirm(_)) => {
+                match sm.handle(Command::PairingRandom(mrand), &[]) {
+                    Some(Command::PairingRandom(srand)) => srand,
+                    rsp => panic!("unexpected response {:?}", rsp),
+                }
+            }
+            rsp => panic!("unexpected response {:?}", rsp),
+        };
+        assert_eq!(
+            sm.stk(),
+            Some(toolbox::s1(&mut SoftAes, passkey, srand, mrand))
+        );
+        assert!(sm.bond().unwrap().authenticated);
+    }
+
+    struct NfcTag(OobData);
+
+    impl PairingDelegate for NfcTag {
+        fn oob_data(&mut self, _peer: &Peer) -> Option<u128> {
+            self.0.tk
+        }
+    }
+
+    #[test]
+    fn legacy_oob() {
+        let oob = OobData::generate(LOCAL, LeRole::PeripheralOnly, &mut TestRng::counting(0x80));
+        let tk = oob.tk.unwrap();
+        let security = LegacyPairing::new(PairingConfig::new(), SoftAes, TestRng::counting(0))
+            .with_delegate(NfcTag(oob));
+        let mut sm = SecurityManager::new(security);
+        sm.connected(LOCAL, peer());
+
+        // NoInputNoOutput master with OOB data
+        let preq = [0x01, 0x03, 0x01, 0x01, 16, 0x00, 0x01];
+        let cmd = Command::from_bytes(&mut ByteReader::new(&preq)).unwrap();
+        let mut pres = [0; 7];
+        match sm.handle(cmd, &preq) {
+            Some(rsp @ Command::PairingResponse(_)) => {
+                rsp.to_bytes(&mut ByteWriter::new(&mut pres)).unwrap()
+            }
+            rsp => panic!("unexpected response {:?}", rsp),
+        }
+        assert_eq!(pres[2], 0x01);
+
+        let mrand = 0x0123_4567_89ab_cdef;
+        let mconfirm = toolbox::c1(&mut SoftAes, tk, mrand, &preq, &pres, &PEER, &LOCAL);
+        sm.handle(Command::PairingConfirm(mconfirm), &[]).unwrap();
+        let srand = match sm.handle(Command::PairingRandom(mrand), &[]) {
+            Some(Command::PairingRandom(srand)) => srand,
+            rsp => panic!("unexpected response {:?}", rsp),
+        };
+        assert_eq!(sm.stk(), Some(toolbox::s1(&mut SoftAes, tk, srand, mrand)));
+        assert!(sm.bond().unwrap().authenticated);
+    }
+
+    #[test]
+    fn method_selection() {
+        use self::IoCapabilities::*;
+
+        assert_eq!(
+            Method::select(KeyboardOnly, DisplayOnly),
+            Ok(Method::PasskeyDisplay)
+        );
+        assert_eq!(
+            Method::select(KeyboardDisplay, KeyboardDisplay),
+            Ok(Method::PasskeyInput)
+        );
+        assert_eq!(
+            Method::select(KeyboardDisplay, NoInputNoOutput),
+            Ok(Method::JustWorks)
+        );
+        assert_eq!(
+            Method::select(DisplayYesNo, DisplayOnly),
+            Ok(Method::JustWorks)
+        );
+    }
+
+    #[derive(Default)]
+    struct Store {
+        bond: Option<Bond>,
+    }
+
+    impl BondStore for Store {
+        fn store(&mut self, bond: &Bond) {
+            self.bond = Some(*bond);
+        }
+    }
+
+    #[test]
+    fn key_distribution() {
+        let irk = 0x0011_2233_4455_6677_8899_aabb_ccdd_eeff;
+        let identity = DeviceAddress::new([0xaa; 6], AddressKind::Random);
+        let config = PairingConfig::new().identity(irk, identity);
+        let security = LegacyPairing::new(config, SoftAes, TestRng::counting(0))
+            .with_bond_store(Store::default());
+        let mut sm = SecurityManager::new(security);
+        sm.connected(LOCAL, peer());
+
+        // Both devices distribute LTK and IRK, but the master doesn't want our CSRK
+        let preq = [0x01, 0x03, 0x00, 0x01, 16, 0x03, 0x03];
+        let cmd = Command::from_bytes(&mut ByteReader::new(&preq)).unwrap();
+        let mut pres = [0; 7];
+        match sm.handle(cmd, &preq) {
+            Some(rsp @ Command::PairingResponse(_)) => {
+                rsp.to_bytes(&mut ByteWriter::new(&mut pres)).unwrap()
+            }
+            rsp => panic!("unexpected response {:?}", rsp),
+        }
+
+        let mrand = 0x55;
+        let mconfirm = toolbox::c1(&mut SoftAes, 0, mrand, &preq, &pres, &PEER, &LOCAL);
+        sm.handle(Command::PairingConfirm(mconfirm), &[]).unwrap();
+        sm.handle(Command::PairingRandom(mrand), &[]).unwrap();
+
+        // Keys are only exchanged once the master has encrypted the link with the STK
+        assert!(!sm.has_keys_to_distribute());
+        assert!(sm.next_key().is_none());
+        match sm.handle(Command::EncryptionInformation(1), &[]) {
+            Some(Command::PairingFailed(Reason::UnspecifiedReason)) => {}
+            rsp => panic!("unexpected response {:?}", rsp),
+        }
+        assert!(sm.stk().is_none());
+
+        // Pair again, this time encrypting the link
+        let cmd = Command::from_bytes(&mut ByteReader::new(&preq)).unwrap();
+        sm.handle(cmd, &preq).unwrap();
+        sm.handle(Command::PairingConfirm(mconfirm), &[]).unwrap();
+        sm.handle(Command::PairingRandom(mrand), &[]).unwrap();
+        let req = EncryptionRequest {
+            rand: 0,
+            ediv: 0,
+            skd_m: 0,
+            iv_m: 0,
+        };
+        sm.encryption_request(&req).unwrap();
+        assert!(!sm.has_keys_to_distribute());
+        sm.encryption_changed(true);
+        assert!(sm.has_keys_to_distribute());
+
+        // We distribute our keys first
+        let ltk = sm.bond().unwrap().local_ltk.unwrap();
+        match sm.next_key() {
+            Some(Command::EncryptionInformation(key)) => assert_eq!(key, ltk.ltk),
+            cmd => panic!("unexpected key {:?}", cmd),
+        }
+        match sm.next_key() {
+            Some(Command::MasterIdentification { ediv, rand }) => {
+                assert_eq!((ediv, rand), (ltk.ediv, ltk.rand))
+            }
+            cmd => panic!("unexpected key {:?}", cmd),
+        }
+        match sm.next_key() {
+            Some(Command::IdentityInformation(key)) => assert_eq!(key, irk),
+            cmd => panic!("unexpected key {:?}", cmd),
+        }
+        match sm.next_key() {
+            Some(Command::IdentityAddressInformation(addr)) => assert_eq!(addr, identity),
+            cmd => panic!("unexpected key {:?}", cmd),
+        }
+        assert!(sm.next_key().is_none());
+        assert!(!sm.has_keys_to_distribute());
+        sm.finish_if_done();
+        assert!(sm.security.bond_store().bond.is_none());
+
+        // Then the master sends its keys; keys that weren't negotiated are rejected
+        let peer_identity = DeviceAddress::new([0xbb; 6], AddressKind::Public);
+        let master_keys = [
+            Command::EncryptionInformation(1),
+            Command::MasterIdentification { ediv: 2, rand: 3 },
+            Command::IdentityInformation(4),
+            Command::IdentityAddressInformation(peer_identity),
+        ];
+        for cmd in &master_keys {
+            let mut buf = [0; 23];
+            let len = encode(*cmd, &mut buf);
+            let cmd = Command::from_bytes(&mut ByteReader::new(&buf[..len])).unwrap();
+            assert!(sm.handle(cmd, &buf[..len]).is_none());
+        }
+
+        let bond = sm.security.bond_store().bond.unwrap();
+        assert_eq!(bond.peer_ltk.unwrap().ltk, 1);
+        assert_eq!(bond.peer_ltk.unwrap().ediv, 2);
+        assert_eq!(bond.peer_ltk.unwrap().rand, 3);
+        assert_eq!(bond.peer_irk, Some(4));
+        assert_eq!(bond.peer_identity, Some(peer_identity));
+        assert_eq!(PeerId::from_bond(&bond), PeerId::Identity(peer_identity));
+        assert_eq!(sm.peer().unwrap().id, PeerId::Identity(peer_identity));
+        assert!(bond.local_csrk.is_none());
+        assert!(sm.stk().is_none());
+
+        match sm.handle(Command::SigningInformation(5), &[]) {
+            Some(Command::PairingFailed(Reason::UnspecifiedReason)) => {}
+            rsp => panic!("unexpected response {:?}", rsp),
+        }
+    }
+
+    #[test]
+    fn key_size_policy() {
+        let config = PairingConfig::new().min_key_size(10);
+        let mut sm =
+            SecurityManager::new(LegacyPairing::new(config, SoftAes, TestRng::counting(0)));
+        sm.connected(LOCAL, peer());
+
+        let rejected = [
+            (9, Reason::EncryptionKeySize),
+            (6, Reason::InvalidParameters),
+            (17, Reason::InvalidParameters),
+        ];
+        for &(max_keysize, reason) in &rejected {
+            let preq = [0x01, 0x03, 0x00, 0x01, max_keysize, 0x00, 0x0F];
+            let cmd = Command::from_bytes(&mut ByteReader::new(&preq)).unwrap();
+            match sm.handle(cmd, &preq) {
+                Some(Command::PairingFailed(r)) if r == reason => {}
+                rsp => panic!("unexpected response {:?}", rsp),
+            }
+        }
+
+        let preq = [0x01, 0x03, 0x00, 0x01, 10, 0x00, 0x0F];
+        let cmd = Command::from_bytes(&mut ByteReader::new(&preq)).unwrap();
+        let mut pres = [0; 7];
+        match sm.handle(cmd, &preq) {
+            Some(rsp @ Command::PairingResponse(_)) => {
+                rsp.to_bytes(&mut ByteWriter::new(&mut pres)).unwrap()
+            }
+            rsp => panic!("unexpected response {:?}", rsp),
+        }
+        assert_eq!(pres[4], 16);
+
+        let mrand = 0x55;
+        let mconfirm = toolbox::c1(&mut SoftAes, 0, mrand, &preq, &pres, &PEER, &LOCAL);
+        sm.handle(Command::PairingConfirm(mconfirm), &[]).unwrap();
+        sm.handle(Command::PairingRandom(mrand), &[]).unwrap();
+
+        // The STK and LTK are shortened to the negotiated size
+        assert!(sm.stk().unwrap() < 1 << 80);
+        let bond = sm.bond().unwrap();
+        assert_eq!(bond.key_size, 10);
+        assert!(bond.local_ltk.unwrap().ltk < 1 << 80);
+
+        let req = EncryptionRequest {
+            rand: 0,
+            ediv: 0,
+            skd_m: 0,
+            iv_m: 0,
+        };
+        sm.encryption_request(&req).unwrap();
+        sm.encryption_changed(true);
+        assert_eq!(sm.peer().unwrap().key_size, 10);
+    }
+
+    #[test]
+    fn reencryption() {
+        let security = LegacyPairing::new(PairingConfig::new(), SoftAes, TestRng::counting(0))
+            .with_bond_store(BondManager::<2>::new());
+        let mut sm = SecurityManager::new(security);
+        sm.connected(LOCAL, peer());
+        let pres = exchange_features(&mut sm);
+
+        let mrand = 0x55;
+        let mconfirm = toolbox::c1(&mut SoftAes, 0, mrand, &PREQ, &pres, &PEER, &LOCAL);
+        sm.handle(Command::PairingConfirm(mconfirm), &[]).unwrap();
+        sm.handle(Command::PairingRandom(mrand), &[]).unwrap();
+
+        // The link is first encrypted with the STK
+        let req = EncryptionRequest {
+            rand: 0,
+            ediv: 0,
+            skd_m: 0xACBDCEDFE0F10213,
+            iv_m: 0xBADCAB24,
+        };
+        let stk = sm.stk().unwrap();
+        let key = sm.encryption_request(&req).unwrap();
+        let skd = u128::from(key.skd_s) << 64 | u128::from(req.skd_m);
+        assert_eq!(key.key, toolbox::e(&mut SoftAes, stk, skd));
+        assert_eq!(key.iv, u64::from(key.iv_s) << 32 | 0xBADCAB24);
+        assert_eq!(sm.peer().unwrap().security, LinkSecurity::Unencrypted);
+        sm.encryption_changed(true);
+        assert_eq!(sm.peer().unwrap().security, LinkSecurity::Encrypted);
+
+        while sm.next_key().is_some() {}
+        sm.finish_if_done();
+        let ltk = sm.bond().unwrap().local_ltk.unwrap();
+        assert_eq!(sm.security.bond_store().bonds().len(), 1);
+
+        // When reconnecting, the master requests encryption with the LTK
+        sm.connected(LOCAL, peer());
+        assert_eq!(sm.peer().unwrap().security, LinkSecurity::Unencrypted);
+        let req = EncryptionRequest {
+            rand: ltk.rand,
+            ediv: ltk.ediv,
+            ..req
+        };
+        let key = sm.encryption_request(&req).unwrap();
+        let skd = u128::from(key.skd_s) << 64 | u128::from(req.skd_m);
+        assert_eq!(key.key, toolbox::e(&mut SoftAes, ltk.ltk, skd));
+        sm.encryption_changed(true);
+        assert_eq!(sm.peer().unwrap().security, LinkSecurity::Encrypted);
+        sm.encryption_changed(false);
+        assert_eq!(sm.peer().unwrap().security, LinkSecurity::Unencrypted);
+
+        // Unknown keys are rejected, and so is the STK outside of pairing
+        let req = EncryptionRequest {
+            rand: ltk.rand.wrapping_add(1),
+            ..req
+        };
+        assert!(sm.encryption_request(&req).is_none());
+        let req = EncryptionRequest {
+            rand: 0,
+            ediv: 0,
+            ..req
+        };
+        assert!(sm.encryption_request(&req).is_none());
+    }
+
+    #[test]
+    fn no_security_rejects_pairing() {
+        let mut sm = SecurityManager::no_security();
+        sm.connected(LOCAL, peer());
+        let cmd = Command::from_bytes(&mut ByteReader::new(&PREQ)).unwrap();
+        match sm.handle(cmd, &PREQ) {
+            Some(Command::PairingFailed(Reason::PairingNotSupported)) => {}
+            rsp => panic!("unexpected response {:?}", rsp),
+        }
+    }
+}
@@ -0,0 +1,273 @@
+//! Out-of-Band (OOB) pairing data, as exchanged via NFC.
+//!
+//! For *"tap-to-pair"*, a device exposes its pairing data on an NFC tag, or reads the data of the
+//! peer from one. The data is carried in an NDEF record of type [`NDEF_MIME_TYPE`], whose payload
+//! is a sequence of AD structures: The *LE Bluetooth Device Address* and *LE Role*, optionally
+//! followed by the *Security Manager TK Value* used by *LE Legacy Pairing* and the confirmation and
+//! random values used by *LE Secure Connections*. [`OobData`] encodes and decodes this payload.
+//!
+//! The Security Manager obtains the TK from the `PairingDelegate::oob_data` callback. When the TK
+//! was generated locally (ie. this device is the NFC tag), the delegate returns it for any peer
+//! that pairs while the tag is readable. When the data was read from the peer, `OobData::tk_for`
+//! only returns the TK when pairing with the device that published it.
+//!
+//! Rubble only implements *LE Legacy Pairing*, so the *LE Secure Connections* values are only
+//! carried along and are not used for pairing.
+//!
+//! [`NDEF_MIME_TYPE`]: constant.NDEF_MIME_TYPE.html
+//! [`OobData`]: struct.OobData.html
+
+use crate::link::ad_structure::{parse_ad_structures, AdStructure, LeRole, Type};
+use crate::link::{AddressKind, DeviceAddress, Peer};
+use crate::{bytes::*, Error};
+use core::convert::TryFrom;
+use core::fmt;
+use rand_core::{CryptoRng, RngCore};
+
+/// MIME type of the NFC NDEF record carrying LE OOB data.
+pub const NDEF_MIME_TYPE: &str = "application/vnd.bluetooth.le.oob";
+
+/// Maximum length of encoded `OobData`, in Bytes.
+///
+/// This is the size of the payload when all fields are present.
+pub const MAX_OOB_DATA_LEN: usize = 9 + 3 + 3 * 18;
+
+/// Values used for *LE Secure Connections* OOB pairing.
+#[derive(Copy, Clone, PartialEq, Eq)]
+pub struct SecureConnectionsOob {
+    /// The confirmation value `Ca` or `Cb`, computed with the `f4` function.
+    pub confirm: u128,
+    /// The random value `ra` or `rb`.
+    pub random: u128,
+}
+
+/// The LE OOB pairing data of a device.
+#[derive(Copy, Clone, PartialEq, Eq)]
+pub struct OobData {
+    /// The address the device advertises with.
+    pub address: DeviceAddress,
+    /// The LE roles supported by the device.
+    pub role: LeRole,
+    /// The *LE Legacy Pairing* Temporary Key (TK).
+    pub tk: Option<u128>,
+    /// Confirmation and random values for *LE Secure Connections*.
+    pub secure_connections: Option<SecureConnectionsOob>,
+}
+
+impl OobData {
+    /// Creates OOB data for the local device with a newly generated TK.
+    ///
+    /// A new TK should be generated for every pairing, and the data should only be readable for as
+    /// long as pairing is allowed.
+    pub fn generate<R: RngCore + CryptoRng>(
+        address: DeviceAddress,
+        role: LeRole,
+        rng: &mut R,
+    ) -> Self {
+        let mut tk = [0; 16];
+        rng.fill_bytes(&mut tk);
+        Self {
+            address,
+            role,
+            tk: Some(u128::from_le_bytes(tk)),
+            secure_connections: None,
+        }
+    }
+
+    /// Returns the TK to use when pairing with `peer`, if this OOB data was published by it.
+    ///
+    /// This should be used with data read from the peer, in `PairingDelegate::oob_data`.
+    pub fn tk_for(&self, peer: &Peer) -> Option<u128> {
+        if peer.address == self.address {
+            self.tk
+        } else {
+            None
+        }
+    }
+}
+
+impl fmt::Debug for OobData {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // Don't leak the key into logs
+        f.debug_struct("OobData")
+            .field("address", &self.address)
+            .field("role", &self.role)
+            .field("tk", &self.tk.is_some())
+            .field("secure_connections", &self.secure_connections.is_some())
+            .finish()
+    }
+}
+
+impl defmt::Format for OobData {
+    fn format(&self, fmt: defmt::Formatter<'_>) {
+        defmt::write!(
+            fmt,
+            "OobData {{ address: {:?}, role: {:?}, tk: {=bool}, secure_connections: {=bool} }}",
+            self.address,
+            self.role,
+            self.tk.is_some(),
+            self.secure_connections.is_some()
+        );
+    }
+}
+
+impl ToBytes for OobData {
+    fn to_bytes(&self, writer: &mut ByteWriter<'_>) -> Result<(), Error> {
+        let mut address = [0; 7];
+        address[..6].copy_from_slice(self.address.raw());
+        address[6] = self.address.is_random() as u8;
+        AdStructure::Unknown {
+            ty: Type::LE_BLUETOOTH_DEVICE_ADDRESS,
+            data: &address,
+        }
+        .to_bytes(writer)?;
+        AdStructure::LeRole(self.role).to_bytes(writer)?;
+
+        if let Some(tk) = self.tk {
+            AdStructure::Unknown {
+                ty: Type::SECURITY_MANAGER_TK_VALUE,
+                data: &tk.to_le_bytes(),
+            }
+            .to_bytes(writer)?;
+        }
+        if let Some(sc) = &self.secure_connections {
+            AdStructure::Unknown {
+                ty: Type::LE_SECURE_CONNECTIONS_CONFIRMATION_VALUE,
+                data: &sc.confirm.to_le_bytes(),
+            }
+            .to_bytes(writer)?;
+            AdStructure::Unknown {
+                ty: Type::LE_SECURE_CONNECTIONS_RANDOM_VALUE,
+                data: &sc.random.to_le_bytes(),
+            }
+            .to_bytes(writer)?;
+        }
+        Ok(())
+    }
+}
+
+impl<'a> FromBytes<'a> for OobData {
+    /// Decodes OOB data from the payload of an NDEF record.
+    ///
+    /// All remaining Bytes are consumed. AD structures that aren't part of the OOB data (eg. the
+    /// device name) are ignored.
+    fn from_bytes(bytes: &mut ByteReader<'a>) -> Result<Self, Error> {
+        let mut address = None;
+        let mut role = None;
+        let mut tk = None;
+        let mut confirm = None;
+        let mut random = None;
+        for ad in parse_ad_structures(bytes.read_rest()) {
+            match ad? {
+                AdStructure::LeRole(r) => role = Some(r),
+                AdStructure::Unknown { ty, data } => match ty {
+                    Type::LE_BLUETOOTH_DEVICE_ADDRESS => {
+                        if data.len() != 7 {
+                            return Err(Error::InvalidLength);
+                        }
+                        let mut raw = [0; 6];
+                        raw.copy_from_slice(&data[..6]);
+                        let kind = if data[6] & 1 == 0 {
+                            AddressKind::Public
+                        } else {
+                            AddressKind::Random
+                        };
+                        address = Some(DeviceAddress::new(raw, kind));
+                    }
+                    Type::SECURITY_MANAGER_TK_VALUE => tk = Some(key(data)?),
+                    Type::LE_SECURE_CONNECTIONS_CONFIRMATION_VALUE => confirm = Some(key(data)?),
+                    Type::LE_SECURE_CONNECTIONS_RANDOM_VALUE => random = Some(key(data)?),
+                    _ => {}
+                },
+                _ => {}
+            }
+        }
+
+        let secure_connections = match (confirm, random) {
+            (Some(confirm), Some(random)) => Some(SecureConnectionsOob { confirm, random }),
+            (None, None) => None,
+            _ => return Err(Error::InvalidValue),
+        };
+        Ok(Self {
+            address: address.ok_or(Error::InvalidValue)?,
+            role: role.ok_or(Error::InvalidValue)?,
+            tk,
+            secure_connections,
+        })
+    }
+}
+
+/// Decodes a 128-bit value sent LSB first.
+fn key(data: &[u8]) -> Result<u128, Error> {
+    let bytes = <[u8; 16]>::try_from(data).map_err(|_| Error::InvalidLength)?;
+    Ok(u128::from_le_bytes(bytes))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::link::ConnectionHandle;
+
+    const ADDRESS: DeviceAddress =
+        DeviceAddress::new([0x01, 0x02, 0x03, 0x04, 0x05, 0xC6], AddressKind::Random);
+
+    fn encode(oob: &OobData, buf: &mut [u8; MAX_OOB_DATA_LEN]) -> usize {
+        let mut writer = ByteWriter::new(buf);
+        oob.to_bytes(&mut writer).unwrap();
+        MAX_OOB_DATA_LEN - writer.space_left()
+    }
+
+    #[test]
+    fn roundtrip() {
+        let mut oob = OobData {
+            address: ADDRESS,
+            role: LeRole::PeripheralOnly,
+            tk: Some(0x00112233_44556677_8899aabb_ccddeeff),
+            secure_connections: None,
+        };
+        let mut buf = [0; MAX_OOB_DATA_LEN];
+        let len = encode(&oob, &mut buf);
+        assert_eq!(
+            buf[..12],
+            [0x08, 0x1B, 0x01, 0x02, 0x03, 0x04, 0x05, 0xC6, 0x01, 0x02, 0x1C, 0x00]
+        );
+        assert_eq!(buf[12..15], [0x11, 0x10, 0xff]);
+        assert_eq!(len, 30);
+        assert_eq!(
+            OobData::from_bytes(&mut ByteReader::new(&buf[..len])),
+            Ok(oob)
+        );
+
+        oob.secure_connections = Some(SecureConnectionsOob {
+            confirm: 1,
+            random: 2,
+        });
+        let len = encode(&oob, &mut buf);
+        assert_eq!(len, MAX_OOB_DATA_LEN);
+        assert_eq!(
+            OobData::from_bytes(&mut ByteReader::new(&buf[..len])),
+            Ok(oob)
+        );
+
+        // The address and role are mandatory
+        assert_eq!(
+            OobData::from_bytes(&mut ByteReader::new(&buf[9..len])),
+            Err(Error::InvalidValue)
+        );
+    }
+
+    #[test]
+    fn tk_for_peer() {
+        let oob = OobData {
+            address: ADDRESS,
+            role: LeRole::CentralOnly,
+            tk: Some(1234),
+            secure_connections: None,
+        };
+        let peer = Peer::new(ConnectionHandle::from_raw(0), ADDRESS);
+        assert_eq!(oob.tk_for(&peer), Some(1234));
+        let other = DeviceAddress::new([0x01, 0x02, 0x03, 0x04, 0x05, 0x06], AddressKind::Public);
+        let peer = Peer::new(ConnectionHandle::from_raw(0), other);
+        assert_eq!(oob.tk_for(&peer), None);
+    }
+}
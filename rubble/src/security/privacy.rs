@@ -0,0 +1,88 @@
+//! Resolvable Private Addresses (RPAs).
+//!
+//! A Resolvable Private Address is a random device address that changes periodically, but can be
+//! recognized by bonded peers that know the device's **I**dentity **R**esolving **K**ey (IRK).
+//! This prevents third parties from tracking a device by its address.
+//!
+//! An RPA consists of a 24-bit random part `prand` (whose two most significant bits are `0b01`)
+//! and a 24-bit `hash` computed from `prand` and the IRK. The address itself is represented by
+//! [`RpaAddress`]; this module provides a generator for the local device's addresses.
+//!
+//! [`RpaAddress`]: ../../link/struct.RpaAddress.html
+
+use super::AesProvider;
+use crate::config::Config;
+use crate::link::{DeviceAddress, RpaAddress};
+use core::convert::TryFrom;
+use rand_core::{CryptoRng, RngCore};
+
+/// Generates Resolvable Private Addresses from the local IRK.
+///
+/// The random number generator used here is usually the one defined by [`Config::Rng`], in which
+/// case the type can be named via [`ConfRpaGenerator`].
+///
+/// [`Config::Rng`]: ../../config/trait.Config.html#associatedtype.Rng
+/// [`ConfRpaGenerator`]: type.ConfRpaGenerator.html
+pub struct RpaGenerator<A: AesProvider, R: RngCore + CryptoRng> {
+    irk: u128,
+    aes: A,
+    rng: R,
+}
+
+/// [`RpaGenerator`] using the random number generator of stack configuration `C`.
+///
+/// [`RpaGenerator`]: struct.RpaGenerator.html
+pub type ConfRpaGenerator<C, A> = RpaGenerator<A, <C as Config>::Rng>;
+
+impl<A: AesProvider, R: RngCore + CryptoRng> RpaGenerator<A, R> {
+    /// Creates a new RPA generator using the local Identity Resolving Key `irk`.
+    pub fn new(irk: u128, aes: A, rng: R) -> Self {
+        Self { irk, aes, rng }
+    }
+
+    /// Generates a new Resolvable Private Address.
+    ///
+    /// The specification recommends generating a new address every 15 minutes.
+    pub fn generate(&mut self) -> DeviceAddress {
+        RpaAddress::generate(self.irk, &mut self.aes, &mut self.rng).into()
+    }
+
+    /// Returns whether `address` is a Resolvable Private Address generated from the local IRK.
+    pub fn resolves(&mut self, address: &DeviceAddress) -> bool {
+        resolve(&mut self.aes, self.irk, address)
+    }
+}
+
+/// Returns whether `address` is a Resolvable Private Address generated from `irk`.
+///
+/// This can be used to recognize a bonded peer using the IRK it distributed during pairing.
+pub fn resolve(aes: &mut dyn AesProvider, irk: u128, address: &DeviceAddress) -> bool {
+    match RpaAddress::try_from(*address) {
+        Ok(rpa) => rpa.resolves(aes, irk),
+        Err(_) => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::link::AddressKind;
+    use crate::security::SoftAes;
+    use crate::test_rng::TestRng;
+
+    const IRK: u128 = 0xec0234a357c8ad05341010a60a397d9b;
+
+    #[test]
+    fn generate_and_resolve() {
+        let mut gen = RpaGenerator::new(IRK, SoftAes, TestRng::fixed(0x30_8194));
+        let addr = gen.generate();
+        assert!(addr.is_random());
+        // Spec sample data (Vol 3, Part H, D.7): prand = 0x708194, hash = 0x0dfbaa
+        assert_eq!(addr.raw(), &[0xaa, 0xfb, 0x0d, 0x94, 0x81, 0x70]);
+        assert!(gen.resolves(&addr));
+        assert!(!resolve(&mut SoftAes, IRK ^ 1, &addr));
+
+        let public = DeviceAddress::new(*addr.raw(), AddressKind::Public);
+        assert!(!gen.resolves(&public));
+    }
+}
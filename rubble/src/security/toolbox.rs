@@ -0,0 +1,203 @@
+//! The Security Manager's cryptographic toolbox.
+//!
+//! All security functions used by pairing are built on top of the AES-128 block cipher, which is
+//! abstracted by the [`AesProvider`] trait. This allows using hardware AES accelerators instead of
+//! the (slow, but always available) software implementation [`SoftAes`].
+//!
+//! The specification writes all values in these functions with the most significant octet first,
+//! while they are transmitted least significant octet first. To avoid confusion, 128-bit values
+//! are represented as `u128` here, and are converted to the right byte order at the edges.
+//!
+//! [`AesProvider`]: trait.AesProvider.html
+//! [`SoftAes`]: struct.SoftAes.html
+
+use crate::link::DeviceAddress;
+
+/// Trait for providers of the AES-128 block cipher.
+pub trait AesProvider {
+    /// Encrypts a single 128-bit `block` in-place using the 128-bit `key`.
+    ///
+    /// Both `key` and `block` are stored most significant octet first (this is the byte order used
+    /// by FIPS-197 and most hardware implementations).
+    fn encrypt_block(&mut self, key: &[u8; 16], block: &mut [u8; 16]);
+}
+
+impl<A: AesProvider + ?Sized> AesProvider for &'_ mut A {
+    fn encrypt_block(&mut self, key: &[u8; 16], block: &mut [u8; 16]) {
+        (**self).encrypt_block(key, block)
+    }
+}
+
+/// A portable software implementation of AES-128.
+///
+/// This implementation is table-based and not hardened against timing side channels. Prefer a
+/// hardware-backed `AesProvider` where one is available.
+#[derive(Debug, Default, Copy, Clone)]
+pub struct SoftAes;
+
+impl SoftAes {
+    /// Creates a new software AES provider.
+    pub fn new() -> Self {
+        SoftAes
+    }
+}
+
+static SBOX: [u8; 256] = [
+    0x63, 0x7c, 0x77, 0x7b, 0xf2, 0x6b, 0x6f, 0xc5, 0x30, 0x01, 0x67, 0x2b, 0xfe, 0xd7, 0xab, 0x76,
+    0xca, 0x82, 0xc9, 0x7d, 0xfa, 0x59, 0x47, 0xf0, 0xad, 0xd4, 0xa2, 0xaf, 0x9c, 0xa4, 0x72, 0xc0,
+    0xb7, 0xfd, 0x93, 0x26, 0x36, 0x3f, 0xf7, 0xcc, 0x34, 0xa5, 0xe5, 0xf1, 0x71, 0xd8, 0x31, 0x15,
+    0x04, 0xc7, 0x23, 0xc3, 0x18, 0x96, 0x05, 0x9a, 0x07, 0x12, 0x80, 0xe2, 0xeb, 0x27, 0xb2, 0x75,
+    0x09, 0x83, 0x2c, 0x1a, 0x1b, 0x6e, 0x5a, 0xa0, 0x52, 0x3b, 0xd6, 0xb3, 0x29, 0xe3, 0x2f, 0x84,
+    0x53, 0xd1, 0x00, 0xed, 0x20, 0xfc, 0xb1, 0x5b, 0x6a, 0xcb, 0xbe, 0x39, 0x4a, 0x4c, 0x58, 0xcf,
+    0xd0, 0xef, 0xaa, 0xfb, 0x43, 0x4d, 0x33, 0x85, 0x45, 0xf9, 0x02, 0x7f, 0x50, 0x3c, 0x9f, 0xa8,
+    0x51, 0xa3, 0x40, 0x8f, 0x92, 0x9d, 0x38, 0xf5, 0xbc, 0xb6, 0xda, 0x21, 0x10, 0xff, 0xf3, 0xd2,
+    0xcd, 0x0c, 0x13, 0xec, 0x5f, 0x97, 0x44, 0x17, 0xc4, 0xa7, 0x7e, 0x3d, 0x64, 0x5d, 0x19, 0x73,
+    0x60, 0x81, 0x4f, 0xdc, 0x22, 0x2a, 0x90, 0x88, 0x46, 0xee, 0xb8, 0x14, 0xde, 0x5e, 0x0b, 0xdb,
+    0xe0, 0x32, 0x3a, 0x0a, 0x49, 0x06, 0x24, 0x5c, 0xc2, 0xd3, 0xac, 0x62, 0x91, 0x95, 0xe4, 0x79,
+    0xe7, 0xc8, 0x37, 0x6d, 0x8d, 0xd5, 0x4e, 0xa9, 0x6c, 0x56, 0xf4, 0xea, 0x65, 0x7a, 0xae, 0x08,
+    0xba, 0x78, 0x25, 0x2e, 0x1c, 0xa6, 0xb4, 0xc6, 0xe8, 0xdd, 0x74, 0x1f, 0x4b, 0xbd, 0x8b, 0x8a,
+    0x70, 0x3e, 0xb5, 0x66, 0x48, 0x03, 0xf6, 0x0e, 0x61, 0x35, 0x57, 0xb9, 0x86, 0xc1, 0x1d, 0x9e,
+    0xe1, 0xf8, 0x98, 0x11, 0x69, 0xd9, 0x8e, 0x94, 0x9b, 0x1e, 0x87, 0xe9, 0xce, 0x55, 0x28, 0xdf,
+    0x8c, 0xa1, 0x89, 0x0d, 0xbf, 0xe6, 0x42, 0x68, 0x41, 0x99, 0x2d, 0x0f, 0xb0, 0x54, 0xbb, 0x16,
+];
+
+static RCON: [u8; 10] = [0x01, 0x02, 0x04, 0x08, 0x10, 0x20, 0x40, 0x80, 0x1b, 0x36];
+
+/// Multiplication by `x` in GF(2^8).
+fn xtime(x: u8) -> u8 {
+    (x << 1) ^ ((x >> 7) * 0x1b)
+}
+
+impl AesProvider for SoftAes {
+    fn encrypt_block(&mut self, key: &[u8; 16], block: &mut [u8; 16]) {
+        // The round keys are computed on the fly, so this only needs 16 Bytes for the key schedule.
+        let mut round_key = *key;
+        add_round_key(block, &round_key);
+
+        for (round, rcon) in RCON.iter().enumerate() {
+            // Expand the next round key.
+            let mut temp = [
+                SBOX[usize::from(round_key[13])] ^ rcon,
+                SBOX[usize::from(round_key[14])],
+                SBOX[usize::from(round_key[15])],
+                SBOX[usize::from(round_key[12])],
+            ];
+            for word in round_key.chunks_exact_mut(4) {
+                for (b, t) in word.iter_mut().zip(temp.iter_mut()) {
+                    *b ^= *t;
+                    *t = *b;
+                }
+            }
+
+            // SubBytes and ShiftRows
+            let state = *block;
+            for col in 0..4 {
+                for row in 0..4 {
+                    block[col * 4 + row] = SBOX[usize::from(state[((col + row) % 4) * 4 + row])];
+                }
+            }
+
+            // MixColumns (skipped in the last round)
+            if round != RCON.len() - 1 {
+                for col in block.chunks_exact_mut(4) {
+                    let a = [col[0], col[1], col[2], col[3]];
+                    let t = a[0] ^ a[1] ^ a[2] ^ a[3];
+                    for i in 0..4 {
+                        col[i] = a[i] ^ t ^ xtime(a[i] ^ a[(i + 1) % 4]);
+                    }
+                }
+            }
+
+            add_round_key(block, &round_key);
+        }
+    }
+}
+
+fn add_round_key(block: &mut [u8; 16], round_key: &[u8; 16]) {
+    for (b, k) in block.iter_mut().zip(round_key) {
+        *b ^= k;
+    }
+}
+
+/// The security function `e`: Encrypts `plaintext` with `key` using AES-128.
+pub(crate) fn e(aes: &mut dyn AesProvider, key: u128, plaintext: u128) -> u128 {
+    let mut block = plaintext.to_be_bytes();
+    aes.encrypt_block(&key.to_be_bytes(), &mut block);
+    u128::from_be_bytes(block)
+}
+
+/// Converts up to 16 Bytes of little-endian data (as sent over the air) to an integer.
+fn le_int(bytes: &[u8]) -> u128 {
+    let mut buf = [0; 16];
+    buf[..bytes.len()].copy_from_slice(bytes);
+    u128::from_le_bytes(buf)
+}
+
+/// The confirm value generation function `c1` used by *LE Legacy Pairing*.
+///
+/// # Parameters
+///
+/// * **`k`**: The Temporary Key (TK).
+/// * **`r`**: The random value (`Mrand` or `Srand`).
+/// * **`preq`**: The *Pairing Request* command, as sent over the air (including the opcode).
+/// * **`pres`**: The *Pairing Response* command, as sent over the air (including the opcode).
+/// * **`ia`**: The address of the initiating device (master).
+/// * **`ra`**: The address of the responding device (slave).
+pub(crate) fn c1(
+    aes: &mut dyn AesProvider,
+    k: u128,
+    r: u128,
+    preq: &[u8; 7],
+    pres: &[u8; 7],
+    ia: &DeviceAddress,
+    ra: &DeviceAddress,
+) -> u128 {
+    let iat = u128::from(ia.is_random());
+    let rat = u128::from(ra.is_random());
+    let p1 = le_int(pres) << 72 | le_int(preq) << 16 | rat << 8 | iat;
+    let p2 = le_int(ia.raw()) << 48 | le_int(ra.raw());
+
+    let tmp = e(aes, k, r ^ p1);
+    e(aes, k, tmp ^ p2)
+}
+
+/// The key generation function `s1` used to derive the Short Term Key (STK) during *LE Legacy
+/// Pairing*.
+///
+/// `r1` is the slave's random value (`Srand`) and `r2` the master's (`Mrand`). Only the least
+/// significant 64 bits of each are used.
+pub(crate) fn s1(aes: &mut dyn AesProvider, k: u128, r1: u128, r2: u128) -> u128 {
+    const LOW: u128 = 0xFFFF_FFFF_FFFF_FFFF;
+    e(aes, k, (r1 & LOW) << 64 | (r2 & LOW))
+}
+
+/// The random address hash function `ah` used to generate and resolve Resolvable Private
+/// Addresses.
+///
+/// `k` is the Identity Resolving Key (IRK) and `r` a 24-bit value. Only the least significant 24
+/// bits of `r` and of the result are used.
+pub(crate) fn ah(aes: &mut dyn AesProvider, k: u128, r: u32) -> u32 {
+    (e(aes, k, u128::from(r & 0xFF_FFFF)) & 0xFF_FFFF) as u32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn aes_fips197() {
+        let key = [
+            0x00, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08, 0x09, 0x0a, 0x0b, 0x0c, 0x0d,
+            0x0e, 0x0f,
+        ];
+        let mut block = [
+            0x00, 0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77, 0x88, 0x99, 0xaa, 0xbb, 0xcc, 0xdd,
+            0xee, 0xff,
+        ];
+        SoftAes.encrypt_block(&key, &mut block);
+        assert_eq!(
+            u128::from_be_bytes(block),
+            0x69c4e0d86a7b0430d8cdb78070b4c55a
+        );
+    }
+}
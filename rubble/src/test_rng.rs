@@ -0,0 +1,50 @@
+//! A deterministic random number generator shared by the unit tests.
+
+use rand_core::{impls, CryptoRng, Error, RngCore};
+
+/// Deterministic "RNG" that adds a fixed step to its state for every generated `u32`.
+///
+/// Do not do this outside of tests.
+pub struct TestRng {
+    state: u32,
+    step: u32,
+}
+
+impl TestRng {
+    /// Creates an RNG returning `start + step`, `start + 2 * step`, and so on.
+    pub fn new(start: u32, step: u32) -> Self {
+        Self { state: start, step }
+    }
+
+    /// Creates an RNG returning consecutive values, starting at `start + 1`.
+    pub fn counting(start: u32) -> Self {
+        Self::new(start, 1)
+    }
+
+    /// Creates an RNG that always returns `value`.
+    pub fn fixed(value: u32) -> Self {
+        Self::new(value, 0)
+    }
+}
+
+impl RngCore for TestRng {
+    fn next_u32(&mut self) -> u32 {
+        self.state = self.state.wrapping_add(self.step);
+        self.state
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        impls::next_u64_via_u32(self)
+    }
+
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        impls::fill_bytes_via_next(self, dest)
+    }
+
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), Error> {
+        self.fill_bytes(dest);
+        Ok(())
+    }
+}
+
+impl CryptoRng for TestRng {}
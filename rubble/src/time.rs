@@ -2,7 +2,28 @@
 //!
 //! These APIs are made for the BLE stack and are not meant to be general-purpose. The APIs here
 //! have microsecond resolution and use 32-bit arithmetic wherever possible.
-
+//!
+//! `Instant`s wrap around roughly every 71 minutes. Applications that need to keep track of time
+//! over longer periods can use [`ExtendedInstant`]s, which count the wraparounds using a
+//! [`Rollover`] counter.
+//!
+//! Code that only needs to read the current time, like loggers or statistics, should take a
+//! [`StampSource`] instead of a [`Timer`]. This allows passing a read-only handle to the
+//! Link-Layer's timer, so the timer itself doesn't have to be borrowed. [`ExtendedStampSource`]
+//! turns any `StampSource` into one providing `ExtendedInstant`s.
+//!
+//! With the `fugit` Cargo feature, conversions from and to the corresponding `fugit` types are
+//! provided.
+//!
+//! [`ExtendedInstant`]: struct.ExtendedInstant.html
+//! [`Rollover`]: struct.Rollover.html
+//! [`StampSource`]: trait.StampSource.html
+//! [`Timer`]: trait.Timer.html
+//! [`ExtendedStampSource`]: struct.ExtendedStampSource.html
+
+use crate::Error;
+use core::cell::Cell;
+use core::convert::TryFrom;
 use core::fmt;
 use core::ops::{Add, AddAssign, Sub, SubAssign};
 
@@ -11,6 +32,7 @@ use core::ops::{Add, AddAssign, Sub, SubAssign};
 /// This can represent a maximum duration of about 1 hour. Overflows will result in a panic, but
 /// shouldn't happen since the BLE stack doesn't deal with durations that large.
 #[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Duration(u32);
 
 impl Duration {
@@ -18,7 +40,7 @@ impl Duration {
     pub const T_IFS: Self = Duration(150);
 
     /// Creates a [`Duration`] from a number of microseconds.
-    pub fn from_micros(micros: u32) -> Self {
+    pub const fn from_micros(micros: u32) -> Self {
         Duration(micros)
     }
 
@@ -51,6 +73,69 @@ impl Duration {
     pub fn subsec_micros(&self) -> u32 {
         self.0 % 1_000_000
     }
+
+    /// Adds `rhs` to `self`, returning `None` on overflow.
+    pub fn checked_add(self, rhs: Self) -> Option<Self> {
+        self.0.checked_add(rhs.0).map(Duration)
+    }
+
+    /// Subtracts `rhs` from `self`, returning `None` if `rhs` is longer than `self`.
+    pub fn checked_sub(self, rhs: Self) -> Option<Self> {
+        self.0.checked_sub(rhs.0).map(Duration)
+    }
+
+    /// Multiplies `self` by `rhs`, returning `None` on overflow.
+    pub fn checked_mul(self, rhs: u32) -> Option<Self> {
+        self.0.checked_mul(rhs).map(Duration)
+    }
+
+    /// Adds `rhs` to `self`, saturating at the maximum representable duration.
+    pub fn saturating_add(self, rhs: Self) -> Self {
+        Duration(self.0.saturating_add(rhs.0))
+    }
+
+    /// Subtracts `rhs` from `self`, saturating at zero.
+    pub fn saturating_sub(self, rhs: Self) -> Self {
+        Duration(self.0.saturating_sub(rhs.0))
+    }
+
+    /// Multiplies `self` by `rhs`, saturating at the maximum representable duration.
+    pub fn saturating_mul(self, rhs: u32) -> Self {
+        Duration(self.0.saturating_mul(rhs))
+    }
+}
+
+impl From<Duration> for core::time::Duration {
+    fn from(d: Duration) -> Self {
+        core::time::Duration::from_micros(d.0.into())
+    }
+}
+
+/// Converts a `core::time::Duration`, truncating it to whole microseconds.
+///
+/// Returns `Error::InvalidValue` if the duration doesn't fit in a `Duration`.
+impl TryFrom<core::time::Duration> for Duration {
+    type Error = Error;
+
+    fn try_from(d: core::time::Duration) -> Result<Self, Error> {
+        u32::try_from(d.as_micros())
+            .map(Duration)
+            .map_err(|_| Error::InvalidValue)
+    }
+}
+
+#[cfg(feature = "fugit")]
+impl From<Duration> for fugit::MicrosDurationU32 {
+    fn from(d: Duration) -> Self {
+        fugit::MicrosDurationU32::from_ticks(d.0)
+    }
+}
+
+#[cfg(feature = "fugit")]
+impl From<fugit::MicrosDurationU32> for Duration {
+    fn from(d: fugit::MicrosDurationU32) -> Self {
+        Duration(d.ticks())
+    }
 }
 
 impl Add for Duration {
@@ -144,6 +229,7 @@ impl defmt::Format for Duration {
 /// them in operations causes unspecified results. [`Duration`]s are independent of the [`Timer`]
 /// implementation and thus can be mixed freely.
 #[derive(Copy, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Instant(u32);
 
 impl Instant {
@@ -193,6 +279,33 @@ impl Instant {
 
         Duration(micros_passed)
     }
+
+    /// Calculates the duration of time that has passed between `earlier` and `self`.
+    ///
+    /// Returns `None` if the result exceeds [`Instant::MAX_TIME_BETWEEN`], which usually means that
+    /// `earlier` is actually later than `self`.
+    pub fn checked_duration_since(&self, earlier: Instant) -> Option<Duration> {
+        let micros_passed = self.0.wrapping_sub(earlier.0);
+        if micros_passed <= Self::MAX_TIME_BETWEEN.0 {
+            Some(Duration(micros_passed))
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(feature = "fugit")]
+impl From<Instant> for fugit::TimerInstantU32<1_000_000> {
+    fn from(i: Instant) -> Self {
+        fugit::TimerInstantU32::from_ticks(i.0)
+    }
+}
+
+#[cfg(feature = "fugit")]
+impl From<fugit::TimerInstantU32<1_000_000>> for Instant {
+    fn from(i: fugit::TimerInstantU32<1_000_000>) -> Self {
+        Instant(i.ticks())
+    }
 }
 
 /// [`Instant`]s can be subtracted, which computes the [`Duration`] between the rhs and lhs using
@@ -267,16 +380,380 @@ impl fmt::Debug for Instant {
     }
 }
 
+impl defmt::Format for Instant {
+    fn format(&self, fmt: defmt::Formatter<'_>) {
+        defmt::write!(fmt, "{=u32:µs}s", self.0);
+    }
+}
+
+/// A point in time with microsecond resolution that does not wrap around (in practice).
+///
+/// `ExtendedInstant`s are created by a [`Rollover`] counter from the `Instant`s of a [`Timer`]. As
+/// with `Instant`s, values obtained from different timers or counters must not be mixed.
+///
+/// [`Rollover`]: struct.Rollover.html
+/// [`Timer`]: trait.Timer.html
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ExtendedInstant(u64);
+
+impl ExtendedInstant {
+    /// Creates an `ExtendedInstant` from microseconds since the reference point of the [`Timer`].
+    ///
+    /// [`Timer`]: trait.Timer.html
+    pub const fn from_micros(micros: u64) -> Self {
+        ExtendedInstant(micros)
+    }
+
+    /// Returns the number of microseconds since the reference point of the [`Timer`].
+    ///
+    /// [`Timer`]: trait.Timer.html
+    pub fn as_micros(&self) -> u64 {
+        self.0
+    }
+
+    /// Returns the `Instant` corresponding to `self`.
+    pub fn instant(&self) -> Instant {
+        Instant(self.0 as u32)
+    }
+
+    /// Calculates the duration of time that has passed between `earlier` and `self`.
+    ///
+    /// Returns `None` if `earlier` is later than `self`, or if the result doesn't fit in a
+    /// `Duration`.
+    pub fn checked_duration_since(&self, earlier: ExtendedInstant) -> Option<Duration> {
+        let micros = self.0.checked_sub(earlier.0)?;
+        u32::try_from(micros).ok().map(Duration)
+    }
+
+    /// Adds `d` to `self`, returning `None` on overflow.
+    pub fn checked_add(self, d: Duration) -> Option<Self> {
+        self.0.checked_add(d.0.into()).map(ExtendedInstant)
+    }
+
+    /// Subtracts `d` from `self`, returning `None` if the result would precede the reference
+    /// point.
+    pub fn checked_sub(self, d: Duration) -> Option<Self> {
+        self.0.checked_sub(d.0.into()).map(ExtendedInstant)
+    }
+}
+
+impl Add<Duration> for ExtendedInstant {
+    type Output = Self;
+
+    fn add(self, d: Duration) -> Self {
+        self.checked_add(d).expect("instant overflow")
+    }
+}
+
+impl Sub<Duration> for ExtendedInstant {
+    type Output = Self;
+
+    fn sub(self, d: Duration) -> Self {
+        self.checked_sub(d).expect("instant underflow")
+    }
+}
+
+impl fmt::Debug for ExtendedInstant {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (secs, subsec_micros) = (self.0 / 1_000_000, self.0 % 1_000_000);
+        write!(f, "{}.{:06}s", secs, subsec_micros)
+    }
+}
+
+impl defmt::Format for ExtendedInstant {
+    fn format(&self, fmt: defmt::Formatter<'_>) {
+        defmt::write!(fmt, "{=u64:µs}s", self.0);
+    }
+}
+
+#[cfg(feature = "fugit")]
+impl From<ExtendedInstant> for fugit::TimerInstantU64<1_000_000> {
+    fn from(i: ExtendedInstant) -> Self {
+        fugit::TimerInstantU64::from_ticks(i.0)
+    }
+}
+
+#[cfg(feature = "fugit")]
+impl From<fugit::TimerInstantU64<1_000_000>> for ExtendedInstant {
+    fn from(i: fugit::TimerInstantU64<1_000_000>) -> Self {
+        ExtendedInstant(i.ticks())
+    }
+}
+
+/// Extends the `Instant`s of a [`Timer`] to `ExtendedInstant`s by counting wraparounds.
+///
+/// This is meant to be embedded in a [`Timer`] implementation, which passes every `Instant` it
+/// obtains to [`extend`]. Since a wraparound can only be detected by comparing consecutive
+/// `Instant`s, `extend` must be called at least once per wraparound period (about 71 minutes).
+/// A running BLE stack reads the time frequently enough for this, but an idle one might not.
+///
+/// [`Timer`]: trait.Timer.html
+/// [`extend`]: #method.extend
+#[derive(Debug, Default)]
+pub struct Rollover {
+    last: Cell<u64>,
+}
+
+impl Rollover {
+    /// Creates a rollover counter starting at the reference point of the [`Timer`].
+    ///
+    /// [`Timer`]: trait.Timer.html
+    pub const fn new() -> Self {
+        Self { last: Cell::new(0) }
+    }
+
+    /// Converts `now`, which must not be earlier than any previously passed `Instant`, to an
+    /// `ExtendedInstant`.
+    pub fn extend(&self, now: Instant) -> ExtendedInstant {
+        let last = self.last.get();
+        let mut extended = (last & !0xFFFF_FFFF) | u64::from(now.0);
+        if extended < last {
+            // The 32-bit counter has wrapped around since the last call
+            extended += 1 << 32;
+        }
+        self.last.set(extended);
+        ExtendedInstant(extended)
+    }
+}
+
 /// Trait for time providers.
 ///
 /// The hardware interface has to provide an implementation of `Timer` to the stack. The
 /// implementation must have microsecond accuracy.
 ///
+/// Timers backed by a hardware timer with compare interrupts should also implement
+/// [`schedule_at`] and [`cancel`]. The `LinkLayer` then schedules its own timer events, and the
+/// application only has to call `LinkLayer::update_timer` when the timer event occurs. Timers that
+/// can't do this keep the default implementations, and the application has to schedule the
+/// update according to `Cmd::next_update` itself.
+///
 /// This trait can also be implemented by a mock timer for testing.
+///
+/// [`schedule_at`]: #method.schedule_at
+/// [`cancel`]: #method.cancel
 pub trait Timer {
     /// Obtain the current time as an [`Instant`].
     ///
     /// The [`Instant`]s returned by this function must never move backwards in time, except when
     /// the underlying value wraps around.
     fn now(&self) -> Instant;
+
+    /// Schedules a timer event (usually an interrupt) at `at`, replacing any previously scheduled
+    /// event.
+    ///
+    /// If `at` lies in the past, the event should occur as soon as possible. The default
+    /// implementation does nothing.
+    fn schedule_at(&mut self, _at: Instant) {}
+
+    /// Cancels the scheduled timer event, if any.
+    ///
+    /// The default implementation does nothing.
+    fn cancel(&mut self) {}
+
+    /// Returns the time captured by the hardware when the radio finished sending or receiving the
+    /// last packet.
+    ///
+    /// Hardware timestamps aren't affected by interrupt latency, so radio drivers should prefer
+    /// them over `now` when determining when a packet was received. The default implementation
+    /// returns `None`, indicating that capturing is not supported or not set up.
+    fn last_capture(&self) -> Option<Instant> {
+        None
+    }
+
+    /// Obtain the current time as an [`ExtendedInstant`], which doesn't wrap around.
+    ///
+    /// Implementations can use a [`Rollover`] counter to provide this. The default implementation
+    /// returns `None`, indicating that extended time is not supported.
+    ///
+    /// [`ExtendedInstant`]: struct.ExtendedInstant.html
+    /// [`Rollover`]: struct.Rollover.html
+    fn now_extended(&self) -> Option<ExtendedInstant> {
+        None
+    }
+}
+
+/// A source of timestamps.
+///
+/// Unlike a [`Timer`], a `StampSource` can only read the current time. Every `Timer` is a
+/// `StampSource`, but radio drivers usually also provide a separate read-only handle to their
+/// timer that can be used while the `LinkLayer` owns the timer (eg. `BleTimer::create_stamp_source`
+/// in `rubble-nrf5x`). Timestamps read from such a handle can be compared to the `Instant`s used by
+/// the stack.
+///
+/// [`Timer`]: trait.Timer.html
+pub trait StampSource {
+    /// Obtain the current time as an [`Instant`].
+    ///
+    /// The [`Instant`]s returned by this function must never move backwards in time, except when
+    /// the underlying value wraps around.
+    fn now(&self) -> Instant;
+
+    /// Obtain the current time as an [`ExtendedInstant`], which doesn't wrap around.
+    ///
+    /// The default implementation returns `None`, indicating that extended time is not supported.
+    ///
+    /// [`ExtendedInstant`]: struct.ExtendedInstant.html
+    fn now_extended(&self) -> Option<ExtendedInstant> {
+        None
+    }
+}
+
+impl<T: Timer> StampSource for T {
+    fn now(&self) -> Instant {
+        Timer::now(self)
+    }
+
+    fn now_extended(&self) -> Option<ExtendedInstant> {
+        Timer::now_extended(self)
+    }
+}
+
+/// A [`StampSource`] that extends the timestamps of another one to 64 bits.
+///
+/// This embeds a [`Rollover`] counter, so the same restriction applies: The time has to be read
+/// at least once per wraparound period of the wrapped `StampSource` (about 71 minutes), otherwise
+/// a wraparound is missed.
+///
+/// [`StampSource`]: trait.StampSource.html
+/// [`Rollover`]: struct.Rollover.html
+#[derive(Debug)]
+pub struct ExtendedStampSource<S: StampSource> {
+    source: S,
+    rollover: Rollover,
+}
+
+impl<S: StampSource> ExtendedStampSource<S> {
+    /// Wraps `source`, starting at its reference point.
+    pub const fn new(source: S) -> Self {
+        Self {
+            source,
+            rollover: Rollover::new(),
+        }
+    }
+
+    /// Returns the current time as a monotonic `ExtendedInstant`.
+    pub fn extended_now(&self) -> ExtendedInstant {
+        self.rollover.extend(self.source.now())
+    }
+
+    /// Returns the wrapped `StampSource`.
+    pub fn into_inner(self) -> S {
+        self.source
+    }
+}
+
+impl<S: StampSource> StampSource for ExtendedStampSource<S> {
+    fn now(&self) -> Instant {
+        // Feed every reading to the rollover counter, so wraparounds are detected
+        let now = self.source.now();
+        self.rollover.extend(now);
+        now
+    }
+
+    fn now_extended(&self) -> Option<ExtendedInstant> {
+        Some(self.extended_now())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rollover() {
+        let rollover = Rollover::new();
+        assert_eq!(rollover.extend(Instant(5)).as_micros(), 5);
+        assert_eq!(
+            rollover.extend(Instant(0xFFFF_FFF0)).as_micros(),
+            0xFFFF_FFF0
+        );
+        assert_eq!(rollover.extend(Instant(3)).as_micros(), 0x1_0000_0003);
+        assert_eq!(rollover.extend(Instant(3)).as_micros(), 0x1_0000_0003);
+        assert_eq!(
+            rollover.extend(Instant(0xFFFF_FFFF)).as_micros(),
+            0x1_FFFF_FFFF
+        );
+        assert_eq!(rollover.extend(Instant(0)).as_micros(), 0x2_0000_0000);
+    }
+
+    #[test]
+    fn extended_stamp_source() {
+        struct Counter(Cell<u32>);
+
+        impl Timer for Counter {
+            fn now(&self) -> Instant {
+                Instant(self.0.get())
+            }
+        }
+
+        let stamps = ExtendedStampSource::new(Counter(Cell::new(0xFFFF_FFF0)));
+        assert_eq!(stamps.extended_now().as_micros(), 0xFFFF_FFF0);
+        stamps.source.0.set(0x10);
+        assert_eq!(StampSource::now(&stamps).raw_micros(), 0x10);
+        assert_eq!(stamps.now_extended().unwrap().as_micros(), 0x1_0000_0010);
+
+        // A plain `Timer` doesn't support extended time
+        assert!(StampSource::now_extended(&stamps.into_inner()).is_none());
+    }
+
+    #[test]
+    fn checked_arithmetic() {
+        let max = Duration::from_micros(u32::MAX);
+        let one = Duration::from_micros(1);
+        assert_eq!(max.checked_add(one), None);
+        assert_eq!(max.saturating_add(one), max);
+        assert_eq!(one.checked_sub(max), None);
+        assert_eq!(one.saturating_sub(max), Duration::from_micros(0));
+        assert_eq!(max.checked_mul(2), None);
+        assert_eq!(one.checked_mul(2), Some(Duration::from_micros(2)));
+
+        let early = Instant(u32::MAX - 10);
+        let late = early + Duration::from_micros(20);
+        assert_eq!(
+            late.checked_duration_since(early),
+            Some(Duration::from_micros(20))
+        );
+        assert_eq!(early.checked_duration_since(late), None);
+
+        let early = ExtendedInstant::from_micros(0xFFFF_FFF0);
+        let late = early + Duration::from_micros(0x20);
+        assert_eq!(late.as_micros(), 0x1_0000_0010);
+        assert_eq!(late.instant().raw_micros(), 0x10);
+        assert_eq!(
+            late.checked_duration_since(early),
+            Some(Duration::from_micros(0x20))
+        );
+        assert_eq!(early.checked_duration_since(late), None);
+    }
+
+    #[test]
+    fn core_duration() {
+        let d = Duration::from_micros(1_500_000);
+        let core = core::time::Duration::from(d);
+        assert_eq!(core, core::time::Duration::from_millis(1500));
+        assert_eq!(Duration::try_from(core), Ok(d));
+        assert_eq!(
+            Duration::try_from(core::time::Duration::from_secs(5000)),
+            Err(Error::InvalidValue)
+        );
+    }
+    #[test]
+    #[cfg(feature = "fugit")]
+    fn fugit() {
+        let d = Duration::from_millis(1500);
+        let fugit = fugit::MicrosDurationU32::from(d);
+        assert_eq!(fugit.to_millis(), 1500);
+        assert_eq!(Duration::from(fugit), d);
+
+        let i = Instant::from_raw_micros(1234);
+        assert_eq!(
+            Instant::from(fugit::TimerInstantU32::from(i)).raw_micros(),
+            1234
+        );
+
+        let e = ExtendedInstant::from_micros(0x1_0000_0000);
+        assert_eq!(fugit::TimerInstantU64::from(e).ticks(), 0x1_0000_0000);
+        assert_eq!(ExtendedInstant::from(fugit::TimerInstantU64::from(e)), e);
+    }
 }
@@ -17,6 +17,7 @@ macro_rules! enum_with_unknown {
         }
     ) => {
         $( #[$enum_attr] )*
+        #[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
         $v enum $name {
             $(
               $( #[$variant_attr] )*
@@ -82,6 +83,13 @@ impl<T: AsRef<[u8]>> defmt::Format for HexSlice<T> {
     }
 }
 
+#[cfg(feature = "serde")]
+impl<T: AsRef<[u8]>> serde::Serialize for HexSlice<T> {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_bytes(self.0.as_ref())
+    }
+}
+
 impl<T: AsRef<[u8]>> AsRef<T> for HexSlice<T> {
     fn as_ref(&self) -> &T {
         &self.0
@@ -103,3 +111,10 @@ impl<T: defmt::Format> defmt::Format for Hex<T> {
         defmt::write!(fmt, "{:x}", self.0);
     }
 }
+
+#[cfg(feature = "serde")]
+impl<T: serde::Serialize> serde::Serialize for Hex<T> {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.0.serialize(serializer)
+    }
+}
@@ -0,0 +1,85 @@
+//! UUIDs of common GATT attributes.
+//!
+//! These are the 16-bit aliases assigned by the Bluetooth SIG. They can be promoted to their full
+//! 128-bit form with `.into()` or `to_uuid128()` when needed.
+
+/// Attribute types of the GATT declarations.
+pub mod declarations {
+    use crate::uuid::Uuid16;
+
+    pub const PRIMARY_SERVICE: Uuid16 = Uuid16(0x2800);
+    pub const SECONDARY_SERVICE: Uuid16 = Uuid16(0x2801);
+    pub const INCLUDE: Uuid16 = Uuid16(0x2802);
+    pub const CHARACTERISTIC: Uuid16 = Uuid16(0x2803);
+}
+
+/// Characteristic descriptors.
+pub mod descriptors {
+    use crate::uuid::Uuid16;
+
+    pub const CHARACTERISTIC_EXTENDED_PROPERTIES: Uuid16 = Uuid16(0x2900);
+    pub const CHARACTERISTIC_USER_DESCRIPTION: Uuid16 = Uuid16(0x2901);
+    pub const CLIENT_CHARACTERISTIC_CONFIGURATION: Uuid16 = Uuid16(0x2902);
+    pub const SERVER_CHARACTERISTIC_CONFIGURATION: Uuid16 = Uuid16(0x2903);
+    pub const CHARACTERISTIC_PRESENTATION_FORMAT: Uuid16 = Uuid16(0x2904);
+    pub const CHARACTERISTIC_AGGREGATE_FORMAT: Uuid16 = Uuid16(0x2905);
+    pub const VALID_RANGE: Uuid16 = Uuid16(0x2906);
+    pub const EXTERNAL_REPORT_REFERENCE: Uuid16 = Uuid16(0x2907);
+    pub const REPORT_REFERENCE: Uuid16 = Uuid16(0x2908);
+}
+
+/// GATT services.
+pub mod services {
+    use crate::uuid::Uuid16;
+
+    pub const GENERIC_ACCESS: Uuid16 = Uuid16(0x1800);
+    pub const GENERIC_ATTRIBUTE: Uuid16 = Uuid16(0x1801);
+    pub const IMMEDIATE_ALERT: Uuid16 = Uuid16(0x1802);
+    pub const LINK_LOSS: Uuid16 = Uuid16(0x1803);
+    pub const TX_POWER: Uuid16 = Uuid16(0x1804);
+    pub const CURRENT_TIME: Uuid16 = Uuid16(0x1805);
+    pub const HEALTH_THERMOMETER: Uuid16 = Uuid16(0x1809);
+    pub const DEVICE_INFORMATION: Uuid16 = Uuid16(0x180A);
+    pub const HEART_RATE: Uuid16 = Uuid16(0x180D);
+    pub const BATTERY: Uuid16 = Uuid16(0x180F);
+    pub const BLOOD_PRESSURE: Uuid16 = Uuid16(0x1810);
+    pub const HUMAN_INTERFACE_DEVICE: Uuid16 = Uuid16(0x1812);
+    pub const SCAN_PARAMETERS: Uuid16 = Uuid16(0x1813);
+    pub const RUNNING_SPEED_AND_CADENCE: Uuid16 = Uuid16(0x1814);
+    pub const CYCLING_SPEED_AND_CADENCE: Uuid16 = Uuid16(0x1816);
+    pub const ENVIRONMENTAL_SENSING: Uuid16 = Uuid16(0x181A);
+    pub const INTERNET_PROTOCOL_SUPPORT: Uuid16 = Uuid16(0x1820);
+}
+
+/// GATT characteristics.
+pub mod characteristics {
+    use crate::uuid::Uuid16;
+
+    pub const DEVICE_NAME: Uuid16 = Uuid16(0x2A00);
+    pub const APPEARANCE: Uuid16 = Uuid16(0x2A01);
+    pub const PERIPHERAL_PREFERRED_CONNECTION_PARAMETERS: Uuid16 = Uuid16(0x2A04);
+    pub const SERVICE_CHANGED: Uuid16 = Uuid16(0x2A05);
+    pub const ALERT_LEVEL: Uuid16 = Uuid16(0x2A06);
+    pub const TX_POWER_LEVEL: Uuid16 = Uuid16(0x2A07);
+    pub const BATTERY_LEVEL: Uuid16 = Uuid16(0x2A19);
+    pub const TEMPERATURE_MEASUREMENT: Uuid16 = Uuid16(0x2A1C);
+    pub const SYSTEM_ID: Uuid16 = Uuid16(0x2A23);
+    pub const MODEL_NUMBER_STRING: Uuid16 = Uuid16(0x2A24);
+    pub const SERIAL_NUMBER_STRING: Uuid16 = Uuid16(0x2A25);
+    pub const FIRMWARE_REVISION_STRING: Uuid16 = Uuid16(0x2A26);
+    pub const HARDWARE_REVISION_STRING: Uuid16 = Uuid16(0x2A27);
+    pub const SOFTWARE_REVISION_STRING: Uuid16 = Uuid16(0x2A28);
+    pub const MANUFACTURER_NAME_STRING: Uuid16 = Uuid16(0x2A29);
+    pub const HEART_RATE_MEASUREMENT: Uuid16 = Uuid16(0x2A37);
+    pub const BODY_SENSOR_LOCATION: Uuid16 = Uuid16(0x2A38);
+    pub const HID_INFORMATION: Uuid16 = Uuid16(0x2A4A);
+    pub const REPORT_MAP: Uuid16 = Uuid16(0x2A4B);
+    pub const HID_CONTROL_POINT: Uuid16 = Uuid16(0x2A4C);
+    pub const REPORT: Uuid16 = Uuid16(0x2A4D);
+    pub const PROTOCOL_MODE: Uuid16 = Uuid16(0x2A4E);
+    pub const PNP_ID: Uuid16 = Uuid16(0x2A50);
+    pub const TEMPERATURE: Uuid16 = Uuid16(0x2A6E);
+    pub const HUMIDITY: Uuid16 = Uuid16(0x2A6F);
+    pub const CENTRAL_ADDRESS_RESOLUTION: Uuid16 = Uuid16(0x2AA6);
+    pub const RESOLVABLE_PRIVATE_ADDRESS_ONLY: Uuid16 = Uuid16(0x2AC9);
+}
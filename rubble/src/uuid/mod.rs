@@ -0,0 +1,477 @@
+//! BLE UUIDs (16, 32 or 128 bits).
+//!
+//! Bluetooth assigns UUIDs to identify services and characteristics. In order to save space, many
+//! common UUIDs can be represented and transmitted as 16- or 32-bit aliases instead of the full
+//! 128 bits.
+//!
+//! The shorter UUIDs can be converted to their full 128-bit counterparts by making use of the
+//! Bluetooth Base UUID, which is defined as `00000000-0000-1000-8000-00805F9B34FB`.
+//!
+//! A 16-bit UUID alias can be converted to its 32-bit equivalent by zero-extending it: `0xABCD`
+//! becomes `0x0000ABCD`.
+//!
+//! A 32-bit UUID alias can then be converted to its full 128-bit equivalent by placing it in the
+//! first 4 Bytes of the Base UUID. Hence `0x1234ABCD` would become
+//! `1234ABCD-0000-1000-8000-00805F9B34FB`.
+//!
+//! UUIDs of different widths compare equal when they stand for the same 128-bit UUID, so
+//! `Uuid16(0x180F) == Uuid128::parse_static("0000180f-0000-1000-8000-00805f9b34fb")`.
+//!
+//! The [`consts`] module contains the UUIDs of common GATT services, characteristics and
+//! descriptors.
+//!
+//! [`consts`]: consts/index.html
+
+pub mod consts;
+
+use crate::{bytes::*, Error};
+use core::{fmt, str::FromStr};
+
+/// A 16-bit UUID alias.
+///
+/// Can be converted to its 32- and 128-bit equivalents via `.into()`.
+#[derive(PartialEq, Eq, Copy, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Uuid16(pub u16);
+
+/// A 32-bit UUID alias.
+///
+/// Can be converted to its 128-bit equivalent via `.into()`.
+#[derive(PartialEq, Eq, Copy, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Uuid32(pub u32);
+
+/// A full 128-bit UUID.
+#[derive(PartialEq, Eq, Copy, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Uuid128([u8; 16]);
+
+impl Uuid16 {
+    /// Converts this 16-bit alias to its 32-bit equivalent.
+    pub const fn to_uuid32(self) -> Uuid32 {
+        Uuid32(self.0 as u32)
+    }
+
+    /// Converts this 16-bit alias to the full 128-bit UUID it stands for.
+    pub const fn to_uuid128(self) -> Uuid128 {
+        self.to_uuid32().to_uuid128()
+    }
+}
+
+impl Uuid32 {
+    /// Converts this 32-bit alias to the full 128-bit UUID it stands for.
+    pub const fn to_uuid128(self) -> Uuid128 {
+        let alias = self.0.to_be_bytes();
+        let mut bytes = Uuid128::BASE_UUID.0;
+        bytes[0] = alias[0];
+        bytes[1] = alias[1];
+        bytes[2] = alias[2];
+        bytes[3] = alias[3];
+        Uuid128(bytes)
+    }
+
+    /// Returns the equivalent 16-bit alias, if this UUID has one.
+    pub const fn to_uuid16(self) -> Option<Uuid16> {
+        if self.0 <= 0xFFFF {
+            Some(Uuid16(self.0 as u16))
+        } else {
+            None
+        }
+    }
+}
+
+impl Uuid128 {
+    const BASE_UUID: Self = Self::parse_static("00000000-0000-1000-8000-00805f9b34fb");
+
+    /// Creates a 128-bit UUID from 16 raw bytes (encoded in big-endian).
+    ///
+    /// This is the order in which the UUID is written in its text form.
+    pub const fn from_bytes(bytes: [u8; 16]) -> Self {
+        Self(bytes)
+    }
+
+    /// Creates a 128-bit UUID from 16 raw bytes encoded in little-endian.
+    ///
+    /// This is the order in which UUIDs are transmitted over the air.
+    pub const fn from_le_bytes(bytes: [u8; 16]) -> Self {
+        Self(reverse(bytes))
+    }
+
+    /// Creates a 128-bit UUID from its integer representation.
+    ///
+    /// This allows writing the UUID `a86a62f0-5d26-4538-b364-5654961515c9` as
+    /// `Uuid128::from_u128(0xa86a62f0_5d26_4538_b364_5654961515c9)`.
+    pub const fn from_u128(uuid: u128) -> Self {
+        Self(uuid.to_be_bytes())
+    }
+
+    /// Returns the big-endian bytes of this UUID (in the order of its text form).
+    pub const fn to_be_bytes(&self) -> [u8; 16] {
+        self.0
+    }
+
+    /// Returns the little-endian bytes of this UUID (in the order used on the air).
+    pub const fn to_le_bytes(&self) -> [u8; 16] {
+        reverse(self.0)
+    }
+
+    /// Returns the integer representation of this UUID.
+    pub const fn to_u128(&self) -> u128 {
+        u128::from_be_bytes(self.0)
+    }
+
+    /// Returns the equivalent 32-bit alias, if this UUID is derived from the Bluetooth Base UUID.
+    pub const fn to_uuid32(&self) -> Option<Uuid32> {
+        let mut i = 4;
+        while i < 16 {
+            if self.0[i] != Self::BASE_UUID.0[i] {
+                return None;
+            }
+            i += 1;
+        }
+
+        Some(Uuid32(u32::from_be_bytes([
+            self.0[0], self.0[1], self.0[2], self.0[3],
+        ])))
+    }
+
+    /// Returns the equivalent 16-bit alias, if this UUID has one.
+    pub const fn to_uuid16(&self) -> Option<Uuid16> {
+        match self.to_uuid32() {
+            Some(uuid) => uuid.to_uuid16(),
+            None => None,
+        }
+    }
+
+    /// Parses a UUID from its canonical text form (eg. `0000fd6f-0000-1000-8000-00805f9b34fb`).
+    ///
+    /// Both lower- and uppercase hex digits are accepted. Returns `Error::InvalidValue` if the
+    /// string is malformed.
+    pub const fn parse(s: &str) -> Result<Self, Error> {
+        // full UUID: 0000fd6f-0000-1000-8000-00805f9b34fb (36 chars/bytes)
+        // dashes at offsets 8, 13, 18, 23
+        let s = s.as_bytes();
+        if s.len() != 36 {
+            return Err(Error::InvalidValue);
+        }
+
+        let mut bytes = [0; 16];
+        let (mut index, mut byte) = (0, 0);
+        while index < s.len() {
+            if index == 8 || index == 13 || index == 18 || index == 23 {
+                if s[index] != b'-' {
+                    return Err(Error::InvalidValue);
+                }
+                index += 1;
+                continue;
+            }
+
+            let (hi, lo) = match (parse_nibble(s[index]), parse_nibble(s[index + 1])) {
+                (Some(hi), Some(lo)) => (hi, lo),
+                _ => return Err(Error::InvalidValue),
+            };
+            bytes[byte] = (hi << 4) | lo;
+            byte += 1;
+            index += 2;
+        }
+
+        Ok(Uuid128(bytes))
+    }
+
+    /// Parses a UUID string literal, panicking when the string is malformed.
+    ///
+    /// This is meant to be used in constant contexts, where a malformed UUID results in a
+    /// compile-time error.
+    pub const fn parse_static(s: &'static str) -> Self {
+        match Self::parse(s) {
+            Ok(uuid) => uuid,
+            Err(_) => panic!("malformed UUID literal"),
+        }
+    }
+}
+
+const fn parse_nibble(nibble: u8) -> Option<u8> {
+    match nibble {
+        b'0'..=b'9' => Some(nibble - b'0'),
+        b'a'..=b'f' => Some(nibble - b'a' + 10),
+        b'A'..=b'F' => Some(nibble - b'A' + 10),
+        _ => None,
+    }
+}
+
+const fn reverse(bytes: [u8; 16]) -> [u8; 16] {
+    let mut reversed = [0; 16];
+    let mut i = 0;
+    while i < 16 {
+        reversed[i] = bytes[15 - i];
+        i += 1;
+    }
+    reversed
+}
+
+impl FromStr for Uuid128 {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Error> {
+        Self::parse(s)
+    }
+}
+
+impl From<Uuid16> for Uuid32 {
+    fn from(smol: Uuid16) -> Self {
+        smol.to_uuid32()
+    }
+}
+
+impl From<Uuid16> for Uuid128 {
+    fn from(uuid: Uuid16) -> Self {
+        uuid.to_uuid128()
+    }
+}
+
+impl From<Uuid32> for Uuid128 {
+    fn from(uuid: Uuid32) -> Self {
+        uuid.to_uuid128()
+    }
+}
+
+// UUIDs of different widths are equal when they stand for the same 128-bit UUID.
+macro_rules! cross_width_eq {
+    ($($a:ident == $b:ident;)+) => {
+        $(
+            impl PartialEq<$b> for $a {
+                fn eq(&self, other: &$b) -> bool {
+                    Uuid128::from(*self) == Uuid128::from(*other)
+                }
+            }
+
+            impl PartialEq<$a> for $b {
+                fn eq(&self, other: &$a) -> bool {
+                    other == self
+                }
+            }
+        )+
+    };
+}
+
+cross_width_eq! {
+    Uuid16 == Uuid32;
+    Uuid16 == Uuid128;
+    Uuid32 == Uuid128;
+}
+
+impl ToBytes for Uuid16 {
+    fn to_bytes(&self, buffer: &mut ByteWriter<'_>) -> Result<(), Error> {
+        buffer.write_slice(&self.0.to_le_bytes())
+    }
+}
+
+impl ToBytes for Uuid32 {
+    fn to_bytes(&self, buffer: &mut ByteWriter<'_>) -> Result<(), Error> {
+        buffer.write_slice(&self.0.to_le_bytes())
+    }
+}
+
+impl ToBytes for Uuid128 {
+    fn to_bytes(&self, buffer: &mut ByteWriter<'_>) -> Result<(), Error> {
+        buffer.write_slice(&self.to_le_bytes())
+    }
+}
+
+impl FromBytes<'_> for Uuid16 {
+    fn from_bytes(bytes: &mut ByteReader<'_>) -> Result<Self, Error> {
+        let array = bytes.read_array()?;
+        Ok(Uuid16(u16::from_le_bytes(array)))
+    }
+}
+
+impl FromBytes<'_> for Uuid32 {
+    fn from_bytes(bytes: &mut ByteReader<'_>) -> Result<Self, Error> {
+        let array = bytes.read_array()?;
+        Ok(Uuid32(u32::from_le_bytes(array)))
+    }
+}
+
+impl FromBytes<'_> for Uuid128 {
+    fn from_bytes(bytes: &mut ByteReader<'_>) -> Result<Self, Error> {
+        let array = bytes.read_array()?;
+        Ok(Uuid128::from_le_bytes(array))
+    }
+}
+
+impl fmt::Debug for Uuid16 {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Uuid16({:04x})", self.0)
+    }
+}
+
+impl fmt::Debug for Uuid32 {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Uuid32({:08x})", self.0)
+    }
+}
+
+impl fmt::Debug for Uuid128 {
+    #[allow(clippy::many_single_char_names, clippy::just_underscores_and_digits)]
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let [_0, _1, _2, _3, _4, _5, _6, _7, _8, _9, _10, _11, _12, _13, _14, _15] = self.0;
+        let a = u32::from_be_bytes([_0, _1, _2, _3]);
+        let b = u16::from_be_bytes([_4, _5]);
+        let c = u16::from_be_bytes([_6, _7]);
+        let d = u16::from_be_bytes([_8, _9]);
+        let e = u64::from_be_bytes([0, 0, _10, _11, _12, _13, _14, _15]);
+        write!(f, "{:08x}-{:04x}-{:04x}-{:04x}-{:012x}", a, b, c, d, e)
+    }
+}
+
+impl defmt::Format for Uuid16 {
+    fn format(&self, f: defmt::Formatter<'_>) {
+        defmt::write!(f, "Uuid16({=u16:04x})", self.0);
+    }
+}
+
+impl defmt::Format for Uuid32 {
+    fn format(&self, f: defmt::Formatter<'_>) {
+        defmt::write!(f, "Uuid32({=u32:08x})", self.0);
+    }
+}
+
+impl defmt::Format for Uuid128 {
+    #[allow(clippy::many_single_char_names, clippy::just_underscores_and_digits)]
+    fn format(&self, f: defmt::Formatter<'_>) {
+        let [_0, _1, _2, _3, _4, _5, _6, _7, _8, _9, _10, _11, _12, _13, _14, _15] = self.0;
+        let a = u32::from_be_bytes([_0, _1, _2, _3]);
+        let b = u16::from_be_bytes([_4, _5]);
+        let c = u16::from_be_bytes([_6, _7]);
+        let d = u16::from_be_bytes([_8, _9]);
+        let e = u64::from_be_bytes([0, 0, _10, _11, _12, _13, _14, _15]);
+        defmt::write!(
+            f,
+            "{=u32:08x}-{=u16:04x}-{=u16:04x}-{=u16:04x}-{=u64:012x}",
+            a,
+            b,
+            c,
+            d,
+            e
+        );
+    }
+}
+
+/// List of the supported UUID types.
+#[derive(Debug, Copy, Clone, defmt::Format)]
+pub enum UuidKind {
+    Uuid16,
+    Uuid32,
+    Uuid128,
+}
+
+/// Marker for UUID types.
+///
+/// This is useful when being generic over the specific type of UUID used. It
+/// also brings in the `ToBytes` and `FromBytes` trait bounds that are likely
+/// needed as well.
+pub trait IsUuid: for<'a> FromBytes<'a> + ToBytes + Copy {
+    const KIND: UuidKind;
+}
+
+impl IsUuid for Uuid16 {
+    const KIND: UuidKind = UuidKind::Uuid16;
+}
+
+impl IsUuid for Uuid32 {
+    const KIND: UuidKind = UuidKind::Uuid32;
+}
+
+impl IsUuid for Uuid128 {
+    const KIND: UuidKind = UuidKind::Uuid128;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fmt() {
+        // Check that all leading 0s are printed.
+        let uuid = Uuid128::from_bytes([
+            0x02, 0x3e, 0x45, 0x67, 0x08, 0x9b, 0x02, 0xd3, 0x04, 0x56, 0x00, 0x66, 0x14, 0x17,
+            0x40, 0x00,
+        ]);
+
+        assert_eq!(
+            format!("{:?}", uuid),
+            "023e4567-089b-02d3-0456-006614174000"
+        );
+    }
+
+    #[test]
+    fn convert() {
+        let uuid = 0xfd6f; // Apple Inc. / Exposure Notification Service
+        let uuid = Uuid128::from(Uuid16(uuid));
+
+        assert_eq!(
+            format!("{:?}", uuid),
+            "0000fd6f-0000-1000-8000-00805f9b34fb"
+        );
+    }
+
+    #[test]
+    fn parse() {
+        let uuid = "0000fd6f-0000-1000-8000-00805f9b34fb";
+        assert_eq!(format!("{:?}", Uuid128::parse_static(uuid)), uuid);
+
+        let upper: Uuid128 = "A86A62F0-5D26-4538-B364-5654961515C9".parse().unwrap();
+        assert_eq!(
+            upper,
+            Uuid128::from_u128(0xa86a62f0_5d26_4538_b364_5654961515c9)
+        );
+
+        for malformed in &[
+            "",
+            "0000fd6f-0000-1000-8000-00805f9b34f",
+            "0000fd6f-0000-1000-8000-00805f9b34fbb",
+            "0000fd6f 0000-1000-8000-00805f9b34fb",
+            "0000fd6g-0000-1000-8000-00805f9b34fb",
+            "0000fd6f0-000-1000-8000-00805f9b34fb",
+        ] {
+            assert_eq!(Uuid128::parse(malformed), Err(Error::InvalidValue));
+        }
+    }
+
+    #[test]
+    fn byte_order() {
+        let uuid = Uuid128::parse_static("a86a62f0-5d26-4538-b364-5654961515c9");
+        let le = [
+            0xC9, 0x15, 0x15, 0x96, 0x54, 0x56, 0x64, 0xB3, 0x38, 0x45, 0x26, 0x5D, 0xF0, 0x62,
+            0x6A, 0xA8,
+        ];
+        assert_eq!(uuid.to_le_bytes(), le);
+        assert_eq!(Uuid128::from_le_bytes(le), uuid);
+
+        // UUIDs are transmitted in little-endian
+        let mut buf = [0; 16];
+        uuid.to_bytes(&mut ByteWriter::new(&mut buf)).unwrap();
+        assert_eq!(buf, le);
+        let parsed = <Uuid128 as FromBytes>::from_bytes(&mut ByteReader::new(&le)).unwrap();
+        assert_eq!(parsed, uuid);
+    }
+
+    #[test]
+    fn cross_width() {
+        let battery = Uuid128::parse_static("0000180f-0000-1000-8000-00805f9b34fb");
+        assert_eq!(Uuid16(0x180F), battery);
+        assert_eq!(battery, Uuid16(0x180F));
+        assert_eq!(Uuid32(0x180F), battery);
+        assert_eq!(Uuid16(0x180F), Uuid32(0x180F));
+        assert_ne!(Uuid16(0x1810), battery);
+        assert_ne!(Uuid32(0x1_180F), Uuid16(0x180F));
+
+        assert_eq!(battery.to_uuid16(), Some(Uuid16(0x180F)));
+        assert_eq!(battery.to_uuid32(), Some(Uuid32(0x180F)));
+        let custom = Uuid128::from_u128(0x12345678_0000_1000_8000_00805f9b34fb);
+        assert_eq!(custom.to_uuid16(), None);
+        assert_eq!(custom.to_uuid32(), Some(Uuid32(0x1234_5678)));
+        let custom = Uuid128::from_u128(0xa86a62f0_5d26_4538_b364_5654961515c9);
+        assert_eq!(custom.to_uuid32(), None);
+    }
+}